@@ -0,0 +1,27 @@
+//! Bridges the agent protocol over stdio, for clients that reach the agent with `exec` instead of
+//! port-forwarding (`agent.connection = "exec"`).
+//!
+//! The bridge is a separate process, started in the agent container next to the agent, so it
+//! connects to the agent over localhost.
+use std::net::Ipv4Addr;
+
+use tokio::{io, net::TcpStream};
+
+use crate::error::Result;
+
+/// Connects to the agent listening on `port`, and copies data between it and stdio until either
+/// side is done.
+pub(crate) async fn run(port: u16) -> Result<()> {
+    let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+    let (mut agent_read, mut agent_write) = stream.into_split();
+
+    let mut stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    tokio::select! {
+        result = io::copy(&mut stdin, &mut agent_write) => result?,
+        result = io::copy(&mut agent_read, &mut stdout) => result?,
+    };
+
+    Ok(())
+}
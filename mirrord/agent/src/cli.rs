@@ -1,5 +1,7 @@
 #![deny(missing_docs)]
 
+use std::net::IpAddr;
+
 use clap::{Parser, Subcommand};
 use mirrord_protocol::{MeshVendor, AGENT_OPERATOR_CERT_ENV};
 
@@ -30,6 +32,15 @@ pub struct Args {
     #[arg(short = 'p', long, default_value_t = false)]
     pub pause: bool,
 
+    /// Unpause the target container after this many seconds, even if clients are still
+    /// connected. `0` disables the timeout.
+    #[arg(long, default_value_t = 1800)]
+    pub pause_timeout: u64,
+
+    /// Allow clients to run processes in the target container (`feature.remote_exec`).
+    #[arg(long, default_value_t = false)]
+    pub remote_exec: bool,
+
     /// Return an error after accepting the first client connection, in order to test agent error
     /// cleanup.
     ///
@@ -68,6 +79,14 @@ pub enum Mode {
         /// Which kind of mesh the remote pod is in, see [`MeshVendor`].
         #[arg(long)]
         mesh: Option<MeshVendor>,
+
+        /// Other containers to load environment variables from, as `<container id>` or
+        /// `<container id>=<prefix>`.
+        ///
+        /// Their environments are merged in order, and the target container's environment is
+        /// merged last.
+        #[arg(long = "env-container")]
+        env_containers: Vec<String>,
     },
     /// Inform the agent to use `proc/1/root` as the root directory.
     Ephemeral {
@@ -79,8 +98,42 @@ pub enum Mode {
     },
     #[default]
     Targetless,
+    /// Runs without privileges in a copy of the target pod, in place of the target container (for
+    /// nodes that don't allow privileged agents).
+    ///
+    /// Stolen ports are bound directly on `pod_ip`, as iptables are not available.
+    Sidecar {
+        /// IP of the pod, from the downward API.
+        #[arg(long, env = "MIRRORD_AGENT_POD_IP")]
+        pod_ip: IpAddr,
+
+        /// IP of the target pod, where the connections to `forward_ports` that are not stolen, and
+        /// the unmatched HTTP requests, are forwarded.
+        #[arg(long)]
+        forward_to: Option<IpAddr>,
+
+        /// Ports of the target container, bound on `pod_ip` from the start, so that the copy
+        /// serves the traffic that Kubernetes sends to it. Used with `forward_to`.
+        #[arg(long = "forward-port", requires = "forward_to")]
+        forward_ports: Vec<u16>,
+    },
     #[clap(hide = true)]
     BlackboxTest,
+    /// Connects stdio to the agent listening on `communicate_port` in this container, for
+    /// clients that connect with `exec` instead of port-forwarding.
+    #[clap(hide = true)]
+    Bridge,
+}
+
+/// Arguments of [`Mode::Sidecar`] for the stealer.
+#[derive(Clone, Debug)]
+pub struct SidecarListeners {
+    /// See [`Mode::Sidecar::pod_ip`].
+    pub pod_ip: IpAddr,
+    /// See [`Mode::Sidecar::forward_to`].
+    pub forward_to: Option<IpAddr>,
+    /// See [`Mode::Sidecar::forward_ports`].
+    pub forward_ports: Vec<u16>,
 }
 
 impl Mode {
@@ -88,6 +141,27 @@ impl Mode {
         matches!(self, Mode::Targetless)
     }
 
+    /// See [`Mode::Sidecar`].
+    pub fn is_sidecar(&self) -> bool {
+        matches!(self, Mode::Sidecar { .. })
+    }
+
+    /// Where to bind stolen ports, and forward the other connections, see [`Mode::Sidecar`].
+    pub fn sidecar_listeners(&self) -> Option<SidecarListeners> {
+        match self {
+            Mode::Sidecar {
+                pod_ip,
+                forward_to,
+                forward_ports,
+            } => Some(SidecarListeners {
+                pod_ip: *pod_ip,
+                forward_to: *forward_to,
+                forward_ports: forward_ports.clone(),
+            }),
+            _ => None,
+        }
+    }
+
     // TODO(alex): Remove this when `mesh` option is removed from `cli::Mode`, and put into
     // `cli::Args`.
     /// Digs into `Mode` subcomand to get the `MeshVendor`.
@@ -1,7 +1,11 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
 
-use tokio::sync::RwLock;
-use tracing::{error, info};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{error, info, warn};
 
 use crate::{
     error::Result,
@@ -18,6 +22,10 @@ struct Inner {
     raw_env: HashMap<String, String>,
     /// Whether the container is paused.
     paused: RwLock<bool>,
+    /// How long the container can stay paused, see [`ContainerHandle::set_paused`].
+    pause_timeout: Option<Duration>,
+    /// Task that unpauses the container when [`Inner::pause_timeout`] elapses.
+    pause_watchdog: Mutex<Option<JoinHandle<()>>>,
     /// Watch for using in the drop
     watch: drain::Watch,
 }
@@ -48,8 +56,14 @@ pub(crate) struct ContainerHandle(Arc<Inner>);
 
 impl ContainerHandle {
     /// Retrieve info about the container and initialize this struct.
+    ///
+    /// When `pause_timeout` is given, the container is never left paused for longer.
     #[tracing::instrument(level = "trace")]
-    pub(crate) async fn new(container: Container, watch: drain::Watch) -> Result<Self> {
+    pub(crate) async fn new(
+        container: Container,
+        watch: drain::Watch,
+        pause_timeout: Option<Duration>,
+    ) -> Result<Self> {
         let ContainerInfo { pid, env: raw_env } = container.get_info().await?;
 
         let inner = Inner {
@@ -58,6 +72,8 @@ impl ContainerHandle {
             raw_env,
             watch,
             paused: Default::default(),
+            pause_timeout,
+            pause_watchdog: Default::default(),
         };
 
         Ok(Self(inner.into()))
@@ -76,6 +92,9 @@ impl ContainerHandle {
     /// Pause or unpause the container.
     /// If the container changed its state, return true.
     /// Otherwise, return false.
+    ///
+    /// A paused container is unpaused automatically when the pause timeout elapses, so that a
+    /// misbehaving client cannot leave it frozen for good.
     #[tracing::instrument(level = "trace", skip(self))]
     pub(crate) async fn set_paused(&self, paused: bool) -> Result<bool> {
         let mut guard = self.0.paused.write().await;
@@ -87,6 +106,49 @@ impl ContainerHandle {
         }
         *guard = paused;
 
+        let watchdog = match (paused, self.0.pause_timeout) {
+            (true, Some(timeout)) => Some(tokio::spawn(Self::pause_watchdog(
+                Arc::downgrade(&self.0),
+                timeout,
+            ))),
+            _ => None,
+        };
+        let previous = std::mem::replace(
+            &mut *self
+                .0
+                .pause_watchdog
+                .lock()
+                .expect("pause watchdog lock poisoned"),
+            watchdog,
+        );
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+
         Ok(true)
     }
+
+    /// Unpauses the container after `timeout`, unless it was unpaused or dropped before.
+    async fn pause_watchdog(inner: Weak<Inner>, timeout: Duration) {
+        tokio::time::sleep(timeout).await;
+
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+
+        let mut guard = inner.paused.write().await;
+        if *guard {
+            warn!(
+                ?timeout,
+                "Target container was paused for too long, unpausing it."
+            );
+
+            match inner.container.unpause().await {
+                Ok(()) => *guard = false,
+                Err(err) => {
+                    error!("Could not unpause target container after timeout, got error: {err:?}")
+                }
+            }
+        }
+    }
 }
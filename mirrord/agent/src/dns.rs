@@ -2,7 +2,7 @@ use std::{future, path::PathBuf, time::Duration};
 
 use futures::{stream::FuturesOrdered, StreamExt};
 use mirrord_protocol::{
-    dns::{DnsLookup, GetAddrInfoRequest, GetAddrInfoResponse},
+    dns::{AddressFamily, DnsLookup, GetAddrInfoRequestV2, GetAddrInfoResponse},
     DnsLookupError, RemoteResult, ResolveErrorKindInternal, ResponseError,
 };
 use tokio::{
@@ -13,7 +13,9 @@ use tokio::{
     },
 };
 use tokio_util::sync::CancellationToken;
-use trust_dns_resolver::{system_conf::parse_resolv_conf, AsyncResolver, Hosts};
+use trust_dns_resolver::{
+    config::LookupIpStrategy, system_conf::parse_resolv_conf, AsyncResolver, Hosts,
+};
 
 use crate::{
     error::{AgentError, Result},
@@ -22,7 +24,7 @@ use crate::{
 
 #[derive(Debug)]
 pub(crate) struct DnsCommand {
-    request: GetAddrInfoRequest,
+    request: GetAddrInfoRequestV2,
     response_tx: oneshot::Sender<RemoteResult<DnsLookup>>,
 }
 
@@ -69,7 +71,7 @@ impl DnsWorker {
     }
 
     /// Reads `/etc/resolv.conf` and `/etc/hosts` files, then uses [`AsyncResolver`] to resolve
-    /// address of the given `host`.
+    /// address of the given `host`, of the given `family`.
     ///
     /// # TODO
     ///
@@ -79,6 +81,7 @@ impl DnsWorker {
     async fn do_lookup(
         etc_path: PathBuf,
         host: String,
+        family: AddressFamily,
         attempts: usize,
         timeout: Duration,
     ) -> RemoteResult<DnsLookup> {
@@ -93,7 +96,11 @@ impl DnsWorker {
             trust_dns_resolver::config::ServerOrderingStrategy::UserProvidedOrder;
         options.timeout = timeout;
         options.attempts = attempts;
-        options.ip_strategy = trust_dns_resolver::config::LookupIpStrategy::Ipv4Only;
+        options.ip_strategy = match family {
+            AddressFamily::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            AddressFamily::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            AddressFamily::Both => LookupIpStrategy::Ipv4AndIpv6,
+        };
 
         let mut resolver = AsyncResolver::tokio(config, options)?;
 
@@ -115,7 +122,8 @@ impl DnsWorker {
         let timeout = self.timeout;
         let attempts = self.attempts;
         let lookup_future = async move {
-            let result = Self::do_lookup(etc_path, message.request.node, attempts, timeout).await;
+            let GetAddrInfoRequestV2 { node, family } = message.request;
+            let result = Self::do_lookup(etc_path, node, family, attempts, timeout).await;
             if let Err(result) = message.response_tx.send(result) {
                 tracing::error!(?result, "Failed to send query response");
             }
@@ -162,7 +170,7 @@ impl DnsApi {
     /// Results of scheduled requests are available via [`Self::recv`] (order is preserved).
     pub(crate) async fn make_request(
         &mut self,
-        request: GetAddrInfoRequest,
+        request: GetAddrInfoRequestV2,
     ) -> Result<(), AgentError> {
         let (response_tx, response_rx) = oneshot::channel();
 
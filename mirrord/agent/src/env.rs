@@ -7,7 +7,10 @@ use mirrord_protocol::RemoteResult;
 use tokio::io::AsyncReadExt;
 use wildmatch::WildMatch;
 
-use crate::error::Result;
+use crate::{
+    error::Result,
+    runtime::{Container, ContainerInfo, ContainerRuntime},
+};
 
 struct EnvFilter {
     include: Vec<WildMatch>,
@@ -107,6 +110,38 @@ pub(crate) async fn get_proc_environ(path: PathBuf) -> Result<HashMap<String, St
     Ok(parse_raw_env(raw_env_vars.split_terminator(char::from(0))))
 }
 
+/// Splits an `--env-container` argument into the container id and the prefix.
+pub(crate) fn parse_env_container(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once('=') {
+        Some((container_id, prefix)) => (container_id, Some(prefix)),
+        None => (arg, None),
+    }
+}
+
+/// Loads the environment of another container in the target pod (from the container runtime and
+/// its process), with `prefix` added to the names.
+pub(crate) async fn container_env(
+    container: &Container,
+    prefix: Option<&str>,
+) -> Result<HashMap<String, String>> {
+    let ContainerInfo { pid, mut env } = container.get_info().await?;
+
+    let environ_path = PathBuf::from("/proc").join(pid.to_string()).join("environ");
+    env.extend(get_proc_environ(environ_path).await?);
+
+    Ok(with_prefix(env, prefix))
+}
+
+fn with_prefix(env: HashMap<String, String>, prefix: Option<&str>) -> HashMap<String, String> {
+    match prefix {
+        Some(prefix) => env
+            .into_iter()
+            .map(|(key, value)| (format!("{prefix}{key}"), value))
+            .collect(),
+        None => env,
+    }
+}
+
 /// Helper function that loads the process' environment variables, and selects only those that were
 /// requested from `mirrord-layer` (ignores vars specified in `filter_env_vars`).
 ///
@@ -158,6 +193,18 @@ mod tests {
         assert!(filter.matches("FOOBAR_TEST"));
     }
 
+    #[test]
+    fn env_container() {
+        assert_eq!(parse_env_container("abc123"), ("abc123", None));
+        assert_eq!(
+            parse_env_container("abc123=WORKER__"),
+            ("abc123", Some("WORKER__"))
+        );
+
+        let env = with_prefix(parse_raw_env(["PORT=80"]), Some("WORKER__"));
+        assert_eq!(env.get("WORKER__PORT").map(String::as_str), Some("80"));
+    }
+
     #[test]
     fn default_exclude() {
         let filter = EnvFilter::new(
@@ -0,0 +1,610 @@
+//! Runs processes in the target container, for `feature.remote_exec`.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::process::ExitStatusExt},
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+};
+
+use mirrord_protocol::{
+    exec::{DaemonExec, ExecData, ExecExit, ExecId, ExecStartRequest, LayerExec},
+    RemoteResult, ResponseError,
+};
+use nix::sched::{setns, CloneFlags};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{Child, ChildStdin, Command},
+    select,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+};
+use tracing::warn;
+
+use crate::namespace::{open_namespace, NamespaceType};
+
+/// Handles to a running process.
+struct ProcessHandle {
+    /// Sends data to the process' stdin, dropped to close it.
+    stdin: Option<Sender<Vec<u8>>>,
+    /// Kills the process, also when dropped.
+    kill: Option<oneshot::Sender<()>>,
+}
+
+/// Runs processes for one client (layer), and streams their output back.
+///
+/// The processes are killed when this struct is dropped.
+pub(crate) struct ExecApi {
+    /// Pid of the target container, we run the processes in its namespaces. [`None`] when
+    /// targetless.
+    pid: Option<u64>,
+    /// Environment of the target container, used for the processes.
+    env: Arc<HashMap<String, String>>,
+    /// Whether the agent was started with `--remote-exec`, we refuse to run processes otherwise.
+    enabled: bool,
+    processes: HashMap<ExecId, ProcessHandle>,
+    daemon_tx: Sender<DaemonExec>,
+    daemon_rx: Receiver<DaemonExec>,
+}
+
+impl ExecApi {
+    const CHANNEL_SIZE: usize = 512;
+
+    /// Size of the buffer for reading the processes' output.
+    const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+    pub(crate) fn new(pid: Option<u64>, env: Arc<HashMap<String, String>>, enabled: bool) -> Self {
+        let (daemon_tx, daemon_rx) = mpsc::channel(Self::CHANNEL_SIZE);
+
+        Self {
+            pid,
+            env,
+            enabled,
+            processes: Default::default(),
+            daemon_tx,
+            daemon_rx,
+        }
+    }
+
+    /// Whether clients may run processes, the agent doesn't report
+    /// [`Capabilities::REMOTE_EXEC`](mirrord_protocol::Capabilities::REMOTE_EXEC) otherwise.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Handles the [`LayerExec`] message.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) async fn layer_message(&mut self, message: LayerExec) {
+        match message {
+            LayerExec::Start(ExecStartRequest { id, argv }) => {
+                let result = self.start(id, argv);
+                // Our own receiver is alive, it's in `self`.
+                let _ = self
+                    .daemon_tx
+                    .send(DaemonExec::Started { id, result })
+                    .await;
+            }
+            LayerExec::Stdin(ExecData { id, bytes }) => {
+                let Some(stdin) = self
+                    .processes
+                    .get(&id)
+                    .and_then(|process| process.stdin.as_ref())
+                else {
+                    return;
+                };
+
+                // The process closed its stdin, nothing to do.
+                let _ = stdin.send(bytes).await;
+            }
+            LayerExec::CloseStdin(id) => {
+                if let Some(process) = self.processes.get_mut(&id) {
+                    process.stdin = None;
+                }
+            }
+            LayerExec::Kill(id) => {
+                if let Some(kill) = self
+                    .processes
+                    .get_mut(&id)
+                    .and_then(|process| process.kill.take())
+                {
+                    let _ = kill.send(());
+                }
+            }
+        }
+    }
+
+    /// Receives the next [`DaemonExec`] message about the running processes.
+    pub(crate) async fn daemon_message(&mut self) -> DaemonExec {
+        let message = self
+            .daemon_rx
+            .recv()
+            .await
+            .expect("the sender is held by this struct");
+
+        if let DaemonExec::Exited { id, .. } = &message {
+            self.processes.remove(id);
+        }
+
+        message
+    }
+
+    /// Spawns the process and its tasks.
+    fn start(&mut self, id: ExecId, argv: Vec<String>) -> RemoteResult<()> {
+        if !self.enabled {
+            return Err(ResponseError::NotImplemented);
+        }
+
+        let mut child = self.spawn(argv)?;
+
+        let (stdin_tx, stdin_rx) = mpsc::channel(Self::CHANNEL_SIZE);
+        let (kill_tx, kill_rx) = oneshot::channel();
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        tokio::spawn(write_stdin(stdin, stdin_rx));
+        tokio::spawn(run_process(id, child, kill_rx, self.daemon_tx.clone()));
+
+        self.processes.insert(
+            id,
+            ProcessHandle {
+                stdin: Some(stdin_tx),
+                kill: Some(kill_tx),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Spawns the process in the mount, network and pid namespaces of the target, in the working
+    /// directory of the target process, and with its user and capabilities (we run as root with
+    /// all of them).
+    fn spawn(&self, argv: Vec<String>) -> io::Result<Child> {
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .env_clear()
+            .envs(self.env.iter())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let Some(pid) = self.pid else {
+            return command.spawn();
+        };
+
+        // Opened here, the child can only make async-signal-safe calls before `exec`.
+        let namespaces = [NamespaceType::Mnt, NamespaceType::Net]
+            .into_iter()
+            .map(|namespace| open_namespace(pid, namespace))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io::Error::other)?;
+        let (pid_namespace, pid_flags) =
+            open_namespace(pid, NamespaceType::Pid).map_err(io::Error::other)?;
+        let cwd = File::open(format!("/proc/{pid}/cwd"))?;
+        let credentials = Credentials::from_pid(pid)?;
+
+        // SAFETY: only async-signal-safe calls in the closure.
+        unsafe {
+            command.pre_exec(move || enter_target(&namespaces, &cwd, &credentials));
+        }
+
+        // Entering a pid namespace only applies to the children of the calling thread, so we
+        // fork from a thread of our own.
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _runtime = runtime.enter();
+                    setns(pid_namespace.as_raw_fd(), pid_flags)?;
+                    command.spawn()
+                })
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("remote exec spawn thread panicked")))
+        })
+    }
+}
+
+/// Runs in the forked child, before `exec`.
+fn enter_target(
+    namespaces: &[(File, CloneFlags)],
+    cwd: &File,
+    credentials: &Credentials,
+) -> io::Result<()> {
+    for (namespace, flags) in namespaces {
+        setns(namespace.as_raw_fd(), *flags)?;
+    }
+
+    // Entering a mount namespace moves us to its root.
+    if unsafe { libc::fchdir(cwd.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    credentials.apply()
+}
+
+/// `prctl` with the unused arguments zeroed, some options fail with `EINVAL` otherwise.
+unsafe fn prctl(option: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong) -> libc::c_long {
+    libc::prctl(option, arg2, arg3, 0 as libc::c_ulong, 0 as libc::c_ulong).into()
+}
+
+/// `struct __user_cap_header_struct` from `linux/capability.h`.
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct` from `linux/capability.h`.
+#[repr(C)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// User, groups and capabilities of the target process, given to the processes we run.
+#[derive(Debug, PartialEq, Eq)]
+struct Credentials {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+    inheritable: u64,
+    permitted: u64,
+    effective: u64,
+    bounding: u64,
+    ambient: u64,
+}
+
+impl Credentials {
+    /// `_LINUX_CAPABILITY_VERSION_3`, 64 bit capability sets.
+    const CAPABILITY_VERSION: u32 = 0x20080522;
+
+    fn from_pid(pid: u64) -> io::Result<Self> {
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status"))?;
+
+        Self::parse(&status).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed /proc/{pid}/status"),
+            )
+        })
+    }
+
+    /// Parses the contents of `/proc/{pid}/status`.
+    fn parse(status: &str) -> Option<Self> {
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+        };
+        // Real, effective, saved and filesystem ids, we take the effective one.
+        let id =
+            |name: &str| -> Option<u32> { field(name)?.split_whitespace().nth(1)?.parse().ok() };
+        let capabilities = |name: &str| u64::from_str_radix(field(name)?, 16).ok();
+
+        Some(Self {
+            uid: id("Uid")?,
+            gid: id("Gid")?,
+            groups: field("Groups")?
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?,
+            inheritable: capabilities("CapInh")?,
+            permitted: capabilities("CapPrm")?,
+            effective: capabilities("CapEff")?,
+            bounding: capabilities("CapBnd")?,
+            // Missing before Linux 4.3.
+            ambient: capabilities("CapAmb").unwrap_or(0),
+        })
+    }
+
+    /// Runs in the forked child, after entering the namespaces. The order matters, changing the
+    /// groups and ids needs capabilities that we drop along the way.
+    fn apply(&self) -> io::Result<()> {
+        fn check(result: libc::c_long) -> io::Result<()> {
+            if result == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        let capabilities =
+            (0..64).map(|capability: libc::c_ulong| (capability, 1u64 << capability));
+
+        // SAFETY: plain syscalls, with pointers to data that outlives them.
+        unsafe {
+            for (capability, bit) in capabilities.clone() {
+                if self.bounding & bit == 0
+                    && prctl(libc::PR_CAPBSET_DROP, capability, 0) == -1
+                    // Capabilities the kernel doesn't know.
+                    && io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL)
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            check(libc::setgroups(self.groups.len(), self.groups.as_ptr()).into())?;
+            check(libc::setgid(self.gid).into())?;
+            // Keeps the permitted set over `setuid`, we set the target's sets right after.
+            check(prctl(libc::PR_SET_KEEPCAPS, 1, 0))?;
+            check(libc::setuid(self.uid).into())?;
+
+            let header = CapUserHeader {
+                version: Self::CAPABILITY_VERSION,
+                pid: 0,
+            };
+            let data = [0, 32].map(|shift| CapUserData {
+                effective: (self.effective >> shift) as u32,
+                permitted: (self.permitted >> shift) as u32,
+                inheritable: (self.inheritable >> shift) as u32,
+            });
+            check(libc::syscall(
+                libc::SYS_capset,
+                &header as *const CapUserHeader,
+                data.as_ptr(),
+            ))?;
+
+            for (capability, bit) in capabilities {
+                if self.ambient & bit != 0 {
+                    check(prctl(
+                        libc::PR_CAP_AMBIENT,
+                        libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                        capability,
+                    ))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn write_stdin(mut stdin: ChildStdin, mut rx: Receiver<Vec<u8>>) {
+    while let Some(bytes) = rx.recv().await {
+        if stdin.write_all(&bytes).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Streams the output of the process, and reports its exit.
+async fn run_process(
+    id: ExecId,
+    mut child: Child,
+    mut kill: oneshot::Receiver<()>,
+    daemon_tx: Sender<DaemonExec>,
+) {
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    let mut stdout_buf = vec![0; ExecApi::READ_BUFFER_SIZE];
+    let mut stderr_buf = vec![0; ExecApi::READ_BUFFER_SIZE];
+    let mut killed = false;
+
+    let status = loop {
+        select! {
+            bytes = read_output(&mut stdout, &mut stdout_buf), if stdout.is_some() => {
+                let message = bytes.map(|bytes| DaemonExec::Stdout(ExecData { id, bytes }));
+                if let Some(message) = message {
+                    if daemon_tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            },
+
+            bytes = read_output(&mut stderr, &mut stderr_buf), if stderr.is_some() => {
+                let message = bytes.map(|bytes| DaemonExec::Stderr(ExecData { id, bytes }));
+                if let Some(message) = message {
+                    if daemon_tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+            },
+
+            _ = &mut kill, if !killed => {
+                killed = true;
+                if let Err(error) = child.start_kill() {
+                    warn!(id, %error, "Failed to kill remote exec process");
+                }
+            },
+
+            status = child.wait(), if stdout.is_none() && stderr.is_none() => break status,
+        }
+    };
+
+    let exit = match status {
+        Ok(status) => exec_exit(status),
+        Err(error) => {
+            warn!(id, %error, "Failed to wait for remote exec process");
+            ExecExit::Code(-1)
+        }
+    };
+
+    let _ = daemon_tx.send(DaemonExec::Exited { id, exit }).await;
+}
+
+/// Reads from the output of the process, and closes it (sets to [`None`]) on EOF or error.
+async fn read_output<R: AsyncRead + Unpin>(
+    output: &mut Option<R>,
+    buf: &mut [u8],
+) -> Option<Vec<u8>> {
+    let read = output.as_mut()?.read(buf).await;
+
+    match read {
+        Ok(0) | Err(..) => {
+            *output = None;
+            None
+        }
+        Ok(read) => buf.get(..read).map(<[u8]>::to_vec),
+    }
+}
+
+fn exec_exit(status: ExitStatus) -> ExecExit {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => ExecExit::Code(code),
+        (None, Some(signal)) => ExecExit::Signal(signal),
+        (None, None) => ExecExit::Code(-1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exec_api() -> ExecApi {
+        let env = HashMap::from([("PATH".to_string(), "/usr/bin:/bin".to_string())]);
+        ExecApi::new(None, Arc::new(env), true)
+    }
+
+    /// Collects the output of the process until it exits.
+    async fn run_to_exit(api: &mut ExecApi, id: ExecId) -> (Vec<u8>, Vec<u8>, ExecExit) {
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+
+        loop {
+            match api.daemon_message().await {
+                DaemonExec::Started {
+                    id: started,
+                    result,
+                } => {
+                    assert_eq!(started, id);
+                    assert!(result.is_ok(), "{result:?}");
+                }
+                DaemonExec::Stdout(data) => stdout.extend(data.bytes),
+                DaemonExec::Stderr(data) => stderr.extend(data.bytes),
+                DaemonExec::Exited { id: exited, exit } => {
+                    assert_eq!(exited, id);
+                    break (stdout, stderr, exit);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_output_and_exit() {
+        let mut api = exec_api();
+        api.layer_message(LayerExec::Start(ExecStartRequest {
+            id: 1,
+            argv: vec![
+                "sh".into(),
+                "-c".into(),
+                "echo out; echo err >&2; exit 3".into(),
+            ],
+        }))
+        .await;
+
+        let (stdout, stderr, exit) = run_to_exit(&mut api, 1).await;
+        assert_eq!(stdout, b"out\n");
+        assert_eq!(stderr, b"err\n");
+        assert_eq!(exit, ExecExit::Code(3));
+        assert!(api.processes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forwards_stdin() {
+        let mut api = exec_api();
+        api.layer_message(LayerExec::Start(ExecStartRequest {
+            id: 1,
+            argv: vec!["cat".into()],
+        }))
+        .await;
+        api.layer_message(LayerExec::Stdin(ExecData {
+            id: 1,
+            bytes: b"hello".to_vec(),
+        }))
+        .await;
+        api.layer_message(LayerExec::CloseStdin(1)).await;
+
+        let (stdout, _, exit) = run_to_exit(&mut api, 1).await;
+        assert_eq!(stdout, b"hello");
+        assert_eq!(exit, ExecExit::Code(0));
+    }
+
+    #[tokio::test]
+    async fn kills() {
+        let mut api = exec_api();
+        api.layer_message(LayerExec::Start(ExecStartRequest {
+            id: 1,
+            argv: vec!["sleep".into(), "60".into()],
+        }))
+        .await;
+        api.layer_message(LayerExec::Kill(1)).await;
+
+        let (_, _, exit) = run_to_exit(&mut api, 1).await;
+        assert_eq!(exit, ExecExit::Signal(libc::SIGKILL));
+    }
+
+    #[tokio::test]
+    async fn fails_to_start() {
+        let mut api = exec_api();
+        api.layer_message(LayerExec::Start(ExecStartRequest {
+            id: 1,
+            argv: vec![],
+        }))
+        .await;
+
+        assert!(matches!(
+            api.daemon_message().await,
+            DaemonExec::Started {
+                id: 1,
+                result: Err(..)
+            }
+        ));
+        assert!(api.processes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let mut api = ExecApi::new(None, Default::default(), false);
+        api.layer_message(LayerExec::Start(ExecStartRequest {
+            id: 1,
+            argv: vec!["true".into()],
+        }))
+        .await;
+
+        assert!(matches!(
+            api.daemon_message().await,
+            DaemonExec::Started {
+                id: 1,
+                result: Err(ResponseError::NotImplemented)
+            }
+        ));
+        assert!(api.processes.is_empty());
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let status = "Name:\tpostgres\n\
+                      Uid:\t999\t70\t999\t999\n\
+                      Gid:\t998\t71\t998\t998\n\
+                      Groups:\t4 998 \n\
+                      CapInh:\t0000000000000000\n\
+                      CapPrm:\t00000000a80425fb\n\
+                      CapEff:\t00000000a80425fb\n\
+                      CapBnd:\t00000000a80425fb\n\
+                      CapAmb:\t0000000000000400\n";
+
+        assert_eq!(
+            Credentials::parse(status),
+            Some(Credentials {
+                uid: 70,
+                gid: 71,
+                groups: vec![4, 998],
+                inheritable: 0,
+                permitted: 0xa80425fb,
+                effective: 0xa80425fb,
+                bounding: 0xa80425fb,
+                ambient: 0x400,
+            })
+        );
+        assert_eq!(Credentials::parse("Name:\tpostgres\n"), None);
+    }
+}
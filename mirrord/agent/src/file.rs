@@ -1,12 +1,15 @@
 use std::{
     self,
     collections::{hash_map::Entry, HashMap},
-    fs::{DirEntry, File, OpenOptions, ReadDir},
+    fs::{self, DirEntry, File, OpenOptions, ReadDir},
     io,
     io::{prelude::*, BufReader, SeekFrom},
     iter::{Enumerate, Map, Peekable},
-    os::unix::{fs::MetadataExt, prelude::FileExt},
-    path::{Path, PathBuf},
+    os::unix::{
+        fs::MetadataExt,
+        prelude::{AsRawFd, FileExt},
+    },
+    path::{Component, Path, PathBuf},
     vec::IntoIter,
 };
 
@@ -14,16 +17,23 @@ use faccess::{AccessMode, PathExt};
 use libc::DT_DIR;
 use mirrord_protocol::{
     file::{
-        AccessFileRequest, AccessFileResponse, CloseDirRequest, CloseFileRequest, DirEntryInternal,
-        FdOpenDirRequest, GetDEnts64Request, GetDEnts64Response, OpenDirResponse, OpenFileRequest,
-        OpenFileResponse, OpenOptionsInternal, OpenRelativeFileRequest, ReadDirRequest,
-        ReadDirResponse, ReadFileRequest, ReadFileResponse, ReadLimitedFileRequest,
-        SeekFileRequest, SeekFileResponse, WriteFileRequest, WriteFileResponse,
+        AccessFileRequest, AccessFileResponse, BatchFileRequest, BatchFileResponse,
+        ChecksumFileRequest, ChecksumFileResponse, CloseDirRequest, CloseFileRequest,
+        DirEntryInternal, FdOpenDirRequest, FileChecksum, FileStreamEnd, GetDEnts64Chunk,
+        GetDEnts64Request, GetDEnts64Response, GetDEnts64StreamRequest, OpenDirResponse,
+        OpenFileRequest, OpenFileResponse, OpenOptionsInternal, OpenRelativeFileRequest,
+        ReadChecksummedFileRequest, ReadChecksummedFileResponse, ReadDirBatchRequest,
+        ReadDirBatchResponse, ReadDirRequest, ReadDirResponse, ReadFileChunk, ReadFileRequest,
+        ReadFileResponse, ReadFileStreamRequest, ReadLimitedFileRequest, ScratchDirRequest,
+        ScratchDirResponse, SeekFileRequest, SeekFileResponse, WriteFileRequest, WriteFileResponse,
         WriteLimitedFileRequest, XstatFsRequest, XstatFsResponse, XstatRequest, XstatResponse,
     },
     FileRequest, FileResponse, RemoteResult, ResponseError,
 };
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use rand::distributions::{Alphanumeric, DistString};
 use tracing::{error, trace};
+use wildmatch::WildMatch;
 
 use crate::{error::Result, util::IndexAllocator};
 
@@ -52,6 +62,91 @@ type GetDEnts64Stream = Peekable<
     >,
 >;
 
+/// What is streamed with a [`FileStream`].
+#[derive(Debug)]
+enum FileStreamKind {
+    /// Bytes of a file, from `start_from` or from the current position.
+    Read { fd: u64, start_from: Option<u64> },
+    /// Entries of a directory.
+    GetDEnts64 { fd: u64 },
+}
+
+/// Response to a [`FileRequest::ReadStream`] or a [`FileRequest::GetDEnts64Stream`], that is
+/// sent in chunks produced with [`FileManager::next_chunk`].
+#[derive(Debug)]
+pub(crate) struct FileStream {
+    kind: FileStreamKind,
+    /// Bytes that are left of the `buffer_size` of the request.
+    remaining: u64,
+    chunk_size: u64,
+    /// Bytes of the file that were already read, for [`FileStreamKind::Read`].
+    streamed: u64,
+    /// Sequence number of the next chunk, which is also the number of chunks sent so far.
+    sequence: u64,
+    done: bool,
+}
+
+impl FileStream {
+    /// Starts the stream for `request`, or gives it back when it's not a streaming request.
+    pub(crate) fn from_request(request: FileRequest) -> std::result::Result<Self, FileRequest> {
+        let (kind, buffer_size, chunk_size) = match request {
+            FileRequest::ReadStream(ReadFileStreamRequest {
+                remote_fd,
+                buffer_size,
+                start_from,
+                chunk_size,
+            }) => (
+                FileStreamKind::Read {
+                    fd: remote_fd,
+                    start_from,
+                },
+                buffer_size,
+                chunk_size,
+            ),
+            FileRequest::GetDEnts64Stream(GetDEnts64StreamRequest {
+                remote_fd,
+                buffer_size,
+                chunk_size,
+            }) => (
+                FileStreamKind::GetDEnts64 { fd: remote_fd },
+                buffer_size,
+                chunk_size,
+            ),
+            other => return Err(other),
+        };
+
+        Ok(Self {
+            kind,
+            remaining: buffer_size,
+            // A chunk of 0 bytes would never make progress.
+            chunk_size: if chunk_size == 0 {
+                buffer_size
+            } else {
+                chunk_size
+            },
+            streamed: 0,
+            sequence: 0,
+            done: false,
+        })
+    }
+
+    /// Ends the stream early, the next chunk produced is the end of the stream.
+    ///
+    /// The client gave up on the whole response, so for reads from the current position of the
+    /// file, returns the fd and where to move it back to where the stream started.
+    pub(crate) fn cancel(&mut self) -> Option<(u64, SeekFrom)> {
+        self.remaining = 0;
+
+        match self.kind {
+            FileStreamKind::Read {
+                fd,
+                start_from: None,
+            } if self.streamed > 0 => Some((fd, SeekFrom::Current(-(self.streamed as i64)))),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct FileManager {
     root_path: PathBuf,
@@ -59,6 +154,9 @@ pub(crate) struct FileManager {
     dir_streams: HashMap<u64, Enumerate<ReadDir>>,
     getdents_streams: HashMap<u64, GetDEnts64Stream>,
     index_allocator: IndexAllocator<u64, 100>,
+    /// Scratch directory of the client in the target, created with the first
+    /// [`ScratchDirRequest`], and removed when the client disconnects.
+    scratch_dir: Option<PathBuf>,
 }
 
 pub fn get_root_path_from_optional_pid(pid: Option<u64>) -> PathBuf {
@@ -68,6 +166,19 @@ pub fn get_root_path_from_optional_pid(pid: Option<u64>) -> PathBuf {
     }
 }
 
+/// Whether the `file` was opened with `O_APPEND`, so the kernel writes it at its end, whatever its
+/// offset.
+fn is_append(file: &File) -> bool {
+    fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)
+        .is_ok_and(|flags| OFlag::from_bits_truncate(flags).contains(OFlag::O_APPEND))
+}
+
+/// Whether the `file` was opened with `O_WRONLY`, so it can't be read through its descriptor.
+fn is_write_only(file: &File) -> bool {
+    fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)
+        .is_ok_and(|flags| OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE == OFlag::O_WRONLY)
+}
+
 /// Resolve a path that might contain symlinks from a specific container to a path accessible from
 /// the root host
 #[tracing::instrument(level = "trace")]
@@ -219,17 +330,194 @@ impl FileManager {
             }) => Some(FileResponse::GetDEnts64(
                 self.getdents64(remote_fd, buffer_size),
             )),
+            FileRequest::ReadDirBatch(ReadDirBatchRequest {
+                remote_fd,
+                amount,
+                name_filter,
+            }) => Some(FileResponse::ReadDirBatch(self.read_dir_batch(
+                remote_fd,
+                amount,
+                name_filter,
+            ))),
+            FileRequest::Checksum(ChecksumFileRequest {
+                remote_fd,
+                start_from,
+                length,
+            }) => Some(FileResponse::Checksum(
+                self.checksum(remote_fd, start_from, length),
+            )),
+            FileRequest::ReadStream(..) | FileRequest::GetDEnts64Stream(..) => {
+                error!("streaming file request should be handled with `FileManager::next_chunk`");
+                None
+            }
+            FileRequest::Batch(BatchFileRequest { requests }) => {
+                Some(FileResponse::Batch(self.batch(requests)))
+            }
+            FileRequest::ReadChecksummed(ReadChecksummedFileRequest {
+                remote_fd,
+                buffer_size,
+                start_from,
+            }) => {
+                let read_result = match start_from {
+                    Some(start_from) => self.read_limited(remote_fd, buffer_size, start_from),
+                    None => self.read(remote_fd, buffer_size),
+                };
+                let response = read_result.map(|ReadFileResponse { bytes, read_amount }| {
+                    ReadChecksummedFileResponse {
+                        checksum: FileChecksum::of(&bytes),
+                        bytes,
+                        read_amount,
+                    }
+                });
+                Some(FileResponse::ReadChecksummed(response))
+            }
+            FileRequest::ScratchDir(ScratchDirRequest { path }) => {
+                Some(FileResponse::ScratchDir(self.scratch_dir(path)))
+            }
         })
     }
 
+    /// Handles the requests of a [`BatchFileRequest`] in order.
+    ///
+    /// Fails without handling any of them when one can't be batched. Otherwise each request gets
+    /// its own response, a request that fails doesn't stop the ones after it, as the ones before
+    /// it may have changed the files already.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) fn batch(&mut self, requests: Vec<FileRequest>) -> RemoteResult<BatchFileResponse> {
+        if !requests.iter().all(BatchFileRequest::can_batch) {
+            return Err(ResponseError::NotImplemented);
+        }
+
+        let responses = requests
+            .into_iter()
+            .map(|request| self.handle_batched(request))
+            .collect();
+
+        Ok(BatchFileResponse { responses })
+    }
+
+    /// Handles a single request of a [`BatchFileRequest`], its failure goes in its response.
+    fn handle_batched(&mut self, request: FileRequest) -> FileResponse {
+        match request {
+            FileRequest::Read(ReadFileRequest {
+                remote_fd,
+                buffer_size,
+            }) => FileResponse::Read(self.read(remote_fd, buffer_size)),
+            FileRequest::ReadLimited(ReadLimitedFileRequest {
+                remote_fd,
+                buffer_size,
+                start_from,
+            }) => FileResponse::ReadLimited(self.read_limited(remote_fd, buffer_size, start_from)),
+            FileRequest::Write(WriteFileRequest { fd, write_bytes }) => {
+                FileResponse::Write(self.write(fd, write_bytes))
+            }
+            FileRequest::WriteLimited(WriteLimitedFileRequest {
+                remote_fd,
+                start_from,
+                write_bytes,
+            }) => {
+                FileResponse::WriteLimited(self.write_limited(remote_fd, start_from, write_bytes))
+            }
+            FileRequest::Seek(SeekFileRequest { fd, seek_from }) => {
+                FileResponse::Seek(self.seek(fd, seek_from.into()))
+            }
+            // Not sent in batches, see `BatchFileRequest::can_batch`.
+            _ => FileResponse::Batch(Err(ResponseError::NotImplemented)),
+        }
+    }
+
+    /// Produces the next response of the `stream`, returns [`None`] after the stream was ended
+    /// (with a [`FileResponse::StreamEnd`] or an error chunk).
+    ///
+    /// The stream ends when `buffer_size` bytes were streamed, when a read comes out shorter than
+    /// asked (e.g. the end of the file was reached), or when the next directory entry doesn't fit,
+    /// so the whole stream behaves like a single `read`/`getdents64` of `buffer_size` bytes.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) fn next_chunk(&mut self, stream: &mut FileStream) -> Option<FileResponse> {
+        if stream.done {
+            return None;
+        }
+
+        let size = stream.chunk_size.min(stream.remaining);
+        let sequence = stream.sequence;
+
+        let (response, streamed, last) = match stream.kind {
+            _ if size == 0 => (None, 0, true),
+            FileStreamKind::Read { fd, start_from } => {
+                let result = match start_from {
+                    Some(start_from) => self.read_limited(fd, size, start_from + stream.streamed),
+                    None => self.read(fd, size),
+                };
+
+                match result {
+                    Ok(ReadFileResponse { read_amount: 0, .. }) => (None, 0, true),
+                    Ok(ReadFileResponse { bytes, read_amount }) => (
+                        Some(FileResponse::ReadChunk(Ok(ReadFileChunk {
+                            sequence,
+                            bytes,
+                        }))),
+                        read_amount,
+                        read_amount < size,
+                    ),
+                    Err(error) => (Some(FileResponse::ReadChunk(Err(error))), 0, true),
+                }
+            }
+            FileStreamKind::GetDEnts64 { fd } => match self.getdents64(fd, size) {
+                Ok(GetDEnts64Response { entries, .. }) if entries.is_empty() => (None, 0, true),
+                Ok(GetDEnts64Response {
+                    fd,
+                    entries,
+                    result_size,
+                }) => (
+                    Some(FileResponse::GetDEnts64Chunk(Ok(GetDEnts64Chunk {
+                        sequence,
+                        fd,
+                        entries,
+                        result_size,
+                    }))),
+                    result_size,
+                    false,
+                ),
+                Err(error) => (Some(FileResponse::GetDEnts64Chunk(Err(error))), 0, true),
+            },
+        };
+
+        match response {
+            Some(FileResponse::ReadChunk(Err(..)) | FileResponse::GetDEnts64Chunk(Err(..))) => {
+                stream.done = true;
+                response
+            }
+            Some(response) => {
+                stream.sequence += 1;
+                stream.streamed += streamed;
+                stream.remaining = if last {
+                    0
+                } else {
+                    stream.remaining.saturating_sub(streamed)
+                };
+
+                Some(response)
+            }
+            None => {
+                stream.done = true;
+                Some(FileResponse::StreamEnd(FileStreamEnd {
+                    chunks: stream.sequence,
+                }))
+            }
+        }
+    }
+
     #[tracing::instrument(level = "trace")]
     pub fn new(pid: Option<u64>) -> Self {
         let root_path = get_root_path_from_optional_pid(pid);
         trace!("Agent root path >> {root_path:?}");
         Self {
-            open_files: HashMap::new(),
             root_path,
-            ..Default::default()
+            open_files: HashMap::new(),
+            dir_streams: HashMap::new(),
+            getdents_streams: HashMap::new(),
+            index_allocator: IndexAllocator::default(),
+            scratch_dir: None,
         }
     }
 
@@ -401,6 +689,9 @@ impl FileManager {
             })
     }
 
+    /// Writes at `start_from`, except for files opened with `O_APPEND`, which are always written
+    /// at their end (like `pwrite` on Linux), so that concurrent appenders (e.g. loggers) never
+    /// overwrite each other's data.
     #[tracing::instrument(level = "trace", skip(self))]
     pub(crate) fn write_limited(
         &mut self,
@@ -413,12 +704,14 @@ impl FileManager {
             .ok_or(ResponseError::NotFound(fd))
             .and_then(|remote_file| {
                 if let RemoteFile::File(file) = remote_file {
-                    let written_amount =
-                        file.write_at(&buffer, start_from).map(|written_amount| {
-                            WriteFileResponse {
-                                written_amount: written_amount as u64,
-                            }
-                        })?;
+                    let written_amount = if is_append(file) {
+                        file.write(&buffer)
+                    } else {
+                        file.write_at(&buffer, start_from)
+                    }
+                    .map(|written_amount| WriteFileResponse {
+                        written_amount: written_amount as u64,
+                    })?;
 
                     Ok(written_amount)
                 } else {
@@ -427,6 +720,91 @@ impl FileManager {
             })
     }
 
+    /// Computes the [`FileChecksum`] of up to `length` bytes, starting at `start_from`, stopping
+    /// early at the end of the file.
+    ///
+    /// Files opened write-only are read through `/proc/self/fd`, so that the writes to them can
+    /// be verified too.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) fn checksum(
+        &mut self,
+        fd: u64,
+        start_from: u64,
+        length: u64,
+    ) -> RemoteResult<ChecksumFileResponse> {
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        self.open_files
+            .get(&fd)
+            .ok_or(ResponseError::NotFound(fd))
+            .and_then(|remote_file| {
+                if let RemoteFile::File(file) = remote_file {
+                    let reopened;
+                    let file = if is_write_only(file) {
+                        reopened = File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+                        &reopened
+                    } else {
+                        file
+                    };
+
+                    let mut checksum = FileChecksum::default();
+                    let mut buffer = vec![0; CHUNK_SIZE];
+                    let mut checked = 0;
+
+                    while checked < length {
+                        let chunk = CHUNK_SIZE.min((length - checked) as usize);
+                        let read_amount =
+                            file.read_at(&mut buffer[..chunk], start_from + checked)?;
+                        if read_amount == 0 {
+                            break;
+                        }
+
+                        checksum.update(&buffer[..read_amount]);
+                        checked += read_amount as u64;
+                    }
+
+                    Ok(ChecksumFileResponse {
+                        checksum: checksum.value(),
+                        length: checked,
+                    })
+                } else {
+                    Err(ResponseError::NotFile(fd))
+                }
+            })
+    }
+
+    /// Creates the directory `path` in the scratch directory of the client (see
+    /// [`ScratchDirRequest`]), creating the scratch directory itself in the target's `/tmp` first.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) fn scratch_dir(&mut self, path: PathBuf) -> RemoteResult<ScratchDirResponse> {
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(..)))
+        {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scratch directory path must be relative, without `..`",
+            ))?
+        }
+
+        let scratch_dir = match &self.scratch_dir {
+            Some(scratch_dir) => scratch_dir.clone(),
+            None => {
+                let scratch_dir = PathBuf::from(format!(
+                    "/tmp/mirrord-scratch-{}",
+                    Alphanumeric.sample_string(&mut rand::thread_rng(), 10)
+                ));
+                fs::create_dir(resolve_path(&scratch_dir, &self.root_path)?)?;
+                self.scratch_dir.insert(scratch_dir).clone()
+            }
+        };
+
+        let path = scratch_dir.join(path);
+        fs::create_dir_all(resolve_path(&path, &self.root_path)?)?;
+
+        Ok(ScratchDirResponse { path })
+    }
+
     pub(crate) fn seek(&mut self, fd: u64, seek_from: SeekFrom) -> RemoteResult<SeekFileResponse> {
         trace!(
             "FileManager::seek -> fd {:#?} | seek_from {:#?}",
@@ -710,6 +1088,39 @@ impl FileManager {
         Ok(result)
     }
 
+    /// Reads up to `amount` entries from the dir stream, skipping the ones with names that don't
+    /// match the `name_filter` glob.
+    ///
+    /// Shares the stream with [`FileManager::read_dir`], so consecutive calls continue where the
+    /// last one stopped.
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) fn read_dir_batch(
+        &mut self,
+        fd: u64,
+        amount: usize,
+        name_filter: Option<String>,
+    ) -> RemoteResult<ReadDirBatchResponse> {
+        let name_filter = name_filter.as_deref().map(WildMatch::new);
+        let dir_stream = self.get_dir_stream(fd)?;
+
+        let mut dir_entries = Vec::with_capacity(amount.min(256));
+        while dir_entries.len() < amount {
+            let Some(offset_entry_pair) = dir_stream.next() else {
+                break;
+            };
+
+            let entry: DirEntryInternal = offset_entry_pair.try_into()?;
+            if name_filter
+                .as_ref()
+                .map_or(true, |filter| filter.matches(&entry.name))
+            {
+                dir_entries.push(entry);
+            }
+        }
+
+        Ok(ReadDirBatchResponse { fd, dir_entries })
+    }
+
     /// The getdents64 syscall writes dir entries to a buffer, as long as they fit.
     /// If a call did not process all the entries in a dir, the result of the next call continues
     /// where the last one stopped.
@@ -767,3 +1178,231 @@ impl FileManager {
         }
     }
 }
+
+impl Drop for FileManager {
+    /// Removes the scratch directory of the client, see [`FileManager::scratch_dir`].
+    fn drop(&mut self) {
+        let Some(scratch_dir) = self.scratch_dir.take() else {
+            return;
+        };
+
+        let removed = resolve_path(&scratch_dir, &self.root_path).and_then(fs::remove_dir_all);
+        if let Err(error) = removed {
+            error!(
+                ?error,
+                ?scratch_dir,
+                "Failed to remove the scratch directory!"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn append_options() -> OpenOptionsInternal {
+        OpenOptionsInternal {
+            append: true,
+            create: true,
+            ..Default::default()
+        }
+    }
+
+    /// Two appenders (and a `pwrite` at a stale offset) never overwrite each other.
+    #[test]
+    fn concurrent_appenders() {
+        let path = std::env::temp_dir().join(format!("mirrord-append-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut manager = FileManager::new(None);
+        let OpenFileResponse { fd: first } = manager.open(path.clone(), append_options()).unwrap();
+        let OpenFileResponse { fd: second } = manager.open(path.clone(), append_options()).unwrap();
+
+        manager.write(first, b"first 1\n".to_vec()).unwrap();
+        manager.write(second, b"second 1\n".to_vec()).unwrap();
+        manager.seek(first, SeekFrom::Start(0)).unwrap();
+        manager.write(first, b"first 2\n".to_vec()).unwrap();
+        manager
+            .write_limited(second, 0, b"second 2\n".to_vec())
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "first 1\nsecond 1\nfirst 2\nsecond 2\n");
+    }
+
+    /// Writes to a file opened write-only are verified like any other.
+    #[test]
+    fn checksum_write_only() {
+        let path = std::env::temp_dir().join(format!("mirrord-checksum-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut manager = FileManager::new(None);
+        let OpenFileResponse { fd } = manager
+            .open(
+                path.clone(),
+                OpenOptionsInternal {
+                    write: true,
+                    create: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        manager.write_limited(fd, 6, b"chunk 2".to_vec()).unwrap();
+        manager.write_limited(fd, 0, b"chunk ".to_vec()).unwrap();
+
+        let response = manager.checksum(fd, 6, 1024);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            response.unwrap(),
+            ChecksumFileResponse {
+                checksum: FileChecksum::of(b"chunk 2"),
+                length: 7,
+            }
+        );
+    }
+
+    /// The requests of a batch are handled in order, and one that fails doesn't stop the others.
+    #[test]
+    fn batch_in_order() {
+        let path = std::env::temp_dir().join(format!("mirrord-batch-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut manager = FileManager::new(None);
+        let OpenFileResponse { fd } = manager
+            .open(
+                path.clone(),
+                OpenOptionsInternal {
+                    read: true,
+                    write: true,
+                    create: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let BatchFileResponse { responses } = manager
+            .batch(vec![
+                FileRequest::Write(WriteFileRequest {
+                    fd,
+                    write_bytes: b"first ".to_vec(),
+                }),
+                FileRequest::Write(WriteFileRequest {
+                    fd: fd + 1,
+                    write_bytes: b"lost ".to_vec(),
+                }),
+                FileRequest::Write(WriteFileRequest {
+                    fd,
+                    write_bytes: b"second".to_vec(),
+                }),
+                FileRequest::ReadLimited(ReadLimitedFileRequest {
+                    remote_fd: fd,
+                    buffer_size: 64,
+                    start_from: 0,
+                }),
+            ])
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            matches!(
+                responses.as_slice(),
+                [
+                    FileResponse::Write(Ok(..)),
+                    FileResponse::Write(Err(..)),
+                    FileResponse::Write(Ok(..)),
+                    FileResponse::ReadLimited(Ok(ReadFileResponse { bytes, .. })),
+                ] if bytes == b"first second"
+            ),
+            "{responses:?}"
+        );
+    }
+
+    /// A batch with a request that can't be batched isn't handled at all.
+    #[test]
+    fn batch_not_batchable() {
+        let path = std::env::temp_dir().join(format!("mirrord-unbatched-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut manager = FileManager::new(None);
+        let OpenFileResponse { fd } = manager
+            .open(
+                path.clone(),
+                OpenOptionsInternal {
+                    write: true,
+                    create: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let response = manager.batch(vec![
+            FileRequest::Write(WriteFileRequest {
+                fd,
+                write_bytes: b"never".to_vec(),
+            }),
+            FileRequest::Close(CloseFileRequest { fd }),
+        ]);
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(response, Err(ResponseError::NotImplemented));
+        assert!(contents.is_empty());
+    }
+
+    /// A cancelled read stream moves the file back to where it started, the client got none of
+    /// it.
+    #[test]
+    fn cancelled_stream_moves_back() {
+        let path = std::env::temp_dir().join(format!("mirrord-stream-{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let mut manager = FileManager::new(None);
+        let OpenFileResponse { fd } = manager
+            .open(
+                path.clone(),
+                OpenOptionsInternal {
+                    read: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut stream = FileStream::from_request(FileRequest::ReadStream(ReadFileStreamRequest {
+            remote_fd: fd,
+            buffer_size: 11,
+            start_from: None,
+            chunk_size: 5,
+        }))
+        .unwrap();
+        assert!(matches!(
+            manager.next_chunk(&mut stream),
+            Some(FileResponse::ReadChunk(Ok(..)))
+        ));
+
+        let (fd, seek_from) = stream.cancel().unwrap();
+        manager.seek(fd, seek_from).unwrap();
+        let read = manager.read(fd, 11);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap().bytes, b"hello world");
+    }
+
+    /// Directories are created in the scratch directory, which is removed with the
+    /// [`FileManager`].
+    #[test]
+    fn scratch_dir() {
+        let mut manager = FileManager::new(None);
+        let ScratchDirResponse { path: scratch_dir } = manager.scratch_dir(PathBuf::new()).unwrap();
+        let ScratchDirResponse { path: nested } =
+            manager.scratch_dir(PathBuf::from("build/out")).unwrap();
+
+        assert_eq!(nested, scratch_dir.join("build/out"));
+        assert!(nested.is_dir());
+        assert!(manager.scratch_dir(PathBuf::from("../escape")).is_err());
+
+        drop(manager);
+        assert!(!scratch_dir.exists());
+    }
+}
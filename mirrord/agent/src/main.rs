@@ -6,9 +6,9 @@
 #![warn(clippy::indexing_slicing)]
 
 use std::{
-    collections::HashMap,
-    mem,
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::{HashMap, VecDeque},
+    future, mem,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::PathBuf,
     sync::{
         atomic::{AtomicU32, Ordering},
@@ -20,7 +20,10 @@ use client_connection::AgentTlsConnector;
 use dns::{DnsCommand, DnsWorker};
 use futures::TryFutureExt;
 use mirrord_protocol::{
-    pause::DaemonPauseTarget, ClientMessage, DaemonMessage, GetEnvVarsRequest, LogMessage,
+    capabilities::Capabilities,
+    pause::DaemonPauseTarget,
+    priority::{FairShare, Prioritized},
+    CancelRequest, ClientMessage, DaemonMessage, FileRequest, GetEnvVarsRequest, LogMessage,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -39,7 +42,8 @@ use crate::{
     container_handle::ContainerHandle,
     dns::DnsApi,
     error::{AgentError, Result},
-    file::FileManager,
+    exec::ExecApi,
+    file::{FileManager, FileStream},
     outgoing::{TcpOutgoingApi, UdpOutgoingApi},
     runtime::get_container,
     sniffer::{SnifferCommand, TcpConnectionSniffer, TcpSnifferApi},
@@ -54,6 +58,7 @@ use crate::{
     watched_task::{TaskStatus, WatchedTask},
 };
 
+mod bridge;
 mod cgroup;
 mod cli;
 mod client_connection;
@@ -61,6 +66,7 @@ mod container_handle;
 mod dns;
 mod env;
 mod error;
+mod exec;
 mod file;
 mod http;
 mod namespace;
@@ -88,6 +94,8 @@ struct State {
     container: Option<ContainerHandle>,
     env: Arc<HashMap<String, String>>,
     ephemeral: bool,
+    /// Whether clients may run processes in the target, see [`ExecApi`].
+    remote_exec: bool,
     /// When present, it is used to secure incoming TCP connections.
     tls_connector: Option<AgentTlsConnector>,
 }
@@ -103,16 +111,38 @@ impl State {
 
         let mut env: HashMap<String, String> = HashMap::new();
 
+        let pause_timeout =
+            (args.pause_timeout > 0).then(|| Duration::from_secs(args.pause_timeout));
+
         let (ephemeral, container, pid) = match &args.mode {
             cli::Mode::Targeted {
                 container_id,
                 container_runtime,
+                env_containers,
                 ..
             } => {
+                // Loaded first, so the target container's environment overrides them.
+                for arg in env_containers {
+                    let (id, prefix) = env::parse_env_container(arg);
+                    let result = match get_container(id.to_string(), Some(container_runtime)).await
+                    {
+                        Ok(container) => env::container_env(&container, prefix).await,
+                        Err(err) => Err(err),
+                    };
+
+                    match result {
+                        Ok(container_env) => env.extend(container_env),
+                        Err(err) => {
+                            error!("Failed to get environment variables of container {id}: {err:?}")
+                        }
+                    }
+                }
+
                 let container =
                     get_container(container_id.clone(), Some(container_runtime)).await?;
 
-                let container_handle = ContainerHandle::new(container, watch).await?;
+                let container_handle =
+                    ContainerHandle::new(container, watch, pause_timeout).await?;
                 let pid = container_handle.pid().to_string();
 
                 env.extend(container_handle.raw_env().clone());
@@ -123,6 +153,7 @@ impl State {
                 let container_handle = ContainerHandle::new(
                     runtime::Container::Ephemeral(runtime::EphemeralContainer {}),
                     watch,
+                    pause_timeout,
                 )
                 .await?;
 
@@ -132,7 +163,10 @@ impl State {
                 // If we are in an ephemeral container, we use pid 1.
                 (true, Some(container_handle), pid)
             }
-            cli::Mode::Targetless | cli::Mode::BlackboxTest => (false, None, "self".to_string()),
+            cli::Mode::Targetless
+            | cli::Mode::Sidecar { .. }
+            | cli::Mode::BlackboxTest
+            | cli::Mode::Bridge => (false, None, "self".to_string()),
         };
 
         let environ_path = PathBuf::from("/proc").join(pid).join("environ");
@@ -149,6 +183,7 @@ impl State {
             container,
             env: Arc::new(env),
             ephemeral,
+            remote_exec: args.remote_exec,
             tls_connector,
         })
     }
@@ -217,17 +252,61 @@ struct BackgroundTasks {
     dns: BackgroundTask<DnsCommand>,
 }
 
+/// A [`FileRequest`] waiting for [`ClientConnectionHandler::file_stream`] to end.
+enum PendingFileRequest {
+    /// A streamed request, with its number (see [`CancelRequest::FileStream`]).
+    Stream(u64, FileStream),
+    Other(FileRequest),
+}
+
 struct ClientConnectionHandler {
     id: ClientId,
     /// Handles mirrord's file operations, see [`FileManager`].
     file_manager: FileManager,
+    /// Stream being sent in chunks, with its number (see [`CancelRequest::FileStream`]).
+    ///
+    /// The chunks are sent between the other messages, so that the stream can be cancelled.
+    file_stream: Option<(u64, FileStream)>,
+    /// File requests received while [`Self::file_stream`] is sent, they're handled after it ends
+    /// so that the responses keep their order.
+    file_requests: VecDeque<PendingFileRequest>,
+    /// Number of streamed file requests received, see [`CancelRequest::FileStream`].
+    file_streams_received: u64,
+    /// Gives [`Self::file_stream`] its share of the connection when traffic keeps coming.
+    fair_share: FairShare,
     connection: ClientConnection,
     tcp_sniffer_api: Option<TcpSnifferApi>,
     tcp_stealer_api: Option<TcpStealerApi>,
     tcp_outgoing_api: TcpOutgoingApi,
     udp_outgoing_api: UdpOutgoingApi,
     dns_api: DnsApi,
+    /// Runs processes for `feature.remote_exec`.
+    exec_api: ExecApi,
     state: State,
+    /// Whether this client asked to pause the target container, and did not unpause it.
+    paused_target: bool,
+}
+
+/// This is to make sure a client that disconnects doesn't leave the target container paused.
+impl Drop for ClientConnectionHandler {
+    fn drop(&mut self) {
+        if !self.paused_target {
+            return;
+        }
+
+        if let Some(container) = self.state.container.clone() {
+            let id = self.id;
+            tokio::spawn(async move {
+                info!(
+                    id,
+                    "Client disconnected with target container paused. Unpausing target container."
+                );
+                if let Err(err) = container.set_paused(false).await {
+                    error!(id, "Could not unpause target container, got error: {err:?}");
+                }
+            });
+        }
+    }
 }
 
 impl ClientConnectionHandler {
@@ -241,9 +320,18 @@ impl ClientConnectionHandler {
     ) -> Result<Self> {
         let pid = state.container_pid();
 
-        let file_manager = FileManager::new(pid.or_else(|| state.ephemeral.then_some(1)));
+        // In an ephemeral container, the target is pid 1.
+        let target_pid = pid.or_else(|| state.ephemeral.then_some(1));
+        let file_manager = FileManager::new(target_pid);
+        let exec_api = ExecApi::new(target_pid, state.env.clone(), state.remote_exec);
 
-        let tcp_sniffer_api = Self::create_sniffer_api(id, bg_tasks.sniffer, &mut connection).await;
+        let tcp_sniffer_api = Self::create_sniffer_api(
+            id,
+            bg_tasks.sniffer,
+            protocol_version.clone(),
+            &mut connection,
+        )
+        .await;
         let tcp_stealer_api =
             Self::create_stealer_api(id, bg_tasks.stealer, protocol_version, &mut connection)
                 .await?;
@@ -255,13 +343,19 @@ impl ClientConnectionHandler {
         let client_handler = Self {
             id,
             file_manager,
+            file_stream: None,
+            file_requests: Default::default(),
+            file_streams_received: 0,
+            fair_share: Default::default(),
             connection,
             tcp_sniffer_api,
             tcp_stealer_api,
             tcp_outgoing_api,
             udp_outgoing_api,
             dns_api,
+            exec_api,
             state,
+            paused_target: false,
         };
 
         Ok(client_handler)
@@ -270,10 +364,19 @@ impl ClientConnectionHandler {
     async fn create_sniffer_api(
         id: ClientId,
         task: BackgroundTask<SnifferCommand>,
+        protocol_version: semver::Version,
         connection: &mut ClientConnection,
     ) -> Option<TcpSnifferApi> {
         if let BackgroundTask::Running(sniffer_status, sniffer_sender) = task {
-            match TcpSnifferApi::new(id, sniffer_sender, sniffer_status, CHANNEL_SIZE).await {
+            match TcpSnifferApi::new(
+                id,
+                sniffer_sender,
+                sniffer_status,
+                CHANNEL_SIZE,
+                protocol_version,
+            )
+            .await
+            {
                 Ok(api) => Some(api),
                 Err(e) => {
                     let message = format!(
@@ -342,7 +445,14 @@ impl ClientConnectionHandler {
     #[tracing::instrument(level = "trace", skip(self))]
     async fn start(mut self, cancellation_token: CancellationToken) -> Result<()> {
         let error = loop {
+            // Traffic goes before the chunks of `file_stream`, except for when it's their turn.
             select! {
+                biased;
+
+                _ = future::ready(()), if self.file_stream.is_some() && self.fair_share.bulk_turn() => {
+                    self.send_file_stream_chunk().await?
+                },
+                _ = cancellation_token.cancelled() => return Ok(()),
                 message = self.connection.receive() => {
                     let Some(message) = message? else {
                         debug!("Client {} disconnected", self.id);
@@ -393,7 +503,12 @@ impl ClientConnectionHandler {
                     Ok(message) => self.respond(DaemonMessage::GetAddrInfoResponse(message)).await?,
                     Err(e) => break e,
                 },
-                _ = cancellation_token.cancelled() => return Ok(()),
+                message = self.exec_api.daemon_message() => {
+                    self.respond(DaemonMessage::Exec(message)).await?
+                },
+                _ = future::ready(()), if self.file_stream.is_some() => {
+                    self.send_file_stream_chunk().await?
+                },
             }
         };
 
@@ -407,6 +522,7 @@ impl ClientConnectionHandler {
     /// Sends a [`DaemonMessage`] response to the connected client (`mirrord-layer`).
     #[tracing::instrument(level = "trace", skip(self))]
     async fn respond(&mut self, response: DaemonMessage) -> Result<()> {
+        self.fair_share.sent(response.priority());
         self.connection.send(response).await.map_err(Into::into)
     }
 
@@ -417,16 +533,17 @@ impl ClientConnectionHandler {
     async fn handle_client_message(&mut self, message: ClientMessage) -> Result<bool> {
         match message {
             ClientMessage::FileRequest(req) => {
-                if let Some(response) = self.file_manager.handle_message(req)? {
-                    self.respond(DaemonMessage::File(response))
-                        .await
-                        .inspect_err(|fail| {
-                            error!(
-                                "handle_client_message -> Failed responding to file message {:#?}!",
-                                fail
-                            )
-                        })?
-                }
+                let request = match FileStream::from_request(req) {
+                    Ok(stream) => {
+                        let id = self.file_streams_received;
+                        self.file_streams_received += 1;
+                        PendingFileRequest::Stream(id, stream)
+                    }
+                    Err(req) => PendingFileRequest::Other(req),
+                };
+
+                self.file_requests.push_back(request);
+                self.handle_file_requests().await?
             }
             ClientMessage::TcpOutgoing(layer_message) => {
                 self.tcp_outgoing_api.layer_message(layer_message).await?
@@ -450,6 +567,9 @@ impl ClientConnectionHandler {
                     .await?
             }
             ClientMessage::GetAddrInfoRequest(request) => {
+                self.dns_api.make_request(request.into()).await?;
+            }
+            ClientMessage::GetAddrInfoRequestV2(request) => {
                 self.dns_api.make_request(request).await?;
             }
             ClientMessage::Ping => self.respond(DaemonMessage::Pong).await?,
@@ -482,6 +602,7 @@ impl ClientConnectionHandler {
                     .await
                 {
                     Ok(changed) => {
+                        self.paused_target = pause;
                         self.respond(DaemonMessage::PauseTarget(
                             DaemonPauseTarget::PauseResponse {
                                 changed,
@@ -499,6 +620,12 @@ impl ClientConnectionHandler {
                 }
             }
             ClientMessage::SwitchProtocolVersion(version) => {
+                if let Some(tcp_sniffer_api) = self.tcp_sniffer_api.as_mut() {
+                    tcp_sniffer_api
+                        .switch_protocol_version(version.clone())
+                        .await?;
+                }
+
                 if let Some(tcp_stealer_api) = self.tcp_stealer_api.as_mut() {
                     tcp_stealer_api
                         .switch_protocol_version(version.clone())
@@ -509,10 +636,120 @@ impl ClientConnectionHandler {
                     .await?;
             }
             ClientMessage::ReadyForLogs => {}
+            ClientMessage::Exec(message) => self.exec_api.layer_message(message).await,
+            ClientMessage::CancelRequest(CancelRequest::FileStream(id)) => {
+                self.cancel_file_stream(id)
+            }
+            ClientMessage::CancelRequest(CancelRequest::TcpConnect(id)) => {
+                self.tcp_outgoing_api.cancel_connect(id).await?
+            }
+            ClientMessage::SwitchCapabilities(capabilities) => {
+                let mut capabilities = capabilities & Capabilities::all();
+                if !self.exec_api.enabled() {
+                    capabilities.remove(Capabilities::REMOTE_EXEC);
+                }
+
+                if let Some(tcp_stealer_api) = self.tcp_stealer_api.as_mut() {
+                    tcp_stealer_api.switch_capabilities(capabilities).await?;
+                }
+
+                self.respond(DaemonMessage::SwitchCapabilitiesResponse(capabilities))
+                    .await?
+            }
         }
 
         Ok(true)
     }
+
+    /// Handles the [`FileRequest`]s waiting in [`Self::file_requests`], until one of them starts
+    /// a stream.
+    async fn handle_file_requests(&mut self) -> Result<()> {
+        while self.file_stream.is_none() {
+            let Some(request) = self.file_requests.pop_front() else {
+                break;
+            };
+
+            match request {
+                PendingFileRequest::Stream(id, stream) => self.file_stream = Some((id, stream)),
+                PendingFileRequest::Other(req) => {
+                    if let Some(response) = self.file_manager.handle_message(req)? {
+                        self.respond(DaemonMessage::File(response))
+                            .await
+                            .inspect_err(|fail| {
+                                error!(
+                                    "handle_client_message -> Failed responding to file message {:#?}!",
+                                    fail
+                                )
+                            })?
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the next chunk of [`Self::file_stream`], and handles the file requests that waited
+    /// for it once it ended.
+    async fn send_file_stream_chunk(&mut self) -> Result<()> {
+        let response = match &mut self.file_stream {
+            Some((_, stream)) => self.file_manager.next_chunk(stream),
+            None => return Ok(()),
+        };
+
+        match response {
+            Some(response) => self
+                .respond(DaemonMessage::File(response))
+                .await
+                .inspect_err(|fail| error!("Failed responding to file stream {fail:#?}!")),
+            None => {
+                self.file_stream = None;
+                self.handle_file_requests().await
+            }
+        }
+    }
+
+    /// Ends the stream with the number `id` early, if it's still being sent or waiting.
+    fn cancel_file_stream(&mut self, id: u64) {
+        let current = self
+            .file_stream
+            .as_mut()
+            .map(|(stream_id, stream)| (*stream_id, stream));
+        let waiting = self
+            .file_requests
+            .iter_mut()
+            .filter_map(|request| match request {
+                PendingFileRequest::Stream(stream_id, stream) => Some((*stream_id, stream)),
+                PendingFileRequest::Other(..) => None,
+            });
+
+        if let Some((_, stream)) = current
+            .into_iter()
+            .chain(waiting)
+            .find(|(stream_id, _)| *stream_id == id)
+        {
+            if let Some((fd, seek_from)) = stream.cancel() {
+                if let Err(fail) = self.file_manager.seek(fd, seek_from) {
+                    warn!(
+                        fd,
+                        ?fail,
+                        "Failed to move the file back after cancelling its stream"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Waits `idle_timeout` for a client to connect, after all the clients are gone. The client may
+/// have lost its connection (e.g. a dropped k8s port forward) and be dialing us again.
+///
+/// Returns [`None`] when no client came back, and the agent should exit.
+async fn wait_for_client(
+    listener: &TcpListener,
+    idle_timeout: Duration,
+) -> Option<(TcpStream, SocketAddr)> {
+    timeout(idle_timeout, listener.accept()).await.ok()?.ok()
 }
 
 /// Initializes the agent's [`State`], channels, threads, and runs [`ClientConnectionHandler`]s.
@@ -537,7 +774,8 @@ async fn start_agent(args: Args, watch: drain::Watch) -> Result<()> {
     let (stealer_command_tx, stealer_command_rx) = mpsc::channel::<StealerCommand>(1000);
     let (dns_command_tx, dns_command_rx) = mpsc::channel::<DnsCommand>(1000);
 
-    let (sniffer_task, sniffer_status) = if args.mode.is_targetless() {
+    // Sniffing needs raw sockets, which sidecar agents don't have.
+    let (sniffer_task, sniffer_status) = if args.mode.is_targetless() || args.mode.is_sidecar() {
         (None, None)
     } else {
         let cancellation_token = cancellation_token.clone();
@@ -573,13 +811,15 @@ async fn start_agent(args: Args, watch: drain::Watch) -> Result<()> {
         let cancellation_token = cancellation_token.clone();
         let watched_task = WatchedTask::new(
             TcpConnectionStealer::TASK_NAME,
-            TcpConnectionStealer::new(stealer_command_rx).and_then(|stealer| async move {
-                let res = stealer.start(cancellation_token).await;
-                if let Err(err) = res.as_ref() {
-                    error!("Stealer failed: {err}");
-                }
-                res
-            }),
+            TcpConnectionStealer::new(stealer_command_rx, args.mode.sidecar_listeners()).and_then(
+                |stealer| async move {
+                    let res = stealer.start(cancellation_token).await;
+                    if let Err(err) = res.as_ref() {
+                        error!("Stealer failed: {err}");
+                    }
+                    res
+                },
+            ),
         );
         let status = watched_task.status();
         let task = run_thread_in_namespace(
@@ -684,10 +924,27 @@ async fn start_agent(args: Args, watch: drain::Watch) -> Result<()> {
                         Err(error)?
                     }
 
-                    None => {
-                        trace!("start_agent -> All clients finished, exiting main agent loop");
-                        break
-                    }
+                    None => match wait_for_client(
+                        &listener,
+                        Duration::from_secs(args.communication_timeout.into()),
+                    )
+                    .await
+                    {
+                        Some((stream, addr)) => {
+                            trace!(peer = %addr, "start_agent -> Client reconnected");
+                            clients.spawn(state.clone().serve_client_connection(
+                                stream,
+                                bg_tasks.clone(),
+                                cancellation_token.clone(),
+                                args.base_protocol_version.clone(),
+                            ));
+                        }
+
+                        None => {
+                            trace!("start_agent -> All clients finished, exiting main agent loop");
+                            break
+                        }
+                    },
                 }
             }
         }
@@ -782,6 +1039,13 @@ async fn start_iptable_guard(args: Args, watch: drain::Watch) -> Result<()> {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
+    let args = cli::parse_args();
+
+    // Stdout carries the agent protocol here, so no logging.
+    if matches!(args.mode, cli::Mode::Bridge) {
+        return bridge::run(args.communicate_port).await;
+    }
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -797,11 +1061,10 @@ async fn main() -> Result<()> {
         env!("CARGO_PKG_VERSION")
     );
 
-    let args = cli::parse_args();
-
     let (signal, watch) = drain::channel();
 
     let agent_result = if args.mode.is_targetless()
+        || args.mode.is_sidecar()
         || (std::env::var(IPTABLE_PREROUTING_ENV).is_ok()
             && std::env::var(IPTABLE_MESH_ENV).is_ok())
     {
@@ -832,3 +1095,21 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The agent exits when no client connects in its idle timeout, and serves the one that does.
+    #[tokio::test]
+    async fn waits_for_client_until_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let idle_timeout = Duration::from_millis(100);
+
+        assert!(wait_for_client(&listener, idle_timeout).await.is_none());
+
+        let _client = TcpStream::connect(address).await.unwrap();
+        assert!(wait_for_client(&listener, idle_timeout).await.is_some());
+    }
+}
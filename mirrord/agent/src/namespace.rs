@@ -16,6 +16,8 @@ pub(crate) enum NamespaceError {
 pub(crate) enum NamespaceType {
     Net,
     Cgroup,
+    Mnt,
+    Pid,
 }
 
 impl NamespaceType {
@@ -24,6 +26,8 @@ impl NamespaceType {
         match self {
             NamespaceType::Net => format!("/proc/{}/ns/net", pid),
             NamespaceType::Cgroup => format!("/proc/{}/ns/cgroup", pid),
+            NamespaceType::Mnt => format!("/proc/{}/ns/mnt", pid),
+            NamespaceType::Pid => format!("/proc/{}/ns/pid", pid),
         }
     }
 }
@@ -33,10 +37,24 @@ impl From<NamespaceType> for CloneFlags {
         match ns_type {
             NamespaceType::Net => CloneFlags::CLONE_NEWNET,
             NamespaceType::Cgroup => CloneFlags::CLONE_NEWCGROUP,
+            NamespaceType::Mnt => CloneFlags::CLONE_NEWNS,
+            NamespaceType::Pid => CloneFlags::CLONE_NEWPID,
         }
     }
 }
 
+/// Opens the namespace file of the given pid, to enter the namespace later with [`setns`].
+///
+/// Used when the namespace has to be entered where we can't open files, e.g. right before `exec`
+/// in a forked child.
+pub(crate) fn open_namespace(
+    pid: u64,
+    namespace_type: NamespaceType,
+) -> Result<(File, CloneFlags), NamespaceError> {
+    let fd = File::open(namespace_type.path_from_pid(pid))?;
+    Ok((fd, namespace_type.into()))
+}
+
 /// Set namespace by cloneflags and pid.
 /// NOTE: don't make it async in the case we're in an multi-thread scheduler and we want it to
 /// happen on the same thread always.
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, fmt, thread, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, future, thread,
+    time::Duration,
+};
 
 use bytes::Bytes;
 use mirrord_protocol::{
@@ -40,6 +44,10 @@ pub(crate) struct TcpOutgoingApi {
     /// Sends the layer messages to the [`TcpOutgoingTask`].
     layer_tx: Sender<LayerTcpOutgoing>,
 
+    /// Sends the numbers of the cancelled [`LayerTcpOutgoing::Connect`]s to the
+    /// [`TcpOutgoingTask`].
+    cancel_tx: Sender<u64>,
+
     /// Reads the daemon messages from the [`TcpOutgoingTask`].
     daemon_rx: Receiver<DaemonTcpOutgoing>,
 }
@@ -56,11 +64,12 @@ impl TcpOutgoingApi {
     #[tracing::instrument(level = "trace")]
     pub(crate) fn new(pid: Option<u64>) -> Self {
         let (layer_tx, layer_rx) = mpsc::channel(1000);
+        let (cancel_tx, cancel_rx) = mpsc::channel(1000);
         let (daemon_tx, daemon_rx) = mpsc::channel(1000);
 
         let watched_task = WatchedTask::new(
             Self::TASK_NAME,
-            TcpOutgoingTask::new(pid, layer_rx, daemon_tx).run(),
+            TcpOutgoingTask::new(pid, layer_rx, cancel_rx, daemon_tx).run(),
         );
         let task_status = watched_task.status();
         let task = run_thread_in_namespace(
@@ -74,10 +83,22 @@ impl TcpOutgoingApi {
             _task: task,
             task_status,
             layer_tx,
+            cancel_tx,
             daemon_rx,
         }
     }
 
+    /// Cancels the [`LayerTcpOutgoing::Connect`] with the number `id`, see
+    /// [`CancelRequest::TcpConnect`](mirrord_protocol::CancelRequest::TcpConnect).
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub(crate) async fn cancel_connect(&mut self, id: u64) -> Result<()> {
+        if self.cancel_tx.send(id).await.is_ok() {
+            Ok(())
+        } else {
+            Err(self.task_status.unwrap_err().await)
+        }
+    }
+
     /// Sends the [`LayerTcpOutgoing`] message to the background task.
     #[tracing::instrument(level = "trace", skip(self))]
     pub(crate) async fn layer_message(&mut self, message: LayerTcpOutgoing) -> Result<()> {
@@ -104,10 +125,19 @@ struct TcpOutgoingTask {
     writers: HashMap<ConnectionId, WriteHalf<SocketStream>>,
     /// Reading halves of peer connections made on layer's requests.
     readers: StreamMap<ConnectionId, ReaderStream<ReadHalf<SocketStream>>>,
+    /// Reading halves of peer connections that the layer paused with
+    /// [`LayerTcpOutgoing::Pause`]. We don't read from them until they're resumed.
+    paused_readers: HashMap<ConnectionId, ReaderStream<ReadHalf<SocketStream>>>,
     /// Optional pid of agent's target. Used in [`SocketStream::connect`].
     pid: Option<u64>,
     layer_rx: Receiver<LayerTcpOutgoing>,
+    /// Numbers of the [`LayerTcpOutgoing::Connect`]s that the layer cancelled.
+    cancel_rx: Receiver<u64>,
     daemon_tx: Sender<DaemonTcpOutgoing>,
+    /// Number of [`LayerTcpOutgoing::Connect`]s received.
+    connects_received: u64,
+    /// Cancelled [`LayerTcpOutgoing::Connect`]s that were not received yet.
+    cancelled_connects: HashSet<u64>,
 }
 
 impl fmt::Debug for TcpOutgoingTask {
@@ -116,6 +146,7 @@ impl fmt::Debug for TcpOutgoingTask {
             .field("next_connection_id", &self.next_connection_id)
             .field("writers", &self.writers.len())
             .field("readers", &self.readers.len())
+            .field("paused_readers", &self.paused_readers.len())
             .field("pid", &self.pid)
             .finish()
     }
@@ -135,18 +166,51 @@ impl TcpOutgoingTask {
     fn new(
         pid: Option<u64>,
         layer_rx: Receiver<LayerTcpOutgoing>,
+        cancel_rx: Receiver<u64>,
         daemon_tx: Sender<DaemonTcpOutgoing>,
     ) -> Self {
         Self {
             next_connection_id: 0,
             writers: Default::default(),
             readers: Default::default(),
+            paused_readers: Default::default(),
             pid,
             layer_rx,
+            cancel_rx,
             daemon_tx,
+            connects_received: 0,
+            cancelled_connects: Default::default(),
+        }
+    }
+
+    /// Remembers that the [`LayerTcpOutgoing::Connect`] with the number `id` was cancelled, if it
+    /// was not received yet.
+    fn connect_cancelled(&mut self, id: u64) {
+        if id >= self.connects_received {
+            self.cancelled_connects.insert(id);
+        }
+    }
+
+    /// Resolves when the [`LayerTcpOutgoing::Connect`] with the number `id` is cancelled.
+    async fn cancelled(&mut self, id: u64) {
+        loop {
+            if self.cancelled_connects.remove(&id) {
+                return;
+            }
+
+            match self.cancel_rx.recv().await {
+                Some(cancelled) => self.connect_cancelled(cancelled),
+                None => future::pending().await,
+            }
         }
     }
 
+    /// Removes the reading half of the connection, paused or not.
+    fn remove_reader(&mut self, connection_id: ConnectionId) {
+        self.readers.remove(&connection_id);
+        self.paused_readers.remove(&connection_id);
+    }
+
     /// Runs this task as long as the channels connecting it with [`TcpOutgoingApi`] are open.
     async fn run(mut self) -> Result<()> {
         loop {
@@ -163,6 +227,8 @@ impl TcpOutgoingTask {
                     },
                 },
 
+                Some(id) = self.cancel_rx.recv() => self.connect_cancelled(id),
+
                 // We have data coming from one of our peers.
                 Some((connection_id, remote_read)) = self.readers.next() => {
                     self.handle_connection_read(connection_id, remote_read).await?;
@@ -206,7 +272,7 @@ impl TcpOutgoingTask {
                     "Reading from peer connection failed, sending close message.",
                 );
 
-                self.readers.remove(&connection_id);
+                self.remove_reader(connection_id);
                 self.writers.remove(&connection_id);
 
                 let daemon_message = DaemonTcpOutgoing::Close(connection_id);
@@ -256,24 +322,35 @@ impl TcpOutgoingTask {
         match message {
             // We make connection to the requested address, split the stream into halves with
             // `io::split`, and put them into respective maps.
+            // The attempt is abandoned when the layer cancels it.
             LayerTcpOutgoing::Connect(LayerConnect { remote_address }) => {
-                let daemon_connect = time::timeout(
+                let id = self.connects_received;
+                self.connects_received += 1;
+
+                let connect = time::timeout(
                     Self::CONNECT_TIMEOUT,
                     SocketStream::connect(remote_address.clone(), self.pid),
-                )
-                .await
-                .unwrap_or_else(|_elapsed| {
-                    tracing::warn!(
-                        %remote_address,
-                        connect_timeout_ms = Self::CONNECT_TIMEOUT.as_millis(),
-                        "Connect attempt timed out."
-                    );
+                );
+
+                let connect_result = select! {
+                    result = connect => result.unwrap_or_else(|_elapsed| {
+                        tracing::warn!(
+                            %remote_address,
+                            connect_timeout_ms = Self::CONNECT_TIMEOUT.as_millis(),
+                            "Connect attempt timed out."
+                        );
 
-                    Err(ResponseError::Remote(RemoteError::ConnectTimedOut(
-                        remote_address.clone(),
-                    )))
-                })
-                .and_then(|remote_stream| {
+                        Err(ResponseError::Remote(RemoteError::ConnectTimedOut(
+                            remote_address.clone(),
+                        )))
+                    }),
+                    _ = self.cancelled(id) => {
+                        tracing::trace!(%remote_address, "Connect attempt cancelled.");
+                        Err(std::io::Error::from(std::io::ErrorKind::Interrupted).into())
+                    },
+                };
+
+                let daemon_connect = connect_result.and_then(|remote_stream| {
                     let agent_address = remote_stream.local_addr()?;
                     let connection_id = self.next_connection_id;
                     self.next_connection_id += 1;
@@ -327,7 +404,9 @@ impl TcpOutgoingTask {
                     Ok(()) if bytes.is_empty() => {
                         self.writers.remove(&connection_id);
 
-                        if !self.readers.contains_key(&connection_id) {
+                        if !self.readers.contains_key(&connection_id)
+                            && !self.paused_readers.contains_key(&connection_id)
+                        {
                             tracing::trace!(
                                 connection_id,
                                 "Peer connection is shut down as well, sending close message.",
@@ -345,7 +424,7 @@ impl TcpOutgoingTask {
                         tracing::trace!(connection_id, ?error, "Failed to handle layer write.",);
 
                         self.writers.remove(&connection_id);
-                        self.readers.remove(&connection_id);
+                        self.remove_reader(connection_id);
 
                         self.daemon_tx
                             .send(DaemonTcpOutgoing::Close(connection_id))
@@ -358,7 +437,21 @@ impl TcpOutgoingTask {
             // We remove io halves and forget about it.
             LayerTcpOutgoing::Close(LayerClose { connection_id }) => {
                 self.writers.remove(&connection_id);
-                self.readers.remove(&connection_id);
+                self.remove_reader(connection_id);
+            }
+
+            // Layer can't keep up with the data of a connection.
+            // We stop reading from the peer, and TCP flow control slows it down.
+            LayerTcpOutgoing::Pause(connection_id) => {
+                if let Some(reader) = self.readers.remove(&connection_id) {
+                    self.paused_readers.insert(connection_id, reader);
+                }
+            }
+
+            LayerTcpOutgoing::Resume(connection_id) => {
+                if let Some(reader) = self.paused_readers.remove(&connection_id) {
+                    self.readers.insert(connection_id, reader);
+                }
             }
         }
 
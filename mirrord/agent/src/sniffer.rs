@@ -5,7 +5,10 @@ use std::{
 };
 
 use mirrord_protocol::{
-    tcp::{DaemonTcp, LayerTcp, NewTcpConnection, TcpClose, TcpData},
+    tcp::{
+        DaemonTcp, LayerTcp, NewTcpConnection, TcpClose, TcpData, TcpSequencedClose,
+        TcpSequencedData, MIRROR_SEQUENCE_VERSION,
+    },
     ConnectionId, MeshVendor, Port,
 };
 use nix::sys::socket::SockaddrStorage;
@@ -95,6 +98,11 @@ impl Hash for TcpSessionIdentifier {
 struct TCPSession {
     id: ConnectionId,
     clients: HashSet<ClientId>,
+    /// TCP sequence number of the first byte sent by the remote peer.
+    base_sequence: u32,
+    /// Offset of the furthest byte sent by the remote peer that we've seen so far, used in
+    /// [`stream_offset`].
+    stream_end: u64,
 }
 
 type TCPSessionMap = HashMap<TcpSessionIdentifier, TCPSession>;
@@ -107,6 +115,18 @@ fn is_closed_connection(flags: u16) -> bool {
     0 != (flags & (TcpFlags::FIN | TcpFlags::RST))
 }
 
+/// Translates the TCP `sequence` number of a packet into an offset in the stream that starts at
+/// `base_sequence`.
+///
+/// TCP sequence numbers wrap around every 4GiB, so the offset is picked to be the closest one to
+/// `stream_end` (the furthest offset seen so far). Returns [`None`] if the packet belongs before
+/// the start of the stream (e.g. a retransmission of data sent before we started sniffing).
+fn stream_offset(base_sequence: u32, stream_end: u64, sequence: u32) -> Option<u64> {
+    let relative = sequence.wrapping_sub(base_sequence);
+    let delta = relative.wrapping_sub(stream_end as u32) as i32;
+    stream_end.checked_add_signed(delta.into())
+}
+
 /// Connects to a remote address (`8.8.8.8:53`) so we can find which network interface to use.
 ///
 /// Used when no `user_interface` is specified in [`prepare_sniffer`] to prevent mirrord from
@@ -173,6 +193,7 @@ async fn prepare_sniffer(
 struct TcpPacketData {
     bytes: Vec<u8>,
     flags: u16,
+    sequence: u32,
 }
 
 #[tracing::instrument(skip(eth_packet), level = "trace", fields(bytes = %eth_packet.len()))]
@@ -203,6 +224,7 @@ fn get_tcp_packet(eth_packet: Vec<u8>) -> Option<(TcpSessionIdentifier, TcpPacke
         identifier,
         TcpPacketData {
             flags: tcp_packet.get_flags(),
+            sequence: tcp_packet.get_sequence(),
             bytes: tcp_packet.payload().to_vec(),
         },
     ))
@@ -210,7 +232,8 @@ fn get_tcp_packet(eth_packet: Vec<u8>) -> Option<(TcpSessionIdentifier, TcpPacke
 
 #[derive(Debug)]
 enum SnifferCommands {
-    NewAgent(Sender<DaemonTcp>),
+    NewAgent(Sender<DaemonTcp>, semver::Version),
+    SwitchProtocolVersion(semver::Version),
     Subscribe(Port),
     UnsubscribePort(Port),
     UnsubscribeConnection(ConnectionId),
@@ -253,18 +276,20 @@ impl TcpSnifferApi {
     /// * `task_status` - handle to the [`TcpConnectionSniffer`] exit status
     /// * `channel_size` - capacity of the channel connecting [`TcpConnectionSniffer`] back to this
     ///   struct
+    /// * `protocol_version` - version of [`mirrord_protocol`] negotiated with the client
     pub async fn new(
         client_id: ClientId,
         sniffer_sender: Sender<SnifferCommand>,
         task_status: TaskStatus,
         channel_size: usize,
+        protocol_version: semver::Version,
     ) -> Result<TcpSnifferApi, AgentError> {
         let (sender, receiver) = mpsc::channel(channel_size);
 
         sniffer_sender
             .send(SnifferCommand {
                 client_id,
-                command: SnifferCommands::NewAgent(sender),
+                command: SnifferCommands::NewAgent(sender, protocol_version),
             })
             .await?;
 
@@ -303,6 +328,16 @@ impl TcpSnifferApi {
     pub async fn handle_client_message(&mut self, message: LayerTcp) -> Result<(), AgentError> {
         self.send_command(message.into()).await
     }
+
+    /// Informs the [`TcpConnectionSniffer`] about the [`mirrord_protocol`] version negotiated
+    /// with the client.
+    pub(crate) async fn switch_protocol_version(
+        &mut self,
+        version: semver::Version,
+    ) -> Result<(), AgentError> {
+        self.send_command(SnifferCommands::SwitchProtocolVersion(version))
+            .await
+    }
 }
 
 impl Drop for TcpSnifferApi {
@@ -316,10 +351,18 @@ impl Drop for TcpSnifferApi {
     }
 }
 
+/// A client connected to the [`TcpConnectionSniffer`].
+struct SnifferClient {
+    /// For sending messages to the client's [`TcpSnifferApi`].
+    sender: Sender<DaemonTcp>,
+    /// Client's [`mirrord_protocol`] version.
+    protocol_version: semver::Version,
+}
+
 pub(crate) struct TcpConnectionSniffer {
     port_subscriptions: Subscriptions<Port, ClientId>,
     receiver: Receiver<SnifferCommand>,
-    client_senders: HashMap<ClientId, Sender<DaemonTcp>>,
+    clients: HashMap<ClientId, SnifferClient>,
     raw_capture: RawCapture,
     sessions: TCPSessionMap,
     //todo: impl drop for index allocator and connection id..
@@ -370,7 +413,7 @@ impl TcpConnectionSniffer {
             receiver,
             raw_capture,
             port_subscriptions: Default::default(),
-            client_senders: HashMap::new(),
+            clients: HashMap::new(),
             sessions: TCPSessionMap::new(),
             //todo: impl drop for index allocator and connection id..
             connection_id_to_tcp_identifier: HashMap::new(),
@@ -380,8 +423,19 @@ impl TcpConnectionSniffer {
 
     /// New layer is connecting to this agent sniffer.
     #[tracing::instrument(level = "trace", ret, skip(self, sender))]
-    fn handle_new_client(&mut self, client_id: ClientId, sender: Sender<DaemonTcp>) {
-        self.client_senders.insert(client_id, sender);
+    fn handle_new_client(
+        &mut self,
+        client_id: ClientId,
+        sender: Sender<DaemonTcp>,
+        protocol_version: semver::Version,
+    ) {
+        self.clients.insert(
+            client_id,
+            SnifferClient {
+                sender,
+                protocol_version,
+            },
+        );
     }
 
     /// layer with `client_id` wants to sniff on `port`.
@@ -400,7 +454,7 @@ impl TcpConnectionSniffer {
     /// Removes the client with `client_id`, and also unsubscribes its port.
     #[tracing::instrument(level = "trace", skip(self))]
     fn handle_client_closed(&mut self, client_id: ClientId) -> Result<(), AgentError> {
-        self.client_senders.remove(&client_id);
+        self.clients.remove(&client_id);
         self.port_subscriptions.remove_client(client_id);
         self.update_sniffer()
     }
@@ -434,9 +488,17 @@ impl TcpConnectionSniffer {
         match command {
             SnifferCommand {
                 client_id,
-                command: SnifferCommands::NewAgent(sender),
+                command: SnifferCommands::NewAgent(sender, protocol_version),
             } => {
-                self.handle_new_client(client_id, sender);
+                self.handle_new_client(client_id, sender, protocol_version);
+            }
+            SnifferCommand {
+                client_id,
+                command: SnifferCommands::SwitchProtocolVersion(version),
+            } => {
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.protocol_version = version;
+                }
             }
             SnifferCommand {
                 client_id,
@@ -487,6 +549,33 @@ impl TcpConnectionSniffer {
         Ok(())
     }
 
+    /// Sends `sequenced` to the clients that support [`MIRROR_SEQUENCE_VERSION`], and `legacy` to
+    /// the rest of them.
+    ///
+    /// When `sequenced` is [`None`], only the legacy clients get a message.
+    async fn send_sequenced_message_to_clients(
+        &mut self,
+        clients: impl Iterator<Item = &ClientId>,
+        sequenced: Option<DaemonTcp>,
+        legacy: DaemonTcp,
+    ) -> Result<(), AgentError> {
+        for client_id in clients {
+            let message = match self.clients.get(client_id) {
+                Some(client) if MIRROR_SEQUENCE_VERSION.matches(&client.protocol_version) => {
+                    match &sequenced {
+                        Some(sequenced) => sequenced.clone(),
+                        None => continue,
+                    }
+                }
+                Some(..) => legacy.clone(),
+                None => continue,
+            };
+
+            self.send_message_to_client(client_id, message).await?;
+        }
+        Ok(())
+    }
+
     /// Sends a [`DaemonTcp`] message back to the client with `client_id`.
     #[tracing::instrument(level = "trace", ret, skip(self, message))]
     async fn send_message_to_client(
@@ -494,8 +583,8 @@ impl TcpConnectionSniffer {
         client_id: &ClientId,
         message: DaemonTcp,
     ) -> Result<(), AgentError> {
-        if let Some(sender) = self.client_senders.get(client_id) {
-            sender.send(message).await.map_err(|err| {
+        if let Some(client) = self.clients.get(client_id) {
+            client.sender.send(message).await.map_err(|err| {
                 warn!(
                     "Failed to send message to client {} with {:#?}!",
                     client_id, err
@@ -541,7 +630,7 @@ impl TcpConnectionSniffer {
 
         let is_client_packet = self.qualified_port(dest_port);
 
-        let session = match self.sessions.remove(&identifier) {
+        let mut session = match self.sessions.remove(&identifier) {
             Some(session) => session,
             None => {
                 // Performs a check on the `tcp_flags` and on the packet contents to see if this
@@ -581,36 +670,80 @@ impl TcpConnectionSniffer {
 
                 self.connection_id_to_tcp_identifier.insert(id, identifier);
 
+                // The SYN flag takes up one sequence number.
+                let base_sequence = if is_new_connection(tcp_flags) {
+                    tcp_packet.sequence.wrapping_add(1)
+                } else {
+                    tcp_packet.sequence
+                };
+
                 TCPSession {
                     id,
                     clients: client_ids.into_iter().collect(),
+                    base_sequence,
+                    stream_end: 0,
                 }
             }
         };
         trace!("session {:#?}", session);
 
+        // Sequence numbers of packets sent by the impersonated pod are not interesting to us.
+        let offset = is_client_packet
+            .then(|| {
+                stream_offset(
+                    session.base_sequence,
+                    session.stream_end,
+                    tcp_packet.sequence,
+                )
+            })
+            .flatten();
+        let end_sequence = offset.map(|offset| offset + tcp_packet.bytes.len() as u64);
+        if let Some(end_sequence) = end_sequence {
+            session.stream_end = session.stream_end.max(end_sequence);
+        }
+
         if is_client_packet && !tcp_packet.bytes.is_empty() {
-            let message = DaemonTcp::Data(TcpData {
+            // Data sent before the start of the stream can't be put in order, so only the legacy
+            // clients (that get the data as it comes) get it.
+            let sequenced = offset.map(|sequence| {
+                DaemonTcp::DataSequenced(TcpSequencedData {
+                    connection_id: session.id,
+                    sequence,
+                    bytes: tcp_packet.bytes.clone(),
+                })
+            });
+            if sequenced.is_none() {
+                trace!(
+                    "not sequencing data of connection {} sent before the start of the stream",
+                    session.id
+                );
+            }
+            let legacy = DaemonTcp::Data(TcpData {
                 bytes: tcp_packet.bytes,
                 connection_id: session.id,
             });
-            self.send_message_to_clients(session.clients.iter(), message)
+
+            self.send_sequenced_message_to_clients(session.clients.iter(), sequenced, legacy)
                 .await?;
         }
 
         if is_closed_connection(tcp_flags) {
             self.index_allocator.free_index(session.id);
             self.connection_id_to_tcp_identifier.remove(&session.id);
-            let message = DaemonTcp::Close(TcpClose {
+            let sequenced = DaemonTcp::CloseSequenced(TcpSequencedClose {
+                connection_id: session.id,
+                end_sequence: end_sequence.filter(|_| 0 != (tcp_flags & TcpFlags::FIN)),
+            });
+            let legacy = DaemonTcp::Close(TcpClose {
                 connection_id: session.id,
             });
 
             debug!(
                 "TcpConnectionSniffer::handle_packet -> message {:#?}",
-                message
+                sequenced
             );
 
-            self.send_message_to_clients(session.clients.iter(), message)
+            self.send_sequenced_message_to_clients(session.clients.iter(), Some(sequenced), legacy)
                 .await?;
         } else {
             self.sessions.insert(identifier, session);
@@ -619,3 +752,26 @@ impl TcpConnectionSniffer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::start(100, 0, 100, Some(0))]
+    #[case::further(100, 10, 150, Some(50))]
+    #[case::retransmission(100, 50, 110, Some(10))]
+    #[case::before_stream(100, 0, 99, None)]
+    #[case::sequence_wrapped(u32::MAX - 9, 5, 5, Some(15))]
+    #[case::past_4gib(0, u32::MAX as u64, 5, Some(u32::MAX as u64 + 6))]
+    fn offset_in_stream(
+        #[case] base_sequence: u32,
+        #[case] stream_end: u64,
+        #[case] sequence: u32,
+        #[case] expected: Option<u64>,
+    ) {
+        assert_eq!(stream_offset(base_sequence, stream_end, sequence), expected);
+    }
+}
@@ -1,4 +1,5 @@
 use mirrord_protocol::{
+    capabilities::Capabilities,
     tcp::{DaemonTcp, HttpResponseFallback, StealType, TcpData},
     ConnectionId, Port,
 };
@@ -47,6 +48,12 @@ enum Command {
     /// The agent stops sending incoming traffic.
     ConnectionUnsubscribe(ConnectionId),
 
+    /// The layer can't keep up with the data of the connection (`true`), or caught up with it
+    /// (`false`).
+    ///
+    /// The agent stops reading from the connection until it's resumed.
+    ConnectionPause(ConnectionId, bool),
+
     /// There is new data in the direction going from the local process to the end-user (Going
     /// via the layer and the agent  local-process -> layer --> agent --> end-user).
     ///
@@ -59,6 +66,9 @@ enum Command {
     HttpResponse(HttpResponseFallback),
 
     SwitchProtocolVersion(semver::Version),
+
+    /// The layer negotiated these [`Capabilities`] with the agent.
+    SwitchCapabilities(Capabilities),
 }
 
 /// Association between a client (identified by the `client_id`) and a [`Command`].
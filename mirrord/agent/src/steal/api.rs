@@ -122,6 +122,20 @@ impl TcpStealerApi {
             .await
     }
 
+    /// Handles the conversion of [`LayerTcpSteal::ConnectionPause`] and
+    /// [`LayerTcpSteal::ConnectionResume`], that are passed from the agent, to an internal stealer
+    /// command [`Command::ConnectionPause`].
+    ///
+    /// The actual handling of this message is done in [`TcpConnectionStealer`].
+    pub(crate) async fn connection_pause(
+        &mut self,
+        connection_id: ConnectionId,
+        paused: bool,
+    ) -> Result<(), AgentError> {
+        self.send_command(Command::ConnectionPause(connection_id, paused))
+            .await
+    }
+
     /// Handles the conversion of [`LayerTcpSteal::TcpData`], that is passed from the
     /// agent, to an internal stealer command [`Command::ResponseData`].
     ///
@@ -149,6 +163,14 @@ impl TcpStealerApi {
             .await
     }
 
+    pub(crate) async fn switch_capabilities(
+        &mut self,
+        capabilities: Capabilities,
+    ) -> Result<(), AgentError> {
+        self.send_command(Command::SwitchCapabilities(capabilities))
+            .await
+    }
+
     pub(crate) async fn handle_client_message(&mut self, message: LayerTcpSteal) -> Result<()> {
         match message {
             LayerTcpSteal::PortSubscribe(port_steal) => self.port_subscribe(port_steal).await,
@@ -165,6 +187,12 @@ impl TcpStealerApi {
                 self.http_response(HttpResponseFallback::Framed(response))
                     .await
             }
+            LayerTcpSteal::ConnectionPause(connection_id) => {
+                self.connection_pause(connection_id, true).await
+            }
+            LayerTcpSteal::ConnectionResume(connection_id) => {
+                self.connection_pause(connection_id, false).await
+            }
         }
     }
 }
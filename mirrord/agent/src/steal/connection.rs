@@ -1,6 +1,6 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::SocketAddr,
 };
 
 use fancy_regex::Regex;
@@ -11,9 +11,11 @@ use hyper::{
     Request,
 };
 use mirrord_protocol::{
+    capabilities::Capabilities,
     tcp::{
         DaemonTcp, HttpRequest, HttpResponseFallback, InternalHttpBody, InternalHttpRequest,
-        StealType, TcpClose, TcpData, HTTP_FILTERED_UPGRADE_VERSION, HTTP_FRAMED_VERSION,
+        NewTcpConnectionV2, StealType, TcpClose, TcpData, HTTP_FILTERED_UPGRADE_VERSION,
+        HTTP_FRAMED_VERSION,
     },
     ConnectionId, Port,
     RemoteError::{BadHttpFilterExRegex, BadHttpFilterRegex},
@@ -26,14 +28,16 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    cli::SidecarListeners,
     error::{AgentError, Result},
     steal::{
         connections::{
             ConnectionMessageIn, ConnectionMessageOut, StolenConnection, StolenConnections,
         },
         http::HttpFilter,
-        orig_dst,
-        subscriptions::{IpTablesRedirector, PortSubscriptions},
+        subscriptions::{
+            IpTablesRedirector, ListenerRedirector, PortRedirector, PortSubscriptions,
+        },
         Command, StealerCommand,
     },
     util::ClientId,
@@ -117,6 +121,10 @@ struct Client {
     tx: Sender<DaemonTcp>,
     /// Clients [`mirrord_protocol`] verison.
     protocol_version: semver::Version,
+    /// Features of the client, negotiated with
+    /// [`ClientMessage::SwitchCapabilities`](mirrord_protocol::ClientMessage::SwitchCapabilities)
+    /// or implied by [`Client::protocol_version`].
+    capabilities: Capabilities,
     /// Client subscriptions to stolen connections.
     /// Used to unsubscribe when the client exits.
     subscribed_connections: HashSet<ConnectionId>,
@@ -176,7 +184,7 @@ impl Client {
 /// run in the same network namespace as the agent's target.
 pub(crate) struct TcpConnectionStealer {
     /// For managing active subscriptions and port redirections.
-    port_subscriptions: PortSubscriptions<IpTablesRedirector>,
+    port_subscriptions: PortSubscriptions<Box<dyn PortRedirector<Error = AgentError> + Send>>,
 
     /// For receiving commands.
     /// The other end of this channel belongs to [`TcpStealerApi`](super::api::TcpStealerApi).
@@ -194,14 +202,28 @@ impl TcpConnectionStealer {
 
     /// Initializes a new [`TcpConnectionStealer`], but doesn't start the actual work.
     /// You need to call [`TcpConnectionStealer::start`] to do so.
+    ///
+    /// When given `sidecar_listeners` (sidecar mode), the stolen ports are bound directly with a
+    /// [`ListenerRedirector`], instead of redirected with iptables.
     #[tracing::instrument(level = "trace")]
-    pub(crate) async fn new(command_rx: Receiver<StealerCommand>) -> Result<Self, AgentError> {
+    pub(crate) async fn new(
+        command_rx: Receiver<StealerCommand>,
+        sidecar_listeners: Option<SidecarListeners>,
+    ) -> Result<Self, AgentError> {
         let port_subscriptions = {
-            let flush_connections = std::env::var("MIRRORD_AGENT_STEALER_FLUSH_CONNECTIONS")
-                .ok()
-                .and_then(|var| var.parse::<bool>().ok())
-                .unwrap_or_default();
-            let redirector = IpTablesRedirector::new(flush_connections).await?;
+            let redirector: Box<dyn PortRedirector<Error = AgentError> + Send> =
+                match sidecar_listeners {
+                    Some(listeners) => Box::new(ListenerRedirector::new(listeners).await?),
+                    None => {
+                        let flush_connections =
+                            std::env::var("MIRRORD_AGENT_STEALER_FLUSH_CONNECTIONS")
+                                .ok()
+                                .and_then(|var| var.parse::<bool>().ok())
+                                .unwrap_or_default();
+
+                        Box::new(IpTablesRedirector::new(flush_connections).await?)
+                    }
+                };
 
             PortSubscriptions::new(redirector, 4)
         };
@@ -262,10 +284,7 @@ impl TcpConnectionStealer {
     /// Handles a new remote connection that was stolen by [`Self::port_subscriptions`].
     #[tracing::instrument(level = "trace", skip(self))]
     async fn incoming_connection(&mut self, stream: TcpStream, peer: SocketAddr) -> Result<()> {
-        let mut real_address = orig_dst::orig_dst_addr(&stream)?;
-        // If we use the original IP we would go through prerouting and hit a loop.
-        // localhost should always work.
-        real_address.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let real_address = self.port_subscriptions.passthrough_destination(&stream)?;
 
         let Some(port_subscription) = self.port_subscriptions.get(real_address.port()).cloned()
         else {
@@ -320,6 +339,7 @@ impl TcpConnectionStealer {
             ConnectionMessageOut::SubscribedTcp {
                 client_id,
                 connection,
+                original_destination,
             } => {
                 let Some(client) = self.clients.get_mut(&client_id) else {
                     tracing::trace!(
@@ -340,7 +360,19 @@ impl TcpConnectionStealer {
                     .subscribed_connections
                     .insert(connection.connection_id);
 
-                let _ = client.tx.send(DaemonTcp::NewConnection(connection)).await;
+                let message = if client
+                    .capabilities
+                    .contains(Capabilities::ORIGINAL_DESTINATION)
+                {
+                    DaemonTcp::NewConnectionV2(NewTcpConnectionV2 {
+                        connection,
+                        original_destination,
+                    })
+                } else {
+                    DaemonTcp::NewConnection(connection)
+                };
+
+                let _ = client.tx.send(message).await;
             }
 
             ConnectionMessageOut::SubscribedHttp {
@@ -522,6 +554,7 @@ impl TcpConnectionStealer {
                     client_id,
                     Client {
                         tx: daemon_tx,
+                        capabilities: Capabilities::from_version(&protocol_version),
                         protocol_version,
                         subscribed_connections: Default::default(),
                     },
@@ -543,6 +576,15 @@ impl TcpConnectionStealer {
                     .await;
             }
 
+            Command::ConnectionPause(connection_id, paused) => {
+                self.connections
+                    .send(
+                        connection_id,
+                        ConnectionMessageIn::Pause { client_id, paused },
+                    )
+                    .await;
+            }
+
             Command::PortSubscribe(port_steal) => {
                 self.port_subscribe(client_id, port_steal).await?
             }
@@ -574,8 +616,14 @@ impl TcpConnectionStealer {
 
             Command::SwitchProtocolVersion(new_version) => {
                 let client = self.clients.get_mut(&client_id).expect("client not found");
+                client.capabilities = Capabilities::from_version(&new_version);
                 client.protocol_version = new_version;
             }
+
+            Command::SwitchCapabilities(capabilities) => {
+                let client = self.clients.get_mut(&client_id).expect("client not found");
+                client.capabilities = capabilities;
+            }
         }
 
         Ok(())
@@ -52,6 +52,13 @@ pub enum ConnectionMessageIn {
     /// [`LayerTcpSteal::ConnectionUnsubscribe](mirrord_protocol::tcp::LayerTcpSteal::ConnectionUnsubscribe)
     /// coming from the layer.
     Unsubscribed { client_id: ClientId },
+    /// Client can't keep up with the data of the connection (`paused`), or caught up with it.
+    ///
+    /// This variant translates to
+    /// [`LayerTcpSteal::ConnectionPause`](mirrord_protocol::tcp::LayerTcpSteal::ConnectionPause)
+    /// or [`LayerTcpSteal::ConnectionResume`](mirrord_protocol::tcp::LayerTcpSteal::ConnectionResume)
+    /// coming from the layer.
+    Pause { client_id: ClientId, paused: bool },
 }
 
 impl fmt::Debug for ConnectionMessageIn {
@@ -86,6 +93,11 @@ impl fmt::Debug for ConnectionMessageIn {
                 debug_struct.field("type", &"Unsubscribed");
                 debug_struct.field("client_id", client_id);
             }
+            Self::Pause { client_id, paused } => {
+                debug_struct.field("type", &"Pause");
+                debug_struct.field("client_id", client_id);
+                debug_struct.field("paused", paused);
+            }
         }
 
         debug_struct.finish()
@@ -100,6 +112,7 @@ impl ConnectionMessageIn {
             Self::Response { client_id, .. } => *client_id,
             Self::ResponseFailed { client_id, .. } => *client_id,
             Self::Unsubscribed { client_id } => *client_id,
+            Self::Pause { client_id, .. } => *client_id,
         }
     }
 }
@@ -137,7 +150,9 @@ pub enum ConnectionMessageOut {
     /// Subscribed the client to a new TCP connection.
     ///
     /// This variant translates to
-    /// [`DaemonTcp::NewConnection`](mirrord_protocol::tcp::DaemonTcp::NewConnection).
+    /// [`DaemonTcp::NewConnection`](mirrord_protocol::tcp::DaemonTcp::NewConnection), or
+    /// [`DaemonTcp::NewConnectionV2`](mirrord_protocol::tcp::DaemonTcp::NewConnectionV2) when the
+    /// client supports it.
     ///
     /// # Note
     ///
@@ -147,6 +162,8 @@ pub enum ConnectionMessageOut {
     SubscribedTcp {
         client_id: ClientId,
         connection: NewTcpConnection,
+        /// [`StolenConnection::destination`].
+        original_destination: SocketAddr,
     },
     /// Subscribed the client to a new filtered HTTP connection.
     ///
@@ -208,11 +225,13 @@ impl fmt::Debug for ConnectionMessageOut {
             Self::SubscribedTcp {
                 client_id,
                 connection,
+                original_destination,
             } => {
                 debug_struct.field("type", &"SubscribedTcp");
                 debug_struct.field("connection_id", &connection.connection_id);
                 debug_struct.field("client_id", client_id);
                 debug_struct.field("connection", connection);
+                debug_struct.field("original_destination", original_destination);
             }
             Self::SubscribedHttp {
                 client_id,
@@ -455,6 +474,7 @@ impl ConnectionTask {
                             source_port: self.connection.source.port(),
                             local_address: self.connection.stream.local_addr()?.ip(),
                         },
+                        original_destination: self.connection.destination,
                     })
                     .await?;
 
@@ -626,6 +626,10 @@ where
                         self.subscribed.insert(client_id, false);
                         self.blocked_requests.retain(|key, _| key.0 != client_id);
                     },
+                    // Only raw data is paused, HTTP requests are delivered one at a time anyway.
+                    ConnectionMessageIn::Pause { client_id, paused } => {
+                        tracing::trace!(client_id, paused, connection_id = self.connection_id, "Ignoring pause of an HTTP connection");
+                    },
                 },
 
                 request = self.requests_rx.recv() => match request {
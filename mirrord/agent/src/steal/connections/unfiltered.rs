@@ -27,6 +27,9 @@ impl<T: AsyncRead + AsyncWrite + Unpin> UnfilteredStealTask<T> {
     /// Does not send [`ConnectionMessageOut::SubscribedTcp`], assuming that this message has
     /// already been sent to the client. This allows this code to be used for both unfiltered
     /// and upgraded connections.
+    ///
+    /// Stops reading from the connection while the client paused it with
+    /// [`ConnectionMessageIn::Pause`], so that TCP flow control slows down the peer.
     pub async fn run(
         mut self,
         tx: Sender<ConnectionMessageOut>,
@@ -34,10 +37,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin> UnfilteredStealTask<T> {
     ) -> Result<(), ConnectionTaskError> {
         let mut buf = BytesMut::with_capacity(64 * 1024);
         let mut reading_closed = false;
+        let mut paused = false;
 
         loop {
             tokio::select! {
-                read = self.stream.read_buf(&mut buf), if !reading_closed => match read {
+                read = self.stream.read_buf(&mut buf), if !reading_closed && !paused => match read {
                     Ok(..) => {
                         if buf.is_empty() {
                             tracing::trace!(
@@ -117,6 +121,17 @@ impl<T: AsyncRead + AsyncWrite + Unpin> UnfilteredStealTask<T> {
                     ConnectionMessageIn::Unsubscribed { .. } => {
                         return Ok(());
                     }
+
+                    ConnectionMessageIn::Pause { paused: pause, .. } => {
+                        tracing::trace!(
+                            client_id = self.client_id,
+                            connection_id = self.connection_id,
+                            paused = pause,
+                            "Client paused or resumed the connection",
+                        );
+
+                        paused = pause;
+                    }
                 }
             }
         }
@@ -125,6 +140,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> UnfilteredStealTask<T> {
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use tokio::{
         net::{TcpListener, TcpStream},
         sync::mpsc,
@@ -190,4 +207,69 @@ mod test {
         let msg = out_rx.recv().await;
         assert!(msg.is_none());
     }
+
+    #[tokio::test]
+    async fn paused_does_not_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (mut client_stream, (server_stream, _)) =
+            tokio::try_join!(TcpStream::connect(addr), listener.accept(),).unwrap();
+
+        let (in_tx, mut in_rx) = mpsc::channel(8);
+        let (out_tx, mut out_rx) = mpsc::channel(8);
+
+        let handle = tokio::spawn(async move {
+            let task = UnfilteredStealTask {
+                connection_id: 1,
+                client_id: 2,
+                stream: server_stream,
+            };
+
+            task.run(out_tx, &mut in_rx).await.unwrap();
+        });
+
+        in_tx
+            .send(ConnectionMessageIn::Pause {
+                client_id: 2,
+                paused: true,
+            })
+            .await
+            .unwrap();
+        // Lets the task handle the pause before the peer writes.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client_stream.write_all(b"bytes from peer").await.unwrap();
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), out_rx.recv())
+                .await
+                .is_err()
+        );
+
+        in_tx
+            .send(ConnectionMessageIn::Pause {
+                client_id: 2,
+                paused: false,
+            })
+            .await
+            .unwrap();
+
+        let msg = out_rx.recv().await.unwrap();
+        let data = match msg {
+            ConnectionMessageOut::Raw {
+                client_id: 2,
+                connection_id: 1,
+                data,
+            } => data,
+            other => unreachable!("unexpected message: {other:?}"),
+        };
+        assert_eq!(data, b"bytes from peer");
+
+        in_tx
+            .send(ConnectionMessageIn::Unsubscribed { client_id: 2 })
+            .await
+            .unwrap();
+
+        handle.await.unwrap();
+    }
 }
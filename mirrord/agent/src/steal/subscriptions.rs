@@ -1,7 +1,9 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    net::{Ipv4Addr, SocketAddr},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    future, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
+    task::Poll,
 };
 
 use dashmap::{mapref::entry::Entry as DashMapEntry, DashMap};
@@ -11,8 +13,9 @@ use tokio::net::{TcpListener, TcpStream};
 use super::{
     http::HttpFilter,
     ip_tables::{new_iptables, IPTablesWrapper, SafeIpTables},
+    orig_dst,
 };
-use crate::{error::AgentError, util::ClientId};
+use crate::{cli::SidecarListeners, error::AgentError, util::ClientId};
 
 /// For stealing incoming TCP connections.
 #[async_trait::async_trait]
@@ -45,6 +48,45 @@ pub trait PortRedirector {
     /// * [`TcpStream`] - redirected connection
     /// * [`SocketAddr`] - peer address
     async fn next_connection(&mut self) -> Result<(TcpStream, SocketAddr), Self::Error>;
+
+    /// Returns the address where a connection from [`PortRedirector::next_connection`] is passed
+    /// through to, when it's not stolen (e.g. unmatched HTTP requests).
+    fn passthrough_destination(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        let mut destination = orig_dst::orig_dst_addr(stream)?;
+        // If we use the original IP we would go through prerouting and hit a loop.
+        // localhost should always work.
+        destination.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        Ok(destination)
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> PortRedirector for Box<R>
+where
+    R: PortRedirector + Send + ?Sized,
+{
+    type Error = R::Error;
+
+    async fn add_redirection(&mut self, from: Port) -> Result<(), Self::Error> {
+        R::add_redirection(self, from).await
+    }
+
+    async fn remove_redirection(&mut self, from: Port) -> Result<(), Self::Error> {
+        R::remove_redirection(self, from).await
+    }
+
+    async fn cleanup(&mut self) -> Result<(), Self::Error> {
+        R::cleanup(self).await
+    }
+
+    async fn next_connection(&mut self) -> Result<(TcpStream, SocketAddr), Self::Error> {
+        R::next_connection(self).await
+    }
+
+    fn passthrough_destination(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        R::passthrough_destination(self, stream)
+    }
 }
 
 /// Implementation of [`PortRedirector`] that manipulates iptables to steal connections by
@@ -124,6 +166,133 @@ impl PortRedirector for IpTablesRedirector {
     }
 }
 
+/// Implementation of [`PortRedirector`] for agents that can't alter iptables (sidecar mode), where
+/// the target container is not running. Binds the stolen ports directly.
+///
+/// When given the IP of the target pod, this is also a reverse proxy in front of it: the ports of
+/// the target container are bound from the start, and their connections are forwarded to the
+/// target pod while they're not stolen, so that the copy can get the target's labels.
+pub(crate) struct ListenerRedirector {
+    /// Address the listeners are bound to, the pod IP.
+    ///
+    /// Not [`Ipv4Addr::UNSPECIFIED`], so that connecting to the original destination at localhost
+    /// (when passing a connection through without a target pod) fails, instead of looping back to
+    /// us.
+    ip: IpAddr,
+    /// IP of the target pod, where the connections that are not stolen are forwarded.
+    forward_to: Option<IpAddr>,
+    /// Ports that are bound even when they're not stolen, see [`Self::forward_to`].
+    forward_ports: HashSet<Port>,
+    listeners: HashMap<Port, TcpListener>,
+    /// Ports whose connections are returned from [`PortRedirector::next_connection`].
+    stolen: HashSet<Port>,
+}
+
+impl ListenerRedirector {
+    /// Binds the [`SidecarListeners::forward_ports`], when given
+    /// [`SidecarListeners::forward_to`].
+    pub(crate) async fn new(
+        SidecarListeners {
+            pod_ip,
+            forward_to,
+            forward_ports,
+        }: SidecarListeners,
+    ) -> Result<Self, AgentError> {
+        let forward_ports: HashSet<Port> = forward_to
+            .map(|_| forward_ports.into_iter().collect())
+            .unwrap_or_default();
+
+        let mut listeners = HashMap::with_capacity(forward_ports.len());
+        for port in &forward_ports {
+            listeners.insert(*port, TcpListener::bind((pod_ip, *port)).await?);
+        }
+
+        Ok(Self {
+            ip: pod_ip,
+            forward_to,
+            forward_ports,
+            listeners,
+            stolen: Default::default(),
+        })
+    }
+}
+
+/// Forwards the connection `stream` to the target pod at `destination`, see
+/// [`ListenerRedirector`].
+async fn forward_connection(mut stream: TcpStream, destination: SocketAddr) {
+    let result = async {
+        let mut target_stream = TcpStream::connect(destination).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut target_stream).await
+    }
+    .await;
+
+    if let Err(error) = result {
+        tracing::debug!(%destination, %error, "Forwarding a connection to the target failed");
+    }
+}
+
+#[async_trait::async_trait]
+impl PortRedirector for ListenerRedirector {
+    type Error = AgentError;
+
+    async fn add_redirection(&mut self, from: Port) -> Result<(), Self::Error> {
+        if let Entry::Vacant(entry) = self.listeners.entry(from) {
+            entry.insert(TcpListener::bind((self.ip, from)).await?);
+        }
+        self.stolen.insert(from);
+
+        Ok(())
+    }
+
+    async fn remove_redirection(&mut self, from: Port) -> Result<(), Self::Error> {
+        self.stolen.remove(&from);
+        if !self.forward_ports.contains(&from) {
+            self.listeners.remove(&from);
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup(&mut self) -> Result<(), Self::Error> {
+        self.stolen.clear();
+        self.listeners
+            .retain(|port, _| self.forward_ports.contains(port));
+
+        Ok(())
+    }
+
+    async fn next_connection(&mut self) -> Result<(TcpStream, SocketAddr), Self::Error> {
+        future::poll_fn(|cx| {
+            for (port, listener) in &self.listeners {
+                while let Poll::Ready(result) = listener.poll_accept(cx) {
+                    let (stream, peer) = result?;
+
+                    if self.stolen.contains(port) {
+                        return Poll::Ready(Ok((stream, peer)));
+                    }
+
+                    if let Some(forward_to) = self.forward_to {
+                        tokio::spawn(forward_connection(
+                            stream,
+                            SocketAddr::new(forward_to, *port),
+                        ));
+                    }
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn passthrough_destination(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        let port = stream.local_addr()?.port();
+        let ip = self.forward_to.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
 /// Set of active port subscriptions.
 pub struct PortSubscriptions<R: PortRedirector> {
     /// Used to implement stealing connections.
@@ -277,6 +446,11 @@ impl<R: PortRedirector> PortSubscriptions<R> {
     pub async fn next_connection(&mut self) -> Result<(TcpStream, SocketAddr), R::Error> {
         self.redirector.next_connection().await
     }
+
+    /// Call [`PortRedirector::passthrough_destination`] on the inner [`PortRedirector`].
+    pub fn passthrough_destination(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        self.redirector.passthrough_destination(stream)
+    }
 }
 
 /// Steal subscription for a port.
@@ -304,14 +478,28 @@ impl PortSubscription {
 
     /// Try extending this subscription with a new subscription request.
     /// Return whether extension was successful.
+    ///
+    /// A client that already has a filter for this port replaces it, which is how filters are
+    /// updated during a session (see
+    /// [`STEAL_FILTER_UPDATE_VERSION`](mirrord_protocol::tcp::STEAL_FILTER_UPDATE_VERSION)). The
+    /// connections stolen so far use the new filter for their next requests.
+    ///
+    /// A client that already stole this port without a filter can subscribe to it again without a
+    /// filter, so it can check that it still holds the port (see
+    /// [`Capabilities::STEAL_RESUBSCRIBE`](mirrord_protocol::capabilities::Capabilities::STEAL_RESUBSCRIBE)).
     fn try_extend(&mut self, client_id: ClientId, filter: Option<HttpFilter>) -> bool {
         match (self, filter) {
+            (Self::Unfiltered(subscribed_client), None) => *subscribed_client == client_id,
+
             (_, None) => false,
 
             (Self::Unfiltered(..), _) => false,
 
             (Self::Filtered(filters), Some(filter)) => match filters.entry(client_id) {
-                DashMapEntry::Occupied(..) => false,
+                DashMapEntry::Occupied(mut e) => {
+                    e.insert(filter);
+                    true
+                }
                 DashMapEntry::Vacant(e) => {
                     e.insert(filter);
                     true
@@ -333,6 +521,8 @@ impl PortSubscription {
 mod test {
     use std::collections::HashSet;
 
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
     use super::*;
 
     /// Implementation of [`PortRedirector`] that stores redirections in memory.
@@ -420,11 +610,8 @@ mod test {
         let sub = subscriptions.get(80).unwrap();
         assert!(matches!(sub, PortSubscription::Unfiltered(0)), "{sub:?}");
 
-        // Same client cannot subscribe again (unfiltered).
-        assert_eq!(
-            subscriptions.add(0, 80, None).await.unwrap(),
-            Err(ResponseError::PortAlreadyStolen(80)),
-        );
+        // Same client can subscribe again (unfiltered), nothing changes.
+        assert_eq!(subscriptions.add(0, 80, None).await.unwrap(), Ok(80));
         check_redirector!(subscriptions.redirector, 80);
         let sub = subscriptions.get(80).unwrap();
         assert!(matches!(sub, PortSubscription::Unfiltered(0)), "{sub:?}");
@@ -496,18 +683,28 @@ mod test {
             "{sub:?}"
         );
 
-        // Same client cannot subscribe again (filtered).
-        assert_eq!(
-            subscriptions
-                .add(0, 80, Some(dummy_filter()))
-                .await
-                .unwrap(),
-            Err(ResponseError::PortAlreadyStolen(80)),
-        );
+        // Same client can replace its filter.
+        subscriptions
+            .add(
+                0,
+                80,
+                Some(HttpFilter::Header("x-user: bear".parse().unwrap())),
+            )
+            .await
+            .unwrap()
+            .unwrap();
         check_redirector!(subscriptions.redirector, 80);
         let sub = subscriptions.get(80).unwrap();
         assert!(
-            matches!(sub, PortSubscription::Filtered(filters) if filters.len() == 1),
+            matches!(
+                sub,
+                PortSubscription::Filtered(filters)
+                    if filters.len() == 1
+                        && matches!(
+                            filters.get(&0).as_deref(),
+                            Some(HttpFilter::Header(filter)) if filter.as_str() == "x-user: bear"
+                        )
+            ),
             "{sub:?}"
         );
 
@@ -637,4 +834,92 @@ mod test {
         let sub = subscriptions.get(81);
         assert!(sub.is_none(), "{sub:?}");
     }
+
+    #[tokio::test]
+    async fn listener_redirector() {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut redirector = ListenerRedirector::new(SidecarListeners {
+            pod_ip: Ipv4Addr::LOCALHOST.into(),
+            forward_to: None,
+            forward_ports: vec![port],
+        })
+        .await
+        .unwrap();
+        // Without a target pod, the ports are bound only when stolen.
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .is_err());
+
+        redirector.add_redirection(port).await.unwrap();
+        // Adding the same port again is a no-op.
+        redirector.add_redirection(port).await.unwrap();
+
+        let client = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let (stream, peer) = redirector.next_connection().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert_eq!(
+            redirector.passthrough_destination(&stream).unwrap(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+        );
+
+        redirector.remove_redirection(port).await.unwrap();
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .is_err());
+    }
+
+    /// Connections to the ports of the target container reach the target pod while the ports are
+    /// not stolen.
+    #[tokio::test]
+    async fn listener_redirector_forwards() {
+        let target_ip = Ipv4Addr::new(127, 0, 0, 2);
+        let target = TcpListener::bind((target_ip, 0)).await.unwrap();
+        let port = target.local_addr().unwrap().port();
+
+        let mut redirector = ListenerRedirector::new(SidecarListeners {
+            pod_ip: Ipv4Addr::LOCALHOST.into(),
+            forward_to: Some(target_ip.into()),
+            forward_ports: vec![port],
+        })
+        .await
+        .unwrap();
+
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+        tokio::select! {
+            _ = redirector.next_connection() => panic!("the port is not stolen"),
+            accepted = target.accept() => {
+                let (mut stream, _) = accepted.unwrap();
+                let mut buffer = [0; 5];
+                stream.read_exact(&mut buffer).await.unwrap();
+                assert_eq!(&buffer, b"hello");
+            }
+        }
+
+        redirector.add_redirection(port).await.unwrap();
+        let _client = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let (stream, _) = redirector.next_connection().await.unwrap();
+        assert_eq!(
+            redirector.passthrough_destination(&stream).unwrap(),
+            SocketAddr::from((target_ip, port))
+        );
+
+        // Still bound, forwarding again.
+        redirector.remove_redirection(port).await.unwrap();
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+    }
 }
@@ -1,6 +1,7 @@
 use std::{collections::HashMap, time::Instant};
 
 use base64::{engine::general_purpose, Engine as _};
+use opentelemetry::{global, KeyValue};
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
@@ -29,6 +30,19 @@ pub enum AnalyticsError {
     Unknown,
 }
 
+impl AnalyticsError {
+    /// Name of the error, as it's sent in the report.
+    fn name(self) -> &'static str {
+        match self {
+            Self::AgentConnection => "agent_connection",
+            Self::EnvFetch => "env_fetch",
+            Self::BinaryExecuteFailed => "binary_execute_failed",
+            Self::IntProxyFirstConnection => "int_proxy_first_connection",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
 /// Struct to store analytics data.
 /// Example usage that would output the following json
 /// ```json
@@ -77,6 +91,24 @@ impl Analytics {
     pub fn add<Key: ToString, Value: Into<AnalyticValue>>(&mut self, key: Key, value: Value) {
         self.data.insert(key.to_string(), value.into());
     }
+
+    /// Adds the data to `attributes`, with the keys of nested values joined by `.`.
+    fn collect_attributes(&self, prefix: Option<&str>, attributes: &mut Vec<KeyValue>) {
+        for (key, value) in &self.data {
+            let key = match prefix {
+                Some(prefix) => format!("{prefix}.{key}"),
+                None => key.clone(),
+            };
+
+            match value {
+                AnalyticValue::Bool(value) => attributes.push(KeyValue::new(key, *value)),
+                AnalyticValue::Number(value) => {
+                    attributes.push(KeyValue::new(key, i64::from(*value)))
+                }
+                AnalyticValue::Nested(nested) => nested.collect_attributes(Some(&key), attributes),
+            }
+        }
+    }
 }
 
 /// Type safe abstraction for Bytes to send hash values, should be explicitly created so we woun't
@@ -245,8 +277,14 @@ impl Reporter for NullReporter {
 /// meaning it will wait for all ongoing tasks to finish before exiting.
 impl Drop for AnalyticsReporter {
     fn drop(&mut self) {
-        if self.enabled && (self.error.is_some() || !self.error_only_send) {
-            let report = self.as_report();
+        if self.error.is_none() && self.error_only_send {
+            return;
+        }
+
+        let report = self.as_report();
+        record_metrics(&report);
+
+        if self.enabled {
             let watch = self.watch.clone();
             tokio::spawn(async move {
                 send_analytics(report).await;
@@ -280,6 +318,36 @@ struct AnalyticsReport {
     error: Option<AnalyticsError>,
 }
 
+/// Counts the `report` in the `mirrord.sessions` (and `mirrord.session.errors`) OpenTelemetry
+/// counters, with its data as attributes.
+///
+/// Does nothing unless an exporter was installed (with `otlp_endpoint`), and doesn't depend on
+/// [`AnalyticsReporter::enabled`], the counters never reach our servers.
+fn record_metrics(report: &AnalyticsReport) {
+    let meter = global::meter("mirrord");
+
+    let mut attributes = vec![
+        KeyValue::new("platform", report.platform),
+        KeyValue::new("version", report.version),
+        KeyValue::new("operator", report.operator),
+    ];
+    report
+        .event_properties
+        .collect_attributes(None, &mut attributes);
+    meter
+        .u64_counter("mirrord.sessions")
+        .init()
+        .add(1, &attributes);
+
+    if let Some(error) = report.error {
+        attributes.push(KeyValue::new("error", error.name()));
+        meter
+            .u64_counter("mirrord.session.errors")
+            .init()
+            .add(1, &attributes);
+    }
+}
+
 /// Actualy send `Analytics` & `AnalyticsOperatorProperties` to analytics.metalbear.co
 #[tracing::instrument(level = "trace")]
 async fn send_analytics(report: AnalyticsReport) {
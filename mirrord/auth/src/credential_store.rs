@@ -12,7 +12,7 @@ use tokio::{
     fs,
     io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom},
 };
-use tracing::info;
+use tracing::{debug, info, warn};
 use whoami::fallible;
 
 use crate::{
@@ -32,6 +32,12 @@ static CREDENTIALS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
 /// "~/.mirrord/credentials"
 static CREDENTIALS_PATH: LazyLock<PathBuf> = LazyLock::new(|| CREDENTIALS_DIR.join("credentials"));
 
+/// Service of the OS keychain entry that holds the [`CredentialStore`].
+const KEYCHAIN_SERVICE: &str = "mirrord";
+
+/// Account of the OS keychain entry that holds the [`CredentialStore`].
+const KEYCHAIN_ACCOUNT: &str = "operator-credentials";
+
 /// Container that is responsible for creating/loading `Credentials`
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct CredentialStore {
@@ -167,13 +173,21 @@ impl CredentialStore {
     }
 }
 
-/// Exposes methods to safely access [`CredentialStore`] stored in a file.
+/// Exposes methods to safely access [`CredentialStore`] stored in the OS keychain (macOS Keychain,
+/// Secret Service, Windows Credential Manager), or in a file.
+///
+/// The file is locked while the store is accessed either way. When the keychain is used, a store
+/// found in the file is moved to the keychain, and the file is left empty. When the keychain is
+/// not available, the file is used.
 pub struct CredentialStoreSync {
     store_file: fs::File,
+    /// Where the store is kept when the keychain is used.
+    keychain: Option<keyring::Entry>,
 }
 
 impl CredentialStoreSync {
-    pub async fn open() -> Result<Self> {
+    /// Opens the store, in the OS keychain when `use_keychain` is set.
+    pub async fn open(use_keychain: bool) -> Result<Self> {
         if !CREDENTIALS_DIR.exists() {
             fs::create_dir_all(&*CREDENTIALS_DIR)
                 .await
@@ -189,7 +203,78 @@ impl CredentialStoreSync {
             .await
             .map_err(CertificateStoreError::from)?;
 
-        Ok(Self { store_file })
+        let keychain = match use_keychain
+            .then(|| keyring::Entry::new(KEYCHAIN_SERVICE, KEYCHAIN_ACCOUNT))
+            .transpose()
+        {
+            Ok(keychain) => keychain,
+            Err(error) => {
+                warn!(%error, "OS keychain unavailable, using {CREDENTIALS_PATH:?}");
+                None
+            }
+        };
+
+        Ok(Self {
+            store_file,
+            keychain,
+        })
+    }
+
+    /// Loads the store from the keychain, or from the file when the keychain has none (yet).
+    async fn load_store(&mut self) -> CredentialStore {
+        if let Some(keychain) = &self.keychain {
+            match keychain.get_password() {
+                Ok(store) => {
+                    return serde_yaml::from_str(&store)
+                        .inspect_err(|err| info!("CredentialStore Load Error {err:?}"))
+                        .unwrap_or_default();
+                }
+                Err(keyring::Error::NoEntry) => {
+                    debug!("no CredentialStore in the OS keychain, loading {CREDENTIALS_PATH:?}");
+                }
+                Err(error) => {
+                    warn!(%error, "OS keychain unavailable, using {CREDENTIALS_PATH:?}");
+                    self.keychain = None;
+                }
+            }
+        }
+
+        CredentialStore::load(&mut self.store_file)
+            .await
+            .inspect_err(|err| info!("CredentialStore Load Error {err:?}"))
+            .unwrap_or_default()
+    }
+
+    /// Saves the store to the keychain and empties the file, or saves it to the file when the
+    /// keychain fails.
+    async fn save_store(&mut self, store: &CredentialStore) -> Result<()> {
+        if let Some(keychain) = &self.keychain {
+            let buffer = serde_yaml::to_string(store).map_err(CertificateStoreError::from)?;
+
+            match keychain.set_password(&buffer) {
+                Ok(()) => {
+                    return self
+                        .store_file
+                        .set_len(0)
+                        .await
+                        .map_err(CertificateStoreError::from)
+                        .map_err(AuthenticationError::from);
+                }
+                Err(error) => {
+                    warn!(%error, "failed to save to the OS keychain, using {CREDENTIALS_PATH:?}");
+                    // So that the next load doesn't get a stale store from the keychain.
+                    let _ = keychain.delete_password();
+                }
+            }
+        }
+
+        // Make sure the store_file's cursor is at the start of the file before sending it to save
+        self.store_file
+            .seek(SeekFrom::Start(0))
+            .await
+            .map_err(CertificateStoreError::from)?;
+
+        store.save(&mut self.store_file).await
     }
 
     /// Try and get/create a specific client certificate.
@@ -207,10 +292,7 @@ impl CredentialStoreSync {
         R::DynamicType: Default,
         C: FnOnce(&mut Credentials) -> V,
     {
-        let mut store = CredentialStore::load(&mut self.store_file)
-            .await
-            .inspect_err(|err| info!("CredentialStore Load Error {err:?}"))
-            .unwrap_or_default();
+        let mut store = self.load_store().await;
 
         let value = callback(
             store
@@ -218,13 +300,7 @@ impl CredentialStoreSync {
                 .await?,
         );
 
-        // Make sure the store_file's cursor is at the start of the file before sending it to save
-        self.store_file
-            .seek(SeekFrom::Start(0))
-            .await
-            .map_err(CertificateStoreError::from)?;
-
-        store.save(&mut self.store_file).await?;
+        self.save_store(&store).await?;
 
         Ok(value)
     }
@@ -261,3 +337,128 @@ impl CredentialStoreSync {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use keyring::{mock::MockCredential, Entry};
+
+    use super::*;
+
+    /// An entry in a mock keychain, each one is independent of the others.
+    fn keychain() -> Entry {
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+        Entry::new(KEYCHAIN_SERVICE, KEYCHAIN_ACCOUNT).unwrap()
+    }
+
+    fn open(keychain: Option<Entry>) -> CredentialStoreSync {
+        CredentialStoreSync {
+            store_file: fs::File::from_std(tempfile::tempfile().unwrap()),
+            keychain,
+        }
+    }
+
+    /// A store that is told apart by the `subscription_id` of its signing key.
+    fn store(subscription_id: &str) -> CredentialStore {
+        CredentialStore {
+            signing_keys: HashMap::from([(subscription_id.to_string(), KeyPair::from("key"))]),
+            ..Default::default()
+        }
+    }
+
+    async fn file_contents(store_sync: &mut CredentialStoreSync) -> String {
+        let mut contents = String::new();
+        store_sync
+            .store_file
+            .seek(SeekFrom::Start(0))
+            .await
+            .unwrap();
+        store_sync
+            .store_file
+            .read_to_string(&mut contents)
+            .await
+            .unwrap();
+        store_sync
+            .store_file
+            .seek(SeekFrom::Start(0))
+            .await
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn keychain_store() {
+        let mut store_sync = open(Some(keychain()));
+
+        store_sync.save_store(&store("sub-1")).await.unwrap();
+        assert!(file_contents(&mut store_sync).await.is_empty());
+        let saved = store_sync
+            .keychain
+            .as_ref()
+            .unwrap()
+            .get_password()
+            .unwrap();
+        assert!(saved.contains("sub-1"));
+
+        let loaded = store_sync.load_store().await;
+        assert!(loaded.signing_keys.contains_key("sub-1"));
+    }
+
+    /// Without a keychain, or when saving to it fails, the store is kept in the file.
+    #[tokio::test]
+    async fn file_fallback() {
+        let mut store_sync = open(None);
+        store_sync.save_store(&store("sub-1")).await.unwrap();
+        assert!(file_contents(&mut store_sync).await.contains("sub-1"));
+        assert!(store_sync
+            .load_store()
+            .await
+            .signing_keys
+            .contains_key("sub-1"));
+
+        let keychain = keychain();
+        keychain.set_password("stale").unwrap();
+        let mock: &MockCredential = keychain.get_credential().downcast_ref().unwrap();
+        mock.set_error(keyring::Error::PlatformFailure("locked".into()));
+
+        let mut store_sync = open(Some(keychain));
+        store_sync.save_store(&store("sub-2")).await.unwrap();
+        assert!(file_contents(&mut store_sync).await.contains("sub-2"));
+        assert!(matches!(
+            store_sync.keychain.as_ref().unwrap().get_password(),
+            Err(keyring::Error::NoEntry)
+        ));
+        assert!(store_sync
+            .load_store()
+            .await
+            .signing_keys
+            .contains_key("sub-2"));
+    }
+
+    /// A store found in the file is moved to the keychain.
+    #[tokio::test]
+    async fn migrates_file_to_keychain() {
+        let mut store_sync = open(Some(keychain()));
+        store("sub-1")
+            .save(&mut store_sync.store_file)
+            .await
+            .unwrap();
+        store_sync
+            .store_file
+            .seek(SeekFrom::Start(0))
+            .await
+            .unwrap();
+
+        let loaded = store_sync.load_store().await;
+        assert!(loaded.signing_keys.contains_key("sub-1"));
+
+        store_sync.save_store(&loaded).await.unwrap();
+        assert!(file_contents(&mut store_sync).await.is_empty());
+        let saved = store_sync
+            .keychain
+            .as_ref()
+            .unwrap()
+            .get_password()
+            .unwrap();
+        assert!(saved.contains("sub-1"));
+    }
+}
@@ -26,6 +26,13 @@ pub struct Credentials {
 }
 
 impl Credentials {
+    pub(crate) fn new(certificate: Certificate, key_pair: KeyPair) -> Self {
+        Self {
+            certificate,
+            key_pair,
+        }
+    }
+
     /// Returns the key pair used to sign certification requests.
     pub fn key_pair(&self) -> &KeyPair {
         &self.key_pair
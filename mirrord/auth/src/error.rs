@@ -23,6 +23,10 @@ pub enum AuthenticationError {
     #[cfg(feature = "client")]
     #[error(transparent)]
     Kube(#[from] kube::Error),
+
+    #[cfg(feature = "client")]
+    #[error(transparent)]
+    CertificateProvider(#[from] CertificateProviderError),
 }
 
 /// Error from CredentialStore operations
@@ -39,5 +43,34 @@ pub enum CertificateStoreError {
     Yaml(#[from] serde_yaml::Error),
 }
 
+/// Error from running the certificate provider command
+#[cfg(feature = "client")]
+#[derive(Debug, Error)]
+pub enum CertificateProviderError {
+    #[error("The certificate provider command is empty")]
+    EmptyCommand,
+
+    #[error("Unable to run the certificate provider command: {0}")]
+    Spawn(std::io::Error),
+
+    #[error("The certificate provider command did not finish within {0:?}")]
+    Timeout(std::time::Duration),
+
+    #[error("The certificate provider command failed ({status}): {stderr}")]
+    Failed {
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+
+    #[error("Unable to deserialize the certificate provider command output: {0}")]
+    Output(serde_yaml::Error),
+
+    #[error("The certificate from the certificate provider command is expired or not yet valid")]
+    Expired,
+
+    #[error("The key from the certificate provider command doesn't match the certificate")]
+    KeyMismatch,
+}
+
 /// `Result` with `AuthenticationError` as default error
 pub type Result<T, E = AuthenticationError> = std::result::Result<T, E>;
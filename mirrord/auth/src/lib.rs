@@ -4,9 +4,12 @@
 pub use pem;
 pub use x509_certificate;
 
+/// Client certificates issued by an external command (`auth.cert_provider_command`)
+#[cfg(feature = "client")]
+pub mod cert_provider;
 /// X509 Certificate abstraction for serialization and deserialization
 pub mod certificate;
-/// FileSystem based storage for multiple credentials (default contents "~/.mirrord/credentials")
+/// Storage for multiple credentials, in the OS keychain or in "~/.mirrord/credentials"
 #[cfg(feature = "client")]
 pub mod credential_store;
 /// Credentials used to create from and validate against Operator License
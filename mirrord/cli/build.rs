@@ -1,8 +1,23 @@
+use std::path::Path;
+
 fn main() {
+    println!("cargo:rerun-if-env-changed=MIRRORD_LAYER_FILE");
     if std::env::var("MIRRORD_LAYER_FILE").is_err() {
         println!(
             "cargo:rustc-env=MIRRORD_LAYER_FILE={}",
             std::env::var("CARGO_CDYLIB_FILE_MIRRORD_LAYER").unwrap()
         );
     };
+
+    // The layer built for the other architecture (Linux only), for children of that architecture.
+    // Embedded as an empty file when not given.
+    println!("cargo:rerun-if-env-changed=MIRRORD_LAYER_FILE_CROSS");
+    if std::env::var("MIRRORD_LAYER_FILE_CROSS").is_err() {
+        let empty = Path::new(&std::env::var("OUT_DIR").unwrap()).join("no_cross_layer");
+        std::fs::write(&empty, []).unwrap();
+        println!(
+            "cargo:rustc-env=MIRRORD_LAYER_FILE_CROSS={}",
+            empty.display()
+        );
+    }
 }
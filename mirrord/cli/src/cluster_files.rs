@@ -0,0 +1,267 @@
+//! `ConfigMap`s and `Secret`s of the target's namespace that the application reads under
+//! `/mirrord/configmaps/<name>/<key>` and `/mirrord/secrets/<name>/<key>`, see
+//! `feature.fs.config_maps` and `feature.fs.secrets`.
+//!
+//! They're fetched into a new local directory when the session starts, that only the user can
+//! read, in `$XDG_RUNTIME_DIR` (usually not on disk) when it's set. The layer finds it in
+//! [`CLUSTER_FILES_DIR_ENV`], and opens the files there instead. The directory is removed by a
+//! [`ClusterFilesGuard`], held by the CLI until the internal proxy starts, and then by the
+//! internal proxy, that lives as long as the session.
+use std::{
+    collections::BTreeMap,
+    fs::{DirBuilder, OpenOptions},
+    io,
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::Api;
+use mirrord_config::{feature::fs::CLUSTER_FILES_DIR_ENV, util::VecOrSingle, LayerConfig};
+use mirrord_kube::api::kubernetes::create_kube_api;
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::warn;
+
+use crate::error::{CliError, Result};
+
+/// Fetches the `ConfigMap`s and `Secret`s to a new local directory, and returns the guard that
+/// removes it.
+///
+/// [`None`] when none are configured.
+pub(crate) async fn fetch(config: &LayerConfig) -> Result<Option<ClusterFilesGuard>> {
+    let config_maps = config
+        .feature
+        .fs
+        .config_maps
+        .clone()
+        .map(VecOrSingle::to_vec)
+        .unwrap_or_default();
+    let secrets = config
+        .feature
+        .fs
+        .secrets
+        .clone()
+        .map(VecOrSingle::to_vec)
+        .unwrap_or_default();
+    if config_maps.is_empty() && secrets.is_empty() {
+        return Ok(None);
+    }
+
+    let client = create_kube_api(
+        config.accept_invalid_certificates,
+        config.kubeconfig.clone(),
+        config.kube_context.clone(),
+        config.proxy.clone(),
+        config.kube_api_url.clone(),
+    )
+    .await?;
+    let (config_map_api, secret_api): (Api<ConfigMap>, Api<Secret>) =
+        match config.target.namespace.as_deref() {
+            Some(namespace) => (
+                Api::namespaced(client.clone(), namespace),
+                Api::namespaced(client, namespace),
+            ),
+            None => (
+                Api::default_namespaced(client.clone()),
+                Api::default_namespaced(client),
+            ),
+        };
+
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!(
+            "mirrord-cluster-files-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 10)
+        ));
+    create_dir(&dir)?;
+    let guard = ClusterFilesGuard(dir);
+
+    write_all(&guard.0, &config_map_api, &secret_api, config_maps, secrets).await?;
+
+    Ok(Some(guard))
+}
+
+/// Writes the keys of every `ConfigMap` and `Secret` to `<dir>/configmaps/<name>/<key>` and
+/// `<dir>/secrets/<name>/<key>`.
+async fn write_all(
+    dir: &Path,
+    config_map_api: &Api<ConfigMap>,
+    secret_api: &Api<Secret>,
+    config_maps: Vec<String>,
+    secrets: Vec<String>,
+) -> Result<()> {
+    for name in config_maps {
+        if !is_file_name(&name) {
+            return Err(CliError::ClusterFileFetchFailed(
+                format!("ConfigMap `{name}`"),
+                "not a valid name".to_string(),
+            ));
+        }
+
+        let config_map = config_map_api.get(&name).await.map_err(|fail| {
+            CliError::ClusterFileFetchFailed(format!("ConfigMap `{name}`"), fail.to_string())
+        })?;
+
+        let data = config_map
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (key, value.into_bytes()))
+            .chain(
+                config_map
+                    .binary_data
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(key, value)| (key, value.0)),
+            )
+            .collect();
+        write_keys(&dir.join("configmaps").join(&name), data)?;
+    }
+
+    for name in secrets {
+        if !is_file_name(&name) {
+            return Err(CliError::ClusterFileFetchFailed(
+                format!("Secret `{name}`"),
+                "not a valid name".to_string(),
+            ));
+        }
+
+        let secret = secret_api.get(&name).await.map_err(|fail| {
+            CliError::ClusterFileFetchFailed(format!("Secret `{name}`"), fail.to_string())
+        })?;
+
+        let data = secret
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, value)| (key, value.0))
+            .collect();
+        write_keys(&dir.join("secrets").join(&name), data)?;
+    }
+
+    Ok(())
+}
+
+/// Writes every key to a file named after it in `dir`, readable only by the user.
+fn write_keys(dir: &Path, data: BTreeMap<String, Vec<u8>>) -> Result<()> {
+    create_dir(dir)?;
+
+    for (key, value) in data {
+        // Keys are validated by Kubernetes, this is just in case.
+        if !is_file_name(&key) {
+            warn!(key, ?dir, "skipping a key that is not a valid file name");
+            continue;
+        }
+
+        let path = dir.join(key);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&value))
+            .map_err(|fail| CliError::ClusterFilesWriteFailed(path, fail))?;
+    }
+
+    Ok(())
+}
+
+/// Whether `name` can be used as a single path component, without leaving its parent directory.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && name != "." && name != ".."
+}
+
+fn create_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|fail| CliError::ClusterFilesWriteFailed(dir.to_path_buf(), fail))
+}
+
+/// Removes the directory of [`fetch`] when dropped.
+///
+/// Held by the CLI until the internal proxy starts, and then by the internal proxy for the
+/// session, so that the directory is removed whenever one of them fails.
+pub(crate) struct ClusterFilesGuard(PathBuf);
+
+impl ClusterFilesGuard {
+    pub(crate) fn from_env() -> Option<Self> {
+        std::env::var_os(CLUSTER_FILES_DIR_ENV).map(|dir| Self(dir.into()))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Keeps the directory, the internal proxy started with its own guard.
+    pub(crate) fn hand_over(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for ClusterFilesGuard {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.0) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                warn!(%error, dir = ?self.0, "failed to remove the ConfigMaps and Secrets");
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("app.properties", true)]
+    #[case("..data", true)]
+    #[case("", false)]
+    #[case(".", false)]
+    #[case("..", false)]
+    #[case("../passwd", false)]
+    #[case("nested/key", false)]
+    fn file_names(#[case] name: &str, #[case] valid: bool) {
+        assert_eq!(is_file_name(name), valid);
+    }
+
+    #[test]
+    fn writes_private_keys() {
+        let root =
+            std::env::temp_dir().join(format!("mirrord-cluster-files-{}", std::process::id()));
+        let dir = root.join("secrets").join("db");
+
+        let data = BTreeMap::from([
+            ("password".to_string(), b"hunter2".to_vec()),
+            ("..".to_string(), b"escaped".to_vec()),
+            ("../escaped".to_string(), b"escaped".to_vec()),
+        ]);
+        write_keys(&dir, data).unwrap();
+
+        assert_eq!(fs::read(dir.join("password")).unwrap(), b"hunter2");
+        assert_eq!(
+            fs::metadata(dir.join("password"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(!root.join("secrets").join("escaped").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
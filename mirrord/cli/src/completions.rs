@@ -0,0 +1,104 @@
+//! `mirrord completions`, shell completions generated by clap.
+//!
+//! For bash, zsh and fish, the target of `-t`/`--target` is completed with the targets in the
+//! cluster, listed with `mirrord completions --targets` (the same listing as `mirrord ls`). The
+//! namespace from `-n`/`--target-namespace` is used when it comes before the target.
+use std::time::Duration;
+
+use clap::CommandFactory;
+use clap_complete::{generate, Shell};
+
+use crate::{
+    config_explain::print_config_keys, list::print_target_paths, Cli, CompletionsArgs, Result,
+};
+
+/// How long we wait for the cluster when completing a target, the shell is blocked meanwhile.
+const TARGETS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wraps the `_mirrord` function generated by clap.
+const BASH_TARGETS: &str = r#"
+_mirrord_targets() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "${prev}" != "-t" && "${prev}" != "--target" ]]; then
+        _mirrord "$@"
+        return
+    fi
+
+    local i namespace=()
+    for ((i = 1; i < COMP_CWORD - 1; i++)); do
+        case "${COMP_WORDS[i]}" in
+            -n|--target-namespace) namespace=(--namespace "${COMP_WORDS[i+1]}") ;;
+        esac
+    done
+    COMPREPLY=($(compgen -W "$(mirrord completions --targets "${namespace[@]}" 2>/dev/null)" -- "${cur}"))
+}
+complete -F _mirrord_targets -o bashdefault -o default mirrord
+"#;
+
+/// Wraps the `_mirrord` function generated by clap. Applies when the script is sourced, e.g.
+/// `source <(mirrord completions zsh)`.
+const ZSH_TARGETS: &str = r#"
+_mirrord_targets() {
+    if [[ "${words[CURRENT-1]}" != (-t|--target) ]]; then
+        _mirrord "$@"
+        return
+    fi
+
+    local i
+    local -a namespace targets
+    for ((i = 2; i < CURRENT - 1; i++)); do
+        case "${words[i]}" in
+            -n|--target-namespace) namespace=(--namespace "${words[i+1]}") ;;
+        esac
+    done
+    targets=(${(f)"$(mirrord completions --targets "${namespace[@]}" 2>/dev/null)"})
+    compadd -a targets
+}
+compdef _mirrord_targets mirrord
+"#;
+
+const FISH_TARGETS: &str = r#"
+function __mirrord_targets
+    set -l args (commandline -opc)
+    set -l namespace
+    for i in (seq (math (count $args) - 1))
+        if contains -- $args[$i] -n --target-namespace
+            set namespace --namespace $args[(math $i + 1)]
+        end
+    end
+    mirrord completions --targets $namespace 2>/dev/null
+end
+complete -c mirrord -n "__fish_seen_subcommand_from exec dump" -s t -l target -x -a "(__mirrord_targets)"
+"#;
+
+/// Runs `mirrord completions`.
+pub(crate) async fn completions_command(args: CompletionsArgs) -> Result<()> {
+    if args.targets {
+        // Nothing to complete is better than an error in the middle of the command line.
+        let _ = tokio::time::timeout(
+            TARGETS_TIMEOUT,
+            print_target_paths(args.namespace.as_deref()),
+        )
+        .await;
+        return Ok(());
+    }
+
+    match args.shell {
+        Some(shell) if !args.config_keys => {
+            let mut cmd: clap::Command = Cli::command();
+            let mut stdout = std::io::stdout();
+            generate(shell, &mut cmd, "mirrord", &mut stdout);
+
+            let targets = match shell {
+                Shell::Bash => BASH_TARGETS,
+                Shell::Zsh => ZSH_TARGETS,
+                Shell::Fish => FISH_TARGETS,
+                _ => "",
+            };
+            print!("{targets}");
+        }
+        _ => print_config_keys(),
+    }
+
+    Ok(())
+}
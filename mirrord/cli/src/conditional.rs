@@ -0,0 +1,194 @@
+//! Resolves the kube side of the conditional config sections (see
+//! [`mirrord_config::conditional`]) before the config is loaded, so that the layer and the
+//! internal proxy don't need the kubeconfig.
+//!
+//! Any setting that can't be resolved fails the session, a section that should apply to it must
+//! not be skipped.
+use std::path::Path;
+
+use kube::config::Kubeconfig;
+use mirrord_config::{
+    conditional::{self, Environment, ENVIRONMENT_ENV},
+    LayerFileConfig,
+};
+use serde_json::Value;
+
+use crate::error::{CliError, Result};
+
+/// Resolves the [`Environment`] for the config file in `MIRRORD_CONFIG_FILE`, see [`resolve`].
+pub(crate) fn resolve_env() -> Result<Option<(String, String)>> {
+    match std::env::var("MIRRORD_CONFIG_FILE") {
+        Ok(path) => resolve(path),
+        Err(..) => Ok(None),
+    }
+}
+
+/// When the config file at `path` has conditional sections, resolves the [`Environment`] they're
+/// checked against and sets it for this process and its children. Returns the env var, for the
+/// processes that don't inherit our environment (e.g. the ones started by an IDE).
+pub(crate) fn resolve<P>(path: P) -> Result<Option<(String, String)>>
+where
+    P: AsRef<Path>,
+{
+    let config = LayerFileConfig::unconditional_value_from_path(path)?;
+    if !conditional::has_sections(&config) {
+        return Ok(None);
+    }
+
+    let env = |key: &str| std::env::var(key).ok();
+    let kubeconfig = read_kubeconfig(&config, env)?;
+    let environment = environment(&config, &kubeconfig, env)?;
+    tracing::debug!(?environment, "resolved the conditional config environment");
+
+    let value = environment.set_env()?;
+    Ok(Some((ENVIRONMENT_ENV.to_string(), value)))
+}
+
+/// String at `path` in the `config` JSON.
+fn config_str(config: &Value, path: &[&str]) -> Option<String> {
+    path.iter()
+        .try_fold(config, |value, key| value.get(key))?
+        .as_str()
+        .map(String::from)
+}
+
+/// Loads the kubeconfig like the session does, from the `kubeconfig` of the config, or else from
+/// all the files in `KUBECONFIG` merged (or `~/.kube/config`).
+fn read_kubeconfig<E>(config: &Value, env: E) -> Result<Kubeconfig>
+where
+    E: Fn(&str) -> Option<String>,
+{
+    let kubeconfig = match env("MIRRORD_KUBECONFIG").or_else(|| config_str(config, &["kubeconfig"]))
+    {
+        Some(path) => shellexpand::full(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|path| Kubeconfig::read_from(path.as_ref()).map_err(|e| e.to_string())),
+        None => Kubeconfig::read().map_err(|error| error.to_string()),
+    };
+
+    kubeconfig.map_err(|error| {
+        CliError::ConditionalConfigFailed(format!("failed to read the kubeconfig: {error}"))
+    })
+}
+
+/// Resolves the [`Environment`] for the `config` in the `kubeconfig`.
+///
+/// The target is in the `target.kube_context`, which defaults to the `kube_context`, and then to
+/// the current context of the kubeconfig.
+fn environment<E>(config: &Value, kubeconfig: &Kubeconfig, env: E) -> Result<Environment>
+where
+    E: Fn(&str) -> Option<String>,
+{
+    let context = env("MIRRORD_TARGET_KUBE_CONTEXT")
+        .or_else(|| config_str(config, &["target", "kube_context"]))
+        .or_else(|| env("MIRRORD_KUBE_CONTEXT"))
+        .or_else(|| config_str(config, &["kube_context"]))
+        .or_else(|| kubeconfig.current_context.clone())
+        .ok_or_else(|| CliError::ConditionalConfigFailed("no kube context is set".to_string()))?;
+
+    let context_config = kubeconfig
+        .contexts
+        .iter()
+        .find(|named| named.name == context)
+        .and_then(|named| named.context.as_ref())
+        .ok_or_else(|| {
+            CliError::ConditionalConfigFailed(format!(
+                "kube context `{context}` is not in the kubeconfig"
+            ))
+        })?;
+
+    let namespace = env("MIRRORD_TARGET_NAMESPACE")
+        .or_else(|| config_str(config, &["target", "namespace"]))
+        .or_else(|| context_config.namespace.clone())
+        .unwrap_or_else(|| "default".to_string());
+
+    Ok(Environment {
+        namespace,
+        cluster: context_config.cluster.clone(),
+        context,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const FIRST: &str = r#"
+current-context: dev
+contexts:
+- name: dev
+  context:
+    cluster: dev-cluster
+    user: dev
+"#;
+
+    const SECOND: &str = r#"
+contexts:
+- name: prod
+  context:
+    cluster: eu-prod
+    user: prod
+    namespace: payments
+"#;
+
+    fn kubeconfig() -> Kubeconfig {
+        let first = Kubeconfig::from_yaml(FIRST).unwrap();
+        first.merge(Kubeconfig::from_yaml(SECOND).unwrap()).unwrap()
+    }
+
+    /// The target's kube context wins over the current one, and the contexts come from every
+    /// kubeconfig file.
+    #[test]
+    fn resolves_target_context() {
+        let kubeconfig = kubeconfig();
+        let no_env = |_: &str| None;
+
+        let current = environment(&json!({}), &kubeconfig, no_env).unwrap();
+        assert_eq!(
+            current,
+            Environment {
+                namespace: "default".to_string(),
+                context: "dev".to_string(),
+                cluster: "dev-cluster".to_string(),
+            }
+        );
+
+        let config = json!({
+            "kube_context": "dev",
+            "target": { "path": "deployment/payments", "kube_context": "prod" }
+        });
+        let target = environment(&config, &kubeconfig, no_env).unwrap();
+        assert_eq!(
+            target,
+            Environment {
+                namespace: "payments".to_string(),
+                context: "prod".to_string(),
+                cluster: "eu-prod".to_string(),
+            }
+        );
+
+        let env = |key: &str| (key == "MIRRORD_TARGET_NAMESPACE").then(|| "staging".to_string());
+        let overridden = environment(&config, &kubeconfig, env).unwrap();
+        assert_eq!(overridden.namespace, "staging");
+    }
+
+    /// A context we can't find fails, instead of skipping the sections.
+    #[test]
+    fn unknown_context_fails() {
+        let kubeconfig = kubeconfig();
+
+        let config = json!({ "kube_context": "staging" });
+        assert!(matches!(
+            environment(&config, &kubeconfig, |_| None),
+            Err(CliError::ConditionalConfigFailed(..))
+        ));
+
+        let no_context = Kubeconfig::from_yaml(SECOND).unwrap();
+        assert!(matches!(
+            environment(&json!({}), &no_context, |_| None),
+            Err(CliError::ConditionalConfigFailed(..))
+        ));
+    }
+}
@@ -5,6 +5,9 @@ use std::{fmt::Display, path::PathBuf};
 use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum, ValueHint};
 use clap_complete::Shell;
 use mirrord_operator::setup::OperatorNamespace;
+use serde::Serialize;
+
+use crate::{daemon::DEFAULT_SESSION, port_forward::PortMapping};
 
 #[derive(Parser)]
 #[command(
@@ -18,6 +21,14 @@ Join our Discord server at https://discord.gg/metalbear, create a GitHub issue a
 pub(super) struct Cli {
     #[command(subcommand)]
     pub(super) commands: Commands,
+
+    /// How to report progress: `standard` (spinners), `simple`, `json` (on stdout), `off`, or
+    /// `ipc:<path>`, to send the JSON messages and structured events about the session (e.g.
+    /// subscribed ports) to the unix socket or named pipe at `path`, one per line.
+    ///
+    /// Overrides `MIRRORD_PROGRESS_MODE`.
+    #[arg(long, global = true, value_name = "MODE")]
+    pub(super) progress: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -56,6 +67,33 @@ pub(super) enum Commands {
 
     /// Diagnostic commands
     Diagnose(Box<DiagnoseArgs>),
+
+    /// Explore the mirrord configuration, e.g. `mirrord config explain feature.network`.
+    Config(Box<ConfigArgs>),
+
+    /// Mirror the traffic of the target's ports without running a local process, and write the
+    /// requests to a file, e.g. `mirrord dump -t deployment/api -p 80 --format har`.
+    Dump(Box<DumpArgs>),
+
+    /// Forward local ports to endpoints in the cluster through the agent, e.g.
+    /// `mirrord port-forward -L 5432:postgres.db.svc:5432`.
+    PortForward(Box<PortForwardArgs>),
+
+    /// Keep a mirrord session in the background, and run processes in it with
+    /// `mirrord exec --attach`, e.g. `mirrord daemon start -t deployment/api`.
+    Daemon(Box<DaemonArgs>),
+
+    /// Show what the running mirrord sessions on this machine are doing: target, agent, ports,
+    /// and counts of connections, remote file operations and DNS queries.
+    Status(StatusArgs),
+
+    /// Send the requests recorded with `mirrord exec --record` to a local process, without a
+    /// cluster, and compare its responses with the recorded ones.
+    Replay(ReplayArgs),
+
+    /// Write the environment of the target, as the binary would get it with `mirrord exec`, to
+    /// a dotenv or JSON file, e.g. `mirrord env -t deployment/api -o .env`.
+    Env(Box<EnvArgs>),
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -91,6 +129,11 @@ pub(super) struct ExecArgs {
     #[arg(short = 't', long)]
     pub target: Option<String>,
 
+    /// Pick the namespace, workload and container of the target interactively in the terminal,
+    /// and optionally save the pick in the config file.
+    #[arg(long, conflicts_with_all = ["target", "attach"])]
+    pub pick: bool,
+
     /// Namespace of the pod to mirror. Defaults to "default".
     #[arg(short = 'n', long)]
     pub target_namespace: Option<String>,
@@ -145,6 +188,11 @@ pub(super) struct ExecArgs {
     /// Arguments to pass to the binary.
     pub(super) binary_args: Vec<String>,
 
+    /// Restart the binary when the files under these paths change, keeping the mirrord session
+    /// (agent, operator session) between the restarts. Can be given multiple times.
+    #[arg(long, value_hint = ValueHint::AnyPath)]
+    pub watch: Vec<PathBuf>,
+
     /// Use an Ephemeral Container to mirror traffic.
     #[arg(short, long)]
     pub ephemeral_container: bool,
@@ -177,13 +225,67 @@ pub(super) struct ExecArgs {
     /// Disable version check on startup.
     pub disable_version_check: bool,
 
-    /// Load config from config file
+    /// Load config from config file: a path, `k8s://<namespace>/<configmap>/<key>` or an https
+    /// URL, optionally pinned with `#sha256=<checksum>`
     #[arg(short = 'f', long, value_hint = ValueHint::FilePath)]
     pub config_file: Option<PathBuf>,
 
+    /// Profile from the config file to use, see the `profiles` config key.
+    #[arg(long)]
+    pub profile: Option<String>,
+
     /// Kube context to use from Kubeconfig
     #[arg(long)]
     pub context: Option<String>,
+
+    /// Print what mirrord would create in the cluster, instead of running the binary.
+    #[arg(long, value_enum)]
+    pub dry_run: Option<DryRun>,
+
+    /// Run the binary in the session of a running `mirrord daemon`, instead of starting a new
+    /// session. The mirrord settings of the daemon are used.
+    #[arg(long, conflicts_with = "dry_run")]
+    pub attach: bool,
+
+    /// Name of the `mirrord daemon` session to attach to. Defaults to "default".
+    #[arg(long, requires = "attach")]
+    pub session: Option<String>,
+
+    /// Write the stolen HTTP requests and the responses of the binary to this file, for
+    /// `mirrord replay`. Only requests stolen with an HTTP filter are recorded.
+    #[arg(long, conflicts_with_all = ["dry_run", "attach"], value_hint = ValueHint::FilePath)]
+    pub record: Option<PathBuf>,
+
+    /// Print how long each phase of the session startup took (Kubernetes authentication, agent
+    /// scheduling, agent startup), before launching the binary.
+    #[arg(long, conflicts_with = "attach")]
+    pub timings: bool,
+
+    /// How mirrord intercepts the calls of the binary.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_enum, default_value_t = Intercept::Layer, conflicts_with = "watch")]
+    pub intercept: Intercept,
+}
+
+/// How `mirrord exec` intercepts the calls of the binary.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub(super) enum Intercept {
+    /// Load the mirrord layer into the binary with `LD_PRELOAD`.
+    Layer,
+    /// Trap the `connect`, `listen` and `open` syscalls of the binary with a seccomp filter, and
+    /// handle them in mirrord. Works for statically linked binaries, with fewer features: outgoing
+    /// traffic, incoming traffic, and reading the files in `feature.fs.read_only` and
+    /// `feature.fs.read_write`.
+    Seccomp,
+}
+
+/// What `mirrord exec --dry-run` prints.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub(super) enum DryRun {
+    /// The YAML of the agent's Job, or its ephemeral container with `agent.ephemeral`. The
+    /// cluster is only read, to resolve the target.
+    Agent,
 }
 
 #[derive(Args, Debug)]
@@ -225,6 +327,10 @@ pub(super) enum OperatorCommand {
         /// Specify config file to use
         #[arg(short = 'f')]
         config_file: Option<String>,
+
+        /// Print the status, including license usage, as JSON.
+        #[arg(long)]
+        json: bool,
     },
     /// Operator session management commands.
     ///
@@ -271,9 +377,180 @@ fn hex_id(raw: &str) -> Result<u64, String> {
         .map_err(|fail| format!("Failed parsing hex session id value with {fail}!"))
 }
 
+/// Format of the `mirrord dump` output.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub(super) enum DumpFormat {
+    /// HTTP Archive (HAR 1.2), written when the dump stops. Responses are not captured.
+    Har,
+    /// Packet capture, with all of the mirrored data, e.g. for Wireshark.
+    Pcap,
+    /// One JSON object per HTTP request.
+    Jsonl,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct DumpArgs {
+    /// Target to mirror, e.g. deployment/name, pod/name, pod/name/container/name.
+    #[arg(short = 't', long)]
+    pub target: Option<String>,
+
+    /// Namespace of the target.
+    #[arg(short = 'n', long)]
+    pub target_namespace: Option<String>,
+
+    /// Ports to mirror, can be given multiple times.
+    #[arg(short = 'p', long = "port", required = true)]
+    pub ports: Vec<u16>,
+
+    /// Format of the dump.
+    #[arg(long, value_enum, default_value_t = DumpFormat::Jsonl)]
+    pub format: DumpFormat,
+
+    /// File to write the dump to. Defaults to stdout.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Stop after this many seconds, instead of waiting for Ctrl+C.
+    #[arg(long)]
+    pub duration: Option<u64>,
+
+    /// Specify config file to use
+    #[arg(short = 'f')]
+    pub config_file: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct PortForwardArgs {
+    /// Target whose network the endpoints are reached from, e.g. deployment/name. Targetless
+    /// when not set.
+    #[arg(short = 't', long)]
+    pub target: Option<String>,
+
+    /// Namespace of the target.
+    #[arg(short = 'n', long)]
+    pub target_namespace: Option<String>,
+
+    /// Endpoint to forward, `[local_port:]remote_host:remote_port`, e.g.
+    /// `5432:postgres.db.svc:5432`. The remote host can be a host name or IP in the cluster. Can
+    /// be given multiple times.
+    #[arg(short = 'L', long = "forward", required = true)]
+    pub mappings: Vec<PortMapping>,
+
+    /// Specify config file to use
+    #[arg(short = 'f')]
+    pub config_file: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct DaemonArgs {
+    #[command(subcommand)]
+    pub command: DaemonCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub(super) enum DaemonCommand {
+    /// Start a session in the background, its agent or operator session is kept until
+    /// `mirrord daemon stop`.
+    Start(DaemonStartArgs),
+
+    /// Stop a session started with `mirrord daemon start`.
+    Stop {
+        /// Name of the session.
+        #[arg(long, default_value = DEFAULT_SESSION)]
+        name: String,
+    },
+}
+
+#[derive(Args, Debug)]
+pub(super) struct DaemonStartArgs {
+    /// Target of the session, e.g. deployment/name, pod/name, pod/name/container/name.
+    #[arg(short = 't', long)]
+    pub target: Option<String>,
+
+    /// Namespace of the target.
+    #[arg(short = 'n', long)]
+    pub target_namespace: Option<String>,
+
+    /// Name of the session, to run more than one at a time.
+    #[arg(long, default_value = DEFAULT_SESSION)]
+    pub name: String,
+
+    /// Specify config file to use
+    #[arg(short = 'f')]
+    pub config_file: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct StatusArgs {
+    /// Print the statuses as JSON.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct EnvArgs {
+    /// Target to read the environment of, e.g. deployment/name, pod/name/container/name.
+    #[arg(short = 't', long)]
+    pub target: Option<String>,
+
+    /// Namespace of the target.
+    #[arg(short = 'n', long)]
+    pub target_namespace: Option<String>,
+
+    /// File to write, readable only by the user. The environment is printed when not set.
+    #[arg(short = 'o', long, value_hint = ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+
+    /// Format of the environment. Defaults to JSON when the output file ends with `.json`, and
+    /// to dotenv otherwise.
+    #[arg(long, value_enum)]
+    pub format: Option<EnvFormat>,
+
+    /// Specify config file to use
+    #[arg(short = 'f')]
+    pub config_file: Option<String>,
+}
+
+/// What `mirrord env` writes.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub(super) enum EnvFormat {
+    /// `KEY="value"` lines, sorted by key.
+    Dotenv,
+
+    /// An object of the variables.
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub(super) struct ReplayArgs {
+    /// File written by `mirrord exec --record`.
+    #[arg(value_hint = ValueHint::FilePath)]
+    pub file: PathBuf,
+
+    /// Local port to send the requests to, instead of the port they were stolen from.
+    #[arg(long)]
+    pub port: Option<u16>,
+}
+
 #[derive(ValueEnum, Clone, Debug)]
 pub enum Format {
+    /// Array of target paths.
     Json,
+    /// Array of targets with their kind, namespace, replicas, containers, labels, and whether the
+    /// operator covers them.
+    DetailedJson,
+}
+
+/// Kind of target listed by `mirrord ls`.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum TargetKind {
+    /// Pods that are running and ready.
+    Pod,
+    /// Deployments with available replicas.
+    Deployment,
+    /// Argo rollouts with available replicas.
+    Rollout,
 }
 
 #[derive(Args, Debug)]
@@ -292,6 +569,10 @@ pub(super) struct ListTargetArgs {
     #[arg(short = 'n', long = "namespace")]
     pub namespace: Option<String>,
 
+    /// List only targets of this kind, can be given multiple times.
+    #[arg(long)]
+    pub kind: Vec<TargetKind>,
+
     /// Specify config file to use
     #[arg(short = 'f')]
     pub config_file: Option<String>,
@@ -302,6 +583,9 @@ pub(super) struct ExtensionExecArgs {
     /// Specify config file to use
     #[arg(short = 'f')]
     pub config_file: Option<String>,
+    /// Specify profile from the config file to use
+    #[arg(long)]
+    pub profile: Option<String>,
     /// Specify target
     #[arg(short = 't')]
     pub target: Option<String>,
@@ -318,13 +602,34 @@ pub(super) struct VerifyConfigArgs {
     #[arg(long)]
     pub(super) ide: bool,
 
+    /// Fail on unknown config keys, instead of warning about them.
+    #[arg(long)]
+    pub(super) strict: bool,
+
     /// Config file path.
     pub(super) path: PathBuf,
 }
 
 #[derive(Args, Debug)]
 pub(super) struct CompletionsArgs {
-    pub(super) shell: Shell,
+    /// Shell to generate completions for. The bash, zsh and fish completions also complete the
+    /// targets in the cluster.
+    #[arg(required_unless_present_any = ["config_keys", "targets"])]
+    pub(super) shell: Option<Shell>,
+
+    /// Print every config key (e.g. `feature.network.incoming.http_filter`), one per line,
+    /// instead of shell completions. Useful for completing `mirrord config explain`.
+    #[arg(long, conflicts_with = "shell")]
+    pub(super) config_keys: bool,
+
+    /// Print the targets in the cluster, one per line, instead of shell completions. Used by the
+    /// completions to complete `--target`.
+    #[arg(long, hide = true, conflicts_with_all = ["shell", "config_keys"])]
+    pub(super) targets: bool,
+
+    /// Namespace of the targets printed with `--targets`.
+    #[arg(long, hide = true, requires = "targets")]
+    pub(super) namespace: Option<String>,
 }
 
 #[derive(Args, Debug)]
@@ -342,4 +647,58 @@ pub(super) enum DiagnoseCommand {
         #[arg(short = 'f')]
         config_file: Option<String>,
     },
+
+    /// Check the connectivity with the cluster and the agent, and print a report: Kubernetes API
+    /// latency, agent startup time, round-trip latency, throughput and DNS resolution time.
+    Connectivity {
+        /// Specify config file to use
+        #[arg(short = 'f')]
+        config_file: Option<String>,
+
+        /// Host name to resolve in the cluster, for the DNS resolution time.
+        #[arg(long, default_value = "kubernetes.default.svc")]
+        dns_host: String,
+    },
+
+    /// Redact secrets (tokens, keys, credentials) from a file before you share it, e.g. logs
+    /// attached to a bug report.
+    Redact {
+        /// File to redact.
+        path: PathBuf,
+
+        /// Where to write the redacted file. Defaults to stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Review every secret found, instead of redacting all of them.
+        #[arg(long)]
+        review: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+pub(super) struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+/// `mirrord config` family of commands.
+#[derive(Subcommand, Debug)]
+pub(super) enum ConfigCommand {
+    /// Print the documentation, type, default and example of a config key.
+    Explain {
+        /// Dotted path of the key, e.g. `feature.network.incoming.http_filter`.
+        key: String,
+    },
+
+    /// Rewrite a config file written for an older version of mirrord, replacing the deprecated
+    /// keys and values. Comments and the order of the keys are not kept.
+    Migrate {
+        /// Config file path.
+        path: PathBuf,
+
+        /// Overwrite the file, instead of printing the migrated config.
+        #[arg(long)]
+        in_place: bool,
+    },
 }
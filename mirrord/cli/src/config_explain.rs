@@ -0,0 +1,84 @@
+//! `mirrord config explain {key}` prints what we know about a config key, straight from the
+//! config schema, so users can explore the configuration without leaving the terminal.
+//!
+//! `mirrord config migrate {path}` rewrites a config file written for an older version of
+//! mirrord.
+use std::path::Path;
+
+use mirrord_config::config::{
+    explain::{config_keys, explain_key},
+    migrate::migrate_file,
+};
+
+use crate::{CliError, ConfigArgs, ConfigCommand, Result};
+
+/// Handles the `mirrord config` family of commands.
+pub(crate) fn config_command(args: ConfigArgs) -> Result<()> {
+    match args.command {
+        ConfigCommand::Explain { key } => explain(&key),
+        ConfigCommand::Migrate { path, in_place } => migrate(&path, in_place),
+    }
+}
+
+fn migrate(path: &Path, in_place: bool) -> Result<()> {
+    let (migrated, deprecations) = migrate_file(path)?;
+
+    for deprecation in &deprecations {
+        eprintln!("{deprecation}");
+    }
+
+    if deprecations.is_empty() {
+        eprintln!("Nothing to migrate in `{}`.", path.display());
+    } else if in_place {
+        std::fs::write(path, migrated)
+            .map_err(|fail| CliError::ConfigFilePathError(path.to_path_buf(), fail))?;
+    } else {
+        print!("{migrated}");
+    }
+
+    Ok(())
+}
+
+fn explain(key: &str) -> Result<()> {
+    let info = explain_key(key).ok_or_else(|| CliError::UnknownConfigKey(key.to_string()))?;
+
+    println!("{}\n", info.key);
+
+    if let Some(description) = info.description {
+        println!("{description}\n");
+    }
+
+    if !info.types.is_empty() {
+        println!("Type: {}", info.types.join(" | "));
+    }
+
+    println!("Default: {}", info.default.as_deref().unwrap_or("-"));
+
+    if let Some(example) = info.example {
+        println!("\nExample:\n{example}");
+    }
+
+    let children = config_keys()
+        .into_iter()
+        .filter_map(|other| {
+            other
+                .strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .filter(|rest| !rest.contains('.'))
+                .map(ToString::to_string)
+        })
+        .collect::<Vec<_>>();
+
+    if !children.is_empty() {
+        println!("\nKeys: {}", children.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Prints every config key, one per line, for `mirrord completions --config-keys`.
+pub(crate) fn print_config_keys() {
+    for key in config_keys() {
+        println!("{key}");
+    }
+}
@@ -4,9 +4,13 @@ use mirrord_analytics::Reporter;
 use mirrord_config::{feature::network::outgoing::OutgoingFilterConfig, LayerConfig};
 use mirrord_intproxy::agent_conn::AgentConnectInfo;
 use mirrord_kube::api::{kubernetes::KubernetesAPI, wrap_raw_connection};
-use mirrord_operator::client::{OperatorApi, OperatorApiError, OperatorOperation};
+use mirrord_operator::client::{
+    OperatorApi, OperatorApiError, OperatorFailureKind, OperatorOperation,
+};
 use mirrord_progress::{
-    messages::MULTIPOD_WARNING, IdeAction, IdeMessage, NotificationLevel, Progress,
+    messages::MULTIPOD_WARNING,
+    timings::{self, StartupPhase},
+    IdeAction, IdeMessage, NotificationLevel, Progress,
 };
 use mirrord_protocol::{ClientMessage, DaemonMessage};
 use tokio::sync::mpsc;
@@ -33,14 +37,21 @@ impl OperatorApiErrorExt for OperatorApiError {
                 ..
             } => false,
             // Fallback to OSS if license is expired
-            Self::NoLicense => false,
+            Self::NoLicense
+            | Self::OperatorFailure {
+                kind: OperatorFailureKind::LicenseExpired,
+                ..
+            } => false,
             // These should either never happen or can happen only if the operator is installed.
             Self::ConcurrentStealAbort
             | Self::ConnectRequestBuildError(..)
+            | Self::SplitQueuesSerializeError(..)
             | Self::CreateApiError(..)
             | Self::InvalidTarget { .. }
             | Self::UnsupportedFeature { .. }
             | Self::StatusFailure { .. }
+            | Self::CertificateProvider(..)
+            | Self::OperatorFailure { .. }
             | Self::KubeError { .. } => true,
         }
     }
@@ -83,6 +94,8 @@ where
         match OperatorApi::create_session(config, &subtask, analytics).await {
             Ok(session) => {
                 subtask.success(Some("connected to the operator"));
+                // The operator creates the agent with the session.
+                timings::mark(StartupPhase::AgentReady);
 
                 return Ok((
                     AgentConnectInfo::Operator(session.info),
@@ -92,7 +105,23 @@ where
                     },
                 ));
             }
-            Err(e) if config.operator == Some(true) || e.should_abort_cli() => return Err(e.into()),
+            Err(OperatorApiError::OperatorFailure {
+                kind,
+                message,
+                operation,
+            }) if config.operator != Some(true) && kind == OperatorFailureKind::LicenseExpired => {
+                tracing::trace!("{operation} failed, {kind}: {message}");
+                subtask.warning(kind.remediation());
+                subtask.success(Some("operator license expired, falling back to OSS"));
+            }
+            Err(e) if config.operator == Some(true) || e.should_abort_cli() => {
+                if let OperatorApiError::OperatorFailure { kind, .. } = &e {
+                    subtask.warning(kind.remediation());
+                    subtask.failure(Some(&kind.to_string()));
+                }
+
+                return Err(e.into());
+            }
             Err(e) => {
                 tracing::trace!("{}", CliError::from(e));
                 subtask.success(Some("operator not found"));
@@ -104,6 +133,12 @@ where
         return Err(CliError::FeatureRequiresOperatorError("copy target".into()));
     }
 
+    if config.feature.split_queues.is_enabled() {
+        return Err(CliError::FeatureRequiresOperatorError(
+            "split queues".into(),
+        ));
+    }
+
     if matches!(
         config.target,
         mirrord_config::target::TargetConfig {
@@ -142,6 +177,7 @@ where
     let _ = k8s_api.detect_openshift(progress).await.map_err(|err| {
         tracing::debug!("couldn't determine OpenShift: {err}");
     });
+    timings::mark(StartupPhase::KubeAuth);
 
     let agent_connect_info = tokio::time::timeout(
         Duration::from_secs(config.agent.startup_timeout),
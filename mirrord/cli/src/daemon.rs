@@ -0,0 +1,309 @@
+//! `mirrord daemon`, a mirrord session (the internal proxy with its agent or operator
+//! connection) that is kept in the background, for the processes started with
+//! `mirrord exec --attach`.
+//!
+//! The internal proxy already multiplexes the layers of many local processes into one agent
+//! connection, so the daemon is the internal proxy started without its idle timeouts. What the
+//! attached processes need to connect to it (the address of the internal proxy, the remote
+//! environment and the mirrord settings of the daemon) is written to a session file, in a
+//! directory that only the user can access. `mirrord daemon stop` stops the internal proxy, which
+//! closes the session.
+use std::{
+    collections::HashMap,
+    fs::{DirBuilder, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use mirrord_analytics::AnalyticsReporter;
+use mirrord_config::LayerConfig;
+use mirrord_progress::{Progress, ProgressTracker};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conditional,
+    config::{DaemonArgs, DaemonCommand, DaemonStartArgs},
+    execution::MirrordExecution,
+    remote_config::config_file_path,
+    CliError, Result,
+};
+
+/// Name of the session when `--name`/`--session` is not given.
+pub(crate) const DEFAULT_SESSION: &str = "default";
+
+/// Idle timeout of the internal proxy of the daemon, long enough to never be reached.
+const DAEMON_IDLE_TIMEOUT: u64 = u32::MAX as u64;
+
+/// What `mirrord exec --attach` needs to run a process in the session of a daemon.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DaemonSession {
+    /// Process id of the internal proxy.
+    pub proxy_pid: u32,
+
+    /// Environment of the attached processes: the remote environment, the layer injection, the
+    /// address of the internal proxy and the mirrord settings of the daemon.
+    pub environment: HashMap<String, String>,
+
+    pub env_to_unset: Vec<String>,
+}
+
+impl DaemonSession {
+    /// Directory of the session files of the user, in `$XDG_RUNTIME_DIR` or the temp dir.
+    ///
+    /// The session files hold the environment of the attached processes (e.g. `LD_PRELOAD`), so
+    /// the directory must belong to the user and be closed to everyone else. We refuse to use it
+    /// otherwise, as another user could have created it first in a shared temp dir.
+    fn dir() -> Result<PathBuf> {
+        let uid = unsafe { libc::getuid() };
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join(format!("mirrord-daemon-{uid}"));
+
+        let check = || {
+            match DirBuilder::new().mode(0o700).create(&dir) {
+                Err(fail) if fail.kind() != io::ErrorKind::AlreadyExists => return Err(fail),
+                _ => {}
+            }
+
+            let metadata = std::fs::symlink_metadata(&dir)?;
+            if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the directory is not private to the user",
+                ));
+            }
+
+            Ok(())
+        };
+
+        check().map_err(|fail| CliError::DaemonSessionFile(dir.clone(), fail))?;
+
+        Ok(dir)
+    }
+
+    /// Path of the session file of `name`, which is used as a file name.
+    fn path(name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(CliError::DaemonSessionName(name.to_string()));
+        }
+
+        Ok(Self::dir()?.join(format!("{name}.json")))
+    }
+
+    /// Opens the session file without following symlinks, and checks that it belongs to the
+    /// user.
+    fn open(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+        let file = options.custom_flags(libc::O_NOFOLLOW).open(path)?;
+
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || metadata.uid() != unsafe { libc::getuid() } {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the session file doesn't belong to the user",
+            ));
+        }
+
+        Ok(file)
+    }
+
+    /// Loads the session `name`, if its internal proxy is still running.
+    pub(crate) fn load(name: &str) -> Result<Self> {
+        let path = Self::path(name)?;
+        let read = Self::open(&path, OpenOptions::new().read(true)).and_then(|mut file| {
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes).map(|_| bytes)
+        });
+        let session = match read {
+            Ok(bytes) => serde_json::from_slice::<Self>(&bytes)?,
+            Err(fail) if fail.kind() == io::ErrorKind::NotFound => {
+                return Err(CliError::DaemonNotRunning(name.to_string()))
+            }
+            Err(fail) => return Err(CliError::DaemonSessionFile(path, fail)),
+        };
+
+        if session.is_running() {
+            Ok(session)
+        } else {
+            let _ = std::fs::remove_file(&path);
+            Err(CliError::DaemonNotRunning(name.to_string()))
+        }
+    }
+
+    /// Writes the session `name`, the file contains the remote environment so only the user can
+    /// read it.
+    fn save(&self, name: &str) -> Result<()> {
+        let path = Self::path(name)?;
+        let write = || {
+            let mut file = Self::open(
+                &path,
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600),
+            )?;
+            file.write_all(&serde_json::to_vec(self)?)
+        };
+
+        write().map_err(|fail| CliError::DaemonSessionFile(path.clone(), fail))
+    }
+
+    fn is_running(&self) -> bool {
+        kill(Pid::from_raw(self.proxy_pid as i32), None).is_ok()
+    }
+}
+
+/// Starts the internal proxy of the daemon and writes its session.
+async fn start(args: DaemonStartArgs, watch: drain::Watch) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord daemon");
+
+    if DaemonSession::load(&args.name).is_ok() {
+        return Err(CliError::DaemonAlreadyRunning(args.name));
+    }
+
+    if let Some(target) = &args.target {
+        std::env::set_var("MIRRORD_IMPERSONATED_TARGET", target);
+    }
+
+    if let Some(namespace) = &args.target_namespace {
+        std::env::set_var("MIRRORD_TARGET_NAMESPACE", namespace);
+    }
+
+    // Handed over to the internal proxy of the session once it starts.
+    let config_file = if let Some(config_file) = &args.config_file {
+        let config_file = config_file_path(config_file).await?;
+        std::env::set_var("MIRRORD_CONFIG_FILE", config_file.path());
+        Some(config_file)
+    } else {
+        None
+    };
+
+    // The session is kept until `mirrord daemon stop`.
+    for key in [
+        "MIRRORD_INTPROXY_START_IDLE_TIMEOUT",
+        "MIRRORD_INTPROXY_IDLE_TIMEOUT",
+    ] {
+        std::env::set_var(key, DAEMON_IDLE_TIMEOUT.to_string());
+    }
+
+    conditional::resolve_env()?;
+    let (config, mut context) = LayerConfig::from_env_with_warnings()?;
+    config.verify(&mut context)?;
+    for warning in context.get_warnings() {
+        progress.warning(warning);
+    }
+
+    let mut analytics = AnalyticsReporter::only_error(config.telemetry, watch);
+
+    let mut sub_progress = progress.subtask("starting the session");
+    #[cfg(target_os = "macos")]
+    let execution =
+        MirrordExecution::start(&config, None, &mut sub_progress, &mut analytics).await?;
+    #[cfg(not(target_os = "macos"))]
+    let execution = MirrordExecution::start(&config, &mut sub_progress, &mut analytics).await?;
+
+    let proxy_pid = execution
+        .proxy_pid()
+        .ok_or_else(|| CliError::DaemonNotRunning(args.name.clone()))?;
+    if let Some(config_file) = config_file {
+        config_file.hand_over();
+    }
+
+    // The attached processes load the layer with the settings of the daemon.
+    let mut environment = std::env::vars()
+        .filter(|(key, _)| key.starts_with("MIRRORD_"))
+        .collect::<HashMap<_, _>>();
+    environment.extend(execution.environment);
+
+    let session = DaemonSession {
+        proxy_pid,
+        environment,
+        env_to_unset: execution.env_to_unset,
+    };
+    session.save(&args.name)?;
+    sub_progress.success(Some("session started"));
+
+    let session_arg = if args.name == DEFAULT_SESSION {
+        String::new()
+    } else {
+        format!(" --session {}", args.name)
+    };
+    progress.success(Some(&format!(
+        "run processes in the session with `mirrord exec --attach{session_arg} -- <command>`"
+    )));
+
+    Ok(())
+}
+
+/// Stops the internal proxy of the daemon, which closes its agent or operator connection.
+fn stop(name: &str) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord daemon");
+
+    let session = DaemonSession::load(name)?;
+    let _ = kill(Pid::from_raw(session.proxy_pid as i32), Signal::SIGTERM);
+
+    let path = DaemonSession::path(name)?;
+    std::fs::remove_file(&path).map_err(|fail| CliError::DaemonSessionFile(path, fail))?;
+
+    progress.success(Some(&format!("stopped the session `{name}`")));
+
+    Ok(())
+}
+
+/// Runs `mirrord daemon`.
+pub(crate) async fn daemon_command(args: DaemonArgs, watch: drain::Watch) -> Result<()> {
+    match args.command {
+        DaemonCommand::Start(args) => start(args, watch).await,
+        DaemonCommand::Stop { name } => stop(&name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn missing_session_is_not_running() {
+        let name = format!("missing-{}", std::process::id());
+
+        assert!(matches!(
+            DaemonSession::load(&name),
+            Err(CliError::DaemonNotRunning(missing)) if missing == name
+        ));
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::parent("..")]
+    #[case::hidden(".session")]
+    #[case::separator("../other")]
+    #[case::absolute("/tmp/session")]
+    fn rejects_session_names(#[case] name: &str) {
+        assert!(matches!(
+            DaemonSession::path(name),
+            Err(CliError::DaemonSessionName(..))
+        ));
+    }
+
+    #[test]
+    fn private_dir() {
+        let dir = DaemonSession::dir().unwrap();
+
+        let metadata = std::fs::symlink_metadata(&dir).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.mode() & 0o777, 0o700);
+    }
+}
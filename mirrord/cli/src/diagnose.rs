@@ -1,18 +1,45 @@
-use std::time::Duration;
+use std::{
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use mirrord_analytics::NullReporter;
 use mirrord_config::{
     config::{ConfigContext, MirrordConfig},
-    LayerFileConfig,
+    LayerConfig, LayerFileConfig,
 };
+use mirrord_intproxy::secrets;
+use mirrord_kube::api::kubernetes::create_kube_api;
 use mirrord_progress::{Progress, ProgressTracker};
-use mirrord_protocol::{ClientMessage, DaemonMessage};
+use mirrord_protocol::{
+    dns::{GetAddrInfoRequest, GetAddrInfoResponse},
+    file::{
+        CloseFileRequest, OpenFileRequest, OpenFileResponse, OpenOptionsInternal, ReadFileRequest,
+        ReadFileResponse,
+    },
+    ClientMessage, DaemonMessage, FileRequest, FileResponse,
+};
 use tokio::{sync::mpsc, time::Instant};
 
 use crate::{
-    connection::create_and_connect, util::remove_proxy_env, DiagnoseArgs, DiagnoseCommand, Result,
+    conditional,
+    connection::{create_and_connect, AgentConnection},
+    remote_config::config_file_path,
+    util::remove_proxy_env,
+    CliError, DiagnoseArgs, DiagnoseCommand, Result,
 };
 
+/// How many times each round trip of `mirrord diagnose connectivity` is measured.
+const CONNECTIVITY_ITERATIONS: usize = 10;
+
+/// How much data `mirrord diagnose connectivity` reads from the agent to measure the throughput.
+const THROUGHPUT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Size of a single read of the throughput check.
+const THROUGHPUT_CHUNK: u64 = 1024 * 1024;
+
 /// Sends a ping the connection and expects a pong.
 async fn ping(
     sender: &mpsc::Sender<ClientMessage>,
@@ -32,14 +59,44 @@ async fn ping(
     }
 }
 
-/// Create a targetless session and run pings to diagnose network latency.
-#[tracing::instrument(level = "trace", ret)]
-async fn diagnose_latency(config: Option<String>) -> Result<()> {
-    let mut progress = ProgressTracker::from_env("mirrord network diagnosis");
+/// Min, max and average of measured durations.
+#[derive(Debug, Clone, Copy)]
+struct Statistics {
+    min: Duration,
+    max: Duration,
+    avg: Duration,
+}
+
+impl Statistics {
+    /// Returns [`None`] if nothing was measured.
+    fn new(durations: &[Duration]) -> Option<Self> {
+        Some(Self {
+            min: durations.iter().min().copied()?,
+            max: durations.iter().max().copied()?,
+            avg: durations.iter().sum::<Duration>() / u32::try_from(durations.len()).ok()?,
+        })
+    }
+}
 
+impl fmt::Display for Statistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min={}ms, max={}ms, avg={}ms",
+            self.min.as_millis(),
+            self.max.as_millis(),
+            self.avg.as_millis()
+        )
+    }
+}
+
+/// Loads the config file at `config`, or the default config.
+async fn load_config(config: Option<String>) -> Result<LayerConfig> {
     let mut cfg_context = ConfigContext::default();
     let config = if let Some(path) = config {
-        LayerFileConfig::from_path(path)?.generate_config(&mut cfg_context)
+        let config_file = config_file_path(&path).await?;
+        conditional::resolve(config_file.path())?;
+        LayerFileConfig::from_path(config_file.path())?.generate_config(&mut cfg_context)
     } else {
         LayerFileConfig::default().generate_config(&mut cfg_context)
     }?;
@@ -48,6 +105,16 @@ async fn diagnose_latency(config: Option<String>) -> Result<()> {
         remove_proxy_env();
     }
 
+    Ok(config)
+}
+
+/// Create a targetless session and run pings to diagnose network latency.
+#[tracing::instrument(level = "trace", ret)]
+async fn diagnose_latency(config: Option<String>) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord network diagnosis");
+
+    let config = load_config(config).await?;
+
     let mut analytics = NullReporter::default();
     let (_, mut connection) = create_and_connect(&config, &mut progress, &mut analytics).await?;
 
@@ -70,26 +137,269 @@ async fn diagnose_latency(config: Option<String>) -> Result<()> {
         statistics.push(elapsed);
     }
 
-    let min = statistics
-        .iter()
-        .min()
-        .map(|d| d.as_millis().to_string())
+    let statistics = Statistics::new(&statistics)
+        .map(|statistics| statistics.to_string())
         .unwrap_or("N/A".to_string());
-    let max = statistics
+    progress.success(Some(format!("Latency statistics: {statistics}").as_str()));
+    Ok(())
+}
+
+/// One line of the `mirrord diagnose connectivity` report.
+struct Check {
+    name: String,
+    /// What was measured, or why the check failed.
+    result: String,
+    /// What the user can do about a bad result.
+    hint: Option<&'static str>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            result: result.into(),
+            hint: None,
+        }
+    }
+
+    /// Adds the `hint` if the result is `bad`.
+    fn hint_if(mut self, bad: bool, hint: &'static str) -> Self {
+        if bad {
+            self.hint = Some(hint);
+        }
+        self
+    }
+}
+
+/// Sends the `request` to the agent and returns its response, skipping the logs.
+async fn request(
+    connection: &mut AgentConnection,
+    request: ClientMessage,
+) -> Result<DaemonMessage, String> {
+    connection
+        .sender
+        .send(request)
+        .await
+        .map_err(|_| "the connection with the agent was closed".to_string())?;
+
+    loop {
+        match connection.receiver.recv().await {
+            Some(DaemonMessage::LogMessage(..)) => {}
+            Some(message) => break Ok(message),
+            None => break Err("the connection with the agent was closed".to_string()),
+        }
+    }
+}
+
+/// Measures the latency of the Kubernetes API with `/version` requests.
+async fn kube_api_latency(config: &LayerConfig) -> Result<Statistics, String> {
+    let client = create_kube_api(
+        config.accept_invalid_certificates,
+        config.kubeconfig.clone(),
+        config.kube_context.clone(),
+        config.proxy.clone(),
+        config.kube_api_url.clone(),
+    )
+    .await
+    .map_err(|error| error.to_string())?;
+
+    let mut durations = Vec::with_capacity(CONNECTIVITY_ITERATIONS);
+    for _ in 0..CONNECTIVITY_ITERATIONS {
+        let start = Instant::now();
+        client
+            .apiserver_version()
+            .await
+            .map_err(|error| error.to_string())?;
+        durations.push(start.elapsed());
+    }
+
+    Statistics::new(&durations).ok_or_else(|| "nothing was measured".to_string())
+}
+
+/// Measures the round-trip latency to the agent with pings.
+async fn agent_latency(connection: &mut AgentConnection) -> Result<Statistics, String> {
+    let mut durations = Vec::with_capacity(CONNECTIVITY_ITERATIONS);
+    for _ in 0..CONNECTIVITY_ITERATIONS {
+        let start = Instant::now();
+        match request(connection, ClientMessage::Ping).await? {
+            DaemonMessage::Pong => durations.push(start.elapsed()),
+            other => return Err(format!("unexpected response {other:?}")),
+        }
+    }
+
+    Statistics::new(&durations).ok_or_else(|| "nothing was measured".to_string())
+}
+
+/// Measures the download throughput from the agent, reading [`THROUGHPUT_BYTES`] from
+/// `/dev/zero` in the target. Returns the bytes per second.
+async fn agent_throughput(connection: &mut AgentConnection) -> Result<f64, String> {
+    let open = ClientMessage::FileRequest(FileRequest::Open(OpenFileRequest {
+        path: "/dev/zero".into(),
+        open_options: OpenOptionsInternal {
+            read: true,
+            ..Default::default()
+        },
+    }));
+    let fd = match request(connection, open).await? {
+        DaemonMessage::File(FileResponse::Open(Ok(OpenFileResponse { fd }))) => fd,
+        DaemonMessage::File(FileResponse::Open(Err(error))) => {
+            return Err(format!("opening `/dev/zero` failed: {error}"))
+        }
+        other => return Err(format!("unexpected response {other:?}")),
+    };
+
+    let start = Instant::now();
+    let mut total = 0;
+    while total < THROUGHPUT_BYTES {
+        let read = ClientMessage::FileRequest(FileRequest::Read(ReadFileRequest {
+            remote_fd: fd,
+            buffer_size: THROUGHPUT_CHUNK,
+        }));
+
+        match request(connection, read).await? {
+            DaemonMessage::File(FileResponse::Read(Ok(ReadFileResponse {
+                read_amount, ..
+            }))) if read_amount > 0 => {
+                total += read_amount;
+            }
+            DaemonMessage::File(FileResponse::Read(Ok(..))) => {
+                return Err("`/dev/zero` returned no data".to_string())
+            }
+            DaemonMessage::File(FileResponse::Read(Err(error))) => {
+                return Err(format!("reading `/dev/zero` failed: {error}"))
+            }
+            other => return Err(format!("unexpected response {other:?}")),
+        }
+    }
+    let elapsed = start.elapsed();
+
+    // The agent doesn't respond to this one.
+    let _ = connection
+        .sender
+        .send(ClientMessage::FileRequest(FileRequest::Close(
+            CloseFileRequest { fd },
+        )))
+        .await;
+
+    Ok(total as f64 / elapsed.as_secs_f64())
+}
+
+/// Measures how long the agent takes to resolve `host`. Returns the duration and the number of
+/// records.
+async fn dns_resolution(
+    connection: &mut AgentConnection,
+    host: &str,
+) -> Result<(Duration, usize), String> {
+    let lookup = ClientMessage::GetAddrInfoRequest(GetAddrInfoRequest {
+        node: host.to_string(),
+    });
+
+    let start = Instant::now();
+    match request(connection, lookup).await? {
+        DaemonMessage::GetAddrInfoResponse(GetAddrInfoResponse(Ok(lookup))) => {
+            Ok((start.elapsed(), lookup.0.len()))
+        }
+        DaemonMessage::GetAddrInfoResponse(GetAddrInfoResponse(Err(error))) => {
+            Err(format!("resolving `{host}` failed: {error}"))
+        }
+        other => Err(format!("unexpected response {other:?}")),
+    }
+}
+
+/// Connects to the agent (creating it, or through the operator, as `mirrord exec` would) and
+/// measures the connectivity with the cluster. Prints a report, with hints for the bad results.
+#[tracing::instrument(level = "trace", ret)]
+async fn diagnose_connectivity(config: Option<String>, dns_host: String) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord connectivity diagnosis");
+
+    let config = load_config(config).await?;
+    let mut checks = Vec::new();
+
+    let mut subtask = progress.subtask("measuring Kubernetes API latency");
+    let check = match kube_api_latency(&config).await {
+        Ok(statistics) => Check::new("Kubernetes API latency", statistics.to_string()).hint_if(
+            statistics.avg > Duration::from_millis(500),
+            "the API server is slow to respond, check the connection to the cluster (VPN, proxy)",
+        ),
+        Err(error) => Check::new("Kubernetes API latency", format!("failed: {error}")),
+    };
+    checks.push(check);
+    subtask.success(None);
+
+    let start = Instant::now();
+    let mut analytics = NullReporter::default();
+    let (_, mut connection) = create_and_connect(&config, &mut progress, &mut analytics).await?;
+    let startup = start.elapsed();
+    checks.push(
+        Check::new("Agent startup", format!("{}ms", startup.as_millis())).hint_if(
+            startup > Duration::from_secs(10),
+            "the agent takes long to start, e.g. pulling its image, consider `agent.image` with \
+             an image that's already on the nodes",
+        ),
+    );
+
+    // The first ping is part of the initialization.
+    let _ = request(&mut connection, ClientMessage::Ping).await;
+
+    let mut subtask = progress.subtask("measuring the round-trip latency to the agent");
+    let check = match agent_latency(&mut connection).await {
+        Ok(statistics) => Check::new("Agent round-trip latency", statistics.to_string()).hint_if(
+            statistics.avg > Duration::from_millis(100),
+            "every remote operation (file, DNS, outgoing traffic) pays this latency, check the \
+             network between you and the cluster",
+        ),
+        Err(error) => Check::new("Agent round-trip latency", format!("failed: {error}")),
+    };
+    checks.push(check);
+    subtask.success(None);
+
+    let mut subtask = progress.subtask("measuring the throughput from the agent");
+    let check = match agent_throughput(&mut connection).await {
+        Ok(throughput) => Check::new(
+            "Agent throughput",
+            format!("{:.1} MiB/s", throughput / (1024.0 * 1024.0)),
+        )
+        .hint_if(
+            throughput < 1024.0 * 1024.0,
+            "large remote files and stolen traffic will be slow, consider reading large files \
+             locally with `feature.fs.local`",
+        ),
+        Err(error) => Check::new("Agent throughput", format!("failed: {error}")),
+    };
+    checks.push(check);
+    subtask.success(None);
+
+    let mut subtask = progress.subtask("measuring the DNS resolution time");
+    let name = format!("DNS resolution of `{dns_host}`");
+    let check = match dns_resolution(&mut connection, &dns_host).await {
+        Ok((duration, records)) => Check::new(
+            name,
+            format!("{}ms ({records} records)", duration.as_millis()),
+        )
+        .hint_if(
+            duration > Duration::from_millis(200),
+            "DNS in the cluster is slow, every outgoing connection resolved remotely pays this",
+        ),
+        Err(error) => Check::new(name, format!("failed: {error}")),
+    };
+    checks.push(check);
+    subtask.success(None);
+
+    progress.success(Some("connectivity diagnosis finished"));
+
+    let width = checks
         .iter()
+        .map(|check| check.name.len())
         .max()
-        .map(|d| d.as_millis().to_string())
-        .unwrap_or("N/A".to_string());
-    let avg: String = (statistics.iter().sum::<Duration>() / statistics.len() as u32)
-        .as_millis()
-        .to_string();
-    progress.success(Some(
-        format!(
-            "Latency statistics: min={}ms, max={}ms, avg={}ms",
-            min, max, avg
-        )
-        .as_str(),
-    ));
+        .unwrap_or_default();
+    println!();
+    for check in checks {
+        println!("{:width$}  {}", check.name, check.result);
+        if let Some(hint) = check.hint {
+            println!("{:width$}  hint: {hint}", "");
+        }
+    }
+
     Ok(())
 }
 
@@ -97,5 +407,41 @@ async fn diagnose_latency(config: Option<String>) -> Result<()> {
 pub(crate) async fn diagnose_command(args: DiagnoseArgs) -> Result<()> {
     match args.command {
         DiagnoseCommand::Latency { config_file } => diagnose_latency(config_file).await,
+        DiagnoseCommand::Connectivity {
+            config_file,
+            dns_host,
+        } => diagnose_connectivity(config_file, dns_host).await,
+        DiagnoseCommand::Redact {
+            path,
+            output,
+            review,
+        } => diagnose_redact(&path, output, review),
+    }
+}
+
+/// Writes a copy of the file at `path` with the secrets redacted, see [`secrets::scan`].
+fn diagnose_redact(path: &Path, output: Option<PathBuf>, review: bool) -> Result<()> {
+    let text = fs::read_to_string(path).map_err(CliError::RedactFailed)?;
+
+    let mut findings = secrets::scan(&text);
+    if review && !findings.is_empty() {
+        if !io::stdin().is_terminal() {
+            return Err(CliError::RedactReviewNotInteractive);
+        }
+
+        // Prompts go to stderr, so that they don't mix with the redacted file on stdout.
+        findings = secrets::review(&text, findings, io::stdin().lock(), io::stderr())
+            .map_err(CliError::RedactFailed)?;
+    }
+
+    let redacted = secrets::redact(&text, &findings);
+    match output {
+        Some(output) => fs::write(output, redacted),
+        None => io::stdout().write_all(redacted.as_bytes()),
     }
+    .map_err(CliError::RedactFailed)?;
+
+    eprintln!("Redacted {} secret(s).", findings.len());
+
+    Ok(())
 }
@@ -0,0 +1,848 @@
+//! `mirrord dump`: mirrors the traffic of some ports of the target without running a local
+//! process, and writes it to a file, to see what a service receives before deciding what to
+//! steal.
+//!
+//! The agent mirrors only the incoming side of the connections, so the dumps have the requests
+//! without their responses. The `har` and `jsonl` formats have the HTTP/1 requests (with
+//! `Content-Length` or chunked bodies), the `pcap` format has all of the mirrored data.
+//!
+//! Secrets in the dumped traffic are redacted, see [`secrets`]. In the `pcap` format, only the
+//! data chunks that are UTF-8 text are scanned.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use mirrord_analytics::NullReporter;
+use mirrord_config::LayerConfig;
+use mirrord_intproxy::secrets;
+use mirrord_progress::{Progress, ProgressTracker};
+use mirrord_protocol::{
+    codec::LogLevel,
+    tcp::{DaemonTcp, LayerTcp, NewTcpConnection, TcpClose, TcpData},
+    ClientMessage, ConnectionId, DaemonMessage,
+};
+use serde_json::{json, Value};
+use tokio::time::{self, Instant};
+
+use crate::{
+    conditional, connection::create_and_connect, remote_config::config_file_path,
+    util::remove_proxy_env, CliError, DumpArgs, DumpFormat, Result,
+};
+
+/// How often we ping the agent, to keep the connection alive while there is no traffic.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `LINKTYPE_RAW` of the `pcap` format, the packets start with their IPv4 or IPv6 header.
+const PCAP_LINKTYPE_RAW: u32 = 101;
+
+/// Largest TCP payload of a single packet in the `pcap` dump, bigger chunks are split.
+const PCAP_MAX_PAYLOAD: usize = 65_000;
+
+/// Max number of headers of a parsed HTTP request.
+const MAX_HEADERS: usize = 128;
+
+/// Largest HTTP request we parse, a connection is not parsed anymore once it has more data than
+/// this pending.
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// Why the data of a mirrored connection can't be parsed as HTTP/1 requests.
+#[derive(Debug, thiserror::Error)]
+enum ParseError {
+    #[error(transparent)]
+    Http(#[from] httparse::Error),
+    #[error("invalid `Content-Length`")]
+    ContentLength,
+    #[error("invalid chunked body")]
+    Chunked,
+    #[error("request too large")]
+    TooLarge,
+}
+
+/// An HTTP request parsed from a mirrored connection.
+#[derive(Debug, PartialEq, Eq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// Minor version of HTTP/1.
+    version: u8,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Parses the first request in `buffer`. Returns the request and its length in `buffer`, or
+    /// [`None`] if the request is not complete yet.
+    fn parse(buffer: &[u8]) -> Result<Option<(Self, usize)>, ParseError> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(head_length) = request.parse(buffer)? else {
+            return Ok(None);
+        };
+
+        let headers = request
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    header.name.to_string(),
+                    String::from_utf8_lossy(header.value).into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+        };
+
+        let rest = buffer.get(head_length..).unwrap_or_default();
+        // `chunked` is the last coding, when there is one.
+        let chunked = header("transfer-encoding").is_some_and(|encoding| {
+            encoding
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        });
+
+        let (body, body_length) = if chunked {
+            match parse_chunked(rest)? {
+                Some(chunked) => chunked,
+                None => return Ok(None),
+            }
+        } else {
+            let body_length = match header("content-length") {
+                Some(length) => length
+                    .parse::<usize>()
+                    .map_err(|_| ParseError::ContentLength)?,
+                None => 0,
+            };
+            if body_length > MAX_REQUEST_SIZE {
+                return Err(ParseError::TooLarge);
+            }
+
+            match rest.get(..body_length) {
+                Some(body) => (body.to_vec(), body_length),
+                None => return Ok(None),
+            }
+        };
+
+        let request = Self {
+            method: request.method.unwrap_or_default().to_string(),
+            path: request.path.unwrap_or_default().to_string(),
+            version: request.version.unwrap_or(1),
+            headers,
+            body,
+        };
+        let length = head_length
+            .checked_add(body_length)
+            .ok_or(ParseError::TooLarge)?;
+
+        Ok(Some((request, length)))
+    }
+
+    /// The request with its secrets redacted.
+    fn redacted(self) -> Self {
+        Self {
+            path: secrets::redact_all(&self.path),
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), secrets::redact_header(name, value)))
+                .collect(),
+            body: secrets::redact_bytes(&self.body).into_owned(),
+            ..self
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn http_version(&self) -> String {
+        format!("HTTP/1.{}", self.version)
+    }
+
+    /// Line of the `jsonl` dump. Bodies that are not UTF-8 are written as `body_base64`.
+    fn to_jsonl(&self, connection: &Connection, time: SystemTime) -> Value {
+        let mut line = json!({
+            "time": humantime::format_rfc3339_millis(time).to_string(),
+            "connection_id": connection.id,
+            "source": connection.source.to_string(),
+            "destination": connection.destination.to_string(),
+            "method": self.method,
+            "path": self.path,
+            "version": self.http_version(),
+            "headers": self.headers,
+        });
+
+        match std::str::from_utf8(&self.body) {
+            Ok(body) => line["body"] = body.into(),
+            Err(..) => {
+                line["body_base64"] = base64::engine::general_purpose::STANDARD
+                    .encode(&self.body)
+                    .into()
+            }
+        }
+
+        line
+    }
+
+    /// Entry of the HAR 1.2 dump, with an empty response.
+    fn to_har_entry(&self, connection: &Connection, time: SystemTime) -> Value {
+        let host = self
+            .header("host")
+            .map(String::from)
+            .unwrap_or_else(|| connection.destination.to_string());
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect::<Vec<_>>();
+        let query = self
+            .path
+            .split_once('?')
+            .map(|(_, query)| query)
+            .unwrap_or_default()
+            .split('&')
+            .filter(|parameter| !parameter.is_empty())
+            .map(|parameter| {
+                let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+                json!({ "name": name, "value": value })
+            })
+            .collect::<Vec<_>>();
+
+        let mut request = json!({
+            "method": self.method,
+            "url": format!("http://{host}{}", self.path),
+            "httpVersion": self.http_version(),
+            "cookies": [],
+            "headers": headers,
+            "queryString": query,
+            "headersSize": -1,
+            "bodySize": self.body.len(),
+        });
+        if !self.body.is_empty() {
+            request["postData"] = json!({
+                "mimeType": self.header("content-type").unwrap_or_default(),
+                "text": String::from_utf8_lossy(&self.body),
+            });
+        }
+
+        json!({
+            "startedDateTime": humantime::format_rfc3339_millis(time).to_string(),
+            "time": 0,
+            "request": request,
+            "response": {
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": 0, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            },
+            "cache": {},
+            "timings": { "send": 0, "wait": 0, "receive": 0 },
+            "serverIPAddress": connection.destination.ip().to_string(),
+            "connection": connection.id.to_string(),
+            "comment": "Mirrored by mirrord, the response is not captured.",
+        })
+    }
+}
+
+/// A mirrored connection.
+#[derive(Debug)]
+struct Connection {
+    id: ConnectionId,
+    source: SocketAddr,
+    destination: SocketAddr,
+    /// Data of the request that is not complete yet, up to [`MAX_REQUEST_SIZE`].
+    pending: Vec<u8>,
+    /// Set when the data is not HTTP/1 (or a request is too large), we stop parsing it then.
+    not_http: bool,
+    /// TCP sequence number of the next packet in the `pcap` dump.
+    sequence: u32,
+}
+
+impl From<NewTcpConnection> for Connection {
+    fn from(connection: NewTcpConnection) -> Self {
+        Self {
+            id: connection.connection_id,
+            source: SocketAddr::new(connection.remote_address, connection.source_port),
+            destination: SocketAddr::new(connection.local_address, connection.destination_port),
+            pending: Vec::new(),
+            not_http: false,
+            sequence: 0,
+        }
+    }
+}
+
+impl Connection {
+    /// Adds `bytes` to the pending data, and takes the complete requests out of it.
+    fn requests(&mut self, bytes: &[u8]) -> Vec<HttpRequest> {
+        if self.not_http {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(bytes);
+
+        let mut requests = Vec::new();
+        loop {
+            match HttpRequest::parse(&self.pending) {
+                Ok(Some((request, length))) => {
+                    self.pending.drain(..length);
+                    requests.push(request);
+                }
+                Ok(None) if self.pending.len() > MAX_REQUEST_SIZE => {
+                    self.stop_parsing(ParseError::TooLarge);
+                    break;
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    self.stop_parsing(error);
+                    break;
+                }
+            }
+        }
+
+        requests
+    }
+
+    fn stop_parsing(&mut self, error: ParseError) {
+        tracing::debug!(connection_id = self.id, %error, "not an HTTP/1 request");
+        self.not_http = true;
+        self.pending = Vec::new();
+    }
+
+    /// Packets of the `pcap` dump for `bytes`, as if they were sent now.
+    fn packets(&mut self, bytes: &[u8], time: SystemTime) -> Vec<u8> {
+        let mut packets = Vec::new();
+        for payload in bytes.chunks(PCAP_MAX_PAYLOAD) {
+            packets.extend(pcap_packet(
+                self.source,
+                self.destination,
+                self.sequence,
+                payload,
+                time,
+            ));
+            // Wraps around, like TCP sequence numbers do.
+            self.sequence = self.sequence.wrapping_add(payload.len() as u32);
+        }
+
+        packets
+    }
+}
+
+/// Decodes the chunked body at the start of `buffer`. Returns the body and its (encoded) length in
+/// `buffer`, or [`None`] if the body is not complete yet.
+fn parse_chunked(buffer: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    let mut body = Vec::new();
+    let mut position = 0;
+
+    loop {
+        let rest = buffer.get(position..).unwrap_or_default();
+        let (size_length, size) =
+            match httparse::parse_chunk_size(rest).map_err(|_| ParseError::Chunked)? {
+                httparse::Status::Complete(chunk_size) => chunk_size,
+                httparse::Status::Partial => return Ok(None),
+            };
+        position += size_length;
+
+        if size == 0 {
+            // The trailers, up to an empty line.
+            loop {
+                let rest = buffer.get(position..).unwrap_or_default();
+                let Some(line_length) = rest.windows(2).position(|end| end == b"\r\n") else {
+                    return Ok(None);
+                };
+                position += line_length + 2;
+
+                if line_length == 0 {
+                    return Ok(Some((body, position)));
+                }
+            }
+        }
+
+        let end = usize::try_from(size)
+            .ok()
+            .and_then(|size| position.checked_add(size))
+            .filter(|end| *end - position + body.len() <= MAX_REQUEST_SIZE)
+            .ok_or(ParseError::TooLarge)?;
+        let Some(chunk) = buffer.get(position..end) else {
+            return Ok(None);
+        };
+        body.extend_from_slice(chunk);
+
+        match buffer.get(end..end + 2) {
+            Some(b"\r\n") => position = end + 2,
+            Some(..) => return Err(ParseError::Chunked),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Writes the mirrored traffic in the [`DumpFormat`].
+struct Dump<W> {
+    format: DumpFormat,
+    writer: W,
+    connections: HashMap<ConnectionId, Connection>,
+    /// Entries of the HAR dump, written when the dump is finished.
+    har_entries: Vec<Value>,
+    /// How many requests (or for `pcap`, data chunks) were dumped.
+    dumped: usize,
+}
+
+impl<W: Write> Dump<W> {
+    fn new(format: DumpFormat, mut writer: W) -> io::Result<Self> {
+        if format == DumpFormat::Pcap {
+            writer.write_all(&pcap_header())?;
+        }
+
+        Ok(Self {
+            format,
+            writer,
+            connections: HashMap::new(),
+            har_entries: Vec::new(),
+            dumped: 0,
+        })
+    }
+
+    fn new_connection(&mut self, connection: NewTcpConnection) {
+        self.connections
+            .insert(connection.connection_id, connection.into());
+    }
+
+    fn data(
+        &mut self,
+        TcpData {
+            connection_id,
+            bytes,
+        }: TcpData,
+    ) -> io::Result<()> {
+        let Some(connection) = self.connections.get_mut(&connection_id) else {
+            tracing::debug!(connection_id, "data of an unknown connection");
+            return Ok(());
+        };
+        let time = SystemTime::now();
+
+        match self.format {
+            DumpFormat::Pcap => {
+                let bytes = secrets::redact_bytes(&bytes);
+                self.writer.write_all(&connection.packets(&bytes, time))?;
+                self.dumped += 1;
+            }
+            DumpFormat::Jsonl => {
+                for request in connection.requests(&bytes) {
+                    let request = request.redacted();
+                    serde_json::to_writer(&mut self.writer, &request.to_jsonl(connection, time))?;
+                    self.writer.write_all(b"\n")?;
+                    self.dumped += 1;
+                }
+            }
+            DumpFormat::Har => {
+                for request in connection.requests(&bytes) {
+                    self.har_entries
+                        .push(request.redacted().to_har_entry(connection, time));
+                    self.dumped += 1;
+                }
+            }
+        }
+
+        // Some formats are followed live, e.g. with `tail -f`.
+        self.writer.flush()
+    }
+
+    fn close(&mut self, connection_id: ConnectionId) {
+        self.connections.remove(&connection_id);
+    }
+
+    /// Writes what is left, and returns how many requests were dumped.
+    fn finish(mut self) -> io::Result<usize> {
+        if self.format == DumpFormat::Har {
+            let har = json!({
+                "log": {
+                    "version": "1.2",
+                    "creator": { "name": "mirrord", "version": env!("CARGO_PKG_VERSION") },
+                    "entries": self.har_entries,
+                }
+            });
+            serde_json::to_writer_pretty(&mut self.writer, &har)?;
+            self.writer.write_all(b"\n")?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(self.dumped)
+    }
+}
+
+/// Global header of the `pcap` dump, little endian.
+fn pcap_header() -> Vec<u8> {
+    [
+        0xa1b2_c3d4_u32.to_le_bytes().as_slice(),
+        &2_u16.to_le_bytes(),
+        &4_u16.to_le_bytes(),
+        &0_i32.to_le_bytes(),
+        &0_u32.to_le_bytes(),
+        &262_144_u32.to_le_bytes(),
+        &PCAP_LINKTYPE_RAW.to_le_bytes(),
+    ]
+    .concat()
+}
+
+/// A `pcap` record of a TCP packet (with the `PSH` and `ACK` flags) from `source` to
+/// `destination`.
+fn pcap_packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+    payload: &[u8],
+    time: SystemTime,
+) -> Vec<u8> {
+    let tcp = [
+        source.port().to_be_bytes().as_slice(),
+        &destination.port().to_be_bytes(),
+        &sequence.to_be_bytes(),
+        // Acknowledgment number, we don't see the other side.
+        &0_u32.to_be_bytes(),
+        // Header length of 5 words, PSH and ACK.
+        &[0x50, 0x18],
+        // Window size.
+        &u16::MAX.to_be_bytes(),
+        // Checksum (not computed) and urgent pointer.
+        &[0, 0, 0, 0],
+        payload,
+    ]
+    .concat();
+
+    let packet = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut header = [
+                [0x45_u8, 0].as_slice(),
+                &((20 + tcp.len()) as u16).to_be_bytes(),
+                // Identification, don't fragment.
+                &[0, 0, 0x40, 0],
+                // TTL and TCP.
+                &[64, 6],
+                // Checksum, filled below.
+                &[0, 0],
+                &source.octets(),
+                &destination.octets(),
+            ]
+            .concat();
+            let checksum = ipv4_checksum(&header).to_be_bytes();
+            if let Some(field) = header.get_mut(10..12) {
+                field.copy_from_slice(&checksum);
+            }
+
+            [header, tcp].concat()
+        }
+        (source, destination) => {
+            let to_v6 = |address| match address {
+                IpAddr::V4(address) => address.to_ipv6_mapped(),
+                IpAddr::V6(address) => address,
+            };
+
+            [
+                [0x60_u8, 0, 0, 0].as_slice(),
+                &(tcp.len() as u16).to_be_bytes(),
+                // TCP and hop limit.
+                &[6, 64],
+                &to_v6(source).octets(),
+                &to_v6(destination).octets(),
+                &tcp,
+            ]
+            .concat()
+        }
+    };
+
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    [
+        (since_epoch.as_secs() as u32).to_le_bytes().as_slice(),
+        &since_epoch.subsec_micros().to_le_bytes(),
+        &(packet.len() as u32).to_le_bytes(),
+        &(packet.len() as u32).to_le_bytes(),
+        &packet,
+    ]
+    .concat()
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| match word {
+            [high, low] => u32::from(u16::from_be_bytes([*high, *low])),
+            [high] => u32::from(*high) << 8,
+            _ => 0,
+        })
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Runs `mirrord dump`, until the user stops it or the `duration` passes.
+pub(crate) async fn dump_command(args: DumpArgs) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord dump");
+
+    if let Some(target) = &args.target {
+        std::env::set_var("MIRRORD_IMPERSONATED_TARGET", target);
+    }
+
+    if let Some(namespace) = &args.target_namespace {
+        std::env::set_var("MIRRORD_TARGET_NAMESPACE", namespace);
+    }
+
+    // Removed when we're done, if it was fetched.
+    let _config_file = if let Some(config_file) = &args.config_file {
+        let config_file = config_file_path(config_file).await?;
+        std::env::set_var("MIRRORD_CONFIG_FILE", config_file.path());
+        Some(config_file)
+    } else {
+        None
+    };
+
+    // The local process would be the only one to get the traffic with `steal`.
+    std::env::set_var("MIRRORD_AGENT_TCP_STEAL_TRAFFIC", "false");
+
+    conditional::resolve_env()?;
+    let (config, mut context) = LayerConfig::from_env_with_warnings()?;
+    config.verify(&mut context)?;
+    for warning in context.get_warnings() {
+        progress.warning(warning);
+    }
+
+    if !config.use_proxy {
+        remove_proxy_env();
+    }
+
+    let writer: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(CliError::DumpFailed)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut dump = Dump::new(args.format, BufWriter::new(writer)).map_err(CliError::DumpFailed)?;
+
+    let mut analytics = NullReporter::default();
+    let (_, mut connection) = create_and_connect(&config, &mut progress, &mut analytics).await?;
+
+    for port in &args.ports {
+        connection
+            .sender
+            .send(ClientMessage::Tcp(LayerTcp::PortSubscribe(*port)))
+            .await
+            .map_err(|_| CliError::AgentClosedConnection("the connection was closed".into()))?;
+    }
+
+    let mut pings = time::interval(PING_INTERVAL);
+    let deadline = args
+        .duration
+        .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+
+    loop {
+        tokio::select! {
+            message = connection.receiver.recv() => match message {
+                Some(DaemonMessage::Tcp(DaemonTcp::SubscribeResult(result))) => {
+                    let port = result.map_err(CliError::DumpSubscribeFailed)?;
+                    progress.info(&format!("mirroring port {port}, press Ctrl+C to stop"));
+                }
+                Some(DaemonMessage::Tcp(DaemonTcp::NewConnection(new_connection))) => {
+                    dump.new_connection(new_connection);
+                }
+                Some(DaemonMessage::Tcp(DaemonTcp::Data(data))) => {
+                    dump.data(data).map_err(CliError::DumpFailed)?;
+                }
+                Some(DaemonMessage::Tcp(DaemonTcp::Close(TcpClose { connection_id }))) => {
+                    dump.close(connection_id);
+                }
+                Some(DaemonMessage::LogMessage(log)) => match log.level {
+                    LogLevel::Warn => progress.warning(&log.message),
+                    LogLevel::Error => tracing::error!("agent error: {}", log.message),
+                },
+                Some(DaemonMessage::Close(reason)) => {
+                    return Err(CliError::AgentClosedConnection(reason));
+                }
+                Some(message) => tracing::trace!(?message, "ignoring agent message"),
+                None => {
+                    progress.warning("the connection with the agent was closed");
+                    break;
+                }
+            },
+
+            _ = pings.tick() => {
+                connection
+                    .sender
+                    .send(ClientMessage::Ping)
+                    .await
+                    .map_err(|_| CliError::CantSendPing)?;
+            },
+
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break,
+
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    let dumped = dump.finish().map_err(CliError::DumpFailed)?;
+    let what = match args.format {
+        DumpFormat::Pcap => "data chunks",
+        DumpFormat::Har | DumpFormat::Jsonl => "requests",
+    };
+    progress.success(Some(&format!("dumped {dumped} {what}")));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn connection() -> Connection {
+        Connection::from(NewTcpConnection {
+            connection_id: 7,
+            remote_address: Ipv4Addr::new(10, 0, 0, 1).into(),
+            destination_port: 80,
+            source_port: 41_000,
+            local_address: Ipv4Addr::new(10, 0, 0, 2).into(),
+        })
+    }
+
+    #[test]
+    fn parses_requests_across_chunks() {
+        let mut connection = connection();
+
+        assert!(connection
+            .requests(b"POST /orders?id=1 HTTP/1.1\r\nHost: shop\r\nContent-Length: 4\r\n\r\nab")
+            .is_empty());
+
+        let requests = connection.requests(b"cdGET / HTTP/1.1\r\n\r\nGET /next");
+        assert_eq!(
+            requests,
+            [
+                HttpRequest {
+                    method: "POST".into(),
+                    path: "/orders?id=1".into(),
+                    version: 1,
+                    headers: vec![
+                        ("Host".into(), "shop".into()),
+                        ("Content-Length".into(), "4".into())
+                    ],
+                    body: b"abcd".to_vec(),
+                },
+                HttpRequest {
+                    method: "GET".into(),
+                    path: "/".into(),
+                    version: 1,
+                    headers: vec![],
+                    body: vec![],
+                }
+            ]
+        );
+        assert_eq!(connection.pending, b"GET /next");
+
+        let entry = requests[0].to_har_entry(&connection, SystemTime::now());
+        assert_eq!(entry["request"]["url"], "http://shop/orders?id=1");
+        assert_eq!(
+            entry["request"]["queryString"],
+            json!([{ "name": "id", "value": "1" }])
+        );
+    }
+
+    #[test]
+    fn redacts_requests() {
+        let mut connection = connection();
+        let request = connection
+            .requests(
+                b"POST /login?token=s3cr3t-value HTTP/1.1\r\nAuthorization: Bearer abc.def-ghi\r\n\
+                Content-Length: 18\r\n\r\npassword=hunter22!",
+            )
+            .remove(0)
+            .redacted();
+
+        assert_eq!(request.path, "/login?token=[REDACTED credential]");
+        assert_eq!(
+            request.headers[0].1,
+            "Bearer [REDACTED authorization header]"
+        );
+        assert_eq!(request.body, b"password=[REDACTED credential]");
+    }
+
+    #[test]
+    fn parses_chunked_requests() {
+        let mut connection = connection();
+
+        assert!(connection
+            .requests(b"POST /upload HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n4\r\nab")
+            .is_empty());
+
+        let requests =
+            connection.requests(b"cd\r\n3;name=value\r\nefg\r\n0\r\nDigest: x\r\n\r\nGET");
+        assert!(
+            matches!(requests.as_slice(), [request] if request.body == b"abcdefg"),
+            "{requests:?}"
+        );
+        assert_eq!(connection.pending, b"GET");
+    }
+
+    #[test]
+    fn stops_parsing_huge_requests() {
+        let mut overflow = connection();
+        assert!(overflow
+            .requests(b"POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n")
+            .is_empty());
+        assert!(overflow.not_http);
+
+        let mut chunked = connection();
+        assert!(chunked
+            .requests(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n")
+            .is_empty());
+        assert!(chunked.not_http);
+
+        let mut endless = connection();
+        endless.requests(b"GET /");
+        endless.requests(&vec![b'a'; MAX_REQUEST_SIZE]);
+        assert!(endless.not_http);
+        assert!(endless.pending.is_empty());
+    }
+
+    #[test]
+    fn stops_parsing_other_protocols() {
+        let mut connection = connection();
+
+        assert!(connection.requests(b"\x16\x03\x01\x02\x00").is_empty());
+        assert!(connection.not_http);
+        assert!(connection.requests(b"GET / HTTP/1.1\r\n\r\n").is_empty());
+    }
+
+    #[test]
+    fn builds_pcap_packets() {
+        let mut connection = connection();
+        let time = UNIX_EPOCH + Duration::from_micros(1_500_000);
+
+        let packets = connection.packets(b"hello", time);
+        let (record, packet) = packets.split_at(16);
+
+        assert!(record.starts_with(&[1, 0, 0, 0, 0x20, 0xa1, 0x07, 0]));
+        assert_eq!(packet.len(), 20 + 20 + 5);
+        assert_eq!(ipv4_checksum(packet.get(..20).unwrap()), 0);
+        assert_eq!(packet.get(20..22).unwrap(), 41_000_u16.to_be_bytes());
+        assert!(packet.ends_with(b"hello"));
+        assert_eq!(connection.sequence, 5);
+
+        let packets = connection.packets(b"!", time);
+        assert_eq!(packets.get(16 + 24..16 + 28).unwrap(), 5_u32.to_be_bytes());
+    }
+}
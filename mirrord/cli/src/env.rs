@@ -0,0 +1,107 @@
+//! `mirrord env`, writes the remote environment to a file, for the tools that can't run with the
+//! layer (IDE run configurations, docker compose, other languages' test runners).
+//!
+//! The environment is fetched like in `mirrord exec`, with
+//! [`MirrordExecution::fetch_env_vars`], see [`remote_env`] for the library API.
+use std::{io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+
+use mirrord_analytics::NullReporter;
+use mirrord_config::LayerConfig;
+use mirrord_intproxy::remote_env;
+use mirrord_progress::{Progress, ProgressTracker};
+
+use crate::{
+    conditional, config::EnvFormat, connection::create_and_connect, execution::MirrordExecution,
+    remote_config::config_file_path, util::remove_proxy_env, CliError, EnvArgs, Result,
+};
+
+/// Format of the `output` file, from its extension when not given.
+fn output_format(format: Option<EnvFormat>, output: Option<&Path>) -> EnvFormat {
+    format.unwrap_or_else(|| {
+        match output
+            .and_then(|output| output.extension())
+            .and_then(|extension| extension.to_str())
+        {
+            Some("json") => EnvFormat::Json,
+            _ => EnvFormat::Dotenv,
+        }
+    })
+}
+
+/// Runs `mirrord env`.
+pub(crate) async fn env_command(args: EnvArgs) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord env");
+
+    if let Some(target) = &args.target {
+        std::env::set_var("MIRRORD_IMPERSONATED_TARGET", target);
+    }
+
+    if let Some(namespace) = &args.target_namespace {
+        std::env::set_var("MIRRORD_TARGET_NAMESPACE", namespace);
+    }
+
+    // Removed when we're done, if it was fetched.
+    let _config_file = if let Some(config_file) = &args.config_file {
+        let config_file = config_file_path(config_file).await?;
+        std::env::set_var("MIRRORD_CONFIG_FILE", config_file.path());
+        Some(config_file)
+    } else {
+        None
+    };
+
+    conditional::resolve_env()?;
+    let (config, mut context) = LayerConfig::from_env_with_warnings()?;
+    config.verify(&mut context)?;
+    for warning in context.get_warnings() {
+        progress.warning(warning);
+    }
+
+    if !config.use_proxy {
+        remove_proxy_env();
+    }
+
+    let mut analytics = NullReporter::default();
+    let (_, mut connection) = create_and_connect(&config, &mut progress, &mut analytics).await?;
+    let env = MirrordExecution::fetch_env_vars(&config, &mut connection).await?;
+
+    let text = match output_format(args.format, args.output.as_deref()) {
+        EnvFormat::Dotenv => remote_env::to_dotenv(&env),
+        EnvFormat::Json => remote_env::to_json(env)?,
+    };
+
+    let Some(output) = args.output else {
+        progress.success(None);
+        print!("{text}");
+        return Ok(());
+    };
+
+    // The environment often has secrets.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&output)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|fail| CliError::EnvFileFailed(output.clone(), fail))?;
+
+    progress.success(Some(&format!(
+        "wrote the environment to `{}`",
+        output.display()
+    )));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            output_format(None, Some(Path::new("env.json"))),
+            EnvFormat::Json
+        );
+    }
+}
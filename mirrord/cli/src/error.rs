@@ -2,9 +2,12 @@ use std::path::PathBuf;
 
 use miette::Diagnostic;
 use mirrord_console::error::ConsoleError;
-use mirrord_intproxy::{agent_conn::AgentConnectionError, error::IntProxyError};
+use mirrord_intproxy::{
+    agent_conn::AgentConnectionError, error::IntProxyError, remote_env::RemoteEnvError,
+};
 use mirrord_kube::error::KubeApiError;
 use mirrord_operator::client::{HttpError, OperatorApiError};
+use mirrord_protocol::ResponseError;
 use thiserror::Error;
 
 pub(crate) type Result<T, E = CliError> = miette::Result<T, E>;
@@ -164,6 +167,13 @@ pub(crate) enum CliError {
     ))]
     BinaryExecuteFailed(String, Vec<String>),
 
+    #[cfg(target_os = "linux")]
+    #[error("Failed to run `{0}` under the seccomp filter: {1}")]
+    #[diagnostic(help(
+        "`--intercept=seccomp` needs Linux 5.14 or later, and permission to read the memory of the binary (see `kernel.yama.ptrace_scope`).{GENERAL_HELP}"
+    ))]
+    InterceptFailed(String, std::io::Error),
+
     #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
     #[error("Binary is SIP protected and rosetta is missing")]
     #[diagnostic(help(
@@ -187,12 +197,168 @@ pub(crate) enum CliError {
     ))]
     ConfigError(#[from] mirrord_config::config::ConfigError),
 
+    #[error("Failed to resolve the conditional sections of the config file: {0}")]
+    #[diagnostic(help(
+        "The sections are checked against the kube context of the target, please check that \
+        it's set and that the kubeconfig can be read.{GENERAL_HELP}"
+    ))]
+    ConditionalConfigFailed(String),
+
     #[error("Error with config file's path at `{0:#?}`: `{1:#?}`")]
     #[diagnostic(help(
         "Please check that the path is correct and that you have permissions to read it.{GENERAL_HELP}",
     ))]
     ConfigFilePathError(PathBuf, std::io::Error),
 
+    #[error("Invalid config file source `{0}`")]
+    #[diagnostic(help(
+        "ConfigMap sources look like `k8s://<namespace>/<configmap>/<key>`, optionally followed by `#sha256=<checksum>`.{GENERAL_HELP}"
+    ))]
+    RemoteConfigSourceInvalid(String),
+
+    #[error("Failed to fetch the config file from `{0}`: {1}")]
+    #[diagnostic(help(
+        "Please check that the source is correct and that you have permissions to read it.{GENERAL_HELP}"
+    ))]
+    RemoteConfigFetchFailed(String, String),
+
+    #[error("Failed to fetch the {0} in `feature.fs`: {1}")]
+    #[diagnostic(help(
+        "Please check that it exists in the target's namespace, and that you have permissions to read it.{GENERAL_HELP}"
+    ))]
+    ClusterFileFetchFailed(String, String),
+
+    #[error("Failed to write the ConfigMaps and Secrets in `feature.fs` to `{0:?}`: {1}")]
+    #[diagnostic(help(
+        "Please check that you can write to the temporary directory.{GENERAL_HELP}"
+    ))]
+    ClusterFilesWriteFailed(PathBuf, std::io::Error),
+
+    #[error("The config file from `{location}` has checksum `{actual}`, expected `{expected}`")]
+    #[diagnostic(help(
+        "The config file changed since it was pinned. Please check the change, and update the checksum if it's expected."
+    ))]
+    RemoteConfigChecksumMismatch {
+        location: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Unknown config key `{0}`")]
+    #[diagnostic(help(
+        "Config keys are dotted paths, e.g. `feature.network.incoming`. Run `mirrord completions --config-keys` to list all of them."
+    ))]
+    UnknownConfigKey(String),
+
+    #[error("Failed to redact the file: {0}")]
+    #[diagnostic(help(
+        "Please check that the paths are correct and that you have permissions to read and write them.{GENERAL_HELP}",
+    ))]
+    RedactFailed(std::io::Error),
+
+    #[error("Reviewing the secrets requires an interactive terminal")]
+    #[diagnostic(help(
+        "Run the command in a terminal, or without `--review` to redact all secrets."
+    ))]
+    RedactReviewNotInteractive,
+
+    #[error("Failed to write the dump: {0}")]
+    #[diagnostic(help(
+        "Please check that the output path is correct and that you have permissions to write it.{GENERAL_HELP}",
+    ))]
+    DumpFailed(std::io::Error),
+
+    #[error("Failed to mirror the port: {0}")]
+    #[diagnostic(help(
+        "Please check that the target listens on the port, and that the agent can mirror its traffic.{GENERAL_HELP}",
+    ))]
+    DumpSubscribeFailed(ResponseError),
+
+    #[error("The agent closed the connection: {0}")]
+    #[diagnostic(help("Please check the agent logs.{GENERAL_HELP}"))]
+    AgentClosedConnection(String),
+
+    #[error("Failed to listen on local port {0}: {1}")]
+    #[diagnostic(help(
+        "Please check that the port is not used by another process, or choose another local port.{GENERAL_HELP}",
+    ))]
+    PortForwardBindFailed(u16, std::io::Error),
+
+    #[error("Failed to watch `{0}`: {1}")]
+    #[diagnostic(help(
+        "Please check that the path exists and that you have permissions to read it."
+    ))]
+    WatchPathInvalid(PathBuf, std::io::Error),
+
+    #[error("Failed to keep the mirrord session for the restarted process: {0}")]
+    #[diagnostic(help("The internal proxy might have exited.{GENERAL_HELP}"))]
+    WatchSessionFailed(std::io::Error),
+
+    #[error("No mirrord daemon session `{0}` is running")]
+    #[diagnostic(help(
+        "Start it with `mirrord daemon start`, or check the name with `--session`.{GENERAL_HELP}"
+    ))]
+    DaemonNotRunning(String),
+
+    #[error("The mirrord daemon session `{0}` is already running")]
+    #[diagnostic(help(
+        "Stop it with `mirrord daemon stop`, or start another one with `--name`.{GENERAL_HELP}"
+    ))]
+    DaemonAlreadyRunning(String),
+
+    #[error("Failed to access the mirrord daemon session file `{0}`: {1}")]
+    #[diagnostic(help(
+        "Please check that you have permissions to read and write it.{GENERAL_HELP}"
+    ))]
+    DaemonSessionFile(PathBuf, std::io::Error),
+
+    #[error("Invalid mirrord daemon session name `{0}`")]
+    #[diagnostic(help(
+        "Session names can only contain letters, digits, `-`, `_` and `.`, and can't start with \
+         `.`.{GENERAL_HELP}"
+    ))]
+    DaemonSessionName(String),
+
+    #[error("Failed to pick the target: {0}")]
+    #[diagnostic(help(
+        "Please set the target with `--target`, or in the config file.{GENERAL_HELP}"
+    ))]
+    TargetPickerFailed(#[from] dialoguer::Error),
+
+    #[error("`--pick` needs a terminal to pick the target in")]
+    #[diagnostic(help(
+        "Please set the target with `--target`, or in the config file.{GENERAL_HELP}"
+    ))]
+    TargetPickerNoTerminal,
+
+    #[error("Failed to read the status of the sessions at `{0}`: {1}")]
+    #[diagnostic(help("Please check that you have permissions to read it.{GENERAL_HELP}"))]
+    SessionStatusFailed(PathBuf, std::io::Error),
+
+    #[error("Failed to access the record file `{0}`: {1}")]
+    #[diagnostic(help(
+        "Please check that you have permissions to read and write it.{GENERAL_HELP}"
+    ))]
+    RecordFileFailed(PathBuf, std::io::Error),
+
+    #[error("Line {1} of the record file `{0}` is not a recorded request: {2}")]
+    #[diagnostic(help("Please use a file written by `mirrord exec --record`.{GENERAL_HELP}"))]
+    RecordFileInvalid(PathBuf, usize, String),
+
+    #[error("Failed to replay `{0}`: {1}")]
+    #[diagnostic(help(
+        "Please check that the local process is running and listens on the port.{GENERAL_HELP}"
+    ))]
+    ReplayFailed(String, String),
+
+    #[error("{0} of the replayed requests got a different status than recorded")]
+    #[diagnostic(help("The responses of the local process differ from the recorded ones."))]
+    ReplayMismatch(usize),
+
+    #[error("Failed to write the environment to `{0}`: {1}")]
+    #[diagnostic(help("Please check that you have permissions to write it.{GENERAL_HELP}"))]
+    EnvFileFailed(PathBuf, std::io::Error),
+
     #[error("Creating kubernetes manifest yaml file failed with err : {0:#?}")]
     #[diagnostic(help(
         r#"Check if you have permissions to write to the file and/or directory exists{GENERAL_HELP}"#
@@ -217,6 +383,9 @@ pub(crate) enum CliError {
     #[error("JSON Serialization error: `{0:#?}`")]
     JsonSerializeError(#[from] serde_json::Error),
 
+    #[error("YAML Serialization error: `{0:#?}`")]
+    YamlSerializeError(#[from] serde_yaml::Error),
+
     #[error("Failed connecting to mirrord console for logging {0:#?}")]
     ConsoleConnectError(#[from] ConsoleError),
 
@@ -312,6 +481,15 @@ pub(crate) enum CliError {
         status: Box<kube::core::Status>,
     },
 
+    #[error("Operator failed when {operation}: {kind}. {message}")]
+    #[diagnostic(help("{remediation}{GENERAL_HELP}"))]
+    OperatorFailure {
+        operation: String,
+        kind: String,
+        message: String,
+        remediation: &'static str,
+    },
+
     #[error("Agent returned invalid response to ping")]
     #[diagnostic(help(
         r#"This usually means that connectivity was lost while pinging. {GENERAL_HELP}"#
@@ -339,6 +517,7 @@ impl From<OperatorApiError> for CliError {
             OperatorApiError::CreateApiError(e) => Self::KubernetesApiFailed(e),
             OperatorApiError::InvalidTarget { reason } => Self::InvalidTargetError(reason),
             OperatorApiError::ConnectRequestBuildError(e) => Self::ConnectRequestBuildError(e),
+            OperatorApiError::SplitQueuesSerializeError(e) => Self::JsonSerializeError(e),
             OperatorApiError::KubeError { error, operation } => {
                 Self::OperatorConnectionFailed(format!("{operation} failed: {error}"))
             }
@@ -348,6 +527,19 @@ impl From<OperatorApiError> for CliError {
             OperatorApiError::NoLicense => {
                 Self::OperatorConnectionFailed("No license found, falling back to OSS".to_string())
             }
+            error @ OperatorApiError::CertificateProvider(..) => {
+                Self::OperatorConnectionFailed(error.to_string())
+            }
+            OperatorApiError::OperatorFailure {
+                kind,
+                message,
+                operation,
+            } => Self::OperatorFailure {
+                operation: operation.to_string(),
+                kind: kind.to_string(),
+                message,
+                remediation: kind.remediation(),
+            },
         }
     }
 }
@@ -366,3 +558,23 @@ impl From<AgentConnectionError> for CliError {
         }
     }
 }
+
+impl From<RemoteEnvError> for CliError {
+    fn from(err: RemoteEnvError) -> Self {
+        match err {
+            RemoteEnvError::IncludeAndExclude(include, exclude) => {
+                CliError::InvalidEnvConfig(include, exclude)
+            }
+            RemoteEnvError::Timeout => CliError::InitialCommFailed(
+                "Timeout waiting for remote environment variables.".to_string(),
+            ),
+            RemoteEnvError::ConnectionClosed => {
+                CliError::InitialCommFailed("Agent connection unexpectedly closed".to_string())
+            }
+            RemoteEnvError::AgentClosed(msg) => {
+                CliError::InitialCommFailed(format!("Connection closed with message: `{msg}`"))
+            }
+            RemoteEnvError::UnexpectedMessage(msg) => CliError::InvalidMessage(msg),
+        }
+    }
+}
@@ -1,13 +1,9 @@
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-    time::Duration,
-};
+use std::{collections::HashMap, sync::Arc};
 
-use mirrord_analytics::{AnalyticsError, AnalyticsReporter, Reporter};
-use mirrord_config::LayerConfig;
-use mirrord_progress::Progress;
-use mirrord_protocol::{ClientMessage, DaemonMessage, EnvVars, GetEnvVarsRequest, LogLevel};
+use mirrord_analytics::{Analytics, AnalyticsError, AnalyticsReporter, CollectAnalytics, Reporter};
+use mirrord_config::{feature::fs::CLUSTER_FILES_DIR_ENV, LayerConfig};
+use mirrord_intproxy::remote_env;
+use mirrord_progress::{timings, Progress};
 #[cfg(target_os = "macos")]
 use mirrord_sip::sip_patch;
 use serde::Serialize;
@@ -18,18 +14,20 @@ use tokio::{
     sync::RwLock,
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, trace};
 
 use crate::{
+    cluster_files,
     connection::{create_and_connect, AgentConnection, AGENT_CONNECT_INFO_ENV_KEY},
+    daemon::DaemonSession,
     error::CliError,
-    extract::extract_library,
+    extract::{extract_library, layer_files_env},
     util::remove_proxy_env,
     Result,
 };
 
 #[cfg(target_os = "linux")]
-const INJECTION_ENV_VAR: &str = "LD_PRELOAD";
+pub(crate) const INJECTION_ENV_VAR: &str = "LD_PRELOAD";
 
 #[cfg(target_os = "macos")]
 const INJECTION_ENV_VAR: &str = "DYLD_INSERT_LIBRARIES";
@@ -40,8 +38,9 @@ const INJECTION_ENV_VAR: &str = "DYLD_INSERT_LIBRARIES";
 pub(crate) struct MirrordExecution {
     pub environment: HashMap<String, String>,
 
+    /// The internal proxy, `None` when attached to the session of a `mirrord daemon`.
     #[serde(skip)]
-    child: Child,
+    child: Option<Child>,
 
     /// The path to the patched binary, if patched for SIP sidestepping.
     pub patched_path: Option<String>,
@@ -49,6 +48,18 @@ pub(crate) struct MirrordExecution {
     pub env_to_unset: Vec<String>,
 }
 
+/// Durations of the startup phases marked so far in this process, in milliseconds.
+pub(crate) struct StartupTimings;
+
+impl CollectAnalytics for StartupTimings {
+    fn collect_analytics(&self, analytics: &mut Analytics) {
+        for (phase, duration) in timings::phases() {
+            let millis = u32::try_from(duration.as_millis()).unwrap_or(u32::MAX);
+            analytics.add(phase.name(), millis);
+        }
+    }
+}
+
 /// Struct that when dropped will cancel the token and wait on the join handle
 /// then update progress with the warnings returned.
 struct DropProgress<'a, P>
@@ -123,7 +134,7 @@ where
 }
 
 impl MirrordExecution {
-    #[tracing::instrument(level = "trace", skip_all)]
+    #[tracing::instrument(level = "info", name = "session_setup", skip_all)]
     pub(crate) async fn start<P>(
         config: &LayerConfig,
         // We only need the executable on macos, for SIP handling.
@@ -140,6 +151,7 @@ impl MirrordExecution {
             remove_proxy_env();
         }
 
+        timings::start();
         let (connect_info, mut connection) = create_and_connect(config, progress, analytics)
             .await
             .inspect_err(|_| analytics.set_error(AnalyticsError::AgentConnection))?;
@@ -152,6 +164,18 @@ impl MirrordExecution {
                 .inspect_err(|_| analytics.set_error(AnalyticsError::EnvFetch))?
         };
 
+        let mut env_to_unset = config
+            .feature
+            .env
+            .unset
+            .clone()
+            .map(|unset| unset.to_vec())
+            .unwrap_or_default();
+        if !config.feature.env.load_from_process.unwrap_or(false) {
+            env_to_unset.extend(config.feature.env.locale_vars_to_unset(&env_vars));
+        }
+
+        env_vars.extend(layer_files_env(&lib_path));
         let lib_path: String = lib_path.to_string_lossy().into();
         // Set LD_PRELOAD/DYLD_INSERT_LIBRARIES
         // If already exists, we append.
@@ -174,8 +198,19 @@ impl MirrordExecution {
             .stderr(std::process::Stdio::piped())
             .stdin(std::process::Stdio::null());
 
+        // Removed when we fail to start the internal proxy, which removes them from then on.
+        let cluster_files = cluster_files::fetch(config).await?;
+        if let Some(cluster_files) = &cluster_files {
+            proxy_command.env(CLUSTER_FILES_DIR_ENV, cluster_files.path());
+            env_vars.insert(
+                CLUSTER_FILES_DIR_ENV.to_string(),
+                cluster_files.path().to_string_lossy().into_owned(),
+            );
+        }
+
         let connect_info = serde_json::to_string(&connect_info)?;
         proxy_command.env(AGENT_CONNECT_INFO_ENV_KEY, connect_info);
+        proxy_command.env(timings::STARTUP_TIMINGS_ENV, timings::to_env());
 
         let mut proxy_process = proxy_command
             .spawn()
@@ -192,20 +227,30 @@ impl MirrordExecution {
             .take()
             .ok_or(CliError::InternalProxyStdoutError)?;
 
-        let port: u16 = BufReader::new(stdout)
+        let address = BufReader::new(stdout)
             .lines()
             .next_line()
             .await
             .map_err(CliError::InternalProxyReadError)?
-            .ok_or(CliError::InternalProxyPortReadError)?
-            .parse()
-            .map_err(CliError::InternalProxyPortParseError)?;
+            .ok_or(CliError::InternalProxyPortReadError)?;
+
+        // The internal proxy started, it holds its own guard.
+        if let Some(cluster_files) = cluster_files {
+            cluster_files.hand_over();
+        }
 
         // Provide details for layer to connect to agent via internal proxy
-        env_vars.insert(
-            "MIRRORD_CONNECT_TCP".to_string(),
-            format!("127.0.0.1:{port}"),
-        );
+        if config.internal_proxy.unix_socket {
+            env_vars.insert("MIRRORD_CONNECT_UNIX".to_string(), address);
+        } else {
+            let port: u16 = address
+                .parse()
+                .map_err(CliError::InternalProxyPortParseError)?;
+            env_vars.insert(
+                "MIRRORD_CONNECT_TCP".to_string(),
+                format!("127.0.0.1:{port}"),
+            );
+        }
 
         // Fix https://github.com/metalbear-co/mirrord/issues/1745
         // by disabling the fork safety check in the Objective-C runtime.
@@ -216,131 +261,76 @@ impl MirrordExecution {
         );
 
         #[cfg(target_os = "macos")]
-        let patched_path = executable
-            .and_then(|exe| {
-                sip_patch(
-                    exe,
-                    &config
-                        .sip_binaries
-                        .clone()
-                        .map(|x| x.to_vec())
-                        .unwrap_or_default(),
-                )
-                .transpose() // We transpose twice to propagate a possible error out of this
-                             // closure.
-            })
-            .transpose()?;
+        let patched_path = Self::patch_executable(config, executable)?;
 
         #[cfg(not(target_os = "macos"))]
         let patched_path = None;
 
         Ok(Self {
             environment: env_vars,
-            child: proxy_process,
+            child: Some(proxy_process),
             patched_path,
-            env_to_unset: config
-                .feature
-                .env
-                .unset
-                .clone()
-                .map(|unset| unset.to_vec())
-                .unwrap_or_default(),
+            env_to_unset,
         })
     }
 
-    /// Construct filter and retrieve remote environment from the connected agent using
-    /// `MirrordExecution::get_remote_env`.
-    async fn fetch_env_vars(
-        config: &LayerConfig,
-        connection: &mut AgentConnection,
-    ) -> Result<HashMap<String, String>> {
-        let mut env_vars = HashMap::new();
-
-        let (env_vars_exclude, env_vars_include) = match (
-            config
-                .feature
-                .env
-                .exclude
-                .clone()
-                .map(|exclude| exclude.join(";")),
-            config
-                .feature
-                .env
-                .include
-                .clone()
-                .map(|include| include.join(";")),
-        ) {
-            (Some(exclude), Some(include)) => {
-                return Err(CliError::InvalidEnvConfig(include, exclude))
-            }
-            (Some(exclude), None) => (HashSet::from(EnvVars(exclude)), HashSet::new()),
-            (None, Some(include)) => (HashSet::new(), HashSet::from(EnvVars(include))),
-            (None, None) => (HashSet::new(), HashSet::from(EnvVars("*".to_owned()))),
-        };
+    /// Uses the session of a running `mirrord daemon`, whose internal proxy is shared with the
+    /// processes attached to it.
+    pub(crate) fn attach(
+        session: DaemonSession,
+        #[cfg(target_os = "macos")] config: &LayerConfig,
+        #[cfg(target_os = "macos")] executable: Option<&str>,
+    ) -> Result<Self> {
+        #[cfg(target_os = "macos")]
+        let patched_path = Self::patch_executable(config, executable)?;
 
-        let communication_timeout =
-            Duration::from_secs(config.agent.communication_timeout.unwrap_or(30).into());
+        #[cfg(not(target_os = "macos"))]
+        let patched_path = None;
 
-        if !env_vars_exclude.is_empty() || !env_vars_include.is_empty() {
-            let remote_env = tokio::time::timeout(
-                communication_timeout,
-                Self::get_remote_env(connection, env_vars_exclude, env_vars_include),
-            )
-            .await
-            .map_err(|_| {
-                CliError::InitialCommFailed(
-                    "Timeout waiting for remote environment variables.".to_string(),
-                )
-            })??;
-            env_vars.extend(remote_env);
-            if let Some(overrides) = &config.feature.env.r#override {
-                env_vars.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
-            }
-        }
+        Ok(Self {
+            environment: session.environment,
+            child: None,
+            patched_path,
+            env_to_unset: session.env_to_unset,
+        })
+    }
 
-        Ok(env_vars)
+    /// Process id of the internal proxy, when we started it.
+    pub(crate) fn proxy_pid(&self) -> Option<u32> {
+        self.child.as_ref().and_then(Child::id)
     }
 
-    /// Retrieve remote environment from the connected agent.
-    async fn get_remote_env(
+    /// Patches the `executable` for SIP sidestepping, if it's SIP protected.
+    #[cfg(target_os = "macos")]
+    fn patch_executable(config: &LayerConfig, executable: Option<&str>) -> Result<Option<String>> {
+        executable
+            .and_then(|exe| {
+                sip_patch(
+                    exe,
+                    &config
+                        .sip_binaries
+                        .clone()
+                        .map(|x| x.to_vec())
+                        .unwrap_or_default(),
+                )
+                .transpose() // We transpose twice to propagate a possible error out of this
+                             // closure.
+            })
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    /// Retrieves the remote environment from the connected agent, see
+    /// [`mirrord_intproxy::remote_env`].
+    ///
+    /// This is the environment the binary gets, also written by `mirrord env`.
+    pub(crate) async fn fetch_env_vars(
+        config: &LayerConfig,
         connection: &mut AgentConnection,
-        env_vars_filter: HashSet<String>,
-        env_vars_select: HashSet<String>,
     ) -> Result<HashMap<String, String>> {
-        connection
-            .sender
-            .send(ClientMessage::GetEnvVarsRequest(GetEnvVarsRequest {
-                env_vars_filter,
-                env_vars_select,
-            }))
+        remote_env::fetch_remote_env(config, &connection.sender, &mut connection.receiver)
             .await
-            .map_err(|_| {
-                CliError::InitialCommFailed(
-                    "Failed to request remote environment variables.".to_string(),
-                )
-            })?;
-
-        let remote_env = loop {
-            match connection.receiver.recv().await {
-                Some(DaemonMessage::GetEnvVarsResponse(Ok(remote_env))) => {
-                    trace!("DaemonMessage::GetEnvVarsResponse {:#?}!", remote_env.len());
-                    break remote_env;
-                }
-                Some(DaemonMessage::LogMessage(msg)) => match msg.level {
-                    LogLevel::Error => error!("Agent log: {}", msg.message),
-                    LogLevel::Warn => warn!("Agent log: {}", msg.message),
-                },
-                Some(DaemonMessage::Close(msg)) => Err(CliError::InitialCommFailed(format!(
-                    "Connection closed with message: `{msg}`"
-                )))?,
-                Some(msg) => Err(CliError::InvalidMessage(format!("{msg:#?}")))?,
-                None => Err(CliError::InitialCommFailed(
-                    "Agent connection unexpectedly closed".to_string(),
-                ))?,
-            }
-        };
-
-        Ok(remote_env)
+            .map_err(Into::into)
     }
 
     /// Wait for the internal proxy to exit.
@@ -349,10 +339,12 @@ impl MirrordExecution {
     /// while the internal proxy is running.
     /// See https://github.com/metalbear-co/mirrord/issues/1211
     pub(crate) async fn wait(mut self) -> Result<()> {
-        self.child
-            .wait()
-            .await
-            .map_err(CliError::InternalProxyWaitError)?;
+        if let Some(child) = self.child.as_mut() {
+            child
+                .wait()
+                .await
+                .map_err(CliError::InternalProxyWaitError)?;
+        }
         Ok(())
     }
 }
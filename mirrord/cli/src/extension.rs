@@ -4,7 +4,9 @@ use mirrord_analytics::{AnalyticsError, AnalyticsReporter, Reporter};
 use mirrord_config::LayerConfig;
 use mirrord_progress::{JsonProgress, Progress, ProgressTracker};
 
-use crate::{config::ExtensionExecArgs, error::CliError, execution::MirrordExecution, Result};
+use crate::{
+    conditional, config::ExtensionExecArgs, execution::MirrordExecution, remote_config, Result,
+};
 
 /// Actualy facilitate execution after all preperatations were complete
 async fn mirrord_exec<P>(
@@ -42,22 +44,29 @@ pub(crate) async fn extension_exec(args: ExtensionExecArgs, watch: drain::Watch)
         .unwrap_or_else(|| JsonProgress::new("mirrord preparing to launch").into());
     let mut env: HashMap<String, String> = HashMap::new();
 
-    if let Some(config_file) = args.config_file.as_ref() {
-        // Set canoncialized path to config file, in case forks/children are in different
-        // working directories.
-        let full_path = std::fs::canonicalize(config_file)
-            .map_err(|e| CliError::ConfigFilePathError(config_file.into(), e))?;
-        std::env::set_var("MIRRORD_CONFIG_FILE", full_path.clone());
+    // Removed when the session ends, if it was fetched.
+    let _config_file = if let Some(config_file) = args.config_file.as_ref() {
+        let config_file = remote_config::config_file_path(config_file).await?;
+        std::env::set_var("MIRRORD_CONFIG_FILE", config_file.path());
         env.insert(
             "MIRRORD_CONFIG_FILE".into(),
-            full_path.to_string_lossy().into(),
+            config_file.path().to_string_lossy().into(),
         );
+        Some(config_file)
+    } else {
+        None
+    };
+    if let Some(profile) = args.profile.as_ref() {
+        std::env::set_var("MIRRORD_PROFILE", profile);
+        env.insert("MIRRORD_PROFILE".into(), profile.clone());
     }
     if let Some(target) = args.target.as_ref() {
         std::env::set_var("MIRRORD_IMPERSONATED_TARGET", target.clone());
         env.insert("MIRRORD_IMPERSONATED_TARGET".into(), target.to_string());
     }
+    env.extend(conditional::resolve_env()?);
     let (config, mut context) = LayerConfig::from_env_with_warnings()?;
+    crate::otel::init(&config);
 
     let mut analytics = AnalyticsReporter::only_error(config.telemetry, watch);
 
@@ -1,3 +1,10 @@
+#[cfg(target_os = "linux")]
+use std::{
+    fs::DirBuilder,
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    sync::OnceLock,
+};
 use std::{
     fs::File,
     io::Write,
@@ -6,6 +13,8 @@ use std::{
 
 use const_random::const_random;
 use mirrord_progress::Progress;
+#[cfg(target_os = "linux")]
+use sha2::{Digest, Sha256};
 use tracing::debug;
 
 use crate::{error::CliError, Result};
@@ -28,6 +37,118 @@ use std::env::temp_dir;
 #[cfg(target_os = "macos")]
 use mac::temp_dir;
 
+/// The layer built for the other architecture, for children of that architecture (e.g. x86_64
+/// processes under emulation on aarch64). Empty when the CLI was built without
+/// `MIRRORD_LAYER_FILE_CROSS`.
+///
+/// macOS doesn't need it, the layer there is a universal binary.
+#[cfg(target_os = "linux")]
+const CROSS_LAYER: &[u8] = include_bytes!(env!("MIRRORD_LAYER_FILE_CROSS"));
+
+/// Architecture of the [`CROSS_LAYER`].
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const CROSS_ARCH: &str = "aarch64";
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const CROSS_ARCH: &str = "x86_64";
+
+/// Writes `bytes` to `file_path`, unless it's already there with the same contents. A file left
+/// by another build (or by anyone else) is never loaded as is.
+fn write_library(file_path: &Path, bytes: &[u8]) -> Result<()> {
+    if std::fs::read(file_path).is_ok_and(|existing| existing == bytes) {
+        return Ok(());
+    }
+
+    let mut file = File::create(file_path)
+        .map_err(|e| CliError::LayerExtractFailed(file_path.to_owned(), e))?;
+    file.write_all(bytes)
+        .map_err(|e| CliError::LayerExtractFailed(file_path.to_owned(), e))?;
+    debug!("Extracted library file to {:?}", file_path);
+
+    Ok(())
+}
+
+/// Name of the directory the [`CROSS_LAYER`] is extracted to, for the user and with a hash of the
+/// layer, so that different users and builds don't share it.
+#[cfg(target_os = "linux")]
+fn cross_layer_dir_name() -> &'static str {
+    static NAME: OnceLock<String> = OnceLock::new();
+
+    NAME.get_or_init(|| {
+        let uid = unsafe { libc::getuid() };
+        let hash = Sha256::digest(CROSS_LAYER)
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        format!("mirrord-{uid}-{CROSS_ARCH}-{hash}")
+    })
+}
+
+/// Where the [`CROSS_LAYER`] is extracted, next to the layer extracted to `lib_path`.
+#[cfg(target_os = "linux")]
+fn cross_layer_path(lib_path: &Path) -> Option<PathBuf> {
+    Some(
+        lib_path
+            .parent()?
+            .join(cross_layer_dir_name())
+            .join(lib_path.file_name()?),
+    )
+}
+
+/// Creates the directory of the [`CROSS_LAYER`], closed to everyone but the user. We refuse to
+/// use it otherwise, as another user could have created it first in a shared temp dir, and
+/// swapped the layer after we checked it.
+#[cfg(target_os = "linux")]
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(fail) if fail.kind() != io::ErrorKind::AlreadyExists => return Err(fail),
+        _ => {}
+    }
+
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::getuid() }
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the directory is not private to the user",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Variable with the path of the layer built for `arch`, read by the exec hooks of the layer
+/// (`child_env`), which load the layer of the architecture of the executed program.
+#[cfg(target_os = "linux")]
+fn layer_file_env(arch: &str) -> String {
+    format!("MIRRORD_LAYER_FILE_{}", arch.to_uppercase())
+}
+
+/// Variables with the path of the layer of every architecture, for the layer extracted to
+/// `lib_path`. Empty when the CLI doesn't ship the [`CROSS_LAYER`].
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn layer_files_env(lib_path: &Path) -> Vec<(String, String)> {
+    #[cfg(target_os = "linux")]
+    if let Some(cross_path) = cross_layer_path(lib_path).filter(|_| !CROSS_LAYER.is_empty()) {
+        return vec![
+            (
+                layer_file_env(std::env::consts::ARCH),
+                lib_path.to_string_lossy().into_owned(),
+            ),
+            (
+                layer_file_env(CROSS_ARCH),
+                cross_path.to_string_lossy().into_owned(),
+            ),
+        ];
+    }
+
+    Vec::new()
+}
+
 /// Extract to given directory, or tmp by default.
 /// If prefix is true, add a random prefix to the file name that identifies the specific build
 /// of the layer. This is useful for debug purposes usually.
@@ -52,18 +173,75 @@ where
         format!("libmirrord_layer.{extension}")
     };
 
-    let file_path = match dest_dir {
-        Some(dest_dir) => std::path::Path::new(&dest_dir).join(file_name),
-        None => temp_dir().as_path().join(file_name),
+    let dest_dir = match dest_dir {
+        Some(dest_dir) => PathBuf::from(dest_dir),
+        None => temp_dir(),
     };
-    if !file_path.exists() {
-        let mut file = File::create(&file_path)
-            .map_err(|e| CliError::LayerExtractFailed(file_path.clone(), e))?;
-        let bytes = include_bytes!(env!("MIRRORD_LAYER_FILE"));
-        file.write_all(bytes).unwrap();
-        debug!("Extracted library file to {:?}", &file_path);
+    let file_path = dest_dir.join(file_name);
+    write_library(&file_path, include_bytes!(env!("MIRRORD_LAYER_FILE")))?;
+
+    #[cfg(target_os = "linux")]
+    if let Some(cross_path) = cross_layer_path(&file_path).filter(|_| !CROSS_LAYER.is_empty()) {
+        let cross_dir = dest_dir.join(cross_layer_dir_name());
+        create_private_dir(&cross_dir).map_err(|e| CliError::LayerExtractFailed(cross_dir, e))?;
+        write_library(&cross_path, CROSS_LAYER)?;
     }
 
     progress.success(Some("layer extracted"));
     Ok(file_path)
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn cross_layer_next_to_layer() {
+        let lib_path = Path::new("/tmp/123-libmirrord_layer.so");
+
+        let cross_path = cross_layer_path(lib_path).unwrap();
+        assert_eq!(
+            cross_path,
+            Path::new("/tmp")
+                .join(cross_layer_dir_name())
+                .join("123-libmirrord_layer.so")
+        );
+        let uid = unsafe { libc::getuid() };
+        assert!(cross_layer_dir_name().starts_with(&format!("mirrord-{uid}-{CROSS_ARCH}-")));
+
+        // Read by the layer.
+        let mut vars = [
+            layer_file_env(std::env::consts::ARCH),
+            layer_file_env(CROSS_ARCH),
+        ];
+        vars.sort();
+        assert_eq!(
+            vars,
+            ["MIRRORD_LAYER_FILE_AARCH64", "MIRRORD_LAYER_FILE_X86_64"]
+        );
+    }
+
+    /// A directory others can write to is not used, and a library with other contents is
+    /// replaced.
+    #[test]
+    fn private_dir_and_verified_library() {
+        let dir = std::env::temp_dir().join(format!("mirrord-extract-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        create_private_dir(&dir).unwrap();
+        create_private_dir(&dir).unwrap();
+
+        let lib_path = dir.join("libmirrord_layer.so");
+        std::fs::write(&lib_path, b"someone else's").unwrap();
+        write_library(&lib_path, b"layer").unwrap();
+        assert_eq!(std::fs::read(&lib_path).unwrap(), b"layer");
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let fail = create_private_dir(&dir).unwrap_err();
+        assert_eq!(fail.kind(), io::ErrorKind::PermissionDenied);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
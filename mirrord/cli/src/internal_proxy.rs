@@ -21,8 +21,10 @@ use mirrord_analytics::{AnalyticsError, AnalyticsReporter, CollectAnalytics, Rep
 use mirrord_config::LayerConfig;
 use mirrord_intproxy::{
     agent_conn::{AgentConnectInfo, AgentConnection},
+    layer_listener::LayerListener,
     IntProxy,
 };
+use mirrord_progress::timings;
 use mirrord_protocol::{pause::DaemonPauseTarget, ClientMessage, DaemonMessage, LogLevel};
 use nix::{
     libc,
@@ -31,11 +33,15 @@ use nix::{
 use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, log::trace, warn};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use crate::{
+    cluster_files::ClusterFilesGuard,
     connection::AGENT_CONNECT_INFO_ENV_KEY,
     error::{CliError, InternalProxySetupError, Result},
+    execution::StartupTimings,
+    otel,
+    remote_config::ConfigFile,
 };
 
 unsafe fn redirect_fd_to_dev_null(fd: libc::c_int) {
@@ -61,14 +67,13 @@ unsafe fn detach_io() -> Result<()> {
     Ok(())
 }
 
-/// Print the port for the caller (mirrord cli execution flow) so it can pass it
-/// back to the layer instances via env var.
-fn print_port(listener: &TcpListener) -> Result<()> {
-    let port = listener
-        .local_addr()
-        .map_err(InternalProxySetupError::LocalPortError)?
-        .port();
-    println!("{port}\n");
+/// Print the port (or the Unix socket path) for the caller (mirrord cli execution flow) so it can
+/// pass it back to the layer instances via env var.
+fn print_address(listener: &LayerListener) -> Result<()> {
+    let address = listener
+        .address()
+        .map_err(InternalProxySetupError::LocalPortError)?;
+    println!("{address}\n");
     Ok(())
 }
 
@@ -136,6 +141,16 @@ fn create_listen_socket() -> Result<TcpListener, InternalProxySetupError> {
     TcpListener::from_std(socket.into()).map_err(InternalProxySetupError::ListenError)
 }
 
+/// Creates the listener for the layers, a Unix socket in the temporary directory when
+/// `internal_proxy.unix_socket` is enabled, or a localhost TCP port otherwise.
+fn create_layer_listener(config: &LayerConfig) -> Result<LayerListener, InternalProxySetupError> {
+    if config.internal_proxy.unix_socket {
+        LayerListener::bind_unix(&env::temp_dir()).map_err(InternalProxySetupError::ListenError)
+    } else {
+        create_listen_socket().map(LayerListener::Tcp)
+    }
+}
+
 fn get_agent_connect_info() -> Result<Option<AgentConnectInfo>> {
     let Ok(var) = env::var(AGENT_CONNECT_INFO_ENV_KEY) else {
         return Ok(None);
@@ -147,25 +162,43 @@ fn get_agent_connect_info() -> Result<Option<AgentConnectInfo>> {
 /// Main entry point for the internal proxy.
 /// It listens for inbound layer connect and forwards to agent.
 pub(crate) async fn proxy(watch: drain::Watch) -> Result<()> {
+    // First, so that the `ConfigMap`s, `Secret`s and fetched config are removed whatever fails
+    // next.
+    let _cluster_files = ClusterFilesGuard::from_env();
+    let _config_file = ConfigFile::from_env();
+
+    // The cli marked the phases up to here.
+    timings::inherit();
+    timings::start();
+
     let config = LayerConfig::from_env()?;
 
-    if let Some(ref log_destination) = config.internal_proxy.log_destination {
+    let log_layer = if let Some(ref log_destination) = config.internal_proxy.log_destination {
         let output_file = std::fs::OpenOptions::new()
             .create(true)
             .append(true)
             .open(log_destination)
             .map_err(CliError::OpenIntProxyLogFile)?;
-        let tracing_registry = tracing_subscriber::fmt()
-            .with_writer(output_file)
-            .with_ansi(false);
-        if let Some(ref log_level) = config.internal_proxy.log_level {
-            tracing_registry
-                .with_env_filter(EnvFilter::builder().parse_lossy(log_level))
-                .init();
-        } else {
-            tracing_registry.init();
-        }
-    }
+        let log_filter = match config.internal_proxy.log_level {
+            Some(ref log_level) => EnvFilter::builder().parse_lossy(log_level),
+            None => EnvFilter::new("info"),
+        };
+
+        Some(
+            fmt::layer()
+                .with_writer(output_file)
+                .with_ansi(false)
+                .with_filter(log_filter),
+        )
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(otel::layer())
+        .with(log_layer)
+        .init();
+    otel::init(&config);
 
     // According to https://wilsonmar.github.io/maximum-limits/ this is the limit on macOS
     // so we assume Linux can be higher and set to that.
@@ -178,8 +211,8 @@ pub(crate) async fn proxy(watch: drain::Watch) -> Result<()> {
     let mut analytics = AnalyticsReporter::new(config.telemetry, watch);
     (&config).collect_analytics(analytics.get_mut());
 
-    // Let it assign port for us then print it for the user.
-    let listener = create_listen_socket()?;
+    // Let it assign port (or socket path) for us then print it for the user.
+    let listener = create_layer_listener(&config)?;
 
     // Create a main connection, that will be held until proxy is closed.
     // This will guarantee agent staying alive and will enable us to
@@ -204,7 +237,7 @@ pub(crate) async fn proxy(watch: drain::Watch) -> Result<()> {
     let (main_connection_cancellation_token, main_connection_task_join) =
         create_ping_loop(main_connection);
 
-    print_port(&listener)?;
+    print_address(&listener)?;
 
     unsafe {
         detach_io()?;
@@ -218,6 +251,14 @@ pub(crate) async fn proxy(watch: drain::Watch) -> Result<()> {
         .run(first_connection_timeout, consecutive_connection_timeout)
         .await?;
 
+    let startup = timings::phases()
+        .into_iter()
+        .map(|(phase, duration)| format!("{}={:?}", phase.name(), duration))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!(startup, "session startup timings");
+    analytics.get_mut().add("startup_ms", StartupTimings);
+
     main_connection_cancellation_token.cancel();
 
     trace!("intproxy joining main connection task");
@@ -243,6 +284,7 @@ async fn connect_and_ping(
     let AgentConnection {
         agent_tx,
         mut agent_rx,
+        ..
     } = AgentConnection::new(config, agent_connect_info, analytics).await?;
     ping(&agent_tx, &mut agent_rx).await?;
     Ok((agent_tx, agent_rx))
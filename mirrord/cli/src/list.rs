@@ -0,0 +1,457 @@
+//! `mirrord ls`, lists the targets in the cluster.
+//!
+//! The default output is a JSON array of target paths. With `-o detailed-json`, every target also
+//! has the metadata that the IDE extensions show (kind, namespace, replicas, containers, labels,
+//! and whether the operator covers it), so that they don't have to query the cluster again.
+use std::collections::{BTreeMap, HashSet};
+
+use k8s_openapi::{
+    api::{
+        apps::v1::Deployment,
+        core::v1::{Namespace, Pod},
+    },
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    Metadata, NamespaceResourceScope,
+};
+use kube::api::ListParams;
+use mirrord_config::{
+    config::{ConfigContext, MirrordConfig},
+    target::{DeploymentTarget, PodTarget, RolloutTarget, Target},
+    LayerConfig, LayerFileConfig,
+};
+use mirrord_kube::{
+    api::{
+        container::SKIP_NAMES,
+        kubernetes::{create_kube_api, get_k8s_resource_api, rollout::Rollout},
+    },
+    error::KubeApiError,
+};
+use mirrord_operator::crd::TargetCrd;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    conditional, remote_config, util::remove_proxy_env, CliError, Format, ListTargetArgs, Result,
+    TargetKind,
+};
+
+impl TargetKind {
+    /// Prefix of the target path.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Pod => "pod",
+            Self::Deployment => "deployment",
+            Self::Rollout => "rollout",
+        }
+    }
+}
+
+/// A target in the `detailed-json` output.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TargetInfo {
+    /// Path of the target, e.g. `pod/nginx/container/nginx`.
+    pub path: String,
+    pub kind: TargetKind,
+    pub name: String,
+    namespace: String,
+    /// Container of the path, only for pods with multiple containers.
+    container: Option<String>,
+    /// Containers of the pods, without the mesh sidecars.
+    pub containers: Vec<String>,
+    /// Ready pods, `1` for a pod.
+    pub ready_replicas: i64,
+    /// Desired pods, `1` for a pod.
+    pub replicas: i64,
+    labels: BTreeMap<String, String>,
+    /// Whether the mirrord operator covers the target, `false` when there is no operator.
+    operator: bool,
+}
+
+impl TargetInfo {
+    fn new(kind: TargetKind, metadata: ObjectMeta, containers: Vec<String>) -> Option<Self> {
+        let name = metadata.name?;
+
+        Some(Self {
+            path: format!("{}/{name}", kind.name()),
+            kind,
+            name,
+            namespace: metadata.namespace.unwrap_or_default(),
+            container: None,
+            containers,
+            ready_replicas: 1,
+            replicas: 1,
+            labels: metadata.labels.unwrap_or_default(),
+            operator: false,
+        })
+    }
+
+    /// Splits a pod with multiple containers into a target per container, like the paths of
+    /// `mirrord ls` always were.
+    fn per_container(self) -> Vec<Self> {
+        if self.containers.len() == 1 {
+            return vec![self];
+        }
+
+        self.containers
+            .iter()
+            .map(|container| Self {
+                path: format!("{}/container/{container}", self.path),
+                container: Some(container.clone()),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// Name of the target in the operator (see [`TargetCrd::target_name`]), without the
+    /// container.
+    fn operator_name(&self) -> String {
+        let target = match self.kind {
+            TargetKind::Pod => Target::Pod(PodTarget {
+                pod: self.name.clone(),
+                container: None,
+            }),
+            TargetKind::Deployment => Target::Deployment(DeploymentTarget {
+                deployment: self.name.clone(),
+                container: None,
+            }),
+            TargetKind::Rollout => Target::Rollout(RolloutTarget {
+                rollout: self.name.clone(),
+                container: None,
+            }),
+        };
+
+        TargetCrd::target_name(&target)
+    }
+}
+
+/// Containers of the pod spec, without the mesh sidecars.
+fn container_names<'a>(names: impl Iterator<Item = &'a str>) -> Vec<String> {
+    names
+        .filter(|name| !SKIP_NAMES.contains(*name))
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the pods, filtering out any pods which are not ready or have crashed.
+async fn get_kube_pods(namespace: Option<&str>, client: &kube::Client) -> Vec<TargetInfo> {
+    get_kube_resources::<Pod>(namespace, client, Some("status.phase=Running"))
+        .await
+        .filter(|pod| {
+            pod.status
+                .as_ref()
+                .and_then(|status| status.conditions.as_ref())
+                .map(|conditions| {
+                    // filter out pods without the Ready condition
+                    conditions
+                        .iter()
+                        .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+                })
+                .unwrap_or(false)
+        })
+        .filter_map(|pod| {
+            let containers = container_names(
+                pod.spec
+                    .as_ref()?
+                    .containers
+                    .iter()
+                    .map(|container| container.name.as_str()),
+            );
+
+            TargetInfo::new(TargetKind::Pod, pod.metadata, containers)
+        })
+        .flat_map(TargetInfo::per_container)
+        .collect()
+}
+
+async fn get_kube_deployments(namespace: Option<&str>, client: &kube::Client) -> Vec<TargetInfo> {
+    get_kube_resources::<Deployment>(namespace, client, None)
+        .await
+        .filter(|deployment| {
+            deployment
+                .status
+                .as_ref()
+                .map(|status| status.available_replicas >= Some(1))
+                .unwrap_or(false)
+        })
+        .filter_map(|deployment| {
+            let spec = deployment.spec.unwrap_or_default();
+            let containers = container_names(
+                spec.template
+                    .spec
+                    .iter()
+                    .flat_map(|spec| spec.containers.iter())
+                    .map(|container| container.name.as_str()),
+            );
+
+            Some(TargetInfo {
+                ready_replicas: deployment
+                    .status
+                    .and_then(|status| status.ready_replicas)
+                    .unwrap_or_default()
+                    .into(),
+                replicas: spec.replicas.unwrap_or(1).into(),
+                ..TargetInfo::new(TargetKind::Deployment, deployment.metadata, containers)?
+            })
+        })
+        .collect()
+}
+
+async fn get_kube_rollouts(namespace: Option<&str>, client: &kube::Client) -> Vec<TargetInfo> {
+    get_kube_resources::<Rollout>(namespace, client, None)
+        .await
+        .filter(|rollout| rollout.available_replicas() >= 1)
+        .filter_map(|rollout| {
+            let containers = container_names(
+                rollout
+                    .spec
+                    .pointer("/template/spec/containers")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|container| container.get("name")?.as_str()),
+            );
+            let replicas = rollout
+                .spec
+                .get("replicas")
+                .and_then(Value::as_i64)
+                .unwrap_or(1);
+
+            Some(TargetInfo {
+                ready_replicas: rollout.available_replicas(),
+                replicas,
+                ..TargetInfo::new(TargetKind::Rollout, rollout.metadata().clone(), containers)?
+            })
+        })
+        .collect()
+}
+
+/// Names of the targets that the operator covers, without their containers. Empty when the
+/// operator is not installed.
+async fn get_operator_targets(namespace: Option<&str>, client: &kube::Client) -> HashSet<String> {
+    get_k8s_resource_api::<TargetCrd>(client, namespace)
+        .list(&ListParams::default())
+        .await
+        .inspect_err(|error| tracing::debug!(%error, "failed to list the operator targets"))
+        .map(|targets| {
+            targets
+                .items
+                .iter()
+                .map(|target| match target.name().split_once(".container.") {
+                    Some((name, _)) => name.to_string(),
+                    None => target.name(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn get_kube_resources<K>(
+    namespace: Option<&str>,
+    client: &kube::Client,
+    field_selector: Option<&str>,
+) -> impl Iterator<Item = K>
+where
+    K: kube::Resource<Scope = NamespaceResourceScope>,
+    <K as kube::Resource>::DynamicType: Default,
+    K: Clone + DeserializeOwned + std::fmt::Debug,
+{
+    // Set up filters on the K8s resources returned - in this case, excluding the agent resources
+    // and then applying any provided field-based filter conditions.
+    let params = &mut ListParams::default().labels("app!=mirrord");
+    if let Some(fields) = field_selector {
+        params.field_selector = Some(fields.to_string())
+    }
+    get_k8s_resource_api(client, namespace)
+        .list(params)
+        .await
+        .map(|resources| resources.into_iter())
+        .map_err(KubeApiError::from)
+        .map_err(CliError::KubernetesApiFailed)
+        .unwrap_or_else(|_| Vec::new().into_iter())
+}
+
+/// Creates the client that lists the targets, with the cluster settings of the `config_file`.
+/// Returns the target namespace of the config too.
+async fn kube_client(config_file: Option<&str>) -> Result<(kube::Client, Option<String>)> {
+    let Some(config) = config_file else {
+        let client = create_kube_api(false, None::<String>, None, None, None)
+            .await
+            .map_err(CliError::KubernetesApiFailed)?;
+
+        return Ok((client, None));
+    };
+
+    let mut cfg_context = ConfigContext::default();
+    let config_file = remote_config::config_file_path(config).await?;
+    conditional::resolve(config_file.path())?;
+    let layer_config =
+        LayerFileConfig::from_path(config_file.path())?.generate_config(&mut cfg_context)?;
+    if !layer_config.use_proxy {
+        remove_proxy_env();
+    }
+
+    let client = config_kube_client(&layer_config).await?;
+
+    Ok((client, layer_config.target.namespace))
+}
+
+/// Creates the client that lists the targets, with the cluster settings of the `config`.
+pub(crate) async fn config_kube_client(config: &LayerConfig) -> Result<kube::Client> {
+    create_kube_api(
+        config.accept_invalid_certificates,
+        config.kubeconfig.clone(),
+        config
+            .target
+            .kube_context
+            .clone()
+            .or_else(|| config.kube_context.clone()),
+        config.proxy.clone(),
+        config.kube_api_url.clone(),
+    )
+    .await
+    .map_err(CliError::KubernetesApiFailed)
+}
+
+/// Names of the namespaces, empty when the user can't list them.
+pub(crate) async fn list_namespaces(client: &kube::Client) -> Vec<String> {
+    kube::Api::<Namespace>::all(client.clone())
+        .list(&ListParams::default())
+        .await
+        .inspect_err(|error| tracing::debug!(%error, "failed to list the namespaces"))
+        .map(|namespaces| {
+            namespaces
+                .items
+                .into_iter()
+                .filter_map(|namespace| namespace.metadata.name)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Lists the targets of the `kinds` (every kind when empty), sorted by their paths.
+pub(crate) async fn list_targets(
+    namespace: Option<&str>,
+    client: &kube::Client,
+    kinds: &[TargetKind],
+) -> Vec<TargetInfo> {
+    let listed = |kind| kinds.is_empty() || kinds.contains(&kind);
+
+    let (pods, deployments, rollouts) = tokio::join!(
+        async {
+            if listed(TargetKind::Pod) {
+                get_kube_pods(namespace, client).await
+            } else {
+                Vec::new()
+            }
+        },
+        async {
+            if listed(TargetKind::Deployment) {
+                get_kube_deployments(namespace, client).await
+            } else {
+                Vec::new()
+            }
+        },
+        async {
+            if listed(TargetKind::Rollout) {
+                get_kube_rollouts(namespace, client).await
+            } else {
+                Vec::new()
+            }
+        },
+    );
+
+    let mut targets = [pods, deployments, rollouts].concat();
+    targets.sort_by(|a, b| a.path.cmp(&b.path));
+    targets
+}
+
+/// Lists all possible target paths for pods.
+/// Example: ```[
+///  "pod/metalbear-deployment-85c754c75f-982p5",
+///  "pod/nginx-deployment-66b6c48dd5-dc9wk",
+///  "pod/py-serv-deployment-5c57fbdc98-pdbn4/container/py-serv",
+/// ]```
+///
+/// With `-o detailed-json`, prints [`TargetInfo`]s instead of the paths.
+pub(crate) async fn print_pod_targets(args: &ListTargetArgs) -> Result<()> {
+    let (client, namespace) = kube_client(args.config_file.as_deref()).await?;
+    let namespace = args.namespace.as_deref().or(namespace.as_deref());
+
+    let mut targets = list_targets(namespace, &client, &args.kind).await;
+
+    let json_obj = match args.output {
+        Format::Json => json!(targets
+            .iter()
+            .map(|target| &target.path)
+            .collect::<Vec<_>>()),
+        Format::DetailedJson => {
+            let operator_targets = get_operator_targets(namespace, &client).await;
+            for target in &mut targets {
+                target.operator = operator_targets.contains(&target.operator_name());
+            }
+
+            json!(targets)
+        }
+    };
+    println!("{json_obj}");
+    Ok(())
+}
+
+/// Prints the target paths in the `namespace`, one per line, for
+/// `mirrord completions --targets`.
+pub(crate) async fn print_target_paths(namespace: Option<&str>) -> Result<()> {
+    let (client, _) = kube_client(None).await?;
+
+    for target in list_targets(namespace, &client, &[]).await {
+        println!("{}", target.path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pods_per_container() {
+        let metadata = ObjectMeta {
+            name: Some("api-7d9f".into()),
+            namespace: Some("shop".into()),
+            ..Default::default()
+        };
+
+        let single = TargetInfo::new(TargetKind::Pod, metadata.clone(), vec!["api".into()])
+            .unwrap()
+            .per_container();
+        assert_eq!(
+            single
+                .iter()
+                .map(|target| target.path.as_str())
+                .collect::<Vec<_>>(),
+            ["pod/api-7d9f"]
+        );
+
+        let multiple = TargetInfo::new(
+            TargetKind::Pod,
+            metadata,
+            vec!["api".into(), "metrics".into()],
+        )
+        .unwrap()
+        .per_container();
+        assert_eq!(
+            multiple
+                .iter()
+                .map(|target| (target.path.as_str(), target.container.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                ("pod/api-7d9f/container/api", Some("api")),
+                ("pod/api-7d9f/container/metrics", Some("metrics"))
+            ]
+        );
+        assert_eq!(
+            multiple.first().unwrap().operator_name(),
+            "pod.api-7d9f".to_string()
+        );
+    }
+}
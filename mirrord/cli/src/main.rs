@@ -2,64 +2,73 @@
 #![feature(lazy_cell)]
 #![warn(clippy::indexing_slicing)]
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    time::Duration,
+};
 
-use clap::{CommandFactory, Parser};
-use clap_complete::generate;
+use clap::Parser;
 use config::*;
+use config_explain::config_command;
+use daemon::DaemonSession;
 use diagnose::diagnose_command;
 use exec::execvp;
-use execution::MirrordExecution;
+use execution::{MirrordExecution, StartupTimings};
 use extension::extension_exec;
 use extract::extract_library;
-use k8s_openapi::{
-    api::{apps::v1::Deployment, core::v1::Pod},
-    Metadata, NamespaceResourceScope,
-};
-use kube::api::ListParams;
 use miette::JSONReportHandler;
 use mirrord_analytics::{AnalyticsError, AnalyticsReporter, CollectAnalytics, Reporter};
-use mirrord_config::{
-    config::{ConfigContext, MirrordConfig},
-    LayerConfig, LayerFileConfig,
-};
-use mirrord_kube::{
-    api::{
-        container::SKIP_NAMES,
-        kubernetes::{create_kube_api, get_k8s_resource_api, rollout::Rollout},
-    },
-    error::KubeApiError,
-};
-use mirrord_progress::{Progress, ProgressTracker};
+use mirrord_config::LayerConfig;
+use mirrord_intproxy::recording::RECORD_FILE_ENV;
+use mirrord_kube::api::kubernetes::KubernetesAPI;
+use mirrord_progress::{timings, Progress, ProgressTracker};
 use operator::operator_command;
+use remote_config::ConfigFile;
 use semver::Version;
-use serde::de::DeserializeOwned;
-use serde_json::json;
 use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
 use which::which;
 
+mod cluster_files;
+mod completions;
+mod conditional;
 mod config;
+mod config_explain;
 mod connection;
+mod daemon;
 mod diagnose;
+mod dump;
+mod env;
 mod error;
 mod execution;
 mod extension;
 mod extract;
 mod internal_proxy;
+mod list;
 mod operator;
+mod otel;
+mod port_forward;
+mod remote_config;
+mod replay;
+#[cfg(target_os = "linux")]
+mod seccomp;
+#[cfg(target_os = "linux")]
+mod static_binary;
+mod status;
+mod target_picker;
 mod teams;
 mod util;
 mod verify_config;
+mod watch;
 
 pub(crate) use error::{CliError, Result};
 use verify_config::verify_config;
 
-use crate::util::remove_proxy_env;
-
 async fn exec_process<P>(
     config: LayerConfig,
     args: &ExecArgs,
+    session: Option<DaemonSession>,
+    config_file: Option<ConfigFile>,
     progress: &P,
     analytics: &mut AnalyticsReporter,
 ) -> Result<()>
@@ -68,11 +77,38 @@ where
 {
     let mut sub_progress = progress.subtask("preparing to launch process");
 
-    #[cfg(target_os = "macos")]
-    let execution_info =
-        MirrordExecution::start(&config, Some(&args.binary), &mut sub_progress, analytics).await?;
-    #[cfg(not(target_os = "macos"))]
-    let execution_info = MirrordExecution::start(&config, &mut sub_progress, analytics).await?;
+    let attached = session.is_some();
+    let execution_info = match session {
+        #[cfg(target_os = "macos")]
+        Some(session) => MirrordExecution::attach(session, &config, Some(&args.binary))?,
+        #[cfg(not(target_os = "macos"))]
+        Some(session) => MirrordExecution::attach(session)?,
+        #[cfg(target_os = "macos")]
+        None => {
+            MirrordExecution::start(&config, Some(&args.binary), &mut sub_progress, analytics)
+                .await?
+        }
+        #[cfg(not(target_os = "macos"))]
+        None => MirrordExecution::start(&config, &mut sub_progress, analytics).await?,
+    };
+
+    // The internal proxy we started holds its own guard. The processes attached to a daemon load
+    // its config file when it has one, otherwise ours is kept for them.
+    if let Some(config_file) = config_file {
+        if !attached
+            || std::env::var_os("MIRRORD_CONFIG_FILE")
+                .is_some_and(|path| path == config_file.path())
+        {
+            config_file.hand_over();
+        }
+    }
+
+    analytics.get_mut().add("startup_ms", StartupTimings);
+    if args.timings {
+        for (phase, duration) in timings::phases() {
+            progress.print(&format!("{}: {:.3}s", phase.name(), duration.as_secs_f64()));
+        }
+    }
 
     #[cfg(target_os = "macos")]
     let (_did_sip_patch, binary) = match execution_info.patched_path {
@@ -88,6 +124,12 @@ where
 
     // Set environment variables from agent + layer settings.
     for (key, value) in &execution_info.environment {
+        // With seccomp the layer would intercept the calls of dynamically linked binaries twice.
+        #[cfg(target_os = "linux")]
+        if args.intercept == Intercept::Seccomp && key == execution::INJECTION_ENV_VAR {
+            continue;
+        }
+
         std::env::set_var(key, value);
     }
 
@@ -100,6 +142,24 @@ where
     binary_args.insert(0, args.binary.clone());
 
     sub_progress.success(Some("ready to launch process"));
+
+    if !args.watch.is_empty() {
+        return watch::exec_watched(&binary, &binary_args, &args.watch, progress).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    if args.intercept == Intercept::Seccomp {
+        let code = seccomp::exec_intercepted(&config, &binary, &binary_args)
+            .map_err(|fail| CliError::InterceptFailed(binary.clone(), fail))?;
+        std::process::exit(code);
+    }
+
+    // The progress messages of the `ipc:<path>` mode are written in the background, don't lose
+    // them with the process image.
+    if let Some(output) = mirrord_progress::IpcOutput::from_env() {
+        output.flush();
+    }
+
     // The execve hook is not yet active and does not hijack this call.
     let err = execvp(binary.clone(), binary_args.clone());
     error!("Couldn't execute {:?}", err);
@@ -219,15 +279,72 @@ async fn exec(args: &ExecArgs, watch: drain::Watch) -> Result<()> {
         std::env::set_var("MIRRORD_KUBE_CONTEXT", context);
     }
 
-    if let Some(config_file) = &args.config_file {
-        // Set canoncialized path to config file, in case forks/children are in different
-        // working directories.
-        let full_path = std::fs::canonicalize(config_file)
-            .map_err(|e| CliError::ConfigFilePathError(config_file.to_owned(), e))?;
-        std::env::set_var("MIRRORD_CONFIG_FILE", full_path);
+    // Handed over to the internal proxy once it starts.
+    let config_file = if let Some(config_file) = &args.config_file {
+        let config_file = remote_config::config_file_path(&config_file.to_string_lossy()).await?;
+        std::env::set_var("MIRRORD_CONFIG_FILE", config_file.path());
+        Some(config_file)
+    } else {
+        None
+    };
+
+    if let Some(profile) = &args.profile {
+        std::env::set_var("MIRRORD_PROFILE", profile);
     }
 
-    let (config, mut context) = LayerConfig::from_env_with_warnings()?;
+    // The internal proxy appends the recorded exchanges to the file, it runs in another directory.
+    // The requests have the headers of the users of the target, so only we can read the file.
+    if let Some(record) = &args.record {
+        let path = std::env::current_dir()
+            .map(|dir| dir.join(record))
+            .and_then(|path| {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&path)?
+                    .set_permissions(std::fs::Permissions::from_mode(0o600))?;
+                Ok(path)
+            })
+            .map_err(|fail| CliError::RecordFileFailed(record.clone(), fail))?;
+        std::env::set_var(RECORD_FILE_ENV, path);
+    }
+
+    // The process runs in the session of the daemon, with its mirrord settings.
+    let session = if args.attach {
+        let session =
+            DaemonSession::load(args.session.as_deref().unwrap_or(daemon::DEFAULT_SESSION))?;
+        for (key, value) in &session.environment {
+            std::env::set_var(key, value);
+        }
+        Some(session)
+    } else {
+        None
+    };
+
+    conditional::resolve_env()?;
+    let (mut config, mut context) = LayerConfig::from_env_with_warnings()?;
+
+    // The user picks the target in the terminal, only when asked for with `--pick`.
+    if args.pick {
+        if !target_picker::can_pick() {
+            return Err(CliError::TargetPickerNoTerminal);
+        }
+
+        if let Some(picked) = target_picker::pick_target(&config, &progress).await? {
+            std::env::set_var("MIRRORD_IMPERSONATED_TARGET", &picked.path);
+            if let Some(namespace) = &picked.namespace {
+                std::env::set_var("MIRRORD_TARGET_NAMESPACE", namespace);
+            }
+            target_picker::offer_save(&picked, &progress)?;
+
+            conditional::resolve_env()?;
+            (config, context) = LayerConfig::from_env_with_warnings()?;
+        }
+    }
+
+    otel::init(&config);
 
     let mut analytics = AnalyticsReporter::only_error(config.telemetry, watch);
     (&config).collect_analytics(analytics.get_mut());
@@ -237,162 +354,66 @@ async fn exec(args: &ExecArgs, watch: drain::Watch) -> Result<()> {
         progress.warning(warning);
     }
 
-    let execution_result = exec_process(config, args, &progress, &mut analytics).await;
+    let incoming = &config.feature.network.incoming;
+    let has_http_filter =
+        incoming.http_filter.header_filter.is_some() || incoming.http_filter.path_filter.is_some();
+    if args.record.is_some() && !(incoming.is_steal() && has_http_filter) {
+        progress.warning(
+            "only requests stolen with an HTTP filter are recorded, and none is configured",
+        );
+    }
 
-    if execution_result.is_err() && !analytics.has_error() {
-        analytics.set_error(AnalyticsError::Unknown);
+    #[cfg(target_os = "linux")]
+    if args.intercept == Intercept::Layer
+        && let Ok(binary) = which(&args.binary)
+        && static_binary::is_statically_linked(&binary).unwrap_or_default()
+    {
+        progress.warning(&format!(
+            "{} is statically linked, so mirrord can't load into it and it will run locally. \
+             Run it with `--intercept=seccomp` to intercept its connections, listeners and \
+             remote files.",
+            binary.display()
+        ));
     }
 
-    execution_result
-}
+    if args.dry_run == Some(DryRun::Agent) {
+        return print_agent_manifest(&config, &progress).await;
+    }
 
-/// Returns a list of (pod name, [container names]) pairs, filtering out mesh side cars
-/// as well as any pods which are not ready or have crashed.
-async fn get_kube_pods(
-    namespace: Option<&str>,
-    client: &kube::Client,
-) -> Result<HashMap<String, Vec<String>>> {
-    let pods = get_kube_resources::<Pod>(namespace, client, Some("status.phase=Running"))
-        .await
-        .filter(|pod| {
-            pod.status
-                .as_ref()
-                .and_then(|status| status.conditions.as_ref())
-                .map(|conditions| {
-                    // filter out pods without the Ready condition
-                    conditions
-                        .iter()
-                        .any(|condition| condition.type_ == "Ready" && condition.status == "True")
-                })
-                .unwrap_or(false)
-        });
-
-    // convert pods to (name, container names) pairs
-    let pod_containers_map: HashMap<String, Vec<String>> = pods
-        .filter_map(|pod| {
-            let name = pod.metadata.name.clone()?;
-            let containers = pod
-                .spec
-                .as_ref()?
-                .containers
-                .iter()
-                .filter(|&container| (!SKIP_NAMES.contains(container.name.as_str())))
-                .map(|container| container.name.clone())
-                .collect();
-            Some((name, containers))
-        })
-        .collect();
-
-    Ok(pod_containers_map)
-}
+    let execution_result = exec_process(
+        config,
+        args,
+        session,
+        config_file,
+        &progress,
+        &mut analytics,
+    )
+    .await;
 
-async fn get_kube_deployments(
-    namespace: Option<&str>,
-    client: &kube::Client,
-) -> Result<impl Iterator<Item = String>> {
-    Ok(get_kube_resources::<Deployment>(namespace, client, None)
-        .await
-        .filter(|deployment| {
-            deployment
-                .status
-                .as_ref()
-                .map(|status| status.available_replicas >= Some(1))
-                .unwrap_or(false)
-        })
-        .filter_map(|deployment| deployment.metadata.name))
-}
+    if execution_result.is_err() && !analytics.has_error() {
+        analytics.set_error(AnalyticsError::Unknown);
+    }
 
-async fn get_kube_rollouts(
-    namespace: Option<&str>,
-    client: &kube::Client,
-) -> Result<impl Iterator<Item = String>> {
-    Ok(get_kube_resources::<Rollout>(namespace, client, None)
-        .await
-        .filter_map(|rollout| rollout.metadata().name.clone()))
+    execution_result
 }
 
-async fn get_kube_resources<K>(
-    namespace: Option<&str>,
-    client: &kube::Client,
-    field_selector: Option<&str>,
-) -> impl Iterator<Item = K>
+/// Prints the YAML of the agent that `mirrord exec` would create, for `--dry-run=agent`.
+async fn print_agent_manifest<P>(config: &LayerConfig, progress: &P) -> Result<()>
 where
-    K: kube::Resource<Scope = NamespaceResourceScope>,
-    <K as kube::Resource>::DynamicType: Default,
-    K: Clone + DeserializeOwned + std::fmt::Debug,
+    P: Progress + Send + Sync,
 {
-    // Set up filters on the K8s resources returned - in this case, excluding the agent resources
-    // and then applying any provided field-based filter conditions.
-    let params = &mut ListParams::default().labels("app!=mirrord");
-    if let Some(fields) = field_selector {
-        params.field_selector = Some(fields.to_string())
-    }
-    get_k8s_resource_api(client, namespace)
-        .list(params)
-        .await
-        .map(|resources| resources.into_iter())
-        .map_err(KubeApiError::from)
-        .map_err(CliError::KubernetesApiFailed)
-        .unwrap_or_else(|_| Vec::new().into_iter())
-}
-
-/// Lists all possible target paths for pods.
-/// Example: ```[
-///  "pod/metalbear-deployment-85c754c75f-982p5",
-///  "pod/nginx-deployment-66b6c48dd5-dc9wk",
-///  "pod/py-serv-deployment-5c57fbdc98-pdbn4/container/py-serv",
-/// ]```
-async fn print_pod_targets(args: &ListTargetArgs) -> Result<()> {
-    let (accept_invalid_certificates, kubeconfig, namespace, kube_context) = if let Some(config) =
-        &args.config_file
-    {
-        let mut cfg_context = ConfigContext::default();
-        let layer_config = LayerFileConfig::from_path(config)?.generate_config(&mut cfg_context)?;
-        if !layer_config.use_proxy {
-            remove_proxy_env();
-        }
-        (
-            layer_config.accept_invalid_certificates,
-            layer_config.kubeconfig,
-            layer_config.target.namespace,
-            layer_config.kube_context,
-        )
-    } else {
-        (false, None, None, None)
-    };
+    if config.operator == Some(true) {
+        progress.warning(
+            "The mirrord operator creates its own agents, this is the agent mirrord would create \
+             without the operator.",
+        );
+    }
 
-    let client = create_kube_api(accept_invalid_certificates, kubeconfig, kube_context)
-        .await
-        .map_err(CliError::KubernetesApiFailed)?;
-
-    let namespace = args.namespace.as_deref().or(namespace.as_deref());
-
-    let (pods, deployments, rollouts) = futures::try_join!(
-        get_kube_pods(namespace, &client),
-        get_kube_deployments(namespace, &client),
-        get_kube_rollouts(namespace, &client)
-    )?;
-
-    let mut target_vector = pods
-        .iter()
-        .flat_map(|(pod, containers)| {
-            if containers.len() == 1 {
-                vec![format!("pod/{pod}")]
-            } else {
-                containers
-                    .iter()
-                    .map(move |container| format!("pod/{pod}/container/{container}"))
-                    .collect::<Vec<String>>()
-            }
-        })
-        .chain(deployments.map(|deployment| format!("deployment/{deployment}")))
-        .chain(rollouts.map(|rollout| format!("rollout/{rollout}")))
-        .collect::<Vec<String>>();
+    let k8s_api = KubernetesAPI::create(config).await?;
+    let manifest = k8s_api.render_agent(&config.target, Some(config)).await?;
 
-    target_vector.sort();
+    serde_yaml::to_writer(std::io::stdout(), &manifest)?;
 
-    let json_obj = json!(target_vector);
-    println!("{json_obj}");
     Ok(())
 }
 
@@ -401,6 +422,10 @@ const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 fn main() -> miette::Result<()> {
     let cli = Cli::parse();
 
+    if let Some(progress) = &cli.progress {
+        std::env::set_var(mirrord_progress::MIRRORD_PROGRESS_ENV, progress);
+    }
+
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -413,9 +438,16 @@ fn main() -> miette::Result<()> {
             mirrord_console::init_async_logger(&console_addr, watch.clone(), 124).await?;
         } else if !init_ext_error_handler(&cli.commands) {
             registry()
-                .with(fmt::layer().with_writer(std::io::stderr))
-                .with(EnvFilter::from_default_env())
+                .with(otel::layer())
+                .with(
+                    fmt::layer()
+                        .with_writer(std::io::stderr)
+                        .with_filter(EnvFilter::from_default_env()),
+                )
                 .init();
+        } else if matches!(cli.commands, Commands::ExtensionExec(_)) {
+            // Errors are reported to the extension, the spans can still be exported.
+            registry().with(otel::layer()).init();
         }
 
         match cli.commands {
@@ -427,19 +459,23 @@ fn main() -> miette::Result<()> {
                     false,
                 )?;
             }
-            Commands::ListTargets(args) => print_pod_targets(&args).await?,
+            Commands::ListTargets(args) => list::print_pod_targets(&args).await?,
             Commands::Operator(args) => operator_command(*args).await?,
             Commands::ExtensionExec(args) => {
                 extension_exec(*args, watch).await?;
             }
             Commands::InternalProxy => internal_proxy::proxy(watch).await?,
             Commands::VerifyConfig(args) => verify_config(args).await?,
-            Commands::Completions(args) => {
-                let mut cmd: clap::Command = Cli::command();
-                generate(args.shell, &mut cmd, "mirrord", &mut std::io::stdout());
-            }
+            Commands::Completions(args) => completions::completions_command(args).await?,
             Commands::Teams => teams::navigate_to_intro().await,
             Commands::Diagnose(args) => diagnose_command(*args).await?,
+            Commands::Config(args) => config_command(*args)?,
+            Commands::Dump(args) => dump::dump_command(*args).await?,
+            Commands::PortForward(args) => port_forward::port_forward_command(*args).await?,
+            Commands::Daemon(args) => daemon::daemon_command(*args, watch).await?,
+            Commands::Status(args) => status::status_command(args).await?,
+            Commands::Replay(args) => replay::replay_command(args).await?,
+            Commands::Env(args) => env::env_command(*args).await?,
         };
         Ok(())
     });
@@ -453,6 +489,8 @@ fn main() -> miette::Result<()> {
             });
     });
 
+    otel::shutdown();
+
     res.map_err(Into::into)
 }
 
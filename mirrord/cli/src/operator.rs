@@ -8,20 +8,25 @@ use mirrord_config::{
 };
 use mirrord_kube::api::kubernetes::create_kube_api;
 use mirrord_operator::{
-    client::{OperatorApiError, OperatorOperation},
-    crd::{LicenseInfoOwned, MirrordOperatorCrd, MirrordOperatorSpec, OPERATOR_STATUS_NAME},
+    client::{license_usage, seat_limit_warning, OperatorApiError, OperatorOperation},
+    crd::{
+        LicenseInfoOwned, LicenseUsageSpec, MirrordOperatorCrd, MirrordOperatorSpec,
+        MirrordOperatorStatus, OPERATOR_STATUS_NAME,
+    },
     setup::{LicenseType, Operator, OperatorNamespace, OperatorSetup, SetupOptions},
 };
-use mirrord_progress::{Progress, ProgressTracker};
+use mirrord_progress::{NullProgress, Progress, ProgressTracker};
 use prettytable::{row, Table};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::fs;
 use tracing::warn;
 
 use self::session::SessionCommandHandler;
 use crate::{
+    conditional,
     config::{OperatorArgs, OperatorCommand},
     error::CliError,
+    remote_config::config_file_path,
     util::remove_proxy_env,
     Result,
 };
@@ -116,17 +121,22 @@ async fn operator_setup(
 async fn get_status_api(config: Option<String>) -> Result<Api<MirrordOperatorCrd>> {
     let kube_api = if let Some(config_path) = config {
         let mut cfg_context = ConfigContext::default();
-        let config = LayerFileConfig::from_path(config_path)?.generate_config(&mut cfg_context)?;
+        let config_file = config_file_path(&config_path).await?;
+        conditional::resolve(config_file.path())?;
+        let config =
+            LayerFileConfig::from_path(config_file.path())?.generate_config(&mut cfg_context)?;
         if !config.use_proxy {
             remove_proxy_env();
         }
         create_kube_api(
             config.accept_invalid_certificates,
             config.kubeconfig,
-            config.kube_context,
+            config.agent.kube_context.or(config.kube_context),
+            config.proxy,
+            config.kube_api_url,
         )
     } else {
-        create_kube_api(false, None, None)
+        create_kube_api(false, None, None, None, None)
     }
     .await
     .map_err(CliError::KubernetesApiFailed)?;
@@ -134,9 +144,23 @@ async fn get_status_api(config: Option<String>) -> Result<Api<MirrordOperatorCrd
     Ok(Api::all(kube_api))
 }
 
+/// Output of `mirrord operator status --json`.
+#[derive(Serialize)]
+struct OperatorStatusOutput {
+    spec: MirrordOperatorSpec,
+    status: Option<MirrordOperatorStatus>,
+    /// [`None`] if the operator does not support license usage queries.
+    license_usage: Option<LicenseUsageSpec>,
+}
+
 #[tracing::instrument(level = "trace", ret)]
-async fn operator_status(config: Option<String>) -> Result<()> {
-    let mut progress = ProgressTracker::from_env("Operator Status");
+async fn operator_status(config: Option<String>, json: bool) -> Result<()> {
+    // Don't mix progress messages with the json output.
+    let mut progress: ProgressTracker = if json {
+        NullProgress.into()
+    } else {
+        ProgressTracker::from_env("Operator Status")
+    };
 
     let status_api = get_status_api(config).await?;
 
@@ -145,10 +169,7 @@ async fn operator_status(config: Option<String>) -> Result<()> {
     let mirrord_status = match status_api
         .get(OPERATOR_STATUS_NAME)
         .await
-        .map_err(|error| OperatorApiError::KubeError {
-            error,
-            operation: OperatorOperation::GettingStatus,
-        })
+        .map_err(|error| OperatorApiError::from_kube_error(error, OperatorOperation::GettingStatus))
         .map_err(CliError::from)
     {
         Ok(status) => status,
@@ -159,10 +180,29 @@ async fn operator_status(config: Option<String>) -> Result<()> {
         }
     };
 
+    // Older operators don't know about license usage, we don't want to fail because of it.
+    let license_usage = license_usage(status_api.into_client(), &mirrord_status)
+        .await
+        .unwrap_or_else(|error| {
+            warn!(%error, "failed to fetch operator license usage");
+            None
+        });
+
     status_progress.success(Some("fetched status"));
 
     progress.success(None);
 
+    if json {
+        let output = OperatorStatusOutput {
+            spec: mirrord_status.spec,
+            status: mirrord_status.status,
+            license_usage,
+        };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+
+        return Ok(());
+    }
+
     let MirrordOperatorSpec {
         operator_version,
         default_namespace,
@@ -185,10 +225,35 @@ Operator default namespace: {default_namespace}
 Operator License
     name: {name}
     organization: {organization}
-    expire at: {expire_at}
-"#
+    expire at: {expire_at}"#
     );
 
+    if let Some(usage) = &license_usage {
+        let seats_total = usage
+            .seats_total
+            .map(|total| total.to_string())
+            .unwrap_or_else(|| "unlimited".to_string());
+        println!("    seats used: {}/{seats_total}", usage.seats_used);
+
+        if let Some(seats_message) = seat_limit_warning(usage) {
+            println!("    {seats_message}");
+        }
+    }
+    println!();
+
+    if let Some(usage) = license_usage.filter(|usage| !usage.user_sessions.is_empty()) {
+        println!("Sessions per User:");
+        let mut user_sessions = Table::new();
+
+        user_sessions.add_row(row!["User", "Sessions"]);
+        for user_session in usage.user_sessions {
+            user_sessions.add_row(row![user_session.user, user_session.sessions]);
+        }
+
+        user_sessions.printstd();
+        println!();
+    }
+
     let Some(status) = mirrord_status.status else {
         return Ok(());
     };
@@ -287,7 +352,7 @@ pub(crate) async fn operator_command(args: OperatorArgs) -> Result<()> {
             license_key,
             license_path,
         } => operator_setup(accept_tos, file, namespace, license_key, license_path).await,
-        OperatorCommand::Status { config_file } => operator_status(config_file).await,
+        OperatorCommand::Status { config_file, json } => operator_status(config_file, json).await,
         OperatorCommand::Session(session_command) => {
             SessionCommandHandler::new(session_command)
                 .and_then(SessionCommandHandler::handle)
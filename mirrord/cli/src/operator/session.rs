@@ -67,9 +67,8 @@ impl SessionCommandHandler {
         let operator_version = operator_api
             .get(OPERATOR_STATUS_NAME)
             .await
-            .map_err(|error| OperatorApiError::KubeError {
-                error,
-                operation: OperatorOperation::GettingStatus,
+            .map_err(|error| {
+                OperatorApiError::from_kube_error(error, OperatorOperation::GettingStatus)
             })
             .map(|crd| crd.spec.operator_version)?;
 
@@ -101,10 +100,7 @@ impl SessionCommandHandler {
                 }
             }
             // Something actually went wrong.
-            other => OperatorApiError::KubeError {
-                error: other,
-                operation: OperatorOperation::SessionManagement,
-            },
+            other => OperatorApiError::from_kube_error(other, OperatorOperation::SessionManagement),
         })
         // Finish the progress report here if we have an error response. 
         .inspect_err(|fail| {
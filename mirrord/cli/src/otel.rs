@@ -0,0 +1,99 @@
+//! Export of the spans and counters of the session to an OpenTelemetry collector, with
+//! [`otlp_endpoint`](mirrord_config::LayerConfig::otlp_endpoint).
+//!
+//! The tracing subscriber is installed before the config is loaded, so it gets the [`layer`] that
+//! exports nothing, until [`init`] swaps the exporter in. Only the spans of mirrord crates, at
+//! `INFO` or above, are exported (session setup, agent creation, stolen requests), regardless of
+//! `RUST_LOG`. The counters are recorded by
+//! [`AnalyticsReporter`](mirrord_analytics::AnalyticsReporter) on the global meter provider.
+
+use std::sync::OnceLock;
+
+use mirrord_config::LayerConfig;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    metrics::SdkMeterProvider, runtime::TokioCurrentThread, trace::Tracer, Resource,
+};
+use tracing::{warn, Level};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{filter::filter_fn, reload, Layer, Registry};
+
+type OtelLayer = Option<OpenTelemetryLayer<Registry, Tracer>>;
+
+/// Swaps the exporter into the [`layer`].
+static RELOAD_HANDLE: OnceLock<reload::Handle<OtelLayer, Registry>> = OnceLock::new();
+
+/// Kept to flush the counters on [`shutdown`].
+static METER_PROVIDER: OnceLock<SdkMeterProvider> = OnceLock::new();
+
+/// Tracing layer that exports the spans of the session once [`init`] is called.
+pub(crate) fn layer() -> impl Layer<Registry> {
+    let (layer, handle) = reload::Layer::new(None);
+    let _ = RELOAD_HANDLE.set(handle);
+
+    layer.with_filter(filter_fn(|metadata| {
+        metadata.is_span()
+            && *metadata.level() <= Level::INFO
+            && metadata.target().starts_with("mirrord")
+    }))
+}
+
+/// Starts exporting to the `otlp_endpoint` of the `config`, if it's set.
+///
+/// Failures are only logged, the session goes on without the export.
+pub(crate) fn init(config: &LayerConfig) {
+    let Some(endpoint) = config.otlp_endpoint.as_deref() else {
+        return;
+    };
+
+    let resource = Resource::new([
+        KeyValue::new("service.name", "mirrord"),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ]);
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource.clone()))
+        .install_batch(TokioCurrentThread);
+    match tracer {
+        Ok(tracer) => {
+            let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+            if let Some(Err(error)) = RELOAD_HANDLE.get().map(|handle| handle.reload(Some(layer))) {
+                warn!(%error, "failed to install the OTLP span exporter");
+            }
+        }
+        Err(error) => warn!(%error, endpoint, "failed to create the OTLP span exporter"),
+    }
+
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(TokioCurrentThread)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_resource(resource)
+        .build();
+    match meter_provider {
+        Ok(meter_provider) => {
+            global::set_meter_provider(meter_provider.clone());
+            let _ = METER_PROVIDER.set(meter_provider);
+        }
+        Err(error) => warn!(%error, endpoint, "failed to create the OTLP metrics exporter"),
+    }
+}
+
+/// Flushes what wasn't exported yet, before the process exits.
+pub(crate) fn shutdown() {
+    global::shutdown_tracer_provider();
+
+    if let Some(Err(error)) = METER_PROVIDER.get().map(SdkMeterProvider::shutdown) {
+        warn!(%error, "failed to flush the OTLP metrics exporter");
+    }
+}
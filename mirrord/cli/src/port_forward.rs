@@ -0,0 +1,529 @@
+//! `mirrord port-forward`: forwards local ports to endpoints in the cluster (services, pods, any
+//! address the target can reach), through the outgoing connections of the agent.
+//!
+//! Unlike `kubectl port-forward`, the remote host can be any host name or IP that resolves in the
+//! cluster, and the agent is created (or reached through the operator) like in `mirrord exec`.
+//!
+//! Every local connection is made remote with a
+//! [`LayerConnect`](mirrord_protocol::outgoing::LayerConnect). Host names are resolved by the
+//! agent first. The agent responds to these requests in order, so the local connections wait for
+//! their responses in queues.
+//!
+//! The data of every connection is written to its local socket by its own task, so a slow local
+//! client doesn't hold up the other connections. A client that falls more than [`CHANNEL_SIZE`]
+//! chunks behind is disconnected.
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use mirrord_analytics::NullReporter;
+use mirrord_config::LayerConfig;
+use mirrord_progress::{Progress, ProgressTracker};
+use mirrord_protocol::{
+    dns::{GetAddrInfoRequest, GetAddrInfoResponse},
+    outgoing::{
+        tcp::{DaemonTcpOutgoing, LayerTcpOutgoing},
+        DaemonConnect, DaemonRead, LayerClose, LayerConnect, LayerWrite, SocketAddress,
+    },
+    ClientMessage, ConnectionId, DaemonMessage,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, error::TrySendError},
+    time,
+};
+
+use crate::{
+    conditional,
+    connection::{create_and_connect, AgentConnection},
+    remote_config::config_file_path,
+    util::remove_proxy_env,
+    CliError, PortForwardArgs, Result,
+};
+
+/// How often we ping the agent, to keep the connection alive while there is no traffic.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Max size of a single read from a local connection.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Size of the channels between the local connections and the agent connection.
+const CHANNEL_SIZE: usize = 512;
+
+/// A forwarded port, `[local_port:]remote_host:remote_port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PortMapping {
+    local_port: u16,
+    remote_host: String,
+    remote_port: u16,
+}
+
+impl FromStr for PortMapping {
+    type Err = String;
+
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("`{mapping}` is not `[local_port:]remote_host:remote_port`");
+
+        let (rest, remote_port) = mapping.rsplit_once(':').ok_or_else(invalid)?;
+        let remote_port = remote_port.parse::<u16>().map_err(|_| invalid())?;
+
+        // IPv6 addresses are in brackets, e.g. `[fd00::1]:5432`.
+        let (local_port, remote_host) = match rest.split_once(':') {
+            Some((local_port, remote_host)) if !rest.starts_with('[') => (
+                local_port.parse::<u16>().map_err(|_| invalid())?,
+                remote_host,
+            ),
+            _ => (remote_port, rest),
+        };
+        let remote_host = remote_host.trim_start_matches('[').trim_end_matches(']');
+
+        if remote_host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            local_port,
+            remote_host: remote_host.to_string(),
+            remote_port,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "127.0.0.1:{} -> {}:{}",
+            self.local_port, self.remote_host, self.remote_port
+        )
+    }
+}
+
+/// A local connection waiting for the agent.
+struct PendingConnection {
+    stream: TcpStream,
+    /// Index of the [`PortMapping`] it was accepted for.
+    mapping: usize,
+}
+
+/// Passes the data between the local connections and the agent.
+struct PortForwarder {
+    connection: AgentConnection,
+    mappings: Vec<PortMapping>,
+    /// Waiting for the agent to resolve their remote host, in the order of the requests.
+    resolving: VecDeque<PendingConnection>,
+    /// Waiting for the agent to connect, in the order of the requests.
+    connecting: VecDeque<PendingConnection>,
+    /// Data from the agent for the local connections.
+    writers: HashMap<ConnectionId, mpsc::Sender<Vec<u8>>>,
+    /// Data from the local connections for the agent, an empty chunk when the connection is
+    /// closed.
+    local_tx: mpsc::Sender<(ConnectionId, Vec<u8>)>,
+}
+
+impl PortForwarder {
+    async fn send(&self, message: ClientMessage) -> Result<()> {
+        self.connection
+            .sender
+            .send(message)
+            .await
+            .map_err(|_| CliError::AgentClosedConnection("the connection was closed".into()))
+    }
+
+    /// Starts making the remote connection for a new local connection.
+    async fn accepted(&mut self, pending: PendingConnection) -> Result<()> {
+        let Some(mapping) = self.mappings.get(pending.mapping) else {
+            return Ok(());
+        };
+
+        match mapping.remote_host.parse::<IpAddr>() {
+            Ok(ip) => {
+                let remote_address = SocketAddr::new(ip, mapping.remote_port);
+                self.connect(pending, remote_address).await
+            }
+            Err(..) => {
+                let request = GetAddrInfoRequest {
+                    node: mapping.remote_host.clone(),
+                };
+                self.resolving.push_back(pending);
+                self.send(ClientMessage::GetAddrInfoRequest(request)).await
+            }
+        }
+    }
+
+    async fn connect(
+        &mut self,
+        pending: PendingConnection,
+        remote_address: SocketAddr,
+    ) -> Result<()> {
+        self.connecting.push_back(pending);
+        self.send(ClientMessage::TcpOutgoing(LayerTcpOutgoing::Connect(
+            LayerConnect {
+                remote_address: SocketAddress::Ip(remote_address),
+            },
+        )))
+        .await
+    }
+
+    async fn resolved(&mut self, response: GetAddrInfoResponse) -> Result<()> {
+        let Some(pending) = self.resolving.pop_front() else {
+            return Ok(());
+        };
+        let Some(mapping) = self.mappings.get(pending.mapping) else {
+            return Ok(());
+        };
+
+        let ip = match response.0 {
+            // The IPv4 addresses first, like most clients.
+            Ok(lookup) => lookup
+                .0
+                .iter()
+                .map(|record| record.ip)
+                .min_by_key(|ip| ip.is_ipv6()),
+            Err(error) => {
+                tracing::warn!(%error, host = %mapping.remote_host, "failed to resolve the host");
+                return Ok(());
+            }
+        };
+        let Some(ip) = ip else {
+            tracing::warn!(host = %mapping.remote_host, "no address for the host");
+            return Ok(());
+        };
+
+        let remote_address = SocketAddr::new(ip, mapping.remote_port);
+        self.connect(pending, remote_address).await
+    }
+
+    /// Starts passing the data of the local connection, once the agent connected.
+    fn connected(&mut self, connect: DaemonConnect) {
+        let Some(PendingConnection { stream, .. }) = self.connecting.pop_front() else {
+            return;
+        };
+        let connection_id = connect.connection_id;
+        let (mut reader, mut writer) = stream.into_split();
+
+        let local_tx = self.local_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut buffer = Vec::with_capacity(READ_BUFFER_SIZE);
+                match reader.read_buf(&mut buffer).await {
+                    Ok(0) | Err(..) => {
+                        let _ = local_tx.send((connection_id, Vec::new())).await;
+                        break;
+                    }
+                    Ok(..) => {
+                        if local_tx.send((connection_id, buffer)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(CHANNEL_SIZE);
+        tokio::spawn(async move {
+            // An empty chunk is a shutdown of the remote side.
+            while let Some(bytes) = rx.recv().await.filter(|bytes| !bytes.is_empty()) {
+                if writer.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        self.writers.insert(connection_id, tx);
+    }
+
+    async fn handle_agent_message(&mut self, message: DaemonMessage) -> Result<()> {
+        match message {
+            DaemonMessage::GetAddrInfoResponse(response) => self.resolved(response).await?,
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(Ok(connect))) => {
+                self.connected(connect)
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(Err(error))) => {
+                self.connecting.pop_front();
+                tracing::warn!(%error, "the agent failed to connect");
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Read(Ok(DaemonRead {
+                connection_id,
+                bytes,
+            }))) => {
+                let Some(writer) = self.writers.get(&connection_id) else {
+                    return Ok(());
+                };
+
+                // Waiting for room here would stop the data of all the other connections.
+                match writer.try_send(bytes) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(..)) => {
+                        tracing::warn!(
+                            connection_id,
+                            "a local client can't keep up with the remote data, disconnecting it"
+                        );
+                        self.writers.remove(&connection_id);
+                        self.send(ClientMessage::TcpOutgoing(LayerTcpOutgoing::Close(
+                            LayerClose { connection_id },
+                        )))
+                        .await?;
+                    }
+                    Err(TrySendError::Closed(..)) => {
+                        self.writers.remove(&connection_id);
+                    }
+                }
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Read(Err(error))) => {
+                tracing::warn!(%error, "the agent failed to read from a remote connection");
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Close(connection_id)) => {
+                self.writers.remove(&connection_id);
+            }
+            DaemonMessage::Close(reason) => return Err(CliError::AgentClosedConnection(reason)),
+            DaemonMessage::LogMessage(log) => tracing::warn!("agent: {}", log.message),
+            other => tracing::trace!(?other, "ignoring agent message"),
+        }
+
+        Ok(())
+    }
+
+    /// Passes the data of a local connection to the agent. Closes the remote connection when the
+    /// local one is closed.
+    ///
+    /// The data of connections that were closed already is dropped.
+    async fn handle_local_data(
+        &mut self,
+        connection_id: ConnectionId,
+        bytes: Vec<u8>,
+    ) -> Result<()> {
+        if !self.writers.contains_key(&connection_id) {
+            return Ok(());
+        }
+
+        let closed = bytes.is_empty();
+        self.send(ClientMessage::TcpOutgoing(LayerTcpOutgoing::Write(
+            LayerWrite {
+                connection_id,
+                bytes,
+            },
+        )))
+        .await?;
+
+        if closed {
+            self.writers.remove(&connection_id);
+            self.send(ClientMessage::TcpOutgoing(LayerTcpOutgoing::Close(
+                LayerClose { connection_id },
+            )))
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `mirrord port-forward`, until the user stops it.
+pub(crate) async fn port_forward_command(args: PortForwardArgs) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord port-forward");
+
+    if let Some(target) = &args.target {
+        std::env::set_var("MIRRORD_IMPERSONATED_TARGET", target);
+    }
+
+    if let Some(namespace) = &args.target_namespace {
+        std::env::set_var("MIRRORD_TARGET_NAMESPACE", namespace);
+    }
+
+    // Removed when we're done, if it was fetched.
+    let _config_file = if let Some(config_file) = &args.config_file {
+        let config_file = config_file_path(config_file).await?;
+        std::env::set_var("MIRRORD_CONFIG_FILE", config_file.path());
+        Some(config_file)
+    } else {
+        None
+    };
+
+    conditional::resolve_env()?;
+    let (config, mut context) = LayerConfig::from_env_with_warnings()?;
+    config.verify(&mut context)?;
+    for warning in context.get_warnings() {
+        progress.warning(warning);
+    }
+
+    if !config.use_proxy {
+        remove_proxy_env();
+    }
+
+    // Bound before the agent is created, so that a used port fails fast.
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(CHANNEL_SIZE);
+    for (index, mapping) in args.mappings.iter().enumerate() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, mapping.local_port))
+            .await
+            .map_err(|error| CliError::PortForwardBindFailed(mapping.local_port, error))?;
+
+        let accepted_tx = accepted_tx.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let pending = PendingConnection {
+                    stream,
+                    mapping: index,
+                };
+                if accepted_tx.send(pending).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut analytics = NullReporter::default();
+    let (_, connection) = create_and_connect(&config, &mut progress, &mut analytics).await?;
+
+    for mapping in &args.mappings {
+        progress.info(&format!("forwarding {mapping}"));
+    }
+    progress.info("press Ctrl+C to stop");
+
+    let (local_tx, mut local_rx) = mpsc::channel(CHANNEL_SIZE);
+    let mut forwarder = PortForwarder {
+        connection,
+        mappings: args.mappings,
+        resolving: Default::default(),
+        connecting: Default::default(),
+        writers: Default::default(),
+        local_tx,
+    };
+    let mut pings = time::interval(PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            message = forwarder.connection.receiver.recv() => match message {
+                Some(message) => forwarder.handle_agent_message(message).await?,
+                None => {
+                    return Err(CliError::AgentClosedConnection("the connection was closed".into()));
+                }
+            },
+
+            Some(pending) = accepted_rx.recv() => forwarder.accepted(pending).await?,
+
+            Some((connection_id, bytes)) = local_rx.recv() => {
+                forwarder.handle_local_data(connection_id, bytes).await?;
+            },
+
+            _ = pings.tick() => forwarder.send(ClientMessage::Ping).await?,
+
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    progress.success(Some("port forwarding stopped"));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connects a local client through the `forwarder`, as the remote connection `connection_id`.
+    async fn local_connection(
+        forwarder: &mut PortForwarder,
+        connection_id: ConnectionId,
+    ) -> TcpStream {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let address = SocketAddress::Ip("127.0.0.1:5432".parse().unwrap());
+        forwarder
+            .connecting
+            .push_back(PendingConnection { stream, mapping: 0 });
+        forwarder.connected(DaemonConnect {
+            connection_id,
+            remote_address: address.clone(),
+            local_address: address,
+        });
+
+        client
+    }
+
+    fn read(connection_id: ConnectionId, bytes: &[u8]) -> DaemonMessage {
+        DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Read(Ok(DaemonRead {
+            connection_id,
+            bytes: bytes.to_vec(),
+        })))
+    }
+
+    /// A local client that doesn't read its data is disconnected, instead of holding up the data
+    /// of the other connections.
+    #[tokio::test]
+    async fn slow_client_does_not_block_others() {
+        let (sender, mut agent_rx) = mpsc::channel(CHANNEL_SIZE);
+        let (_agent_tx, receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (local_tx, _local_rx) = mpsc::channel(CHANNEL_SIZE);
+        let mut forwarder = PortForwarder {
+            connection: AgentConnection { sender, receiver },
+            mappings: vec!["5432:postgres:5432".parse().unwrap()],
+            resolving: Default::default(),
+            connecting: Default::default(),
+            writers: Default::default(),
+            local_tx,
+        };
+
+        let _slow = local_connection(&mut forwarder, 1).await;
+        let mut fast = local_connection(&mut forwarder, 2).await;
+
+        // Nothing is written to the slow client while we don't yield, so its channel fills up.
+        for _ in 0..=CHANNEL_SIZE {
+            forwarder.handle_agent_message(read(1, b"x")).await.unwrap();
+        }
+        assert!(!forwarder.writers.contains_key(&1));
+        assert!(matches!(
+            agent_rx.try_recv(),
+            Ok(ClientMessage::TcpOutgoing(LayerTcpOutgoing::Close(
+                LayerClose { connection_id: 1 }
+            )))
+        ));
+
+        forwarder
+            .handle_agent_message(read(2, b"hello"))
+            .await
+            .unwrap();
+        let mut buffer = [0; 5];
+        time::timeout(Duration::from_secs(5), fast.read_exact(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[test]
+    fn parses_port_mappings() {
+        let mapping = |local_port, remote_host: &str, remote_port| PortMapping {
+            local_port,
+            remote_host: remote_host.to_string(),
+            remote_port,
+        };
+
+        assert_eq!(
+            "5432:postgres.db.svc:5432".parse(),
+            Ok(mapping(5432, "postgres.db.svc", 5432))
+        );
+        assert_eq!(
+            "redis.cache:6379".parse(),
+            Ok(mapping(6379, "redis.cache", 6379))
+        );
+        assert_eq!(
+            "8080:[fd00::1]:80".parse(),
+            Ok(mapping(8080, "fd00::1", 80))
+        );
+        assert_eq!("[fd00::1]:80".parse(), Ok(mapping(80, "fd00::1", 80)));
+
+        assert!("postgres.db.svc".parse::<PortMapping>().is_err());
+        assert!("x:postgres:5432".parse::<PortMapping>().is_err());
+        assert!(":5432".parse::<PortMapping>().is_err());
+    }
+}
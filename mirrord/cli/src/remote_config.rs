@@ -0,0 +1,255 @@
+//! Config files fetched at startup, so that platform teams can distribute one approved config
+//! instead of copying it into every repository.
+//!
+//! The config file argument (`-f`) can be:
+//!
+//! 1. `k8s://<namespace>/<configmap>/<key>`, a key of a `ConfigMap` in the cluster;
+//! 2. `https://...`, a URL;
+//! 3. a local path.
+//!
+//! Remote configs can be pinned with a `#sha256=<checksum>` suffix, and are then rejected when
+//! their content changes. The format of a remote config comes from the extension of its key or
+//! URL path (`json` when there is none).
+//!
+//! Local config files are templates, but remote configs are loaded as they are, so that they can't
+//! read the local environment (e.g. with `get_env`).
+//!
+//! A fetched config is written to a temporary file, removed by its [`ConfigFile`]. Like the cluster
+//! files, it's held by the CLI until the internal proxy starts, and then by the internal proxy
+//! (which finds it in [`REMOTE_CONFIG_FILE_ENV`]), since the layer loads the config in every
+//! process of the session.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::Api;
+use mirrord_kube::api::kubernetes::create_kube_api;
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+
+use crate::error::{CliError, Result};
+
+/// Separates the checksum of a remote config from its location.
+const CHECKSUM_SEPARATOR: &str = "#sha256=";
+
+/// Path of the fetched config file, for the internal proxy to remove it.
+pub(crate) const REMOTE_CONFIG_FILE_ENV: &str = "MIRRORD_REMOTE_CONFIG_FILE";
+
+/// The local config file of [`config_file_path`].
+///
+/// When the config was fetched, the file is removed when this is dropped.
+pub(crate) struct ConfigFile {
+    path: PathBuf,
+    fetched: Option<TempPath>,
+}
+
+impl ConfigFile {
+    /// The fetched config file of the session, in [`REMOTE_CONFIG_FILE_ENV`].
+    pub(crate) fn from_env() -> Option<Self> {
+        std::env::var_os(REMOTE_CONFIG_FILE_ENV).map(|path| Self {
+            path: path.clone().into(),
+            fetched: Some(TempPath::from_path(path)),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps the file, the internal proxy started with its own [`ConfigFile`].
+    pub(crate) fn hand_over(self) {
+        if self.fetched.is_some() {
+            std::env::remove_var(REMOTE_CONFIG_FILE_ENV);
+        }
+        std::mem::forget(self);
+    }
+}
+
+/// Resolves the config file argument to a local file, fetching the config first if it's remote.
+pub(crate) async fn config_file_path(source: &str) -> Result<ConfigFile> {
+    let location = source
+        .split_once(CHECKSUM_SEPARATOR)
+        .map_or(source, |(location, _)| location);
+
+    let (config, name) = if let Some(config_map) = location.strip_prefix("k8s://") {
+        let config = fetch_config_map(source, config_map).await?;
+        (config, config_map.rsplit('/').next())
+    } else if location.starts_with("https://") {
+        let config = fetch_url(source, location).await?;
+        let path = location.split(['?', '#']).next().unwrap_or(location);
+        (config, path.rsplit('/').next())
+    } else {
+        // Canonicalized, in case forks/children are in different working directories.
+        let path = std::fs::canonicalize(source)
+            .map_err(|fail| CliError::ConfigFilePathError(source.into(), fail))?;
+        return Ok(ConfigFile {
+            path,
+            fetched: None,
+        });
+    };
+
+    let checksum = Sha256::digest(config.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    if let Some((_, expected)) = source.split_once(CHECKSUM_SEPARATOR) {
+        if !expected.eq_ignore_ascii_case(&checksum) {
+            return Err(CliError::RemoteConfigChecksumMismatch {
+                location: source.to_string(),
+                expected: expected.to_string(),
+                actual: checksum,
+            });
+        }
+    }
+
+    let extension = name
+        .map(Path::new)
+        .and_then(Path::extension)
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| matches!(*extension, "json" | "toml" | "yaml" | "yml"))
+        .unwrap_or("json");
+
+    let fetched = write_config(&checksum, extension, &escape_template(source, &config)?)?;
+    std::env::set_var(REMOTE_CONFIG_FILE_ENV, &fetched);
+
+    Ok(ConfigFile {
+        path: fetched.to_path_buf(),
+        fetched: Some(fetched),
+    })
+}
+
+/// Writes the fetched `config` to a new file in the temporary directory, that only we can read.
+///
+/// The file has a new random name, so it's not one someone else created (or linked) in advance.
+fn write_config(checksum: &str, extension: &str, config: &str) -> Result<TempPath> {
+    let mut file = tempfile::Builder::new()
+        .prefix(&format!("mirrord-config-{checksum}-"))
+        .suffix(&format!(".{extension}"))
+        .tempfile()
+        .map_err(|fail| CliError::ConfigFilePathError(std::env::temp_dir(), fail))?;
+    file.write_all(config.as_bytes())
+        .map_err(|fail| CliError::ConfigFilePathError(file.path().to_path_buf(), fail))?;
+
+    Ok(file.into_temp_path())
+}
+
+/// Wraps the remote `config` in a `raw` block, so that it comes out of the template rendering of
+/// config files as it is.
+fn escape_template(source: &str, config: &str) -> Result<String> {
+    // Would end the block early.
+    if config.contains("endraw") {
+        return Err(CliError::RemoteConfigFetchFailed(
+            source.to_string(),
+            "remote configs can't contain `endraw`".to_string(),
+        ));
+    }
+
+    Ok(format!("{{% raw %}}{config}{{% endraw %}}"))
+}
+
+/// Fetches the config from the `ConfigMap` key at `<namespace>/<configmap>/<key>`.
+///
+/// The config isn't loaded yet, so the cluster is reached with the kubeconfig settings from the
+/// env and CLI flags only.
+async fn fetch_config_map(source: &str, config_map: &str) -> Result<String> {
+    let mut parts = config_map.splitn(3, '/').filter(|part| !part.is_empty());
+    let (Some(namespace), Some(name), Some(key)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(CliError::RemoteConfigSourceInvalid(source.to_string()));
+    };
+
+    let client = create_kube_api(
+        std::env::var("MIRRORD_ACCEPT_INVALID_CERTIFICATES")
+            .is_ok_and(|accept| accept.parse().unwrap_or_default()),
+        std::env::var("MIRRORD_KUBECONFIG").ok(),
+        std::env::var("MIRRORD_KUBE_CONTEXT").ok(),
+        std::env::var("MIRRORD_PROXY_URL").ok(),
+        std::env::var("MIRRORD_KUBE_API_URL").ok(),
+    )
+    .await?;
+
+    let config_map = Api::<ConfigMap>::namespaced(client, namespace)
+        .get(name)
+        .await
+        .map_err(|fail| CliError::RemoteConfigFetchFailed(source.to_string(), fail.to_string()))?;
+
+    config_map
+        .data
+        .and_then(|mut data| data.remove(key))
+        .ok_or_else(|| {
+            CliError::RemoteConfigFetchFailed(
+                source.to_string(),
+                format!("the ConfigMap has no `{key}` key"),
+            )
+        })
+}
+
+async fn fetch_url(source: &str, url: &str) -> Result<String> {
+    let fetch = async { reqwest::get(url).await?.error_for_status()?.text().await };
+
+    fetch
+        .await
+        .map_err(|fail| CliError::RemoteConfigFetchFailed(source.to_string(), fail.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use mirrord_config::LayerFileConfig;
+
+    use super::*;
+
+    /// Templates in remote configs are not rendered.
+    #[test]
+    fn remote_config_is_not_a_template() {
+        let config = r#"{ "target": { "namespace": "{{ get_env(name='HOME') }}" } }"#;
+        let path =
+            std::env::temp_dir().join(format!("mirrord-remote-config-{}.json", std::process::id()));
+        std::fs::write(&path, escape_template("https://configs", config).unwrap()).unwrap();
+
+        let loaded = LayerFileConfig::value_from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            loaded.unwrap()["target"]["namespace"],
+            "{{ get_env(name='HOME') }}"
+        );
+        assert!(
+            escape_template("https://configs", "{% endraw %}{{ get_env(name='HOME') }}").is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn local_config_path() {
+        let config_file = config_file_path(".").await.unwrap();
+        assert_eq!(
+            config_file.path(),
+            std::env::current_dir().unwrap().canonicalize().unwrap()
+        );
+
+        assert!(matches!(
+            config_file_path("k8s://default/mirrord-config").await,
+            Err(CliError::RemoteConfigSourceInvalid(..))
+        ));
+    }
+
+    #[test]
+    fn private_remote_config_file() {
+        let first = write_config("abc", "json", "{}").unwrap();
+        let second = write_config("abc", "json", "{}").unwrap();
+        let mode = std::fs::metadata(&first).unwrap().permissions().mode();
+        let contents = std::fs::read_to_string(&first).unwrap();
+
+        assert_ne!(*first, *second);
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, "{}");
+        assert_eq!(first.extension().unwrap(), "json");
+
+        let path = first.to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
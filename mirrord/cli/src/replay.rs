@@ -0,0 +1,128 @@
+//! `mirrord replay`, sends the requests recorded with `mirrord exec --record` to a local process,
+//! without a cluster.
+//!
+//! The requests are sent one after another, in the order they were recorded, and only the status
+//! of every response is compared with the recorded one.
+use std::path::Path;
+
+use mirrord_intproxy::recording::RecordedExchange;
+use mirrord_progress::{Progress, ProgressTracker};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+    Method,
+};
+
+use crate::{CliError, ReplayArgs, Result};
+
+/// Reads the exchanges of the record file at `path`.
+fn read_record(path: &Path) -> Result<Vec<RecordedExchange>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|fail| CliError::RecordFileFailed(path.to_path_buf(), fail))?;
+
+    parse_record(&text)
+        .map_err(|(line, fail)| CliError::RecordFileInvalid(path.to_path_buf(), line, fail))
+}
+
+/// Parses the lines of a record file, the error has the number of the invalid line.
+fn parse_record(text: &str) -> Result<Vec<RecordedExchange>, (usize, String)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str::<RecordedExchange>(line)
+                .map_err(|fail| (index + 1, fail.to_string()))
+        })
+        .collect()
+}
+
+/// Sends the recorded request to the local process, returns the status of the response.
+async fn replay(client: &reqwest::Client, port: u16, exchange: &RecordedExchange) -> Result<u16> {
+    let request = &exchange.request;
+    let description = format!("{} {}", request.method, request.path);
+
+    let method = Method::from_bytes(request.method.as_bytes())
+        .map_err(|fail| CliError::ReplayFailed(description.clone(), fail.to_string()))?;
+    let body = request
+        .body
+        .bytes()
+        .map_err(|fail| CliError::ReplayFailed(description.clone(), fail.to_string()))?;
+
+    // The length and the framing are set by the client, the host is the local process.
+    let headers = request
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = HeaderValue::from_str(value).ok()?;
+            Some((name, value))
+        })
+        .filter(|(name, _)| ![CONTENT_LENGTH, TRANSFER_ENCODING, HOST].contains(name))
+        .collect::<HeaderMap>();
+
+    let response = client
+        .request(method, format!("http://127.0.0.1:{port}{}", request.path))
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+        .map_err(|fail| CliError::ReplayFailed(description, fail.to_string()))?;
+
+    Ok(response.status().as_u16())
+}
+
+/// Runs `mirrord replay`.
+pub(crate) async fn replay_command(args: ReplayArgs) -> Result<()> {
+    let mut progress = ProgressTracker::from_env("mirrord replay");
+
+    let exchanges = read_record(&args.file)?;
+    let client = reqwest::Client::new();
+
+    let mut mismatches = 0;
+    for exchange in &exchanges {
+        let port = args.port.unwrap_or(exchange.port);
+        let status = replay(&client, port, exchange).await?;
+        let recorded = exchange.response.status;
+
+        let message = format!(
+            "{} {} -> {status} (recorded {recorded})",
+            exchange.request.method, exchange.request.path
+        );
+        if status == recorded {
+            progress.info(&message);
+        } else {
+            mismatches += 1;
+            progress.warning(&message);
+        }
+    }
+
+    if mismatches > 0 {
+        progress.failure(Some("responses differ from the recording"));
+        return Err(CliError::ReplayMismatch(mismatches));
+    }
+
+    progress.success(Some(&format!("replayed {} requests", exchanges.len())));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_invalid_line() {
+        let valid = r#"{"port":80,"request":{"method":"GET","path":"/health","version":"HTTP/1.1","headers":[]},"response":{"status":200,"version":"HTTP/1.1","headers":[],"body":"ok"}}"#;
+
+        let exchanges = parse_record(&format!("{valid}\n\n{valid}\n")).unwrap();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(
+            exchanges
+                .first()
+                .and_then(|exchange| exchange.response.body.body.as_deref()),
+            Some("ok")
+        );
+
+        let (line, _) = parse_record(&format!("{valid}\nnot json\n")).unwrap_err();
+        assert_eq!(line, 2);
+    }
+}
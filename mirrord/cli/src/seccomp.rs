@@ -0,0 +1,1099 @@
+//! `mirrord exec --intercept=seccomp`, for binaries that the layer can't be loaded into.
+//!
+//! Statically linked executables (e.g. Go binaries built with `CGO_ENABLED=0`) don't go through
+//! the dynamic loader, so `LD_PRELOAD` does nothing for them. Instead, the binary runs under a
+//! seccomp filter that makes the kernel notify the CLI of its `connect`, `listen` and `open(at)`
+//! calls ([`SECCOMP_RET_USER_NOTIF`]), and the CLI handles them with the internal proxy, like the
+//! layer would:
+//!
+//! - `connect` of a TCP/UDP socket to a non-loopback address is made through the outgoing traffic
+//!   feature: the CLI connects the binary's socket (taken with `pidfd_getfd`) to the address the
+//!   internal proxy returns.
+//! - `listen` of a TCP socket subscribes its port, in the incoming mode of the config.
+//! - `open` for reading of an absolute path that matches `feature.fs.read_only` or
+//!   `feature.fs.read_write` returns a memfd with the contents of the remote file.
+//!
+//! Every other call continues in the kernel. The filter is inherited by the children of the
+//! binary, so they're intercepted the same way.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStringExt,
+            fs::FileExt,
+            net::UnixStream,
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+};
+
+use libc::{c_int, c_uint, c_ulong};
+use mirrord_config::{
+    feature::{
+        fs::FsConfig,
+        network::incoming::{IncomingConfig, IncomingMode},
+    },
+    util::VecOrSingle,
+    LayerConfig,
+};
+use mirrord_intproxy_protocol::{
+    codec::{SyncDecoder, SyncEncoder},
+    IsLayerRequest, IsLayerRequestWithResponse, LayerToProxyMessage, LocalMessage, MessageId,
+    NetProtocol, NewSessionRequest, OutgoingConnectRequest, OutgoingConnectResponse, PortSubscribe,
+    PortSubscription, ProcessInfo, ProxyToLayerMessage,
+};
+use mirrord_protocol::{
+    file::{
+        CloseFileRequest, OpenFileRequest, OpenFileResponse, OpenOptionsInternal, ReadFileRequest,
+        ReadFileResponse,
+    },
+    outgoing::SocketAddress,
+    tcp::{Filter, HttpFilter, StealType},
+    ResponseError,
+};
+use regex::RegexSet;
+use socket2::{Domain, SockAddr, Socket, Type};
+use tracing::{debug, warn};
+
+/// `SECCOMP_SET_MODE_FILTER` operation of the `seccomp` syscall.
+const SECCOMP_SET_MODE_FILTER: c_uint = 1;
+/// Makes the `seccomp` syscall return the notification fd of the filter.
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: c_ulong = 1 << 3;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+/// Lets the intercepted syscall continue in the kernel.
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+/// Adds the fd to the process and returns it from the intercepted syscall, in one step.
+const SECCOMP_ADDFD_FLAG_SEND: u32 = 1 << 1;
+
+const SECCOMP_IOCTL_NOTIF_RECV: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 0, mem::size_of::<SeccompNotif>());
+const SECCOMP_IOCTL_NOTIF_SEND: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 1, mem::size_of::<SeccompNotifResp>());
+const SECCOMP_IOCTL_NOTIF_ID_VALID: c_ulong = ioc(IOC_WRITE, 2, mem::size_of::<u64>());
+const SECCOMP_IOCTL_NOTIF_ADDFD: c_ulong = ioc(IOC_WRITE, 3, mem::size_of::<SeccompNotifAddfd>());
+
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets of the fields of [`SeccompData`], loaded by the filter.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Size of the remote file reads.
+const READ_BUFFER_SIZE: u64 = 1024 * 1024;
+
+/// `_IOC` from `asm-generic/ioctl.h`, with the seccomp ioctl type `'!'`.
+const fn ioc(direction: c_ulong, number: c_ulong, size: usize) -> c_ulong {
+    (direction << 30) | ((size as c_ulong) << 16) | ((b'!' as c_ulong) << 8) | number
+}
+
+/// `struct seccomp_data` from `linux/seccomp.h`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SeccompData {
+    nr: c_int,
+    arch: u32,
+    instruction_pointer: u64,
+    args: [u64; 6],
+}
+
+/// `struct seccomp_notif`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: SeccompData,
+}
+
+/// `struct seccomp_notif_resp`.
+#[repr(C)]
+#[derive(Debug)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+/// `struct seccomp_notif_addfd`.
+#[repr(C)]
+#[derive(Debug)]
+struct SeccompNotifAddfd {
+    id: u64,
+    flags: u32,
+    srcfd: u32,
+    newfd: u32,
+    newfd_flags: u32,
+}
+
+/// `struct sock_filter`, a classic BPF instruction.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// `struct sock_fprog`.
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+/// The syscalls the CLI handles for the `config`, the others never leave the kernel.
+fn intercepted_syscalls(config: &LayerConfig) -> Vec<u32> {
+    let mut syscalls = Vec::new();
+
+    let outgoing = &config.feature.network.outgoing;
+    if outgoing.tcp || outgoing.udp {
+        syscalls.push(libc::SYS_connect as u32);
+    }
+
+    if !matches!(config.feature.network.incoming.mode, IncomingMode::Off) {
+        syscalls.push(libc::SYS_listen as u32);
+    }
+
+    let fs = &config.feature.fs;
+    if !fs.mode.is_local() && (fs.read_only.is_some() || fs.read_write.is_some()) {
+        syscalls.push(libc::SYS_openat as u32);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.push(libc::SYS_open as u32);
+    }
+
+    syscalls
+}
+
+/// Builds a filter that notifies the supervisor of the `syscalls` and allows everything else,
+/// including all syscalls of other architectures (e.g. 32-bit ones).
+fn build_filter(syscalls: &[u32]) -> Vec<SockFilter> {
+    let instruction = |code, jt, jf, k| SockFilter { code, jt, jf, k };
+
+    let mut filter = vec![
+        instruction(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_ARCH),
+        instruction(BPF_JMP_JEQ_K, 1, 0, AUDIT_ARCH),
+        instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        instruction(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_NR),
+    ];
+
+    // Each match jumps over the following checks and the allow, to the notify at the end.
+    for (index, syscall) in syscalls.iter().enumerate() {
+        let to_notify = (syscalls.len() - index) as u8;
+        filter.push(instruction(BPF_JMP_JEQ_K, to_notify, 0, *syscall));
+    }
+
+    filter.push(instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+    filter.push(instruction(BPF_RET_K, 0, 0, SECCOMP_RET_USER_NOTIF));
+
+    filter
+}
+
+/// Installs the `filter` in the calling process and returns its notification fd.
+///
+/// Runs in the child between `fork` and `exec`, so it only makes syscalls.
+fn install_filter(filter: &[SockFilter]) -> io::Result<RawFd> {
+    let program = SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let listener = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &program as *const SockFprog,
+        )
+    };
+    if listener < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener as RawFd)
+}
+
+/// A control message with a single fd, `SCM_RIGHTS`.
+#[repr(C)]
+struct FdMessage {
+    header: libc::cmsghdr,
+    fd: c_int,
+}
+
+/// Sends the `fd` through the `socket`, without allocating (it runs between `fork` and `exec`).
+fn send_fd(socket: RawFd, fd: RawFd) -> io::Result<()> {
+    let mut byte = [0_u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+
+    let mut control: FdMessage = unsafe { mem::zeroed() };
+    control.header.cmsg_len = (mem::size_of::<libc::cmsghdr>() + mem::size_of::<c_int>()) as _;
+    control.header.cmsg_level = libc::SOL_SOCKET;
+    control.header.cmsg_type = libc::SCM_RIGHTS;
+    control.fd = fd;
+
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = (&mut control as *mut FdMessage).cast();
+    message.msg_controllen = mem::size_of::<FdMessage>() as _;
+
+    if unsafe { libc::sendmsg(socket, &message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Receives an fd sent with [`send_fd`].
+fn receive_fd(socket: &UnixStream) -> io::Result<OwnedFd> {
+    let mut byte = [0_u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+
+    let mut control: FdMessage = unsafe { mem::zeroed() };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = (&mut control as *mut FdMessage).cast();
+    message.msg_controllen = mem::size_of::<FdMessage>() as _;
+
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if message.msg_controllen == 0
+        || control.header.cmsg_level != libc::SOL_SOCKET
+        || control.header.cmsg_type != libc::SCM_RIGHTS
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the binary didn't send the seccomp notification fd",
+        ));
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(control.fd) })
+}
+
+/// Reads the thread group (process) id from the contents of `/proc/<tid>/status`.
+fn parse_thread_group(status: &str) -> Option<libc::pid_t> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+}
+
+/// Maps the local address a listener is bound on to the address the internal proxy connects to.
+fn listening_on(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, address.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, address.port()).into(),
+        _ => address,
+    }
+}
+
+/// The errno the binary gets for a failed remote operation.
+fn remote_errno(error: &ResponseError) -> c_int {
+    match error {
+        ResponseError::NotFound(_) => libc::ENOENT,
+        ResponseError::NotDirectory(_) => libc::ENOTDIR,
+        ResponseError::NotFile(_) => libc::EISDIR,
+        ResponseError::RemoteIO(error) => error.raw_os_error.unwrap_or(libc::EIO),
+        ResponseError::PortAlreadyStolen(_) => libc::EADDRINUSE,
+        ResponseError::Forbidden { .. } => libc::EACCES,
+        _ => libc::EIO,
+    }
+}
+
+fn remote_error(error: ResponseError) -> io::Error {
+    io::Error::from_raw_os_error(remote_errno(&error))
+}
+
+/// Which listeners subscribe their port, and how, following the layer's `IncomingMode`.
+struct Subscriptions {
+    config: IncomingConfig,
+    http_filter: Option<HttpFilter>,
+}
+
+impl Subscriptions {
+    fn new(config: &IncomingConfig) -> io::Result<Self> {
+        let http_filter = match (
+            &config.http_filter.path_filter,
+            &config.http_filter.header_filter,
+        ) {
+            (Some(path), _) => Some(HttpFilter::Path(
+                Filter::new(path.clone()).map_err(io::Error::other)?,
+            )),
+            (None, Some(header)) => Some(HttpFilter::Header(
+                Filter::new(header.clone()).map_err(io::Error::other)?,
+            )),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            http_filter,
+        })
+    }
+
+    /// The subscription of a TCP listener on the local `address`, [`None`] when it stays local.
+    fn subscription(&self, address: SocketAddr) -> Option<PortSubscription> {
+        let port = address.port();
+        if matches!(self.config.mode, IncomingMode::Off)
+            || port == 0
+            || self.config.ignore_ports.contains(&port)
+            || (address.ip().is_loopback() && !self.config.intercepts_loopback_port(port))
+        {
+            return None;
+        }
+
+        let remote_port = self
+            .config
+            .port_mapping
+            .get_by_left(&port)
+            .copied()
+            .unwrap_or(port);
+        if self
+            .config
+            .ports
+            .as_ref()
+            .is_some_and(|ports| !ports.contains(remote_port))
+        {
+            return None;
+        }
+
+        if !self.config.is_steal() {
+            return Some(PortSubscription::Mirror(remote_port));
+        }
+
+        let steal_type = match &self.http_filter {
+            Some(filter)
+                if self
+                    .config
+                    .http_filter
+                    .ports
+                    .as_slice()
+                    .contains(&remote_port) =>
+            {
+                StealType::FilteredHttpEx(remote_port, filter.clone())
+            }
+            _ => StealType::All(remote_port),
+        };
+
+        Some(PortSubscription::Steal(steal_type))
+    }
+}
+
+/// Files that are read from the target: `feature.fs.read_only` and `feature.fs.read_write`,
+/// except `feature.fs.local`.
+struct RemoteFiles {
+    remote: RegexSet,
+    local: RegexSet,
+}
+
+impl RemoteFiles {
+    fn new(config: &FsConfig) -> Result<Self, regex::Error> {
+        if config.mode.is_local() {
+            return Ok(Self {
+                remote: RegexSet::empty(),
+                local: RegexSet::empty(),
+            });
+        }
+
+        let patterns = |patterns: &Option<VecOrSingle<String>>| {
+            patterns
+                .clone()
+                .map(VecOrSingle::to_vec)
+                .unwrap_or_default()
+        };
+
+        let mut remote = patterns(&config.read_only);
+        remote.extend(patterns(&config.read_write));
+
+        Ok(Self {
+            remote: RegexSet::new(remote)?,
+            local: RegexSet::new(patterns(&config.local))?,
+        })
+    }
+
+    fn is_remote(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        self.remote.is_match(&path) && !self.local.is_match(&path)
+    }
+}
+
+/// How the supervisor completes an intercepted syscall.
+#[derive(Debug)]
+enum Reply {
+    /// The syscall runs in the kernel, as if it wasn't intercepted.
+    Continue,
+    /// The syscall returns this value.
+    Return(i64),
+    /// The syscall fails with this errno.
+    Error(c_int),
+    /// The fd is added to the process, and the syscall returns its number.
+    Fd { fd: OwnedFd, cloexec: bool },
+}
+
+/// A syscall of the binary waiting for the reply of the supervisor.
+struct Notification<'a> {
+    listener: BorrowedFd<'a>,
+    id: u64,
+    /// Id of the calling thread.
+    pid: u32,
+}
+
+impl Notification<'_> {
+    /// Reads the memory of the calling process at `address`, returns how many bytes were read.
+    fn read(&self, address: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        let local = libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: address as *mut _,
+            iov_len: buffer.len(),
+        };
+
+        let read = unsafe { libc::process_vm_readv(self.pid as _, &local, 1, &remote, 1, 0) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(read as usize)
+    }
+
+    /// Reads a `sockaddr` of `length` bytes, [`None`] if it's not an IP address.
+    fn read_sockaddr(&self, address: u64, length: u64) -> io::Result<Option<SocketAddr>> {
+        let length = usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= mem::size_of::<libc::sockaddr_storage>())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let buffer = unsafe {
+            std::slice::from_raw_parts_mut(
+                (&mut storage as *mut libc::sockaddr_storage).cast(),
+                length,
+            )
+        };
+        if self.read(address, buffer)? != length {
+            return Err(io::Error::from_raw_os_error(libc::EFAULT));
+        }
+
+        let address = unsafe { SockAddr::new(storage, length as libc::socklen_t) };
+        Ok(address.as_socket())
+    }
+
+    /// Reads a nul-terminated path.
+    fn read_path(&self, mut address: u64) -> io::Result<PathBuf> {
+        // Chunks don't cross 256 byte boundaries, so they never cross into an unmapped page.
+        const CHUNK: u64 = 256;
+
+        let mut path = Vec::new();
+        while path.len() < libc::PATH_MAX as usize {
+            let mut chunk = [0; CHUNK as usize];
+            let length = (CHUNK - address % CHUNK) as usize;
+            let chunk = chunk.get_mut(..length).unwrap_or_default();
+            let read = self.read(address, chunk)?;
+            let chunk = chunk.get(..read).unwrap_or_default();
+
+            match chunk.iter().position(|byte| *byte == 0) {
+                Some(end) => {
+                    path.extend(chunk.iter().take(end));
+                    return Ok(OsString::from_vec(path).into());
+                }
+                None => path.extend_from_slice(chunk),
+            }
+            address += read as u64;
+        }
+
+        Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG))
+    }
+
+    /// Whether the syscall is still waiting, i.e. the memory read for it belongs to the process
+    /// that made it (and not to another process that reused its id).
+    fn check_valid(&self) -> io::Result<()> {
+        let id = self.id;
+        let result = unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_ID_VALID as _,
+                &id as *const u64,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Takes a duplicate of the socket `fd` of the calling process.
+    fn socket(&self, fd: u64) -> io::Result<Socket> {
+        let status = fs::read_to_string(format!("/proc/{}/status", self.pid))?;
+        let process =
+            parse_thread_group(&status).ok_or_else(|| io::Error::from_raw_os_error(libc::ESRCH))?;
+
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, process, 0) };
+        if pidfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+
+        let socket =
+            unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd as c_int, 0) };
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { Socket::from_raw_fd(socket as RawFd) })
+    }
+
+    /// Completes the syscall.
+    fn reply(&self, reply: Reply) -> io::Result<()> {
+        let (val, error, flags) = match reply {
+            Reply::Continue => (0, 0, SECCOMP_USER_NOTIF_FLAG_CONTINUE),
+            Reply::Return(value) => (value, 0, 0),
+            Reply::Error(errno) => (0, -errno, 0),
+            Reply::Fd { fd, cloexec } => return self.add_fd(fd, cloexec),
+        };
+
+        let response = SeccompNotifResp {
+            id: self.id,
+            val,
+            error,
+            flags,
+        };
+        let result = unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_SEND as _,
+                &response as *const SeccompNotifResp,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Completes the syscall with a copy of the `fd` in the calling process.
+    fn add_fd(&self, fd: OwnedFd, cloexec: bool) -> io::Result<()> {
+        let addfd = SeccompNotifAddfd {
+            id: self.id,
+            flags: SECCOMP_ADDFD_FLAG_SEND,
+            srcfd: fd.as_raw_fd() as u32,
+            newfd: 0,
+            newfd_flags: if cloexec { libc::O_CLOEXEC as u32 } else { 0 },
+        };
+        let result = unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_ADDFD as _,
+                &addfd as *const SeccompNotifAddfd,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Synchronous connection with the internal proxy, with one request in flight at a time.
+struct ProxyConnection {
+    sender: SyncEncoder<LocalMessage<LayerToProxyMessage>, Box<dyn Write + Send>>,
+    receiver: SyncDecoder<LocalMessage<ProxyToLayerMessage>, Box<dyn Read + Send>>,
+    next_message_id: MessageId,
+}
+
+impl ProxyConnection {
+    /// Connects to the internal proxy at the address the layer would get, and starts the session
+    /// of the process.
+    fn connect(process: ProcessInfo) -> io::Result<Self> {
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) =
+            match std::env::var("MIRRORD_CONNECT_UNIX") {
+                Ok(path) => {
+                    let stream = UnixStream::connect(path)?;
+                    (Box::new(stream.try_clone()?), Box::new(stream))
+                }
+                Err(..) => {
+                    let address = std::env::var("MIRRORD_CONNECT_TCP").map_err(io::Error::other)?;
+                    let stream = TcpStream::connect(address)?;
+                    (Box::new(stream.try_clone()?), Box::new(stream))
+                }
+            };
+
+        let mut connection = Self {
+            sender: SyncEncoder::new(writer),
+            receiver: SyncDecoder::new(reader),
+            next_message_id: 0,
+        };
+
+        let message_id = connection.send(LayerToProxyMessage::NewSession(
+            NewSessionRequest::New(process),
+        ))?;
+        match connection.receive(message_id)? {
+            ProxyToLayerMessage::NewSession(..) => Ok(connection),
+            other => Err(io::Error::other(format!("unexpected response {other:?}"))),
+        }
+    }
+
+    fn send(&mut self, message: LayerToProxyMessage) -> io::Result<MessageId> {
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+
+        self.sender
+            .send(&LocalMessage {
+                message_id,
+                inner: message,
+            })
+            .map_err(io::Error::other)?;
+        self.sender.flush().map_err(io::Error::other)?;
+
+        Ok(message_id)
+    }
+
+    fn receive(&mut self, message_id: MessageId) -> io::Result<ProxyToLayerMessage> {
+        loop {
+            let message = self
+                .receiver
+                .receive()
+                .map_err(io::Error::other)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+            if message.message_id == message_id {
+                break Ok(message.inner);
+            }
+        }
+    }
+
+    fn request<T: IsLayerRequestWithResponse>(&mut self, request: T) -> io::Result<T::Response> {
+        let message_id = self.send(request.wrap())?;
+
+        match self.receive(message_id)? {
+            ProxyToLayerMessage::Interrupted => Err(io::Error::from_raw_os_error(libc::EINTR)),
+            response => T::try_unwrap_response(response)
+                .map_err(|other| io::Error::other(format!("unexpected response {other:?}"))),
+        }
+    }
+}
+
+/// Handles the intercepted syscalls of the binary.
+struct Supervisor {
+    proxy: ProxyConnection,
+    outgoing_tcp: bool,
+    outgoing_udp: bool,
+    subscriptions: Subscriptions,
+    remote_files: RemoteFiles,
+}
+
+impl Supervisor {
+    /// Receives the notifications until every process under the filter exits.
+    fn run(mut self, listener: OwnedFd) {
+        loop {
+            let mut poll = libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut poll, 1, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                warn!(%error, "failed to wait for seccomp notifications");
+                break;
+            }
+            // `POLLHUP` without `POLLIN`, no process is left under the filter.
+            if poll.revents & libc::POLLIN == 0 {
+                break;
+            }
+
+            let mut notif: SeccompNotif = unsafe { mem::zeroed() };
+            let received = unsafe {
+                libc::ioctl(
+                    listener.as_raw_fd(),
+                    SECCOMP_IOCTL_NOTIF_RECV as _,
+                    &mut notif as *mut SeccompNotif,
+                )
+            };
+            if received < 0 {
+                let error = io::Error::last_os_error();
+                // The calling thread may have been interrupted before we got to it.
+                if matches!(error.raw_os_error(), Some(libc::EINTR | libc::ENOENT)) {
+                    continue;
+                }
+
+                warn!(%error, "failed to receive a seccomp notification");
+                break;
+            }
+
+            let notification = Notification {
+                listener: listener.as_fd(),
+                id: notif.id,
+                pid: notif.pid,
+            };
+            let reply = match self.handle(&notification, &notif.data) {
+                Ok(reply) => reply,
+                Err(error) => {
+                    debug!(%error, syscall = notif.data.nr, "intercepted syscall failed");
+                    Reply::Error(error.raw_os_error().unwrap_or(libc::EIO))
+                }
+            };
+
+            if let Err(error) = notification.reply(reply) {
+                debug!(%error, syscall = notif.data.nr, "failed to complete intercepted syscall");
+            }
+        }
+    }
+
+    fn handle(&mut self, notification: &Notification, data: &SeccompData) -> io::Result<Reply> {
+        let [arg0, arg1, arg2, ..] = data.args;
+
+        match i64::from(data.nr) {
+            libc::SYS_connect => self.connect(notification, arg0, arg1, arg2),
+            libc::SYS_listen => self.listen(notification, arg0, arg1),
+            libc::SYS_openat => self.open(notification, arg1, arg2),
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_open => self.open(notification, arg0, arg1),
+            _ => Ok(Reply::Continue),
+        }
+    }
+
+    fn connect(
+        &mut self,
+        notification: &Notification,
+        fd: u64,
+        address: u64,
+        length: u64,
+    ) -> io::Result<Reply> {
+        let Some(remote_address) = notification.read_sockaddr(address, length)? else {
+            return Ok(Reply::Continue);
+        };
+        notification.check_valid()?;
+        if remote_address.ip().is_loopback() || remote_address.ip().is_unspecified() {
+            return Ok(Reply::Continue);
+        }
+
+        let socket = notification.socket(fd)?;
+        let protocol = match socket.r#type()? {
+            Type::STREAM if self.outgoing_tcp => NetProtocol::Stream,
+            Type::DGRAM if self.outgoing_udp => NetProtocol::Datagrams,
+            _ => return Ok(Reply::Continue),
+        };
+
+        let OutgoingConnectResponse { layer_address, .. } = self
+            .proxy
+            .request(OutgoingConnectRequest {
+                remote_address: SocketAddress::Ip(remote_address),
+                protocol,
+            })?
+            .map_err(remote_error)?;
+        let SocketAddress::Ip(mut layer_address) = layer_address else {
+            return Ok(Reply::Error(libc::EAFNOSUPPORT));
+        };
+
+        if let (Domain::IPV6, SocketAddr::V4(address)) = (socket.domain()?, layer_address) {
+            layer_address = (address.ip().to_ipv6_mapped(), address.port()).into();
+        }
+
+        // The socket is shared with the binary, so a non-blocking one returns `EINPROGRESS`.
+        Ok(match socket.connect(&layer_address.into()) {
+            Ok(()) => Reply::Return(0),
+            Err(error) => Reply::Error(error.raw_os_error().unwrap_or(libc::EIO)),
+        })
+    }
+
+    fn listen(&mut self, notification: &Notification, fd: u64, backlog: u64) -> io::Result<Reply> {
+        let socket = notification.socket(fd)?;
+        if socket.r#type()? != Type::STREAM {
+            return Ok(Reply::Continue);
+        }
+
+        let Some(address) = socket.local_addr()?.as_socket() else {
+            return Ok(Reply::Continue);
+        };
+        let Some(subscription) = self.subscriptions.subscription(address) else {
+            return Ok(Reply::Continue);
+        };
+
+        if let Err(error) = socket.listen(backlog as c_int) {
+            return Ok(Reply::Error(error.raw_os_error().unwrap_or(libc::EIO)));
+        }
+
+        self.proxy
+            .request(PortSubscribe {
+                listening_on: listening_on(address),
+                subscription,
+            })?
+            .map_err(remote_error)?;
+
+        Ok(Reply::Return(0))
+    }
+
+    fn open(&mut self, notification: &Notification, path: u64, flags: u64) -> io::Result<Reply> {
+        let flags = flags as c_int;
+        let not_read_only =
+            libc::O_CREAT | libc::O_TRUNC | libc::O_DIRECTORY | libc::O_PATH | libc::O_TMPFILE;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & not_read_only != 0 {
+            return Ok(Reply::Continue);
+        }
+
+        let path = notification.read_path(path)?;
+        notification.check_valid()?;
+        if !path.is_absolute() || !self.remote_files.is_remote(&path) {
+            return Ok(Reply::Continue);
+        }
+
+        let contents = self.read_remote_file(path)?;
+
+        let fd = unsafe { libc::memfd_create(c"mirrord-remote-file".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        // Keeps the offset at the start, the binary shares it.
+        file.write_all_at(&contents, 0)?;
+
+        Ok(Reply::Fd {
+            fd: file.into(),
+            cloexec: flags & libc::O_CLOEXEC != 0,
+        })
+    }
+
+    fn read_remote_file(&mut self, path: PathBuf) -> io::Result<Vec<u8>> {
+        let OpenFileResponse { fd } = self
+            .proxy
+            .request(OpenFileRequest {
+                path,
+                open_options: OpenOptionsInternal {
+                    read: true,
+                    ..Default::default()
+                },
+            })?
+            .map_err(remote_error)?;
+
+        let mut contents = Vec::new();
+        let result = loop {
+            let response = self.proxy.request(ReadFileRequest {
+                remote_fd: fd,
+                buffer_size: READ_BUFFER_SIZE,
+            });
+
+            match response {
+                Ok(Ok(ReadFileResponse { read_amount: 0, .. })) => break Ok(contents),
+                Ok(Ok(ReadFileResponse { bytes, .. })) => contents.extend_from_slice(&bytes),
+                Ok(Err(error)) => break Err(remote_error(error)),
+                Err(error) => break Err(error),
+            }
+        };
+
+        self.proxy.send(CloseFileRequest { fd }.wrap())?;
+
+        result
+    }
+}
+
+/// Runs the `binary` under the seccomp filter, where `binary_args` start with its `argv[0]`, and
+/// handles its intercepted syscalls until it exits. Returns the exit code of the binary.
+pub(crate) fn exec_intercepted(
+    config: &LayerConfig,
+    binary: &str,
+    binary_args: &[String],
+) -> io::Result<i32> {
+    let filter = build_filter(&intercepted_syscalls(config));
+    let (parent_socket, child_socket) = UnixStream::pair()?;
+    let child_socket_fd = child_socket.as_raw_fd();
+
+    let mut command = Command::new(binary);
+    if let Some((arg0, args)) = binary_args.split_first() {
+        command.arg0(arg0).args(args);
+    }
+
+    // The binary gets ^C from the terminal, and the CLI must outlive it to complete its syscalls.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+
+        command.pre_exec(move || {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+
+            let listener = install_filter(&filter)?;
+            let sent = send_fd(child_socket_fd, listener);
+            libc::close(listener);
+            sent
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(child_socket);
+
+    let supervisor = receive_fd(&parent_socket).and_then(|listener| {
+        let name = Path::new(binary)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let proxy = ProxyConnection::connect(ProcessInfo {
+            pid: child.id(),
+            name,
+            cmdline: binary_args.to_vec(),
+            loaded: true,
+        })?;
+
+        let supervisor = Supervisor {
+            proxy,
+            outgoing_tcp: config.feature.network.outgoing.tcp,
+            outgoing_udp: config.feature.network.outgoing.udp,
+            subscriptions: Subscriptions::new(&config.feature.network.incoming)?,
+            remote_files: RemoteFiles::new(&config.feature.fs).map_err(io::Error::other)?,
+        };
+
+        Ok((supervisor, listener))
+    });
+    let (supervisor, listener) = match supervisor {
+        Ok(supervisor) => supervisor,
+        Err(error) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(error);
+        }
+    };
+
+    // Processes started by the binary may outlive it, their syscalls fail once the CLI exits.
+    thread::spawn(move || supervisor.run(listener));
+
+    let status = child.wait()?;
+    Ok(status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_config::feature::network::incoming::PortRanges;
+
+    use super::*;
+
+    #[test]
+    fn ioctl_numbers() {
+        assert_eq!(SECCOMP_IOCTL_NOTIF_RECV, 0xc050_2100);
+        assert_eq!(SECCOMP_IOCTL_NOTIF_SEND, 0xc018_2101);
+        assert_eq!(SECCOMP_IOCTL_NOTIF_ID_VALID, 0x4008_2102);
+        assert_eq!(SECCOMP_IOCTL_NOTIF_ADDFD, 0x4018_2103);
+    }
+
+    #[test]
+    fn filter_notifies_syscalls() {
+        let filter = build_filter(&[42, 50]);
+
+        assert_eq!(filter.len(), 8);
+        // `connect` jumps over the `listen` check and the allow, `listen` over the allow.
+        assert_eq!(
+            filter.get(4),
+            Some(&SockFilter {
+                code: BPF_JMP_JEQ_K,
+                jt: 2,
+                jf: 0,
+                k: 42
+            })
+        );
+        assert_eq!(filter.get(5).map(|check| check.jt), Some(1));
+        assert_eq!(filter.get(6).map(|ret| ret.k), Some(SECCOMP_RET_ALLOW));
+        assert_eq!(filter.get(7).map(|ret| ret.k), Some(SECCOMP_RET_USER_NOTIF));
+    }
+
+    #[test]
+    fn thread_group_from_status() {
+        let status = "Name:\tserver\nUmask:\t0022\nState:\tS (sleeping)\nTgid:\t4242\nNgid:\t0\n\
+                      Pid:\t4250\n";
+
+        assert_eq!(parse_thread_group(status), Some(4242));
+        assert_eq!(parse_thread_group("Name:\tserver\n"), None);
+    }
+
+    #[test]
+    fn subscribes_listeners() {
+        let config = IncomingConfig {
+            mode: IncomingMode::Steal,
+            port_mapping: [(8080, 80)].into_iter().collect(),
+            ignore_ports: [9999].into(),
+            ports: Some(PortRanges::from_iter([80..=80, 3000..=3000])),
+            ..Default::default()
+        };
+        let subscriptions = Subscriptions::new(&config).unwrap();
+
+        assert_eq!(
+            subscriptions.subscription("0.0.0.0:8080".parse().unwrap()),
+            Some(PortSubscription::Steal(StealType::All(80)))
+        );
+        assert_eq!(
+            subscriptions.subscription("[::]:3000".parse().unwrap()),
+            Some(PortSubscription::Steal(StealType::All(3000)))
+        );
+        assert_eq!(
+            subscriptions.subscription("0.0.0.0:9999".parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            subscriptions.subscription("0.0.0.0:4000".parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            subscriptions.subscription("127.0.0.1:4000".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn listeners_on_unspecified_addresses() {
+        assert_eq!(
+            listening_on("0.0.0.0:80".parse().unwrap()),
+            "127.0.0.1:80".parse().unwrap()
+        );
+        assert_eq!(
+            listening_on("[::]:80".parse().unwrap()),
+            "[::1]:80".parse().unwrap()
+        );
+        assert_eq!(
+            listening_on("10.0.0.1:80".parse().unwrap()),
+            "10.0.0.1:80".parse().unwrap()
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Detection of statically linked executables, that mirrord can't load into.
+//!
+//! The layer is loaded with `LD_PRELOAD` by the dynamic loader, and statically linked executables
+//! (e.g. Go binaries built with `CGO_ENABLED=0`, or Rust binaries built for musl) run without one,
+//! so they'd silently run without mirrord.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// 64-bit ELF, `e_ident[EI_CLASS]`.
+const ELFCLASS64: u8 = 2;
+/// Little-endian ELF, `e_ident[EI_DATA]`.
+const ELFDATA2LSB: u8 = 1;
+/// Program header with the path of the dynamic loader.
+const PT_INTERP: u32 = 3;
+
+/// Reads `(e_phoff, e_phentsize, e_phnum)` from the header of a 64-bit little-endian ELF.
+fn program_headers(header: &[u8; 64]) -> Option<(u64, usize, usize)> {
+    if !header.starts_with(ELF_MAGIC)
+        || header.get(4) != Some(&ELFCLASS64)
+        || header.get(5) != Some(&ELFDATA2LSB)
+    {
+        return None;
+    }
+
+    let phoff = u64::from_le_bytes(header.get(0x20..0x28)?.try_into().ok()?);
+    let phentsize = u16::from_le_bytes(header.get(0x36..0x38)?.try_into().ok()?);
+    let phnum = u16::from_le_bytes(header.get(0x38..0x3a)?.try_into().ok()?);
+
+    Some((phoff, phentsize.into(), phnum.into()))
+}
+
+/// Whether the executable at `path` is a statically linked ELF, i.e. it has no `PT_INTERP`
+/// program header.
+///
+/// Only 64-bit little-endian ELFs are checked, anything else (e.g. scripts) is not statically
+/// linked.
+pub(crate) fn is_statically_linked(path: &Path) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+
+    let mut header = [0; 64];
+    match file.read_exact(&mut header) {
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        result => result?,
+    }
+
+    let Some((phoff, phentsize, phnum)) = program_headers(&header) else {
+        return Ok(false);
+    };
+    if phentsize < 4 {
+        return Ok(false);
+    }
+
+    let mut headers = vec![0; phentsize * phnum];
+    file.seek(SeekFrom::Start(phoff))?;
+    file.read_exact(&mut headers)?;
+
+    Ok(!headers
+        .chunks_exact(phentsize)
+        .any(|header| header.get(..4) == Some(PT_INTERP.to_le_bytes().as_slice())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamic_executable() {
+        let path = std::env::current_exe().unwrap();
+        assert!(!is_statically_linked(&path).unwrap());
+    }
+
+    #[test]
+    fn not_elf() {
+        let path = std::env::temp_dir().join(format!(
+            "mirrord-static-binary-test-{}.sh",
+            std::process::id()
+        ));
+        std::fs::write(&path, "#!/bin/sh\necho hello\n").unwrap();
+
+        let result = is_statically_linked(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!result.unwrap());
+    }
+}
@@ -0,0 +1,203 @@
+//! `mirrord status`, what the running sessions on this machine are doing.
+//!
+//! Every internal proxy serves its [`SessionStatus`] on a unix socket in [`sessions_dir`], which
+//! is private to the user. The sockets of sessions that ended without removing them are removed
+//! here.
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use mirrord_intproxy::status::{check_private_dir, sessions_dir, SessionStatus};
+use tokio::{io::AsyncReadExt, net::UnixStream};
+
+use crate::{CliError, Result, StatusArgs};
+
+/// How long we wait for a session to send its status.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Reads the status from the socket at `path`, `Ok(None)` when the session is gone.
+async fn session_status(path: &Path) -> io::Result<Option<SessionStatus>> {
+    let mut stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(fail)
+            if matches!(
+                fail.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+            ) =>
+        {
+            let _ = std::fs::remove_file(path);
+            return Ok(None);
+        }
+        Err(fail) => return Err(fail),
+    };
+
+    let mut bytes = Vec::new();
+    tokio::time::timeout(STATUS_TIMEOUT, stream.read_to_end(&mut bytes))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// Statuses of the running sessions, the oldest first.
+async fn session_statuses() -> Result<Vec<SessionStatus>> {
+    let dir = sessions_dir();
+    match check_private_dir(&dir) {
+        Ok(()) => {}
+        Err(fail) if fail.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(fail) => return Err(CliError::SessionStatusFailed(dir, fail)),
+    }
+
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(fail) if fail.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(fail) => return Err(CliError::SessionStatusFailed(dir, fail)),
+    };
+
+    let mut statuses = Vec::new();
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().and_then(|extension| extension.to_str()) != Some("sock") {
+            continue;
+        }
+
+        match session_status(&path).await {
+            Ok(Some(status)) => statuses.push(status),
+            Ok(None) => {}
+            Err(fail) => return Err(CliError::SessionStatusFailed(path, fail)),
+        }
+    }
+
+    statuses.sort_by_key(|status| status.started);
+    Ok(statuses)
+}
+
+/// Joins the `items`, or `none` when there are none.
+fn list<I>(items: I) -> String
+where
+    I: IntoIterator<Item = String>,
+{
+    let items = items.into_iter().collect::<Vec<_>>();
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+/// Human readable status, for the terminal.
+fn format_status(status: &SessionStatus, now: SystemTime) -> String {
+    let running = now
+        .duration_since(UNIX_EPOCH + Duration::from_secs(status.started))
+        .map(|running| Duration::from_secs(running.as_secs()))
+        .unwrap_or_default();
+
+    let target = match &status.namespace {
+        Some(namespace) => format!("{} in `{namespace}`", status.target),
+        None => status.target.clone(),
+    };
+    let agent = match (&status.agent, status.operator) {
+        (_, true) => "created by the operator".to_string(),
+        (Some(agent), _) => agent.clone(),
+        (None, _) => "unknown".to_string(),
+    };
+    let agent = match (status.reconnecting, status.reconnects) {
+        (Some(attempt), _) => format!("{agent}, reconnecting (attempt {attempt})"),
+        (None, 0) => agent,
+        (None, reconnects) => format!("{agent}, reconnected {reconnects} times"),
+    };
+    let mirrored_ports = list(status.mirrored_ports.iter().map(ToString::to_string));
+    let stolen_ports = list(
+        status
+            .stolen_ports
+            .iter()
+            .map(|(port, filter)| match filter {
+                Some(filter) => format!("{port} ({filter})"),
+                None => port.to_string(),
+            }),
+    );
+
+    format!(
+        "Session {} (running for {})\n  \
+         target: {target}\n  \
+         agent: {agent}\n  \
+         processes: {}\n  \
+         mirrored ports: {mirrored_ports}\n  \
+         stolen ports: {stolen_ports}\n  \
+         connections: {} mirrored, {} stolen ({} filtered requests), {} outgoing\n  \
+         remote file operations: {}\n  \
+         DNS queries: {}",
+        status.pid,
+        humantime::format_duration(running),
+        status.processes,
+        status.mirrored_connections,
+        status.stolen_connections,
+        status.stolen_requests,
+        status.outgoing_connections,
+        status.file_operations,
+        status.dns_queries,
+    )
+}
+
+/// Runs `mirrord status`.
+pub(crate) async fn status_command(args: StatusArgs) -> Result<()> {
+    let statuses = session_statuses().await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&statuses)?);
+    } else if statuses.is_empty() {
+        println!("No mirrord sessions are running.");
+    } else {
+        let now = SystemTime::now();
+        let formatted = statuses
+            .iter()
+            .map(|status| format_status(status, now))
+            .collect::<Vec<_>>();
+        println!("{}", formatted.join("\n\n"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::*;
+
+    #[test]
+    fn formats_status() {
+        let status = SessionStatus {
+            pid: 4242,
+            started: 1_700_000_000,
+            target: "deployment/api/container/app".to_string(),
+            namespace: Some("shop".to_string()),
+            agent: Some("mirrord-agent-x1".to_string()),
+            processes: 2,
+            mirrored_ports: BTreeSet::from([8080]),
+            stolen_ports: BTreeMap::from([(80, Some("header=x-user: me".to_string()))]),
+            mirrored_connections: 3,
+            stolen_connections: 1,
+            stolen_requests: 7,
+            outgoing_connections: 5,
+            file_operations: 120,
+            dns_queries: 9,
+            ..Default::default()
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_090);
+
+        assert_eq!(
+            format_status(&status, now),
+            "Session 4242 (running for 1m 30s)\n  \
+             target: deployment/api/container/app in `shop`\n  \
+             agent: mirrord-agent-x1\n  \
+             processes: 2\n  \
+             mirrored ports: 8080\n  \
+             stolen ports: 80 (header=x-user: me)\n  \
+             connections: 3 mirrored, 1 stolen (7 filtered requests), 5 outgoing\n  \
+             remote file operations: 120\n  \
+             DNS queries: 9"
+        );
+    }
+}
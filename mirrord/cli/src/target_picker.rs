@@ -0,0 +1,233 @@
+//! Interactive target picker of `mirrord exec`, shown when no target is configured and mirrord
+//! runs in a terminal.
+//!
+//! The user picks a namespace, then a workload in it (or targetless), then one of its containers,
+//! from the same listing as `mirrord ls`. The pick can be saved in the config file, so that the
+//! picker is not shown the next time.
+use std::{
+    io::{self, IsTerminal},
+    path::Path,
+};
+
+use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect};
+use mirrord_config::LayerConfig;
+use mirrord_progress::Progress;
+use serde_json::{json, Value};
+
+use crate::{
+    list::{config_kube_client, list_namespaces, list_targets},
+    Result,
+};
+
+/// The target the user picked.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PickedTarget {
+    /// Path of the target, e.g. `deployment/api/container/app`, or `targetless`.
+    pub path: String,
+
+    /// Namespace of the target, `None` when targetless.
+    pub namespace: Option<String>,
+}
+
+/// Whether the picker can be shown: the user is there to answer.
+pub(crate) fn can_pick() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Lets the user pick the namespace, workload and container. `None` when the user cancels.
+pub(crate) async fn pick_target<P>(
+    config: &LayerConfig,
+    progress: &P,
+) -> Result<Option<PickedTarget>>
+where
+    P: Progress + Send + Sync,
+{
+    let client = config_kube_client(config).await?;
+    let theme = ColorfulTheme::default();
+
+    let namespace = match &config.target.namespace {
+        Some(namespace) => namespace.clone(),
+        None => {
+            let namespaces = list_namespaces(&client).await;
+
+            // Without the permissions to list them, the namespace of the kube context is used.
+            if namespaces.len() < 2 {
+                namespaces
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| client.default_namespace().to_string())
+            } else {
+                let default = namespaces
+                    .iter()
+                    .position(|namespace| namespace == client.default_namespace())
+                    .unwrap_or_default();
+                let picked = progress.suspend(|| {
+                    FuzzySelect::with_theme(&theme)
+                        .with_prompt("Namespace")
+                        .items(&namespaces)
+                        .default(default)
+                        .interact_opt()
+                })?;
+                let Some(namespace) = picked.and_then(|index| namespaces.get(index)) else {
+                    return Ok(None);
+                };
+                namespace.clone()
+            }
+        }
+    };
+
+    // Pods with multiple containers are listed once per container, the container is picked next.
+    let mut workloads = list_targets(Some(&namespace), &client, &[]).await;
+    workloads.dedup_by(|a, b| a.kind == b.kind && a.name == b.name);
+
+    let items = ["targetless".to_string()]
+        .into_iter()
+        .chain(workloads.iter().map(|workload| {
+            format!(
+                "{}/{} ({}/{} ready)",
+                workload.kind.name(),
+                workload.name,
+                workload.ready_replicas,
+                workload.replicas
+            )
+        }))
+        .collect::<Vec<_>>();
+    let picked = progress.suspend(|| {
+        FuzzySelect::with_theme(&theme)
+            .with_prompt(format!("Target in `{namespace}`"))
+            .items(&items)
+            .default(0)
+            .interact_opt()
+    })?;
+    let workload = match picked {
+        None => return Ok(None),
+        Some(0) => {
+            return Ok(Some(PickedTarget {
+                path: "targetless".to_string(),
+                namespace: None,
+            }))
+        }
+        Some(index) => workloads.get(index - 1),
+    };
+    let Some(workload) = workload else {
+        return Ok(None);
+    };
+
+    let path = format!("{}/{}", workload.kind.name(), workload.name);
+    let path = match workload.containers.as_slice() {
+        [_, _, ..] => {
+            let picked = progress.suspend(|| {
+                FuzzySelect::with_theme(&theme)
+                    .with_prompt("Container")
+                    .items(&workload.containers)
+                    .default(0)
+                    .interact_opt()
+            })?;
+            let Some(container) = picked.and_then(|index| workload.containers.get(index)) else {
+                return Ok(None);
+            };
+            format!("{path}/container/{container}")
+        }
+        _ => path,
+    };
+
+    Ok(Some(PickedTarget {
+        path,
+        namespace: Some(namespace),
+    }))
+}
+
+/// Offers to save the `picked` target in the config file, when mirrord runs with one.
+pub(crate) fn offer_save<P>(picked: &PickedTarget, progress: &P) -> Result<()>
+where
+    P: Progress + Send + Sync,
+{
+    let Ok(config_file) = std::env::var("MIRRORD_CONFIG_FILE") else {
+        return Ok(());
+    };
+    let path = Path::new(&config_file);
+
+    let save = progress.suspend(|| {
+        Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "Save the target in `{config_file}`? Comments and formatting are not kept"
+            ))
+            .default(false)
+            .interact()
+    })?;
+
+    if save {
+        match save_target(path, picked) {
+            Ok(()) => progress.info(&format!("saved the target in `{config_file}`")),
+            Err(error) => progress.warning(&format!(
+                "failed to save the target in `{config_file}`: {error}"
+            )),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets `target` in the config file at `path`, in the format of its extension.
+fn save_target(path: &Path, picked: &PickedTarget) -> io::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+
+    let mut config = match extension {
+        Some("json") => serde_json::from_str::<Value>(&text)?,
+        Some("toml") => toml::from_str::<Value>(&text).map_err(io::Error::other)?,
+        Some("yaml" | "yml") => serde_yaml::from_str::<Value>(&text).map_err(io::Error::other)?,
+        _ => return Err(io::Error::other("unsupported config file format")),
+    };
+    set_target(&mut config, picked)?;
+
+    let text = match extension {
+        Some("toml") => toml::to_string_pretty(&config).map_err(io::Error::other)?,
+        Some("yaml" | "yml") => serde_yaml::to_string(&config).map_err(io::Error::other)?,
+        _ => serde_json::to_string_pretty(&config)?,
+    };
+
+    std::fs::write(path, text)
+}
+
+/// Replaces the `target` of the `config`.
+fn set_target(config: &mut Value, picked: &PickedTarget) -> io::Result<()> {
+    let object = config
+        .as_object_mut()
+        .ok_or_else(|| io::Error::other("the config is not an object"))?;
+
+    let target = match &picked.namespace {
+        Some(namespace) => json!({ "path": picked.path, "namespace": namespace }),
+        None => json!({ "path": picked.path }),
+    };
+    object.insert("target".to_string(), target);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_target() {
+        let mut config = json!({
+            "target": "pod/old",
+            "feature": { "network": { "incoming": "steal" } }
+        });
+        let picked = PickedTarget {
+            path: "deployment/api/container/app".to_string(),
+            namespace: Some("shop".to_string()),
+        };
+
+        set_target(&mut config, &picked).unwrap();
+
+        assert_eq!(
+            config,
+            json!({
+                "target": { "path": "deployment/api/container/app", "namespace": "shop" },
+                "feature": { "network": { "incoming": "steal" } }
+            })
+        );
+    }
+}
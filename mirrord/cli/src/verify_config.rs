@@ -3,13 +3,13 @@
 //! without having to start mirrord-layer.
 use error::Result;
 use mirrord_config::{
-    config::{ConfigContext, MirrordConfig},
+    config::{explain::unknown_keys, ConfigContext, MirrordConfig},
     feature::FeatureConfig,
     target::{DeploymentTarget, PodTarget, RolloutTarget, Target, TargetConfig},
 };
 use serde::Serialize;
 
-use crate::{config::VerifyConfigArgs, error, LayerFileConfig};
+use crate::{conditional, config::VerifyConfigArgs, error, LayerFileConfig};
 
 /// Practically the same as [`Target`], but differs in the way the `targetless` option is
 /// serialized. [`Target::Targetless`] serializes as `null`, [`VerifiedTarget::Targetless`]
@@ -110,9 +110,11 @@ enum VerifiedConfig {
 /// ## Usage
 ///
 /// ```sh
-/// mirrord verify-config [path]
+/// mirrord verify-config [--strict] [path]
 /// ```
 ///
+/// Unknown keys in the config are reported as warnings, or as errors with `--strict`.
+///
 /// - Example:
 ///
 /// ```sh
@@ -141,26 +143,47 @@ enum VerifiedConfig {
 ///   "errors": ["mirrord-config: IO operation failed with `No such file or directory (os error 2)`"]
 /// }
 /// ```
-pub(super) async fn verify_config(VerifyConfigArgs { ide, path }: VerifyConfigArgs) -> Result<()> {
+pub(super) async fn verify_config(
+    VerifyConfigArgs { ide, strict, path }: VerifyConfigArgs,
+) -> Result<()> {
     let mut config_context = ConfigContext::new(ide);
 
-    let layer_config = LayerFileConfig::from_path(path)
-        .and_then(|config| config.generate_config(&mut config_context))
-        .and_then(|config| {
-            config.verify(&mut config_context)?;
-            Ok(config)
+    // Reported with their paths and the closest known keys, unlike the errors from parsing.
+    let unknown_keys = LayerFileConfig::value_from_path(&path)
+        .map(|config| unknown_keys(&config))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|unknown| unknown.to_string())
+        .collect::<Vec<_>>();
+
+    let layer_config = conditional::resolve(&path)
+        .map_err(|fail| fail.to_string())
+        .and_then(|_| {
+            LayerFileConfig::from_path_with_warnings(&path, &mut config_context)
+                .and_then(|config| config.generate_config(&mut config_context))
+                .and_then(|config| {
+                    config.verify(&mut config_context)?;
+                    Ok(config)
+                })
+                .map_err(|fail| fail.to_string())
         });
 
     let verified = match layer_config {
+        Ok(..) if strict && !unknown_keys.is_empty() => VerifiedConfig::Fail {
+            errors: unknown_keys,
+        },
         Ok(config) => VerifiedConfig::Success {
             config: config.target.into(),
-            warnings: config_context.get_warnings().to_owned(),
+            warnings: unknown_keys
+                .into_iter()
+                .chain(config_context.get_warnings().iter().cloned())
+                .collect(),
             compatible_target_types: TargetType::all()
                 .filter(|tt| tt.compatible_with(&config.feature))
                 .collect(),
         },
         Err(fail) => VerifiedConfig::Fail {
-            errors: vec![fail.to_string()],
+            errors: unknown_keys.into_iter().chain([fail.to_string()]).collect(),
         },
     };
 
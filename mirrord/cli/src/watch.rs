@@ -0,0 +1,256 @@
+//! `mirrord exec --watch`: restarts the local process when files change, keeping the mirrord
+//! session (the internal proxy with its agent or operator connection) between the restarts.
+//!
+//! The files are polled, so that it works the same on every platform and file system. Every
+//! restarted process connects to the same internal proxy, and subscribes to its ports again. The
+//! internal proxy exits when no process is connected to it for `internal_proxy.idle_timeout`, so
+//! we stay connected to it ourselves (see [`SessionKeepalive`]) until the user stops the watch,
+//! also while no process is running.
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime},
+};
+
+use mirrord_intproxy_protocol::{
+    codec::{SyncDecoder, SyncEncoder},
+    LayerToProxyMessage, LocalMessage, NewSessionRequest, ProcessInfo, ProxyToLayerMessage,
+};
+use mirrord_progress::Progress;
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use tokio::{
+    process::{Child, Command},
+    time,
+};
+
+use crate::{CliError, Result};
+
+/// How often the watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the process has to exit after `SIGTERM`, before it's killed.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Modification times of the watched files.
+type Snapshot = HashMap<PathBuf, SystemTime>;
+
+/// Takes the modification times of the files under the `paths`, skipping hidden files and
+/// directories (e.g. `.git`) inside them. Symlinks to directories are not followed.
+fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    let mut pending = paths
+        .iter()
+        .map(|path| (path.clone(), true))
+        .collect::<Vec<_>>();
+
+    while let Some((path, top_level)) = pending.pop() {
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let is_symlink = path.is_symlink();
+
+        if metadata.is_dir() && (top_level || !is_symlink) {
+            let entries = std::fs::read_dir(&path).into_iter().flatten().flatten();
+            pending.extend(
+                entries
+                    .map(|entry| entry.path())
+                    .filter(|path| !is_hidden(path))
+                    .map(|path| (path, false)),
+            );
+        } else if let Ok(modified) = metadata.modified() {
+            snapshot.insert(path, modified);
+        }
+    }
+
+    snapshot
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Waits until the files stop changing, e.g. until a build finished writing the binary.
+async fn settle(paths: &[PathBuf], mut current: Snapshot) -> Snapshot {
+    loop {
+        time::sleep(POLL_INTERVAL).await;
+
+        let next = snapshot(paths);
+        if next == current {
+            break next;
+        }
+        current = next;
+    }
+}
+
+/// Starts the `binary`, where `binary_args` start with its `argv[0]`.
+fn spawn(binary: &str, binary_args: &[String]) -> Result<Child> {
+    let mut command = Command::new(binary);
+    if let Some((arg0, args)) = binary_args.split_first() {
+        command.arg0(arg0).args(args);
+    }
+
+    command
+        .spawn()
+        .map_err(|_| CliError::BinaryExecuteFailed(binary.to_string(), binary_args.to_vec()))
+}
+
+/// Stops the process with `SIGTERM`, killing it if it doesn't exit in [`TERMINATE_TIMEOUT`].
+async fn terminate(mut child: Child) {
+    if let Some(pid) = child.id() {
+        let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+    }
+
+    if time::timeout(TERMINATE_TIMEOUT, child.wait())
+        .await
+        .is_err()
+    {
+        let _ = child.kill().await;
+    }
+}
+
+/// Connection to the internal proxy, made like a layer would, that keeps the session alive while
+/// it's open. It doesn't make any requests.
+struct SessionKeepalive {
+    _connection: SyncDecoder<LocalMessage<ProxyToLayerMessage>, Box<dyn Read + Send>>,
+}
+
+impl SessionKeepalive {
+    /// Connects to the internal proxy at the address the layer gets, and starts a session for
+    /// the CLI itself.
+    fn connect(binary_args: &[String]) -> io::Result<Self> {
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) =
+            match std::env::var("MIRRORD_CONNECT_UNIX") {
+                Ok(path) => {
+                    let stream = UnixStream::connect(path)?;
+                    (Box::new(stream.try_clone()?), Box::new(stream))
+                }
+                Err(..) => {
+                    let address = std::env::var("MIRRORD_CONNECT_TCP").map_err(io::Error::other)?;
+                    let stream = TcpStream::connect(address)?;
+                    (Box::new(stream.try_clone()?), Box::new(stream))
+                }
+            };
+
+        let mut sender = SyncEncoder::new(writer);
+        sender
+            .send(&LocalMessage {
+                message_id: 0,
+                inner: LayerToProxyMessage::NewSession(NewSessionRequest::New(ProcessInfo {
+                    pid: std::process::id(),
+                    name: "mirrord".to_string(),
+                    cmdline: binary_args.to_vec(),
+                    loaded: false,
+                })),
+            })
+            .and_then(|()| sender.flush())
+            .map_err(io::Error::other)?;
+
+        let mut receiver = SyncDecoder::new(reader);
+        match receiver.receive().map_err(io::Error::other)? {
+            Some(LocalMessage {
+                inner: ProxyToLayerMessage::NewSession(..),
+                ..
+            }) => Ok(Self {
+                _connection: receiver,
+            }),
+            other => Err(io::Error::other(format!("unexpected response {other:?}"))),
+        }
+    }
+}
+
+/// Waits for the process to exit, forever if there is none.
+async fn wait(child: &mut Option<Child>) -> std::io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Runs the `binary`, and restarts it whenever the files under the `paths` change, until the
+/// user stops mirrord. When the process exits, it's started again on the next change.
+///
+/// The session is kept until the user stops mirrord, see [`SessionKeepalive`].
+pub(crate) async fn exec_watched<P>(
+    binary: &str,
+    binary_args: &[String],
+    paths: &[PathBuf],
+    progress: &P,
+) -> Result<()>
+where
+    P: Progress + Send + Sync,
+{
+    let paths = paths
+        .iter()
+        .map(|path| {
+            std::fs::canonicalize(path)
+                .map_err(|fail| CliError::WatchPathInvalid(path.clone(), fail))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Dropped (which closes the session) when we return.
+    let _keepalive =
+        SessionKeepalive::connect(binary_args).map_err(CliError::WatchSessionFailed)?;
+
+    let mut files = snapshot(&paths);
+    let mut child = Some(spawn(binary, binary_args)?);
+    let mut ticker = time::interval(POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            status = wait(&mut child) => {
+                child = None;
+                let status = status.map_or_else(|error| error.to_string(), |status| status.to_string());
+                progress.info(&format!("the process exited ({status}), waiting for changes to restart it"));
+            },
+
+            _ = ticker.tick() => {
+                let current = snapshot(&paths);
+                if current == files {
+                    continue;
+                }
+                files = settle(&paths, current).await;
+
+                progress.info("files changed, restarting the process");
+                if let Some(child) = child.take() {
+                    terminate(child).await;
+                }
+                child = Some(spawn(binary, binary_args)?);
+            },
+
+            _ = tokio::signal::ctrl_c() => {
+                if let Some(child) = child.take() {
+                    terminate(child).await;
+                }
+                break Ok(());
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_files() {
+        let dir = std::env::temp_dir().join(format!("mirrord-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(dir.join(".git/HEAD"), "ref: refs/heads/main").unwrap();
+
+        let files = snapshot(&[dir.clone()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.keys().collect::<Vec<_>>(), [&dir.join("src/main.rs")]);
+    }
+}
@@ -1,6 +1,9 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
-use k8s_openapi::api::core::v1::{ResourceRequirements, Toleration};
+use k8s_openapi::api::core::v1::{Affinity, ResourceRequirements, Toleration};
 use mirrord_analytics::CollectAnalytics;
 use mirrord_config_derive::MirrordConfig;
 use schemars::JsonSchema;
@@ -32,6 +35,17 @@ impl LinuxCapability {
     }
 }
 
+/// How the mirrord CLI connects to the agent, see [`AgentConfig::connection`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, JsonSchema, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentConnection {
+    /// Port-forward to the port the agent listens on.
+    #[default]
+    PortForward,
+    /// Exec into the agent container, and bridge its stdio to the port the agent listens on.
+    Exec,
+}
+
 /// Configuration for the mirrord-agent pod that is spawned in the Kubernetes cluster.
 ///
 /// We provide sane defaults for this option, so you don't have to set up anything here.
@@ -138,6 +152,25 @@ pub struct AgentConfig {
     #[config(env = "MIRRORD_EPHEMERAL_CONTAINER", default = false)]
     pub ephemeral: bool,
 
+    /// ### agent.sidecar {#agent-sidecar}
+    ///
+    /// Runs the agent without privileges, in a copy of the target pod (with the target's volumes,
+    /// environment and ports) instead of next to the target. For nodes where privileged agents
+    /// can't run, like virtual kubelets or EKS Fargate.
+    ///
+    /// The agent in the copy is a reverse proxy in front of the target: the copy gets the target's
+    /// labels, so that Kubernetes sends it part of the target's traffic, and the agent forwards
+    /// the connections and the unmatched HTTP requests that it doesn't steal to the target. When
+    /// the target pod has no IP yet, the copy doesn't get the target's labels.
+    ///
+    /// Only some features work this way: environment, files from the target's volumes, outgoing
+    /// traffic, and stealing incoming traffic that Kubernetes sends to the copy. Mirroring
+    /// traffic and pausing the target are not available.
+    ///
+    /// Defaults to running this way only when the target is on a virtual node.
+    #[config(env = "MIRRORD_AGENT_SIDECAR")]
+    pub sidecar: Option<bool>,
+
     /// ### agent.communication_timeout {#agent-communication_timeout}
     ///
     /// Controls how long the agent lives when there are no connections.
@@ -147,9 +180,31 @@ pub struct AgentConfig {
     #[config(env = "MIRRORD_AGENT_COMMUNICATION_TIMEOUT")]
     pub communication_timeout: Option<u16>,
 
+    /// ### agent.pause_timeout {#agent-pause_timeout}
+    ///
+    /// Hard limit (in seconds) on how long the target container stays paused with
+    /// [`pause`](#root-pause).
+    ///
+    /// The agent unpauses the target container when this elapses, or when the client that
+    /// paused it disconnects, whichever comes first. Set to `0` to only unpause on disconnect.
+    ///
+    /// Defaults to `1800` (30 minutes).
+    #[config(env = "MIRRORD_AGENT_PAUSE_TIMEOUT")]
+    pub pause_timeout: Option<u64>,
+
+    /// ### agent.kube_context {#agent-kube_context}
+    ///
+    /// Kube context of the cluster the agent (or the mirrord operator) runs in, when it's not the
+    /// one the target is in, see [`target.kube_context`](#target-kube_context).
+    ///
+    /// Defaults to [`kube_context`](#root-kube_context).
+    #[config(env = "MIRRORD_AGENT_KUBE_CONTEXT")]
+    pub kube_context: Option<String>,
+
     /// ### agent.startup_timeout {#agent-startup_timeout}
     ///
-    /// Controls how long to wait for the agent to finish initialization.
+    /// Controls how long (in seconds) to wait for the agent to finish initialization, and for
+    /// its pod (or ephemeral container) to start running before that.
     ///
     /// If initialization takes longer than this value, mirrord exits.
     ///
@@ -157,6 +212,27 @@ pub struct AgentConfig {
     #[config(env = "MIRRORD_AGENT_STARTUP_TIMEOUT", default = 60)]
     pub startup_timeout: u64,
 
+    /// ### agent.kube_api_retries {#agent-kube_api_retries}
+    ///
+    /// How many times to retry a Kubernetes API call made while creating the agent (resolving
+    /// the target, creating the agent, waiting for it) when the API server is busy or
+    /// unavailable (`429`, `502`, `503` and `504` responses), or the call times out (see
+    /// [`agent.kube_api_timeout`](#agent-kube_api_timeout)). Retries wait with an exponential
+    /// backoff.
+    ///
+    /// Defaults to `5`.
+    #[config(env = "MIRRORD_AGENT_KUBE_API_RETRIES", default = 5)]
+    pub kube_api_retries: u32,
+
+    /// ### agent.kube_api_timeout {#agent-kube_api_timeout}
+    ///
+    /// How long (in seconds) a single Kubernetes API call made while creating the agent can take
+    /// before it's retried, see [`agent.kube_api_retries`](#agent-kube_api_retries).
+    ///
+    /// Defaults to `30`.
+    #[config(env = "MIRRORD_AGENT_KUBE_API_TIMEOUT", default = 30)]
+    pub kube_api_timeout: u64,
+
     /// ### agent.network_interface {#agent-network_interface}
     ///
     /// Which network interface to use for mirroring.
@@ -202,6 +278,66 @@ pub struct AgentConfig {
     /// Set to an empty array to have no tolerations at all
     pub tolerations: Option<Vec<Toleration>>,
 
+    /// ### agent.node_selector {#agent-node_selector}
+    ///
+    /// Set the pod's `nodeSelector`. (only with targetless agents, targeted agents always run on
+    /// the target's node)
+    ///
+    /// ```json
+    /// {
+    ///   "agent": {
+    ///     "node_selector": {
+    ///       "kubernetes.io/os": "linux"
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub node_selector: Option<BTreeMap<String, String>>,
+
+    /// ### agent.affinity {#agent-affinity}
+    ///
+    /// Set the pod's `affinity`. (only with targetless agents, targeted agents always run on the
+    /// target's node)
+    pub affinity: Option<Affinity>,
+
+    /// ### agent.priority_class_name {#agent-priority_class_name}
+    ///
+    /// Set the pod's `priorityClassName`, e.g. to keep the agent from being evicted before the
+    /// session ends. (not with ephemeral agents)
+    pub priority_class_name: Option<String>,
+
+    /// ### agent.pod_template_patch {#agent-pod_template_patch}
+    ///
+    /// Patch applied to the agent pod (the template of its job) after everything mirrord sets,
+    /// for cluster-specific requirements like extra labels, annotations, containers, volumes or
+    /// security context tweaks. (not with ephemeral agents)
+    ///
+    /// Objects are merged, and lists of named items (`containers`, `volumes`, `env`...) are merged
+    /// by name, other lists (e.g. `args`) are replaced. This is not a full strategic merge patch:
+    /// fields can't be removed (`null` leaves them as they are), and directives like
+    /// `$patch: delete` are rejected.
+    ///
+    /// ```json
+    /// {
+    ///   "agent": {
+    ///     "pod_template_patch": {
+    ///       "metadata": {
+    ///         "annotations": { "cluster-autoscaler.kubernetes.io/safe-to-evict": "false" }
+    ///       },
+    ///       "spec": {
+    ///         "containers": [
+    ///           {
+    ///             "name": "mirrord-agent",
+    ///             "securityContext": { "seccompProfile": { "type": "RuntimeDefault" } }
+    ///           }
+    ///         ]
+    ///       }
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub pod_template_patch: Option<serde_json::Value>,
+
     /// ### agent.resources {#agent-resources}
     ///
     /// Set pod resource reqirements. (not with ephemeral agents)
@@ -241,6 +377,38 @@ pub struct AgentConfig {
     #[config(default = false)]
     pub privileged: bool,
 
+    /// ### agent.remote_exec {#agent-remote_exec}
+    ///
+    /// Allow the agent to run processes in the target container, for
+    /// [`feature.remote_exec`](#feature-remote_exec). The processes run with the user,
+    /// capabilities and namespaces of the target.
+    ///
+    /// Defaults to `false`. Anyone who can connect to an agent with this enabled can run commands
+    /// in the target container.
+    #[config(env = "MIRRORD_AGENT_REMOTE_EXEC", default = false)]
+    pub remote_exec: bool,
+
+    /// ### agent.openshift {#agent-openshift}
+    ///
+    /// Create the agent with a spec that OpenShift's security context constraints (SCCs) accept:
+    /// all capabilities dropped except the ones the agent needs, the `RuntimeDefault` seccomp
+    /// profile, and no fixed user. Targetless agents pass the `restricted` SCCs, targeted agents
+    /// still need an SCC that allows host PID, host paths and their capabilities.
+    ///
+    /// Defaults to detecting OpenShift in the cluster.
+    #[config(env = "MIRRORD_AGENT_OPENSHIFT")]
+    pub openshift: Option<bool>,
+
+    /// ### agent.openshift_uid_range {#agent-openshift_uid_range}
+    ///
+    /// With [`agent.openshift`](#agent-openshift), run targetless agents as the first user of the
+    /// agent namespace's allowed UID range (the `openshift.io/sa.scc.uid-range` annotation),
+    /// instead of leaving it to SCC admission.
+    ///
+    /// Defaults to `false`.
+    #[config(default = false)]
+    pub openshift_uid_range: bool,
+
     /// ### agent.nftables {#agent-nftables}
     ///
     /// Use iptables-nft instead of iptables-legacy.
@@ -254,6 +422,27 @@ pub struct AgentConfig {
     #[config(nested)]
     pub dns: AgentDnsConfig,
 
+    /// ### agent.connection {#agent-connection}
+    ///
+    /// How to connect to the agent:
+    ///
+    /// - `"port_forward"` forwards a random high port to the agent, which some clusters block
+    ///   (e.g. with `NetworkPolicy`s, or API server proxies that don't allow port-forwarding).
+    /// - `"exec"` runs a small bridge in the agent container with `exec`, and talks to the agent
+    ///   over the bridge's stdio. It only needs the `pods/exec` permission.
+    ///
+    /// Defaults to `"port_forward"`.
+    ///
+    /// ```json
+    /// {
+    ///   "agent": {
+    ///     "connection": "exec"
+    ///   }
+    /// }
+    /// ```
+    #[config(default)]
+    pub connection: AgentConnection,
+
     /// <!--${internal}-->
     /// Create an agent that returns an error after accepting the first client. For testing
     /// purposes. Only supported with job agents (not with ephemeral agents).
@@ -414,10 +603,27 @@ pub struct AgentDnsConfig {
     pub attempts: Option<u32>,
 }
 
+/// The first strategic merge patch directive (e.g. `$patch`, `$retainKeys`) in the
+/// [`AgentConfig::pod_template_patch`], which we merge without them.
+pub(crate) fn patch_directive(patch: &serde_json::Value) -> Option<&str> {
+    match patch {
+        serde_json::Value::Object(object) => object.iter().find_map(|(key, value)| {
+            if key.starts_with('$') {
+                Some(key.as_str())
+            } else {
+                patch_directive(value)
+            }
+        }),
+        serde_json::Value::Array(array) => array.iter().find_map(patch_directive),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::too_many_arguments)]
 mod tests {
     use rstest::rstest;
+    use serde_json::json;
 
     use super::*;
     use crate::{
@@ -479,4 +685,15 @@ mod tests {
             },
         );
     }
+
+    #[rstest]
+    #[case::plain(json!({ "spec": { "containers": [{ "name": "agent" }] } }), None)]
+    #[case::delete(
+        json!({ "spec": { "containers": [{ "name": "agent", "$patch": "delete" }] } }),
+        Some("$patch")
+    )]
+    #[case::retain_keys(json!({ "spec": { "$retainKeys": ["volumes"] } }), Some("$retainKeys"))]
+    fn finds_patch_directives(#[case] patch: serde_json::Value, #[case] directive: Option<&str>) {
+        assert_eq!(patch_directive(&patch), directive);
+    }
 }
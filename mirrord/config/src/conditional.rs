@@ -0,0 +1,218 @@
+//! Config sections that apply only to some namespaces, clusters or targets, see
+//! [`LayerConfig::conditional`](crate::LayerConfig::conditional).
+//!
+//! The conditions are checked while the config file is loaded, once all of its layers (the files
+//! it extends, the target overrides and the profile) are merged. The kube context, its cluster and
+//! the target namespace need the kubeconfig, so the mirrord CLI resolves them before the session
+//! starts and passes them on in [`ENVIRONMENT_ENV`], to itself, the internal proxy and the layer.
+//! A config with conditional sections fails to load without it, so that the sections can't be
+//! skipped by accident.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{ConfigContext, ConfigError, Result};
+
+/// Env var with the [`Environment`] resolved by the CLI, as JSON.
+pub const ENVIRONMENT_ENV: &str = "MIRRORD_CONDITIONAL_ENVIRONMENT";
+
+/// The `when` of a conditional section. Every condition that is set must match, patterns can use
+/// `*` to match any sequence of characters.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Condition {
+    /// Namespace of the target.
+    namespace: Option<String>,
+    /// Name of the kube context.
+    context: Option<String>,
+    /// Name of the cluster of the kube context, as in the kubeconfig file.
+    cluster: Option<String>,
+    /// Path of the target, e.g. `deployment/payments`.
+    target: Option<String>,
+}
+
+/// The kube side of what the [`Condition`]s are checked against, resolved by the CLI.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Environment {
+    /// Namespace of the target.
+    pub namespace: String,
+    /// Name of the kube context of the target.
+    pub context: String,
+    /// Name of the cluster of the kube context.
+    pub cluster: String,
+}
+
+impl Environment {
+    /// Reads the environment from [`ENVIRONMENT_ENV`].
+    pub fn from_env() -> Result<Self> {
+        let environment = std::env::var(ENVIRONMENT_ENV)
+            .map_err(|_| ConfigError::ConditionalEnvironmentMissing)?;
+
+        Ok(serde_json::from_str(&environment)?)
+    }
+
+    /// Sets [`ENVIRONMENT_ENV`] for this process and its children, returns the value it's set to.
+    pub fn set_env(&self) -> Result<String> {
+        let environment = serde_json::to_string(self)?;
+        std::env::set_var(ENVIRONMENT_ENV, &environment);
+        Ok(environment)
+    }
+}
+
+/// Whether the `config` (a config file as JSON) has any conditional sections.
+pub fn has_sections(config: &Value) -> bool {
+    config
+        .get("conditional")
+        .and_then(Value::as_array)
+        .is_some_and(|sections| !sections.is_empty())
+}
+
+impl Condition {
+    fn matches(&self, environment: &Environment, target: Option<&str>) -> bool {
+        let matches = |pattern: &Option<String>, value: Option<&str>| match pattern {
+            Some(pattern) => value.is_some_and(|value| crate::target_matches(pattern, value)),
+            None => true,
+        };
+
+        matches(&self.namespace, Some(&environment.namespace))
+            && matches(&self.context, Some(&environment.context))
+            && matches(&self.cluster, Some(&environment.cluster))
+            && matches(&self.target, target)
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let conditions = [
+            ("namespace", &self.namespace),
+            ("context", &self.context),
+            ("cluster", &self.cluster),
+            ("target", &self.target),
+        ]
+        .into_iter()
+        .filter_map(|(name, pattern)| Some(format!("{name} `{}`", pattern.as_ref()?)))
+        .collect::<Vec<_>>();
+
+        if conditions.is_empty() {
+            f.write_str("every session")
+        } else {
+            f.write_str(&conditions.join(", "))
+        }
+    }
+}
+
+/// Takes the sections from the `conditional` of `config` that match the `environment` and the
+/// `target` mirrord runs with, in order, without their `when`. Adds a warning to `context` for each
+/// one, so that the user knows where the settings come from.
+pub(crate) fn matching_sections(
+    config: &Value,
+    environment: &Environment,
+    target: Option<&str>,
+    context: &mut ConfigContext,
+) -> Result<Vec<Value>> {
+    let Some(sections) = config.get("conditional").and_then(Value::as_array) else {
+        return Ok(Vec::new());
+    };
+
+    let mut matching = Vec::new();
+    for section in sections {
+        let mut section = section.clone();
+        let when = section
+            .as_object_mut()
+            .and_then(|section| section.remove("when"))
+            .unwrap_or_default();
+        let condition = match when {
+            Value::Null => Condition::default(),
+            when => serde_json::from_value::<Condition>(when)?,
+        };
+
+        if condition.matches(environment, target) {
+            context.add_warning(format!(
+                "Applying the conditional config section for {condition}."
+            ));
+            matching.push(section);
+        }
+    }
+
+    Ok(matching)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::util::testing::with_env_vars;
+
+    #[test]
+    fn matches_sections() {
+        let config = json!({
+            "conditional": [
+                { "when": { "namespace": "prod-*" }, "feature": { "fs": "read" } },
+                {
+                    "when": { "namespace": "prod-*", "cluster": "eu-*" },
+                    "feature": { "copy_target": true }
+                },
+                { "when": { "target": "deployment/payments*" }, "feature": { "env": false } }
+            ]
+        });
+        let environment = Environment {
+            namespace: "prod-payments".to_string(),
+            cluster: "us-east".to_string(),
+            context: "us-east".to_string(),
+        };
+
+        let mut context = ConfigContext::default();
+        let sections = matching_sections(
+            &config,
+            &environment,
+            Some("deployment/payments"),
+            &mut context,
+        )
+        .unwrap();
+
+        assert_eq!(
+            sections,
+            [
+                json!({ "feature": { "fs": "read" } }),
+                json!({ "feature": { "env": false } })
+            ]
+        );
+        assert_eq!(context.get_warnings().len(), 2);
+    }
+
+    #[test]
+    fn rejects_unknown_conditions() {
+        let config = json!({
+            "conditional": [{ "when": { "namspace": "prod-*" }, "feature": { "fs": "read" } }]
+        });
+
+        let result = matching_sections(
+            &config,
+            &Environment::default(),
+            None,
+            &mut ConfigContext::default(),
+        );
+        assert!(result.is_err());
+    }
+
+    /// Without the environment from the CLI, the sections are not skipped silently.
+    #[test]
+    fn requires_environment() {
+        with_env_vars(vec![(ENVIRONMENT_ENV, None)], || {
+            assert!(matches!(
+                Environment::from_env(),
+                Err(ConfigError::ConditionalEnvironmentMissing)
+            ));
+        });
+
+        let environment = Environment {
+            namespace: "prod".to_string(),
+            context: "eu".to_string(),
+            cluster: "eu-prod".to_string(),
+        };
+        let json = serde_json::to_string(&environment).unwrap();
+        with_env_vars(vec![(ENVIRONMENT_ENV, Some(&json))], || {
+            assert_eq!(Environment::from_env().unwrap(), environment);
+        });
+    }
+}
@@ -1,8 +1,12 @@
 pub mod deprecated;
+pub mod explain;
 pub mod from_env;
+pub mod migrate;
 pub mod source;
 pub mod unstable;
 
+use std::path::PathBuf;
+
 use thiserror::Error;
 
 /// <!--${internal}-->
@@ -49,6 +53,31 @@ pub enum ConfigError {
     )]
     TargetNamespaceWithoutTarget,
 
+    #[error("Profile `{0}` not found in the config file, available profiles: {1:?}.")]
+    ProfileNotFound(String, Vec<String>),
+
+    #[error("Failed to load `{0}`, extended by the config file: {1}")]
+    Extends(PathBuf, Box<ConfigError>),
+
+    #[error("Config files extend each other in a cycle: {0:?}.")]
+    ExtendsCycle(Vec<PathBuf>),
+
+    #[error(
+        "The config file has `conditional` sections, but they were not resolved by the mirrord \
+        CLI (`MIRRORD_CONDITIONAL_ENVIRONMENT` is not set). Please run mirrord with the CLI or an \
+        IDE extension."
+    )]
+    ConditionalEnvironmentMissing,
+
+    #[error("mirrord-config: `{0}`!")]
+    TomlSerialize(#[from] toml::ser::Error),
+
+    #[error(
+        "Config files with templates can't be migrated automatically, please apply the changes \
+        from the deprecation warnings by hand."
+    )]
+    MigrateTemplate,
+
     #[error("Template rendering failed, check your config file `{0}`.")]
     TemplateRenderingFailed(#[from] tera::Error),
 }
@@ -67,6 +96,9 @@ pub struct ConfigContext {
     ///
     /// Some _target_ related errors become warning when `ide == true`.
     warnings: Vec<String>,
+
+    /// Deprecated keys and values found in the config file, see [`migrate`].
+    deprecations: Vec<migrate::Deprecation>,
 }
 
 impl ConfigContext {
@@ -0,0 +1,468 @@
+//! <!--${internal}-->
+//! Config key lookup, backing the `mirrord config explain`, `mirrord completions --config-keys`
+//! and `mirrord verify-config --strict` commands.
+//!
+//! Everything here is read from the JSON schema of [`LayerFileConfig`], which is generated from
+//! the `MirrordConfig` derive (doc comments included), so it never drifts from the actual config.
+use std::{collections::HashSet, fmt};
+
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+
+use crate::LayerFileConfig;
+
+/// Maximum depth we descend into the schema when listing keys, guards against recursive
+/// definitions.
+const MAX_KEY_DEPTH: usize = 16;
+
+/// Everything we know about a single config key, e.g. `feature.network.incoming.http_filter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigKeyInfo {
+    /// Full dotted path of the key.
+    pub key: String,
+
+    /// Doc comment of the field, or of its type when the field has none.
+    pub description: Option<String>,
+
+    /// Human readable names of the types accepted by this key.
+    pub types: Vec<String>,
+
+    /// Default value, taken from the schema or from the `Defaults to ...` doc sentence.
+    pub default: Option<String>,
+
+    /// First `json` example found in the doc comment.
+    pub example: Option<String>,
+}
+
+/// A key set in a config file that is not in the config schema, see [`unknown_keys`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownKey {
+    /// Full dotted path of the key, e.g. `feature.network.incoming.http_filtr`.
+    pub key: String,
+
+    /// Full dotted path of the closest known key, when there is one close enough.
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown config key `{}`", self.key)?;
+
+        match &self.suggestion {
+            Some(suggestion) => write!(f, ", did you mean `{suggestion}`?"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the dotted paths of every key that can be set in the config file, sorted.
+pub fn config_keys() -> Vec<String> {
+    let root = schemars::schema_for!(LayerFileConfig);
+
+    let mut keys = Vec::new();
+    collect_keys(&root, &root.schema, None, 0, &mut keys);
+
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Looks up `key` (e.g. `feature.network.incoming.http_filter`) in the config schema.
+///
+/// Returns [`None`] when there is no such key.
+pub fn explain_key(key: &str) -> Option<ConfigKeyInfo> {
+    let root = schemars::schema_for!(LayerFileConfig);
+
+    let mut current = &root.schema;
+    let mut field = None;
+
+    for segment in key.split('.') {
+        let next = properties(&root, current)
+            .into_iter()
+            .find_map(|(name, schema)| (name == segment).then_some(schema))?;
+
+        field = Some(next);
+        current = next;
+    }
+
+    let field = field?;
+    let own_description = field
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.description.clone());
+    let type_description = type_description(&root, field);
+    let docs = || own_description.iter().chain(type_description.iter());
+
+    let default = field
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.default.as_ref())
+        .map(ToString::to_string)
+        .or_else(|| docs().find_map(|doc| default_from_doc(doc)));
+
+    let example = docs().find_map(|doc| example_from_doc(doc));
+
+    let mut types = Vec::new();
+    type_names(&root, field, &mut types);
+
+    Some(ConfigKeyInfo {
+        key: key.to_string(),
+        description: own_description.or(type_description),
+        types,
+        default,
+        example,
+    })
+}
+
+/// Finds the keys set in `config` (the config file as JSON) that are not in the config schema.
+///
+/// The entries of `profiles` and `targets` are checked like the root of the config. Maps (like
+/// `agent.node_selector`) and arrays are not checked.
+pub fn unknown_keys(config: &serde_json::Value) -> Vec<UnknownKey> {
+    let root = schemars::schema_for!(LayerFileConfig);
+
+    let mut unknown = Vec::new();
+    find_unknown_keys(&root, &root.schema, config, None, 0, &mut unknown);
+
+    unknown
+}
+
+fn find_unknown_keys(
+    root: &RootSchema,
+    schema: &SchemaObject,
+    value: &serde_json::Value,
+    prefix: Option<&str>,
+    depth: usize,
+    unknown: &mut Vec<UnknownKey>,
+) {
+    let Some(object) = value.as_object() else {
+        return;
+    };
+    if depth >= MAX_KEY_DEPTH {
+        return;
+    }
+
+    let known = properties(root, schema);
+    if known.is_empty() {
+        return;
+    }
+
+    let full_key = |name: &str| match prefix {
+        Some(prefix) => format!("{prefix}.{name}"),
+        None => name.to_string(),
+    };
+
+    for (name, value) in object {
+        let key = full_key(name);
+
+        match known.iter().find(|(known, _)| *known == name.as_str()) {
+            Some(_) if depth == 0 && matches!(name.as_str(), "profiles" | "targets") => {
+                for (entry, overrides) in value.as_object().into_iter().flatten() {
+                    let entry = format!("{key}.{entry}");
+                    find_unknown_keys(
+                        root,
+                        &root.schema,
+                        overrides,
+                        Some(&entry),
+                        depth + 1,
+                        unknown,
+                    );
+                }
+            }
+            Some(_) if depth == 0 && name == "conditional" => {
+                for (index, section) in value.as_array().into_iter().flatten().enumerate() {
+                    // Checked when the config is loaded.
+                    let mut section = section.clone();
+                    if let Some(section) = section.as_object_mut() {
+                        section.remove("when");
+                    }
+
+                    let entry = format!("{key}.{index}");
+                    find_unknown_keys(
+                        root,
+                        &root.schema,
+                        &section,
+                        Some(&entry),
+                        depth + 1,
+                        unknown,
+                    );
+                }
+            }
+            Some((_, property)) => {
+                find_unknown_keys(root, property, value, Some(&key), depth + 1, unknown)
+            }
+            None => {
+                let suggestion = known
+                    .iter()
+                    .map(|(known, _)| (edit_distance(name, known), *known))
+                    .min()
+                    .filter(|(distance, known)| {
+                        *distance <= (name.len().max(known.len()) / 3).max(1)
+                    })
+                    .map(|(_, known)| full_key(known));
+
+                unknown.push(UnknownKey { key, suggestion });
+            }
+        }
+    }
+}
+
+/// Levenshtein distance between `a` and `b`, to suggest the key the user meant.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let mut previous = (0..=b.chars().count()).collect::<Vec<_>>();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, b) in b.chars().enumerate() {
+            let substitution = previous.get(j).copied().unwrap_or_default() + usize::from(a != b);
+            let insertion = current.get(j).copied().unwrap_or_default() + 1;
+            let deletion = previous.get(j + 1).copied().unwrap_or_default() + 1;
+
+            current.push(substitution.min(insertion).min(deletion));
+        }
+
+        previous = current;
+    }
+
+    previous.last().copied().unwrap_or_default()
+}
+
+/// Follows a `$ref` into the schema definitions, if there is one.
+fn resolve<'a>(root: &'a RootSchema, schema: &'a SchemaObject) -> &'a SchemaObject {
+    schema
+        .reference
+        .as_deref()
+        .and_then(|reference| reference.strip_prefix("#/definitions/"))
+        .and_then(|name| root.definitions.get(name))
+        .and_then(|schema| match schema {
+            Schema::Object(object) => Some(object),
+            Schema::Bool(_) => None,
+        })
+        .unwrap_or(schema)
+}
+
+/// Subschemas of `schema` that are alternatives or parts of it (`anyOf`, `oneOf`, `allOf`).
+fn subschemas(schema: &SchemaObject) -> impl Iterator<Item = &SchemaObject> {
+    schema
+        .subschemas
+        .iter()
+        .flat_map(|subschemas| {
+            [
+                subschemas.all_of.as_ref(),
+                subschemas.any_of.as_ref(),
+                subschemas.one_of.as_ref(),
+            ]
+        })
+        .flatten()
+        .flatten()
+        .filter_map(|schema| match schema {
+            Schema::Object(object) => Some(object),
+            Schema::Bool(_) => None,
+        })
+}
+
+/// Object properties reachable from `schema`, looking through references and subschemas, so
+/// that both `Option<T>` and toggleable (`bool` or `T`) fields expose the properties of `T`.
+fn properties<'a>(
+    root: &'a RootSchema,
+    schema: &'a SchemaObject,
+) -> Vec<(&'a str, &'a SchemaObject)> {
+    let schema = resolve(root, schema);
+
+    let own = schema
+        .object
+        .iter()
+        .flat_map(|object| object.properties.iter())
+        .filter_map(|(name, schema)| match schema {
+            Schema::Object(object) => Some((name.as_str(), object)),
+            Schema::Bool(_) => None,
+        });
+
+    own.chain(
+        subschemas(schema)
+            .flat_map(|subschema| properties(root, subschema))
+            .collect::<Vec<_>>(),
+    )
+    .collect()
+}
+
+fn collect_keys(
+    root: &RootSchema,
+    schema: &SchemaObject,
+    prefix: Option<&str>,
+    depth: usize,
+    keys: &mut Vec<String>,
+) {
+    if depth >= MAX_KEY_DEPTH {
+        return;
+    }
+
+    for (name, property) in properties(root, schema) {
+        let key = match prefix {
+            Some(prefix) => format!("{prefix}.{name}"),
+            None => name.to_string(),
+        };
+
+        collect_keys(root, property, Some(&key), depth + 1, keys);
+        keys.push(key);
+    }
+}
+
+/// Description of the type behind `schema`, looking through references and subschemas.
+fn type_description(root: &RootSchema, schema: &SchemaObject) -> Option<String> {
+    let resolved = resolve(root, schema);
+
+    if !std::ptr::eq(resolved, schema) {
+        resolved
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.description.clone())
+            .or_else(|| type_description(root, resolved))
+    } else {
+        subschemas(schema).find_map(|subschema| type_description(root, subschema))
+    }
+}
+
+fn type_names(root: &RootSchema, schema: &SchemaObject, names: &mut Vec<String>) {
+    let mut push = |name: String| {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    };
+
+    if let Some(name) = schema
+        .reference
+        .as_deref()
+        .and_then(|reference| reference.strip_prefix("#/definitions/"))
+    {
+        let resolved = resolve(root, schema);
+
+        // Plain enums and unions (like `ToggleableConfig`) are more useful to the user as the
+        // list of what they accept.
+        if resolved.enum_values.is_some() || resolved.subschemas.is_some() {
+            return type_names(root, resolved, names);
+        }
+
+        push(name.to_string());
+        return;
+    }
+
+    if let Some(values) = &schema.enum_values {
+        values.iter().map(ToString::to_string).for_each(&mut push);
+    } else if let Some(instance_type) = &schema.instance_type {
+        let instance_types = match instance_type {
+            SingleOrVec::Single(single) => vec![**single],
+            SingleOrVec::Vec(many) => many.clone(),
+        };
+
+        instance_types
+            .into_iter()
+            .map(|instance_type| match instance_type {
+                InstanceType::Null => "null",
+                InstanceType::Boolean => "boolean",
+                InstanceType::Object => "object",
+                InstanceType::Array => "array",
+                InstanceType::Number => "number",
+                InstanceType::String => "string",
+                InstanceType::Integer => "integer",
+            })
+            .map(ToString::to_string)
+            .for_each(&mut push);
+    }
+
+    let mut seen = names.iter().cloned().collect::<HashSet<_>>();
+    for subschema in subschemas(schema) {
+        let mut inner = Vec::new();
+        type_names(root, subschema, &mut inner);
+        names.extend(inner.into_iter().filter(|name| seen.insert(name.clone())));
+    }
+}
+
+/// Picks the value out of a `Defaults to ...` sentence in the doc comment.
+fn default_from_doc(doc: &str) -> Option<String> {
+    let (_, rest) = doc.split_once("Defaults to ")?;
+    let line = rest.lines().next()?.trim();
+    let value = line.strip_suffix('.').unwrap_or(line);
+
+    Some(value.trim_matches('`').to_string())
+}
+
+/// First ```` ```json ```` block in the doc comment.
+fn example_from_doc(doc: &str) -> Option<String> {
+    let (_, rest) = doc.split_once("```json")?;
+    let (example, _) = rest.split_once("```")?;
+
+    Some(example.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_nested_keys() {
+        let keys = config_keys();
+
+        assert!(keys.contains(&"target".to_string()));
+        assert!(keys.contains(&"feature.network.incoming.http_filter".to_string()));
+        assert!(keys.contains(&"feature.network.incoming.http_filter.header_filter".to_string()));
+    }
+
+    #[test]
+    fn explains_http_filter() {
+        let info = explain_key("feature.network.incoming.http_filter").unwrap();
+
+        assert!(info
+            .description
+            .unwrap()
+            .starts_with("Sets up the HTTP traffic filter"));
+        assert!(info.example.unwrap().contains("header_filter"));
+    }
+
+    #[test]
+    fn finds_unknown_keys() {
+        let config = serde_json::json!({
+            "target": "pod/bear-pod",
+            "feature": {
+                "network": {
+                    "incoming": {
+                        "mode": "steal",
+                        "http_filtr": { "header_filter": "x-user: bear" }
+                    }
+                }
+            },
+            "agent": { "node_selector": { "any-label": "any-value" } },
+            "profiles": {
+                "offline": { "featur": { "fs": "local" } }
+            },
+            "bear": true
+        });
+
+        let mut unknown = unknown_keys(&config);
+        unknown.sort_by(|a, b| a.key.cmp(&b.key));
+
+        assert_eq!(
+            unknown,
+            [
+                UnknownKey {
+                    key: "bear".to_string(),
+                    suggestion: None,
+                },
+                UnknownKey {
+                    key: "feature.network.incoming.http_filtr".to_string(),
+                    suggestion: Some("feature.network.incoming.http_filter".to_string()),
+                },
+                UnknownKey {
+                    key: "profiles.offline.featur".to_string(),
+                    suggestion: Some("profiles.offline.feature".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_key() {
+        assert!(explain_key("feature.network.nope").is_none());
+        assert!(explain_key("").is_none());
+    }
+}
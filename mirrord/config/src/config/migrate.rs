@@ -0,0 +1,321 @@
+//! Migrations of config files written for older versions of mirrord: keys that were moved, and
+//! values that were replaced.
+//!
+//! The migrations are applied whenever a config file is loaded, adding a [`Deprecation`] warning
+//! for each one (see [`ConfigContext::add_deprecation`]), and `mirrord config migrate` uses them to
+//! rewrite the file.
+//!
+//! To deprecate a key or a value, add a [`Migration`] to [`MIGRATIONS`].
+use std::{fmt, path::Path};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    config::{ConfigContext, ConfigError, Result},
+    LayerFileConfig,
+};
+
+/// A deprecated key or value found in a config file, and how it was migrated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Deprecation {
+    /// Dotted path of the deprecated key, e.g. `feature.network.incoming.http_header_filter`.
+    pub key: String,
+
+    /// Dotted path of the key that replaces it, when it was moved.
+    pub replacement: Option<String>,
+
+    /// What changed.
+    pub message: String,
+}
+
+impl fmt::Display for Deprecation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is deprecated: {}", self.key, self.message)?;
+
+        match &self.replacement {
+            Some(replacement) => write!(f, " Use `{replacement}` instead."),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A change of the config schema that old config files are migrated through.
+#[derive(Debug)]
+enum Migration {
+    /// The key was moved to another path.
+    Moved {
+        from: &'static str,
+        to: &'static str,
+    },
+
+    /// A value of the key was replaced.
+    Replaced {
+        key: &'static str,
+        from: &'static str,
+        to: &'static str,
+    },
+}
+
+/// Every migration, applied in order.
+const MIGRATIONS: &[Migration] = &[
+    Migration::Moved {
+        from: "feature.network.incoming.http_header_filter",
+        to: "feature.network.incoming.http_filter.header_filter",
+    },
+    Migration::Replaced {
+        key: "feature.fs",
+        from: "disabled",
+        to: "local",
+    },
+    Migration::Replaced {
+        key: "feature.fs.mode",
+        from: "disabled",
+        to: "local",
+    },
+];
+
+/// Applies the [`MIGRATIONS`] to `config` (a config file as JSON), including the entries of
+/// its `profiles`, `targets` and `conditional`. Returns what was migrated.
+pub fn migrate(config: &mut Value) -> Vec<Deprecation> {
+    let mut deprecations = migrate_at(config, None);
+
+    for section in ["profiles", "targets"] {
+        let Some(entries) = config.get_mut(section).and_then(Value::as_object_mut) else {
+            continue;
+        };
+
+        for (name, entry) in entries {
+            let prefix = format!("{section}.{name}");
+            deprecations.extend(migrate_at(entry, Some(&prefix)));
+        }
+    }
+
+    let sections = config
+        .get_mut("conditional")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+    for (index, section) in sections.enumerate() {
+        let prefix = format!("conditional.{index}");
+        deprecations.extend(migrate_at(section, Some(&prefix)));
+    }
+
+    deprecations
+}
+
+fn migrate_at(config: &mut Value, prefix: Option<&str>) -> Vec<Deprecation> {
+    let full_key = |key: &str| match prefix {
+        Some(prefix) => format!("{prefix}.{key}"),
+        None => key.to_string(),
+    };
+
+    let mut deprecations = Vec::new();
+
+    for migration in MIGRATIONS {
+        match migration {
+            Migration::Moved { from, to } => {
+                let Some(value) = take(config, from) else {
+                    continue;
+                };
+
+                let message = if get_mut(config, to).is_some() {
+                    format!("it's ignored, because `{}` is set.", full_key(to))
+                } else if insert(config, to, value) {
+                    "it was moved.".to_string()
+                } else {
+                    format!("it's ignored, because `{}` can't be set.", full_key(to))
+                };
+
+                deprecations.push(Deprecation {
+                    key: full_key(from),
+                    replacement: Some(full_key(to)),
+                    message,
+                });
+            }
+            Migration::Replaced { key, from, to } => {
+                let Some(value) =
+                    get_mut(config, key).filter(|value| value.as_str() == Some(*from))
+                else {
+                    continue;
+                };
+                *value = Value::String(to.to_string());
+
+                deprecations.push(Deprecation {
+                    key: full_key(key),
+                    replacement: None,
+                    message: format!("`\"{from}\"` was replaced with `\"{to}\"`."),
+                });
+            }
+        }
+    }
+
+    deprecations
+}
+
+fn get_mut<'a>(config: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    key.split('.')
+        .try_fold(config, |value, segment| value.get_mut(segment))
+}
+
+/// Removes the value at the dotted `key`.
+fn take(config: &mut Value, key: &str) -> Option<Value> {
+    let (parent, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (get_mut(config, parent)?, last),
+        None => (config, key),
+    };
+
+    parent.as_object_mut()?.remove(last)
+}
+
+/// Sets the value at the dotted `key`, creating the missing objects on the way. Returns `false`
+/// when something on the way is not an object.
+fn insert(config: &mut Value, key: &str, value: Value) -> bool {
+    let mut current = config;
+    let mut segments = key.split('.').peekable();
+
+    while let Some(segment) = segments.next() {
+        let Some(object) = current.as_object_mut() else {
+            return false;
+        };
+
+        if segments.peek().is_none() {
+            object.insert(segment.to_string(), value);
+            return true;
+        }
+
+        current = object
+            .entry(segment)
+            .or_insert_with(|| Value::Object(Default::default()));
+    }
+
+    false
+}
+
+/// Migrates the config file at `path`, returning its new content and what was migrated.
+///
+/// The file is parsed as is, so files with templates can't be migrated. Comments and the order
+/// of the keys are not kept.
+pub fn migrate_file(path: &Path) -> Result<(String, Vec<Deprecation>)> {
+    let raw = std::fs::read_to_string(path)?;
+    if raw.contains("{{") || raw.contains("{%") {
+        return Err(ConfigError::MigrateTemplate);
+    }
+
+    let mut config = LayerFileConfig::parse::<Value>(path, &raw)?;
+    let deprecations = migrate(&mut config);
+
+    let migrated = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::to_string_pretty(&config)?,
+        Some("yaml" | "yml") => serde_yaml::to_string(&config)?,
+        _ => serde_json::to_string_pretty(&config)? + "\n",
+    };
+
+    Ok((migrated, deprecations))
+}
+
+impl ConfigContext {
+    /// Records a [`Deprecation`], which is also reported with the other warnings.
+    pub fn add_deprecation(&mut self, deprecation: Deprecation) {
+        self.add_warning(deprecation.to_string());
+        self.deprecations.push(deprecation);
+    }
+
+    pub fn get_deprecations(&self) -> &[Deprecation] {
+        &self.deprecations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn migrates_old_config() {
+        let mut config = json!({
+            "feature": {
+                "fs": "disabled",
+                "network": {
+                    "incoming": {
+                        "mode": "steal",
+                        "http_header_filter": "x-user: bear"
+                    }
+                }
+            },
+            "profiles": {
+                "read": { "feature": { "fs": { "mode": "disabled" } } }
+            }
+        });
+
+        let deprecations = migrate(&mut config);
+
+        assert_eq!(
+            config,
+            json!({
+                "feature": {
+                    "fs": "local",
+                    "network": {
+                        "incoming": {
+                            "mode": "steal",
+                            "http_filter": { "header_filter": "x-user: bear" }
+                        }
+                    }
+                },
+                "profiles": {
+                    "read": { "feature": { "fs": { "mode": "local" } } }
+                }
+            })
+        );
+        assert_eq!(
+            deprecations
+                .iter()
+                .map(|deprecation| deprecation.key.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "feature.network.incoming.http_header_filter",
+                "feature.fs",
+                "profiles.read.feature.fs.mode",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_replacement() {
+        let mut config = json!({
+            "feature": {
+                "network": {
+                    "incoming": {
+                        "http_header_filter": "x-user: bear",
+                        "http_filter": { "header_filter": "x-user: panda" }
+                    }
+                }
+            }
+        });
+
+        let deprecations = migrate(&mut config);
+
+        assert_eq!(
+            config,
+            json!({
+                "feature": {
+                    "network": {
+                        "incoming": {
+                            "http_filter": { "header_filter": "x-user: panda" }
+                        }
+                    }
+                }
+            })
+        );
+        assert!(deprecations.first().unwrap().message.contains("ignored"));
+    }
+
+    #[test]
+    fn nothing_to_migrate() {
+        let mut config = json!({ "feature": { "fs": "read" } });
+
+        assert!(migrate(&mut config).is_empty());
+        assert_eq!(config, json!({ "feature": { "fs": "read" } }));
+    }
+}
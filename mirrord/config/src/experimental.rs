@@ -0,0 +1,156 @@
+//! Capabilities that are still in development, enabled (or disabled, when they are on by
+//! default) in [`LayerConfig::experimental`](crate::LayerConfig::experimental).
+//!
+//! To gate a new capability, add an [`ExperimentalFeature`], and check it with
+//! [`LayerConfig::is_experimental_enabled`](crate::LayerConfig::is_experimental_enabled). Remove
+//! it (and the checks) once the capability is stable.
+use std::collections::HashMap;
+
+use crate::config::ConfigContext;
+
+/// Registry of the experimental capabilities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExperimentalFeature {
+    /// Remote `glob`, `nftw` and `fts_*` traversals of directory trees.
+    TraversalHooks,
+    /// Remote directories listed in batches, filtered by name in the agent, when traversing them.
+    BatchedDirReads,
+}
+
+impl ExperimentalFeature {
+    pub const ALL: &'static [Self] = &[Self::TraversalHooks, Self::BatchedDirReads];
+
+    /// Key of the feature in the `experimental` config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TraversalHooks => "traversal_hooks",
+            Self::BatchedDirReads => "batched_dir_reads",
+        }
+    }
+
+    /// Whether the feature is enabled when the `experimental` config doesn't mention it, for
+    /// capabilities that were on before they were gated.
+    pub fn enabled_by_default(self) -> bool {
+        match self {
+            Self::TraversalHooks => true,
+            Self::BatchedDirReads => false,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::TraversalHooks => {
+                "traverse remote directory trees with `glob`, `nftw` and `fts_*` through the agent"
+            }
+            Self::BatchedDirReads => {
+                "list remote directories in batches, filtered by name in the agent, when \
+                 traversing them"
+            }
+        }
+    }
+}
+
+/// Whether the `feature` is enabled by the `experimental` config, or by default.
+pub(crate) fn is_enabled(
+    experimental: &HashMap<String, bool>,
+    feature: ExperimentalFeature,
+) -> bool {
+    experimental
+        .get(feature.name())
+        .copied()
+        .unwrap_or(feature.enabled_by_default())
+}
+
+/// Warns about the unknown features in the `experimental` config (they may have become stable,
+/// or been removed), and lists the ones it enables.
+pub(crate) fn verify(experimental: &HashMap<String, bool>, context: &mut ConfigContext) {
+    let mut unknown = experimental
+        .keys()
+        .filter(|name| {
+            !ExperimentalFeature::ALL
+                .iter()
+                .any(|feature| feature.name() == name.as_str())
+        })
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    unknown.sort_unstable();
+
+    if !unknown.is_empty() {
+        let known = ExperimentalFeature::ALL
+            .iter()
+            .map(|feature| feature.name())
+            .collect::<Vec<_>>();
+
+        context.add_warning(format!(
+            "Unknown experimental features are ignored: {}. The experimental features are: {}.",
+            unknown.join(", "),
+            known.join(", "),
+        ));
+    }
+
+    let enabled = ExperimentalFeature::ALL
+        .iter()
+        .filter(|feature| {
+            experimental
+                .get(feature.name())
+                .copied()
+                .unwrap_or_default()
+        })
+        .map(|feature| format!("{} ({})", feature.name(), feature.description()))
+        .collect::<Vec<_>>();
+
+    if !enabled.is_empty() {
+        context.add_warning(format!(
+            "Experimental features enabled: {}. They are still in development, and may change or \
+             break.",
+            enabled.join(", ")
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warns_about_experimental_features() {
+        let mut context = ConfigContext::default();
+        verify(&HashMap::new(), &mut context);
+        assert!(context.get_warnings().is_empty());
+
+        let experimental = HashMap::from([
+            ("batched_dir_reads".to_string(), true),
+            ("teleport".to_string(), true),
+        ]);
+        verify(&experimental, &mut context);
+
+        let warnings = context.get_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().any(|warning| warning.contains("teleport")));
+        let enabled = "Experimental features enabled: batched_dir_reads";
+        assert!(warnings.iter().any(|warning| warning.starts_with(enabled)));
+    }
+
+    #[test]
+    fn batched_dir_reads_off_by_default() {
+        let feature = ExperimentalFeature::BatchedDirReads;
+        assert!(!is_enabled(&HashMap::new(), feature));
+
+        let experimental = HashMap::from([("batched_dir_reads".to_string(), true)]);
+        assert!(is_enabled(&experimental, feature));
+    }
+
+    #[test]
+    fn traversal_hooks_on_by_default() {
+        let feature = ExperimentalFeature::TraversalHooks;
+        assert!(is_enabled(&HashMap::new(), feature));
+
+        let experimental = HashMap::from([("traversal_hooks".to_string(), false)]);
+        assert!(!is_enabled(&experimental, feature));
+
+        // Turning a feature off is not worth a warning.
+        let mut context = ConfigContext::default();
+        verify(&experimental, &mut context);
+        assert!(context.get_warnings().is_empty());
+    }
+}
@@ -2,13 +2,17 @@ use mirrord_analytics::CollectAnalytics;
 use mirrord_config_derive::MirrordConfig;
 use schemars::JsonSchema;
 
-use self::{copy_target::CopyTargetConfig, env::EnvConfig, fs::FsConfig, network::NetworkConfig};
-use crate::config::source::MirrordConfigSource;
+use self::{
+    copy_target::CopyTargetConfig, env::EnvConfig, fs::FsConfig, network::NetworkConfig,
+    split_queues::SplitQueuesConfig,
+};
+use crate::{config::source::MirrordConfigSource, util::VecOrSingle};
 
 pub mod copy_target;
 pub mod env;
 pub mod fs;
 pub mod network;
+pub mod split_queues;
 
 /// Controls mirrord features.
 ///
@@ -90,11 +94,73 @@ pub struct FeatureConfig {
     #[config(nested)]
     pub copy_target: CopyTargetConfig,
 
+    /// ## feature.split_queues {#feature-split_queues}
+    ///
+    /// Splits queues consumed by the target between the target and mirrord sessions, routing
+    /// messages to sessions based on their attributes. This feature requires a
+    /// [mirrord operator](https://mirrord.dev/docs/overview/teams/).
+    #[config(nested)]
+    pub split_queues: SplitQueuesConfig,
+
     /// ## feature.hostname {#feature-hostname}
     ///
     /// Should mirrord return the hostname of the target pod when calling `gethostname`
     #[config(default = true)]
     pub hostname: bool,
+
+    /// ## feature.identity {#feature-identity}
+    ///
+    /// Should mirrord return the user and group IDs of the target container when calling
+    /// `getuid`, `geteuid`, `getgid`, `getegid` and `getgroups`, instead of the local ones.
+    ///
+    /// Useful when the application checks whether it runs as root, or compares its uid with the
+    /// owner of files. The IDs are those of the process that the target container runs, so they
+    /// follow the `securityContext` of the pod. Ignored when running without a target.
+    ///
+    /// Defaults to `false`.
+    #[config(default = false)]
+    pub identity: bool,
+
+    /// ## feature.remote_exec {#feature-remote_exec}
+    ///
+    /// Processes that run in the target container instead of locally, with their stdio streamed
+    /// back. Matched by executable name, like [`skip_processes`](#root-skip_processes).
+    ///
+    /// Useful when the application shells out to tools that need the cluster's network or files,
+    /// e.g. `pg_dump`. The processes run with the target container's filesystem, network,
+    /// processes, user and environment, and the local working directory is ignored. Needs
+    /// [`agent.remote_exec`](#agent-remote_exec).
+    /// Accepts a single value, or multiple values separated by `;`.
+    ///
+    ///```json
+    /// {
+    ///   "feature": {
+    ///     "remote_exec": "pg_dump;psql"
+    ///   }
+    /// }
+    /// ```
+    #[config(env = "MIRRORD_REMOTE_EXEC")]
+    pub remote_exec: Option<VecOrSingle<String>>,
+
+    /// ## feature.only_processes {#feature-only_processes}
+    ///
+    /// The only processes that mirrord operates on, the opposite of
+    /// [`skip_processes`](#root-skip_processes). Matched by executable name.
+    ///
+    /// Useful when the application is started by wrappers, e.g. just `node`, and not the `npm`
+    /// and shells around it. The other processes run locally, but their children are still
+    /// checked, so the listed processes are found also when started by a process that isn't.
+    /// Accepts a single value, or multiple values separated by `;`.
+    ///
+    ///```json
+    /// {
+    ///   "feature": {
+    ///     "only_processes": "node"
+    ///   }
+    /// }
+    /// ```
+    #[config(env = "MIRRORD_ONLY_PROCESSES")]
+    pub only_processes: Option<VecOrSingle<String>>,
 }
 
 impl CollectAnalytics for &FeatureConfig {
@@ -103,6 +169,10 @@ impl CollectAnalytics for &FeatureConfig {
         analytics.add("fs", &self.fs);
         analytics.add("network", &self.network);
         analytics.add("copy_target", &self.copy_target);
+        analytics.add("split_queues", &self.split_queues);
         analytics.add("hostname", self.hostname);
+        analytics.add("identity", self.identity);
+        analytics.add("remote_exec", self.remote_exec.is_some());
+        analytics.add("only_processes", self.only_processes.is_some());
     }
 }
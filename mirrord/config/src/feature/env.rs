@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use mirrord_analytics::CollectAnalytics;
 use mirrord_config_derive::MirrordConfig;
 use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use wildmatch::WildMatch;
 
 use crate::{
     config::{from_env::FromEnv, source::MirrordConfigSource, ConfigContext, Result},
@@ -79,6 +81,19 @@ pub struct EnvConfig {
     /// and the remote environment contains a lot of variables.
     pub load_from_process: Option<bool>,
 
+    /// ### feature.env.lazy {#feature-env-lazy}
+    ///
+    /// Looks up the variables that are missing from the local environment in the remote one, when
+    /// the process reads them with `getenv`.
+    ///
+    /// Useful for programs that compute the names of the variables at runtime, or read the
+    /// environment again after changing it. The remote environment is fetched on the first
+    /// missing variable, and [`include`](#feature-env-include) and
+    /// [`exclude`](#feature-env-exclude) apply to it. Variables the process removed with
+    /// `unsetenv` are not looked up. Programs that don't read the environment through libc (e.g.
+    /// Go) aren't affected.
+    pub lazy: Option<bool>,
+
     /// ### feature.env.unset {#feature-env-unset}
     ///
     /// Allows unsetting environment variables in the executed process.
@@ -88,6 +103,109 @@ pub struct EnvConfig {
     /// The unsetting happens from extension (if possible)/CLI and when process initializes.
     /// In some cases, such as Go the env might not be able to be modified from the process itself.
     pub unset: Option<VecOrSingle<String>>,
+
+    /// ### feature.env.containers {#feature-env-containers}
+    ///
+    /// Other containers in the target pod to load environment variables from, for apps that
+    /// span multiple containers (e.g. `app` and `worker`).
+    ///
+    /// The environments are merged in the order the containers are listed, with later
+    /// containers overriding earlier ones, and the target container overriding all of them.
+    /// When a `prefix` is set, the container's variables are added with it (e.g. `WORKER__PORT`),
+    /// so they don't collide with the target container's.
+    ///
+    /// [`include`](#feature-env-include) and [`exclude`](#feature-env-exclude) apply to the
+    /// prefixed names. Not supported with [`agent.ephemeral`](#agent-ephemeral), or with the
+    /// mirrord operator.
+    ///
+    /// ```json
+    /// {
+    ///   "feature": {
+    ///     "env": {
+    ///       "containers": [
+    ///         { "name": "worker", "prefix": "WORKER__" }
+    ///       ]
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub containers: Option<Vec<EnvContainer>>,
+
+    /// ### feature.env.target_locale {#feature-env-target_locale}
+    ///
+    /// Unsets the timezone and locale variables (`TZ`, `LANG`, `LANGUAGE` and `LC_*`) that the
+    /// remote pod doesn't set, so that the process uses the timezone and locale of the remote pod
+    /// (e.g. its `/etc/localtime`) instead of the local ones.
+    ///
+    /// Variables that are not loaded from the remote pod, due to
+    /// [`include`](#feature-env-include) or [`exclude`](#feature-env-exclude), are kept.
+    ///
+    /// Also reads the timezone files of the remote pod (`/etc/localtime`, `/etc/timezone` and
+    /// `/usr/share/zoneinfo`) by default, unless the [`fs`](#feature-fs) overrides say otherwise.
+    ///
+    /// Defaults to `false`.
+    pub target_locale: Option<bool>,
+}
+
+/// A container to load environment variables from, see
+/// [`containers`](#feature-env-containers).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct EnvContainer {
+    /// Name of the container in the target pod.
+    pub name: String,
+
+    /// Added to the names of the container's environment variables.
+    pub prefix: Option<String>,
+}
+
+/// Variables that set the timezone and the locale of a process.
+pub const LOCALE_ENV_VARS: [&str; 9] = [
+    "TZ",
+    "LANG",
+    "LANGUAGE",
+    "LC_ALL",
+    "LC_COLLATE",
+    "LC_CTYPE",
+    "LC_MESSAGES",
+    "LC_NUMERIC",
+    "LC_TIME",
+];
+
+impl EnvConfig {
+    /// The [`LOCALE_ENV_VARS`] to unset in the local process with
+    /// [`target_locale`](#feature-env-target_locale), because the target doesn't set them in its
+    /// `remote_env`.
+    ///
+    /// Variables that aren't loaded from the target, due to [`include`](#feature-env-include) or
+    /// [`exclude`](#feature-env-exclude) (matched like the agent does), are kept.
+    pub fn locale_vars_to_unset(&self, remote_env: &HashMap<String, String>) -> Vec<String> {
+        if !self.target_locale.unwrap_or_default() {
+            return Vec::new();
+        }
+
+        let matches = |patterns: &VecOrSingle<String>, name: &str| {
+            patterns
+                .as_slice()
+                .iter()
+                .any(|pattern| WildMatch::new(pattern).matches(name))
+        };
+        let loaded = |name: &str| {
+            self.include
+                .as_ref()
+                .map_or(true, |include| matches(include, name))
+                && !self
+                    .exclude
+                    .as_ref()
+                    .is_some_and(|exclude| matches(exclude, name))
+        };
+
+        LOCALE_ENV_VARS
+            .into_iter()
+            .filter(|name| !remote_env.contains_key(*name) && loaded(name))
+            .map(String::from)
+            .collect()
+    }
 }
 
 impl MirrordToggleableConfig for EnvFileConfig {
@@ -101,8 +219,11 @@ impl MirrordToggleableConfig for EnvFileConfig {
                 .transpose()?
                 .or_else(|| Some(VecOrSingle::Single("*".to_owned()))),
             load_from_process: None,
+            lazy: None,
             r#override: None,
             unset: None,
+            containers: None,
+            target_locale: None,
         })
     }
 }
@@ -137,6 +258,14 @@ impl CollectAnalytics for &EnvConfig {
                 .map(|v| v.len() as u32)
                 .unwrap_or_default(),
         );
+        analytics.add("lazy", self.lazy.unwrap_or_default());
+        analytics.add(
+            "containers_count",
+            self.containers
+                .as_ref()
+                .map(|v| v.len() as u32)
+                .unwrap_or_default(),
+        );
     }
 }
 
@@ -203,4 +332,38 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn locale_vars_to_unset() {
+        let env = EnvFileConfig::default()
+            .generate_config(&mut ConfigContext::default())
+            .unwrap();
+        let remote_env = HashMap::from([("LANG".to_string(), "C.UTF-8".to_string())]);
+        assert!(env.locale_vars_to_unset(&remote_env).is_empty());
+
+        let env = EnvConfig {
+            target_locale: Some(true),
+            ..env
+        };
+        let unset = env.locale_vars_to_unset(&remote_env);
+        assert!(unset.contains(&"TZ".to_string()));
+        assert!(unset.contains(&"LC_TIME".to_string()));
+        assert!(!unset.contains(&"LANG".to_string()));
+
+        let excluded = EnvConfig {
+            exclude: Some(VecOrSingle::Multiple(vec![
+                "TZ".to_string(),
+                "LC_*".to_string(),
+            ])),
+            ..env.clone()
+        };
+        let unset = excluded.locale_vars_to_unset(&remote_env);
+        assert_eq!(unset, ["LANGUAGE"]);
+
+        let included = EnvConfig {
+            include: Some(VecOrSingle::Single("LC_?IME".to_string())),
+            ..env
+        };
+        assert_eq!(included.locale_vars_to_unset(&remote_env), ["LC_TIME"]);
+    }
 }
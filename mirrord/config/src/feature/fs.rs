@@ -90,6 +90,15 @@ impl MirrordConfig for FsUserConfig {
                     .source_value(context)
                     .transpose()?,
                 not_found: None,
+                fallback_local: None,
+                max_file_size: None,
+                max_transfer_bytes: None,
+                max_open_files: None,
+                verify_reads: false,
+                readahead: None,
+                remote_temp: None,
+                config_maps: None,
+                secrets: None,
             },
             FsUserConfig::Advanced(advanced) => advanced.generate_config(context)?,
         };
@@ -117,6 +126,15 @@ impl MirrordToggleableConfig for FsUserConfig {
             read_only,
             local,
             not_found: None,
+            fallback_local: None,
+            max_file_size: None,
+            max_transfer_bytes: None,
+            max_open_files: None,
+            verify_reads: false,
+            readahead: None,
+            remote_temp: None,
+            config_maps: None,
+            secrets: None,
         })
     }
 }
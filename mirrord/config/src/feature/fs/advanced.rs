@@ -8,6 +8,10 @@ use crate::{
     util::{MirrordToggleableConfig, VecOrSingle},
 };
 
+/// Local directory with the `ConfigMap`s and `Secret`s of [`FsConfig::config_maps`] and
+/// [`FsConfig::secrets`], which the CLI fetches for the layer.
+pub const CLUSTER_FILES_DIR_ENV: &str = "MIRRORD_CLUSTER_FILES_DIR";
+
 // TODO(alex): We could turn this derive macro (`MirrordConfig`) into an attribute version, which
 // would allow us to "capture" the `derive` statement, making it possible to implement the same for
 // whatever is generated by `map_to`.
@@ -106,6 +110,95 @@ pub struct FsConfig {
     ///
     /// Specify file path patterns that if matched will be treated as non-existent.
     pub not_found: Option<VecOrSingle<String>>,
+
+    /// ### feature.fs.fallback_local {#feature-fs-fallback_local}
+    ///
+    /// Specify file path patterns that if matched will be opened locally when opening them
+    /// remotely fails because they don't exist (`ENOENT`) or can't be accessed (`EACCES`) in the
+    /// remote, instead of failing the open. `stat`, `access` and `statx` fall back the same way.
+    /// Use `".*"` to fall back for every path.
+    ///
+    /// Useful when some of the files exist only locally, and you don't want to list them in
+    /// [`feature.fs.local`](#feature-fs-local).
+    pub fallback_local: Option<VecOrSingle<String>>,
+
+    /// ### feature.fs.max_file_size {#feature-fs-max_file_size}
+    ///
+    /// Largest remote file (in bytes) that can be opened for reading. Opening a larger one fails
+    /// with `EFBIG`.
+    pub max_file_size: Option<u64>,
+
+    /// ### feature.fs.max_transfer_bytes {#feature-fs-max_transfer_bytes}
+    ///
+    /// How many bytes the session can read from and write to remote files, in total. Once
+    /// reached, reads and writes of remote files fail with `EDQUOT`.
+    ///
+    /// All the processes of the session share the count, including the children they spawn.
+    pub max_transfer_bytes: Option<u64>,
+
+    /// ### feature.fs.max_open_files {#feature-fs-max_open_files}
+    ///
+    /// How many remote files the session can have open at the same time, in all of its processes
+    /// (copies of a file descriptor made with `dup` or inherited by a child count once). Opening
+    /// another remote file fails with `EMFILE`.
+    pub max_open_files: Option<usize>,
+
+    /// ### feature.fs.verify_reads {#feature-fs-verify_reads}
+    ///
+    /// Have the agent send a checksum with every read of a remote file, and check it, so that
+    /// data corrupted on the way (e.g. by a flaky proxy) fails the read with `EIO`, instead of
+    /// reaching the application. Positional reads (`pread`) are retried first.
+    ///
+    /// Reads are not verified with agents that don't support it.
+    ///
+    /// Defaults to `false`.
+    #[config(default = false)]
+    pub verify_reads: bool,
+
+    /// ### feature.fs.readahead {#feature-fs-readahead}
+    ///
+    /// Size (in bytes) of the windows in which remote files that are read sequentially are read
+    /// ahead, e.g. `1048576`. After a few `read`s of a remote file in a row, the next window is
+    /// read in the background while the application reads the current one, so streaming large
+    /// remote files doesn't wait for the agent on every `read`. Anything else done with the file
+    /// (e.g. `lseek` or `write`) stops the readahead, until the next `read`s in a row.
+    ///
+    /// Files read at the same time by several processes (e.g. shared after a `fork`) should not
+    /// be read ahead.
+    ///
+    /// Disabled by default.
+    pub readahead: Option<u64>,
+
+    /// ### feature.fs.remote_temp {#feature-fs-remote_temp}
+    ///
+    /// Specify file path patterns in the local temporary directory (`$TMPDIR` or `/tmp`) that if
+    /// matched will be read and written remotely, in a scratch directory that mirrord creates
+    /// in the target's `/tmp`, instead of locally. Useful when a process in the target has to see
+    /// the temporary files your application produces.
+    ///
+    /// The files keep their path under the temporary directory, e.g. with `"^/tmp/build-"`,
+    /// `/tmp/build-1/out.txt` is `/tmp/mirrord-scratch-<id>/build-1/out.txt` in the target.
+    /// The scratch directory is removed when the session ends.
+    pub remote_temp: Option<VecOrSingle<String>>,
+
+    /// ### feature.fs.config_maps {#feature-fs-config_maps}
+    ///
+    /// Names of `ConfigMap`s in the target's namespace that your application can read under
+    /// `/mirrord/configmaps/<name>/<key>`, also when they're not mounted into the target.
+    ///
+    /// The `ConfigMap`s are fetched when the session starts. Requires a `feature.fs.mode` other
+    /// than `local`.
+    pub config_maps: Option<VecOrSingle<String>>,
+
+    /// ### feature.fs.secrets {#feature-fs-secrets}
+    ///
+    /// Names of `Secret`s in the target's namespace that your application can read under
+    /// `/mirrord/secrets/<name>/<key>`, also when they're not mounted into the target.
+    ///
+    /// The `Secret`s are fetched when the session starts, into a local directory that only you
+    /// can read, which is removed when the session ends. Requires a `feature.fs.mode` other than
+    /// `local`.
+    pub secrets: Option<VecOrSingle<String>>,
 }
 
 impl MirrordToggleableConfig for AdvancedFsUserConfig {
@@ -127,6 +220,15 @@ impl MirrordToggleableConfig for AdvancedFsUserConfig {
             read_only,
             local,
             not_found: None,
+            fallback_local: None,
+            max_file_size: None,
+            max_transfer_bytes: None,
+            max_open_files: None,
+            verify_reads: false,
+            readahead: None,
+            remote_temp: None,
+            config_maps: None,
+            secrets: None,
         })
     }
 }
@@ -188,6 +290,39 @@ impl CollectAnalytics for &FsConfig {
                 .map(VecOrSingle::len)
                 .unwrap_or_default(),
         );
+        analytics.add("max_file_size", self.max_file_size.is_some());
+        analytics.add("max_transfer_bytes", self.max_transfer_bytes.is_some());
+        analytics.add("max_open_files", self.max_open_files.is_some());
+        analytics.add("verify_reads", self.verify_reads);
+        analytics.add("readahead", self.readahead.is_some());
+        analytics.add(
+            "remote_temp_paths",
+            self.remote_temp
+                .as_ref()
+                .map(VecOrSingle::len)
+                .unwrap_or_default(),
+        );
+        analytics.add(
+            "config_maps",
+            self.config_maps
+                .as_ref()
+                .map(VecOrSingle::len)
+                .unwrap_or_default(),
+        );
+        analytics.add(
+            "secrets",
+            self.secrets
+                .as_ref()
+                .map(VecOrSingle::len)
+                .unwrap_or_default(),
+        );
+        analytics.add(
+            "fallback_local_paths",
+            self.fallback_local
+                .as_ref()
+                .map(VecOrSingle::len)
+                .unwrap_or_default(),
+        );
     }
 }
 
@@ -38,7 +38,8 @@ pub mod outgoing;
 ///         "ignore_localhost": false,
 ///         "unix_streams": "bear.+"
 ///       },
-///       "dns": false
+///       "dns": false,
+///       "ipv6": false
 ///     }
 ///   }
 /// }
@@ -68,6 +69,37 @@ pub struct NetworkConfig {
     /// and setting `read_only: ["/etc/resolv.conf"]`.
     #[config(env = "MIRRORD_REMOTE_DNS", default = true)]
     pub dns: bool,
+
+    /// ### feature.network.cluster_names {#feature-network-cluster_names}
+    ///
+    /// Always resolve cluster-internal names via the remote pod, and connect to them through it,
+    /// even when [`dns`](#feature-network-dns) is disabled, or a `remote` outgoing
+    /// [`filter`](#feature.network.outgoing.filter) doesn't match them. Names that match a
+    /// `local` outgoing filter still connect locally.
+    ///
+    /// Cluster-internal names are the names ending with `.svc` or `.cluster.local`
+    /// (`my-service.my-namespace.svc.cluster.local`). Single-label names (`my-service`) are not,
+    /// they're resolved with the search domains of wherever the name is resolved.
+    ///
+    /// Defaults to `false`.
+    #[config(default = false)]
+    pub cluster_names: bool,
+
+    /// ### feature.network.ipv6 {#feature-network-ipv6}
+    ///
+    /// Allow outgoing TCP connections over IPv6, and resolve names to IPv6 addresses when the
+    /// application asks for them.
+    ///
+    /// Clients that race IPv4 and IPv6 connections (happy eyeballs) connect to both families
+    /// through the remote pod.
+    ///
+    /// When disabled, creating IPv6 sockets fails with `EAFNOSUPPORT`, and names are only
+    /// resolved to IPv4 addresses. IPv6 UDP sockets are never supported, and IPv6 listeners are
+    /// always local.
+    ///
+    /// Defaults to `false`.
+    #[config(env = "MIRRORD_ENABLE_IPV6", default = false)]
+    pub ipv6: bool,
 }
 
 impl MirrordToggleableConfig for NetworkFileConfig {
@@ -77,10 +109,17 @@ impl MirrordToggleableConfig for NetworkFileConfig {
             .transpose()?
             .unwrap_or(false);
 
+        let ipv6 = FromEnv::new("MIRRORD_ENABLE_IPV6")
+            .source_value(context)
+            .transpose()?
+            .unwrap_or(false);
+
         Ok(NetworkConfig {
             incoming: IncomingFileConfig::disabled_config(context)?,
             dns,
             outgoing: OutgoingFileConfig::disabled_config(context)?,
+            cluster_names: false,
+            ipv6,
         })
     }
 }
@@ -90,6 +129,8 @@ impl CollectAnalytics for &NetworkConfig {
         analytics.add("incoming", &self.incoming);
         analytics.add("outgoing", &self.outgoing);
         analytics.add("dns", self.dns);
+        analytics.add("cluster_names", self.cluster_names);
+        analytics.add("ipv6", self.ipv6);
     }
 }
 
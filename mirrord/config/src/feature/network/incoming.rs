@@ -1,4 +1,4 @@
-use std::{collections::HashSet, fmt, str::FromStr};
+use std::{collections::HashSet, fmt, ops::RangeInclusive, path::PathBuf, str::FromStr};
 
 use bimap::BiMap;
 use mirrord_analytics::{AnalyticValue, Analytics, CollectAnalytics};
@@ -95,6 +95,10 @@ impl MirrordConfig for IncomingFileConfig {
                     .transpose()?
                     .unwrap_or_default(),
                 http_filter: HttpFilterFileConfig::default().generate_config(context)?,
+                intercept_loopback: FromEnv::new("MIRRORD_INCOMING_INTERCEPT_LOOPBACK")
+                    .source_value(context)
+                    .transpose()?
+                    .unwrap_or_default(),
                 on_concurrent_steal: FromEnv::new("MIRRORD_OPERATOR_ON_CONCURRENT_STEAL")
                     .layer(|layer| {
                         Unstable::new("IncomingFileConfig", "on_concurrent_steal", layer)
@@ -123,6 +127,11 @@ impl MirrordConfig for IncomingFileConfig {
                     .map(|m| m.into_iter().collect())
                     .unwrap_or_default(),
                 ignore_localhost: advanced.ignore_localhost.unwrap_or_default(),
+                intercept_loopback: FromEnv::new("MIRRORD_INCOMING_INTERCEPT_LOOPBACK")
+                    .or(advanced.intercept_loopback)
+                    .source_value(context)
+                    .transpose()?
+                    .unwrap_or_default(),
                 listen_ports: advanced
                     .listen_ports
                     .map(|m| m.into_iter().collect())
@@ -135,7 +144,16 @@ impl MirrordConfig for IncomingFileConfig {
                     .source_value(context)
                     .transpose()?
                     .unwrap_or_default(),
-                ports: advanced.ports.map(|ports| ports.into_iter().collect()),
+                ports: advanced
+                    .ports
+                    .map(|ports| ports.iter().map(PortOrRange::ports).collect())
+                    .transpose()?,
+                steal_delivery: advanced
+                    .steal_delivery
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(StealDelivery::try_from)
+                    .collect::<Result<_>>()?,
             },
         };
 
@@ -198,6 +216,14 @@ pub struct IncomingAdvancedFileConfig {
     /// Consider removing when adding https://github.com/metalbear-co/mirrord/issues/702
     pub ignore_localhost: Option<bool>,
 
+    /// ### intercept_loopback
+    ///
+    /// Intercept ports that the local application listens on only on a loopback address (e.g.
+    /// `127.0.0.1`), even when they're not explicitly listed.
+    ///
+    /// See [`intercept_loopback`](#feature-network-incoming-intercept_loopback) for details.
+    pub intercept_loopback: Option<bool>,
+
     /// ### ignore_ports
     ///
     /// Ports to ignore when mirroring/stealing traffic. Useful if you want specific ports to be
@@ -232,8 +258,19 @@ pub struct IncomingAdvancedFileConfig {
     ///
     /// List of ports to mirror/steal traffic from. Other ports will remain local.
     ///
+    /// Ranges of ports can be listed as `"<first>-<last>"`, e.g. `[80, "8000-8100"]`, for services
+    /// that open a listener per tenant or shard.
+    ///
     /// Mutually exclusive with [`ignore_ports`](###ignore_ports).
-    pub ports: Option<Vec<u16>>,
+    pub ports: Option<Vec<PortOrRange>>,
+
+    /// ### steal_delivery
+    ///
+    /// Deliver raw TCP stolen from a port to a unix socket or to a command, instead of to a port
+    /// the local application listens on.
+    ///
+    /// See [`steal_delivery`](##steal_delivery) for details.
+    pub steal_delivery: Option<Vec<StealDeliveryFileConfig>>,
 }
 
 /// Controls the incoming TCP traffic feature.
@@ -318,6 +355,22 @@ pub struct IncomingConfig {
     /// #### feature.network.incoming.ignore_localhost {#feature-network-incoming-ignore_localhost}
     pub ignore_localhost: bool,
 
+    /// #### feature.network.incoming.intercept_loopback {#feature-network-incoming-intercept_loopback}
+    ///
+    /// Applications often serve internal endpoints (admin, metrics, debugging) on a loopback
+    /// address, and their public server on `0.0.0.0`. By default, TCP ports bound only on a
+    /// loopback address are not mirrored/stolen, and remain local, unless they are explicitly
+    /// listed in [`feature.network.incoming.ports`](#feature-network-incoming-ports),
+    /// [`feature.network.incoming.port_mapping`](#feature-network-incoming-port_mapping) or
+    /// [`feature.network.incoming.listen_ports`](#feature-network-incoming-listen_ports).
+    ///
+    /// Set to `true` to intercept them anyway.
+    ///
+    /// Can be set with the `MIRRORD_INCOMING_INTERCEPT_LOOPBACK` environment variable.
+    ///
+    /// Defaults to `false`.
+    pub intercept_loopback: bool,
+
     /// #### feature.network.incoming.ignore_ports {#feature-network-incoming-ignore_ports}
     ///
     /// Ports to ignore when mirroring/stealing traffic, these ports will remain local.
@@ -359,9 +412,43 @@ pub struct IncomingConfig {
     ///
     /// List of ports to mirror/steal traffic from. Other ports will remain local.
     ///
+    /// Ranges of ports can be listed as `"<first>-<last>"` (inclusive), e.g. `[80, "8000-8100"]`.
+    /// Whichever port in a range the local application binds, it's mirrored/stolen.
+    ///
     /// Mutually exclusive with
     /// [`feature.network.incoming.ignore_ports`](#feature-network-ignore_ports).
-    pub ports: Option<HashSet<u16>>,
+    pub ports: Option<PortRanges>,
+
+    /// #### feature.network.incoming.steal_delivery {#feature-network-incoming-steal_delivery}
+    ///
+    /// Deliver the raw TCP stolen from a remote port to a unix socket or to a command, instead of
+    /// to a port bound by the local application.
+    ///
+    /// The internal proxy steals these ports as soon as the session starts, so the local
+    /// application doesn't need to listen on them. Every stolen connection gets its own
+    /// connection to the unix socket (`"to": "unix:///path/to/socket"`), or its own instance of
+    /// the command (`"command": ["program", "arg"]`), which reads the data on stdin and writes
+    /// the response to stdout.
+    ///
+    /// Only used when [`feature.network.incoming.mode`](#feature-network-incoming-mode) is
+    /// `"steal"`.
+    ///
+    /// ```json
+    /// {
+    ///   "feature": {
+    ///     "network": {
+    ///       "incoming": {
+    ///         "mode": "steal",
+    ///         "steal_delivery": [
+    ///           { "port": 9000, "to": "unix:///tmp/sink.sock" },
+    ///           { "port": 9001, "command": ["tee", "/tmp/stolen.bin"] }
+    ///         ]
+    ///       }
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub steal_delivery: Vec<StealDelivery>,
 }
 
 impl IncomingConfig {
@@ -372,6 +459,168 @@ impl IncomingConfig {
     pub fn is_steal(&self) -> bool {
         matches!(self.mode, IncomingMode::Steal)
     }
+
+    /// <!--${internal}-->
+    /// Helper function.
+    ///
+    /// Used by mirrord-layer to decide whether a TCP listener bound on a loopback address to the
+    /// local `port` should be mirrored/stolen, see
+    /// [`IncomingConfig::intercept_loopback`].
+    pub fn intercepts_loopback_port(&self, port: u16) -> bool {
+        let remote_port = self
+            .port_mapping
+            .get_by_left(&port)
+            .copied()
+            .unwrap_or(port);
+
+        self.intercept_loopback
+            || self.port_mapping.contains_left(&port)
+            || self.listen_ports.contains_left(&port)
+            || self
+                .ports
+                .as_ref()
+                .is_some_and(|ports| ports.contains(remote_port))
+    }
+}
+
+/// <!--${internal}-->
+/// Ports of [`IncomingConfig::ports`], kept as the configured inclusive ranges.
+#[derive(Default, PartialEq, Eq, Clone, Debug)]
+pub struct PortRanges(Vec<RangeInclusive<u16>>);
+
+impl PortRanges {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&port))
+    }
+}
+
+impl FromIterator<RangeInclusive<u16>> for PortRanges {
+    fn from_iter<T: IntoIterator<Item = RangeInclusive<u16>>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+/// <!--${internal}-->
+/// An entry of [`IncomingAdvancedFileConfig::ports`], a port (`8080`), or an inclusive range of
+/// ports (`"8000-8100"`).
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[serde(untagged)]
+pub enum PortOrRange {
+    Port(u16),
+    Range(String),
+}
+
+impl PortOrRange {
+    /// The ports of this entry, fails when it's not a valid range.
+    fn ports(&self) -> Result<RangeInclusive<u16>> {
+        let range = match self {
+            Self::Port(port) => return Ok(*port..=*port),
+            Self::Range(range) => range,
+        };
+
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        match (first.trim().parse::<u16>(), last.trim().parse::<u16>()) {
+            (Ok(first), Ok(last)) if first <= last => Ok(first..=last),
+            _ => Err(ConfigError::InvalidValue(
+                range.clone(),
+                "feature.network.incoming.ports",
+            )),
+        }
+    }
+}
+
+/// <!--${internal}-->
+/// Prefix of [`StealDeliveryFileConfig::to`] addresses that point to a unix socket.
+const UNIX_SOCKET_SCHEME: &str = "unix://";
+
+/// ## steal_delivery
+///
+/// Where to deliver the raw TCP stolen from a remote port.
+///
+/// Exactly one of `to` (a `unix://` socket address) and `command` has to be set.
+///
+/// ```json
+/// { "port": 9000, "to": "unix:///tmp/sink.sock" }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[serde(deny_unknown_fields)]
+pub struct StealDeliveryFileConfig {
+    /// ### port
+    ///
+    /// Remote port to steal.
+    pub port: u16,
+
+    /// ### to
+    ///
+    /// Unix socket the stolen connections are made to, e.g. `"unix:///tmp/sink.sock"`.
+    pub to: Option<String>,
+
+    /// ### command
+    ///
+    /// Command spawned for every stolen connection, it gets the data on stdin and its stdout is
+    /// sent back, e.g. `["nc", "-U", "/tmp/sink.sock"]`.
+    pub command: Option<Vec<String>>,
+}
+
+/// <!--${internal}-->
+/// Validated [`StealDeliveryFileConfig`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct StealDelivery {
+    /// Remote port to steal.
+    pub port: u16,
+
+    /// Where the stolen connections go.
+    pub target: StealDeliveryTarget,
+}
+
+/// <!--${internal}-->
+/// Where [`StealDelivery`] sends the stolen connections.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum StealDeliveryTarget {
+    /// Connect to this unix socket.
+    UnixSocket(PathBuf),
+
+    /// Spawn this command (program followed by its arguments), and talk over its stdio.
+    Command(Vec<String>),
+}
+
+impl TryFrom<StealDeliveryFileConfig> for StealDelivery {
+    type Error = ConfigError;
+
+    fn try_from(config: StealDeliveryFileConfig) -> Result<Self, Self::Error> {
+        let target = match (config.to, config.command) {
+            (Some(to), None) => {
+                let path = to.strip_prefix(UNIX_SOCKET_SCHEME).ok_or_else(|| {
+                    ConfigError::InvalidValue(
+                        to.clone(),
+                        "feature.network.incoming.steal_delivery.to",
+                    )
+                })?;
+
+                StealDeliveryTarget::UnixSocket(path.into())
+            }
+            (None, Some(command)) if !command.is_empty() => StealDeliveryTarget::Command(command),
+            (None, Some(..)) => {
+                return Err(ConfigError::InvalidValue(
+                    "[]".to_string(),
+                    "feature.network.incoming.steal_delivery.command",
+                ))
+            }
+            (Some(..), Some(..)) | (None, None) => {
+                return Err(ConfigError::Conflict(format!(
+                    "steal_delivery for port {} must set exactly one of `to` and `command`",
+                    config.port
+                )))
+            }
+        };
+
+        Ok(Self {
+            port: config.port,
+            target,
+        })
+    }
 }
 
 /// Allows selecting between mirrorring or stealing traffic.
@@ -525,7 +774,88 @@ impl CollectAnalytics for &IncomingConfig {
         analytics.add("port_mapping_count", self.port_mapping.len());
         analytics.add("listen_ports_count", self.listen_ports.len());
         analytics.add("ignore_localhost", self.ignore_localhost);
+        analytics.add("intercept_loopback", self.intercept_loopback);
         analytics.add("ignore_ports_count", self.ignore_ports.len());
+        analytics.add("steal_delivery_count", self.steal_delivery.len());
         analytics.add("http", &self.http_filter);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::not_listed(IncomingConfig::default(), false)]
+    #[case::intercept_loopback(
+        IncomingConfig { intercept_loopback: true, ..Default::default() },
+        true
+    )]
+    #[case::ports(
+        IncomingConfig { ports: Some(PortRanges::from_iter([8080..=8080])), ..Default::default() },
+        true
+    )]
+    #[case::other_ports(
+        IncomingConfig { ports: Some(PortRanges::from_iter([80..=80])), ..Default::default() },
+        false
+    )]
+    #[case::port_mapping(
+        IncomingConfig { port_mapping: [(8080, 80)].into_iter().collect(), ..Default::default() },
+        true
+    )]
+    #[case::mapped_ports(
+        IncomingConfig {
+            port_mapping: [(8080, 80)].into_iter().collect(),
+            ports: Some(PortRanges::from_iter([80..=80])),
+            ..Default::default()
+        },
+        true
+    )]
+    #[case::listen_ports(
+        IncomingConfig { listen_ports: [(8080, 4480)].into_iter().collect(), ..Default::default() },
+        true
+    )]
+    fn loopback_port(#[case] config: IncomingConfig, #[case] intercepted: bool) {
+        assert_eq!(config.intercepts_loopback_port(8080), intercepted);
+    }
+
+    #[rstest]
+    #[case::port(PortOrRange::Port(8080), Some(8080..=8080))]
+    #[case::range(PortOrRange::Range("8000-8100".into()), Some(8000..=8100))]
+    #[case::single(PortOrRange::Range("8080".into()), Some(8080..=8080))]
+    #[case::reversed(PortOrRange::Range("8100-8000".into()), None)]
+    #[case::invalid(PortOrRange::Range("8000-".into()), None)]
+    fn port_or_range(#[case] entry: PortOrRange, #[case] expected: Option<RangeInclusive<u16>>) {
+        assert_eq!(entry.ports().ok(), expected);
+    }
+
+    #[test]
+    fn ports_with_ranges() {
+        let config = serde_json::from_value::<IncomingFileConfig>(serde_json::json!({
+            "ports": [80, "8000-8002"]
+        }))
+        .unwrap()
+        .generate_config(&mut ConfigContext::default())
+        .unwrap();
+
+        assert_eq!(
+            config.ports,
+            Some(PortRanges::from_iter([80..=80, 8000..=8002]))
+        );
+    }
+
+    #[rstest]
+    #[case(80, true)]
+    #[case(8000, true)]
+    #[case(8050, true)]
+    #[case(8100, true)]
+    #[case(8101, false)]
+    #[case(81, false)]
+    fn port_ranges_contains(#[case] port: u16, #[case] contained: bool) {
+        let ports = PortRanges::from_iter([80..=80, 8000..=8100]);
+
+        assert_eq!(ports.contains(port), contained);
+    }
+}
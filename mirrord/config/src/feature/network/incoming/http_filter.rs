@@ -32,6 +32,9 @@ use crate::{
 ///   "path_filter": "host: api\..+",
 /// }
 /// ```
+///
+/// Changes of `header_filter` or `path_filter` in the config file are applied while mirrord runs,
+/// without restarting the application. Other changes take effect after a restart.
 #[derive(MirrordConfig, Default, PartialEq, Eq, Clone, Debug)]
 #[config(map_to = "HttpFilterFileConfig", derive = "JsonSchema")]
 #[cfg_attr(test, config(derive = "PartialEq, Eq"))]
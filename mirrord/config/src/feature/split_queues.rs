@@ -0,0 +1,363 @@
+//! Config for the `split queues` feature, which lets multiple users consume messages from the same
+//! queue without stealing each other's messages.
+
+use std::collections::{BTreeMap, HashSet};
+
+use mirrord_analytics::CollectAnalytics;
+use mirrord_config_derive::MirrordConfig;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{source::MirrordConfigSource, ConfigError};
+
+/// Allows the user to consume only the messages meant for them from queues that are also consumed
+/// by the target (and other users). The operator takes over the queue and routes each message
+/// either to the target or to the mirrord session whose filter matches the message. This feature
+/// requires a [mirrord operator](https://mirrord.dev/docs/overview/teams/).
+///
+/// ```json
+/// {
+///   "feature": {
+///     "split_queues": {
+///       "sqs": [
+///         {
+///           "queue": "https://sqs.eu-north-1.amazonaws.com/123456789012/orders",
+///           "message_filter": {
+///             "author": "^me$",
+///             "kind": "order-.*"
+///           }
+///         }
+///       ],
+///       "kafka": [
+///         {
+///           "topic": "orders",
+///           "consumer_group": "order-processor",
+///           "header_filter": {
+///             "author": "^me$"
+///           }
+///         }
+///       ]
+///     }
+///   }
+/// }
+/// ```
+#[derive(MirrordConfig, Clone, Debug)]
+#[config(map_to = "SplitQueuesFileConfig", derive = "JsonSchema")]
+#[cfg_attr(test, config(derive = "PartialEq, Eq"))]
+pub struct SplitQueuesConfig {
+    /// ### feature.split_queues.sqs {#feature-split_queues-sqs}
+    ///
+    /// Amazon SQS queues to split, see [`SqsQueueConfig`].
+    #[config(default)]
+    pub sqs: Vec<SqsQueueConfig>,
+
+    /// ### feature.split_queues.kafka {#feature-split_queues-kafka}
+    ///
+    /// Kafka topics to split, see [`KafkaTopicConfig`].
+    #[config(default)]
+    pub kafka: Vec<KafkaTopicConfig>,
+}
+
+impl SplitQueuesConfig {
+    /// Returns whether any queue is configured to be split.
+    pub fn is_enabled(&self) -> bool {
+        !self.sqs.is_empty() || !self.kafka.is_empty()
+    }
+
+    /// Validates the configured queues, so that the user does not find out about a typo only when
+    /// the operator rejects the session.
+    pub fn verify(&self) -> Result<(), ConfigError> {
+        let mut seen = HashSet::new();
+
+        for queue in &self.sqs {
+            queue.verify()?;
+
+            if !seen.insert(queue.queue.as_str()) {
+                return Err(ConfigError::Conflict(format!(
+                    "SQS queue `{}` is configured to be split more than once",
+                    queue.queue
+                )));
+            }
+        }
+
+        let mut seen = HashSet::new();
+
+        for topic in &self.kafka {
+            topic.verify()?;
+
+            if !seen.insert((topic.topic.as_str(), topic.consumer_group.as_str())) {
+                return Err(ConfigError::Conflict(format!(
+                    "Kafka topic `{}` is configured to be split more than once for consumer group \
+                    `{}`",
+                    topic.topic, topic.consumer_group
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A single Amazon SQS queue to split.
+///
+/// Messages whose attributes match all the regexes in `message_filter` are routed to this mirrord
+/// session.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SqsQueueConfig {
+    /// Name or URL of the queue, e.g. `orders` or
+    /// `https://sqs.eu-north-1.amazonaws.com/123456789012/orders`.
+    pub queue: String,
+
+    /// Maps names of message attributes to regexes the attribute values must match.
+    pub message_filter: BTreeMap<String, String>,
+}
+
+impl SqsQueueConfig {
+    /// Maximum length of an SQS queue name.
+    const MAX_NAME_LENGTH: usize = 80;
+
+    /// Returns the name of the queue, extracted from the URL if needed.
+    pub fn queue_name(&self) -> &str {
+        if self.is_url() {
+            self.queue
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+        } else {
+            &self.queue
+        }
+    }
+
+    fn is_url(&self) -> bool {
+        self.queue.starts_with("https://") || self.queue.starts_with("http://")
+    }
+
+    fn verify(&self) -> Result<(), ConfigError> {
+        // Queue URLs look like `https://<endpoint>/<account-id>/<queue-name>`.
+        let valid_url = !self.is_url()
+            || self
+                .queue
+                .split("://")
+                .nth(1)
+                .map(|rest| rest.trim_end_matches('/').split('/').count() == 3)
+                .unwrap_or_default();
+
+        // Queue names can contain only alphanumeric characters, hyphens and underscores, and may
+        // end with `.fifo`.
+        let name = self.queue_name();
+        let valid_name = (1..=Self::MAX_NAME_LENGTH).contains(&name.len())
+            && name
+                .strip_suffix(".fifo")
+                .unwrap_or(name)
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid_url || !valid_name {
+            return Err(ConfigError::InvalidValue(
+                self.queue.clone(),
+                "feature.split_queues.sqs.queue",
+            ));
+        }
+
+        if self.message_filter.is_empty() {
+            return Err(ConfigError::Conflict(format!(
+                "message_filter of SQS queue `{}` is empty, mirrord would consume all of its \
+                messages, please specify at least one message attribute to filter on",
+                self.queue
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A single Kafka topic to split.
+///
+/// Records consumed by `consumer_group` whose headers match all the regexes in `header_filter`
+/// are routed to this mirrord session.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaTopicConfig {
+    /// Name of the topic.
+    pub topic: String,
+
+    /// Consumer group of the target that consumes the topic.
+    pub consumer_group: String,
+
+    /// Maps names of record headers to regexes the header values must match.
+    pub header_filter: BTreeMap<String, String>,
+}
+
+impl KafkaTopicConfig {
+    /// Maximum length of a Kafka topic name.
+    const MAX_TOPIC_LENGTH: usize = 249;
+
+    fn verify(&self) -> Result<(), ConfigError> {
+        // Topic names can contain only alphanumeric characters, `.`, `_` and `-`.
+        let valid_topic = (1..=Self::MAX_TOPIC_LENGTH).contains(&self.topic.len())
+            && self.topic != "."
+            && self.topic != ".."
+            && self
+                .topic
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+
+        if !valid_topic {
+            return Err(ConfigError::InvalidValue(
+                self.topic.clone(),
+                "feature.split_queues.kafka.topic",
+            ));
+        }
+
+        if self.consumer_group.is_empty() {
+            return Err(ConfigError::InvalidValue(
+                self.consumer_group.clone(),
+                "feature.split_queues.kafka.consumer_group",
+            ));
+        }
+
+        if self.header_filter.is_empty() {
+            return Err(ConfigError::Conflict(format!(
+                "header_filter of Kafka topic `{}` is empty, mirrord would consume all of its \
+                records, please specify at least one record header to filter on",
+                self.topic
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl CollectAnalytics for &SplitQueuesConfig {
+    fn collect_analytics(&self, analytics: &mut mirrord_analytics::Analytics) {
+        analytics.add("sqs_queue_count", self.sqs.len());
+        analytics.add("kafka_topic_count", self.kafka.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn queue(queue: &str, filter: &[(&str, &str)]) -> SqsQueueConfig {
+        SqsQueueConfig {
+            queue: queue.to_string(),
+            message_filter: filter
+                .iter()
+                .map(|(name, regex)| (name.to_string(), regex.to_string()))
+                .collect(),
+        }
+    }
+
+    #[rstest]
+    #[case::name("orders", &[("author", "^me$")], true)]
+    #[case::fifo("orders.fifo", &[("author", "^me$")], true)]
+    #[case::url(
+        "https://sqs.eu-north-1.amazonaws.com/123456789012/orders",
+        &[("author", "^me$")],
+        true
+    )]
+    #[case::empty("", &[("author", "^me$")], false)]
+    #[case::space("my orders", &[("author", "^me$")], false)]
+    #[case::too_long(&"a".repeat(81), &[("author", "^me$")], false)]
+    #[case::url_without_account(
+        "https://sqs.eu-north-1.amazonaws.com/orders",
+        &[("author", "^me$")],
+        false
+    )]
+    #[case::no_filter("orders", &[], false)]
+    fn verify_queues(#[case] name: &str, #[case] filter: &[(&str, &str)], #[case] valid: bool) {
+        let config = SplitQueuesConfig {
+            sqs: vec![queue(name, filter)],
+            kafka: vec![],
+        };
+
+        assert_eq!(config.verify().is_ok(), valid);
+    }
+
+    #[test]
+    fn duplicate_queues() {
+        let config = SplitQueuesConfig {
+            sqs: vec![
+                queue("orders", &[("author", "^me$")]),
+                queue("orders", &[("kind", "order-.*")]),
+            ],
+            kafka: vec![],
+        };
+
+        assert!(matches!(config.verify(), Err(ConfigError::Conflict(..))));
+    }
+
+    fn topic(topic: &str, consumer_group: &str, filter: &[(&str, &str)]) -> KafkaTopicConfig {
+        KafkaTopicConfig {
+            topic: topic.to_string(),
+            consumer_group: consumer_group.to_string(),
+            header_filter: filter
+                .iter()
+                .map(|(name, regex)| (name.to_string(), regex.to_string()))
+                .collect(),
+        }
+    }
+
+    #[rstest]
+    #[case::name("orders", "processor", &[("author", "^me$")], true)]
+    #[case::all_characters("orders.v2_eu-north", "processor", &[("author", "^me$")], true)]
+    #[case::empty("", "processor", &[("author", "^me$")], false)]
+    #[case::dots("..", "processor", &[("author", "^me$")], false)]
+    #[case::slash("orders/v2", "processor", &[("author", "^me$")], false)]
+    #[case::no_consumer_group("orders", "", &[("author", "^me$")], false)]
+    #[case::no_filter("orders", "processor", &[], false)]
+    fn verify_topics(
+        #[case] name: &str,
+        #[case] consumer_group: &str,
+        #[case] filter: &[(&str, &str)],
+        #[case] valid: bool,
+    ) {
+        let config = SplitQueuesConfig {
+            sqs: vec![],
+            kafka: vec![topic(name, consumer_group, filter)],
+        };
+
+        assert_eq!(config.verify().is_ok(), valid);
+    }
+
+    #[test]
+    fn duplicate_topics() {
+        let filter = [("author", "^me$")];
+
+        let config = SplitQueuesConfig {
+            sqs: vec![],
+            kafka: vec![
+                topic("orders", "processor", &filter),
+                topic("orders", "auditor", &filter),
+            ],
+        };
+        assert!(config.verify().is_ok());
+
+        let config = SplitQueuesConfig {
+            sqs: vec![],
+            kafka: vec![
+                topic("orders", "processor", &filter),
+                topic("orders", "processor", &filter),
+            ],
+        };
+        assert!(matches!(config.verify(), Err(ConfigError::Conflict(..))));
+    }
+
+    #[test]
+    fn queue_name_from_url() {
+        assert_eq!(
+            queue(
+                "https://sqs.eu-north-1.amazonaws.com/123456789012/orders/",
+                &[]
+            )
+            .queue_name(),
+            "orders"
+        );
+        assert_eq!(queue("orders", &[]).queue_name(), "orders");
+    }
+}
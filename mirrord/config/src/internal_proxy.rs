@@ -1,3 +1,5 @@
+use std::{net::SocketAddr, path::PathBuf};
+
 use mirrord_config_derive::MirrordConfig;
 use schemars::JsonSchema;
 
@@ -35,7 +37,7 @@ pub struct InternalProxyConfig {
     ///   }
     /// }
     /// ```
-    #[config(default = 60)]
+    #[config(env = "MIRRORD_INTPROXY_START_IDLE_TIMEOUT", default = 60)]
     pub start_idle_timeout: u64,
 
     /// ### internal_proxy.idle_timeout {#internal_proxy-idle_timeout}
@@ -52,7 +54,11 @@ pub struct InternalProxyConfig {
     ///   }
     /// }
     /// ```
-    #[config(default = 5)]
+    ///
+    /// `mirrord exec --watch` keeps the session while no process is running, until the watch is
+    /// stopped. `mirrord daemon` disables it, the session is kept until
+    /// `mirrord daemon stop`.
+    #[config(env = "MIRRORD_INTPROXY_IDLE_TIMEOUT", default = 5)]
     pub idle_timeout: u64,
 
     /// ### internal_proxy.log_level {#internal_proxy-log_level}
@@ -64,4 +70,121 @@ pub struct InternalProxyConfig {
     /// ### internal_proxy.log_destination {#internal_proxy-log_destination}
     /// Set the log file destination for the internal proxy.
     pub log_destination: Option<String>,
+
+    /// ### internal_proxy.operator_keepalive_interval {#internal_proxy-operator_keepalive_interval}
+    ///
+    /// How often (in seconds) to send websocket pings on an idle connection to the mirrord
+    /// operator, so that load balancers between us and the cluster don't drop it (e.g. while
+    /// the application is stopped on a breakpoint).
+    ///
+    /// When nothing is received from the operator for 3 intervals, the connection is considered
+    /// dead and closed. Set to `0` to disable.
+    ///
+    /// Defaults to `30`.
+    ///
+    /// ```json
+    /// {
+    ///   "internal_proxy": {
+    ///     "operator_keepalive_interval": 15
+    ///   }
+    /// }
+    /// ```
+    #[config(default = 30)]
+    pub operator_keepalive_interval: u64,
+
+    /// ### internal_proxy.connection_buffer_size {#internal_proxy-connection_buffer_size}
+    ///
+    /// How many messages can be queued for every intercepted connection (incoming or outgoing),
+    /// between the internal proxy and the local socket, in both directions.
+    ///
+    /// Defaults to `512`.
+    ///
+    /// ```json
+    /// {
+    ///   "internal_proxy": {
+    ///     "connection_buffer_size": 1024
+    ///   }
+    /// }
+    /// ```
+    #[config(default = 512)]
+    pub connection_buffer_size: usize,
+
+    /// ### internal_proxy.connection_high_watermark {#internal_proxy-connection_high_watermark}
+    ///
+    /// How many bytes received from the agent can be queued for the local socket of every
+    /// intercepted connection (incoming or outgoing).
+    ///
+    /// When a slow local process doesn't read fast enough, the internal proxy asks the agent to
+    /// pause the connection until it does, so that the remote peer is slowed down, instead of
+    /// buffering without a limit. The other connections are not affected. Mirrored connections
+    /// can't be paused, they are closed when they fall behind by more than this, and datagrams
+    /// over this are dropped.
+    ///
+    /// Defaults to `16777216` (16 MiB).
+    ///
+    /// ```json
+    /// {
+    ///   "internal_proxy": {
+    ///     "connection_high_watermark": 4194304
+    ///   }
+    /// }
+    /// ```
+    #[config(default = 16777216)]
+    pub connection_high_watermark: usize,
+
+    /// ### internal_proxy.metrics {#internal_proxy-metrics}
+    ///
+    /// Local address where the internal proxy serves Prometheus metrics of the session: stolen,
+    /// mirrored and outgoing traffic, remote file operations, requests waiting for the agent, and
+    /// how long the agent and the local application take to respond.
+    ///
+    /// Disabled by default.
+    ///
+    /// ```json
+    /// {
+    ///   "internal_proxy": {
+    ///     "metrics": "127.0.0.1:9100"
+    ///   }
+    /// }
+    /// ```
+    pub metrics: Option<SocketAddr>,
+
+    /// ### internal_proxy.traffic_log {#internal_proxy-traffic_log}
+    ///
+    /// Path of a file where the internal proxy logs every intercepted connection and every HTTP
+    /// request stolen with a filter, as lines of JSON, regardless of `RUST_LOG`.
+    ///
+    /// Every line is written when the connection or request ends, with when it started, its
+    /// duration, the remote peer or the port of the target, the filter the request matched, and
+    /// the bytes received and sent. The values of the query parameters are left out of the logged
+    /// paths, and the file is only readable by the user.
+    ///
+    /// ```json
+    /// {
+    ///   "internal_proxy": {
+    ///     "traffic_log": "/tmp/mirrord-traffic.log"
+    ///   }
+    /// }
+    /// ```
+    pub traffic_log: Option<PathBuf>,
+
+    /// ### internal_proxy.unix_socket {#internal_proxy-unix_socket}
+    ///
+    /// Whether the internal proxy accepts the connections of the local processes on a Unix socket
+    /// in the temporary directory, instead of a localhost TCP port.
+    ///
+    /// Useful when many mirrord sessions run at the same time, since they don't compete for
+    /// ports, and on shared machines, since only the user can connect to the socket.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// ```json
+    /// {
+    ///   "internal_proxy": {
+    ///     "unix_socket": true
+    ///   }
+    /// }
+    /// ```
+    #[config(default = false)]
+    pub unix_socket: bool,
 }
@@ -8,24 +8,32 @@
 //! Remember to re-generate the `mirrord-schema.json` if you make **ANY** changes to this lib,
 //! including if you only made documentation changes.
 pub mod agent;
+pub mod auth;
+pub mod conditional;
 pub mod config;
+pub mod experimental;
 pub mod feature;
 pub mod internal_proxy;
 pub mod target;
 pub mod util;
 
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use config::{ConfigContext, ConfigError, MirrordConfig};
 use mirrord_analytics::CollectAnalytics;
 use mirrord_config_derive::MirrordConfig;
 use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tera::Tera;
 use tracing::warn;
 
 use crate::{
-    agent::AgentConfig, config::source::MirrordConfigSource, feature::FeatureConfig,
-    internal_proxy::InternalProxyConfig, target::TargetConfig, util::VecOrSingle,
+    agent::AgentConfig, auth::AuthConfig, config::source::MirrordConfigSource,
+    experimental::ExperimentalFeature, feature::FeatureConfig, internal_proxy::InternalProxyConfig,
+    target::TargetConfig, util::VecOrSingle,
 };
 
 const PAUSE_WITHOUT_STEAL_WARNING: &str =
@@ -178,7 +186,7 @@ pub struct LayerConfig {
     /// Allows mirrord to skip unwanted processes.
     ///
     /// Useful when process A spawns process B, and the user wants mirrord to operate only on
-    /// process B.
+    /// process B. See also [`feature.only_processes`](#feature-only_processes).
     /// Accepts a single value, or multiple values separated by `;`.
     ///
     ///```json
@@ -230,6 +238,19 @@ pub struct LayerConfig {
     #[config(env = "MIRRORD_CONNECT_TCP")]
     pub connect_tcp: Option<String>,
 
+    /// ## connect_unix {#root-connect_unix}
+    ///
+    /// Path of the Unix socket of the internal proxy, which the layer connects to instead of
+    /// `connect_tcp`. Set by mirrord when `internal_proxy.unix_socket` is enabled.
+    ///
+    /// ```json
+    /// {
+    ///   "connect_unix": "/tmp/mirrord-intproxy-1234.sock"
+    /// }
+    /// ```
+    #[config(env = "MIRRORD_CONNECT_UNIX")]
+    pub connect_unix: Option<String>,
+
     /// ## operator {#root-operator}
     ///
     /// Whether mirrord should use the operator.
@@ -238,6 +259,49 @@ pub struct LayerConfig {
     #[config(env = "MIRRORD_OPERATOR_ENABLE")]
     pub operator: Option<bool>,
 
+    /// ## on_agent_loss {#root-on_agent_loss}
+    ///
+    /// What the local process does when the connection with the agent is lost for good (the
+    /// internal proxy gave up reconnecting):
+    ///
+    /// - `"fail"` kills the process.
+    /// - `"degrade"` keeps the process running locally, with a warning: new files are opened
+    ///   locally, outgoing connections and DNS queries go local, and no more incoming traffic
+    ///   arrives. Operations on files, directories and sockets that were handled remotely before
+    ///   fail with `EIO`.
+    ///
+    /// Defaults to `"fail"`.
+    ///
+    /// ```json
+    /// {
+    ///   "on_agent_loss": "degrade"
+    /// }
+    /// ```
+    #[config(default)]
+    pub on_agent_loss: OnAgentLoss,
+
+    /// ## on_layer_error {#root-on_layer_error}
+    ///
+    /// What the local process does when mirrord fails internally in it, e.g. on an error in the
+    /// protocol with the internal proxy, or a bug:
+    ///
+    /// - `"fail"` kills the process.
+    /// - `"degrade"` disables mirrord for the rest of the process, with an error message: new
+    ///   files, connections and DNS queries are handled locally, as without mirrord. Files,
+    ///   directories and sockets that were handled remotely before fail with `EIO`. This is best
+    ///   effort: the failure may have left mirrord or the process in an inconsistent state, and
+    ///   the process may still misbehave or crash.
+    ///
+    /// Defaults to `"fail"`.
+    ///
+    /// ```json
+    /// {
+    ///   "on_layer_error": "degrade"
+    /// }
+    /// ```
+    #[config(default)]
+    pub on_layer_error: OnLayerError,
+
     /// ## kubeconfig {#root-kubeconfig}
     ///
     /// Path to a kubeconfig file, if not specified, will use `KUBECONFIG`, or `~/.kube/config`, or
@@ -314,6 +378,236 @@ pub struct LayerConfig {
     /// If the remote pod sets this env, the mirrord process will still use it.
     #[config(env = "MIRRORD_PROXY", default = true)]
     pub use_proxy: bool,
+
+    /// ## proxy {#root-proxy}
+    ///
+    /// Proxy to use when connecting to the Kubernetes API server and the mirrord operator.
+    ///
+    /// When not set, mirrord uses the `proxy-url` from the kubeconfig, or the `HTTPS_PROXY`
+    /// (`HTTP_PROXY` for plain http clusters) env variable, unless the cluster host is listed
+    /// in `NO_PROXY`. Connections that are upgraded (port forwarding to the agent, the operator
+    /// websocket) are tunneled through the proxy with `CONNECT`.
+    ///
+    /// Besides `http://` proxies, this can be a `socks5://host:port` proxy, or an ssh bastion
+    /// (jump host) as `ssh://[user@]host[:port]`. mirrord reaches the cluster through the bastion
+    /// by running `ssh -W`, so your ssh config and keys are used, but ssh can't prompt for
+    /// passwords or unknown host keys.
+    ///
+    /// ```json
+    /// {
+    ///   "proxy": "ssh://jump@bastion.corp.example"
+    /// }
+    /// ```
+    #[config(env = "MIRRORD_PROXY_URL")]
+    pub proxy: Option<String>,
+
+    /// ## kube_api_url {#root-kube_api_url}
+    ///
+    /// URL of a [`kubectl proxy`](https://kubernetes.io/docs/reference/kubectl/generated/kubectl_proxy/)
+    /// (or another API server proxy that authenticates for us) to send all the Kubernetes API
+    /// requests to, including the port forwarding to the agent. For environments where mirrord
+    /// can't authenticate to the API server directly.
+    ///
+    /// The cluster address and credentials from the kubeconfig are not used, only its default
+    /// namespace. Note that `kubectl proxy` rejects `exec` requests unless started with
+    /// `--reject-paths`, so [`agent.connection`](#agent-connection) should stay
+    /// `"port_forward"`.
+    ///
+    /// ```json
+    /// {
+    ///   "kube_api_url": "http://127.0.0.1:8001"
+    /// }
+    /// ```
+    #[config(env = "MIRRORD_KUBE_API_URL")]
+    pub kube_api_url: Option<String>,
+
+    /// ## otlp_endpoint {#root-otlp_endpoint}
+    ///
+    /// OTLP (gRPC) endpoint of an OpenTelemetry collector to export the traces and metrics of
+    /// mirrord sessions to, e.g. to observe mirrord usage in Grafana or Jaeger.
+    ///
+    /// The spans cover the session setup, the creation of the agent and the handling of stolen
+    /// requests, and the counters mirror the [telemetry](#root-telemetry) events. Nothing is
+    /// exported when not set. This doesn't depend on [`telemetry`](#root-telemetry), the data
+    /// only goes to this endpoint.
+    ///
+    /// ```json
+    /// {
+    ///   "otlp_endpoint": "http://otel-collector.example:4317"
+    /// }
+    /// ```
+    #[config(env = "MIRRORD_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// # auth {#root-auth}
+    #[config(nested)]
+    pub auth: AuthConfig,
+
+    /// ## profiles {#root-profiles}
+    ///
+    /// Named variants of this config, to keep e.g. a mirroring and a stealing setup in one file.
+    ///
+    /// Select a profile with `mirrord exec --profile <name>` or the `MIRRORD_PROFILE` env
+    /// variable. The selected profile is merged into the rest of the file: objects are merged key
+    /// by key, any other value in the profile replaces the one in the file. Env variables and CLI
+    /// flags still take precedence over both.
+    ///
+    /// ```json
+    /// {
+    ///   "target": "deployment/bear-deployment",
+    ///   "feature": {
+    ///     "network": {
+    ///       "incoming": "mirror"
+    ///     }
+    ///   },
+    ///   "profiles": {
+    ///     "steal-with-filter": {
+    ///       "feature": {
+    ///         "network": {
+    ///           "incoming": {
+    ///             "mode": "steal",
+    ///             "http_filter": { "header_filter": "x-user: bear" }
+    ///           }
+    ///         }
+    ///       }
+    ///     },
+    ///     "full-offline": {
+    ///       "feature": { "fs": "local", "env": false, "network": false }
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub profiles: Option<HashMap<String, serde_json::Value>>,
+
+    /// ## targets {#root-targets}
+    ///
+    /// Overrides of this config for specific targets, chosen by the target mirrord runs with (also
+    /// when it's selected in the IDE). The keys are target paths, where `*` matches any sequence
+    /// of characters.
+    ///
+    /// The overrides for the target are merged like [`profiles`](#root-profiles). When several
+    /// patterns match, the shorter (less specific) ones are merged first, and the ones of the same
+    /// length in alphabetical order. A selected profile is merged after them.
+    ///
+    /// ```json
+    /// {
+    ///   "feature": {
+    ///     "fs": "read"
+    ///   },
+    ///   "targets": {
+    ///     "deployment/payments*": {
+    ///       "feature": {
+    ///         "fs": "local",
+    ///         "network": {
+    ///           "incoming": {
+    ///             "mode": "steal",
+    ///             "http_filter": { "header_filter": "x-payments-debug: true" }
+    ///           }
+    ///         }
+    ///       }
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    pub targets: Option<HashMap<String, serde_json::Value>>,
+
+    /// ## extends {#root-extends}
+    ///
+    /// Other config files this one is based on, e.g. a policy shared by the whole organization.
+    /// Relative paths are resolved from the directory of this file, and the files can extend
+    /// other files too.
+    ///
+    /// The files are merged in order, and this file is merged last: objects are merged key by
+    /// key, any other value (arrays included) replaces the one from the earlier files.
+    ///
+    /// ```json
+    /// {
+    ///   "extends": ["base.mirrord.json", "team-overrides.mirrord.json"],
+    ///   "target": "deployment/bear-deployment"
+    /// }
+    /// ```
+    pub extends: Option<Vec<String>>,
+
+    /// ## conditional {#root-conditional}
+    ///
+    /// Config sections that apply only when the session matches their `when`, e.g. to keep
+    /// sessions in protected namespaces read-only. They are checked by the mirrord CLI before the
+    /// session starts, a session started without it fails when the config has conditional
+    /// sections.
+    ///
+    /// The conditions are patterns, where `*` matches any sequence of characters, and all the
+    /// conditions of a section must match:
+    ///
+    /// - `namespace`: namespace of the target (the namespace of the kube context by default);
+    /// - `context`: name of the kube context;
+    /// - `cluster`: name of the cluster of the kube context, as in the kubeconfig file;
+    /// - `target`: path of the target, e.g. `deployment/payments`.
+    ///
+    /// The matching sections are merged like [`profiles`](#root-profiles), in order, after the
+    /// [`targets`](#root-targets) overrides and the selected profile, so they can't be overridden
+    /// by them.
+    ///
+    /// ```json
+    /// {
+    ///   "conditional": [
+    ///     {
+    ///       "when": { "namespace": "prod-*" },
+    ///       "feature": {
+    ///         "fs": "read",
+    ///         "network": { "incoming": "mirror" },
+    ///         "copy_target": true
+    ///       }
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    pub conditional: Option<Vec<serde_json::Value>>,
+
+    /// ## experimental {#root-experimental}
+    ///
+    /// Opt-in for capabilities that are still in development, which may change or break. mirrord
+    /// lists the ones enabled here when it starts. Some of them are on by default, and can be
+    /// turned off here.
+    ///
+    /// The experimental features are:
+    ///
+    /// - `traversal_hooks` (on by default): traverse remote directory trees with `glob`, `nftw`
+    ///   and `fts_*` through the agent.
+    /// - `batched_dir_reads`: list remote directories in batches, filtered by name in the agent,
+    ///   when traversing them, instead of entry by entry. Needs an agent that supports it.
+    ///
+    /// ```json
+    /// {
+    ///   "experimental": {
+    ///     "batched_dir_reads": true
+    ///   }
+    /// }
+    /// ```
+    #[config(default)]
+    pub experimental: HashMap<String, bool>,
+}
+
+/// What the local process does when the agent is lost, see [`LayerConfig::on_agent_loss`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, JsonSchema, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnAgentLoss {
+    /// Kill the process.
+    #[default]
+    Fail,
+    /// Keep the process running, doing everything locally.
+    Degrade,
+}
+
+/// What the local process does when mirrord fails internally, see
+/// [`LayerConfig::on_layer_error`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, JsonSchema, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnLayerError {
+    /// Kill the process.
+    #[default]
+    Fail,
+    /// Keep the process running, with mirrord disabled.
+    Degrade,
 }
 
 impl LayerConfig {
@@ -323,7 +617,8 @@ impl LayerConfig {
     pub fn from_env_with_warnings() -> Result<(Self, ConfigContext), ConfigError> {
         let mut cfg_context = ConfigContext::default();
         if let Ok(path) = std::env::var("MIRRORD_CONFIG_FILE") {
-            LayerFileConfig::from_path(path)?.generate_config(&mut cfg_context)
+            LayerFileConfig::from_path_with_warnings(path, &mut cfg_context)?
+                .generate_config(&mut cfg_context)
         } else {
             LayerFileConfig::default().generate_config(&mut cfg_context)
         }
@@ -337,6 +632,11 @@ impl LayerConfig {
         Self::from_env_with_warnings().map(|(config, _)| config)
     }
 
+    /// Whether the user opted in to the experimental `feature`, see [`LayerConfig::experimental`].
+    pub fn is_experimental_enabled(&self, feature: ExperimentalFeature) -> bool {
+        experimental::is_enabled(&self.experimental, feature)
+    }
+
     /// Verify that there are no conflicting settings.
     ///
     /// We don't call it from `from_env` since we want to verify it only once (from cli)
@@ -356,6 +656,8 @@ impl LayerConfig {
             }
         }
 
+        experimental::verify(&self.experimental, context);
+
         if self.agent.ephemeral && self.agent.namespace.is_some() {
             context.add_warning(
                 "Agent namespace is ignored when using an ephemeral container for the agent."
@@ -363,6 +665,34 @@ impl LayerConfig {
             );
         }
 
+        if self.agent.ephemeral && self.agent.pod_template_patch.is_some() {
+            context.add_warning(
+                "`agent.pod_template_patch` is ignored when using an ephemeral container for the \
+                 agent."
+                    .to_string(),
+            );
+        }
+
+        if let Some(directive) = self
+            .agent
+            .pod_template_patch
+            .as_ref()
+            .and_then(agent::patch_directive)
+        {
+            Err(ConfigError::InvalidValue(
+                directive.to_string(),
+                "agent.pod_template_patch, strategic merge patch directives are not supported",
+            ))?
+        }
+
+        if self.agent.ephemeral && self.agent.sidecar == Some(true) {
+            Err(ConfigError::Conflict(
+                "Cannot run the agent both as an ephemeral container and in a copy of the target \
+                 pod, please disable either `agent.ephemeral` or `agent.sidecar`."
+                    .to_string(),
+            ))?
+        }
+
         if self
             .feature
             .network
@@ -383,6 +713,14 @@ impl LayerConfig {
             ))?
         }
 
+        if self.feature.remote_exec.is_some() && !self.agent.remote_exec {
+            Err(ConfigError::Conflict(
+                "`feature.remote_exec` needs an agent that allows it, please enable \
+                 `agent.remote_exec`."
+                    .to_string(),
+            ))?
+        }
+
         if !self.feature.network.incoming.ignore_ports.is_empty()
             && self.feature.network.incoming.ports.is_some()
         {
@@ -422,6 +760,38 @@ impl LayerConfig {
             }
         }
 
+        if !self.feature.fs.is_active()
+            && (self.feature.fs.config_maps.is_some() || self.feature.fs.secrets.is_some())
+        {
+            return Err(ConfigError::Conflict(
+                "ConfigMaps and Secrets are read by the fs feature, which does nothing in \
+                `local` mode, please either remove `feature.fs.config_maps` and \
+                `feature.fs.secrets` or use another `feature.fs.mode`."
+                    .into(),
+            ));
+        }
+
+        if self.feature.split_queues.is_enabled() {
+            if self.operator == Some(false) {
+                return Err(ConfigError::Conflict(
+                    "The split queues feature requires a mirrord operator, \
+                    please either disable this option or use the operator."
+                        .into(),
+                ));
+            }
+
+            // Target may also be set later in the UI.
+            if self.target.path.is_none() && !context.ide {
+                return Err(ConfigError::Conflict(
+                    "The split queues feature is not compatible with a targetless agent, \
+                    please either disable this option or specify a target."
+                        .into(),
+                ));
+            }
+
+            self.feature.split_queues.verify()?;
+        }
+
         if self.feature.copy_target.enabled {
             if self.operator == Some(false) {
                 return Err(ConfigError::Conflict(
@@ -470,23 +840,265 @@ impl CollectAnalytics for &LayerConfig {
 }
 
 impl LayerFileConfig {
+    /// Loads the config file at `path`, with the files it [extends](LayerConfig::extends), the
+    /// [overrides for the target](LayerConfig::targets), the profile from `MIRRORD_PROFILE`
+    /// (see [`LayerConfig::profiles`]) and the matching [conditional](LayerConfig::conditional)
+    /// sections merged in.
+    ///
+    /// Deprecated keys and values are [migrated](config::migrate), see
+    /// [`LayerFileConfig::from_path_with_warnings`] to report them.
     pub fn from_path<P>(path: P) -> Result<Self, ConfigError>
     where
         P: AsRef<Path>,
     {
+        Self::from_path_with_warnings(path, &mut ConfigContext::default())
+    }
+
+    /// Like [`LayerFileConfig::from_path`], adding a deprecation warning to `context` for every
+    /// deprecated key or value that was migrated.
+    pub fn from_path_with_warnings<P>(
+        path: P,
+        context: &mut ConfigContext,
+    ) -> Result<Self, ConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let rendered = Self::render(path)?;
+        let config = Self::parse::<serde_json::Value>(path, &rendered)?;
+
+        if Self::profile().is_none()
+            && config.get("extends").is_none()
+            && config.get("targets").is_none()
+            && config.get("conditional").is_none()
+            && config::migrate::migrate(&mut config.clone()).is_empty()
+        {
+            // Parsed directly, for the line numbers in the errors.
+            return Self::parse(path, &rendered);
+        }
+
+        let (mut config, target) = Self::merge_overrides(path, config, context)?;
+
+        if conditional::has_sections(&config) {
+            let environment = conditional::Environment::from_env()?;
+            for section in
+                conditional::matching_sections(&config, &environment, target.as_deref(), context)?
+            {
+                merge_config(&mut config, section);
+            }
+        }
+
+        Ok(serde_json::from_value(config)?)
+    }
+
+    /// Loads the config file at `path` as JSON, like [`LayerFileConfig::from_path`], but without
+    /// the [conditional](LayerConfig::conditional) sections merged in. The CLI resolves the
+    /// [`conditional::Environment`] the sections are checked against from it.
+    pub fn unconditional_value_from_path<P>(path: P) -> Result<serde_json::Value, ConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let config = Self::parse(path, &Self::render(path)?)?;
+
+        Self::merge_overrides(path, config, &mut ConfigContext::default()).map(|(config, _)| config)
+    }
+
+    /// The profile selected with `MIRRORD_PROFILE`, see [`LayerConfig::profiles`].
+    fn profile() -> Option<String> {
+        std::env::var("MIRRORD_PROFILE")
+            .ok()
+            .filter(|profile| !profile.is_empty())
+    }
+
+    /// Merges the files `config` (loaded from `path`) extends, the overrides for the target and
+    /// the selected profile into it, and migrates the deprecated keys, adding a deprecation
+    /// warning to `context` for each one. Returns the merged config, with the target mirrord runs
+    /// with.
+    fn merge_overrides(
+        path: &Path,
+        config: serde_json::Value,
+        context: &mut ConfigContext,
+    ) -> Result<(serde_json::Value, Option<String>), ConfigError> {
+        let mut visited = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+        let mut config = Self::resolve_extends(path, config, &mut visited)?;
+
+        for deprecation in config::migrate::migrate(&mut config) {
+            context.add_deprecation(deprecation);
+        }
+
+        let target = std::env::var("MIRRORD_IMPERSONATED_TARGET")
+            .ok()
+            .or_else(|| {
+                let target = config.get("target")?;
+                target
+                    .as_str()
+                    .or_else(|| target.get("path")?.as_str())
+                    .map(String::from)
+            });
+
+        if let Some(targets) = config.get("targets").and_then(serde_json::Value::as_object) {
+            // Less specific (shorter) patterns first, so that the more specific ones win, and the
+            // ones of the same length in a stable order, whatever the order of the keys.
+            let mut overrides = targets
+                .iter()
+                .filter(|(pattern, _)| {
+                    target
+                        .as_deref()
+                        .is_some_and(|target| target_matches(pattern, target))
+                })
+                .collect::<Vec<_>>();
+            overrides.sort_by_key(|(pattern, _)| (pattern.len(), *pattern));
+            let overrides = overrides
+                .into_iter()
+                .map(|(_, overrides)| overrides.clone())
+                .collect::<Vec<_>>();
+
+            for target_overrides in overrides {
+                merge_config(&mut config, target_overrides);
+            }
+        }
+
+        if let Some(profile) = Self::profile() {
+            let overrides = config
+                .get("profiles")
+                .and_then(|profiles| profiles.get(&profile))
+                .cloned()
+                .ok_or_else(|| {
+                    let available = config
+                        .get("profiles")
+                        .and_then(serde_json::Value::as_object)
+                        .map(|profiles| profiles.keys().cloned().collect())
+                        .unwrap_or_default();
+
+                    ConfigError::ProfileNotFound(profile, available)
+                })?;
+            merge_config(&mut config, overrides);
+        }
+
+        Ok((config, target))
+    }
+
+    /// Loads the config file at `path` as JSON, with the files it [extends](LayerConfig::extends)
+    /// merged in and the deprecated keys [migrated](config::migrate), but without the
+    /// [target overrides](LayerConfig::targets), a [profile](LayerConfig::profiles) or the
+    /// [conditional](LayerConfig::conditional) sections.
+    pub fn value_from_path<P>(path: P) -> Result<serde_json::Value, ConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let config = Self::parse(path, &Self::render(path)?)?;
+
+        let mut visited = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+        let mut config = Self::resolve_extends(path, config, &mut visited)?;
+        config::migrate::migrate(&mut config);
+
+        Ok(config)
+    }
+
+    /// Merges the files listed in the `extends` of `config` (loaded from `path`) under it, in
+    /// order. `visited` holds the files we're in the middle of loading, to detect cycles.
+    fn resolve_extends(
+        path: &Path,
+        config: serde_json::Value,
+        visited: &mut Vec<PathBuf>,
+    ) -> Result<serde_json::Value, ConfigError> {
+        let Some(extends) = config.get("extends").cloned() else {
+            return Ok(config);
+        };
+        let extends = serde_json::from_value::<Vec<String>>(extends)?;
+
+        let mut merged = serde_json::Value::Object(Default::default());
+        for base in extends {
+            // Relative to the file that extends it.
+            let base = path.parent().unwrap_or(Path::new("")).join(base);
+
+            let canonical = base.canonicalize().unwrap_or_else(|_| base.clone());
+            if visited.contains(&canonical) {
+                let mut cycle = visited.clone();
+                cycle.push(canonical);
+                return Err(ConfigError::ExtendsCycle(cycle));
+            }
+
+            visited.push(canonical);
+            let base_config = Self::render(&base)
+                .and_then(|rendered| Self::parse(&base, &rendered))
+                .and_then(|base_config| Self::resolve_extends(&base, base_config, visited))
+                .map_err(|fail| match fail {
+                    ConfigError::ExtendsCycle(..) => fail,
+                    fail => ConfigError::Extends(base.clone(), Box::new(fail)),
+                })?;
+            visited.pop();
+
+            merge_config(&mut merged, base_config);
+        }
+        merge_config(&mut merged, config);
+
+        Ok(merged)
+    }
+
+    fn render(path: &Path) -> Result<String, ConfigError> {
         let mut template_engine = Tera::default();
-        template_engine.add_template_file(path.as_ref(), Some("main"))?;
-        let rendered = template_engine.render("main", &tera::Context::new())?;
+        template_engine.add_template_file(path, Some("main"))?;
 
-        match path.as_ref().extension().and_then(|os_val| os_val.to_str()) {
-            Some("json") => Ok(serde_json::from_str::<Self>(&rendered)?),
-            Some("toml") => Ok(toml::from_str::<Self>(&rendered)?),
-            Some("yaml" | "yml") => Ok(serde_yaml::from_str::<Self>(&rendered)?),
+        Ok(template_engine.render("main", &tera::Context::new())?)
+    }
+
+    fn parse<T>(path: &Path, rendered: &str) -> Result<T, ConfigError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match path.extension().and_then(|os_val| os_val.to_str()) {
+            Some("json") => Ok(serde_json::from_str::<T>(rendered)?),
+            Some("toml") => Ok(toml::from_str::<T>(rendered)?),
+            Some("yaml" | "yml") => Ok(serde_yaml::from_str::<T>(rendered)?),
             _ => Err(ConfigError::UnsupportedFormat),
         }
     }
 }
 
+/// Whether the `target` (e.g. `deployment/payments/container/app`) matches the `pattern` from
+/// [`LayerConfig::targets`], where `*` matches any sequence of characters. Also used for the
+/// patterns of [`LayerConfig::conditional`].
+fn target_matches(pattern: &str, target: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == target;
+    };
+    let Some(target) = target.strip_prefix(prefix) else {
+        return false;
+    };
+
+    target
+        .char_indices()
+        .map(|(index, _)| index)
+        .chain([target.len()])
+        .any(|index| {
+            target
+                .get(index..)
+                .is_some_and(|target| target_matches(rest, target))
+        })
+}
+
+/// Merges `overrides` (a file that [extends](LayerConfig::extends) the `config`, or a
+/// [profile](LayerConfig::profiles)) into the `config`: objects are merged key by key, other
+/// values (arrays included) replace the ones in `config`.
+fn merge_config(config: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (config, overrides) {
+        (serde_json::Value::Object(config), serde_json::Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match config.get_mut(&key) {
+                    Some(existing) => merge_config(existing, value),
+                    None => {
+                        config.insert(key, value);
+                    }
+                }
+            }
+        }
+        (config, overrides) => *config = overrides,
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -682,6 +1294,8 @@ mod tests {
                     container: None,
                 })),
                 namespace: Some("default".to_owned()),
+                container_index: None,
+                kube_context: None,
             }),
             skip_processes: None,
             skip_build_tools: None,
@@ -698,11 +1312,17 @@ mod tests {
                 ttl: Some(60),
                 ephemeral: Some(false),
                 communication_timeout: None,
+                pause_timeout: None,
+                kube_context: None,
+                connection: None,
                 startup_timeout: None,
                 network_interface: None,
                 flush_connections: Some(false),
                 disabled_capabilities: None,
                 tolerations: None,
+                node_selector: None,
+                affinity: None,
+                priority_class_name: None,
                 check_out_of_pods: None,
                 resources: None,
                 nftables: None,
@@ -713,16 +1333,20 @@ mod tests {
                 fs: ToggleableConfig::Config(FsUserConfig::Simple(FsModeConfig::Write)).into(),
                 network: Some(ToggleableConfig::Config(NetworkFileConfig {
                     dns: Some(false),
+                    cluster_names: None,
+                    ipv6: None,
                     incoming: Some(ToggleableConfig::Config(IncomingFileConfig::Advanced(
                         Box::new(IncomingAdvancedFileConfig {
                             mode: Some(IncomingMode::Mirror),
                             http_filter: None,
                             port_mapping: None,
                             ignore_localhost: None,
+                            intercept_loopback: None,
                             ignore_ports: None,
                             listen_ports: None,
                             on_concurrent_steal: None,
                             ports: None,
+                            steal_delivery: None,
                         }),
                     ))),
                     outgoing: Some(ToggleableConfig::Config(OutgoingFileConfig {
@@ -732,19 +1356,239 @@ mod tests {
                     })),
                 })),
                 copy_target: None,
+                split_queues: None,
                 hostname: None,
+                identity: None,
+                remote_exec: None,
+                only_processes: None,
             }),
             connect_tcp: None,
+            connect_unix: None,
             operator: None,
+            on_agent_loss: None,
+            on_layer_error: None,
             sip_binaries: None,
             kube_context: None,
             internal_proxy: None,
             use_proxy: None,
+            proxy: None,
+            kube_api_url: None,
+            otlp_endpoint: None,
+            auth: None,
+            profiles: None,
+            targets: None,
+            extends: None,
+            conditional: None,
+            experimental: None,
         };
 
         assert_eq!(config, expect);
     }
 
+    #[test]
+    fn profiles() {
+        let path =
+            std::env::temp_dir().join(format!("mirrord-profiles-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            {
+                "target": "pod/bear-pod",
+                "feature": {
+                    "fs": "read",
+                    "network": { "incoming": "mirror" }
+                },
+                "profiles": {
+                    "steal": {
+                        "feature": {
+                            "network": { "incoming": { "mode": "steal" } }
+                        }
+                    }
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        crate::util::testing::with_env_vars(vec![("MIRRORD_PROFILE", Some("steal"))], || {
+            let config = LayerFileConfig::from_path(&path)
+                .unwrap()
+                .generate_config(&mut ConfigContext::default())
+                .unwrap();
+
+            assert!(config.feature.network.incoming.is_steal());
+            assert_eq!(config.feature.fs.mode, FsModeConfig::Read);
+        });
+
+        crate::util::testing::with_env_vars(vec![("MIRRORD_PROFILE", None)], || {
+            let config = LayerFileConfig::from_path(&path)
+                .unwrap()
+                .generate_config(&mut ConfigContext::default())
+                .unwrap();
+
+            assert!(!config.feature.network.incoming.is_steal());
+        });
+
+        crate::util::testing::with_env_vars(vec![("MIRRORD_PROFILE", Some("offline"))], || {
+            let error = LayerFileConfig::from_path(&path).unwrap_err();
+
+            assert!(
+                matches!(&error, ConfigError::ProfileNotFound(profile, available) if profile == "offline" && available == &["steal"]),
+                "{error:?}"
+            );
+        });
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn extends() {
+        let dir = std::env::temp_dir().join(format!("mirrord-extends-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("policy")).unwrap();
+        std::fs::write(
+            dir.join("policy/base.json"),
+            r#"{ "agent": { "ttl": 30, "namespace": "mirrord" }, "feature": { "fs": "read" } }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("policy/team.yaml"),
+            "extends: [base.json]\nagent:\n  ttl: 60\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("mirrord.json"),
+            r#"{ "extends": ["policy/team.yaml"], "feature": { "fs": "write" } }"#,
+        )
+        .unwrap();
+
+        crate::util::testing::with_env_vars(vec![("MIRRORD_PROFILE", None)], || {
+            let config = LayerFileConfig::from_path(dir.join("mirrord.json"))
+                .unwrap()
+                .generate_config(&mut ConfigContext::default())
+                .unwrap();
+            assert_eq!(config.agent.ttl, 60);
+            assert_eq!(config.agent.namespace.as_deref(), Some("mirrord"));
+            assert_eq!(config.feature.fs.mode, FsModeConfig::Write);
+
+            std::fs::write(
+                dir.join("policy/base.json"),
+                r#"{ "extends": ["../mirrord.json"] }"#,
+            )
+            .unwrap();
+            let error = LayerFileConfig::from_path(dir.join("mirrord.json")).unwrap_err();
+            assert!(matches!(error, ConfigError::ExtendsCycle(..)), "{error:?}");
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rstest]
+    #[case("deployment/payments*", "deployment/payments-v2", true)]
+    #[case("deployment/payments*", "deployment/payments/container/app", true)]
+    #[case("deployment/payments*", "pod/payments", false)]
+    #[case("*/payments/*", "deployment/payments/container/app", true)]
+    #[case("deployment/payments", "deployment/payments-v2", false)]
+    #[case("deployment/payments", "deployment/payments", true)]
+    fn target_patterns(#[case] pattern: &str, #[case] target: &str, #[case] matches: bool) {
+        assert_eq!(target_matches(pattern, target), matches);
+    }
+
+    #[rstest]
+    #[case(
+        r#"{ "feature": { "fs": { "mode": "local", "secrets": "db" } } }"#,
+        false
+    )]
+    #[case(
+        r#"{ "feature": { "fs": { "mode": "local", "config_maps": "app" } } }"#,
+        false
+    )]
+    #[case(
+        r#"{ "feature": { "fs": { "mode": "read", "secrets": "db" } } }"#,
+        true
+    )]
+    #[case(r#"{ "feature": { "fs": "local" } }"#, true)]
+    fn cluster_files_need_fs(#[case] config: &str, #[case] valid: bool) {
+        let config = serde_json::from_str::<LayerFileConfig>(config)
+            .unwrap()
+            .generate_config(&mut ConfigContext::default())
+            .unwrap();
+
+        assert_eq!(config.verify(&mut ConfigContext::default()).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(r#"{ "feature": { "remote_exec": "psql" } }"#, false)]
+    #[case(
+        r#"{ "feature": { "remote_exec": "psql" }, "agent": { "remote_exec": true } }"#,
+        true
+    )]
+    fn remote_exec_needs_agent(#[case] config: &str, #[case] valid: bool) {
+        let config = serde_json::from_str::<LayerFileConfig>(config)
+            .unwrap()
+            .generate_config(&mut ConfigContext::default())
+            .unwrap();
+
+        assert_eq!(config.verify(&mut ConfigContext::default()).is_ok(), valid);
+    }
+
+    #[test]
+    fn targets() {
+        let path =
+            std::env::temp_dir().join(format!("mirrord-targets-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            {
+                "target": "deployment/payments-v2",
+                "feature": { "fs": "read" },
+                "targets": {
+                    "deployment/*": { "agent": { "ttl": 10 }, "feature": { "fs": "write" } },
+                    "deployment/payments*": { "feature": { "fs": "local" } },
+                    "deployment/orders*": { "agent": { "ttl": 20 } },
+                    "deployment/pa*": { "agent": { "log_level": "trace" } },
+                    "deployment/*v2": { "agent": { "log_level": "debug" } }
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        crate::util::testing::with_env_vars(
+            vec![
+                ("MIRRORD_PROFILE", None),
+                ("MIRRORD_IMPERSONATED_TARGET", None),
+            ],
+            || {
+                let config = LayerFileConfig::from_path(&path)
+                    .unwrap()
+                    .generate_config(&mut ConfigContext::default())
+                    .unwrap();
+
+                assert_eq!(config.agent.ttl, 10);
+                assert_eq!(config.feature.fs.mode, FsModeConfig::Local);
+                assert_eq!(config.agent.log_level, "trace");
+            },
+        );
+
+        crate::util::testing::with_env_vars(
+            vec![
+                ("MIRRORD_PROFILE", None),
+                ("MIRRORD_IMPERSONATED_TARGET", Some("deployment/orders")),
+            ],
+            || {
+                let config = LayerFileConfig::from_path(&path)
+                    .unwrap()
+                    .generate_config(&mut ConfigContext::default())
+                    .unwrap();
+
+                assert_eq!(config.agent.ttl, 20);
+                assert_eq!(config.feature.fs.mode, FsModeConfig::Write);
+            },
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
     /// <!--${internal}-->
     /// Helper for printing the config schema.
     ///
@@ -34,6 +34,8 @@ pub enum TargetFileConfig {
         #[serde(default, deserialize_with = "string_or_struct_option")]
         path: Option<Target>,
         namespace: Option<String>,
+        container_index: Option<usize>,
+        kube_context: Option<String>,
     },
 }
 
@@ -117,6 +119,48 @@ pub struct TargetConfig {
     ///
     /// Defaults to `"default"`.
     pub namespace: Option<String>,
+
+    /// ### target.container_index {#target-container_index}
+    ///
+    /// Selects the target container by its position in the pod spec (starting at `0`), for pods
+    /// where the container names differ between environments. Ignored when
+    /// [`target.path`](#target-path) specifies a container.
+    ///
+    /// When neither is set, mirrord picks the first container that isn't a known sidecar. Not
+    /// supported with the mirrord operator.
+    ///
+    /// ```json
+    /// {
+    ///   "target": {
+    ///     "path": "deployment/my-app",
+    ///     "container_index": 1
+    ///   }
+    /// }
+    /// ```
+    pub container_index: Option<usize>,
+
+    /// ### target.kube_context {#target-kube_context}
+    ///
+    /// Kube context of the cluster the target is in, when it's not the one the agent runs in
+    /// (e.g. vcluster setups, where the target is resolved in the virtual cluster, but the agent
+    /// runs in the host cluster). The clusters have to share the target's node.
+    ///
+    /// Defaults to [`kube_context`](#root-kube_context). Not supported with
+    /// [`agent.ephemeral`](#agent-ephemeral), and ignored when using the mirrord operator, which
+    /// resolves targets in its own cluster.
+    ///
+    /// ```json
+    /// {
+    ///   "target": {
+    ///     "path": "deployment/my-app",
+    ///     "kube_context": "vcluster_my-vcluster"
+    ///   },
+    ///   "agent": {
+    ///     "kube_context": "host-cluster"
+    ///   }
+    /// }
+    /// ```
+    pub kube_context: Option<String>,
 }
 
 impl Default for TargetFileConfig {
@@ -151,15 +195,31 @@ impl MirrordConfig for TargetFileConfig {
     /// Generate the final config object, out of the configuration parsed from a configuration file,
     /// factoring in environment variables (which are also set by the front end - CLI/IDE-plugin).
     fn generate_config(self, context: &mut ConfigContext) -> Result<Self::Generated> {
-        let (path_from_conf_file, namespace_from_conf_file) = match self {
-            TargetFileConfig::Simple(path) => (path, None),
-            TargetFileConfig::Advanced { path, namespace } => (path, namespace),
-        };
+        let (path_from_conf_file, namespace_from_conf_file, container_index, kube_context) =
+            match self {
+                TargetFileConfig::Simple(path) => (path, None, None, None),
+                TargetFileConfig::Advanced {
+                    path,
+                    namespace,
+                    container_index,
+                    kube_context,
+                } => (path, namespace, container_index, kube_context),
+            };
 
         // Env overrides configuration if both there.
         let path = Self::get_target_path_from_env(context)?.or(path_from_conf_file);
         let namespace = Self::get_target_namespace_from_env(context)?.or(namespace_from_conf_file);
-        Ok(TargetConfig { path, namespace })
+        let kube_context = FromEnv::new("MIRRORD_TARGET_KUBE_CONTEXT")
+            .source_value(context)
+            .transpose()?
+            .or(kube_context);
+
+        Ok(TargetConfig {
+            path,
+            namespace,
+            container_index,
+            kube_context,
+        })
     }
 }
 
@@ -477,7 +537,9 @@ mod tests {
     #[case(None, None,
         TargetConfig {
             path: None,
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
         }
     )] // Nothing specified - no target config (targetless mode).
     #[case(
@@ -485,7 +547,9 @@ mod tests {
         Some("ns"),
         TargetConfig{
             path: None,
-            namespace: Some("ns".to_string())
+            namespace: Some("ns".to_string()),
+            container_index: None,
+            kube_context: None
         }
     )] // Namespace without target - error.
     #[case(
@@ -493,7 +557,9 @@ mod tests {
         None,
         TargetConfig{
             path: Some(Target::Pod(PodTarget {pod: "foo".to_string(), container: None})),
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
         }
     )] // Only pod specified
     #[case(
@@ -504,7 +570,9 @@ mod tests {
                 pod: "foo".to_string(),
                 container: Some("bar".to_string())
             })),
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
         }
     )] // Pod and container specified.
     #[case(
@@ -512,7 +580,9 @@ mod tests {
         Some("baz"),
         TargetConfig{
             path: Some(Target::Pod(PodTarget {pod: "foo".to_string(), container: None})),
-            namespace: Some("baz".to_string())
+            namespace: Some("baz".to_string()),
+            container_index: None,
+            kube_context: None
         }
     )] // Pod and namespace specified.
     #[case(
@@ -523,7 +593,9 @@ mod tests {
                 rollout: "foo".to_string(),
                 container: None
             })),
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
         }
     )] // Rollout specified.
     fn default(
@@ -565,7 +637,9 @@ mod tests {
         r#"{ "namespace": "my-test-namespace" }"#,
         TargetConfig {
             path: None,
-            namespace: Some("my-test-namespace".to_string())
+            namespace: Some("my-test-namespace".to_string()),
+            container_index: None,
+            kube_context: None
         }
     )]
     // simple variant of file config - path string, not an object.
@@ -573,7 +647,9 @@ mod tests {
         r#""pod/my-cool-pod""#,
         TargetConfig{
             path: Some(Target::Pod(PodTarget {pod: "my-cool-pod".to_string(), container: None})),
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
         }
     )]
     // advanced variant of file config.
@@ -581,7 +657,9 @@ mod tests {
         r#"{ "path": "pod/my-cool-pod" }"#,
         TargetConfig{
             path: Some(Target::Pod(PodTarget {pod: "my-cool-pod".to_string(), container: None})),
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
         }
     )]
     // advanced variant of file config, with object as path.
@@ -593,7 +671,22 @@ mod tests {
         }"#,
         TargetConfig{
             path: Some(Target::Pod(PodTarget {pod: "my-cool-pod".to_string(), container: None})),
-            namespace: None
+            namespace: None,
+            container_index: None,
+            kube_context: None
+        }
+    )]
+    // advanced variant of file config, with the container index.
+    #[case(
+        r#"{ "path": "deployment/my-app", "container_index": 1 }"#,
+        TargetConfig{
+            path: Some(Target::Deployment(DeploymentTarget {
+                deployment: "my-app".to_string(),
+                container: None
+            })),
+            namespace: None,
+            container_index: Some(1),
+            kube_context: None
         }
     )]
     fn parse_target_config_from_json(
@@ -139,6 +139,35 @@ where
             Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
             Err(e) => Err(e)?,
         }
+
+        self.receive_body(len_buffer)
+    }
+
+    /// Like [`SyncDecoder::receive`], but fails with [`ErrorKind::Interrupted`] when a signal
+    /// interrupts the wait before the next message starts, so that the caller can give up on it.
+    ///
+    /// Once the message started, signals don't interrupt reading the rest of it.
+    pub fn receive_interruptible(&mut self) -> Result<Option<T>> {
+        let mut len_buffer = [0; 4];
+        let read = match self.reader.read(&mut len_buffer) {
+            Ok(0) => return Ok(None),
+            Ok(read) => read,
+            Err(e) => Err(e)?,
+        };
+        match self
+            .reader
+            .read_exact(len_buffer.get_mut(read..).unwrap_or_default())
+        {
+            Ok(..) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => Err(e)?,
+        }
+
+        self.receive_body(len_buffer)
+    }
+
+    /// Reads and decodes the message, after its length prefix.
+    fn receive_body(&mut self, len_buffer: [u8; 4]) -> Result<Option<T>> {
         let len = u32::from_be_bytes(len_buffer);
 
         self.buffer.resize(len as usize, 0);
@@ -165,3 +194,61 @@ pub fn make_sync_framed<T1: Encode, T2: Decode>(
 
     Ok((sender, receiver))
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Fails the first read with [`ErrorKind::Interrupted`], like a blocking read interrupted by a
+    /// signal.
+    struct Interrupted<R> {
+        interrupt: bool,
+        reader: R,
+    }
+
+    impl<R: Read> Read for Interrupted<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if std::mem::take(&mut self.interrupt) {
+                return Err(ErrorKind::Interrupted.into());
+            }
+
+            self.reader.read(buf)
+        }
+    }
+
+    fn encoded(messages: &[u64]) -> Vec<u8> {
+        let mut encoder = SyncEncoder::new(Vec::new());
+        for message in messages {
+            encoder.send(message).unwrap();
+        }
+        encoder.into_inner()
+    }
+
+    #[test]
+    fn interruptible_receive() {
+        let mut decoder = SyncDecoder::<u64, _>::new(Interrupted {
+            interrupt: true,
+            reader: Cursor::new(encoded(&[1, 2])),
+        });
+
+        assert!(matches!(
+            decoder.receive_interruptible(),
+            Err(CodecError::IoError(error)) if error.kind() == ErrorKind::Interrupted
+        ));
+        assert_eq!(decoder.receive_interruptible().unwrap(), Some(1));
+        assert_eq!(decoder.receive().unwrap(), Some(2));
+        assert_eq!(decoder.receive_interruptible().unwrap(), None);
+    }
+
+    #[test]
+    fn receive_retries_interrupted() {
+        let mut decoder = SyncDecoder::<u64, _>::new(Interrupted {
+            interrupt: true,
+            reader: Cursor::new(encoded(&[1])),
+        });
+
+        assert_eq!(decoder.receive().unwrap(), Some(1));
+    }
+}
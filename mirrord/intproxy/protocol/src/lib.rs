@@ -4,24 +4,29 @@
 
 use std::{
     collections::HashMap,
-    fmt,
+    fmt, io,
     net::{IpAddr, SocketAddr},
 };
 
 use bincode::{Decode, Encode};
 use mirrord_protocol::{
-    dns::{GetAddrInfoRequest, GetAddrInfoResponse},
+    dns::{GetAddrInfoRequestV2, GetAddrInfoResponse},
+    exec::{ExecData, ExecExit, ExecId},
     file::{
-        AccessFileRequest, AccessFileResponse, CloseDirRequest, CloseFileRequest, FdOpenDirRequest,
-        GetDEnts64Request, GetDEnts64Response, OpenDirResponse, OpenFileRequest, OpenFileResponse,
-        OpenRelativeFileRequest, ReadDirRequest, ReadDirResponse, ReadFileRequest,
-        ReadFileResponse, ReadLimitedFileRequest, SeekFileRequest, SeekFileResponse,
-        WriteFileRequest, WriteFileResponse, WriteLimitedFileRequest, XstatFsRequest,
-        XstatFsResponse, XstatRequest, XstatResponse,
+        AccessFileRequest, AccessFileResponse, BatchFileRequest, BatchFileResponse,
+        ChecksumFileRequest, ChecksumFileResponse, CloseDirRequest, CloseFileRequest,
+        FdOpenDirRequest, GetDEnts64Request, GetDEnts64Response, OpenDirResponse, OpenFileRequest,
+        OpenFileResponse, OpenRelativeFileRequest, ReadChecksummedFileRequest,
+        ReadChecksummedFileResponse, ReadDirBatchRequest, ReadDirBatchResponse, ReadDirRequest,
+        ReadDirResponse, ReadFileRequest, ReadFileResponse, ReadLimitedFileRequest,
+        ScratchDirRequest, ScratchDirResponse, SeekFileRequest, SeekFileResponse, WriteFileRequest,
+        WriteFileResponse, WriteLimitedFileRequest, XstatFsRequest, XstatFsResponse, XstatRequest,
+        XstatResponse,
     },
     outgoing::SocketAddress,
     tcp::StealType,
-    FileRequest, FileResponse, GetEnvVarsRequest, Port, RemoteResult,
+    ErrorKindInternal, FileRequest, FileResponse, GetEnvVarsRequest, Port, RemoteIOError,
+    RemoteResult, ResponseError,
 };
 
 #[cfg(feature = "codec")]
@@ -51,13 +56,20 @@ pub enum LayerToProxyMessage {
     /// A file operation request.
     File(FileRequest),
     /// A DNS request.
-    GetAddrInfo(GetAddrInfoRequest),
+    GetAddrInfo(GetAddrInfoRequestV2),
     /// A request to initiate a new outgoing connection.
     OutgoingConnect(OutgoingConnectRequest),
     /// Requests related to incoming connections.
     Incoming(IncomingRequest),
     /// Fetch environment variables from the target.
     GetEnv(GetEnvVarsRequest),
+    /// Requests related to processes run in the target container.
+    Exec(ExecRequest),
+    /// The layer stopped waiting for the response to the request with this [`MessageId`] (e.g.
+    /// the hooked call was interrupted), the work it started in the agent can be abandoned.
+    ///
+    /// The response may still come, and should be dropped.
+    Cancel(MessageId),
 }
 
 /// Layer process information
@@ -157,7 +169,7 @@ pub struct ConnMetadataRequest {
 }
 
 /// A response to layer's [`ConnMetadataRequest`].
-/// Contains metadata useful for hooking `getsockname` and `getpeername`.
+/// Contains metadata useful for hooking `getsockname`, `getpeername` and `getsockopt`.
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct ConnMetadataResponse {
     /// Original source of data, provided by the agent. Meant to be exposed to the user instead of
@@ -176,6 +188,15 @@ pub struct ConnMetadataResponse {
     /// Due to limitations of the `intproxy <-> agent` protocol, HTTP connections will send the
     /// real address (localhost).
     pub local_address: IpAddr,
+    /// Address the connection was originally sent to in the target, provided by the agent. Meant
+    /// to be returned from `getsockopt(SO_ORIGINAL_DST)`.
+    ///
+    /// # Note
+    ///
+    /// Agents that don't support
+    /// [`Capabilities::ORIGINAL_DESTINATION`](mirrord_protocol::capabilities::Capabilities::ORIGINAL_DESTINATION)
+    /// don't send it, then it's the local address with the remote port.
+    pub original_destination: SocketAddr,
 }
 
 /// A request to start proxying incoming connections.
@@ -191,7 +212,7 @@ pub struct PortSubscribe {
 }
 
 /// Instructions for the internal proxy and the agent on how to execute port mirroring.
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
 pub enum PortSubscription {
     /// Wrapped [`StealType`] specifies how to execute port mirroring.
     Steal(StealType),
@@ -208,6 +229,55 @@ pub struct PortUnsubscribe {
     pub listening_on: SocketAddr,
 }
 
+/// Layer's requests related to processes run in the target container (`feature.remote_exec`).
+///
+/// The layer starts the process with [`ExecStart`], and then calls [`ExecPoll`] in a loop to get
+/// its output, until the process exits.
+#[derive(Encode, Decode, Debug)]
+pub enum ExecRequest {
+    Start(ExecStart),
+    /// Data for the stdin of the process.
+    Stdin(ExecData),
+    CloseStdin(ExecCloseStdin),
+    Poll(ExecPoll),
+}
+
+/// A request to start a process in the target container.
+#[derive(Encode, Decode, Debug)]
+pub struct ExecStart {
+    /// The command, the program is looked up in the `PATH` of the target container.
+    pub argv: Vec<String>,
+}
+
+/// A request to close the stdin of a remote process.
+#[derive(Encode, Decode, Debug)]
+pub struct ExecCloseStdin(pub ExecId);
+
+/// A request for the output of a remote process.
+///
+/// The internal proxy responds once there is new output, or after a short while without any, so
+/// the layer never hits its connection timeout.
+#[derive(Encode, Decode, Debug)]
+pub struct ExecPoll(pub ExecId);
+
+/// A response to layer's [`ExecRequest`].
+#[derive(Encode, Decode, Debug)]
+pub enum ExecResponse {
+    /// A response to [`ExecStart`], with the id of the new process.
+    Start(RemoteResult<ExecId>),
+    /// A response to [`ExecPoll`].
+    Poll(RemoteResult<ExecOutput>),
+}
+
+/// Output of a remote process since the previous [`ExecPoll`].
+#[derive(Encode, Decode, Debug, Default)]
+pub struct ExecOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Set when the process exited, after all of its output was returned.
+    pub exit: Option<ExecExit>,
+}
+
 /// Messages sent by the internal proxy and handled by the layer.
 #[derive(Encode, Decode, Debug)]
 pub enum ProxyToLayerMessage {
@@ -216,7 +286,7 @@ pub enum ProxyToLayerMessage {
     NewSession(LayerId),
     /// A response to layer's [`FileRequest`].
     File(FileResponse),
-    /// A response to layer's [`GetAddrInfoRequest`].
+    /// A response to layer's [`GetAddrInfoRequestV2`].
     GetAddrInfo(GetAddrInfoResponse),
     /// A response to layer's [`OutgoingConnectRequest`].
     OutgoingConnect(RemoteResult<OutgoingConnectResponse>),
@@ -224,6 +294,31 @@ pub enum ProxyToLayerMessage {
     Incoming(IncomingResponse),
     /// A response to layer's [`LayerToProxyMessage::GetEnv`].
     GetEnv(RemoteResult<HashMap<String, String>>),
+    /// A response to layer's [`ExecRequest`].
+    Exec(ExecResponse),
+    /// A response to any layer's request, when the connection with the agent was reset (e.g.
+    /// after the machine woke from sleep) before the agent responded. The request can be made
+    /// again.
+    Interrupted,
+}
+
+/// The error of a request that may have changed the remote state already (e.g. a write), when the
+/// connection with the agent was lost before the agent responded. Unlike the other requests, it's
+/// not sent again after reconnecting, so the layer decides whether making it again is safe, see
+/// [`is_connection_lost`]. The application gets `EIO`.
+pub fn connection_lost() -> ResponseError {
+    io::Error::from(io::ErrorKind::ConnectionAborted).into()
+}
+
+/// Whether `error` is the [`connection_lost`] error.
+pub fn is_connection_lost(error: &ResponseError) -> bool {
+    matches!(
+        error,
+        ResponseError::RemoteIO(RemoteIOError {
+            raw_os_error: None,
+            kind: ErrorKindInternal::ConnectionAborted,
+        })
+    )
 }
 
 /// A response to layer's [`IncomingRequest`].
@@ -271,6 +366,12 @@ pub trait IsLayerRequestWithResponse: IsLayerRequest {
     /// Type of response to this request.
     type Response: Sized;
 
+    /// Whether the layer can stop waiting for the response when a signal interrupts the wait.
+    /// Only for requests that leave no trace when abandoned: the ones without side effects, and
+    /// the ones we cancel on [`LayerToProxyMessage::Cancel`]. The layer keeps waiting for the
+    /// others, so that their responses are not lost.
+    const CANCELLABLE: bool = false;
+
     /// Wraps the response so that it can be sent through the connection.
     fn wrap_response(response: Self::Response) -> ProxyToLayerMessage;
 
@@ -307,6 +408,7 @@ impl_request!(
     res = RemoteResult<ReadFileResponse>,
     req_path = LayerToProxyMessage::File => FileRequest::ReadLimited,
     res_path = ProxyToLayerMessage::File => FileResponse::ReadLimited,
+    cancellable = true,
 );
 
 impl_request!(
@@ -335,6 +437,7 @@ impl_request!(
     res = RemoteResult<AccessFileResponse>,
     req_path = LayerToProxyMessage::File => FileRequest::Access,
     res_path = ProxyToLayerMessage::File => FileResponse::Access,
+    cancellable = true,
 );
 
 impl_request!(
@@ -342,6 +445,7 @@ impl_request!(
     res = RemoteResult<XstatResponse>,
     req_path = LayerToProxyMessage::File => FileRequest::Xstat,
     res_path = ProxyToLayerMessage::File => FileResponse::Xstat,
+    cancellable = true,
 );
 
 impl_request!(
@@ -349,6 +453,7 @@ impl_request!(
     res = RemoteResult<XstatFsResponse>,
     req_path = LayerToProxyMessage::File => FileRequest::XstatFs,
     res_path = ProxyToLayerMessage::File => FileResponse::XstatFs,
+    cancellable = true,
 );
 
 impl_request!(
@@ -365,6 +470,42 @@ impl_request!(
     res_path = ProxyToLayerMessage::File => FileResponse::ReadDir,
 );
 
+impl_request!(
+    req = ReadDirBatchRequest,
+    res = RemoteResult<ReadDirBatchResponse>,
+    req_path = LayerToProxyMessage::File => FileRequest::ReadDirBatch,
+    res_path = ProxyToLayerMessage::File => FileResponse::ReadDirBatch,
+);
+
+impl_request!(
+    req = ChecksumFileRequest,
+    res = RemoteResult<ChecksumFileResponse>,
+    req_path = LayerToProxyMessage::File => FileRequest::Checksum,
+    res_path = ProxyToLayerMessage::File => FileResponse::Checksum,
+    cancellable = true,
+);
+
+impl_request!(
+    req = ReadChecksummedFileRequest,
+    res = RemoteResult<ReadChecksummedFileResponse>,
+    req_path = LayerToProxyMessage::File => FileRequest::ReadChecksummed,
+    res_path = ProxyToLayerMessage::File => FileResponse::ReadChecksummed,
+);
+
+impl_request!(
+    req = ScratchDirRequest,
+    res = RemoteResult<ScratchDirResponse>,
+    req_path = LayerToProxyMessage::File => FileRequest::ScratchDir,
+    res_path = ProxyToLayerMessage::File => FileResponse::ScratchDir,
+);
+
+impl_request!(
+    req = BatchFileRequest,
+    res = RemoteResult<BatchFileResponse>,
+    req_path = LayerToProxyMessage::File => FileRequest::Batch,
+    res_path = ProxyToLayerMessage::File => FileResponse::Batch,
+);
+
 impl_request!(
     req = GetDEnts64Request,
     res = RemoteResult<GetDEnts64Response>,
@@ -383,10 +524,11 @@ impl_request!(
 );
 
 impl_request!(
-    req = GetAddrInfoRequest,
+    req = GetAddrInfoRequestV2,
     res = GetAddrInfoResponse,
     req_path = LayerToProxyMessage::GetAddrInfo,
     res_path = ProxyToLayerMessage::GetAddrInfo,
+    cancellable = true,
 );
 
 impl_request!(
@@ -394,6 +536,7 @@ impl_request!(
     res = RemoteResult<OutgoingConnectResponse>,
     req_path = LayerToProxyMessage::OutgoingConnect,
     res_path = ProxyToLayerMessage::OutgoingConnect,
+    cancellable = true,
 );
 
 impl_request!(
@@ -413,6 +556,7 @@ impl_request!(
     res = ConnMetadataResponse,
     req_path = LayerToProxyMessage::Incoming => IncomingRequest::ConnMetadata,
     res_path = ProxyToLayerMessage::Incoming => IncomingResponse::ConnMetadata,
+    cancellable = true,
 );
 
 impl_request!(
@@ -420,4 +564,29 @@ impl_request!(
     res = RemoteResult<HashMap<String, String>>,
     req_path = LayerToProxyMessage::GetEnv,
     res_path = ProxyToLayerMessage::GetEnv,
+    cancellable = true,
+);
+
+impl_request!(
+    req = ExecStart,
+    res = RemoteResult<ExecId>,
+    req_path = LayerToProxyMessage::Exec => ExecRequest::Start,
+    res_path = ProxyToLayerMessage::Exec => ExecResponse::Start,
+);
+
+impl_request!(
+    req = ExecData,
+    req_path = LayerToProxyMessage::Exec => ExecRequest::Stdin,
+);
+
+impl_request!(
+    req = ExecCloseStdin,
+    req_path = LayerToProxyMessage::Exec => ExecRequest::CloseStdin,
+);
+
+impl_request!(
+    req = ExecPoll,
+    res = RemoteResult<ExecOutput>,
+    req_path = LayerToProxyMessage::Exec => ExecRequest::Poll,
+    res_path = ProxyToLayerMessage::Exec => ExecResponse::Poll,
 );
@@ -49,6 +49,7 @@ macro_rules! impl_request {
         res = $res_type: path,
         req_path = $($req_variants: path) => +,
         res_path = $($res_variants: path) => +,
+        $(cancellable = $cancellable: literal,)?
     ) => {
         impl_request!(
             req = $req_type,
@@ -58,6 +59,8 @@ macro_rules! impl_request {
         impl IsLayerRequestWithResponse for $req_type {
             type Response = $res_type;
 
+            $(const CANCELLABLE: bool = $cancellable;)?
+
             fn wrap_response(response: Self::Response) -> ProxyToLayerMessage {
                 bind_nested!(response, $($res_variants),+)
             }
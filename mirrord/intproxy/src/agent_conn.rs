@@ -1,9 +1,9 @@
 //! Implementation of `proxy <-> agent` connection through [`mpsc`](tokio::sync::mpsc) channels
 //! created in different mirrord crates.
 
-use std::{io, net::SocketAddr};
+use std::{io, net::SocketAddr, time::Duration};
 
-use mirrord_analytics::Reporter;
+use mirrord_analytics::{NullReporter, Reporter};
 use mirrord_config::LayerConfig;
 use mirrord_kube::{
     api::{
@@ -13,12 +13,14 @@ use mirrord_kube::{
     error::KubeApiError,
 };
 use mirrord_operator::client::{OperatorApi, OperatorApiError, OperatorSessionInformation};
+use mirrord_progress::{IpcOutput, JsonProgress, NullProgress, Progress, ProgressTracker};
 use mirrord_protocol::{ClientMessage, DaemonMessage};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
     net::TcpStream,
     sync::mpsc::{Receiver, Sender},
+    time,
 };
 
 use crate::{
@@ -63,9 +65,40 @@ pub enum AgentConnectInfo {
 pub struct AgentConnection {
     pub agent_tx: Sender<ClientMessage>,
     pub agent_rx: Receiver<DaemonMessage>,
+    /// Set when we connect to the agent with a k8s port forward, which the API server can drop
+    /// at any time, or through the operator.
+    reconnect: Option<AgentReconnect>,
+    /// Whether we reconnected and the [`IntProxy`](crate::IntProxy) did not resume the session
+    /// yet. See [`AgentConnectionMessage::ToAgent`].
+    resuming: bool,
+}
+
+/// What we need to connect to the agent again, or to a new agent when it's gone (e.g. its pod was
+/// deleted with the target).
+enum AgentReconnect {
+    /// Through a k8s port forward to the agent.
+    DirectKubernetes {
+        k8s_api: KubernetesAPI,
+        connect_info: AgentKubernetesConnectInfo,
+        config: Box<LayerConfig>,
+    },
+    /// Through the operator session.
+    Operator {
+        session: Box<OperatorSessionInformation>,
+        config: Box<LayerConfig>,
+    },
 }
 
 impl AgentConnection {
+    /// How many times we try to connect to the agent again, before giving up on the session.
+    const RECONNECT_ATTEMPTS: u32 = 8;
+    /// After how many failed attempts we assume the agent is gone, and create a new one. The
+    /// next attempts connect to that one, we never create more than one per reconnect.
+    const NEW_AGENT_AFTER_ATTEMPTS: u32 = 2;
+    /// Delay after the first failed attempt, doubled after each next one.
+    const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
     /// Creates a new agent connection based on the provided [`LayerConfig`] and optional
     /// [`AgentConnectInfo`].
     pub async fn new<R: Reporter>(
@@ -73,13 +106,18 @@ impl AgentConnection {
         connect_info: Option<AgentConnectInfo>,
         analytics: &mut R,
     ) -> Result<Self, AgentConnectionError> {
-        let (agent_tx, agent_rx) = match connect_info {
+        let (agent_tx, agent_rx, reconnect) = match connect_info {
             Some(AgentConnectInfo::Operator(operator_session_information)) => {
                 let session = OperatorApi::connect(config, operator_session_information, analytics)
                     .await
                     .map_err(AgentConnectionError::Operator)?;
 
-                (session.tx, session.rx)
+                let reconnect = AgentReconnect::Operator {
+                    session: Box::new(session.info),
+                    config: Box::new(config.clone()),
+                };
+
+                (session.tx, session.rx, Some(reconnect))
             }
 
             Some(AgentConnectInfo::DirectKubernetes(connect_info)) => {
@@ -91,8 +129,15 @@ impl AgentConnection {
                     .create_connection(connect_info.clone())
                     .await
                     .map_err(AgentConnectionError::Kube)?;
+                let (agent_tx, agent_rx) = wrap_raw_connection(stream);
 
-                wrap_raw_connection(stream)
+                let reconnect = AgentReconnect::DirectKubernetes {
+                    k8s_api,
+                    connect_info,
+                    config: Box::new(config.clone()),
+                };
+
+                (agent_tx, agent_rx, Some(reconnect))
             }
 
             None => {
@@ -101,24 +146,189 @@ impl AgentConnection {
                     .as_ref()
                     .ok_or(AgentConnectionError::NoConnectionMethod)?;
                 let stream = TcpStream::connect(address).await?;
-                wrap_raw_connection(stream)
+                let (agent_tx, agent_rx) = wrap_raw_connection(stream);
+
+                (agent_tx, agent_rx, None)
             }
         };
 
-        Ok(Self { agent_tx, agent_rx })
+        Ok(Self {
+            agent_tx,
+            agent_rx,
+            reconnect,
+            resuming: false,
+        })
     }
 
     pub async fn new_for_raw_address(address: SocketAddr) -> Result<Self, AgentConnectionError> {
         let stream = TcpStream::connect(address).await?;
         let (agent_tx, agent_rx) = wrap_raw_connection(stream);
 
-        Ok(Self { agent_tx, agent_rx })
+        Ok(Self {
+            agent_tx,
+            agent_rx,
+            reconnect: None,
+            resuming: false,
+        })
     }
 
     #[tracing::instrument(level = "trace", name = "send_agent_message", skip(self), ret)]
     async fn send(&self, msg: ClientMessage) -> Result<(), AgentChannelError> {
         self.agent_tx.send(msg).await.map_err(|_| AgentChannelError)
     }
+
+    /// Connects to the agent again, with a backoff, replacing the inner channels. After a few
+    /// failed attempts, a new agent (or operator session) is created once, like when mirrord
+    /// starts, and the previous agent is deleted.
+    ///
+    /// Every attempt is reported with [`ProxyMessage::AgentReconnecting`], and to the user with
+    /// [`Progress`] in the `ipc:<path>` progress mode (the proxy has no terminal). Fails when this
+    /// connection does not support reconnecting (e.g. it goes to a raw address) or when all
+    /// attempts failed.
+    async fn reconnect(
+        &mut self,
+        message_bus: &mut MessageBus<Self>,
+    ) -> Result<(), AgentChannelError> {
+        if self.reconnect.is_none() {
+            return Err(AgentChannelError);
+        }
+
+        let mut progress: ProgressTracker = match IpcOutput::from_env() {
+            Some(output) => {
+                JsonProgress::with_output("reconnecting to the agent", Some(output)).into()
+            }
+            None => NullProgress.into(),
+        };
+
+        let mut replaced = false;
+        let mut backoff = Self::RECONNECT_BACKOFF;
+        for attempt in 1..=Self::RECONNECT_ATTEMPTS {
+            message_bus
+                .send(ProxyMessage::AgentReconnecting(attempt))
+                .await;
+
+            let new_agent = !replaced && attempt > Self::NEW_AGENT_AFTER_ATTEMPTS;
+            progress.info(&format!(
+                "attempt {attempt} of {}{}",
+                Self::RECONNECT_ATTEMPTS,
+                if new_agent {
+                    ", creating a new agent"
+                } else {
+                    ""
+                }
+            ));
+
+            match self
+                .try_reconnect(new_agent, &mut replaced, &mut progress)
+                .await
+            {
+                Ok(()) => {
+                    progress.success(Some("reconnected to the agent"));
+                    self.resuming = true;
+                    return Ok(());
+                }
+                Err(error) => {
+                    progress.warning(&format!("failed to reconnect to the agent: {error}"));
+                    tracing::warn!(%error, attempt, new_agent, "failed to reconnect to the agent")
+                }
+            }
+
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Self::MAX_RECONNECT_BACKOFF);
+        }
+
+        progress.failure(Some("failed to reconnect to the agent"));
+        tracing::error!("failed to reconnect to the agent, giving up");
+        Err(AgentChannelError)
+    }
+
+    /// Makes a single attempt to connect to the agent again, or to a `new_agent`. Sets `replaced`
+    /// when it tries to create the new agent, so that the next attempts don't create another one
+    /// when this one fails half-way.
+    async fn try_reconnect(
+        &mut self,
+        new_agent: bool,
+        replaced: &mut bool,
+        progress: &mut ProgressTracker,
+    ) -> Result<(), AgentConnectionError> {
+        let (agent_tx, agent_rx) = match self.reconnect.as_mut() {
+            None => return Err(AgentConnectionError::NoConnectionMethod),
+
+            Some(AgentReconnect::DirectKubernetes {
+                k8s_api,
+                connect_info,
+                config,
+            }) => {
+                if new_agent {
+                    *replaced = true;
+                    let created = time::timeout(
+                        Duration::from_secs(config.agent.startup_timeout),
+                        k8s_api.create_agent(
+                            progress,
+                            &config.target,
+                            Some(&**config),
+                            Default::default(),
+                        ),
+                    )
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+                    tracing::warn!(pod_name = %created.pod_name, "created a new agent");
+
+                    let previous = std::mem::replace(connect_info, created);
+                    if let Err(error) = k8s_api.delete_agent(&previous).await {
+                        tracing::warn!(
+                            %error,
+                            pod_name = %previous.pod_name,
+                            "failed to delete the previous agent"
+                        );
+                    }
+                }
+
+                let stream = k8s_api.reconnect_connection(connect_info.clone()).await?;
+                wrap_raw_connection(stream)
+            }
+
+            Some(AgentReconnect::Operator { session, config }) => {
+                // The operator cleans up the previous session, no client is connected to it.
+                let connection = if new_agent {
+                    *replaced = true;
+                    OperatorApi::create_session(config, &*progress, &mut NullReporter::default())
+                        .await?
+                } else {
+                    OperatorApi::connect(config, (**session).clone(), &mut NullReporter::default())
+                        .await?
+                };
+
+                **session = connection.info;
+                (connection.tx, connection.rx)
+            }
+        };
+
+        self.agent_tx = agent_tx;
+        self.agent_rx = agent_rx;
+
+        Ok(())
+    }
+}
+
+/// Messages consumed by the [`AgentConnection`] running as a [`BackgroundTask`].
+pub enum AgentConnectionMessage {
+    /// Message to be sent to the agent.
+    ///
+    /// After we reconnect, the messages routed before the [`IntProxy`](crate::IntProxy) resumed
+    /// the session with [`ClientMessage::SwitchProtocolVersion`] are dropped. They were meant for
+    /// the previous connection, and the proxies send again what's still needed.
+    ToAgent(ClientMessage),
+    /// The connection is most likely dead (e.g. the machine was asleep), drop it and connect
+    /// again without waiting for it to time out.
+    Reset,
+}
+
+impl From<ClientMessage> for AgentConnectionMessage {
+    fn from(value: ClientMessage) -> Self {
+        Self::ToAgent(value)
+    }
 }
 
 /// This error occurs when the [`AgentConnection`] fails to communicate with the inner
@@ -129,7 +339,7 @@ pub struct AgentChannelError;
 
 impl BackgroundTask for AgentConnection {
     type Error = AgentChannelError;
-    type MessageIn = ClientMessage;
+    type MessageIn = AgentConnectionMessage;
     type MessageOut = ProxyMessage;
 
     async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
@@ -140,10 +350,36 @@ impl BackgroundTask for AgentConnection {
                         tracing::trace!("message bus closed, exiting");
                         break Ok(());
                     },
-                    Some(msg) => {
+                    Some(AgentConnectionMessage::Reset) if self.reconnect.is_none() => {
+                        tracing::warn!("connection with the agent does not support reconnecting, keeping it");
+                    }
+                    Some(AgentConnectionMessage::Reset) => {
+                        tracing::warn!("resetting connection with the agent");
+                        // Everything we got through the old connection was already passed on, so
+                        // the requests still pending will never get a response.
+                        message_bus.send(ProxyMessage::AgentConnectionReset).await;
+                        self.reconnect(message_bus).await?;
+                        message_bus.send(ProxyMessage::AgentReconnected).await;
+                    }
+                    Some(AgentConnectionMessage::ToAgent(msg)) if self.resuming => {
+                        if matches!(msg, ClientMessage::SwitchProtocolVersion(..)) {
+                            self.resuming = false;
+                            if let Err(error) = self.send(msg).await {
+                                tracing::error!(%error, "failed to send message to the agent");
+                                self.reconnect(message_bus).await?;
+                                message_bus.send(ProxyMessage::AgentReconnected).await;
+                            }
+                        } else {
+                            tracing::trace!(?msg, "dropping a message meant for the previous connection");
+                        }
+                    }
+                    Some(AgentConnectionMessage::ToAgent(msg)) => {
                         if let Err(error) = self.send(msg).await {
                             tracing::error!(%error, "failed to send message to the agent");
-                            break Err(error);
+                            // The message is lost, but it will be sent again by the proxy
+                            // that's waiting for its response.
+                            self.reconnect(message_bus).await?;
+                            message_bus.send(ProxyMessage::AgentReconnected).await;
                         }
                     }
                 },
@@ -151,7 +387,8 @@ impl BackgroundTask for AgentConnection {
                 msg = self.agent_rx.recv() => match msg {
                     None => {
                         tracing::error!("failed to receive message from the agent, inner task down");
-                        break Err(AgentChannelError);
+                        self.reconnect(message_bus).await?;
+                        message_bus.send(ProxyMessage::AgentReconnected).await;
                     }
                     Some(msg) => message_bus.send(ProxyMessage::FromAgent(msg)).await,
                 }
@@ -8,7 +8,7 @@
 use std::{collections::HashMap, fmt, future::Future, hash::Hash};
 
 use tokio::{
-    sync::mpsc::{self, Receiver, Sender},
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
     task::JoinHandle,
 };
 use tokio_stream::{wrappers::ReceiverStream, StreamExt, StreamMap, StreamNotifyClose};
@@ -191,4 +191,18 @@ impl<T: BackgroundTask> TaskSender<T> {
     pub async fn send<M: Into<T::MessageIn>>(&self, msg: M) {
         let _ = self.0.send(msg.into()).await;
     }
+
+    /// Attempt to send a message to the task, without waiting for room in its channel.
+    /// Returns the message back when the channel is full. Messages for a finished task are dropped,
+    /// like with [`TaskSender::send`].
+    pub fn try_send<M: Into<T::MessageIn>>(&self, msg: M) -> Result<(), M> {
+        match self.0.try_reserve() {
+            Ok(permit) => {
+                permit.send(msg.into());
+                Ok(())
+            }
+            Err(TrySendError::Full(())) => Err(msg),
+            Err(TrySendError::Closed(())) => Ok(()),
+        }
+    }
 }
@@ -0,0 +1,402 @@
+//! Limits on the data queued for the local sockets of the intercepted connections.
+//!
+//! Every intercepted connection (incoming or outgoing) has its own channel of
+//! `internal_proxy.connection_buffer_size` messages, and a [`ByteBudget`] of
+//! `internal_proxy.connection_high_watermark` bytes. The proxies take bytes from the budget before
+//! sending the data received from the agent to the interceptor, and the interceptors give them
+//! back once the data is written to the local socket.
+//!
+//! The proxies never wait for the budget of one connection, that would stop the data of all the
+//! other connections too. When a slow local process exhausts the budget of its connection, the data
+//! waits in the [`ConnectionQueue`] of the connection instead, and the proxy asks the agent to
+//! pause the connection (see [`FlowControl`]), so that TCP flow control slows down its peer. The
+//! proxy resumes the connection once the interceptor caught up, woken by
+//! [`DrainedConnections`].
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use mirrord_config::internal_proxy::InternalProxyConfig;
+use tokio::sync::{mpsc, Semaphore, TryAcquireError};
+
+/// Sizes of the queues of every intercepted connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionBuffers {
+    /// Size of the channel of every interceptor, in messages.
+    pub size: usize,
+    /// Bytes that can be queued for the local socket of every connection.
+    pub high_watermark: usize,
+}
+
+impl ConnectionBuffers {
+    pub fn new(config: &InternalProxyConfig) -> Self {
+        Self {
+            size: config.connection_buffer_size.max(1),
+            high_watermark: config.connection_high_watermark.max(1),
+        }
+    }
+}
+
+impl Default for ConnectionBuffers {
+    fn default() -> Self {
+        Self {
+            size: 512,
+            high_watermark: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Bytes that can still be queued for the local socket of a connection.
+/// Shared between the proxy that queues the data and the interceptor that writes it.
+#[derive(Clone)]
+pub struct ByteBudget {
+    semaphore: Arc<Semaphore>,
+    high_watermark: usize,
+    /// Set while the proxy has data waiting for this budget, see [`ConnectionQueue::flush`].
+    waiting: Arc<AtomicBool>,
+    /// Called when bytes are given back while the proxy waits for them.
+    wake: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl fmt::Debug for ByteBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ByteBudget")
+            .field("available", &self.semaphore.available_permits())
+            .field("high_watermark", &self.high_watermark)
+            .field("waiting", &self.waiting.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl ByteBudget {
+    /// Creates a budget of `high_watermark` bytes, calling `wake` when the interceptor gives back
+    /// bytes that queued data waits for.
+    pub fn new<W>(high_watermark: usize, wake: W) -> Self
+    where
+        W: Fn() + Send + Sync + 'static,
+    {
+        let high_watermark = high_watermark
+            .min(Semaphore::MAX_PERMITS)
+            .min(u32::MAX as usize);
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(high_watermark)),
+            high_watermark,
+            waiting: Default::default(),
+            wake: Arc::new(wake),
+        }
+    }
+
+    /// Chunks bigger than the whole budget take all of it.
+    fn permits(&self, bytes: usize) -> u32 {
+        bytes.min(self.high_watermark) as u32
+    }
+
+    /// Takes `bytes` from the budget, if there are enough.
+    ///
+    /// Always succeeds when the interceptor is gone, so that the proxy does not wait for it.
+    pub fn try_take(&self, bytes: usize) -> bool {
+        match self.semaphore.try_acquire_many(self.permits(bytes)) {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(TryAcquireError::Closed) => true,
+            Err(TryAcquireError::NoPermits) => false,
+        }
+    }
+
+    /// Returns `bytes` taken with [`ByteBudget::try_take`] that were not used, without waking the
+    /// proxy.
+    fn untake(&self, bytes: usize) {
+        self.semaphore.add_permits(self.permits(bytes) as usize);
+    }
+
+    /// Gives back `bytes` taken with [`ByteBudget::try_take`], after they were written.
+    pub fn give_back(&self, bytes: usize) {
+        self.untake(bytes);
+
+        if self.waiting.swap(false, Ordering::AcqRel) {
+            (self.wake)();
+        }
+    }
+
+    /// Returns a guard that closes the budget when dropped, so that the proxy does not wait for
+    /// an interceptor that's gone.
+    pub fn close_on_drop(&self) -> BudgetGuard {
+        BudgetGuard(self.semaphore.clone())
+    }
+}
+
+/// Closes a [`ByteBudget`] when dropped, see [`ByteBudget::close_on_drop`].
+pub struct BudgetGuard(Arc<Semaphore>);
+
+impl Drop for BudgetGuard {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// What the proxy asks the agent to do with a connection, after flushing its
+/// [`ConnectionQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    /// Stop reading from the connection, its local socket can't keep up.
+    Pause,
+    /// Read from the connection again, its local socket caught up.
+    Resume,
+}
+
+/// Data received from the agent for one connection, waiting for room in its [`ByteBudget`] and in
+/// the channel of its interceptor.
+#[derive(Debug)]
+pub struct ConnectionQueue {
+    budget: ByteBudget,
+    pending: VecDeque<Vec<u8>>,
+    /// Bytes in [`Self::pending`].
+    pending_bytes: usize,
+    /// Whether the last [`FlowControl`] returned from [`Self::flush`] was a pause.
+    paused: bool,
+    /// The agent closed the connection, the queue is dropped once it's flushed.
+    closed: bool,
+}
+
+impl ConnectionQueue {
+    pub fn new(budget: ByteBudget) -> Self {
+        Self {
+            budget,
+            pending: Default::default(),
+            pending_bytes: 0,
+            paused: false,
+            closed: false,
+        }
+    }
+
+    /// Queues `bytes`, to be sent with [`Self::flush`].
+    pub fn push(&mut self, bytes: Vec<u8>) {
+        self.pending_bytes += bytes.len();
+        self.pending.push_back(bytes);
+    }
+
+    /// Sends the queued data with `send`, as long as it fits in the budget. `send` returns the
+    /// data back when there's no room in the channel of the interceptor.
+    ///
+    /// Returns [`FlowControl::Pause`] when data is left in the queue, and [`FlowControl::Resume`]
+    /// when the queue of a paused connection was emptied.
+    pub fn flush<F>(&mut self, mut send: F) -> Option<FlowControl>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), Vec<u8>>,
+    {
+        // Set before taking from the budget, so that bytes given back in the meantime wake the
+        // proxy.
+        if !self.pending.is_empty() {
+            self.budget.waiting.store(true, Ordering::Release);
+        }
+
+        while let Some(bytes) = self.pending.pop_front() {
+            let len = bytes.len();
+            if !self.budget.try_take(len) {
+                self.pending.push_front(bytes);
+                break;
+            }
+
+            if let Err(bytes) = send(bytes) {
+                self.budget.untake(len);
+                self.pending.push_front(bytes);
+                break;
+            }
+
+            self.pending_bytes -= len;
+        }
+
+        if self.pending.is_empty() {
+            self.budget.waiting.store(false, Ordering::Release);
+        }
+
+        match (self.pending.is_empty(), self.paused) {
+            (false, false) => {
+                self.paused = true;
+                Some(FlowControl::Pause)
+            }
+            (true, true) => {
+                self.paused = false;
+                Some(FlowControl::Resume)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether more than the whole budget waits in the queue, for connections that can't be
+    /// paused.
+    pub fn overflowing(&self) -> bool {
+        self.pending_bytes > self.budget.high_watermark
+    }
+
+    /// Marks the connection as closed by the agent, see [`Self::is_done`].
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Whether the connection was closed by the agent and all its data was sent to the
+    /// interceptor.
+    pub fn is_done(&self) -> bool {
+        self.closed && self.pending.is_empty()
+    }
+}
+
+/// Ids of the connections whose interceptors gave back bytes that their [`ConnectionQueue`]s
+/// wait for.
+pub struct DrainedConnections<K> {
+    tx: mpsc::UnboundedSender<K>,
+    rx: mpsc::UnboundedReceiver<K>,
+}
+
+impl<K> Default for DrainedConnections<K> {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self { tx, rx }
+    }
+}
+
+impl<K: Clone + Send + Sync + 'static> DrainedConnections<K> {
+    /// Creates a [`ByteBudget`] of `high_watermark` bytes for the connection `id`, that reports
+    /// here.
+    pub fn budget(&self, id: K, high_watermark: usize) -> ByteBudget {
+        let tx = self.tx.clone();
+        ByteBudget::new(high_watermark, move || {
+            let _ = tx.send(id.clone());
+        })
+    }
+
+    /// Returns the next connection that should be flushed.
+    pub async fn next(&mut self) -> K {
+        self.rx.recv().await.expect("sender is held in this struct")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn try_take_over_high_watermark() {
+        let budget = ByteBudget::new(10, || {});
+
+        assert!(budget.try_take(6));
+        // Bigger than the whole budget, takes all of it once the first chunk is written.
+        assert!(!budget.try_take(100));
+
+        budget.give_back(6);
+        assert!(budget.try_take(100));
+    }
+
+    #[test]
+    fn closed_does_not_wait() {
+        let budget = ByteBudget::new(10, || {});
+        assert!(budget.try_take(10));
+
+        drop(budget.close_on_drop());
+        assert!(budget.try_take(10));
+    }
+
+    /// Data over the budget waits in the queue, pausing the connection until the interceptor gives
+    /// back its bytes.
+    #[test]
+    fn pauses_over_budget() {
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let budget = ByteBudget::new(10, {
+            let wakes = wakes.clone();
+            move || {
+                wakes.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let mut queue = ConnectionQueue::new(budget.clone());
+        let mut sent = Vec::new();
+
+        queue.push(vec![0; 6]);
+        queue.push(vec![1; 6]);
+        let flow = queue.flush(|bytes| {
+            sent.push(bytes);
+            Ok(())
+        });
+        assert_eq!(flow, Some(FlowControl::Pause));
+        assert_eq!(sent, [vec![0; 6]]);
+
+        // Still waiting, the connection is already paused.
+        queue.push(vec![2; 1]);
+        assert_eq!(queue.flush(|_| unreachable!()), None);
+
+        budget.give_back(6);
+        assert_eq!(wakes.load(Ordering::Relaxed), 1);
+
+        let flow = queue.flush(|bytes| {
+            sent.push(bytes);
+            Ok(())
+        });
+        assert_eq!(flow, Some(FlowControl::Resume));
+        assert_eq!(sent, [vec![0; 6], vec![1; 6], vec![2; 1]]);
+
+        // Nothing waits for these bytes.
+        budget.give_back(7);
+        assert_eq!(wakes.load(Ordering::Relaxed), 1);
+    }
+
+    /// Data that doesn't fit in the channel of the interceptor stays in the queue, without using
+    /// the budget.
+    #[test]
+    fn keeps_data_when_channel_is_full() {
+        let budget = ByteBudget::new(10, || {});
+        let mut queue = ConnectionQueue::new(budget.clone());
+
+        queue.push(vec![0; 4]);
+        queue.close();
+        assert_eq!(queue.flush(Err), Some(FlowControl::Pause));
+        assert!(!queue.is_done());
+        assert!(budget.try_take(10));
+        budget.give_back(10);
+
+        assert_eq!(queue.flush(|_| Ok(())), Some(FlowControl::Resume));
+        assert!(queue.is_done());
+    }
+
+    #[test]
+    fn overflowing() {
+        let budget = ByteBudget::new(10, || {});
+        let mut queue = ConnectionQueue::new(budget);
+
+        queue.push(vec![0; 10]);
+        queue.push(vec![0; 10]);
+        assert!(!queue.overflowing());
+
+        queue.push(vec![0; 1]);
+        assert!(queue.overflowing());
+    }
+
+    #[tokio::test]
+    async fn drained_connections() {
+        let mut drained = DrainedConnections::default();
+        let budget = drained.budget(7_u64, 10);
+        let mut queue = ConnectionQueue::new(budget.clone());
+
+        assert!(budget.try_take(10));
+        queue.push(vec![0; 1]);
+        assert_eq!(queue.flush(|_| Ok(())), Some(FlowControl::Pause));
+
+        budget.give_back(10);
+        let id = tokio::time::timeout(Duration::from_secs(1), drained.next())
+            .await
+            .unwrap();
+        assert_eq!(id, 7);
+    }
+}
@@ -0,0 +1,179 @@
+//! Reloading of the `steal` HTTP filter while the session runs.
+//!
+//! Tweaking a header or path filter shouldn't require restarting the application. We watch the
+//! config file, and when `feature.network.incoming.http_filter` changes, the
+//! [`IncomingProxy`](crate::proxies::incoming::IncomingProxy) updates the filter of the stolen
+//! ports in the agent.
+//!
+//! Only the filter itself is reloaded. Other changes (including the filtered ports, or removing
+//! the filter) take effect after a restart.
+
+use std::{
+    convert::Infallible,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use mirrord_config::{feature::network::incoming::http_filter::HttpFilterConfig, LayerConfig};
+use mirrord_protocol::tcp::{Filter, HttpFilter};
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+use crate::{
+    background_tasks::{BackgroundTask, MessageBus},
+    ProxyMessage,
+};
+
+/// Sends [`ProxyMessage::HttpFilterChanged`] when the HTTP filter changes in the config file.
+/// Run as a [`BackgroundTask`].
+pub struct ConfigWatcher {
+    /// Path of the config file.
+    path: PathBuf,
+    ticker: Interval,
+    /// Modification time of the config file when we last loaded it.
+    modified: Option<SystemTime>,
+    /// The HTTP filter config currently in use.
+    http_filter: HttpFilterConfig,
+}
+
+impl ConfigWatcher {
+    /// Creates a new instance of this struct.
+    ///
+    /// # Arguments
+    ///
+    /// * path - path of the config file, the same one that `http_filter` was loaded from
+    /// * http_filter - the HTTP filter config the session started with
+    /// * frequency - how often the config file is checked for changes
+    pub fn new(path: PathBuf, http_filter: HttpFilterConfig, frequency: Duration) -> Self {
+        let mut ticker = time::interval(frequency);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        Self {
+            path,
+            ticker,
+            modified,
+            http_filter,
+        }
+    }
+
+    /// Loads the config again if the file was modified since we last loaded it, and returns the
+    /// new HTTP filter if it changed.
+    fn reload(&mut self) -> Option<HttpFilter> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        let config = LayerConfig::from_env()
+            .inspect_err(|error| {
+                tracing::warn!(
+                    %error,
+                    "failed to reload the config file, keeping the current HTTP filter"
+                )
+            })
+            .ok()?;
+        let http_filter = config.feature.network.incoming.http_filter;
+
+        if http_filter.ports != self.http_filter.ports {
+            tracing::warn!(
+                "`feature.network.incoming.http_filter.ports` changed, restart mirrord to use the \
+                 new ports"
+            );
+        }
+
+        let changed = http_filter.header_filter != self.http_filter.header_filter
+            || http_filter.path_filter != self.http_filter.path_filter;
+        let filter = changed
+            .then(|| steal_filter(&http_filter))?
+            .inspect_err(|error| {
+                tracing::warn!(%error, "keeping the current HTTP filter");
+            })
+            .ok()?;
+
+        self.http_filter = http_filter;
+
+        Some(filter)
+    }
+}
+
+/// Builds the [`HttpFilter`] that the layers use for the given config.
+fn steal_filter(config: &HttpFilterConfig) -> Result<HttpFilter, String> {
+    match (&config.path_filter, &config.header_filter) {
+        (Some(path), None) => Filter::new(path.clone())
+            .map(HttpFilter::Path)
+            .map_err(|error| format!("invalid `path_filter`: {error}")),
+        (None, Some(header)) => Filter::new(header.clone())
+            .map(HttpFilter::Header)
+            .map_err(|error| format!("invalid `header_filter`: {error}")),
+        (None, None) => {
+            Err("the HTTP filter was removed, restart mirrord to stop filtering".into())
+        }
+        (Some(..), Some(..)) => {
+            Err("only one of `path_filter` and `header_filter` can be set".into())
+        }
+    }
+}
+
+impl BackgroundTask for ConfigWatcher {
+    type Error = Infallible;
+    type MessageIn = ();
+    type MessageOut = ProxyMessage;
+
+    async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        loop {
+            tokio::select! {
+                _ = self.ticker.tick() => {
+                    if let Some(filter) = self.reload() {
+                        tracing::info!(%filter, "HTTP filter changed in the config file");
+                        message_bus.send(ProxyMessage::HttpFilterChanged(filter)).await;
+                    }
+                },
+
+                msg = message_bus.recv() => if msg.is_none() {
+                    tracing::trace!("message bus closed, exiting");
+                    break Ok(());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_steal_filter() {
+        let config = HttpFilterConfig {
+            header_filter: Some("x-user: bear".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            steal_filter(&config).unwrap(),
+            HttpFilter::Header(Filter::new("x-user: bear".into()).unwrap())
+        );
+
+        let config = HttpFilterConfig {
+            path_filter: Some("/api/.*".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            steal_filter(&config).unwrap(),
+            HttpFilter::Path(Filter::new("/api/.*".into()).unwrap())
+        );
+
+        assert!(steal_filter(&HttpFilterConfig::default()).is_err());
+
+        let config = HttpFilterConfig {
+            path_filter: Some("(unclosed".into()),
+            ..Default::default()
+        };
+        assert!(steal_filter(&config).is_err());
+    }
+}
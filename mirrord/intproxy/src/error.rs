@@ -1,4 +1,4 @@
-use std::io;
+use std::{convert::Infallible, io};
 
 use mirrord_intproxy_protocol::{codec::CodecError, LayerToProxyMessage};
 use mirrord_protocol::DaemonMessage;
@@ -50,4 +50,10 @@ pub enum IntProxyError {
     IncomingProxy(#[from] IncomingProxyError),
 }
 
+impl From<Infallible> for IntProxyError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
 pub type Result<T> = core::result::Result<T, IntProxyError>;
@@ -0,0 +1,287 @@
+//! Guardrails for remote file operations, set with `feature.fs.max_file_size`,
+//! `feature.fs.max_transfer_bytes` and `feature.fs.max_open_files`, so that a session can't stream
+//! huge amounts of data through the agent by accident.
+//!
+//! Every process of the session (including the children it spawns) sends its [`FileRequest`]s
+//! through the same internal proxy, so the limits are counted here, for the whole session. A
+//! request that would go past a limit fails without reaching the agent, and the layer passes the
+//! errno on to the application: `EMFILE` for opens, `EDQUOT` for reads and writes.
+//!
+//! The size of a file opened for reading is only known once it's open, so the response to the
+//! open is held back until the agent responds to an [`XstatRequest`] for the file. Opening a file
+//! that's too large fails with `EFBIG`, and the file is closed in the agent.
+
+use std::{collections::HashMap, io};
+
+use mirrord_config::feature::fs::FsConfig;
+use mirrord_intproxy_protocol::{LayerId, MessageId};
+use mirrord_protocol::{
+    file::{
+        BatchFileRequest, BatchFileResponse, OpenFileRequest, OpenRelativeFileRequest,
+        ReadChecksummedFileResponse, ReadFileResponse, WriteFileResponse, XstatRequest,
+    },
+    FileRequest, FileResponse, ResponseError,
+};
+
+/// Counts what the session opened and moved through the agent, see the [module docs](self).
+#[derive(Default)]
+pub struct FileLimits {
+    max_open_files: Option<usize>,
+    max_file_size: Option<u64>,
+    max_transfer_bytes: Option<u64>,
+    /// Bytes read from and written to remote files so far.
+    transferred: u64,
+    /// Descriptors of the files opened for reading, held back until their size is known, by the
+    /// ids of the layer's request.
+    sizing: HashMap<(MessageId, LayerId), u64>,
+}
+
+impl FileLimits {
+    pub fn new(config: &FsConfig) -> Self {
+        Self {
+            max_open_files: config.max_open_files,
+            max_file_size: config.max_file_size,
+            max_transfer_bytes: config.max_transfer_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// The error `req` fails with, when it would go past a limit with `open_files` remote files
+    /// already open (or being opened) in the session.
+    pub fn check(&self, req: &FileRequest, open_files: usize) -> Option<ResponseError> {
+        match req {
+            FileRequest::Open(..) | FileRequest::OpenRelative(..) => {
+                let limit = self.max_open_files?;
+                (open_files >= limit).then(|| {
+                    tracing::warn!(limit, "reached `feature.fs.max_open_files`");
+                    limit_error(libc::EMFILE)
+                })
+            }
+            req if transfers(req) => {
+                let limit = self.max_transfer_bytes?;
+                (self.transferred >= limit).then(|| {
+                    tracing::warn!(limit, "reached `feature.fs.max_transfer_bytes`");
+                    limit_error(libc::EDQUOT)
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Counts the bytes read or written in `res` towards `feature.fs.max_transfer_bytes`.
+    pub fn record(&mut self, res: &FileResponse) {
+        self.transferred += transferred(res);
+    }
+
+    /// When `res` opened a file for reading with `req`, and its size has to be checked, returns
+    /// the [`XstatRequest`] for the file (with the descriptor in `res`). The open is held back
+    /// until [`Self::sized`] is called with the response.
+    pub fn size_request(
+        &mut self,
+        message_id: MessageId,
+        layer_id: LayerId,
+        req: &FileRequest,
+        res: &FileResponse,
+    ) -> Option<FileRequest> {
+        self.max_file_size?;
+
+        let read = match req {
+            FileRequest::Open(OpenFileRequest { open_options, .. })
+            | FileRequest::OpenRelative(OpenRelativeFileRequest { open_options, .. }) => {
+                open_options.read
+            }
+            _ => false,
+        };
+        let FileResponse::Open(Ok(open)) = res else {
+            return None;
+        };
+        if !read {
+            return None;
+        }
+
+        self.sizing.insert((message_id, layer_id), open.fd);
+
+        Some(FileRequest::Xstat(XstatRequest {
+            path: None,
+            fd: Some(open.fd),
+            follow_symlink: true,
+        }))
+    }
+
+    /// When `res` is the response to an [`XstatRequest`] made by [`Self::size_request`], returns
+    /// the descriptor of the file, with the error the open fails with, if any.
+    pub fn sized(
+        &mut self,
+        message_id: MessageId,
+        layer_id: LayerId,
+        res: &FileResponse,
+    ) -> Option<(u64, Option<ResponseError>)> {
+        let fd = self.sizing.remove(&(message_id, layer_id))?;
+
+        let size = match res {
+            FileResponse::Xstat(Ok(stat)) => stat.metadata.size,
+            FileResponse::Xstat(Err(error)) => return Some((fd, Some(error.clone()))),
+            _ => return Some((fd, Some(limit_error(libc::EIO)))),
+        };
+
+        let error = self
+            .max_file_size
+            .filter(|limit| size > *limit)
+            .map(|limit| {
+                tracing::warn!(
+                    size,
+                    limit,
+                    "remote file is larger than `feature.fs.max_file_size`"
+                );
+                limit_error(libc::EFBIG)
+            });
+
+        Some((fd, error))
+    }
+
+    /// Forgets the held back opens, their responses are not coming anymore.
+    pub fn reset(&mut self) {
+        self.sizing.clear();
+    }
+}
+
+/// Whether `req` reads from or writes to a remote file.
+fn transfers(req: &FileRequest) -> bool {
+    match req {
+        FileRequest::Read(..)
+        | FileRequest::ReadLimited(..)
+        | FileRequest::ReadChecksummed(..)
+        | FileRequest::Write(..)
+        | FileRequest::WriteLimited(..) => true,
+        FileRequest::Batch(BatchFileRequest { requests }) => requests.iter().any(transfers),
+        _ => false,
+    }
+}
+
+/// Bytes read or written in `res`.
+fn transferred(res: &FileResponse) -> u64 {
+    match res {
+        FileResponse::Read(Ok(ReadFileResponse { read_amount, .. }))
+        | FileResponse::ReadLimited(Ok(ReadFileResponse { read_amount, .. }))
+        | FileResponse::ReadChecksummed(Ok(ReadChecksummedFileResponse { read_amount, .. })) => {
+            *read_amount
+        }
+        FileResponse::Write(Ok(WriteFileResponse { written_amount }))
+        | FileResponse::WriteLimited(Ok(WriteFileResponse { written_amount })) => *written_amount,
+        FileResponse::Batch(Ok(BatchFileResponse { responses })) => {
+            responses.iter().map(transferred).sum()
+        }
+        _ => 0,
+    }
+}
+
+fn limit_error(errno: i32) -> ResponseError {
+    io::Error::from_raw_os_error(errno).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::file::{
+        MetadataInternal, OpenFileResponse, OpenOptionsInternal, ReadFileRequest, WriteFileRequest,
+        XstatResponse,
+    };
+
+    use super::*;
+
+    fn limits(max_open_files: usize, max_file_size: u64, max_transfer_bytes: u64) -> FileLimits {
+        FileLimits {
+            max_open_files: Some(max_open_files),
+            max_file_size: Some(max_file_size),
+            max_transfer_bytes: Some(max_transfer_bytes),
+            ..Default::default()
+        }
+    }
+
+    fn open(read: bool) -> FileRequest {
+        FileRequest::Open(OpenFileRequest {
+            path: "/app/data".into(),
+            open_options: OpenOptionsInternal {
+                read,
+                write: !read,
+                ..Default::default()
+            },
+        })
+    }
+
+    fn errno(error: Option<ResponseError>) -> Option<i32> {
+        match error? {
+            ResponseError::RemoteIO(error) => error.raw_os_error,
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[test]
+    fn open_files() {
+        let limits = limits(2, 100, 100);
+
+        assert_eq!(errno(limits.check(&open(true), 1)), None);
+        assert_eq!(errno(limits.check(&open(true), 2)), Some(libc::EMFILE));
+        assert_eq!(errno(FileLimits::default().check(&open(true), 1000)), None);
+    }
+
+    #[test]
+    fn transfer() {
+        let mut limits = limits(10, 100, 100);
+        let read = FileRequest::Read(ReadFileRequest {
+            remote_fd: 1,
+            buffer_size: 60,
+        });
+        let write = FileRequest::Batch(BatchFileRequest {
+            requests: vec![FileRequest::Write(WriteFileRequest {
+                fd: 1,
+                write_bytes: vec![0; 60],
+            })],
+        });
+
+        limits.record(&FileResponse::Read(Ok(ReadFileResponse {
+            bytes: vec![0; 60],
+            read_amount: 60,
+        })));
+        assert_eq!(errno(limits.check(&write, 1)), None);
+
+        limits.record(&FileResponse::Batch(Ok(BatchFileResponse {
+            responses: vec![FileResponse::Write(Ok(WriteFileResponse {
+                written_amount: 40,
+            }))],
+        })));
+        assert_eq!(errno(limits.check(&read, 1)), Some(libc::EDQUOT));
+        assert_eq!(errno(limits.check(&write, 1)), Some(libc::EDQUOT));
+        assert_eq!(errno(limits.check(&open(true), 1)), None);
+    }
+
+    #[test]
+    fn file_size() {
+        let mut limits = limits(10, 100, 100);
+        let opened = FileResponse::Open(Ok(OpenFileResponse { fd: 7 }));
+        let stat = |size| {
+            FileResponse::Xstat(Ok(XstatResponse {
+                metadata: MetadataInternal {
+                    size,
+                    ..Default::default()
+                },
+            }))
+        };
+
+        assert!(limits
+            .size_request(1, LayerId(0), &open(false), &opened)
+            .is_none());
+
+        let request = limits.size_request(1, LayerId(0), &open(true), &opened);
+        assert!(matches!(
+            request,
+            Some(FileRequest::Xstat(XstatRequest { fd: Some(7), .. }))
+        ));
+        let (fd, error) = limits.sized(1, LayerId(0), &stat(101)).unwrap();
+        assert_eq!((fd, errno(error)), (7, Some(libc::EFBIG)));
+
+        limits.size_request(2, LayerId(0), &open(true), &opened);
+        assert!(limits.sized(3, LayerId(0), &stat(100)).is_none());
+        let (fd, error) = limits.sized(2, LayerId(0), &stat(100)).unwrap();
+        assert_eq!((fd, errno(error)), (7, None));
+    }
+}
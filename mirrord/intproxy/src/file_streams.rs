@@ -0,0 +1,507 @@
+//! Puts back together the responses that the agent streams in chunks.
+//!
+//! When the agent supports [`FILE_STREAM_VERSION`], large [`FileRequest::Read`]s,
+//! [`FileRequest::ReadLimited`]s and [`FileRequest::GetDEnts64`]s are sent to it as
+//! [`FileRequest::ReadStream`]s and [`FileRequest::GetDEnts64Stream`]s, see [`to_stream`]. The
+//! agent sends the data in chunks of up to [`CHUNK_SIZE`] bytes as soon as it reads them, and we
+//! put them back together into the response the layer expects.
+//!
+//! Once a stream started, every chunk has to come within [`CHUNK_TIMEOUT`] of the previous one,
+//! otherwise the request fails with [`ErrorKindInternal::TimedOut`], the stream is cancelled in
+//! the agent (which moves the file back to where the read started), and the rest of the chunks
+//! of this stream are dropped when they come. So a large read only fails when the agent stops
+//! making progress, not when the whole buffer takes long to arrive.
+//!
+//! The layer can also cancel a request that is being streamed, see [`FileStreams::cancel`].
+//!
+//! [`FILE_STREAM_VERSION`]: mirrord_protocol::file::FILE_STREAM_VERSION
+//! [`ErrorKindInternal::TimedOut`]: mirrord_protocol::ErrorKindInternal::TimedOut
+
+use std::{collections::HashMap, io, time::Duration};
+
+use mirrord_intproxy_protocol::{LayerId, MessageId};
+use mirrord_protocol::{
+    file::{
+        FileStreamEnd, GetDEnts64Chunk, GetDEnts64Request, GetDEnts64Response,
+        GetDEnts64StreamRequest, ReadFileChunk, ReadFileRequest, ReadFileResponse,
+        ReadFileStreamRequest, ReadLimitedFileRequest,
+    },
+    CancelRequest, FileRequest, FileResponse, RemoteResult, ResponseError,
+};
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Largest chunk the agent sends, requests for fewer bytes are not streamed.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// How long to wait for the next chunk of a stream.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest read (or `getdents64`) the agent is asked for when it can't stream the response, since
+/// large responses can lead to timeouts.
+pub const MAX_UNSTREAMED_SIZE: u64 = 1024 * 1024;
+
+/// Limits the `request` to [`MAX_UNSTREAMED_SIZE`] bytes, for agents that can't stream the
+/// response. Like with `read` and `getdents64`, the response can be shorter than asked for.
+pub fn limit_unstreamed(request: FileRequest) -> FileRequest {
+    match request {
+        FileRequest::Read(ReadFileRequest {
+            remote_fd,
+            buffer_size,
+        }) => FileRequest::Read(ReadFileRequest {
+            remote_fd,
+            buffer_size: buffer_size.min(MAX_UNSTREAMED_SIZE),
+        }),
+        FileRequest::ReadLimited(ReadLimitedFileRequest {
+            remote_fd,
+            buffer_size,
+            start_from,
+        }) => FileRequest::ReadLimited(ReadLimitedFileRequest {
+            remote_fd,
+            buffer_size: buffer_size.min(MAX_UNSTREAMED_SIZE),
+            start_from,
+        }),
+        FileRequest::GetDEnts64(GetDEnts64Request {
+            remote_fd,
+            buffer_size,
+        }) => FileRequest::GetDEnts64(GetDEnts64Request {
+            remote_fd,
+            buffer_size: buffer_size.min(MAX_UNSTREAMED_SIZE),
+        }),
+        request => request,
+    }
+}
+
+/// Turns the `request` into its streaming version, when it has one and asks for more than
+/// [`CHUNK_SIZE`] bytes.
+pub fn to_stream(request: FileRequest) -> FileRequest {
+    match request {
+        FileRequest::Read(ReadFileRequest {
+            remote_fd,
+            buffer_size,
+        }) if buffer_size > CHUNK_SIZE => FileRequest::ReadStream(ReadFileStreamRequest {
+            remote_fd,
+            buffer_size,
+            start_from: None,
+            chunk_size: CHUNK_SIZE,
+        }),
+        FileRequest::ReadLimited(ReadLimitedFileRequest {
+            remote_fd,
+            buffer_size,
+            start_from,
+        }) if buffer_size > CHUNK_SIZE => FileRequest::ReadStream(ReadFileStreamRequest {
+            remote_fd,
+            buffer_size,
+            start_from: Some(start_from),
+            chunk_size: CHUNK_SIZE,
+        }),
+        FileRequest::GetDEnts64(GetDEnts64Request {
+            remote_fd,
+            buffer_size,
+        }) if buffer_size > CHUNK_SIZE => FileRequest::GetDEnts64Stream(GetDEnts64StreamRequest {
+            remote_fd,
+            buffer_size,
+            chunk_size: CHUNK_SIZE,
+        }),
+        request => request,
+    }
+}
+
+/// A stream whose chunks are being put together.
+struct Stream {
+    /// Response to the layer's request, with the chunks received so far.
+    response: FileResponse,
+    next_sequence: u64,
+    deadline: Instant,
+}
+
+impl Stream {
+    /// Starts putting together the response to the layer's `request`, [`None`] when it's not a
+    /// request that is streamed.
+    fn new(request: &FileRequest) -> Option<Self> {
+        let empty_read = || {
+            Ok(ReadFileResponse {
+                bytes: Vec::new(),
+                read_amount: 0,
+            })
+        };
+
+        let response = match request {
+            FileRequest::Read(..) => FileResponse::Read(empty_read()),
+            FileRequest::ReadLimited(..) => FileResponse::ReadLimited(empty_read()),
+            FileRequest::GetDEnts64(GetDEnts64Request { remote_fd, .. }) => {
+                FileResponse::GetDEnts64(Ok(GetDEnts64Response {
+                    fd: *remote_fd,
+                    entries: Vec::new(),
+                    result_size: 0,
+                }))
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            response,
+            next_sequence: 0,
+            deadline: Instant::now() + CHUNK_TIMEOUT,
+        })
+    }
+
+    /// Adds the `chunk` to the response, returns whether the stream ended.
+    fn add(&mut self, chunk: FileResponse) -> RemoteResult<bool> {
+        match (&mut self.response, chunk) {
+            (_, FileResponse::StreamEnd(FileStreamEnd { chunks }))
+                if chunks == self.next_sequence =>
+            {
+                return Ok(true)
+            }
+            (
+                FileResponse::Read(Ok(read)) | FileResponse::ReadLimited(Ok(read)),
+                FileResponse::ReadChunk(Ok(ReadFileChunk { sequence, bytes })),
+            ) if sequence == self.next_sequence => {
+                read.read_amount += bytes.len() as u64;
+                read.bytes.extend(bytes);
+            }
+            (
+                FileResponse::GetDEnts64(Ok(dents)),
+                FileResponse::GetDEnts64Chunk(Ok(GetDEnts64Chunk {
+                    sequence,
+                    entries,
+                    result_size,
+                    ..
+                })),
+            ) if sequence == self.next_sequence => {
+                dents.entries.extend(entries);
+                dents.result_size += result_size;
+            }
+            (
+                _,
+                FileResponse::ReadChunk(Err(error)) | FileResponse::GetDEnts64Chunk(Err(error)),
+            ) => return Err(error),
+            (_, chunk) => {
+                warn!(
+                    ?chunk,
+                    next_sequence = self.next_sequence,
+                    "unexpected chunk of a file stream"
+                );
+                return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+            }
+        }
+
+        self.next_sequence += 1;
+        self.deadline = Instant::now() + CHUNK_TIMEOUT;
+        Ok(false)
+    }
+
+    /// Fails the response with the `error`, unless some data was received already. Then the
+    /// response has this data, like a `read` that fails after reading some bytes returns them,
+    /// since the agent is already past them in the file.
+    fn fail(self, error: ResponseError) -> FileResponse {
+        let partial = match &self.response {
+            FileResponse::Read(Ok(read)) | FileResponse::ReadLimited(Ok(read)) => {
+                read.read_amount > 0
+            }
+            FileResponse::GetDEnts64(Ok(dents)) => !dents.entries.is_empty(),
+            _ => false,
+        };
+        if partial {
+            return self.response;
+        }
+
+        self.error(error)
+    }
+
+    /// Fails the response with the `error`, dropping the data received so far.
+    fn error(self, error: ResponseError) -> FileResponse {
+        match self.response {
+            FileResponse::ReadLimited(..) => FileResponse::ReadLimited(Err(error)),
+            FileResponse::GetDEnts64(..) => FileResponse::GetDEnts64(Err(error)),
+            _ => FileResponse::Read(Err(error)),
+        }
+    }
+}
+
+/// Puts the streamed responses back together, see the [module docs](self).
+///
+/// The agent handles [`FileRequest`]s one by one, so the chunks of a stream are never mixed
+/// with other responses.
+#[derive(Default)]
+pub struct FileStreams {
+    /// The stream being received.
+    current: Option<Stream>,
+    /// Number of streams that failed before they ended, the chunks that are still coming for
+    /// them are dropped.
+    abandoned: usize,
+    /// Number of streams requested from the agent, see [`CancelRequest::FileStream`].
+    sent: u64,
+    /// Numbers of the streams that were requested for the layers and did not end yet, by the
+    /// layer and the id of its request.
+    ids: HashMap<(LayerId, MessageId), u64>,
+}
+
+impl FileStreams {
+    /// When the stream being received fails, if no chunk comes before, see [`Self::timed_out`].
+    pub fn deadline(&self) -> Option<Instant> {
+        self.current.as_ref().map(|stream| stream.deadline)
+    }
+
+    /// Handles a `response` from the agent to the oldest `request` that is waiting for one.
+    ///
+    /// Returns the response to this request, or [`None`] when the `response` was a chunk of a
+    /// stream that goes on, or of a stream that failed.
+    pub fn response(
+        &mut self,
+        request: Option<&FileRequest>,
+        response: FileResponse,
+    ) -> Option<FileResponse> {
+        let ends_stream = matches!(
+            response,
+            FileResponse::StreamEnd(..)
+                | FileResponse::ReadChunk(Err(..))
+                | FileResponse::GetDEnts64Chunk(Err(..))
+        );
+        if !ends_stream
+            && !matches!(
+                response,
+                FileResponse::ReadChunk(..) | FileResponse::GetDEnts64Chunk(..)
+            )
+        {
+            return Some(response);
+        }
+
+        if self.abandoned > 0 {
+            self.abandoned -= usize::from(ends_stream);
+            return None;
+        }
+
+        let Some(mut stream) = self.current.take().or_else(|| Stream::new(request?)) else {
+            warn!(
+                ?response,
+                ?request,
+                "file stream chunk for a request that is not streamed"
+            );
+            return None;
+        };
+
+        match stream.add(response) {
+            Ok(true) => Some(stream.response),
+            Ok(false) => {
+                self.current = Some(stream);
+                None
+            }
+            Err(error) => {
+                self.abandoned += usize::from(!ends_stream);
+                Some(stream.fail(error))
+            }
+        }
+    }
+
+    /// Fails the stream being received, after its [`Self::deadline`] passed.
+    ///
+    /// Returns the response to the request of this stream, an error even when some data came
+    /// already. The stream has to be [cancelled](Self::cancel) in the agent, which moves the file
+    /// back to where the read started, since we don't know how far the agent got.
+    pub fn timed_out(&mut self) -> Option<FileResponse> {
+        let stream = self.current.take()?;
+        self.abandoned += 1;
+
+        Some(stream.error(io::Error::from(io::ErrorKind::TimedOut).into()))
+    }
+
+    /// Records that the request `message_id` of the layer `layer_id` was sent to the agent as
+    /// `request`.
+    pub fn request_sent(
+        &mut self,
+        layer_id: LayerId,
+        message_id: MessageId,
+        request: &FileRequest,
+    ) {
+        if matches!(
+            request,
+            FileRequest::ReadStream(..) | FileRequest::GetDEnts64Stream(..)
+        ) {
+            self.ids.insert((layer_id, message_id), self.sent);
+            self.sent += 1;
+        }
+    }
+
+    /// Forgets the request `message_id` of the layer `layer_id`, it got its response.
+    pub fn responded(&mut self, layer_id: LayerId, message_id: MessageId) {
+        self.ids.remove(&(layer_id, message_id));
+    }
+
+    /// Returns the [`CancelRequest`] for the request `message_id` of the layer `layer_id`, when
+    /// it's being streamed.
+    pub fn cancel(&mut self, layer_id: LayerId, message_id: MessageId) -> Option<CancelRequest> {
+        self.ids
+            .remove(&(layer_id, message_id))
+            .map(CancelRequest::FileStream)
+    }
+
+    /// Returns the [`CancelRequest`]s for all requests of the layer `layer_id` that are being
+    /// streamed.
+    pub fn cancel_layer(&mut self, layer_id: LayerId) -> Vec<CancelRequest> {
+        let cancelled = self
+            .ids
+            .keys()
+            .filter(|(id, _)| *id == layer_id)
+            .copied()
+            .collect::<Vec<_>>();
+
+        cancelled
+            .into_iter()
+            .filter_map(|(layer_id, message_id)| self.cancel(layer_id, message_id))
+            .collect()
+    }
+
+    /// Forgets all streams, the agent connection they came through is gone.
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::{ErrorKindInternal, RemoteIOError};
+
+    use super::*;
+
+    fn read_request() -> FileRequest {
+        FileRequest::Read(ReadFileRequest {
+            remote_fd: 1,
+            buffer_size: 4 * CHUNK_SIZE,
+        })
+    }
+
+    fn chunk(sequence: u64, bytes: &[u8]) -> FileResponse {
+        FileResponse::ReadChunk(Ok(ReadFileChunk {
+            sequence,
+            bytes: bytes.to_vec(),
+        }))
+    }
+
+    #[test]
+    fn puts_chunks_together() {
+        let request = read_request();
+        let mut streams = FileStreams::default();
+
+        assert!(streams
+            .response(Some(&request), chunk(0, b"hello "))
+            .is_none());
+        assert!(streams.deadline().is_some());
+        assert!(streams
+            .response(Some(&request), chunk(1, b"world"))
+            .is_none());
+
+        let response = streams.response(
+            Some(&request),
+            FileResponse::StreamEnd(FileStreamEnd { chunks: 2 }),
+        );
+        assert_eq!(
+            response,
+            Some(FileResponse::Read(Ok(ReadFileResponse {
+                bytes: b"hello world".to_vec(),
+                read_amount: 11,
+            })))
+        );
+        assert!(streams.deadline().is_none());
+    }
+
+    #[test]
+    fn drops_chunks_after_timeout() {
+        let request = read_request();
+        let mut streams = FileStreams::default();
+
+        assert!(streams
+            .response(Some(&request), chunk(0, b"hello"))
+            .is_none());
+        assert_eq!(
+            streams.timed_out(),
+            Some(FileResponse::Read(Err(io::Error::from(
+                io::ErrorKind::TimedOut
+            )
+            .into())))
+        );
+
+        let next_request = FileRequest::Read(ReadFileRequest {
+            remote_fd: 1,
+            buffer_size: 10,
+        });
+        assert!(streams
+            .response(Some(&next_request), chunk(1, b"world"))
+            .is_none());
+        assert!(streams
+            .response(
+                Some(&next_request),
+                FileResponse::StreamEnd(FileStreamEnd { chunks: 2 })
+            )
+            .is_none());
+
+        let response = FileResponse::Read(Ok(ReadFileResponse {
+            bytes: b"next".to_vec(),
+            read_amount: 4,
+        }));
+        assert_eq!(
+            streams.response(Some(&next_request), response.clone()),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn fails_without_chunks() {
+        let request = read_request();
+        let mut streams = FileStreams::default();
+
+        let error = ResponseError::RemoteIO(RemoteIOError {
+            raw_os_error: Some(5),
+            kind: ErrorKindInternal::Other,
+        });
+        assert_eq!(
+            streams.response(Some(&request), FileResponse::ReadChunk(Err(error.clone()))),
+            Some(FileResponse::Read(Err(error)))
+        );
+        assert!(streams.deadline().is_none());
+    }
+
+    #[test]
+    fn keeps_chunks_before_error() {
+        let request = FileRequest::ReadLimited(ReadLimitedFileRequest {
+            remote_fd: 1,
+            buffer_size: 4 * CHUNK_SIZE,
+            start_from: 10,
+        });
+        let mut streams = FileStreams::default();
+
+        assert!(streams
+            .response(Some(&request), chunk(0, b"hello"))
+            .is_none());
+        let response = streams.response(
+            Some(&request),
+            FileResponse::ReadChunk(Err(ResponseError::NotFound(1))),
+        );
+        assert_eq!(
+            response,
+            Some(FileResponse::ReadLimited(Ok(ReadFileResponse {
+                bytes: b"hello".to_vec(),
+                read_amount: 5,
+            })))
+        );
+        assert!(streams.deadline().is_none());
+    }
+
+    #[test]
+    fn limits_unstreamed() {
+        assert_eq!(
+            limit_unstreamed(read_request()),
+            FileRequest::Read(ReadFileRequest {
+                remote_fd: 1,
+                buffer_size: MAX_UNSTREAMED_SIZE,
+            })
+        );
+
+        let small = FileRequest::Read(ReadFileRequest {
+            remote_fd: 1,
+            buffer_size: 10,
+        });
+        assert_eq!(limit_unstreamed(small.clone()), small);
+    }
+}
@@ -1,16 +1,14 @@
-//! Implementation of `layer <-> proxy` connection through a [`TcpStream`].
+//! Implementation of `layer <-> proxy` connection through a [`LayerStream`].
 
 use mirrord_intproxy_protocol::{
-    codec::{self, AsyncDecoder, AsyncEncoder, CodecError},
+    codec::{AsyncDecoder, AsyncEncoder, CodecError},
     LayerId, LayerToProxyMessage, LocalMessage, ProxyToLayerMessage,
 };
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
-};
+use tokio::io::{ReadHalf, WriteHalf};
 
 use crate::{
     background_tasks::{BackgroundTask, MessageBus},
+    layer_listener::LayerStream,
     main_tasks::FromLayer,
     ProxyMessage,
 };
@@ -18,19 +16,19 @@ use crate::{
 /// Handles logic of a single `layer <-> proxy` connection.
 /// Run as a [`BackgroundTask`].
 pub struct LayerConnection {
-    layer_codec_tx: AsyncEncoder<LocalMessage<ProxyToLayerMessage>, OwnedWriteHalf>,
-    layer_codec_rx: AsyncDecoder<LocalMessage<LayerToProxyMessage>, OwnedReadHalf>,
+    layer_codec_tx: AsyncEncoder<LocalMessage<ProxyToLayerMessage>, WriteHalf<LayerStream>>,
+    layer_codec_rx: AsyncDecoder<LocalMessage<LayerToProxyMessage>, ReadHalf<LayerStream>>,
     layer_id: LayerId,
 }
 
 impl LayerConnection {
-    /// Wraps a raw [`TcpStream`] to be used as a `layer <-> proxy` connection.
-    pub fn new(stream: TcpStream, layer_id: LayerId) -> Self {
-        let (layer_codec_tx, layer_codec_rx) = codec::make_async_framed(stream);
+    /// Wraps a raw [`LayerStream`] to be used as a `layer <-> proxy` connection.
+    pub fn new(stream: LayerStream, layer_id: LayerId) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
 
         Self {
-            layer_codec_rx,
-            layer_codec_tx,
+            layer_codec_rx: AsyncDecoder::new(reader),
+            layer_codec_tx: AsyncEncoder::new(writer),
             layer_id,
         }
     }
@@ -5,11 +5,11 @@ use mirrord_intproxy_protocol::{
     LayerId, LayerToProxyMessage, LocalMessage, NewSessionRequest, ProxyToLayerMessage,
 };
 use thiserror::Error;
-use tokio::net::{TcpListener, TcpStream};
 use tracing::info;
 
 use crate::{
     background_tasks::{BackgroundTask, MessageBus},
+    layer_listener::{LayerListener, LayerStream},
     main_tasks::NewLayer,
     ProxyMessage,
 };
@@ -30,12 +30,12 @@ pub enum LayerInitializerError {
 /// Run as a [`BackgroundTask`].
 #[derive(Debug)]
 pub struct LayerInitializer {
-    listener: TcpListener,
+    listener: LayerListener,
     next_layer_id: LayerId,
 }
 
 impl LayerInitializer {
-    pub fn new(listener: TcpListener) -> Self {
+    pub fn new(listener: LayerListener) -> Self {
         Self {
             listener,
             next_layer_id: LayerId(0),
@@ -46,7 +46,7 @@ impl LayerInitializer {
     #[tracing::instrument(level = "trace" ret)]
     async fn handle_new_stream(
         &mut self,
-        stream: TcpStream,
+        stream: LayerStream,
     ) -> Result<NewLayer, LayerInitializerError> {
         let mut decoder: AsyncDecoder<LocalMessage<LayerToProxyMessage>, _> =
             AsyncDecoder::new(stream);
@@ -0,0 +1,178 @@
+//! Sockets where the internal proxy accepts the connections of the layers.
+//!
+//! By default the proxy listens on a localhost TCP port. With `internal_proxy.unix_socket`, it
+//! listens on a Unix socket instead, which only the user can connect to.
+
+use std::{
+    fmt,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+/// Listens for the connections of the layers.
+#[derive(Debug)]
+pub enum LayerListener {
+    Tcp(TcpListener),
+    /// The socket file and its directory are removed when this listener is dropped.
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+impl LayerListener {
+    /// Binds a Unix socket in a new directory in `parent`, that only the user can access.
+    ///
+    /// The directory is created with a random name, so no other user can make us bind the socket
+    /// somewhere else (e.g. with a symlink), or connect to it before its permissions are set.
+    pub fn bind_unix(parent: &Path) -> io::Result<Self> {
+        let dir = parent.join(format!(
+            "mirrord-intproxy-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 10)
+        ));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let path = dir.join("intproxy.sock");
+        let listener = UnixListener::bind(&path).inspect_err(|_| {
+            let _ = fs::remove_dir(&dir);
+        })?;
+
+        Ok(Self::Unix { listener, path })
+    }
+
+    /// Accepts the connection of a layer, returning the stream and a description of the peer.
+    pub async fn accept(&self) -> io::Result<(LayerStream, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((LayerStream::Tcp(stream), peer.to_string()))
+            }
+            Self::Unix { listener, path } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((LayerStream::Unix(stream), path.display().to_string()))
+            }
+        }
+    }
+
+    /// Returns what the layers should connect to, that is the port of the TCP listener or the
+    /// path of the Unix socket.
+    pub fn address(&self) -> io::Result<String> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.port().to_string()),
+            Self::Unix { path, .. } => Ok(path.display().to_string()),
+        }
+    }
+}
+
+impl From<TcpListener> for LayerListener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+impl Drop for LayerListener {
+    fn drop(&mut self) {
+        if let Self::Unix { path, .. } = self {
+            let _ = fs::remove_file(&path);
+            if let Some(dir) = path.parent() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+    }
+}
+
+/// Connection with a layer, accepted by the [`LayerListener`].
+pub enum LayerStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl fmt::Debug for LayerStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(stream) => stream.fmt(f),
+            Self::Unix(stream) => stream.fmt(f),
+        }
+    }
+}
+
+impl AsyncRead for LayerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LayerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unix_socket() {
+        let listener = LayerListener::bind_unix(&std::env::temp_dir()).unwrap();
+        let path = PathBuf::from(listener.address().unwrap());
+        let dir = path.parent().unwrap().to_owned();
+        assert_eq!(
+            fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        drop(listener);
+        assert!(!path.exists());
+        assert!(!dir.exists());
+    }
+}
@@ -1,25 +1,42 @@
 #![warn(clippy::indexing_slicing)]
 
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use background_tasks::{BackgroundTasks, TaskSender, TaskUpdate};
+use backpressure::ConnectionBuffers;
+use config_watcher::ConfigWatcher;
+use file_limits::FileLimits;
 use layer_conn::LayerConnection;
 use layer_initializer::LayerInitializer;
+use layer_listener::LayerListener;
 use main_tasks::{FromLayer, LayerForked, MainTaskId, ProxyMessage, ToLayer};
+use metrics::{Metrics, MetricsServer};
 use mirrord_analytics::NullReporter;
 use mirrord_config::LayerConfig;
 use mirrord_intproxy_protocol::{LayerId, LayerToProxyMessage, LocalMessage};
-use mirrord_protocol::{ClientMessage, DaemonMessage, LogLevel, CLIENT_READY_FOR_LOGS};
-use ping_pong::{AgentMessageNotification, PingPong};
+use mirrord_progress::{
+    timings::{self, StartupPhase},
+    IpcOutput,
+};
+use mirrord_protocol::{
+    capabilities::Capabilities, tcp::DaemonTcp, ClientMessage, DaemonMessage, LogLevel,
+    CAPABILITIES_VERSION, CLIENT_READY_FOR_LOGS,
+};
+use ping_pong::{AgentMessageNotification, PingPong, PingPongMessage};
 use proxies::{
+    exec::{ExecProxy, ExecProxyMessage},
     incoming::{IncomingProxy, IncomingProxyMessage},
     outgoing::{OutgoingProxy, OutgoingProxyMessage},
     simple::{SimpleProxy, SimpleProxyMessage},
 };
-use tokio::{net::TcpListener, time};
+use recording::{Recorder, RECORD_FILE_ENV};
+use status::{SessionStatus, StatusEvents, StatusServer};
+use tokio::{net::TcpListener, sync::watch, time};
+use traffic_log::TrafficLog;
+use wake_detector::WakeDetector;
 
 use crate::{
-    agent_conn::{AgentConnectInfo, AgentConnection},
+    agent_conn::{AgentConnectInfo, AgentConnection, AgentConnectionMessage},
     background_tasks::TaskError,
     error::IntProxyError,
     main_tasks::LayerClosed,
@@ -27,14 +44,27 @@ use crate::{
 
 pub mod agent_conn;
 mod background_tasks;
+mod backpressure;
+mod config_watcher;
 pub mod error;
+mod file_limits;
+mod file_streams;
 mod layer_conn;
 mod layer_initializer;
+pub mod layer_listener;
 mod main_tasks;
+mod metrics;
 mod ping_pong;
 mod proxies;
+pub mod recording;
+pub mod remote_env;
+mod remote_files;
 mod remote_resources;
 mod request_queue;
+pub mod secrets;
+pub mod status;
+mod traffic_log;
+mod wake_detector;
 
 /// [`TaskSender`]s for main background tasks. See [`MainTaskId`].
 struct TaskTxs {
@@ -44,7 +74,13 @@ struct TaskTxs {
     simple: TaskSender<SimpleProxy>,
     outgoing: TaskSender<OutgoingProxy>,
     incoming: TaskSender<IncomingProxy>,
+    exec: TaskSender<ExecProxy>,
     ping_pong: TaskSender<PingPong>,
+    _wake_detector: TaskSender<WakeDetector>,
+    _config_watcher: Option<TaskSender<ConfigWatcher>>,
+    _status_server: Option<TaskSender<StatusServer>>,
+    _status_events: Option<TaskSender<StatusEvents>>,
+    _metrics_server: Option<TaskSender<MetricsServer>>,
 }
 
 /// This struct contains logic for proxying between multiple layer instances and one agent.
@@ -56,6 +92,15 @@ pub struct IntProxy {
     any_connection_accepted: bool,
     background_tasks: BackgroundTasks<MainTaskId, ProxyMessage, IntProxyError>,
     task_txs: TaskTxs,
+    /// What the session is doing, served by the [`StatusServer`].
+    status: watch::Sender<SessionStatus>,
+    /// Writes the stolen HTTP traffic to the record file of `mirrord exec --record`.
+    recorder: Option<Recorder>,
+    /// Metrics of the session, served by the [`MetricsServer`] when `internal_proxy.metrics` is
+    /// set.
+    metrics: Option<watch::Sender<Metrics>>,
+    /// Writes the intercepted connections and requests to `internal_proxy.traffic_log`.
+    traffic_log: Option<TrafficLog>,
 }
 
 impl IntProxy {
@@ -63,24 +108,135 @@ impl IntProxy {
     const CHANNEL_SIZE: usize = 512;
     /// How long can the agent connection remain silent.
     const PING_INTERVAL: Duration = Duration::from_secs(30);
+    /// How often we check whether the machine was asleep.
+    const WAKE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+    /// How long the machine must have been asleep for us to reset the agent connection.
+    const WAKE_THRESHOLD: Duration = Duration::from_secs(15);
+    /// How often we check whether the config file changed.
+    const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(2);
 
     /// Initiates a new agent connection and creates a new [`IntProxy`].
     /// The returned instance will accept connections from the layers using the given
-    /// [`TcpListener`].
+    /// [`LayerListener`].
     pub async fn new(
         config: &LayerConfig,
         agent_connect_info: Option<AgentConnectInfo>,
-        listener: TcpListener,
+        listener: LayerListener,
     ) -> Result<Self, IntProxyError> {
+        let status = SessionStatus::new(config, agent_connect_info.as_ref());
+
         let mut reporter = NullReporter::default();
         let agent_conn = AgentConnection::new(config, agent_connect_info, &mut reporter).await?;
-        Ok(Self::new_with_connection(agent_conn, listener))
+
+        let incoming_config = &config.feature.network.incoming;
+        let deliveries = if incoming_config.is_steal() {
+            incoming_config.steal_delivery.clone()
+        } else {
+            if !incoming_config.steal_delivery.is_empty() {
+                tracing::warn!("`steal_delivery` is ignored, because incoming mode is not steal");
+            }
+
+            Default::default()
+        };
+
+        // Only a filter can be changed during the session, the subscriptions can't switch between
+        // filtered and unfiltered.
+        let has_http_filter = incoming_config.http_filter.header_filter.is_some()
+            || incoming_config.http_filter.path_filter.is_some();
+        let config_watcher = std::env::var_os("MIRRORD_CONFIG_FILE")
+            .filter(|_| incoming_config.is_steal() && has_http_filter)
+            .map(|path| {
+                ConfigWatcher::new(
+                    PathBuf::from(path),
+                    incoming_config.http_filter.clone(),
+                    Self::CONFIG_CHECK_INTERVAL,
+                )
+            });
+
+        let (status, status_rx) = watch::channel(status);
+        let status_server = StatusServer::bind(status_rx)
+            .inspect_err(|error| {
+                tracing::warn!(%error, "failed to serve the session status for `mirrord status`")
+            })
+            .ok();
+
+        let recorder = std::env::var_os(RECORD_FILE_ENV).and_then(|path| {
+            Recorder::open(path.as_ref())
+                .inspect_err(
+                    |error| tracing::warn!(%error, "failed to open the record file, not recording"),
+                )
+                .ok()
+        });
+
+        let buffers = ConnectionBuffers::new(&config.internal_proxy);
+        let mut proxy = Self::new_with_incoming(
+            agent_conn,
+            listener,
+            IncomingProxy::new(deliveries, buffers),
+            OutgoingProxy::new(buffers),
+            SimpleProxy::new(FileLimits::new(&config.feature.fs)),
+            config_watcher,
+            status,
+            status_server,
+            recorder,
+        );
+
+        if let Some(address) = config.internal_proxy.metrics {
+            proxy.serve_metrics(address).await;
+        }
+
+        if let Some(output) = IpcOutput::from_env() {
+            proxy.send_status_events(output);
+        }
+
+        proxy.traffic_log = config
+            .internal_proxy
+            .traffic_log
+            .as_deref()
+            .and_then(|path| {
+                TrafficLog::open(path)
+                    .inspect_err(|error| tracing::warn!(%error, "failed to open the traffic log"))
+                    .ok()
+            });
+
+        Ok(proxy)
     }
 
     /// Creates a new [`IntProxy`] using existing [`AgentConnection`].
     /// The returned instance will accept connections from the layers using the given
     /// [`TcpListener`].
     pub fn new_with_connection(agent_conn: AgentConnection, listener: TcpListener) -> Self {
+        let (status, _) = watch::channel(SessionStatus::default());
+
+        Self::new_with_incoming(
+            agent_conn,
+            listener.into(),
+            IncomingProxy::default(),
+            OutgoingProxy::default(),
+            SimpleProxy::default(),
+            None,
+            status,
+            None,
+            None,
+        )
+    }
+
+    /// Creates a new [`IntProxy`] using existing [`AgentConnection`], the given
+    /// [`IncomingProxy`], [`OutgoingProxy`] and [`SimpleProxy`], optionally a [`ConfigWatcher`] for
+    /// reloading the HTTP filter, and the session status with its optional [`StatusServer`],
+    /// and optionally a [`Recorder`].
+    #[allow(clippy::too_many_arguments)]
+    fn new_with_incoming(
+        agent_conn: AgentConnection,
+        listener: LayerListener,
+        incoming: IncomingProxy,
+        outgoing: OutgoingProxy,
+        simple: SimpleProxy,
+        config_watcher: Option<ConfigWatcher>,
+        status: watch::Sender<SessionStatus>,
+        status_server: Option<StatusServer>,
+        recorder: Option<Recorder>,
+    ) -> Self {
         let mut background_tasks: BackgroundTasks<MainTaskId, ProxyMessage, IntProxyError> =
             Default::default();
 
@@ -96,19 +252,29 @@ impl IntProxy {
             MainTaskId::PingPong,
             Self::CHANNEL_SIZE,
         );
-        let simple = background_tasks.register(
-            SimpleProxy::default(),
-            MainTaskId::SimpleProxy,
+        let wake_detector = background_tasks.register(
+            WakeDetector::new(Self::WAKE_CHECK_INTERVAL, Self::WAKE_THRESHOLD),
+            MainTaskId::WakeDetector,
             Self::CHANNEL_SIZE,
         );
-        let outgoing = background_tasks.register(
-            OutgoingProxy::default(),
-            MainTaskId::OutgoingProxy,
-            Self::CHANNEL_SIZE,
-        );
-        let incoming = background_tasks.register(
-            IncomingProxy::default(),
-            MainTaskId::IncomingProxy,
+        let config_watcher = config_watcher.map(|config_watcher| {
+            background_tasks.register(
+                config_watcher,
+                MainTaskId::ConfigWatcher,
+                Self::CHANNEL_SIZE,
+            )
+        });
+        let status_server = status_server.map(|status_server| {
+            background_tasks.register(status_server, MainTaskId::StatusServer, Self::CHANNEL_SIZE)
+        });
+        let simple = background_tasks.register(simple, MainTaskId::SimpleProxy, Self::CHANNEL_SIZE);
+        let outgoing =
+            background_tasks.register(outgoing, MainTaskId::OutgoingProxy, Self::CHANNEL_SIZE);
+        let incoming =
+            background_tasks.register(incoming, MainTaskId::IncomingProxy, Self::CHANNEL_SIZE);
+        let exec = background_tasks.register(
+            ExecProxy::default(),
+            MainTaskId::ExecProxy,
             Self::CHANNEL_SIZE,
         );
 
@@ -122,11 +288,54 @@ impl IntProxy {
                 simple,
                 outgoing,
                 incoming,
+                exec,
                 ping_pong,
+                _wake_detector: wake_detector,
+                _config_watcher: config_watcher,
+                _status_server: status_server,
+                _status_events: None,
+                _metrics_server: None,
             },
+            status,
+            recorder,
+            metrics: None,
+            traffic_log: None,
         }
     }
 
+    /// Starts recording the [`Metrics`] of the session, and serving them on the given `address`.
+    async fn serve_metrics(&mut self, address: SocketAddr) {
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                tracing::warn!(%error, %address, "failed to serve the metrics");
+                return;
+            }
+        };
+
+        let (metrics, metrics_rx) = watch::channel(Metrics::default());
+        let metrics_server = self.background_tasks.register(
+            MetricsServer::new(listener, metrics_rx),
+            MainTaskId::MetricsServer,
+            Self::CHANNEL_SIZE,
+        );
+
+        self.task_txs._metrics_server = Some(metrics_server);
+        self.metrics = Some(metrics);
+    }
+
+    /// Starts sending the [`StatusEvents`] of the session to the `output` of
+    /// `--progress=ipc:<path>`.
+    fn send_status_events(&mut self, output: IpcOutput) {
+        let status_events = self.background_tasks.register(
+            StatusEvents::new(output, self.status.subscribe()),
+            MainTaskId::StatusEvents,
+            Self::CHANNEL_SIZE,
+        );
+
+        self.task_txs._status_events = Some(status_events);
+    }
+
     /// Runs main event loop of this proxy.
     /// Expects to accept the first layer connection within the given `first_timeout`.
     /// Exits after `idle_timeout` when there are no more layer connections.
@@ -186,6 +395,8 @@ impl IntProxy {
                     Self::CHANNEL_SIZE,
                 );
                 self.task_txs.layers.insert(new_layer.id, tx);
+                self.status
+                    .send_modify(|status| status.processes = self.task_txs.layers.len());
 
                 if let Some(parent) = new_layer.parent_id {
                     let msg = LayerForked {
@@ -205,7 +416,20 @@ impl IntProxy {
             }
             ProxyMessage::FromAgent(msg) => self.handle_agent_message(msg).await?,
             ProxyMessage::FromLayer(msg) => self.handle_layer_message(msg).await?,
-            ProxyMessage::ToAgent(msg) => self.task_txs.agent.send(msg).await,
+            ProxyMessage::ToAgent(msg) => {
+                self.status
+                    .send_modify(|status| status.client_message(&msg));
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.client_message(&msg);
+                }
+                if let Some(metrics) = &self.metrics {
+                    metrics.send_modify(|metrics| metrics.client_message(&msg));
+                }
+                if let Some(traffic_log) = &mut self.traffic_log {
+                    traffic_log.client_message(&msg);
+                }
+                self.task_txs.agent.send(msg).await
+            }
             ProxyMessage::ToLayer(msg) => {
                 let ToLayer {
                     message,
@@ -221,11 +445,101 @@ impl IntProxy {
                     .await;
                 }
             }
+            ProxyMessage::AgentReconnecting(attempt) => {
+                tracing::warn!(attempt, "connection with the agent was lost, reconnecting");
+
+                self.status
+                    .send_modify(|status| status.reconnecting = Some(attempt));
+                self.task_txs
+                    .ping_pong
+                    .send(PingPongMessage::AgentReconnecting)
+                    .await;
+            }
+            ProxyMessage::AgentReconnected => self.handle_agent_reconnected().await,
+            ProxyMessage::AgentConnectionReset => self.handle_agent_connection_reset().await,
+            ProxyMessage::SystemWoke => {
+                self.task_txs
+                    .agent
+                    .send(AgentConnectionMessage::Reset)
+                    .await
+            }
+            ProxyMessage::HttpFilterChanged(filter) => {
+                self.task_txs
+                    .incoming
+                    .send(IncomingProxyMessage::HttpFilterChanged(filter))
+                    .await
+            }
         }
 
         Ok(())
     }
 
+    /// Resumes the session after the connection with the agent was established again.
+    ///
+    /// The new agent client knows nothing about us, so we negotiate the protocol version again
+    /// and let the background tasks restore their state.
+    async fn handle_agent_reconnected(&mut self) {
+        tracing::warn!("connection with the agent was reestablished, resuming the session");
+
+        self.status.send_modify(|status| {
+            status.reconnecting = None;
+            status.reconnects += 1;
+        });
+        if let Some(metrics) = &self.metrics {
+            metrics.send_modify(Metrics::agent_reconnected);
+        }
+        if let Some(traffic_log) = &mut self.traffic_log {
+            traffic_log.agent_reconnected();
+        }
+
+        self.task_txs
+            .agent
+            .send(ClientMessage::SwitchProtocolVersion(
+                mirrord_protocol::VERSION.clone(),
+            ))
+            .await;
+
+        self.task_txs
+            .ping_pong
+            .send(PingPongMessage::AgentReconnected)
+            .await;
+        self.task_txs
+            .simple
+            .send(SimpleProxyMessage::AgentReconnected)
+            .await;
+        self.task_txs
+            .outgoing
+            .send(OutgoingProxyMessage::AgentReconnected)
+            .await;
+        self.task_txs
+            .incoming
+            .send(IncomingProxyMessage::AgentReconnected)
+            .await;
+        self.task_txs
+            .exec
+            .send(ExecProxyMessage::AgentReconnected)
+            .await;
+    }
+
+    /// Fails the requests that were sent through the dropped agent connection, so the layers don't
+    /// wait until we connect again. They get
+    /// [`ProxyToLayerMessage::Interrupted`](mirrord_intproxy_protocol::ProxyToLayerMessage::Interrupted)
+    /// and can retry.
+    async fn handle_agent_connection_reset(&mut self) {
+        self.task_txs
+            .simple
+            .send(SimpleProxyMessage::AgentConnectionReset)
+            .await;
+        self.task_txs
+            .outgoing
+            .send(OutgoingProxyMessage::AgentConnectionReset)
+            .await;
+        self.task_txs
+            .exec
+            .send(ExecProxyMessage::AgentConnectionReset)
+            .await;
+    }
+
     /// Handles a [`TaskUpdate`] from one of the main tasks (see [`MainTaskId`]).
     async fn handle_task_update(
         &mut self,
@@ -242,12 +556,22 @@ impl IntProxy {
                     .simple
                     .send(SimpleProxyMessage::LayerClosed(msg))
                     .await;
+                self.task_txs
+                    .outgoing
+                    .send(OutgoingProxyMessage::LayerClosed(msg))
+                    .await;
                 self.task_txs
                     .incoming
                     .send(IncomingProxyMessage::LayerClosed(msg))
                     .await;
+                self.task_txs
+                    .exec
+                    .send(ExecProxyMessage::LayerClosed(msg))
+                    .await;
 
                 self.task_txs.layers.remove(&LayerId(id));
+                self.status
+                    .send_modify(|status| status.processes = self.task_txs.layers.len());
             }
             (task_id, TaskUpdate::Finished(res)) => match res {
                 Ok(()) => {
@@ -269,10 +593,43 @@ impl IntProxy {
         Ok(())
     }
 
+    /// Passes the [`Capabilities`] negotiated with the agent to the background tasks that depend
+    /// on them.
+    async fn capabilities_negotiated(&mut self, capabilities: Capabilities) {
+        self.task_txs
+            .exec
+            .send(ExecProxyMessage::Capabilities(capabilities))
+            .await;
+        self.task_txs
+            .incoming
+            .send(IncomingProxyMessage::Capabilities(capabilities))
+            .await;
+        self.task_txs
+            .outgoing
+            .send(OutgoingProxyMessage::Capabilities(capabilities))
+            .await;
+        self.task_txs
+            .simple
+            .send(SimpleProxyMessage::Capabilities(capabilities))
+            .await;
+    }
+
     /// Routes most messages from the agent to the correct background task.
     /// Some messages are handled here.
     #[tracing::instrument(level = "trace", skip(self), ret)]
     async fn handle_agent_message(&mut self, message: DaemonMessage) -> Result<(), IntProxyError> {
+        self.status
+            .send_modify(|status| status.daemon_message(&message));
+        if let Some(recorder) = &mut self.recorder {
+            recorder.daemon_message(&message);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.send_modify(|metrics| metrics.daemon_message(&message));
+        }
+        if let Some(traffic_log) = &mut self.traffic_log {
+            traffic_log.daemon_message(&message);
+        }
+
         self.task_txs
             .ping_pong
             .send(AgentMessageNotification {
@@ -308,12 +665,18 @@ impl IntProxy {
                     .await
             }
             DaemonMessage::Tcp(msg) => {
+                if matches!(msg, DaemonTcp::SubscribeResult(Ok(..))) {
+                    timings::mark(StartupPhase::PortSubscribe);
+                }
                 self.task_txs
                     .incoming
                     .send(IncomingProxyMessage::AgentMirror(msg))
                     .await
             }
             DaemonMessage::TcpSteal(msg) => {
+                if matches!(msg, DaemonTcp::SubscribeResult(Ok(..))) {
+                    timings::mark(StartupPhase::PortSubscribe);
+                }
                 self.task_txs
                     .incoming
                     .send(IncomingProxyMessage::AgentSteal(msg))
@@ -323,6 +686,19 @@ impl IntProxy {
                 if CLIENT_READY_FOR_LOGS.matches(&protocol_version) {
                     self.task_txs.agent.send(ClientMessage::ReadyForLogs).await;
                 }
+
+                if CAPABILITIES_VERSION.matches(&protocol_version) {
+                    self.task_txs
+                        .agent
+                        .send(ClientMessage::SwitchCapabilities(Capabilities::all()))
+                        .await;
+                } else {
+                    self.capabilities_negotiated(Capabilities::from_version(&protocol_version))
+                        .await;
+                }
+            }
+            DaemonMessage::SwitchCapabilitiesResponse(capabilities) => {
+                self.capabilities_negotiated(capabilities).await
             }
             DaemonMessage::LogMessage(log) => match log.level {
                 LogLevel::Error => tracing::error!("agent log: {}", log.message),
@@ -334,6 +710,7 @@ impl IntProxy {
                     .send(SimpleProxyMessage::GetEnvRes(res))
                     .await
             }
+            DaemonMessage::Exec(msg) => self.task_txs.exec.send(ExecProxyMessage::Agent(msg)).await,
             other => {
                 return Err(IntProxyError::UnexpectedAgentMessage(other));
             }
@@ -351,6 +728,8 @@ impl IntProxy {
             message,
         } = message;
 
+        timings::mark(StartupPhase::FirstProxyMessage);
+
         match message {
             LayerToProxyMessage::File(req) => {
                 self.task_txs
@@ -386,6 +765,23 @@ impl IntProxy {
                     .send(SimpleProxyMessage::GetEnvReq(message_id, layer_id, req))
                     .await
             }
+            LayerToProxyMessage::Exec(req) => {
+                self.task_txs
+                    .exec
+                    .send(ExecProxyMessage::LayerRequest(message_id, layer_id, req))
+                    .await
+            }
+            // Only the proxy that handles the request knows it.
+            LayerToProxyMessage::Cancel(cancelled) => {
+                self.task_txs
+                    .simple
+                    .send(SimpleProxyMessage::Cancel(cancelled, layer_id))
+                    .await;
+                self.task_txs
+                    .outgoing
+                    .send(OutgoingProxyMessage::Cancel(cancelled, layer_id))
+                    .await
+            }
             other => return Err(IntProxyError::UnexpectedLayerMessage(other)),
         }
 
@@ -1,8 +1,9 @@
 use std::fmt;
 
 use mirrord_intproxy_protocol::{LayerId, LayerToProxyMessage, MessageId, ProxyToLayerMessage};
-use mirrord_protocol::{ClientMessage, DaemonMessage};
-use tokio::net::TcpStream;
+use mirrord_protocol::{tcp::HttpFilter, ClientMessage, DaemonMessage};
+
+use crate::layer_listener::LayerStream;
 
 /// Messages sent back to the [`IntProxy`](crate::IntProxy) from the main background tasks. See
 /// [`MainTaskId`].
@@ -18,6 +19,18 @@ pub enum ProxyMessage {
     FromLayer(FromLayer),
     /// New layer instance to serve.
     NewLayer(NewLayer),
+    /// The connection with the agent was lost, and this is the attempt to establish it again.
+    AgentReconnecting(u32),
+    /// The connection with the agent was lost and established again. Per-client state in the
+    /// agent (open files, port subscriptions, outgoing connections) is gone.
+    AgentReconnected,
+    /// The connection with the agent was dropped on purpose, and is being established again.
+    /// Requests still waiting for a response will never get one, and should be failed.
+    AgentConnectionReset,
+    /// The machine woke from sleep, so the connection with the agent is most likely dead.
+    SystemWoke,
+    /// The `steal` HTTP filter was changed in the config file.
+    HttpFilterChanged(HttpFilter),
 }
 
 #[derive(Debug)]
@@ -36,7 +49,7 @@ pub struct FromLayer {
 
 #[derive(Debug)]
 pub struct NewLayer {
-    pub stream: TcpStream,
+    pub stream: LayerStream,
     pub id: LayerId,
     /// [`LayerId`] of the fork parent.
     pub parent_id: Option<LayerId>,
@@ -80,8 +93,14 @@ pub enum MainTaskId {
     SimpleProxy,
     OutgoingProxy,
     IncomingProxy,
+    ExecProxy,
     PingPong,
     AgentConnection,
+    WakeDetector,
+    ConfigWatcher,
+    StatusServer,
+    StatusEvents,
+    MetricsServer,
     LayerConnection(LayerId),
 }
 
@@ -93,8 +112,14 @@ impl fmt::Display for MainTaskId {
             Self::OutgoingProxy => f.write_str("OUTGOING_PROXY"),
             Self::PingPong => f.write_str("PING_PONG"),
             Self::AgentConnection => f.write_str("AGENT_CONNECTION"),
+            Self::WakeDetector => f.write_str("WAKE_DETECTOR"),
+            Self::ConfigWatcher => f.write_str("CONFIG_WATCHER"),
+            Self::StatusServer => f.write_str("STATUS_SERVER"),
+            Self::StatusEvents => f.write_str("STATUS_EVENTS"),
+            Self::MetricsServer => f.write_str("METRICS_SERVER"),
             Self::LayerConnection(id) => write!(f, "LAYER_CONNECTION {}", id.0),
             Self::IncomingProxy => f.write_str("INCOMING_PROXY"),
+            Self::ExecProxy => f.write_str("EXEC_PROXY"),
         }
     }
 }
@@ -0,0 +1,471 @@
+//! Prometheus metrics of the session, served on `internal_proxy.metrics`.
+//!
+//! Like the [`SessionStatus`](crate::status::SessionStatus), the [`Metrics`] are recorded by the
+//! [`IntProxy`](crate::IntProxy) from the messages that go through it. The agent answers requests
+//! of the same kind in order (see [`RequestQueue`](crate::request_queue::RequestQueue)), so the
+//! latency of its responses is measured with a queue of send times for every kind, and the length
+//! of the queue is the number of requests waiting for the agent.
+//!
+//! The time the local application takes to respond to the stolen HTTP requests is measured too,
+//! to tell a slow agent (or cluster) from a slow application.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::Infallible,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use mirrord_protocol::{
+    outgoing::{
+        tcp::{DaemonTcpOutgoing, LayerTcpOutgoing},
+        udp::{DaemonUdpOutgoing, LayerUdpOutgoing},
+        DaemonRead, LayerWrite,
+    },
+    tcp::{DaemonTcp, LayerTcpSteal, TcpClose, TcpData, TcpSequencedData},
+    ClientMessage, ConnectionId, DaemonMessage, FileRequest, FileResponse, RequestId,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::watch,
+};
+
+use crate::{
+    background_tasks::{BackgroundTask, MessageBus},
+    ProxyMessage,
+};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Prometheus histogram of latencies, with [`BUCKETS`].
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Observations in every bucket, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+
+        if let Some(bucket) = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .and_then(|position| self.buckets.get_mut(position))
+        {
+            *bucket += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// Writes the samples of the histogram `name`, with the extra `label` (e.g. `kind="file"`).
+    fn render(&self, out: &mut String, name: &str, label: Option<&str>) {
+        let (bucket_label, label) = match label {
+            Some(label) => (format!("{label},"), format!("{{{label}}}")),
+            None => Default::default(),
+        };
+
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{bucket_label}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{bucket_label}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let _ = writeln!(out, "{name}_sum{label} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{label} {}", self.count);
+    }
+}
+
+/// Kind of the requests sent to the agent, for the requests that get a response.
+fn request_kind(message: &ClientMessage) -> Option<&'static str> {
+    match message {
+        // The only file requests without a response.
+        ClientMessage::FileRequest(FileRequest::Close(..) | FileRequest::CloseDir(..)) => None,
+        ClientMessage::FileRequest(..) => Some("file"),
+        ClientMessage::GetAddrInfoRequest(..) | ClientMessage::GetAddrInfoRequestV2(..) => {
+            Some("dns")
+        }
+        ClientMessage::GetEnvVarsRequest(..) => Some("env"),
+        ClientMessage::TcpOutgoing(LayerTcpOutgoing::Connect(..)) => Some("tcp_connect"),
+        ClientMessage::UdpOutgoing(LayerUdpOutgoing::Connect(..)) => Some("udp_connect"),
+        _ => None,
+    }
+}
+
+/// Kind of the request the agent responded to.
+fn response_kind(message: &DaemonMessage) -> Option<&'static str> {
+    match message {
+        // Only the end of a stream completes the request.
+        DaemonMessage::File(
+            FileResponse::ReadChunk(Ok(..)) | FileResponse::GetDEnts64Chunk(Ok(..)),
+        ) => None,
+        DaemonMessage::File(..) => Some("file"),
+        DaemonMessage::GetAddrInfoResponse(..) => Some("dns"),
+        DaemonMessage::GetEnvVarsResponse(..) => Some("env"),
+        DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(..)) => Some("tcp_connect"),
+        DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Connect(..)) => Some("udp_connect"),
+        _ => None,
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines of the metric `name`.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn file_operation(request: &FileRequest) -> &'static str {
+    match request {
+        FileRequest::Open(..) => "open",
+        FileRequest::OpenRelative(..) => "open_relative",
+        FileRequest::Read(..) => "read",
+        FileRequest::ReadLimited(..) => "read_limited",
+        FileRequest::Seek(..) => "seek",
+        FileRequest::Write(..) => "write",
+        FileRequest::WriteLimited(..) => "write_limited",
+        FileRequest::Close(..) => "close",
+        FileRequest::Access(..) => "access",
+        FileRequest::Xstat(..) => "xstat",
+        FileRequest::XstatFs(..) => "xstatfs",
+        FileRequest::FdOpenDir(..) => "fdopendir",
+        FileRequest::ReadDir(..) => "readdir",
+        FileRequest::CloseDir(..) => "closedir",
+        FileRequest::GetDEnts64(..) => "getdents64",
+        FileRequest::ReadDirBatch(..) => "readdir_batch",
+        FileRequest::Checksum(..) => "checksum",
+        FileRequest::ReadStream(..) => "read_stream",
+        FileRequest::GetDEnts64Stream(..) => "getdents64_stream",
+        FileRequest::Batch(..) => "batch",
+        FileRequest::ReadChecksummed(..) => "read_checksummed",
+        FileRequest::ScratchDir(..) => "scratch_dir",
+    }
+}
+
+/// Counters and latencies of the session, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    stolen_connections: u64,
+    stolen_requests: u64,
+    stolen_bytes: u64,
+    mirrored_connections: u64,
+    mirrored_bytes: u64,
+    outgoing_connections: u64,
+    outgoing_bytes_sent: u64,
+    outgoing_bytes_received: u64,
+    file_operations: BTreeMap<&'static str, u64>,
+    /// Send times of the requests waiting for the agent, by kind.
+    pending: BTreeMap<&'static str, VecDeque<Instant>>,
+    agent_latency: BTreeMap<&'static str, Histogram>,
+    /// Arrival times of the stolen HTTP requests waiting for the local application.
+    stolen_pending: HashMap<(ConnectionId, RequestId), Instant>,
+    local_latency: Histogram,
+}
+
+impl Metrics {
+    /// Records a message sent to the agent.
+    pub(crate) fn client_message(&mut self, message: &ClientMessage) {
+        if let Some(kind) = request_kind(message) {
+            self.pending
+                .entry(kind)
+                .or_default()
+                .push_back(Instant::now());
+        }
+
+        match message {
+            ClientMessage::FileRequest(request) => {
+                *self
+                    .file_operations
+                    .entry(file_operation(request))
+                    .or_default() += 1;
+            }
+            ClientMessage::TcpOutgoing(LayerTcpOutgoing::Write(LayerWrite { bytes, .. }))
+            | ClientMessage::UdpOutgoing(LayerUdpOutgoing::Write(LayerWrite { bytes, .. })) => {
+                self.outgoing_bytes_sent += bytes.len() as u64
+            }
+            ClientMessage::TcpSteal(LayerTcpSteal::HttpResponse(response)) => {
+                self.stolen_response(response.connection_id, response.request_id)
+            }
+            ClientMessage::TcpSteal(LayerTcpSteal::HttpResponseFramed(response)) => {
+                self.stolen_response(response.connection_id, response.request_id)
+            }
+            ClientMessage::TcpSteal(LayerTcpSteal::ConnectionUnsubscribe(connection_id)) => {
+                self.stolen_closed(*connection_id)
+            }
+            _ => {}
+        }
+    }
+
+    /// Records a message received from the agent.
+    pub(crate) fn daemon_message(&mut self, message: &DaemonMessage) {
+        if let Some(kind) = response_kind(message) {
+            let sent = self
+                .pending
+                .get_mut(kind)
+                .and_then(|pending| pending.pop_front());
+            if let Some(sent) = sent {
+                self.agent_latency
+                    .entry(kind)
+                    .or_default()
+                    .observe(sent.elapsed());
+            }
+        }
+
+        match message {
+            DaemonMessage::Tcp(DaemonTcp::NewConnection(..)) => self.mirrored_connections += 1,
+            DaemonMessage::Tcp(
+                DaemonTcp::Data(TcpData { bytes, .. })
+                | DaemonTcp::DataSequenced(TcpSequencedData { bytes, .. }),
+            ) => self.mirrored_bytes += bytes.len() as u64,
+            DaemonMessage::TcpSteal(
+                DaemonTcp::NewConnection(..) | DaemonTcp::NewConnectionV2(..),
+            ) => self.stolen_connections += 1,
+            DaemonMessage::TcpSteal(DaemonTcp::Data(TcpData { bytes, .. })) => {
+                self.stolen_bytes += bytes.len() as u64
+            }
+            DaemonMessage::TcpSteal(DaemonTcp::HttpRequest(request)) => {
+                self.stolen_request(request.connection_id, request.request_id)
+            }
+            DaemonMessage::TcpSteal(DaemonTcp::HttpRequestFramed(request)) => {
+                self.stolen_request(request.connection_id, request.request_id)
+            }
+            DaemonMessage::TcpSteal(DaemonTcp::Close(TcpClose { connection_id })) => {
+                self.stolen_closed(*connection_id)
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(Ok(..)))
+            | DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Connect(Ok(..))) => {
+                self.outgoing_connections += 1
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Read(Ok(DaemonRead {
+                bytes, ..
+            })))
+            | DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Read(Ok(DaemonRead {
+                bytes, ..
+            }))) => self.outgoing_bytes_received += bytes.len() as u64,
+            _ => {}
+        }
+    }
+
+    /// Forgets the requests sent through the previous agent connection, they will never get a
+    /// response.
+    pub(crate) fn agent_reconnected(&mut self) {
+        self.pending.clear();
+        self.stolen_pending.clear();
+    }
+
+    fn stolen_request(&mut self, connection_id: ConnectionId, request_id: RequestId) {
+        self.stolen_requests += 1;
+        self.stolen_pending
+            .insert((connection_id, request_id), Instant::now());
+    }
+
+    fn stolen_response(&mut self, connection_id: ConnectionId, request_id: RequestId) {
+        if let Some(received) = self.stolen_pending.remove(&(connection_id, request_id)) {
+            self.local_latency.observe(received.elapsed());
+        }
+    }
+
+    fn stolen_closed(&mut self, connection_id: ConnectionId) {
+        self.stolen_pending
+            .retain(|(id, _), _| *id != connection_id);
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "mirrord_stolen_connections_total",
+                "Connections stolen from the target.",
+                self.stolen_connections,
+            ),
+            (
+                "mirrord_stolen_requests_total",
+                "HTTP requests stolen with a filter.",
+                self.stolen_requests,
+            ),
+            (
+                "mirrord_stolen_bytes_total",
+                "Bytes received in stolen connections.",
+                self.stolen_bytes,
+            ),
+            (
+                "mirrord_mirrored_connections_total",
+                "Connections mirrored from the target.",
+                self.mirrored_connections,
+            ),
+            (
+                "mirrord_mirrored_bytes_total",
+                "Bytes received in mirrored connections.",
+                self.mirrored_bytes,
+            ),
+            (
+                "mirrord_outgoing_connections_total",
+                "Outgoing connections made from the target.",
+                self.outgoing_connections,
+            ),
+            (
+                "mirrord_outgoing_bytes_sent_total",
+                "Bytes sent through outgoing connections.",
+                self.outgoing_bytes_sent,
+            ),
+            (
+                "mirrord_outgoing_bytes_received_total",
+                "Bytes received through outgoing connections.",
+                self.outgoing_bytes_received,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        let name = "mirrord_file_operations_total";
+        header(&mut out, name, "counter", "Remote file operations.");
+        for (operation, count) in &self.file_operations {
+            let _ = writeln!(out, "{name}{{operation=\"{operation}\"}} {count}");
+        }
+
+        let name = "mirrord_agent_pending_requests";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Requests waiting for a response from the agent.",
+        );
+        for (kind, pending) in &self.pending {
+            let _ = writeln!(out, "{name}{{kind=\"{kind}\"}} {}", pending.len());
+        }
+
+        let name = "mirrord_agent_response_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time the agent took to respond to requests.",
+        );
+        for (kind, histogram) in &self.agent_latency {
+            histogram.render(&mut out, name, Some(&format!("kind=\"{kind}\"")));
+        }
+
+        let name = "mirrord_local_response_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Time the local application took to respond to stolen HTTP requests.",
+        );
+        self.local_latency.render(&mut out, name, None);
+
+        out
+    }
+}
+
+/// Serves the current [`Metrics`] over HTTP, to every request, whatever its path.
+/// Run as a [`BackgroundTask`].
+pub struct MetricsServer {
+    listener: TcpListener,
+    metrics: watch::Receiver<Metrics>,
+}
+
+impl MetricsServer {
+    pub fn new(listener: TcpListener, metrics: watch::Receiver<Metrics>) -> Self {
+        Self { listener, metrics }
+    }
+}
+
+impl BackgroundTask for MetricsServer {
+    type Error = Infallible;
+    type MessageIn = ();
+    type MessageOut = ProxyMessage;
+
+    async fn run(self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        loop {
+            tokio::select! {
+                msg = message_bus.recv() => {
+                    if msg.is_none() {
+                        break;
+                    }
+                },
+
+                accepted = self.listener.accept() => match accepted {
+                    Ok((mut stream, _)) => {
+                        let body = self.metrics.borrow().render();
+
+                        tokio::spawn(async move {
+                            // The request is not needed, but the scraper expects us to read it.
+                            let mut request = [0; 1024];
+                            let response = format!(
+                                "HTTP/1.1 200 OK\r\n\
+                                Content-Type: text/plain; version=0.0.4\r\n\
+                                Content-Length: {}\r\n\
+                                Connection: close\r\n\r\n{body}",
+                                body.len()
+                            );
+
+                            let result = match stream.read(&mut request).await {
+                                Ok(..) => stream.write_all(response.as_bytes()).await,
+                                Err(error) => Err(error),
+                            };
+                            if let Err(error) = result {
+                                tracing::debug!(%error, "failed to send the metrics");
+                            }
+                        });
+                    }
+                    Err(error) => tracing::debug!(%error, "failed to accept a metrics client"),
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::file::{CloseFileRequest, ReadFileRequest};
+
+    use super::*;
+
+    #[test]
+    fn measures_file_requests() {
+        let mut metrics = Metrics::default();
+
+        metrics.client_message(&ClientMessage::FileRequest(FileRequest::Read(
+            ReadFileRequest {
+                remote_fd: 1,
+                buffer_size: 16,
+            },
+        )));
+        metrics.client_message(&ClientMessage::FileRequest(FileRequest::Close(
+            CloseFileRequest { fd: 1 },
+        )));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("mirrord_file_operations_total{operation=\"read\"} 1\n"));
+        assert!(rendered.contains("mirrord_file_operations_total{operation=\"close\"} 1\n"));
+        assert!(rendered.contains("mirrord_agent_pending_requests{kind=\"file\"} 1\n"));
+
+        metrics.daemon_message(&DaemonMessage::File(mirrord_protocol::FileResponse::Read(
+            Err(mirrord_protocol::ResponseError::NotImplemented),
+        )));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("mirrord_agent_pending_requests{kind=\"file\"} 0\n"));
+        assert!(rendered.contains("mirrord_agent_response_seconds_count{kind=\"file\"} 1\n"));
+        assert!(rendered.contains("mirrord_local_response_seconds_count 0\n"));
+    }
+}
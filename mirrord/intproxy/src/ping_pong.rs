@@ -34,6 +34,22 @@ pub struct AgentMessageNotification {
     pub pong: bool,
 }
 
+/// Messages consumed by [`PingPong`] running as a [`BackgroundTask`].
+pub enum PingPongMessage {
+    /// A message was received from the agent.
+    AgentMessage(AgentMessageNotification),
+    /// The connection with the agent was lost, no pongs are expected until it's dialed again.
+    AgentReconnecting,
+    /// The connection with the agent was dialed again, so our last ping might have been lost.
+    AgentReconnected,
+}
+
+impl From<AgentMessageNotification> for PingPongMessage {
+    fn from(value: AgentMessageNotification) -> Self {
+        Self::AgentMessage(value)
+    }
+}
+
 /// Encapsulates logic of the ping pong mechanism on the proxy side.
 /// Run as a [`BackgroundTask`].
 pub struct PingPong {
@@ -41,6 +57,9 @@ pub struct PingPong {
     ticker: Interval,
     /// Whether this struct awaits for a pong from the agent.
     awaiting_pong: bool,
+    /// Whether the connection with the agent is being dialed again, which can take longer than
+    /// the ping frequency.
+    reconnecting: bool,
 }
 
 impl PingPong {
@@ -56,19 +75,20 @@ impl PingPong {
         Self {
             ticker,
             awaiting_pong: false,
+            reconnecting: false,
         }
     }
 }
 
 impl BackgroundTask for PingPong {
     type Error = PingPongError;
-    type MessageIn = AgentMessageNotification;
+    type MessageIn = PingPongMessage;
     type MessageOut = ProxyMessage;
 
     async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
         loop {
             tokio::select! {
-                _ = self.ticker.tick() => {
+                _ = self.ticker.tick(), if !self.reconnecting => {
                     if self.awaiting_pong {
                         tracing::error!("pong timeout");
                         break Err(PingPongError::PongTimeout);
@@ -85,19 +105,32 @@ impl BackgroundTask for PingPong {
                         tracing::trace!("message bus closed, exiting");
                         break Ok(())
                     },
-                    (Some(AgentMessageNotification { pong: true }), true) => {
+                    (Some(PingPongMessage::AgentReconnecting), _) => {
+                        self.reconnecting = true;
+                    },
+                    (Some(PingPongMessage::AgentReconnected), true) => {
+                        self.reconnecting = false;
+                        tracing::trace!("agent reconnected, sending ping again");
+                        let _ = message_bus.send(ProxyMessage::ToAgent(ClientMessage::Ping)).await;
+                        self.ticker.reset();
+                    },
+                    (Some(PingPongMessage::AgentReconnected), false) => {
+                        self.reconnecting = false;
+                        self.ticker.reset();
+                    },
+                    (Some(PingPongMessage::AgentMessage(AgentMessageNotification { pong: true })), true) => {
                         tracing::trace!("agent responded to ping");
                         self.awaiting_pong = false;
                         self.ticker.reset();
                     },
-                    (Some(AgentMessageNotification { pong: false }), true) => {
+                    (Some(PingPongMessage::AgentMessage(AgentMessageNotification { pong: false })), true) => {
                         tracing::trace!("agent sent message, still waiting for pong")
                     },
-                    (Some(AgentMessageNotification { pong: true }), false) => {
+                    (Some(PingPongMessage::AgentMessage(AgentMessageNotification { pong: true })), false) => {
                         tracing::error!("agent sent an unexpected pong");
                         break Err(PingPongError::UnmatchedPong)
                     },
-                    (Some(AgentMessageNotification { pong: false }), false) => {
+                    (Some(PingPongMessage::AgentMessage(AgentMessageNotification { pong: false })), false) => {
                         self.ticker.reset();
                     }
                 },
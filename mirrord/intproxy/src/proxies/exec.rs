@@ -0,0 +1,560 @@
+//! Handles the logic of the `remote_exec` feature.
+//!
+//! The agent streams the output of remote processes, while the layer polls for it with
+//! [`ExecPoll`] requests. This proxy buffers the output in between, up to
+//! [`ExecProxy::MAX_BUFFERED_OUTPUT`] per process.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use mirrord_intproxy_protocol::{
+    ExecCloseStdin, ExecOutput, ExecPoll, ExecRequest, ExecResponse, ExecStart, LayerId, MessageId,
+    ProxyToLayerMessage,
+};
+use mirrord_protocol::{
+    capabilities::Capabilities,
+    exec::{DaemonExec, ExecData, ExecId, ExecStartRequest, LayerExec},
+    ClientMessage, ResponseError,
+};
+use tokio::time;
+use tracing::warn;
+
+use crate::{
+    background_tasks::{BackgroundTask, MessageBus},
+    main_tasks::{LayerClosed, ToLayer},
+    ProxyMessage,
+};
+
+pub enum ExecProxyMessage {
+    LayerRequest(MessageId, LayerId, ExecRequest),
+    Agent(DaemonExec),
+    LayerClosed(LayerClosed),
+    /// [`Capabilities`] were negotiated with the agent.
+    Capabilities(Capabilities),
+    /// The connection with the agent was dialed again, the processes died with the previous one.
+    AgentReconnected,
+    /// The connection with the agent was dropped on purpose, the processes died with it.
+    AgentConnectionReset,
+}
+
+/// [`ExecPoll`] waiting for the output of a process.
+struct PendingPoll {
+    message_id: MessageId,
+    /// When to respond without any output.
+    deadline: Instant,
+}
+
+/// A process running in the agent.
+struct Process {
+    layer_id: LayerId,
+    /// Output not yet returned to the layer.
+    output: ExecOutput,
+    poll: Option<PendingPoll>,
+    /// Killed for not polling its output, the rest of it is dropped.
+    killed: bool,
+}
+
+impl Process {
+    fn buffered(&self) -> usize {
+        self.output.stdout.len() + self.output.stderr.len()
+    }
+
+    fn has_output(&self) -> bool {
+        !self.output.stdout.is_empty()
+            || !self.output.stderr.is_empty()
+            || self.output.exit.is_some()
+    }
+}
+
+/// Runs processes in the target container for the layers, see [`ExecRequest`].
+/// Run as a [`BackgroundTask`].
+#[derive(Default)]
+pub struct ExecProxy {
+    next_id: ExecId,
+    /// [`ExecStart`] requests waiting for the agent.
+    starting: HashMap<ExecId, (MessageId, LayerId)>,
+    processes: HashMap<ExecId, Process>,
+    /// [`Capabilities`] negotiated with the agent.
+    capabilities: Capabilities,
+}
+
+impl ExecProxy {
+    /// How long an [`ExecPoll`] can wait for output. Must be shorter than the layer's timeout for
+    /// responses.
+    const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// How much output of a process can wait for an [`ExecPoll`]. When the layer doesn't poll
+    /// for it and there's more, the process is killed.
+    const MAX_BUFFERED_OUTPUT: usize = 8 * 1024 * 1024;
+
+    fn remote_exec_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::REMOTE_EXEC)
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_layer_request(
+        &mut self,
+        message_id: MessageId,
+        layer_id: LayerId,
+        request: ExecRequest,
+        message_bus: &mut MessageBus<Self>,
+    ) {
+        match request {
+            ExecRequest::Start(ExecStart { .. }) if !self.remote_exec_supported() => {
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        layer_id,
+                        message: ProxyToLayerMessage::Exec(ExecResponse::Start(Err(
+                            ResponseError::NotImplemented,
+                        ))),
+                    })
+                    .await;
+            }
+            ExecRequest::Start(ExecStart { argv }) => {
+                let id = self.next_id;
+                self.next_id += 1;
+
+                self.starting.insert(id, (message_id, layer_id));
+                message_bus
+                    .send(ClientMessage::Exec(LayerExec::Start(ExecStartRequest {
+                        id,
+                        argv,
+                    })))
+                    .await;
+            }
+            ExecRequest::Stdin(data) => {
+                if self.processes.contains_key(&data.id) {
+                    message_bus
+                        .send(ClientMessage::Exec(LayerExec::Stdin(data)))
+                        .await;
+                }
+            }
+            ExecRequest::CloseStdin(ExecCloseStdin(id)) => {
+                if self.processes.contains_key(&id) {
+                    message_bus
+                        .send(ClientMessage::Exec(LayerExec::CloseStdin(id)))
+                        .await;
+                }
+            }
+            ExecRequest::Poll(ExecPoll(id)) => {
+                let Some(process) = self.processes.get_mut(&id) else {
+                    message_bus
+                        .send(ToLayer {
+                            message_id,
+                            layer_id,
+                            message: ProxyToLayerMessage::Exec(ExecResponse::Poll(Err(
+                                ResponseError::NotFound(id),
+                            ))),
+                        })
+                        .await;
+                    return;
+                };
+
+                process.poll = Some(PendingPoll {
+                    message_id,
+                    deadline: Instant::now() + Self::POLL_TIMEOUT,
+                });
+
+                if process.has_output() {
+                    self.respond_poll(id, message_bus).await;
+                }
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_agent_message(
+        &mut self,
+        message: DaemonExec,
+        message_bus: &mut MessageBus<Self>,
+    ) {
+        match message {
+            DaemonExec::Started { id, result } => {
+                let Some((message_id, layer_id)) = self.starting.remove(&id) else {
+                    // The layer is gone.
+                    if result.is_ok() {
+                        message_bus
+                            .send(ClientMessage::Exec(LayerExec::Kill(id)))
+                            .await;
+                    }
+                    return;
+                };
+
+                if result.is_ok() {
+                    self.processes.insert(
+                        id,
+                        Process {
+                            layer_id,
+                            output: Default::default(),
+                            poll: None,
+                            killed: false,
+                        },
+                    );
+                }
+
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        layer_id,
+                        message: ProxyToLayerMessage::Exec(ExecResponse::Start(
+                            result.map(|()| id),
+                        )),
+                    })
+                    .await;
+            }
+            DaemonExec::Stdout(ExecData { id, bytes }) => {
+                self.handle_output(id, false, bytes, message_bus).await;
+            }
+            DaemonExec::Stderr(ExecData { id, bytes }) => {
+                self.handle_output(id, true, bytes, message_bus).await;
+            }
+            DaemonExec::Exited { id, exit } => {
+                if let Some(process) = self.processes.get_mut(&id) {
+                    process.output.exit = Some(exit);
+                    self.respond_poll(id, message_bus).await;
+                }
+            }
+        }
+    }
+
+    /// Buffers the output of the given process, and kills it when there's more than
+    /// [`Self::MAX_BUFFERED_OUTPUT`] waiting for the layer.
+    async fn handle_output(
+        &mut self,
+        id: ExecId,
+        stderr: bool,
+        bytes: Vec<u8>,
+        message_bus: &mut MessageBus<Self>,
+    ) {
+        let Some(process) = self.processes.get_mut(&id) else {
+            return;
+        };
+        if process.killed {
+            return;
+        }
+
+        if stderr {
+            process.output.stderr.extend(bytes);
+        } else {
+            process.output.stdout.extend(bytes);
+        }
+
+        if process.buffered() > Self::MAX_BUFFERED_OUTPUT {
+            warn!(
+                id,
+                buffered = process.buffered(),
+                "Remote exec process output is not read, killing it"
+            );
+            process.killed = true;
+            message_bus
+                .send(ClientMessage::Exec(LayerExec::Kill(id)))
+                .await;
+        }
+
+        self.respond_poll(id, message_bus).await;
+    }
+
+    /// Responds to the [`PendingPoll`] of the given process, if there is one, with all the
+    /// buffered output. Forgets the process once the layer learns about its exit.
+    async fn respond_poll(&mut self, id: ExecId, message_bus: &mut MessageBus<Self>) {
+        let Some(process) = self.processes.get_mut(&id) else {
+            return;
+        };
+        let Some(poll) = process.poll.take() else {
+            return;
+        };
+
+        let layer_id = process.layer_id;
+        let output = std::mem::take(&mut process.output);
+        if output.exit.is_some() {
+            self.processes.remove(&id);
+        }
+
+        message_bus
+            .send(ToLayer {
+                message_id: poll.message_id,
+                layer_id,
+                message: ProxyToLayerMessage::Exec(ExecResponse::Poll(Ok(output))),
+            })
+            .await;
+    }
+
+    /// Returns when the next [`PendingPoll`] should be answered without any output.
+    fn next_poll_deadline(&self) -> Option<Instant> {
+        self.processes
+            .values()
+            .filter_map(|process| process.poll.as_ref())
+            .map(|poll| poll.deadline)
+            .min()
+    }
+
+    /// Answers the [`PendingPoll`]s that waited for too long.
+    async fn respond_expired_polls(&mut self, message_bus: &mut MessageBus<Self>) {
+        let now = Instant::now();
+        let expired = self
+            .processes
+            .iter()
+            .filter(|(_, process)| {
+                process
+                    .poll
+                    .as_ref()
+                    .is_some_and(|poll| poll.deadline <= now)
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            self.respond_poll(id, message_bus).await;
+        }
+    }
+
+    /// Kills the processes of the closed layer.
+    async fn handle_layer_closed(&mut self, msg: LayerClosed, message_bus: &mut MessageBus<Self>) {
+        self.starting.retain(|_, (_, layer_id)| *layer_id != msg.id);
+
+        let closed = self
+            .processes
+            .iter()
+            .filter(|(_, process)| process.layer_id == msg.id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in closed {
+            self.processes.remove(&id);
+            message_bus
+                .send(ClientMessage::Exec(LayerExec::Kill(id)))
+                .await;
+        }
+    }
+
+    /// The processes died with the agent connection. Fails the requests waiting for them with
+    /// [`ProxyToLayerMessage::Interrupted`], later polls get [`ResponseError::NotFound`].
+    async fn handle_processes_lost(&mut self, message_bus: &mut MessageBus<Self>) {
+        let starting = self.starting.drain().map(|(_, request)| request);
+        let polls = self.processes.drain().filter_map(|(_, process)| {
+            process.poll.map(|poll| (poll.message_id, process.layer_id))
+        });
+
+        for (message_id, layer_id) in starting.chain(polls).collect::<Vec<_>>() {
+            message_bus
+                .send(ToLayer {
+                    message_id,
+                    layer_id,
+                    message: ProxyToLayerMessage::Interrupted,
+                })
+                .await;
+        }
+    }
+}
+
+impl BackgroundTask for ExecProxy {
+    type Error = Infallible;
+    type MessageIn = ExecProxyMessage;
+    type MessageOut = ProxyMessage;
+
+    async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        loop {
+            let next_poll_deadline = self.next_poll_deadline();
+
+            tokio::select! {
+                msg = message_bus.recv() => match msg {
+                    None => {
+                        tracing::trace!("message bus closed, exiting");
+                        break Ok(());
+                    }
+                    Some(ExecProxyMessage::LayerRequest(message_id, layer_id, req)) => {
+                        self.handle_layer_request(message_id, layer_id, req, message_bus).await
+                    }
+                    Some(ExecProxyMessage::Agent(msg)) => self.handle_agent_message(msg, message_bus).await,
+                    Some(ExecProxyMessage::LayerClosed(msg)) => self.handle_layer_closed(msg, message_bus).await,
+                    Some(ExecProxyMessage::Capabilities(capabilities)) => {
+                        self.capabilities = capabilities;
+                    }
+                    Some(ExecProxyMessage::AgentReconnected | ExecProxyMessage::AgentConnectionReset) => {
+                        self.handle_processes_lost(message_bus).await
+                    }
+                },
+
+                _ = time::sleep_until(next_poll_deadline.unwrap_or_else(Instant::now).into()), if next_poll_deadline.is_some() => {
+                    self.respond_expired_polls(message_bus).await;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mirrord_protocol::exec::ExecExit;
+
+    use super::*;
+    use crate::background_tasks::{BackgroundTasks, TaskSender, TaskUpdate};
+
+    type Tasks = BackgroundTasks<(), ProxyMessage, Infallible>;
+
+    /// Registers an [`ExecProxy`] that supports remote exec, and starts a process with it.
+    async fn started(tasks: &mut Tasks) -> TaskSender<ExecProxy> {
+        let proxy = tasks.register(ExecProxy::default(), (), 32);
+        proxy
+            .send(ExecProxyMessage::Capabilities(Capabilities::REMOTE_EXEC))
+            .await;
+
+        proxy
+            .send(ExecProxyMessage::LayerRequest(
+                0,
+                LayerId(0),
+                ExecRequest::Start(ExecStart {
+                    argv: vec!["ls".into()],
+                }),
+            ))
+            .await;
+        assert!(matches!(
+            next(tasks).await,
+            ProxyMessage::ToAgent(ClientMessage::Exec(LayerExec::Start(ExecStartRequest {
+                id: 0,
+                ..
+            })))
+        ));
+
+        proxy
+            .send(ExecProxyMessage::Agent(DaemonExec::Started {
+                id: 0,
+                result: Ok(()),
+            }))
+            .await;
+        assert!(matches!(
+            next(tasks).await,
+            ProxyMessage::ToLayer(ToLayer {
+                message_id: 0,
+                message: ProxyToLayerMessage::Exec(ExecResponse::Start(Ok(0))),
+                ..
+            })
+        ));
+
+        proxy
+    }
+
+    async fn next(tasks: &mut Tasks) -> ProxyMessage {
+        match tasks.next().await {
+            Some(((), TaskUpdate::Message(message))) => message,
+            other => panic!("unexpected task update: {other:?}"),
+        }
+    }
+
+    async fn poll(proxy: &TaskSender<ExecProxy>, tasks: &mut Tasks) -> ExecOutput {
+        proxy
+            .send(ExecProxyMessage::LayerRequest(
+                1,
+                LayerId(0),
+                ExecRequest::Poll(ExecPoll(0)),
+            ))
+            .await;
+
+        match next(tasks).await {
+            ProxyMessage::ToLayer(ToLayer {
+                message: ProxyToLayerMessage::Exec(ExecResponse::Poll(Ok(output))),
+                ..
+            }) => output,
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn not_supported() {
+        let mut tasks = Tasks::default();
+        let proxy = tasks.register(ExecProxy::default(), (), 32);
+
+        proxy
+            .send(ExecProxyMessage::LayerRequest(
+                0,
+                LayerId(0),
+                ExecRequest::Start(ExecStart {
+                    argv: vec!["ls".into()],
+                }),
+            ))
+            .await;
+
+        assert!(matches!(
+            next(&mut tasks).await,
+            ProxyMessage::ToLayer(ToLayer {
+                message: ProxyToLayerMessage::Exec(ExecResponse::Start(Err(
+                    ResponseError::NotImplemented
+                ))),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn buffers_output_until_polled() {
+        let mut tasks = Tasks::default();
+        let proxy = started(&mut tasks).await;
+
+        for bytes in [b"hello ".to_vec(), b"world".to_vec()] {
+            proxy
+                .send(ExecProxyMessage::Agent(DaemonExec::Stdout(ExecData {
+                    id: 0,
+                    bytes,
+                })))
+                .await;
+        }
+        proxy
+            .send(ExecProxyMessage::Agent(DaemonExec::Stderr(ExecData {
+                id: 0,
+                bytes: b"oops".to_vec(),
+            })))
+            .await;
+
+        let output = poll(&proxy, &mut tasks).await;
+        assert_eq!(output.stdout, b"hello world");
+        assert_eq!(output.stderr, b"oops");
+        assert_eq!(output.exit, None);
+
+        proxy
+            .send(ExecProxyMessage::Agent(DaemonExec::Exited {
+                id: 0,
+                exit: ExecExit::Code(0),
+            }))
+            .await;
+
+        let output = poll(&proxy, &mut tasks).await;
+        assert!(output.stdout.is_empty());
+        assert_eq!(output.exit, Some(ExecExit::Code(0)));
+    }
+
+    #[tokio::test]
+    async fn kills_process_with_unread_output() {
+        let mut tasks = Tasks::default();
+        let proxy = started(&mut tasks).await;
+
+        let chunk = vec![0; ExecProxy::MAX_BUFFERED_OUTPUT / 2 + 1];
+        for _ in 0..3 {
+            proxy
+                .send(ExecProxyMessage::Agent(DaemonExec::Stdout(ExecData {
+                    id: 0,
+                    bytes: chunk.clone(),
+                })))
+                .await;
+        }
+        proxy
+            .send(ExecProxyMessage::Agent(DaemonExec::Exited {
+                id: 0,
+                exit: ExecExit::Signal(9),
+            }))
+            .await;
+
+        // Killed once, the output after it is dropped.
+        assert!(matches!(
+            next(&mut tasks).await,
+            ProxyMessage::ToAgent(ClientMessage::Exec(LayerExec::Kill(0)))
+        ));
+
+        let output = poll(&proxy, &mut tasks).await;
+        assert_eq!(output.stdout.len(), chunk.len() * 2);
+        assert_eq!(output.exit, Some(ExecExit::Signal(9)));
+    }
+}
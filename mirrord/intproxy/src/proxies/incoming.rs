@@ -4,33 +4,45 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     fmt, io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
 };
 
+use mirrord_config::feature::network::incoming::{StealDelivery, StealDeliveryTarget};
 use mirrord_intproxy_protocol::{
     ConnMetadataRequest, ConnMetadataResponse, IncomingRequest, IncomingResponse, LayerId,
     MessageId, PortSubscribe, PortSubscription, PortUnsubscribe, ProxyToLayerMessage,
 };
 use mirrord_protocol::{
-    tcp::{DaemonTcp, HttpRequestFallback, NewTcpConnection},
-    ConnectionId, ResponseError,
+    capabilities::Capabilities,
+    tcp::{
+        DaemonTcp, HttpFilter, HttpRequestFallback, NewTcpConnection, NewTcpConnectionV2, StealType,
+    },
+    ConnectionId, Port, ResponseError,
 };
 use thiserror::Error;
-use tokio::net::TcpSocket;
+use tokio::{
+    net::TcpSocket,
+    time::{self, MissedTickBehavior},
+};
 
 use self::{
     interceptor::{Interceptor, InterceptorError, MessageOut},
     port_subscription_ext::PortSubscriptionExt,
+    reassembly::StreamReassembler,
     subscriptions::SubscriptionsManager,
 };
 use crate::{
     background_tasks::{BackgroundTask, BackgroundTasks, MessageBus, TaskSender, TaskUpdate},
+    backpressure::{ConnectionBuffers, ConnectionQueue, DrainedConnections},
     main_tasks::{LayerClosed, LayerForked, ToLayer},
     ProxyMessage,
 };
 
+mod delivery;
 mod http;
 mod interceptor;
 mod port_subscription_ext;
+mod reassembly;
 mod subscriptions;
 
 /// Creates and binds a new [`TcpSocket`].
@@ -91,6 +103,12 @@ pub enum IncomingProxyMessage {
     LayerClosed(LayerClosed),
     AgentMirror(DaemonTcp),
     AgentSteal(DaemonTcp),
+    /// The connection with the agent was dialed again.
+    AgentReconnected,
+    /// [`Capabilities`] were negotiated with the agent.
+    Capabilities(Capabilities),
+    /// The `steal` HTTP filter was changed in the config file.
+    HttpFilterChanged(HttpFilter),
 }
 
 /// Handle for an [`Interceptor`].
@@ -99,6 +117,10 @@ struct InterceptorHandle {
     tx: TaskSender<Interceptor>,
     /// Port subscription that the intercepted connection belongs to.
     subscription: PortSubscription,
+    /// Restores the order of [`DaemonTcp::DataSequenced`] messages.
+    reassembler: StreamReassembler,
+    /// Data waiting for room in the queue of the [`Interceptor`] task.
+    queue: ConnectionQueue,
 }
 
 /// Store for mapping [`Interceptor`] socket addresses to addresses of the original peers.
@@ -115,6 +137,7 @@ impl MetadataStore {
             .unwrap_or_else(|| ConnMetadataResponse {
                 remote_source: req.peer_address,
                 local_address: req.listener_address.ip(),
+                original_destination: req.listener_address,
             })
     }
 
@@ -141,6 +164,12 @@ impl MetadataStore {
 ///
 /// Incoming connections are created by the agent either explicitly ([`NewTcpConnection`] message)
 /// or implicitly ([`HttpRequest`](mirrord_protocol::tcp::HttpRequest)).
+///
+/// Ports from `feature.network.incoming.steal_delivery` are stolen by this proxy itself, when it
+/// starts. Their connections go to the configured [`StealDeliveryTarget`]s instead of the layers.
+///
+/// The HTTP filter of the stolen ports can be replaced during the session, when it changes in the
+/// config file (see [`ConfigWatcher`](crate::config_watcher::ConfigWatcher)).
 #[derive(Default)]
 pub struct IncomingProxy {
     /// Active port subscriptions for all layers.
@@ -151,46 +180,127 @@ pub struct IncomingProxy {
     background_tasks: BackgroundTasks<InterceptorId, MessageOut, InterceptorError>,
     /// For managing intercepted connections metadata.
     metadata_store: MetadataStore,
+    /// Where to deliver connections stolen from `feature.network.incoming.steal_delivery` ports.
+    deliveries: HashMap<Port, StealDeliveryTarget>,
+    /// [`Capabilities`] negotiated with the agent.
+    capabilities: Capabilities,
+    /// Sizes of the queues of the [`Interceptor`]s.
+    buffers: ConnectionBuffers,
+    /// [`Interceptor`]s that got room for the data waiting in their [`ConnectionQueue`]s.
+    drained: DrainedConnections<InterceptorId>,
 }
 
 impl IncomingProxy {
-    /// Used when registering new [`RawInterceptor`] and [`HttpInterceptor`] tasks in the
-    /// [`BackgroundTasks`] struct.
-    const CHANNEL_SIZE: usize = 512;
+    /// How often port subscriptions are verified with the agent.
+    const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Creates a new instance that steals the ports of the given `deliveries`, and queues the
+    /// data of every connection within the given `buffers`.
+    pub fn new(deliveries: Vec<StealDelivery>, buffers: ConnectionBuffers) -> Self {
+        Self {
+            deliveries: deliveries
+                .into_iter()
+                .map(|delivery| (delivery.port, delivery.target))
+                .collect(),
+            buffers,
+            ..Default::default()
+        }
+    }
 
-    /// Tries to register the new subscription in the [`SubscriptionsManager`].
+    /// Steals all `feature.network.incoming.steal_delivery` ports.
+    async fn subscribe_deliveries(&self, message_bus: &mut MessageBus<Self>) {
+        for port in self.deliveries.keys() {
+            message_bus
+                .send(PortSubscription::Steal(StealType::All(*port)).agent_subscribe())
+                .await;
+        }
+    }
+
+    /// Handles the agent connection being dialed again.
+    ///
+    /// Connections intercepted through the previous agent connection are gone, so their
+    /// [`Interceptor`]s are dropped without notifying the agent. All port subscriptions are made
+    /// again.
     #[tracing::instrument(level = "trace", skip(self, message_bus))]
-    async fn handle_port_subscribe(
+    async fn handle_agent_reconnected(&mut self, message_bus: &mut MessageBus<Self>) {
+        self.interceptors.clear();
+        self.background_tasks = Default::default();
+        self.metadata_store = Default::default();
+
+        for msg in self.subscriptions.resubscribe() {
+            message_bus.send(msg).await;
+        }
+
+        self.subscribe_deliveries(message_bus).await;
+    }
+
+    /// Updates the filter of the ports stolen with an HTTP filter, after it was changed in the
+    /// config file.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_http_filter_changed(
         &mut self,
-        message_id: MessageId,
-        layer_id: LayerId,
-        subscribe: PortSubscribe,
+        filter: HttpFilter,
         message_bus: &mut MessageBus<Self>,
     ) {
-        let msg = self
-            .subscriptions
-            .layer_subscribed(layer_id, message_id, subscribe);
+        let supported = self
+            .capabilities
+            .contains(Capabilities::STEAL_FILTER_UPDATE);
+        if !supported {
+            tracing::warn!(
+                %filter,
+                "the agent can't update the HTTP filter during the session, restart mirrord to use \
+                 the new filter"
+            );
+            return;
+        }
 
-        if let Some(msg) = msg {
+        for msg in self.subscriptions.http_filter_changed(filter) {
             message_bus.send(msg).await;
         }
     }
 
-    /// Tries to unregister the subscription from the [`SubscriptionManager`].
+    /// Tries to register the new subscription in the [`SubscriptionsManager`].
     #[tracing::instrument(level = "trace", skip(self, message_bus))]
-    async fn handle_port_unsubscribe(
+    async fn handle_port_subscribe(
         &mut self,
+        message_id: MessageId,
         layer_id: LayerId,
-        request: PortUnsubscribe,
+        subscribe: PortSubscribe,
         message_bus: &mut MessageBus<Self>,
     ) {
-        let msg = self.subscriptions.layer_unsubscribed(layer_id, request);
+        let port = subscribe.subscription.port();
+        if self.deliveries.contains_key(&port) {
+            tracing::warn!(
+                "port {port} is delivered with `steal_delivery`, the local application won't \
+                receive its connections"
+            );
+
+            message_bus
+                .send(ToLayer {
+                    message_id,
+                    layer_id,
+                    message: ProxyToLayerMessage::Incoming(IncomingResponse::PortSubscribe(Ok(()))),
+                })
+                .await;
 
-        if let Some(msg) = msg {
+            return;
+        }
+
+        let msgs = self
+            .subscriptions
+            .layer_subscribed(layer_id, message_id, subscribe);
+
+        for msg in msgs {
             message_bus.send(msg).await;
         }
     }
 
+    /// Tries to unregister the subscription from the [`SubscriptionManager`].
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn handle_port_unsubscribe(&mut self, layer_id: LayerId, request: PortUnsubscribe) {
+        self.subscriptions.layer_unsubscribed(layer_id, request);
+    }
+
     /// Retrieves or creates an [`Interceptor`] for the given [`HttpRequestFallback`].
     /// The request may or may not belong to an existing connection (when stealing with an http
     /// filter, connections are created implicitly).
@@ -215,16 +325,23 @@ impl IncomingProxy {
                 };
 
                 let interceptor_socket = bind_similar(subscription.listening_on)?;
+                let budget = self.drained.budget(id, self.buffers.high_watermark);
 
                 let interceptor = self.background_tasks.register(
-                    Interceptor::new(interceptor_socket, subscription.listening_on),
+                    Interceptor::new(
+                        interceptor_socket,
+                        subscription.listening_on,
+                        budget.clone(),
+                    ),
                     id,
-                    Self::CHANNEL_SIZE,
+                    self.buffers.size,
                 );
 
                 e.insert(InterceptorHandle {
                     tx: interceptor,
                     subscription: subscription.subscription.clone(),
+                    reassembler: Default::default(),
+                    queue: ConnectionQueue::new(budget),
                 })
             }
         };
@@ -232,6 +349,138 @@ impl IncomingProxy {
         Ok(Some(&interceptor.tx))
     }
 
+    /// Starts proxying a new connection from the agent to the layer, or to its `steal_delivery`
+    /// target.
+    fn handle_new_connection(
+        &mut self,
+        NewTcpConnection {
+            connection_id,
+            remote_address,
+            destination_port,
+            source_port,
+            local_address,
+        }: NewTcpConnection,
+        original_destination: SocketAddr,
+    ) -> Result<(), IncomingProxyError> {
+        if let Some(target) = self.deliveries.get(&destination_port) {
+            let id = InterceptorId(connection_id);
+            let budget = self.drained.budget(id, self.buffers.high_watermark);
+            let interceptor = self.background_tasks.register(
+                Interceptor::new_delivery(target.clone(), budget.clone()),
+                id,
+                self.buffers.size,
+            );
+
+            self.interceptors.insert(
+                id,
+                InterceptorHandle {
+                    tx: interceptor,
+                    subscription: PortSubscription::Steal(StealType::All(destination_port)),
+                    reassembler: Default::default(),
+                    queue: ConnectionQueue::new(budget),
+                },
+            );
+
+            return Ok(());
+        }
+
+        let Some(subscription) = self.subscriptions.get(destination_port) else {
+            tracing::trace!(
+                "received a new connection for port {destination_port} that is no longer mirrored"
+            );
+            return Ok(());
+        };
+
+        let interceptor_socket = bind_similar(subscription.listening_on)?;
+
+        let id = InterceptorId(connection_id);
+
+        self.metadata_store.expect(
+            ConnMetadataRequest {
+                listener_address: subscription.listening_on,
+                peer_address: interceptor_socket.local_addr()?,
+            },
+            id,
+            ConnMetadataResponse {
+                remote_source: SocketAddr::new(remote_address, source_port),
+                local_address,
+                original_destination,
+            },
+        );
+
+        let budget = self.drained.budget(id, self.buffers.high_watermark);
+        let interceptor = self.background_tasks.register(
+            Interceptor::new(
+                interceptor_socket,
+                subscription.listening_on,
+                budget.clone(),
+            ),
+            id,
+            self.buffers.size,
+        );
+
+        self.interceptors.insert(
+            id,
+            InterceptorHandle {
+                tx: interceptor,
+                subscription: subscription.subscription.clone(),
+                reassembler: Default::default(),
+                queue: ConnectionQueue::new(budget),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Sends the data waiting in the [`ConnectionQueue`] of the [`Interceptor`] that fits in its
+    /// queue, and asks the agent to pause or resume the connection when needed.
+    ///
+    /// Mirrored connections can't be paused, the agent only sees a copy of their data. A mirrored
+    /// connection that falls behind by more than its whole budget is closed instead.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn flush_interceptor(&mut self, id: InterceptorId, message_bus: &mut MessageBus<Self>) {
+        let pause_supported = self.capabilities.contains(Capabilities::CONNECTION_PAUSE);
+
+        let Some(interceptor) = self.interceptors.get_mut(&id) else {
+            return;
+        };
+
+        let flow = interceptor
+            .queue
+            .flush(|bytes| interceptor.tx.try_send(bytes));
+
+        if matches!(interceptor.subscription, PortSubscription::Mirror(..))
+            && interceptor.queue.overflowing()
+        {
+            tracing::warn!(
+                "mirrored connection {} can't keep up with the remote traffic, closing it",
+                id.0
+            );
+
+            let msg = interceptor
+                .subscription
+                .wrap_agent_unsubscribe_connection(id.0);
+            self.interceptors.remove(&id);
+            self.metadata_store.no_longer_expect(id);
+            message_bus.send(msg).await;
+            return;
+        }
+
+        let msg = flow.filter(|_| pause_supported).and_then(|flow| {
+            interceptor
+                .subscription
+                .wrap_agent_pause_connection(id.0, flow)
+        });
+
+        if interceptor.queue.is_done() {
+            self.interceptors.remove(&id);
+        }
+
+        if let Some(msg) = msg {
+            message_bus.send(msg).await;
+        }
+    }
+
     /// Handles all agent messages.
     #[tracing::instrument(level = "trace", skip(self, message_bus))]
     async fn handle_agent_message(
@@ -241,13 +490,52 @@ impl IncomingProxy {
     ) -> Result<(), IncomingProxyError> {
         match message {
             DaemonTcp::Close(close) => {
-                self.interceptors
-                    .remove(&InterceptorId(close.connection_id));
+                let id = InterceptorId(close.connection_id);
+                if let Some(interceptor) = self.interceptors.get_mut(&id) {
+                    interceptor.queue.close();
+                    self.flush_interceptor(id, message_bus).await;
+                }
             }
             DaemonTcp::Data(data) => {
-                if let Some(interceptor) = self.interceptors.get(&InterceptorId(data.connection_id))
-                {
-                    interceptor.tx.send(data.bytes).await;
+                let id = InterceptorId(data.connection_id);
+                if let Some(interceptor) = self.interceptors.get_mut(&id) {
+                    interceptor.queue.push(data.bytes);
+                    self.flush_interceptor(id, message_bus).await;
+                } else {
+                    tracing::trace!(
+                        "received new data for connection {} that is already closed",
+                        data.connection_id
+                    );
+                }
+            }
+            DaemonTcp::CloseSequenced(close) => {
+                let id = InterceptorId(close.connection_id);
+                let Some(interceptor) = self.interceptors.get_mut(&id) else {
+                    return Ok(());
+                };
+
+                for bytes in interceptor.reassembler.finish(close.end_sequence) {
+                    interceptor.queue.push(bytes);
+                }
+                interceptor.queue.close();
+
+                let dropped_bytes = interceptor.reassembler.dropped_bytes();
+                if dropped_bytes > 0 {
+                    tracing::warn!(
+                        "mirrored connection {} was closed with {dropped_bytes} bytes missing",
+                        close.connection_id
+                    );
+                }
+
+                self.flush_interceptor(id, message_bus).await;
+            }
+            DaemonTcp::DataSequenced(data) => {
+                let id = InterceptorId(data.connection_id);
+                if let Some(interceptor) = self.interceptors.get_mut(&id) {
+                    for bytes in interceptor.reassembler.push(data.sequence, data.bytes) {
+                        interceptor.queue.push(bytes);
+                    }
+                    self.flush_interceptor(id, message_bus).await;
                 } else {
                     tracing::trace!(
                         "received new data for connection {} that is already closed",
@@ -269,49 +557,24 @@ impl IncomingProxy {
                     interceptor.send(req).await;
                 }
             }
-            DaemonTcp::NewConnection(NewTcpConnection {
-                connection_id,
-                remote_address,
-                destination_port,
-                source_port,
-                local_address,
-            }) => {
-                let Some(subscription) = self.subscriptions.get(destination_port) else {
-                    tracing::trace!("received a new connection for port {destination_port} that is no longer mirrored");
-                    return Ok(());
-                };
-
-                let interceptor_socket = bind_similar(subscription.listening_on)?;
-
-                let id = InterceptorId(connection_id);
-
-                self.metadata_store.expect(
-                    ConnMetadataRequest {
-                        listener_address: subscription.listening_on,
-                        peer_address: interceptor_socket.local_addr()?,
-                    },
-                    id,
-                    ConnMetadataResponse {
-                        remote_source: SocketAddr::new(remote_address, source_port),
-                        local_address,
-                    },
-                );
-
-                let interceptor = self.background_tasks.register(
-                    Interceptor::new(interceptor_socket, subscription.listening_on),
-                    id,
-                    Self::CHANNEL_SIZE,
-                );
-
-                self.interceptors.insert(
-                    id,
-                    InterceptorHandle {
-                        tx: interceptor,
-                        subscription: subscription.subscription.clone(),
-                    },
-                );
+            DaemonTcp::NewConnection(connection) => {
+                let original_destination =
+                    SocketAddr::new(connection.local_address, connection.destination_port);
+                self.handle_new_connection(connection, original_destination)?;
             }
+            DaemonTcp::NewConnectionV2(NewTcpConnectionV2 {
+                connection,
+                original_destination,
+            }) => self.handle_new_connection(connection, original_destination)?,
             DaemonTcp::SubscribeResult(result) => {
+                if let Err(ResponseError::PortAlreadyStolen(port)) = &result {
+                    if self.deliveries.contains_key(port) {
+                        tracing::warn!(
+                            "port {port} from `steal_delivery` is already stolen by someone else"
+                        );
+                    }
+                }
+
                 let msgs = self.subscriptions.agent_responded(result)?;
 
                 for msg in msgs {
@@ -328,12 +591,8 @@ impl IncomingProxy {
         self.subscriptions.layer_forked(parent, child);
     }
 
-    async fn handle_layer_close(&mut self, msg: LayerClosed, message_bus: &MessageBus<Self>) {
-        let msgs = self.subscriptions.layer_closed(msg.id);
-
-        for msg in msgs {
-            message_bus.send(msg).await;
-        }
+    fn handle_layer_close(&mut self, msg: LayerClosed) {
+        self.subscriptions.layer_closed(msg.id);
     }
 
     fn get_subscription(&self, interceptor_id: InterceptorId) -> Option<&PortSubscription> {
@@ -349,7 +608,17 @@ impl BackgroundTask for IncomingProxy {
     type MessageOut = ProxyMessage;
 
     async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        self.subscribe_deliveries(message_bus).await;
+
+        let mut reconcile = time::interval_at(
+            time::Instant::now() + Self::RECONCILE_INTERVAL,
+            Self::RECONCILE_INTERVAL,
+        );
+        reconcile.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
+            let next_release = self.subscriptions.next_release();
+
             tokio::select! {
                 msg = message_bus.recv() => match msg {
                     None => {
@@ -358,7 +627,7 @@ impl BackgroundTask for IncomingProxy {
                     },
                     Some(IncomingProxyMessage::LayerRequest(message_id, layer_id, req)) => match req {
                         IncomingRequest::PortSubscribe(subscribe) => self.handle_port_subscribe(message_id, layer_id, subscribe, message_bus).await,
-                        IncomingRequest::PortUnsubscribe(unsubscribe) => self.handle_port_unsubscribe(layer_id, unsubscribe, message_bus).await,
+                        IncomingRequest::PortUnsubscribe(unsubscribe) => self.handle_port_unsubscribe(layer_id, unsubscribe),
                         IncomingRequest::ConnMetadata(req) => {
                             let res = self.metadata_store.get(req);
                             message_bus.send(ToLayer { message_id, layer_id, message: ProxyToLayerMessage::Incoming(IncomingResponse::ConnMetadata(res))  }).await;
@@ -370,8 +639,14 @@ impl BackgroundTask for IncomingProxy {
                     Some(IncomingProxyMessage::AgentSteal(msg)) => {
                         self.handle_agent_message(msg, message_bus).await?;
                     }
-                    Some(IncomingProxyMessage::LayerClosed(msg)) => self.handle_layer_close(msg, message_bus).await,
+                    Some(IncomingProxyMessage::LayerClosed(msg)) => self.handle_layer_close(msg),
                     Some(IncomingProxyMessage::LayerForked(msg)) => self.handle_layer_fork(msg),
+                    Some(IncomingProxyMessage::AgentReconnected) => self.handle_agent_reconnected(message_bus).await,
+                    Some(IncomingProxyMessage::Capabilities(capabilities)) => {
+                        self.capabilities = capabilities;
+                        self.subscriptions.capabilities_negotiated(capabilities);
+                    }
+                    Some(IncomingProxyMessage::HttpFilterChanged(filter)) => self.handle_http_filter_changed(filter, message_bus).await,
                 },
 
                 Some(task_update) = self.background_tasks.next() => match task_update {
@@ -393,6 +668,20 @@ impl BackgroundTask for IncomingProxy {
                         }
                     },
                 },
+
+                _ = time::sleep_until(next_release.unwrap_or_else(Instant::now).into()), if next_release.is_some() => {
+                    for msg in self.subscriptions.release_expired(Instant::now()) {
+                        message_bus.send(msg).await;
+                    }
+                },
+
+                id = self.drained.next() => self.flush_interceptor(id, message_bus).await,
+
+                _ = reconcile.tick() => {
+                    for msg in self.subscriptions.reconcile() {
+                        message_bus.send(msg).await;
+                    }
+                },
             }
         }
     }
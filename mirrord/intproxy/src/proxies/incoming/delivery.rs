@@ -0,0 +1,182 @@
+//! Delivery of stolen raw TCP to a unix socket or a command, configured with
+//! `feature.network.incoming.steal_delivery`.
+
+use std::{
+    io,
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use mirrord_config::feature::network::incoming::StealDeliveryTarget;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UnixStream,
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+/// Connection to a [`StealDeliveryTarget`].
+pub enum DeliveryStream {
+    UnixSocket(UnixStream),
+    Command(CommandStream),
+}
+
+impl DeliveryStream {
+    /// Connects to the unix socket, or spawns the command.
+    pub async fn connect(target: &StealDeliveryTarget) -> io::Result<Self> {
+        match target {
+            StealDeliveryTarget::UnixSocket(path) => {
+                UnixStream::connect(path).await.map(Self::UnixSocket)
+            }
+            StealDeliveryTarget::Command(command) => {
+                CommandStream::spawn(command).map(Self::Command)
+            }
+        }
+    }
+}
+
+/// Stdio of a command spawned for a single stolen connection.
+///
+/// Data is written to the command's stdin and read from its stdout. Shutting down closes stdin, so
+/// the command sees EOF. The command is killed when this struct is dropped.
+pub struct CommandStream {
+    /// Kept only to kill the command on drop.
+    _child: Child,
+    /// [`None`] after shutdown.
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl CommandStream {
+    /// Spawns the `command`, which is the program followed by its arguments.
+    fn spawn(command: &[String]) -> io::Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        Ok(Self {
+            _child: child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+
+    fn stdin(&mut self) -> io::Result<Pin<&mut ChildStdin>> {
+        self.stdin
+            .as_mut()
+            .map(Pin::new)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.stdin() {
+            Ok(stdin) => stdin.poll_write(cx, buf),
+            Err(error) => Poll::Ready(Err(error)),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stdin() {
+            Ok(stdin) => stdin.poll_flush(cx),
+            Err(..) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Ok(stdin) = self.stdin() {
+            std::task::ready!(stdin.poll_flush(cx))?;
+        }
+
+        self.stdin = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for DeliveryStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::UnixSocket(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Command(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for DeliveryStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::UnixSocket(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Command(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::UnixSocket(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Command(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::UnixSocket(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Command(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn command_echoes() {
+        let mut stream =
+            DeliveryStream::connect(&StealDeliveryTarget::Command(vec!["cat".to_string()]))
+                .await
+                .unwrap();
+
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+}
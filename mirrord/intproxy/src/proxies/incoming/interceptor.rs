@@ -10,18 +10,22 @@ use std::{
 use bytes::BytesMut;
 use hyper::{upgrade::OnUpgrade, StatusCode, Version};
 use hyper_util::rt::TokioIo;
+use mirrord_config::feature::network::incoming::StealDeliveryTarget;
 use mirrord_protocol::tcp::{
     HttpRequestFallback, HttpResponse, HttpResponseFallback, InternalHttpBody,
 };
 use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpSocket, TcpStream},
     time,
 };
 
-use super::http::HttpSender;
-use crate::background_tasks::{BackgroundTask, MessageBus};
+use super::{delivery::DeliveryStream, http::HttpSender};
+use crate::{
+    background_tasks::{BackgroundTask, MessageBus},
+    backpressure::ByteBudget,
+};
 
 /// Messages consumed by the [`Interceptor`] when it runs as a [`BackgroundTask`].
 pub enum MessageIn {
@@ -85,9 +89,23 @@ pub type InterceptorResult<T, E = InterceptorError> = core::result::Result<T, E>
 /// This interceptor can proxy both raw TCP data and HTTP messages in the same TCP connection.
 /// When it receives [`MessageIn::Raw`], it starts acting as a simple proxy.
 /// When it received [`MessageIn::Http`], it starts acting as an HTTP gateway.
+///
+/// When created with [`Interceptor::new_delivery`], it only proxies raw TCP data to a
+/// [`StealDeliveryTarget`].
+///
+/// Raw data is taken from the connection's [`ByteBudget`] by the parent, and given back once it's
+/// written.
 pub struct Interceptor {
-    socket: TcpSocket,
-    peer: SocketAddr,
+    target: InterceptorTarget,
+    budget: ByteBudget,
+}
+
+/// Where the [`Interceptor`] sends the intercepted connection.
+enum InterceptorTarget {
+    /// The user application's port.
+    Peer { socket: TcpSocket, peer: SocketAddr },
+    /// A unix socket or command from `feature.network.incoming.steal_delivery`.
+    Delivery(StealDeliveryTarget),
 }
 
 impl Interceptor {
@@ -97,8 +115,19 @@ impl Interceptor {
     /// # Note
     ///
     /// The socket can be replaced when retrying HTTP requests.
-    pub fn new(socket: TcpSocket, peer: SocketAddr) -> Self {
-        Self { socket, peer }
+    pub fn new(socket: TcpSocket, peer: SocketAddr, budget: ByteBudget) -> Self {
+        Self {
+            target: InterceptorTarget::Peer { socket, peer },
+            budget,
+        }
+    }
+
+    /// Creates a new instance that proxies raw TCP data to the given delivery `target`.
+    pub fn new_delivery(target: StealDeliveryTarget, budget: ByteBudget) -> Self {
+        Self {
+            target: InterceptorTarget::Delivery(target),
+            budget,
+        }
     }
 }
 
@@ -108,7 +137,17 @@ impl BackgroundTask for Interceptor {
     type MessageOut = MessageOut;
 
     async fn run(self, message_bus: &mut MessageBus<Self>) -> InterceptorResult<(), Self::Error> {
-        let mut stream = self.socket.connect(self.peer).await?;
+        let budget = self.budget;
+        let _guard = budget.close_on_drop();
+        let (socket, peer) = match self.target {
+            InterceptorTarget::Peer { socket, peer } => (socket, peer),
+            InterceptorTarget::Delivery(target) => {
+                let stream = DeliveryStream::connect(&target).await?;
+                return RawConnection { stream, budget }.run(message_bus).await;
+            }
+        };
+
+        let mut stream = socket.connect(peer).await?;
 
         // First, we determine whether this is a raw TCP connection or an HTTP connection.
         // If we receive an HTTP request from our parent task, this must be an HTTP connection.
@@ -121,9 +160,10 @@ impl BackgroundTask for Interceptor {
                         stream.shutdown().await?;
                     } else {
                         stream.write_all(&data).await?;
+                        budget.give_back(data.len());
                     }
 
-                    return RawConnection { stream }.run(message_bus).await;
+                    return RawConnection { stream, budget }.run(message_bus).await;
                 }
                 Some(MessageIn::Http(request)) => request,
                 None => return Ok(()),
@@ -131,15 +171,12 @@ impl BackgroundTask for Interceptor {
 
             result = stream.readable() => {
                 result?;
-                return RawConnection { stream }.run(message_bus).await;
+                return RawConnection { stream, budget }.run(message_bus).await;
             }
         };
 
         let sender = super::http::handshake(request.version(), stream).await?;
-        let mut http_conn = HttpConnection {
-            sender,
-            peer: self.peer,
-        };
+        let mut http_conn = HttpConnection { sender, peer };
         let (response, on_upgrade) = http_conn.send(request).await?;
         message_bus.send(MessageOut::Http(response)).await;
 
@@ -154,15 +191,13 @@ impl BackgroundTask for Interceptor {
                     .await;
             }
 
-            Some(RawConnection {
-                stream: parts.io.into_inner(),
-            })
+            Some(parts.io.into_inner())
         } else {
             http_conn.run(message_bus).await?
         };
 
-        if let Some(raw) = raw {
-            raw.run(message_bus).await
+        if let Some(stream) = raw {
+            RawConnection { stream, budget }.run(message_bus).await
         } else {
             Ok(())
         }
@@ -286,6 +321,16 @@ impl HttpConnection {
     /// Sends the given [`HttpRequestFallback`] to the server.
     /// If the HTTP connection with server is closed too soon, starts a new connection and retries
     /// once. Returns [`HttpResponseFallback`] from the server.
+    #[tracing::instrument(
+        level = "info",
+        name = "stolen_request",
+        skip_all,
+        fields(
+            connection_id = request.connection_id(),
+            request_id = request.request_id(),
+            port = self.peer.port(),
+        )
+    )]
     async fn send(
         &mut self,
         request: HttpRequestFallback,
@@ -312,12 +357,12 @@ impl HttpConnection {
     /// Proxies HTTP messages until an HTTP upgrade happens or the [`MessageBus`] closes.
     /// Support retries (with reconnecting to the HTTP server).
     ///
-    /// When an HTTP upgrade happens, the underlying [`TcpStream`] is reclaimed, wrapped
-    /// in a [`RawConnection`] and returned. When [`MessageBus`] closes, [`None`] is returned.
+    /// When an HTTP upgrade happens, the underlying [`TcpStream`] is reclaimed and returned, to be
+    /// wrapped in a [`RawConnection`]. When [`MessageBus`] closes, [`None`] is returned.
     async fn run(
         mut self,
         message_bus: &mut MessageBus<Interceptor>,
-    ) -> InterceptorResult<Option<RawConnection>> {
+    ) -> InterceptorResult<Option<TcpStream>> {
         let upgrade = loop {
             let Some(msg) = message_bus.recv().await else {
                 return Ok(None);
@@ -352,18 +397,23 @@ impl HttpConnection {
             message_bus.send(MessageOut::Raw(read_buf.into())).await;
         }
 
-        Ok(Some(RawConnection { stream }))
+        Ok(Some(stream))
     }
 }
 
 /// Utilized by the [`Interceptor`] when it acts as a TCP proxy.
 /// See [`RawConnection::run`] for usage.
-struct RawConnection {
-    /// Connection between the [`Interceptor`] and the server.
-    stream: TcpStream,
+struct RawConnection<S> {
+    /// Connection between the [`Interceptor`] and the server (or the [`DeliveryStream`]).
+    stream: S,
+    /// Given back the bytes written to the `stream`.
+    budget: ByteBudget,
 }
 
-impl RawConnection {
+impl<S> RawConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     /// Proxies raw TCP data until the [`MessageBus`] closes.
     ///
     /// # Notes
@@ -409,6 +459,7 @@ impl RawConnection {
                             self.stream.shutdown().await?;
                         } else {
                             self.stream.write_all(&data).await?;
+                            self.budget.give_back(data.len());
                         }
                     },
                     Some(MessageIn::Http(..)) => break Err(InterceptorError::UnexpectedHttpRequest),
@@ -552,7 +603,11 @@ mod test {
         let interceptor = {
             let socket = TcpSocket::new_v4().unwrap();
             socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
-            tasks.register(Interceptor::new(socket, local_destination), (), 8)
+            tasks.register(
+                Interceptor::new(socket, local_destination, ByteBudget::new(1024, || {})),
+                (),
+                8,
+            )
         };
 
         interceptor
@@ -7,6 +7,7 @@ use mirrord_protocol::{
 };
 
 use super::interceptor::MessageOut;
+use crate::backpressure::FlowControl;
 
 /// Retrieves subscribed port from the given [`StealType`].
 fn get_port(steal_type: &StealType) -> Port {
@@ -32,6 +33,14 @@ pub trait PortSubscriptionExt {
     /// Returns an unsubscribe connection request to be sent to the agent.
     fn wrap_agent_unsubscribe_connection(&self, connection_id: ConnectionId) -> ClientMessage;
 
+    /// Returns a request to pause or resume the connection, to be sent to the agent.
+    /// [`None`] means that the connection can't be paused.
+    fn wrap_agent_pause_connection(
+        &self,
+        connection_id: ConnectionId,
+        flow: FlowControl,
+    ) -> Option<ClientMessage>;
+
     /// Returns a message to be sent to the agent in response to data coming from an interceptor.
     /// [`None`] means that the data should be discarded.
     fn wrap_response(&self, res: MessageOut, connection_id: ConnectionId) -> Option<ClientMessage>;
@@ -75,6 +84,25 @@ impl PortSubscriptionExt for PortSubscription {
         }
     }
 
+    /// Always [`None`] for the `mirror` mode - the agent only sees a copy of the data.
+    /// [`LayerTcpSteal::ConnectionPause`] or [`LayerTcpSteal::ConnectionResume`] for the `steal`
+    /// mode.
+    fn wrap_agent_pause_connection(
+        &self,
+        connection_id: ConnectionId,
+        flow: FlowControl,
+    ) -> Option<ClientMessage> {
+        match (self, flow) {
+            (Self::Mirror(..), _) => None,
+            (Self::Steal(..), FlowControl::Pause) => Some(ClientMessage::TcpSteal(
+                LayerTcpSteal::ConnectionPause(connection_id),
+            )),
+            (Self::Steal(..), FlowControl::Resume) => Some(ClientMessage::TcpSteal(
+                LayerTcpSteal::ConnectionResume(connection_id),
+            )),
+        }
+    }
+
     /// Always [`None`] for the `mirror` mode - data coming from the layer is discarded.
     /// Corrent [`LayerTcpSteal`] variant for the `steal` mode.
     fn wrap_response(&self, res: MessageOut, connection_id: ConnectionId) -> Option<ClientMessage> {
@@ -0,0 +1,194 @@
+//! Utilities for restoring the order of data in mirrored connections.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+/// Puts the data of a single mirrored connection back in order, using the sequence numbers from
+/// [`TcpSequencedData`](mirrord_protocol::tcp::TcpSequencedData).
+///
+/// The agent sniffs packets as they go through the network interface, so the data can come
+/// duplicated (retransmissions) or out of order. Out of order data is buffered until the gap
+/// before it is filled. When the buffer grows above [`StreamReassembler::MAX_PENDING_BYTES`], or
+/// the connection is closed, we give up on the gaps and account the missing bytes as dropped.
+#[derive(Debug, Default)]
+pub struct StreamReassembler {
+    /// Offset of the next byte to deliver.
+    next: u64,
+    /// Out of order segments, keyed by their offsets.
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// Total length of segments in [`Self::pending`].
+    pending_bytes: usize,
+    /// Number of bytes we gave up on.
+    dropped_bytes: u64,
+}
+
+impl StreamReassembler {
+    /// Maximum amount of out of order data buffered for a single connection.
+    pub const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+    /// Accepts a segment starting at offset `sequence`.
+    ///
+    /// Returns data that is ready to be delivered, in order.
+    pub fn push(&mut self, sequence: u64, bytes: Vec<u8>) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+
+        if sequence <= self.next {
+            self.deliver(sequence, bytes, &mut ready);
+        } else {
+            self.buffer(sequence, bytes);
+        }
+        self.drain_pending(&mut ready);
+
+        while self.pending_bytes > Self::MAX_PENDING_BYTES {
+            self.skip_gap();
+            self.drain_pending(&mut ready);
+        }
+
+        ready
+    }
+
+    /// Called when the connection is closed.
+    ///
+    /// Skips all remaining gaps and returns the buffered data. `end_sequence` is the total length
+    /// of the stream, if known.
+    pub fn finish(&mut self, end_sequence: Option<u64>) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+
+        self.drain_pending(&mut ready);
+        while !self.pending.is_empty() {
+            self.skip_gap();
+            self.drain_pending(&mut ready);
+        }
+
+        if let Some(end_sequence) = end_sequence.filter(|end| *end > self.next) {
+            self.dropped_bytes += end_sequence - self.next;
+            self.next = end_sequence;
+        }
+
+        ready
+    }
+
+    /// Number of bytes that were never received and thus never delivered.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes
+    }
+
+    /// Pushes the not yet delivered part of the segment into `ready`.
+    ///
+    /// The segment must not start after [`Self::next`].
+    fn deliver(&mut self, sequence: u64, mut bytes: Vec<u8>, ready: &mut Vec<Vec<u8>>) {
+        let end = sequence + bytes.len() as u64;
+        if end <= self.next {
+            return;
+        }
+
+        bytes.drain(..(self.next - sequence) as usize);
+        self.next = end;
+        ready.push(bytes);
+    }
+
+    fn buffer(&mut self, sequence: u64, bytes: Vec<u8>) {
+        match self.pending.entry(sequence) {
+            Entry::Occupied(mut e) if e.get().len() < bytes.len() => {
+                self.pending_bytes += bytes.len() - e.get().len();
+                e.insert(bytes);
+            }
+            Entry::Occupied(..) => {}
+            Entry::Vacant(e) => {
+                self.pending_bytes += bytes.len();
+                e.insert(bytes);
+            }
+        }
+    }
+
+    /// Delivers buffered segments, until a gap is encountered.
+    fn drain_pending(&mut self, ready: &mut Vec<Vec<u8>>) {
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.next {
+                break;
+            }
+
+            let (sequence, bytes) = entry.remove_entry();
+            self.pending_bytes -= bytes.len();
+            self.deliver(sequence, bytes, ready);
+        }
+    }
+
+    /// Gives up on the gap before the first buffered segment.
+    fn skip_gap(&mut self) {
+        if let Some(sequence) = self.pending.keys().next().copied() {
+            self.dropped_bytes += sequence.saturating_sub(self.next);
+            self.next = self.next.max(sequence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut reassembler = StreamReassembler::default();
+
+        assert_eq!(
+            reassembler.push(0, b"hello".to_vec()),
+            vec![b"hello".to_vec()]
+        );
+        assert_eq!(
+            reassembler.push(5, b" there".to_vec()),
+            vec![b" there".to_vec()]
+        );
+        assert!(reassembler.finish(Some(11)).is_empty());
+        assert_eq!(reassembler.dropped_bytes(), 0);
+    }
+
+    #[test]
+    fn out_of_order_and_retransmitted() {
+        let mut reassembler = StreamReassembler::default();
+
+        assert!(reassembler.push(5, b" there".to_vec()).is_empty());
+        assert!(reassembler.push(11, b"!".to_vec()).is_empty());
+        assert_eq!(
+            reassembler.push(0, b"hello".to_vec()),
+            vec![b"hello".to_vec(), b" there".to_vec(), b"!".to_vec()]
+        );
+
+        // Full and partial retransmissions.
+        assert!(reassembler.push(0, b"hello".to_vec()).is_empty());
+        assert_eq!(reassembler.push(10, b"e!?".to_vec()), vec![b"?".to_vec()]);
+
+        assert!(reassembler.finish(Some(13)).is_empty());
+        assert_eq!(reassembler.dropped_bytes(), 0);
+    }
+
+    #[test]
+    fn gaps_on_close() {
+        let mut reassembler = StreamReassembler::default();
+
+        assert_eq!(
+            reassembler.push(0, b"hello".to_vec()),
+            vec![b"hello".to_vec()]
+        );
+        assert!(reassembler.push(8, b"re".to_vec()).is_empty());
+        assert_eq!(reassembler.finish(Some(15)), vec![b"re".to_vec()]);
+        assert_eq!(reassembler.dropped_bytes(), 3 + 5);
+    }
+
+    #[test]
+    fn pending_limit() {
+        let mut reassembler = StreamReassembler::default();
+        let segment = vec![0; StreamReassembler::MAX_PENDING_BYTES / 2];
+
+        assert!(reassembler.push(10, segment.clone()).is_empty());
+        assert!(reassembler
+            .push(10 + segment.len() as u64, segment.clone())
+            .is_empty());
+
+        let ready = reassembler.push(10 + 2 * segment.len() as u64, vec![1]);
+        assert_eq!(ready.len(), 3);
+        assert_eq!(reassembler.dropped_bytes(), 10);
+
+        // Data from the skipped gap is no longer delivered.
+        assert!(reassembler.push(0, vec![2; 10]).is_empty());
+    }
+}
@@ -1,12 +1,18 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
     net::SocketAddr,
+    time::{Duration, Instant},
 };
 
 use mirrord_intproxy_protocol::{
-    IncomingResponse, LayerId, MessageId, PortSubscribe, PortUnsubscribe, ProxyToLayerMessage,
+    IncomingResponse, LayerId, MessageId, PortSubscribe, PortSubscription, PortUnsubscribe,
+    ProxyToLayerMessage,
+};
+use mirrord_protocol::{
+    capabilities::Capabilities,
+    tcp::{HttpFilter, StealType},
+    BlockedAction, ClientMessage, Port, RemoteResult, ResponseError,
 };
-use mirrord_protocol::{BlockedAction, ClientMessage, Port, RemoteResult, ResponseError};
 
 use super::{port_subscription_ext::PortSubscriptionExt, IncomingProxyError};
 use crate::{
@@ -32,6 +38,9 @@ struct Subscription {
     active_source: Source,
     /// Whether this subscription is confirmed.
     confirmed: bool,
+    /// Whether the agent lost this subscription, and another client holds the port now. We keep
+    /// subscribing again in [`SubscriptionsManager::reconcile`] until we get the port back.
+    lost: bool,
 }
 
 impl Subscription {
@@ -45,6 +54,7 @@ impl Subscription {
                 queued_sources: Default::default(),
                 active_source: source,
                 confirmed: false,
+                lost: false,
             },
             message,
         )
@@ -117,8 +127,8 @@ impl Subscription {
     }
 
     /// Removed a source from this subscription.
-    /// If this source is the last one, returns [`Err`] with a message to be sent to the agent.
-    fn remove_source(mut self, listening_on: SocketAddr) -> Result<Self, ClientMessage> {
+    /// If this source is the last one, returns [`Err`] with the released subscription.
+    fn remove_source(mut self, listening_on: SocketAddr) -> Result<Self, ReleasedSubscription> {
         let queue_size = self.queued_sources.len();
         self.queued_sources
             .retain(|source| source.request.listening_on != listening_on);
@@ -135,15 +145,40 @@ impl Subscription {
                 self.active_source = next_in_queue;
                 Ok(self)
             }
-            None => Err(self
-                .active_source
-                .request
-                .subscription
-                .wrap_agent_unsubscribe()),
+            None => Err(ReleasedSubscription {
+                subscription: self.active_source.request.subscription,
+                confirmed: self.confirmed,
+                release_at: Instant::now() + SubscriptionsManager::RELEASE_DELAY,
+            }),
+        }
+    }
+}
+
+/// Replaces the filter of a `steal` subscription with an HTTP filter. Returns whether it changed.
+fn replace_filter(subscription: &mut PortSubscription, filter: &HttpFilter) -> bool {
+    match subscription {
+        PortSubscription::Steal(StealType::FilteredHttpEx(_, current)) if current != filter => {
+            *current = filter.clone();
+            true
         }
+        _ => false,
     }
 }
 
+/// A subscription without any sources, that is still held in the agent.
+///
+/// Servers that restart quickly (e.g. with `SO_REUSEADDR`) close their listener and `listen` on
+/// the same port right after. Unsubscribing from the agent in between would drop the port for a
+/// moment, and with the `steal` mode, incoming connections would reach the remote application.
+#[derive(Debug)]
+struct ReleasedSubscription {
+    subscription: PortSubscription,
+    /// Whether the agent confirmed this subscription.
+    confirmed: bool,
+    /// When to unsubscribe from the agent, if the port is not subscribed again.
+    release_at: Instant,
+}
+
 /// Manages port subscriptions across all connected layers.
 /// Logic of this struct is a bit complicated for several reasons:
 /// 1. Layer can subscribe to a single port multiple times (e.g. with `port_mapping`)
@@ -156,9 +191,32 @@ impl Subscription {
 pub struct SubscriptionsManager {
     remote_ports: RemoteResources<(Port, SocketAddr)>,
     subscriptions: HashMap<Port, Subscription>,
+    /// Subscriptions that lost their last source, but are not yet unsubscribed from the agent.
+    released: HashMap<Port, ReleasedSubscription>,
+    /// Number of subscribe requests sent by [`Self::reconcile`] and [`Self::http_filter_changed`]
+    /// for confirmed subscriptions, that the agent did not respond to yet.
+    ///
+    /// The agent responds to requests for the same port in order, so these are always the first
+    /// responses we get for the port.
+    pending_checks: HashMap<Port, usize>,
+    /// HTTP filter that replaces the one in the layers' requests, after it was changed in the
+    /// config file.
+    http_filter: Option<HttpFilter>,
+    /// Whether the agent has [`Capabilities::STEAL_RESUBSCRIBE`], so that
+    /// [`ResponseError::PortAlreadyStolen`] in a response to [`Self::reconcile`] means that
+    /// another client took the port.
+    steal_resubscribe: bool,
 }
 
 impl SubscriptionsManager {
+    /// How long a port stays subscribed in the agent after its last source is removed.
+    pub const RELEASE_DELAY: Duration = Duration::from_secs(1);
+
+    /// Notifies this struct about the [`Capabilities`] negotiated with the agent.
+    pub fn capabilities_negotiated(&mut self, capabilities: Capabilities) {
+        self.steal_resubscribe = capabilities.contains(Capabilities::STEAL_RESUBSCRIBE);
+    }
+
     /// Returns active [`PortSubscribe`] request for the given [`Port`].
     pub fn get(&self, port: Port) -> Option<&PortSubscribe> {
         self.subscriptions
@@ -167,17 +225,25 @@ impl SubscriptionsManager {
     }
 
     /// Registers a new port subscription in this struct.
-    /// Optionally returns a message to be sent.
+    /// Returns messages to be sent.
     ///
     /// Subsequent subscriptions of the same port will take precedence over previous ones, meaning
     /// that new connections will be routed to the listener from the most recent [`PortSubscribe`]
     /// request.
+    ///
+    /// A [released](ReleasedSubscription) subscription with the same [`PortSubscription`] is
+    /// reused, without sending anything to the agent.
     pub fn layer_subscribed(
         &mut self,
         layer_id: LayerId,
         message_id: MessageId,
-        request: PortSubscribe,
-    ) -> Option<ProxyMessage> {
+        mut request: PortSubscribe,
+    ) -> Vec<ProxyMessage> {
+        // The layers keep using the filter they started with.
+        if let Some(filter) = &self.http_filter {
+            replace_filter(&mut request.subscription, filter);
+        }
+
         self.remote_ports.add(
             layer_id,
             (request.subscription.port(), request.listening_on),
@@ -190,41 +256,115 @@ impl SubscriptionsManager {
             request,
         };
 
-        match self.subscriptions.entry(port) {
-            Entry::Occupied(mut e) => e.get_mut().push_source(source).map(ProxyMessage::ToLayer),
-            Entry::Vacant(e) => {
+        let e = match self.subscriptions.entry(port) {
+            Entry::Occupied(mut e) => {
+                return e
+                    .get_mut()
+                    .push_source(source)
+                    .map(ProxyMessage::ToLayer)
+                    .into_iter()
+                    .collect();
+            }
+            Entry::Vacant(e) => e,
+        };
+
+        match self.released.remove(&port) {
+            Some(released) if released.subscription == source.request.subscription => {
+                let mut subscription = Subscription {
+                    queued_sources: Default::default(),
+                    active_source: source,
+                    confirmed: false,
+                    lost: false,
+                };
+
+                // If not confirmed, the response to the original request is still on its way.
+                let messages = if released.confirmed {
+                    subscription
+                        .confirm()
+                        .into_iter()
+                        .map(ProxyMessage::ToLayer)
+                        .collect()
+                } else {
+                    vec![]
+                };
+
+                e.insert(subscription);
+                messages
+            }
+
+            released => {
+                // The agent won't take a second subscription for the port.
+                let unsubscribe = released.map(|released| {
+                    ProxyMessage::ToAgent(released.subscription.wrap_agent_unsubscribe())
+                });
                 let (subscription, message) = Subscription::new(source);
                 e.insert(subscription);
-                Some(ProxyMessage::ToAgent(message))
+
+                unsubscribe
+                    .into_iter()
+                    .chain(std::iter::once(ProxyMessage::ToAgent(message)))
+                    .collect()
             }
         }
     }
 
     /// Unregisters a subscription from this struct.
-    /// Optionally returns a message to be sent to the agent.
-    pub fn layer_unsubscribed(
-        &mut self,
-        layer_id: LayerId,
-        request: PortUnsubscribe,
-    ) -> Option<ClientMessage> {
+    ///
+    /// When the last source of the port is removed, the agent subscription is only released, see
+    /// [`Self::release_expired`].
+    pub fn layer_unsubscribed(&mut self, layer_id: LayerId, request: PortUnsubscribe) {
         let closed_in_all_forks = self
             .remote_ports
             .remove(layer_id, (request.port, request.listening_on));
         if !closed_in_all_forks {
-            return None;
+            return;
         }
 
-        let subscription = self.subscriptions.remove(&request.port)?;
+        self.remove_source(request.port, request.listening_on);
+    }
 
-        match subscription.remove_source(request.listening_on) {
+    /// Removes a source from the subscription of the given `port`, releasing the subscription if
+    /// it was the last one.
+    fn remove_source(&mut self, port: Port, listening_on: SocketAddr) {
+        let Some(subscription) = self.subscriptions.remove(&port) else {
+            return;
+        };
+
+        match subscription.remove_source(listening_on) {
             Ok(subscription) => {
-                self.subscriptions.insert(request.port, subscription);
-                None
+                self.subscriptions.insert(port, subscription);
+            }
+            Err(released) => {
+                self.released.insert(port, released);
             }
-            Err(message) => Some(message),
         }
     }
 
+    /// Returns when the next [released](ReleasedSubscription) subscription should be unsubscribed.
+    pub fn next_release(&self) -> Option<Instant> {
+        self.released
+            .values()
+            .map(|released| released.release_at)
+            .min()
+    }
+
+    /// Drops [released](ReleasedSubscription) subscriptions that were not reused before `now`.
+    /// Returns messages to be sent to the agent.
+    pub fn release_expired(&mut self, now: Instant) -> Vec<ClientMessage> {
+        let expired = self
+            .released
+            .iter()
+            .filter(|(_, released)| released.release_at <= now)
+            .map(|(port, _)| *port)
+            .collect::<Vec<_>>();
+
+        expired
+            .into_iter()
+            .filter_map(|port| self.released.remove(&port))
+            .map(|released| released.subscription.wrap_agent_unsubscribe())
+            .collect()
+    }
+
     /// Notifies this struct about agent's response.
     /// Returns messages to be sent to the layers.
     #[tracing::instrument(level = "trace", ret, skip(self))]
@@ -232,6 +372,40 @@ impl SubscriptionsManager {
         &mut self,
         result: RemoteResult<Port>,
     ) -> Result<Vec<ToLayer>, IncomingProxyError> {
+        let port = match &result {
+            Ok(port) | Err(ResponseError::PortAlreadyStolen(port)) => Some(*port),
+            Err(ResponseError::Forbidden {
+                blocked_action: BlockedAction::Steal(steal_type),
+                ..
+            }) => Some(steal_type.get_port()),
+            Err(..) => None,
+        };
+
+        if let Some(port) = port {
+            if let Entry::Occupied(mut e) = self.pending_checks.entry(port) {
+                *e.get_mut() -= 1;
+                if *e.get() == 0 {
+                    e.remove();
+                }
+
+                self.check_responded(port, result);
+                return Ok(vec![]);
+            }
+
+            if let Some(released) = self.released.get_mut(&port) {
+                match result {
+                    Ok(..) => released.confirmed = true,
+                    // Never subscribed in the agent, nothing to unsubscribe.
+                    Err(..) if !released.confirmed => {
+                        self.released.remove(&port);
+                    }
+                    Err(..) => {}
+                }
+
+                return Ok(vec![]);
+            }
+        }
+
         match result {
             Ok(port) => {
                 let Some(subscription) = self.subscriptions.get_mut(&port) else {
@@ -274,34 +448,137 @@ impl SubscriptionsManager {
         }
     }
 
-    /// Notifies this struct about layer closing.
-    /// Returns messages to be sent to the agent.
-    pub fn layer_closed(&mut self, layer_id: LayerId) -> Vec<ClientMessage> {
-        self.remote_ports
-            .remove_all(layer_id)
-            .filter_map(|(port, listening_on)| {
-                let subscription = self.subscriptions.remove(&port)?;
-                match subscription.remove_source(listening_on) {
-                    Ok(subscription) => {
-                        self.subscriptions.insert(port, subscription);
-                        None
-                    }
-                    Err(message) => Some(message),
+    /// Handles the agent's response to a subscribe request sent by [`Self::reconcile`] or
+    /// [`Self::http_filter_changed`].
+    fn check_responded(&mut self, port: Port, result: RemoteResult<Port>) {
+        let subscription = self.subscriptions.get_mut(&port);
+
+        match result {
+            Err(ResponseError::PortAlreadyStolen(..)) if self.steal_resubscribe => {
+                let Some(subscription) = subscription.filter(|subscription| !subscription.lost)
+                else {
+                    return;
+                };
+
+                tracing::warn!(
+                    port,
+                    "the agent lost the port subscription, and another client stole the port, \
+                     subscribing again until we get it back"
+                );
+                subscription.lost = true;
+            }
+            // We still hold the port, or the agent lost the subscription and we made it again.
+            // Older agents fail with `PortAlreadyStolen` for the unfiltered steals we hold.
+            Ok(..) | Err(ResponseError::PortAlreadyStolen(..)) => {
+                if let Some(subscription) = subscription.filter(|subscription| subscription.lost) {
+                    tracing::info!(port, "got the port subscription back");
+                    subscription.lost = false;
                 }
-            })
-            .collect()
+            }
+            Err(error) => {
+                tracing::warn!(port, %error, "failed to verify port subscription with the agent")
+            }
+        }
+    }
+
+    /// Notifies this struct about layer closing.
+    pub fn layer_closed(&mut self, layer_id: LayerId) {
+        let ports = self.remote_ports.remove_all(layer_id).collect::<Vec<_>>();
+
+        for (port, listening_on) in ports {
+            self.remove_source(port, listening_on);
+        }
     }
 
     /// Notifies this struct about layer forking.
     pub fn layer_forked(&mut self, parent: LayerId, child: LayerId) {
         self.remote_ports.clone_all(parent, child);
     }
+
+    /// Returns messages to be sent to a new agent connection, to make all subscriptions again.
+    /// Confirmed subscriptions stay confirmed, as the layers already know about them.
+    ///
+    /// [Released](ReleasedSubscription) subscriptions are dropped, the new connection never had
+    /// them.
+    pub fn resubscribe(&mut self) -> Vec<ClientMessage> {
+        self.released.clear();
+        self.pending_checks.clear();
+
+        self.subscriptions
+            .values()
+            .map(|subscription| {
+                subscription
+                    .active_source
+                    .request
+                    .subscription
+                    .agent_subscribe()
+            })
+            .collect()
+    }
+
+    /// Replaces the HTTP filter of all `steal` subscriptions with a filter, including the ones the
+    /// layers make later. Returns messages to be sent to the agent.
+    ///
+    /// The agent replaces the filter of a port that we already stole with a filter (see
+    /// [`STEAL_FILTER_UPDATE_VERSION`](mirrord_protocol::tcp::STEAL_FILTER_UPDATE_VERSION)).
+    pub fn http_filter_changed(&mut self, filter: HttpFilter) -> Vec<ClientMessage> {
+        let mut messages = Vec::new();
+
+        for (port, subscription) in &mut self.subscriptions {
+            for source in &mut subscription.queued_sources {
+                replace_filter(&mut source.request.subscription, &filter);
+            }
+
+            let active = &mut subscription.active_source.request.subscription;
+            if replace_filter(active, &filter) {
+                tracing::info!(port, %filter, "updating the HTTP filter");
+
+                // Responses to unconfirmed subscriptions are handled as usual.
+                if subscription.confirmed {
+                    *self.pending_checks.entry(*port).or_default() += 1;
+                }
+                messages.push(active.agent_subscribe());
+            }
+        }
+
+        for (port, released) in &mut self.released {
+            if replace_filter(&mut released.subscription, &filter) {
+                if released.confirmed {
+                    *self.pending_checks.entry(*port).or_default() += 1;
+                }
+                messages.push(released.subscription.agent_subscribe());
+            }
+        }
+
+        self.http_filter = Some(filter);
+
+        messages
+    }
+
+    /// Returns messages that verify confirmed subscriptions with the agent.
+    ///
+    /// Subscribing again is a no-op for ports we still hold, and restores the ones the agent lost.
+    /// Ports that another client stole in the meantime are [lost](Subscription::lost), and we get
+    /// them back with a later check, once the other client lets go of them.
+    pub fn reconcile(&mut self) -> Vec<ClientMessage> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, subscription)| subscription.confirmed)
+            .map(|(port, subscription)| {
+                *self.pending_checks.entry(*port).or_default() += 1;
+                subscription
+                    .active_source
+                    .request
+                    .subscription
+                    .agent_subscribe()
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use mirrord_intproxy_protocol::PortSubscription;
-    use mirrord_protocol::tcp::LayerTcp;
+    use mirrord_protocol::tcp::{Filter, LayerTcp, LayerTcpSteal};
 
     use super::*;
 
@@ -322,10 +599,10 @@ mod test {
         );
         assert!(
             matches!(
-                response,
-                Some(ProxyMessage::ToAgent(ClientMessage::Tcp(
+                response.as_slice(),
+                [ProxyMessage::ToAgent(ClientMessage::Tcp(
                     LayerTcp::PortSubscribe(80)
-                )))
+                ))]
             ),
             "{response:?}"
         );
@@ -338,7 +615,7 @@ mod test {
                 subscription: PortSubscription::Mirror(80),
             },
         );
-        assert!(response.is_none(), "{response:?}");
+        assert!(response.is_empty(), "{response:?}");
 
         let mut responses = manager.agent_responded(Ok(80)).unwrap();
         assert_eq!(responses.len(), 2, "{responses:?}");
@@ -361,28 +638,33 @@ mod test {
         }
         assert_eq!(manager.get(80).unwrap().listening_on, listener_2);
 
-        let response = manager.layer_unsubscribed(
+        manager.layer_unsubscribed(
             LayerId(0),
             PortUnsubscribe {
                 port: 80,
                 listening_on: listener_2,
             },
         );
-        assert!(response.is_none(), "{response:?}");
         assert_eq!(manager.get(80).unwrap().listening_on, listener_1);
 
-        let response = manager.layer_unsubscribed(
+        manager.layer_unsubscribed(
             LayerId(0),
             PortUnsubscribe {
                 port: 80,
                 listening_on: listener_1,
             },
         );
-        assert!(matches!(
-            response,
-            Some(ClientMessage::Tcp(LayerTcp::PortUnsubscribe(80)))
-        ));
         assert!(manager.get(80).is_none());
+
+        let messages =
+            manager.release_expired(Instant::now() + SubscriptionsManager::RELEASE_DELAY);
+        assert!(
+            matches!(
+                messages.as_slice(),
+                [ClientMessage::Tcp(LayerTcp::PortUnsubscribe(80))]
+            ),
+            "{messages:?}"
+        );
     }
 
     #[test]
@@ -401,10 +683,10 @@ mod test {
         );
         assert!(
             matches!(
-                response,
-                Some(ProxyMessage::ToAgent(ClientMessage::Tcp(
+                response.as_slice(),
+                [ProxyMessage::ToAgent(ClientMessage::Tcp(
                     LayerTcp::PortSubscribe(80)
-                )))
+                ))]
             ),
             "{response:?}"
         );
@@ -427,28 +709,33 @@ mod test {
 
         manager.layer_forked(LayerId(0), LayerId(1));
 
-        let response = manager.layer_unsubscribed(
+        manager.layer_unsubscribed(
             LayerId(0),
             PortUnsubscribe {
                 port: 80,
                 listening_on,
             },
         );
-        assert!(response.is_none(), "{response:?}");
         assert_eq!(manager.get(80).unwrap().listening_on, listening_on);
 
-        let response = manager.layer_unsubscribed(
+        manager.layer_unsubscribed(
             LayerId(1),
             PortUnsubscribe {
                 port: 80,
                 listening_on,
             },
         );
-        assert!(matches!(
-            response,
-            Some(ClientMessage::Tcp(LayerTcp::PortUnsubscribe(80)))
-        ));
         assert!(manager.get(80).is_none());
+
+        let messages =
+            manager.release_expired(Instant::now() + SubscriptionsManager::RELEASE_DELAY);
+        assert!(
+            matches!(
+                messages.as_slice(),
+                [ClientMessage::Tcp(LayerTcp::PortUnsubscribe(80))]
+            ),
+            "{messages:?}"
+        );
     }
 
     #[test]
@@ -467,10 +754,10 @@ mod test {
         );
         assert!(
             matches!(
-                response,
-                Some(ProxyMessage::ToAgent(ClientMessage::Tcp(
+                response.as_slice(),
+                [ProxyMessage::ToAgent(ClientMessage::Tcp(
                     LayerTcp::PortSubscribe(80)
-                )))
+                ))]
             ),
             "{response:?}"
         );
@@ -496,4 +783,282 @@ mod test {
             .unwrap();
         assert!(responses.is_empty(), "{responses:?}");
     }
+
+    #[test]
+    fn resubscribe_after_reconnect() {
+        let listening_on = "127.0.0.1:1111".parse().unwrap();
+
+        let mut manager = SubscriptionsManager::default();
+
+        manager.layer_subscribed(
+            LayerId(0),
+            0,
+            PortSubscribe {
+                listening_on,
+                subscription: PortSubscription::Mirror(80),
+            },
+        );
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert_eq!(responses.len(), 1, "{responses:?}");
+
+        let messages = manager.resubscribe();
+        assert!(
+            matches!(
+                messages.as_slice(),
+                [ClientMessage::Tcp(LayerTcp::PortSubscribe(80))]
+            ),
+            "{messages:?}"
+        );
+
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert!(responses.is_empty(), "{responses:?}");
+    }
+
+    #[test]
+    fn resubscribe_after_fast_restart() {
+        let listening_on = "127.0.0.1:1111".parse().unwrap();
+        let subscribe = || PortSubscribe {
+            listening_on,
+            subscription: PortSubscription::Steal(StealType::All(80)),
+        };
+
+        let mut manager = SubscriptionsManager::default();
+
+        manager.layer_subscribed(LayerId(0), 0, subscribe());
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert_eq!(responses.len(), 1, "{responses:?}");
+
+        manager.layer_unsubscribed(
+            LayerId(0),
+            PortUnsubscribe {
+                port: 80,
+                listening_on,
+            },
+        );
+        assert!(manager.get(80).is_none());
+        assert!(manager.release_expired(Instant::now()).is_empty());
+
+        // Reused without asking the agent.
+        let response = manager.layer_subscribed(LayerId(0), 1, subscribe());
+        assert!(
+            matches!(
+                response.as_slice(),
+                [ProxyMessage::ToLayer(ToLayer {
+                    layer_id: LayerId(0),
+                    message_id: 1,
+                    message: ProxyToLayerMessage::Incoming(IncomingResponse::PortSubscribe(Ok(()))),
+                })]
+            ),
+            "{response:?}"
+        );
+        assert_eq!(manager.get(80).unwrap().listening_on, listening_on);
+
+        let messages =
+            manager.release_expired(Instant::now() + SubscriptionsManager::RELEASE_DELAY);
+        assert!(messages.is_empty(), "{messages:?}");
+        assert!(manager.next_release().is_none());
+    }
+
+    #[test]
+    fn resubscribe_with_different_filter() {
+        let listening_on = "127.0.0.1:1111".parse().unwrap();
+
+        let mut manager = SubscriptionsManager::default();
+
+        manager.layer_subscribed(
+            LayerId(0),
+            0,
+            PortSubscribe {
+                listening_on,
+                subscription: PortSubscription::Steal(StealType::All(80)),
+            },
+        );
+        manager.layer_unsubscribed(
+            LayerId(0),
+            PortUnsubscribe {
+                port: 80,
+                listening_on,
+            },
+        );
+
+        let response = manager.layer_subscribed(
+            LayerId(0),
+            1,
+            PortSubscribe {
+                listening_on,
+                subscription: PortSubscription::Mirror(80),
+            },
+        );
+        assert!(
+            matches!(
+                response.as_slice(),
+                [
+                    ProxyMessage::ToAgent(ClientMessage::TcpSteal(LayerTcpSteal::PortUnsubscribe(
+                        80
+                    ))),
+                    ProxyMessage::ToAgent(ClientMessage::Tcp(LayerTcp::PortSubscribe(80))),
+                ]
+            ),
+            "{response:?}"
+        );
+        assert!(manager.next_release().is_none());
+    }
+
+    #[test]
+    fn reconcile_ignores_held_ports() {
+        let listening_on = "127.0.0.1:1111".parse().unwrap();
+
+        let mut manager = SubscriptionsManager::default();
+
+        manager.layer_subscribed(
+            LayerId(0),
+            0,
+            PortSubscribe {
+                listening_on,
+                subscription: PortSubscription::Steal(StealType::All(80)),
+            },
+        );
+        assert!(manager.reconcile().is_empty());
+
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert_eq!(responses.len(), 1, "{responses:?}");
+
+        let messages = manager.reconcile();
+        assert!(
+            matches!(
+                messages.as_slice(),
+                [ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+                    StealType::All(80)
+                ))]
+            ),
+            "{messages:?}"
+        );
+
+        manager.layer_unsubscribed(
+            LayerId(0),
+            PortUnsubscribe {
+                port: 80,
+                listening_on,
+            },
+        );
+        manager.release_expired(Instant::now() + SubscriptionsManager::RELEASE_DELAY);
+        manager.layer_subscribed(
+            LayerId(0),
+            1,
+            PortSubscribe {
+                listening_on,
+                subscription: PortSubscription::Steal(StealType::All(80)),
+            },
+        );
+
+        // Response to the check, must not reject the new subscription.
+        let responses = manager
+            .agent_responded(Err(ResponseError::PortAlreadyStolen(80)))
+            .unwrap();
+        assert!(responses.is_empty(), "{responses:?}");
+        // The agent doesn't have `STEAL_RESUBSCRIBE`, so we might still hold the port.
+        assert!(!manager.subscriptions[&80].lost);
+
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert_eq!(responses.len(), 1, "{responses:?}");
+    }
+
+    #[test]
+    fn reconcile_detects_lost_ports() {
+        let mut manager = SubscriptionsManager::default();
+        manager.capabilities_negotiated(Capabilities::STEAL_RESUBSCRIBE);
+
+        manager.layer_subscribed(
+            LayerId(0),
+            0,
+            PortSubscribe {
+                listening_on: "127.0.0.1:1111".parse().unwrap(),
+                subscription: PortSubscription::Steal(StealType::All(80)),
+            },
+        );
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert_eq!(responses.len(), 1, "{responses:?}");
+
+        assert_eq!(manager.reconcile().len(), 1);
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert!(responses.is_empty(), "{responses:?}");
+        assert!(!manager.subscriptions[&80].lost);
+
+        // Another client stole the port after the agent lost our subscription.
+        assert_eq!(manager.reconcile().len(), 1);
+        let responses = manager
+            .agent_responded(Err(ResponseError::PortAlreadyStolen(80)))
+            .unwrap();
+        assert!(responses.is_empty(), "{responses:?}");
+        assert!(manager.subscriptions[&80].lost);
+
+        // We keep subscribing again, until the other client lets go of the port.
+        let messages = manager.reconcile();
+        assert!(
+            matches!(
+                messages.as_slice(),
+                [ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+                    StealType::All(80)
+                ))]
+            ),
+            "{messages:?}"
+        );
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert!(responses.is_empty(), "{responses:?}");
+        assert!(!manager.subscriptions[&80].lost);
+    }
+
+    #[test]
+    fn http_filter_changed() {
+        let header = |filter: &str| HttpFilter::Header(Filter::new(filter.into()).unwrap());
+        let subscribe = |port, filter| PortSubscribe {
+            listening_on: format!("127.0.0.1:{port}").parse().unwrap(),
+            subscription: PortSubscription::Steal(StealType::FilteredHttpEx(port, filter)),
+        };
+
+        let mut manager = SubscriptionsManager::default();
+
+        manager.layer_subscribed(LayerId(0), 0, subscribe(80, header("x-user: bear")));
+        manager.layer_subscribed(
+            LayerId(0),
+            1,
+            PortSubscribe {
+                listening_on: "127.0.0.1:81".parse().unwrap(),
+                subscription: PortSubscription::Steal(StealType::All(81)),
+            },
+        );
+        assert_eq!(manager.agent_responded(Ok(80)).unwrap().len(), 1);
+        assert_eq!(manager.agent_responded(Ok(81)).unwrap().len(), 1);
+
+        // Unfiltered subscriptions keep stealing everything.
+        let messages = manager.http_filter_changed(header("x-user: panda"));
+        assert_eq!(
+            messages,
+            [ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+                StealType::FilteredHttpEx(80, header("x-user: panda"))
+            ))]
+        );
+
+        // Response to the update, must not confirm again.
+        let responses = manager.agent_responded(Ok(80)).unwrap();
+        assert!(responses.is_empty(), "{responses:?}");
+
+        // The layers still use the old filter.
+        let response =
+            manager.layer_subscribed(LayerId(0), 2, subscribe(82, header("x-user: bear")));
+        assert!(
+            matches!(
+                response.as_slice(),
+                [ProxyMessage::ToAgent(ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+                    StealType::FilteredHttpEx(82, filter)
+                )))] if *filter == header("x-user: panda")
+            ),
+            "{response:?}"
+        );
+
+        // Nothing changed.
+        assert!(manager
+            .http_filter_changed(header("x-user: panda"))
+            .is_empty());
+    }
 }
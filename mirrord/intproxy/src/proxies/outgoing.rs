@@ -1,21 +1,26 @@
 //! Handles the logic of the `outgoing` feature.
 
-use std::{collections::HashMap, fmt, io};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+};
 
 use mirrord_intproxy_protocol::{
     LayerId, MessageId, NetProtocol, OutgoingConnectRequest, OutgoingConnectResponse,
     ProxyToLayerMessage,
 };
 use mirrord_protocol::{
+    capabilities::Capabilities,
     outgoing::{tcp::DaemonTcpOutgoing, udp::DaemonUdpOutgoing, DaemonConnect, DaemonRead},
-    ConnectionId, RemoteResult, ResponseError,
+    CancelRequest, ClientMessage, ConnectionId, RemoteResult, ResponseError,
 };
 use thiserror::Error;
 
 use self::interceptor::Interceptor;
 use crate::{
     background_tasks::{BackgroundTask, BackgroundTasks, MessageBus, TaskSender, TaskUpdate},
-    main_tasks::ToLayer,
+    backpressure::{ConnectionBuffers, ConnectionQueue, DrainedConnections},
+    main_tasks::{LayerClosed, ToLayer},
     proxies::outgoing::net_protocol_ext::NetProtocolExt,
     request_queue::{RequestQueue, RequestQueueEmpty},
     ProxyMessage,
@@ -79,35 +84,104 @@ impl fmt::Display for InterceptorId {
 #[derive(Default)]
 pub struct OutgoingProxy {
     /// For [`OutgoingConnectRequest`]s related to [`NetProtocol::Datagrams`].
-    datagrams_reqs: RequestQueue,
+    datagrams_reqs: RequestQueue<ClientMessage>,
     /// For [`OutgoingConnectRequest`]s related to [`NetProtocol::Stream`].
-    stream_reqs: RequestQueue,
-    /// [`TaskSender`]s for active [`Interceptor`] tasks.
-    txs: HashMap<InterceptorId, TaskSender<Interceptor>>,
+    stream_reqs: RequestQueue<ClientMessage>,
+    /// [`TaskSender`]s for active [`Interceptor`] tasks, with the data waiting for room in their
+    /// queues.
+    txs: HashMap<InterceptorId, (TaskSender<Interceptor>, ConnectionQueue)>,
     /// For managing [`Interceptor`] tasks.
     background_tasks: BackgroundTasks<InterceptorId, Vec<u8>, io::Error>,
+    /// Sizes of the queues of the [`Interceptor`]s.
+    buffers: ConnectionBuffers,
+    /// [`Interceptor`]s that got room for the data waiting in their [`ConnectionQueue`]s.
+    drained: DrainedConnections<InterceptorId>,
+    /// [`Capabilities`] negotiated with the agent.
+    capabilities: Capabilities,
+    /// Number of [`NetProtocol::Stream`] connection requests sent through the current agent
+    /// connection, which is how the agent identifies them in [`CancelRequest::TcpConnect`].
+    stream_connects_sent: u64,
+    /// Numbers of the [`NetProtocol::Stream`] connection requests that wait for a response.
+    stream_connect_ids: HashMap<(LayerId, MessageId), u64>,
+    /// Connection requests that the layer no longer waits for. Connections made for them are
+    /// closed right away.
+    cancelled: HashSet<(LayerId, MessageId)>,
 }
 
 impl OutgoingProxy {
-    /// Used when registering new [`Interceptor`] tasks in the [`BackgroundTasks`] struct.
-    const CHANNEL_SIZE: usize = 512;
+    /// Creates a new instance that queues the data of every connection within the given
+    /// `buffers`.
+    pub fn new(buffers: ConnectionBuffers) -> Self {
+        Self {
+            buffers,
+            ..Default::default()
+        }
+    }
 
     /// Retrieves correct [`RequestQueue`] for the given [`NetProtocol`].
-    fn queue(&mut self, protocol: NetProtocol) -> &mut RequestQueue {
+    fn queue(&mut self, protocol: NetProtocol) -> &mut RequestQueue<ClientMessage> {
         match protocol {
             NetProtocol::Datagrams => &mut self.datagrams_reqs,
             NetProtocol::Stream => &mut self.stream_reqs,
         }
     }
 
+    /// Checks whether the agent is able to handle [`ClientMessage::CancelRequest`].
+    fn cancel_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::CANCEL_REQUEST)
+    }
+
+    /// Checks whether the agent is able to pause connections, see [`Self::flush_interceptor`].
+    fn pause_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::CONNECTION_PAUSE)
+    }
+
+    /// Sends the data waiting in the [`ConnectionQueue`] of the [`Interceptor`] that fits in its
+    /// queue, and asks the agent to pause or resume the connection when needed.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn flush_interceptor(&mut self, id: InterceptorId, message_bus: &mut MessageBus<Self>) {
+        let pause_supported = self.pause_supported();
+
+        let Some((interceptor, queue)) = self.txs.get_mut(&id) else {
+            return;
+        };
+
+        let msg = queue
+            .flush(|bytes| interceptor.try_send(bytes))
+            .filter(|_| pause_supported)
+            .and_then(|flow| id.protocol.wrap_agent_pause(id.connection_id, flow));
+
+        if queue.is_done() {
+            self.txs.remove(&id);
+        }
+
+        if let Some(msg) = msg {
+            message_bus.send(ProxyMessage::ToAgent(msg)).await;
+        }
+    }
+
+    /// Handles the agent closing the connection. The [`Interceptor`] is dropped once all the data
+    /// waiting in its [`ConnectionQueue`] is sent to it.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_agent_close(&mut self, id: InterceptorId, message_bus: &mut MessageBus<Self>) {
+        if let Some((_, queue)) = self.txs.get_mut(&id) {
+            queue.close();
+            self.flush_interceptor(id, message_bus).await;
+        }
+    }
+
     /// Passes the data to the correct [`Interceptor`] task.
     /// Fails when the agent sends an error, because this error cannot be traced back to an exact
     /// connection.
-    #[tracing::instrument(level = "trace", skip(self))]
+    ///
+    /// Datagrams can't be paused, so they are dropped when the [`Interceptor`] falls behind by
+    /// more than its whole budget.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
     async fn handle_agent_read(
         &mut self,
         read: RemoteResult<DaemonRead>,
         protocol: NetProtocol,
+        message_bus: &mut MessageBus<Self>,
     ) -> Result<(), OutgoingProxyError> {
         let DaemonRead {
             connection_id,
@@ -119,14 +193,20 @@ impl OutgoingProxy {
             protocol,
         };
 
-        let Some(interceptor) = self.txs.get(&id) else {
+        let Some((_, queue)) = self.txs.get_mut(&id) else {
             tracing::trace!(
                 "{id} does not exist, received data for connection that is already closed"
             );
             return Ok(());
         };
 
-        interceptor.send(bytes).await;
+        if matches!(protocol, NetProtocol::Datagrams) && queue.overflowing() {
+            tracing::trace!("{id} can't keep up with the remote traffic, dropping a datagram");
+            return Ok(());
+        }
+
+        queue.push(bytes);
+        self.flush_interceptor(id, message_bus).await;
 
         Ok(())
     }
@@ -142,6 +222,17 @@ impl OutgoingProxy {
         message_bus: &mut MessageBus<Self>,
     ) -> Result<(), OutgoingProxyError> {
         let (message_id, layer_id) = self.queue(protocol).get()?;
+        self.stream_connect_ids.remove(&(layer_id, message_id));
+
+        if self.cancelled.remove(&(layer_id, message_id)) {
+            if let Ok(DaemonConnect { connection_id, .. }) = connect {
+                tracing::trace!("connection request was cancelled, closing {connection_id}");
+                let msg = protocol.wrap_agent_close(connection_id);
+                message_bus.send(ProxyMessage::ToAgent(msg)).await;
+            }
+
+            return Ok(());
+        }
 
         let connect = match connect {
             Ok(connect) => connect,
@@ -172,12 +263,14 @@ impl OutgoingProxy {
             protocol,
         };
 
+        let budget = self.drained.budget(id, self.buffers.high_watermark);
         let interceptor = self.background_tasks.register(
-            Interceptor::new(prepared_socket),
+            Interceptor::new(prepared_socket, budget.clone()),
             id,
-            Self::CHANNEL_SIZE,
+            self.buffers.size,
         );
-        self.txs.insert(id, interceptor);
+        self.txs
+            .insert(id, (interceptor, ConnectionQueue::new(budget)));
 
         message_bus
             .send(ToLayer {
@@ -202,11 +295,127 @@ impl OutgoingProxy {
         request: OutgoingConnectRequest,
         message_bus: &mut MessageBus<Self>,
     ) {
-        self.queue(request.protocol).insert(message_id, session_id);
-
         let msg = request.protocol.wrap_agent_connect(request.remote_address);
+        self.queue(request.protocol)
+            .insert_request(message_id, session_id, msg.clone());
+
+        if let NetProtocol::Stream = request.protocol {
+            self.stream_connect_ids
+                .insert((session_id, message_id), self.stream_connects_sent);
+            self.stream_connects_sent += 1;
+        }
+
         message_bus.send(ProxyMessage::ToAgent(msg)).await;
     }
+
+    /// Handles the layer giving up on its connection request.
+    ///
+    /// The agent is told to abandon the request when it's able to, and the connection is closed
+    /// if it's made anyway.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_cancel(
+        &mut self,
+        message_id: MessageId,
+        layer_id: LayerId,
+        message_bus: &mut MessageBus<Self>,
+    ) {
+        let pending = self
+            .stream_reqs
+            .pending_with_ids()
+            .chain(self.datagrams_reqs.pending_with_ids())
+            .any(|(pending_message_id, pending_layer_id, _)| {
+                pending_message_id == message_id && pending_layer_id == layer_id
+            });
+        if !pending {
+            return;
+        }
+
+        self.cancelled.insert((layer_id, message_id));
+
+        let Some(id) = self.stream_connect_ids.remove(&(layer_id, message_id)) else {
+            return;
+        };
+        if self.cancel_supported() {
+            let msg = ClientMessage::CancelRequest(CancelRequest::TcpConnect(id));
+            message_bus.send(ProxyMessage::ToAgent(msg)).await;
+        }
+    }
+
+    /// Cancels all connection requests of the closed layer, see [`Self::handle_cancel`].
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_layer_closed(&mut self, id: LayerId, message_bus: &mut MessageBus<Self>) {
+        let pending = self
+            .stream_reqs
+            .pending_with_ids()
+            .chain(self.datagrams_reqs.pending_with_ids())
+            .filter(|(_, layer_id, _)| *layer_id == id)
+            .map(|(message_id, ..)| message_id)
+            .collect::<Vec<_>>();
+
+        for message_id in pending {
+            self.handle_cancel(message_id, id, message_bus).await;
+        }
+    }
+
+    /// Handles the agent connection being dialed again.
+    ///
+    /// Connections made through the previous agent connection are gone, so their [`Interceptor`]s
+    /// are dropped, which closes the layer's sockets. Connection requests that were still waiting
+    /// for a response are sent again.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_agent_reconnected(&mut self, message_bus: &mut MessageBus<Self>) {
+        self.txs.clear();
+
+        self.stream_connect_ids.clear();
+        self.stream_connects_sent = 0;
+        for (message_id, layer_id, _) in self.stream_reqs.pending_with_ids() {
+            if !self.cancelled.contains(&(layer_id, message_id)) {
+                self.stream_connect_ids
+                    .insert((layer_id, message_id), self.stream_connects_sent);
+            }
+            self.stream_connects_sent += 1;
+        }
+
+        let pending = self
+            .stream_reqs
+            .pending()
+            .chain(self.datagrams_reqs.pending())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for msg in pending {
+            message_bus.send(ProxyMessage::ToAgent(msg)).await;
+        }
+    }
+
+    /// Handles the agent connection being dropped on purpose.
+    ///
+    /// Closes the layer's sockets of connections made through it, and responds with
+    /// [`ProxyToLayerMessage::Interrupted`] to connection requests that were still waiting for a
+    /// response.
+    #[tracing::instrument(level = "trace", skip(self, message_bus))]
+    async fn handle_agent_connection_reset(&mut self, message_bus: &mut MessageBus<Self>) {
+        self.txs.clear();
+        self.stream_connect_ids.clear();
+        self.stream_connects_sent = 0;
+        self.cancelled.clear();
+
+        let pending = self
+            .stream_reqs
+            .drain()
+            .chain(self.datagrams_reqs.drain())
+            .collect::<Vec<_>>();
+
+        for (message_id, layer_id) in pending {
+            message_bus
+                .send(ToLayer {
+                    message_id,
+                    layer_id,
+                    message: ProxyToLayerMessage::Interrupted,
+                })
+                .await;
+        }
+    }
 }
 
 /// Messages consumed by the [`OutgoingProxy`] running as a [`BackgroundTask`].
@@ -214,6 +423,16 @@ pub enum OutgoingProxyMessage {
     AgentStream(DaemonTcpOutgoing),
     AgentDatagrams(DaemonUdpOutgoing),
     LayerConnect(OutgoingConnectRequest, MessageId, LayerId),
+    /// The connection with the agent was dialed again.
+    AgentReconnected,
+    /// The connection with the agent was dropped on purpose.
+    AgentConnectionReset,
+    /// [`Capabilities`] were negotiated with the agent.
+    Capabilities(Capabilities),
+    LayerClosed(LayerClosed),
+    /// The layer no longer waits for the response to its request, see
+    /// [`LayerToProxyMessage::Cancel`](mirrord_intproxy_protocol::LayerToProxyMessage::Cancel).
+    Cancel(MessageId, LayerId),
 }
 
 impl BackgroundTask for OutgoingProxy {
@@ -232,17 +451,17 @@ impl BackgroundTask for OutgoingProxy {
                     Some(OutgoingProxyMessage::AgentStream(req)) => match req {
                         DaemonTcpOutgoing::Close(close) => {
                             let id = InterceptorId { connection_id: close, protocol: NetProtocol::Stream};
-                            self.txs.remove(&id);
+                            self.handle_agent_close(id, message_bus).await;
                         },
-                        DaemonTcpOutgoing::Read(read) => self.handle_agent_read(read, NetProtocol::Stream).await?,
+                        DaemonTcpOutgoing::Read(read) => self.handle_agent_read(read, NetProtocol::Stream, message_bus).await?,
                         DaemonTcpOutgoing::Connect(connect) => self.handle_connect_response(connect, NetProtocol::Stream, message_bus).await?,
                     }
                     Some(OutgoingProxyMessage::AgentDatagrams(req)) => match req {
                         DaemonUdpOutgoing::Close(close) => {
                             let id = InterceptorId { connection_id: close, protocol: NetProtocol::Datagrams};
-                            self.txs.remove(&id);
+                            self.handle_agent_close(id, message_bus).await;
                         }
-                        DaemonUdpOutgoing::Read(read) => self.handle_agent_read(read, NetProtocol::Datagrams).await?,
+                        DaemonUdpOutgoing::Read(read) => self.handle_agent_read(read, NetProtocol::Datagrams, message_bus).await?,
                         DaemonUdpOutgoing::Connect(connect) => self.handle_connect_response(connect, NetProtocol::Datagrams, message_bus).await?,
                     }
                     Some(OutgoingProxyMessage::LayerConnect(req, message_id, session_id)) => self.handle_connect_request(
@@ -251,8 +470,17 @@ impl BackgroundTask for OutgoingProxy {
                         req,
                         message_bus
                     ).await,
+                    Some(OutgoingProxyMessage::AgentReconnected) => self.handle_agent_reconnected(message_bus).await,
+                    Some(OutgoingProxyMessage::AgentConnectionReset) => self.handle_agent_connection_reset(message_bus).await,
+                    Some(OutgoingProxyMessage::Capabilities(capabilities)) => {
+                        self.capabilities = capabilities;
+                    }
+                    Some(OutgoingProxyMessage::LayerClosed(LayerClosed { id })) => self.handle_layer_closed(id, message_bus).await,
+                    Some(OutgoingProxyMessage::Cancel(message_id, layer_id)) => self.handle_cancel(message_id, layer_id, message_bus).await,
                 },
 
+                id = self.drained.next() => self.flush_interceptor(id, message_bus).await,
+
                 Some(task_update) = self.background_tasks.next() => match task_update {
                     (id, TaskUpdate::Message(bytes)) => {
                         let msg = id.protocol.wrap_agent_write(id.connection_id, bytes);
@@ -5,6 +5,7 @@ use std::io;
 
 use crate::{
     background_tasks::{BackgroundTask, MessageBus},
+    backpressure::ByteBudget,
     proxies::outgoing::net_protocol_ext::PreparedSocket,
 };
 
@@ -13,13 +14,15 @@ use crate::{
 /// to manage individual connections.
 pub struct Interceptor {
     socket: PreparedSocket,
+    /// Given back the bytes sent to the layer.
+    budget: ByteBudget,
 }
 
 impl Interceptor {
     /// Creates a new instance. This instance will use the provided [`PreparedSocket`] to accept the
     /// layer's connection and manage it.
-    pub fn new(socket: PreparedSocket) -> Self {
-        Self { socket }
+    pub fn new(socket: PreparedSocket, budget: ByteBudget) -> Self {
+        Self { socket, budget }
     }
 }
 
@@ -43,6 +46,7 @@ impl BackgroundTask for Interceptor {
     /// 3. This implementation exits only when an error is encountered or the [`MessageBus`] is
     ///    closed.
     async fn run(self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        let _guard = self.budget.close_on_drop();
         let mut connected_socket = self.socket.accept().await?;
         let mut reading_closed = false;
 
@@ -69,6 +73,7 @@ impl BackgroundTask for Interceptor {
                             connected_socket.shutdown().await?;
                         } else {
                             connected_socket.send(&bytes).await?;
+                            self.budget.give_back(bytes.len());
                         }
                     }
 
@@ -23,6 +23,8 @@ use tokio::{
     net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream},
 };
 
+use crate::backpressure::FlowControl;
+
 /// Trait for [`NetProtocol`] that handles differences in [`mirrord_protocol::outgoing`] between
 /// network protocols. Allows to unify logic.
 pub trait NetProtocolExt: Sized {
@@ -34,6 +36,14 @@ pub trait NetProtocolExt: Sized {
     /// The enum path used here depends on this protocol.
     fn wrap_agent_close(self, connection_id: ConnectionId) -> ClientMessage;
 
+    /// Creates a [`LayerTcpOutgoing::Pause`] or [`LayerTcpOutgoing::Resume`] message and wraps it
+    /// into the common [`ClientMessage`] type. [`None`] means that this protocol can't be paused.
+    fn wrap_agent_pause(
+        self,
+        connection_id: ConnectionId,
+        flow: FlowControl,
+    ) -> Option<ClientMessage>;
+
     /// Creates a [`LayerConnect`] message and wraps it into the common [`ClientMessage`] type.
     /// The enum path used here depends on this protocol.
     fn wrap_agent_connect(self, remote_address: SocketAddress) -> ClientMessage;
@@ -67,6 +77,22 @@ impl NetProtocolExt for NetProtocol {
         }
     }
 
+    fn wrap_agent_pause(
+        self,
+        connection_id: ConnectionId,
+        flow: FlowControl,
+    ) -> Option<ClientMessage> {
+        match (self, flow) {
+            (Self::Datagrams, _) => None,
+            (Self::Stream, FlowControl::Pause) => Some(ClientMessage::TcpOutgoing(
+                LayerTcpOutgoing::Pause(connection_id),
+            )),
+            (Self::Stream, FlowControl::Resume) => Some(ClientMessage::TcpOutgoing(
+                LayerTcpOutgoing::Resume(connection_id),
+            )),
+        }
+    }
+
     fn wrap_agent_connect(self, remote_address: SocketAddress) -> ClientMessage {
         match self {
             Self::Datagrams => {
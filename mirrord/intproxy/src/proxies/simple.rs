@@ -5,14 +5,23 @@ use std::collections::HashMap;
 
 use mirrord_intproxy_protocol::{LayerId, MessageId, ProxyToLayerMessage};
 use mirrord_protocol::{
-    dns::{GetAddrInfoRequest, GetAddrInfoResponse},
-    file::{CloseDirRequest, CloseFileRequest, OpenDirResponse, OpenFileResponse},
-    ClientMessage, FileRequest, FileResponse, GetEnvVarsRequest, RemoteResult,
+    capabilities::Capabilities,
+    dns::{GetAddrInfoRequestV2, GetAddrInfoResponse},
+    file::{
+        BatchFileRequest, CloseDirRequest, CloseFileRequest, OpenDirResponse, OpenFileRequest,
+        OpenFileResponse, OpenRelativeFileRequest,
+    },
+    CancelRequest, ClientMessage, FileRequest, FileResponse, GetEnvVarsRequest, RemoteResult,
+    ResponseError,
 };
+use tokio::time::{self, Instant};
 
 use crate::{
     background_tasks::{BackgroundTask, MessageBus},
+    file_limits::FileLimits,
+    file_streams::{self, FileStreams},
     main_tasks::{LayerClosed, LayerForked, ToLayer},
+    remote_files::RemoteFiles,
     remote_resources::RemoteResources,
     request_queue::{RequestQueue, RequestQueueEmpty},
     ProxyMessage,
@@ -21,12 +30,22 @@ use crate::{
 pub enum SimpleProxyMessage {
     FileReq(MessageId, LayerId, FileRequest),
     FileRes(FileResponse),
-    AddrInfoReq(MessageId, LayerId, GetAddrInfoRequest),
+    AddrInfoReq(MessageId, LayerId, GetAddrInfoRequestV2),
     AddrInfoRes(GetAddrInfoResponse),
     LayerForked(LayerForked),
     LayerClosed(LayerClosed),
     GetEnvReq(MessageId, LayerId, GetEnvVarsRequest),
     GetEnvRes(RemoteResult<HashMap<String, String>>),
+    /// [`Capabilities`] were negotiated with the agent.
+    Capabilities(Capabilities),
+    /// The connection with the agent was dialed again, requests sent before were lost, and so were
+    /// the remote descriptors.
+    AgentReconnected,
+    /// The connection with the agent was dropped on purpose, requests sent before are lost.
+    AgentConnectionReset,
+    /// The layer stopped waiting for the response to its request, see
+    /// [`LayerToProxyMessage::Cancel`](mirrord_intproxy_protocol::LayerToProxyMessage::Cancel).
+    Cancel(MessageId, LayerId),
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
@@ -35,155 +54,688 @@ enum RemoteFd {
     Dir(u64),
 }
 
+/// Whether sending `req` twice has the same effect as sending it once, so that it can be sent
+/// again when the agent connection was lost before its response came, and the agent may have
+/// handled it.
+///
+/// Reads only change the remote descriptors, which are opened again anyway. Writes, and opens
+/// that create or truncate the file, are not sent again.
+fn is_idempotent(req: &FileRequest) -> bool {
+    match req {
+        FileRequest::Open(OpenFileRequest { open_options, .. })
+        | FileRequest::OpenRelative(OpenRelativeFileRequest { open_options, .. }) => {
+            !(open_options.create || open_options.create_new || open_options.truncate)
+        }
+        FileRequest::Batch(BatchFileRequest { requests }) => requests.iter().all(is_idempotent),
+        FileRequest::Read(..)
+        | FileRequest::ReadLimited(..)
+        | FileRequest::Seek(..)
+        | FileRequest::Access(..)
+        | FileRequest::Xstat(..)
+        | FileRequest::XstatFs(..)
+        | FileRequest::FdOpenDir(..)
+        | FileRequest::ReadDir(..)
+        | FileRequest::GetDEnts64(..)
+        | FileRequest::ReadDirBatch(..)
+        | FileRequest::Checksum(..)
+        | FileRequest::ReadStream(..)
+        | FileRequest::GetDEnts64Stream(..)
+        | FileRequest::ReadChecksummed(..)
+        | FileRequest::ScratchDir(..) => true,
+        FileRequest::Write(..)
+        | FileRequest::WriteLimited(..)
+        | FileRequest::Close(..)
+        | FileRequest::CloseDir(..) => false,
+    }
+}
+
+/// The response that fails a `req` that is not sent again after the agent connection was lost,
+/// see [`is_idempotent`] and [`mirrord_intproxy_protocol::connection_lost`].
+///
+/// [`None`] for the requests without a response.
+fn lost_response(req: &FileRequest) -> Option<FileResponse> {
+    error_response(req, mirrord_intproxy_protocol::connection_lost())
+}
+
+/// The response that fails `req` with `error`.
+///
+/// [`None`] for the requests without a response.
+fn error_response(req: &FileRequest, error: ResponseError) -> Option<FileResponse> {
+    let res = match req {
+        FileRequest::Open(..) | FileRequest::OpenRelative(..) => FileResponse::Open(Err(error)),
+        FileRequest::Write(..) => FileResponse::Write(Err(error)),
+        FileRequest::WriteLimited(..) => FileResponse::WriteLimited(Err(error)),
+        FileRequest::Batch(..) => FileResponse::Batch(Err(error)),
+        FileRequest::Read(..) | FileRequest::ReadStream(..) => FileResponse::Read(Err(error)),
+        FileRequest::ReadLimited(..) => FileResponse::ReadLimited(Err(error)),
+        FileRequest::Seek(..) => FileResponse::Seek(Err(error)),
+        FileRequest::Access(..) => FileResponse::Access(Err(error)),
+        FileRequest::Xstat(..) => FileResponse::Xstat(Err(error)),
+        FileRequest::XstatFs(..) => FileResponse::XstatFs(Err(error)),
+        FileRequest::FdOpenDir(..) => FileResponse::OpenDir(Err(error)),
+        FileRequest::ReadDir(..) => FileResponse::ReadDir(Err(error)),
+        FileRequest::GetDEnts64(..) | FileRequest::GetDEnts64Stream(..) => {
+            FileResponse::GetDEnts64(Err(error))
+        }
+        FileRequest::ReadDirBatch(..) => FileResponse::ReadDirBatch(Err(error)),
+        FileRequest::Checksum(..) => FileResponse::Checksum(Err(error)),
+        FileRequest::ReadChecksummed(..) => FileResponse::ReadChecksummed(Err(error)),
+        FileRequest::ScratchDir(..) => FileResponse::ScratchDir(Err(error)),
+        FileRequest::Close(..) | FileRequest::CloseDir(..) => return None,
+    };
+
+    Some(res)
+}
+
 /// For passing messages between the layer and the agent without custom internal logic.
 /// Run as a [`BackgroundTask`].
 #[derive(Default)]
 pub struct SimpleProxy {
-    /// Remote descriptors for open files and directories. Allows tracking across layer forks.
+    /// Descriptors of the open files and directories, given to the layers by [`RemoteFiles`].
+    /// Allows tracking across layer forks.
     remote_fds: RemoteResources<RemoteFd>,
-    /// For [`FileRequest`]s.
-    file_reqs: RequestQueue,
-    /// For [`GetAddrInfoRequest`]s.
-    addr_info_reqs: RequestQueue,
+    /// Maps the descriptors of the layers to the descriptors of the agent.
+    files: RemoteFiles,
+    /// For [`FileRequest`]s, with the descriptors of the layers.
+    file_reqs: RequestQueue<FileRequest>,
+    /// For [`GetAddrInfoRequestV2`]s.
+    addr_info_reqs: RequestQueue<GetAddrInfoRequestV2>,
     /// For [`GetEnvVarsRequest`]s.
-    get_env_reqs: RequestQueue,
+    get_env_reqs: RequestQueue<GetEnvVarsRequest>,
+    /// [`Capabilities`] negotiated with the agent.
+    /// Determines which [`FileRequest`]s can be sent.
+    capabilities: Capabilities,
+    /// Puts together the responses streamed by the agent.
+    file_streams: FileStreams,
+    /// Enforces the `feature.fs.max_*` limits for the whole session.
+    limits: FileLimits,
 }
 
-impl BackgroundTask for SimpleProxy {
-    type Error = RequestQueueEmpty;
-    type MessageIn = SimpleProxyMessage;
-    type MessageOut = ProxyMessage;
+impl SimpleProxy {
+    pub fn new(limits: FileLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
 
-    async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), RequestQueueEmpty> {
-        while let Some(msg) = message_bus.recv().await {
-            match msg {
-                SimpleProxyMessage::FileReq(
-                    _,
+    /// Checks whether the agent is able to handle [`FileRequest::ReadDirBatch`].
+    fn readdir_batch_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::READDIR_BATCH)
+    }
+
+    /// Checks whether the agent is able to handle [`FileRequest::Checksum`].
+    fn checksum_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::CHECKSUM)
+    }
+
+    /// Checks whether the agent is able to handle [`FileRequest::ReadChecksummed`].
+    fn read_checksum_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::READ_CHECKSUM)
+    }
+
+    /// Checks whether the agent is able to handle [`FileRequest::ScratchDir`].
+    fn scratch_dir_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::SCRATCH_DIR)
+    }
+
+    /// Checks whether the agent is able to handle [`FileRequest::Batch`].
+    fn file_batch_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::FILE_BATCH)
+    }
+
+    /// Checks whether the agent is able to handle [`FileRequest::ReadStream`] and
+    /// [`FileRequest::GetDEnts64Stream`].
+    fn file_stream_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::FILE_STREAM)
+    }
+
+    /// Checks whether the agent is able to handle [`ClientMessage::GetAddrInfoRequestV2`].
+    fn addr_info_family_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::ADDRINFO_FAMILY)
+    }
+
+    /// Prepares the layer's `req` to be sent to the agent, resolving IPv4 addresses only when the
+    /// agent can't resolve the address families the layer asked for.
+    fn addr_info_request_to_agent(&self, req: GetAddrInfoRequestV2) -> ClientMessage {
+        if self.addr_info_family_supported() {
+            ClientMessage::GetAddrInfoRequestV2(req)
+        } else {
+            ClientMessage::GetAddrInfoRequest(req.into())
+        }
+    }
+
+    /// Checks whether the agent is able to handle [`ClientMessage::CancelRequest`].
+    fn cancel_supported(&self) -> bool {
+        self.capabilities.contains(Capabilities::CANCEL_REQUEST)
+    }
+
+    /// Prepares the layer's `req` to be sent to the agent, with the agent descriptors, and
+    /// streamed when possible (see [`file_streams`]), or limited in size otherwise.
+    fn request_to_agent(&self, req: FileRequest) -> FileRequest {
+        let req = self.files.request_to_agent(req);
+        if self.file_stream_supported() {
+            file_streams::to_stream(req)
+        } else {
+            file_streams::limit_unstreamed(req)
+        }
+    }
+}
+
+impl SimpleProxy {
+    /// Sends again all requests that are still waiting for a response, since the agent
+    /// connection they were sent through is gone.
+    ///
+    /// The [`FileRequest`]s are sent after [`RemoteFiles`] opens the files again, see
+    /// [`Self::resend_file_reqs`]. The ones that may have changed the remote files already fail
+    /// instead, see [`Self::fail_not_idempotent`].
+    async fn resend_pending(&mut self, message_bus: &mut MessageBus<Self>) {
+        for failed in self.fail_not_idempotent() {
+            message_bus.send(failed).await;
+        }
+
+        let reopen = self.files.agent_reconnected();
+        if reopen.is_empty() {
+            self.resend_file_reqs(message_bus).await;
+        }
+        for req in reopen {
+            message_bus
+                .send(ProxyMessage::ToAgent(ClientMessage::FileRequest(req)))
+                .await;
+        }
+
+        let addr_info_reqs = self
+            .addr_info_reqs
+            .pending()
+            .cloned()
+            .map(|req| self.addr_info_request_to_agent(req));
+        let get_env_reqs = self
+            .get_env_reqs
+            .pending()
+            .cloned()
+            .map(ClientMessage::GetEnvVarsRequest);
+
+        for msg in addr_info_reqs.chain(get_env_reqs) {
+            message_bus.send(ProxyMessage::ToAgent(msg)).await;
+        }
+    }
+
+    /// Removes the pending [`FileRequest`]s that can't be sent again (see [`is_idempotent`]), and
+    /// returns the error responses for the layers.
+    fn fail_not_idempotent(&mut self) -> Vec<ToLayer> {
+        self.file_reqs
+            .remove_if(|req| !is_idempotent(req))
+            .into_iter()
+            .filter_map(|(message_id, layer_id, req)| {
+                Some(ToLayer {
+                    message_id,
+                    message: ProxyToLayerMessage::File(lost_response(&req)?),
                     layer_id,
-                    FileRequest::Close(CloseFileRequest { fd }),
-                ) => {
-                    let do_close = self.remote_fds.remove(layer_id, RemoteFd::File(fd));
-                    if do_close {
-                        message_bus
-                            .send(ClientMessage::FileRequest(FileRequest::Close(
-                                CloseFileRequest { fd },
-                            )))
-                            .await;
+                })
+            })
+            .collect()
+    }
+
+    /// Sends all [`FileRequest`]s that are still waiting for a response, held back while
+    /// [`RemoteFiles`] was opening the files again.
+    async fn resend_file_reqs(&mut self, message_bus: &mut MessageBus<Self>) {
+        let file_reqs = self
+            .file_reqs
+            .pending_with_ids()
+            .map(|(message_id, layer_id, req)| {
+                (message_id, layer_id, self.request_to_agent(req.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        for (message_id, layer_id, req) in file_reqs {
+            self.file_streams.request_sent(layer_id, message_id, &req);
+            message_bus
+                .send(ProxyMessage::ToAgent(ClientMessage::FileRequest(req)))
+                .await;
+        }
+    }
+
+    /// Tells the agent to abandon a request, when it's able to.
+    async fn cancel_in_agent(&self, cancel: CancelRequest, message_bus: &mut MessageBus<Self>) {
+        if self.cancel_supported() {
+            message_bus
+                .send(ProxyMessage::ToAgent(ClientMessage::CancelRequest(cancel)))
+                .await;
+        }
+    }
+
+    /// How many remote files are open, or being opened, in the session.
+    fn open_files(&self) -> usize {
+        let opening = self
+            .file_reqs
+            .pending()
+            .filter(|req| matches!(req, FileRequest::Open(..) | FileRequest::OpenRelative(..)))
+            .count();
+
+        self.files.open_files() + opening
+    }
+
+    /// Closes the file `fd` of the layer `layer_id`, in the agent too when no other layer has it.
+    async fn close_file(&mut self, layer_id: LayerId, fd: u64, message_bus: &mut MessageBus<Self>) {
+        let do_close = self.remote_fds.remove(layer_id, RemoteFd::File(fd));
+        if let Some(fd) = do_close.then(|| self.files.close_file(fd)).flatten() {
+            message_bus
+                .send(ClientMessage::FileRequest(FileRequest::Close(
+                    CloseFileRequest { fd },
+                )))
+                .await;
+        }
+    }
+
+    /// Passes the response to the oldest [`FileRequest`] to the layer that sent it.
+    ///
+    /// The response to an open of a file for reading is held back until its size is checked, see
+    /// [`FileLimits`].
+    async fn file_response(
+        &mut self,
+        res: FileResponse,
+        message_bus: &mut MessageBus<Self>,
+    ) -> Result<(), RequestQueueEmpty> {
+        let (message_id, layer_id, req) = self.file_reqs.get_request()?;
+        self.file_streams.responded(layer_id, message_id);
+        let res = self.files.response_to_layer(&req, res);
+        self.limits.record(&res);
+
+        let res = match self.limits.sized(message_id, layer_id, &res) {
+            Some((fd, None)) => FileResponse::Open(Ok(OpenFileResponse { fd })),
+            Some((fd, Some(error))) => {
+                self.close_file(layer_id, fd, message_bus).await;
+                FileResponse::Open(Err(error))
+            }
+            None => {
+                match &res {
+                    FileResponse::Open(Ok(OpenFileResponse { fd })) => {
+                        self.remote_fds.add(layer_id, RemoteFd::File(*fd))
                     }
-                }
-                SimpleProxyMessage::FileReq(
-                    _,
-                    layer_id,
-                    FileRequest::CloseDir(CloseDirRequest { remote_fd }),
-                ) => {
-                    let do_close = self.remote_fds.remove(layer_id, RemoteFd::Dir(remote_fd));
-                    if do_close {
-                        message_bus
-                            .send(ClientMessage::FileRequest(FileRequest::CloseDir(
-                                CloseDirRequest { remote_fd },
-                            )))
-                            .await;
+                    FileResponse::OpenDir(Ok(OpenDirResponse { fd })) => {
+                        self.remote_fds.add(layer_id, RemoteFd::Dir(*fd))
                     }
+                    _ => {}
                 }
-                SimpleProxyMessage::FileReq(message_id, session_id, req) => {
-                    self.file_reqs.insert(message_id, session_id);
+
+                if let Some(stat) = self.limits.size_request(message_id, layer_id, &req, &res) {
+                    self.file_reqs
+                        .insert_request(message_id, layer_id, stat.clone());
                     message_bus
-                        .send(ProxyMessage::ToAgent(ClientMessage::FileRequest(req)))
+                        .send(ProxyMessage::ToAgent(ClientMessage::FileRequest(
+                            self.request_to_agent(stat),
+                        )))
                         .await;
+
+                    return Ok(());
                 }
-                SimpleProxyMessage::FileRes(FileResponse::Open(Ok(OpenFileResponse { fd }))) => {
-                    let (message_id, layer_id) = self.file_reqs.get()?;
 
-                    self.remote_fds.add(layer_id, RemoteFd::File(fd));
+                res
+            }
+        };
 
-                    message_bus
-                        .send(ToLayer {
-                            message_id,
-                            message: ProxyToLayerMessage::File(FileResponse::Open(Ok(
-                                OpenFileResponse { fd },
-                            ))),
-                            layer_id,
-                        })
-                        .await;
-                }
-                SimpleProxyMessage::FileRes(FileResponse::OpenDir(Ok(OpenDirResponse { fd }))) => {
-                    let (message_id, layer_id) = self.file_reqs.get()?;
+        message_bus
+            .send(ToLayer {
+                message_id,
+                message: ProxyToLayerMessage::File(res),
+                layer_id,
+            })
+            .await;
+
+        Ok(())
+    }
 
-                    self.remote_fds.add(layer_id, RemoteFd::Dir(fd));
+    /// Responds with [`ProxyToLayerMessage::Interrupted`] to all requests that are still waiting
+    /// for a response, since the agent connection they were sent through is gone.
+    async fn interrupt_pending(&mut self, message_bus: &mut MessageBus<Self>) {
+        self.limits.reset();
+
+        let pending = self
+            .file_reqs
+            .drain()
+            .chain(self.addr_info_reqs.drain())
+            .chain(self.get_env_reqs.drain())
+            .collect::<Vec<_>>();
+
+        for (message_id, layer_id) in pending {
+            message_bus
+                .send(ToLayer {
+                    message_id,
+                    message: ProxyToLayerMessage::Interrupted,
+                    layer_id,
+                })
+                .await;
+        }
+    }
+}
 
+impl SimpleProxy {
+    /// Handles a message from the layers or from the agent.
+    async fn handle_message(
+        &mut self,
+        msg: SimpleProxyMessage,
+        message_bus: &mut MessageBus<Self>,
+    ) -> Result<(), RequestQueueEmpty> {
+        match msg {
+            SimpleProxyMessage::FileReq(
+                _,
+                layer_id,
+                FileRequest::Close(CloseFileRequest { fd }),
+            ) => {
+                self.close_file(layer_id, fd, message_bus).await;
+            }
+            SimpleProxyMessage::FileReq(
+                _,
+                layer_id,
+                FileRequest::CloseDir(CloseDirRequest { remote_fd }),
+            ) => {
+                let do_close = self.remote_fds.remove(layer_id, RemoteFd::Dir(remote_fd));
+                if let Some(remote_fd) = do_close.then(|| self.files.close_dir(remote_fd)).flatten()
+                {
                     message_bus
-                        .send(ToLayer {
-                            message_id,
-                            message: ProxyToLayerMessage::File(FileResponse::OpenDir(Ok(
-                                OpenDirResponse { fd },
-                            ))),
-                            layer_id,
-                        })
+                        .send(ClientMessage::FileRequest(FileRequest::CloseDir(
+                            CloseDirRequest { remote_fd },
+                        )))
                         .await;
                 }
-                SimpleProxyMessage::FileRes(res) => {
-                    let (message_id, layer_id) = self.file_reqs.get()?;
+            }
+            SimpleProxyMessage::FileReq(message_id, layer_id, FileRequest::ReadDirBatch(..))
+                if !self.readdir_batch_supported() =>
+            {
+                // The layer falls back to reading the directory entry by entry.
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::File(FileResponse::ReadDirBatch(Err(
+                            ResponseError::NotImplemented,
+                        ))),
+                        layer_id,
+                    })
+                    .await;
+            }
+            SimpleProxyMessage::FileReq(message_id, layer_id, FileRequest::Checksum(..))
+                if !self.checksum_supported() =>
+            {
+                // The layer skips verifying the transfer.
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::File(FileResponse::Checksum(Err(
+                            ResponseError::NotImplemented,
+                        ))),
+                        layer_id,
+                    })
+                    .await;
+            }
+            SimpleProxyMessage::FileReq(message_id, layer_id, FileRequest::ReadChecksummed(..))
+                if !self.read_checksum_supported() =>
+            {
+                // The layer reads without verifying.
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::File(FileResponse::ReadChecksummed(Err(
+                            ResponseError::NotImplemented,
+                        ))),
+                        layer_id,
+                    })
+                    .await;
+            }
+            SimpleProxyMessage::FileReq(message_id, layer_id, FileRequest::ScratchDir(..))
+                if !self.scratch_dir_supported() =>
+            {
+                // The layer keeps the temporary files local.
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::File(FileResponse::ScratchDir(Err(
+                            ResponseError::NotImplemented,
+                        ))),
+                        layer_id,
+                    })
+                    .await;
+            }
+            SimpleProxyMessage::FileReq(message_id, layer_id, FileRequest::Batch(..))
+                if !self.file_batch_supported() =>
+            {
+                // The layer sends the requests one by one.
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::File(FileResponse::Batch(Err(
+                            ResponseError::NotImplemented,
+                        ))),
+                        layer_id,
+                    })
+                    .await;
+            }
+            SimpleProxyMessage::FileReq(message_id, session_id, req) => {
+                if let Some(res) = self
+                    .limits
+                    .check(&req, self.open_files())
+                    .and_then(|error| error_response(&req, error))
+                {
                     message_bus
                         .send(ToLayer {
                             message_id,
                             message: ProxyToLayerMessage::File(res),
-                            layer_id,
+                            layer_id: session_id,
                         })
                         .await;
+
+                    return Ok(());
                 }
-                SimpleProxyMessage::AddrInfoReq(message_id, session_id, req) => {
-                    self.addr_info_reqs.insert(message_id, session_id);
+
+                self.file_reqs
+                    .insert_request(message_id, session_id, req.clone());
+                // Otherwise, it's sent when the files are open again.
+                if !self.files.is_reopening() {
+                    let req = self.request_to_agent(req);
+                    self.file_streams.request_sent(session_id, message_id, &req);
                     message_bus
-                        .send(ProxyMessage::ToAgent(ClientMessage::GetAddrInfoRequest(
-                            req,
-                        )))
+                        .send(ProxyMessage::ToAgent(ClientMessage::FileRequest(req)))
                         .await;
                 }
-                SimpleProxyMessage::AddrInfoRes(res) => {
-                    let (message_id, layer_id) = self.addr_info_reqs.get()?;
+            }
+            SimpleProxyMessage::FileRes(res) if self.files.is_reopening() => {
+                if let Some(req) = self.files.reopen_response(res) {
                     message_bus
-                        .send(ToLayer {
-                            message_id,
-                            message: ProxyToLayerMessage::GetAddrInfo(res),
-                            layer_id,
-                        })
+                        .send(ProxyMessage::ToAgent(ClientMessage::FileRequest(req)))
                         .await;
                 }
-                SimpleProxyMessage::LayerClosed(LayerClosed { id }) => {
-                    for to_close in self.remote_fds.remove_all(id) {
-                        let req = match to_close {
-                            RemoteFd::Dir(remote_fd) => {
-                                FileRequest::CloseDir(CloseDirRequest { remote_fd })
-                            }
-                            RemoteFd::File(fd) => FileRequest::Close(CloseFileRequest { fd }),
-                        };
-
-                        message_bus.send(ClientMessage::FileRequest(req)).await;
-                    }
+                if !self.files.is_reopening() {
+                    self.resend_file_reqs(message_bus).await;
                 }
-                SimpleProxyMessage::LayerForked(LayerForked { child, parent }) => {
-                    self.remote_fds.clone_all(parent, child);
+            }
+            SimpleProxyMessage::FileRes(res) => {
+                if let Some(res) = self
+                    .file_streams
+                    .response(self.file_reqs.pending().next(), res)
+                {
+                    self.file_response(res, message_bus).await?;
                 }
-                SimpleProxyMessage::GetEnvReq(message_id, layer_id, req) => {
-                    self.get_env_reqs.insert(message_id, layer_id);
-                    message_bus
-                        .send(ProxyMessage::ToAgent(ClientMessage::GetEnvVarsRequest(req)))
-                        .await;
+            }
+            SimpleProxyMessage::AddrInfoReq(message_id, session_id, req) => {
+                self.addr_info_reqs
+                    .insert_request(message_id, session_id, req.clone());
+                message_bus
+                    .send(ProxyMessage::ToAgent(self.addr_info_request_to_agent(req)))
+                    .await;
+            }
+            SimpleProxyMessage::AddrInfoRes(res) => {
+                let (message_id, layer_id) = self.addr_info_reqs.get()?;
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::GetAddrInfo(res),
+                        layer_id,
+                    })
+                    .await;
+            }
+            SimpleProxyMessage::Cancel(message_id, layer_id) => {
+                if let Some(cancel) = self.file_streams.cancel(layer_id, message_id) {
+                    self.cancel_in_agent(cancel, message_bus).await;
                 }
-                SimpleProxyMessage::GetEnvRes(res) => {
-                    let (message_id, layer_id) = self.get_env_reqs.get()?;
-                    message_bus
-                        .send(ToLayer {
-                            message_id,
-                            message: ProxyToLayerMessage::GetEnv(res),
-                            layer_id,
-                        })
-                        .await
+            }
+            SimpleProxyMessage::LayerClosed(LayerClosed { id }) => {
+                for cancel in self.file_streams.cancel_layer(id) {
+                    self.cancel_in_agent(cancel, message_bus).await;
+                }
+
+                for to_close in self.remote_fds.remove_all(id) {
+                    let req = match to_close {
+                        RemoteFd::Dir(remote_fd) => {
+                            let Some(remote_fd) = self.files.close_dir(remote_fd) else {
+                                continue;
+                            };
+                            FileRequest::CloseDir(CloseDirRequest { remote_fd })
+                        }
+                        RemoteFd::File(fd) => {
+                            let Some(fd) = self.files.close_file(fd) else {
+                                continue;
+                            };
+                            FileRequest::Close(CloseFileRequest { fd })
+                        }
+                    };
+
+                    message_bus.send(ClientMessage::FileRequest(req)).await;
                 }
             }
+            SimpleProxyMessage::LayerForked(LayerForked { child, parent }) => {
+                self.remote_fds.clone_all(parent, child);
+            }
+            SimpleProxyMessage::GetEnvReq(message_id, layer_id, req) => {
+                self.get_env_reqs
+                    .insert_request(message_id, layer_id, req.clone());
+                message_bus
+                    .send(ProxyMessage::ToAgent(ClientMessage::GetEnvVarsRequest(req)))
+                    .await;
+            }
+            SimpleProxyMessage::GetEnvRes(res) => {
+                let (message_id, layer_id) = self.get_env_reqs.get()?;
+                message_bus
+                    .send(ToLayer {
+                        message_id,
+                        message: ProxyToLayerMessage::GetEnv(res),
+                        layer_id,
+                    })
+                    .await
+            }
+            SimpleProxyMessage::Capabilities(capabilities) => {
+                self.capabilities = capabilities;
+            }
+            SimpleProxyMessage::AgentReconnected => {
+                self.file_streams.reset();
+                self.resend_pending(message_bus).await;
+            }
+            SimpleProxyMessage::AgentConnectionReset => {
+                self.file_streams.reset();
+                self.interrupt_pending(message_bus).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BackgroundTask for SimpleProxy {
+    type Error = RequestQueueEmpty;
+    type MessageIn = SimpleProxyMessage;
+    type MessageOut = ProxyMessage;
+
+    async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), RequestQueueEmpty> {
+        loop {
+            let stream_deadline = self.file_streams.deadline();
+
+            tokio::select! {
+                msg = message_bus.recv() => match msg {
+                    Some(msg) => self.handle_message(msg, message_bus).await?,
+                    None => break,
+                },
+
+                _ = time::sleep_until(stream_deadline.unwrap_or_else(Instant::now)), if stream_deadline.is_some() => {
+                    if let Some(res) = self.file_streams.timed_out() {
+                        // The agent stops the stream, and moves the file back to where it was.
+                        let oldest = self.file_reqs.pending_with_ids().next();
+                        if let Some(cancel) = oldest.and_then(|(message_id, layer_id, _)| {
+                            self.file_streams.cancel(layer_id, message_id)
+                        }) {
+                            self.cancel_in_agent(cancel, message_bus).await;
+                        }
+
+                        self.file_response(res, message_bus).await?;
+                    }
+                },
+            }
         }
 
         tracing::trace!("message bus closed, exiting");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::file::{OpenOptionsInternal, ReadFileRequest, WriteFileRequest};
+
+    use super::*;
+
+    #[test]
+    fn fails_not_idempotent_requests() {
+        let mut proxy = SimpleProxy::default();
+        let layer_id = LayerId(0);
+        let open = |create| {
+            FileRequest::Open(OpenFileRequest {
+                path: "/app/data".into(),
+                open_options: OpenOptionsInternal {
+                    read: true,
+                    write: true,
+                    create,
+                    ..Default::default()
+                },
+            })
+        };
+        let read = FileRequest::Read(ReadFileRequest {
+            remote_fd: 1,
+            buffer_size: 1024,
+        });
+        let write = FileRequest::Write(WriteFileRequest {
+            fd: 1,
+            write_bytes: b"hello".to_vec(),
+        });
+
+        proxy.file_reqs.insert_request(0, layer_id, open(false));
+        proxy.file_reqs.insert_request(1, layer_id, write.clone());
+        proxy.file_reqs.insert_request(2, layer_id, read.clone());
+        proxy.file_reqs.insert_request(3, layer_id, open(true));
+        proxy.file_reqs.insert_request(
+            4,
+            layer_id,
+            FileRequest::Batch(BatchFileRequest {
+                requests: vec![read.clone(), write],
+            }),
+        );
+
+        let failed = proxy.fail_not_idempotent();
+        assert_eq!(
+            failed
+                .iter()
+                .map(|failed| failed.message_id)
+                .collect::<Vec<_>>(),
+            [1, 3, 4]
+        );
+        assert!(matches!(
+            failed.first().map(|failed| &failed.message),
+            Some(ProxyToLayerMessage::File(FileResponse::Write(Err(..))))
+        ));
+        assert!(matches!(
+            failed.get(1).map(|failed| &failed.message),
+            Some(ProxyToLayerMessage::File(FileResponse::Open(Err(..))))
+        ));
+
+        assert_eq!(
+            proxy.file_reqs.pending().cloned().collect::<Vec<_>>(),
+            [open(false), read]
+        );
+    }
+}
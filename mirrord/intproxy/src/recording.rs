@@ -0,0 +1,270 @@
+//! Recording of the stolen HTTP requests and the responses of the local process, for
+//! `mirrord exec --record` and `mirrord replay`.
+//!
+//! Only requests stolen with an HTTP filter reach us as requests; the other stolen connections are
+//! plain TCP streams and are not recorded. Every exchange is a line of JSON, written when the
+//! response is sent to the agent.
+//!
+//! Secrets in the paths, headers and text bodies are redacted (see [`secrets`]), so the replayed
+//! requests carry the redacted values. What the scanner doesn't recognize is recorded as is, so the
+//! record file is only readable by the user.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{self, LineWriter, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use base64::Engine;
+use hyper::{HeaderMap, Version};
+use mirrord_protocol::{
+    tcp::{DaemonTcp, HttpRequest, HttpResponse, LayerTcpSteal, TcpClose},
+    ClientMessage, ConnectionId, DaemonMessage, Port, RequestId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::secrets;
+
+/// Path of the record file, set by `mirrord exec --record`.
+pub const RECORD_FILE_ENV: &str = "MIRRORD_RECORD_FILE";
+
+/// Body of a recorded message, as text, or as base64 when it's not UTF-8.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedBody {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+impl RecordedBody {
+    fn new(bytes: Vec<u8>) -> Self {
+        let bytes = match secrets::redact_bytes(&bytes) {
+            Cow::Borrowed(..) => bytes,
+            Cow::Owned(redacted) => redacted,
+        };
+
+        match String::from_utf8(bytes) {
+            Ok(body) if body.is_empty() => Self::default(),
+            Ok(body) => Self {
+                body: Some(body),
+                body_base64: None,
+            },
+            Err(fail) => Self {
+                body: None,
+                body_base64: Some(
+                    base64::engine::general_purpose::STANDARD.encode(fail.as_bytes()),
+                ),
+            },
+        }
+    }
+
+    /// The recorded bytes.
+    pub fn bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        match (&self.body, &self.body_base64) {
+            (_, Some(body)) => base64::engine::general_purpose::STANDARD.decode(body),
+            (Some(body), None) => Ok(body.clone().into_bytes()),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path and query of the request.
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(flatten)]
+    pub body: RecordedBody,
+}
+
+/// A line of the record file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedExchange {
+    /// Port the request was stolen from.
+    pub port: Port,
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+fn headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            (
+                name.to_string(),
+                secrets::redact_header(name.as_str(), &value),
+            )
+        })
+        .collect()
+}
+
+fn version(version: Version) -> String {
+    format!("{version:?}")
+}
+
+fn recorded_request<B>(request: &HttpRequest<B>, body: Vec<u8>) -> RecordedRequest
+where
+    for<'de> B: Serialize + Deserialize<'de>,
+{
+    let request = &request.internal_request;
+
+    RecordedRequest {
+        method: request.method.to_string(),
+        path: request
+            .uri
+            .path_and_query()
+            .map(|path| secrets::redact_all(path.as_str()))
+            .unwrap_or_else(|| "/".to_string()),
+        version: version(request.version),
+        headers: headers(&request.headers),
+        body: RecordedBody::new(body),
+    }
+}
+
+fn recorded_response<B>(response: &HttpResponse<B>, body: Vec<u8>) -> RecordedResponse
+where
+    for<'de> B: Serialize + Deserialize<'de>,
+{
+    let response = &response.internal_response;
+
+    RecordedResponse {
+        status: response.status().as_u16(),
+        version: version(response.version()),
+        headers: headers(response.headers()),
+        body: RecordedBody::new(body),
+    }
+}
+
+/// Writes the [`RecordedExchange`]s of the session to the record file.
+pub(crate) struct Recorder {
+    file: LineWriter<File>,
+    /// Requests waiting for their responses.
+    pending: HashMap<(ConnectionId, RequestId), (Port, RecordedRequest)>,
+}
+
+impl Recorder {
+    /// Appends to the file at `path`, created by `mirrord exec`. Only we can read the file.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+
+        Ok(Self {
+            file: LineWriter::new(file),
+            pending: Default::default(),
+        })
+    }
+
+    /// Records a stolen request.
+    pub(crate) fn daemon_message(&mut self, message: &DaemonMessage) {
+        let (key, port, request) = match message {
+            DaemonMessage::TcpSteal(DaemonTcp::HttpRequest(request)) => (
+                (request.connection_id, request.request_id),
+                request.port,
+                recorded_request(request, request.internal_request.body.clone()),
+            ),
+            DaemonMessage::TcpSteal(DaemonTcp::HttpRequestFramed(request)) => (
+                (request.connection_id, request.request_id),
+                request.port,
+                recorded_request(request, request.internal_request.body.data()),
+            ),
+            DaemonMessage::TcpSteal(DaemonTcp::Close(TcpClose { connection_id })) => {
+                self.connection_closed(*connection_id);
+                return;
+            }
+            _ => return,
+        };
+
+        self.pending.insert(key, (port, request));
+    }
+
+    /// Records the response to a stolen request.
+    pub(crate) fn client_message(&mut self, message: &ClientMessage) {
+        let (key, response) = match message {
+            ClientMessage::TcpSteal(LayerTcpSteal::HttpResponse(response)) => (
+                (response.connection_id, response.request_id),
+                recorded_response(response, response.internal_response.body().clone()),
+            ),
+            ClientMessage::TcpSteal(LayerTcpSteal::HttpResponseFramed(response)) => (
+                (response.connection_id, response.request_id),
+                recorded_response(response, response.internal_response.body().data()),
+            ),
+            ClientMessage::TcpSteal(LayerTcpSteal::ConnectionUnsubscribe(connection_id)) => {
+                self.connection_closed(*connection_id);
+                return;
+            }
+            _ => return,
+        };
+
+        let Some((port, request)) = self.pending.remove(&key) else {
+            return;
+        };
+        let exchange = RecordedExchange {
+            port,
+            request,
+            response,
+        };
+
+        let written = serde_json::to_vec(&exchange)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file.write_all(&line)
+            });
+        if let Err(error) = written {
+            tracing::warn!(%error, "failed to record a stolen request");
+        }
+    }
+
+    fn connection_closed(&mut self, connection_id: ConnectionId) {
+        self.pending.retain(|(id, _), _| *id != connection_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn record_file_is_private() {
+        let path =
+            std::env::temp_dir().join(format!("mirrord-record-{}.jsonl", std::process::id()));
+        Recorder::open(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn binary_body_is_base64() {
+        let body = RecordedBody::new(vec![0xff, 0x00, 0x01]);
+
+        assert_eq!(body.body, None);
+        assert_eq!(body.bytes().unwrap(), [0xff, 0x00, 0x01]);
+        assert_eq!(
+            RecordedBody::new(b"{}".to_vec()).body.as_deref(),
+            Some("{}")
+        );
+    }
+}
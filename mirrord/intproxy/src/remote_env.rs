@@ -0,0 +1,163 @@
+//! The remote environment, as the binary gets it with `mirrord exec`, for the tools that can't run
+//! with the layer. Used by `mirrord env`, which writes it to a file.
+//!
+//! The agent reads the environment of the target process, so the variables from `envFrom` are
+//! expanded, and the `feature.env` includes, excludes and overrides apply.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use mirrord_config::LayerConfig;
+use mirrord_protocol::{ClientMessage, DaemonMessage, EnvVars, GetEnvVarsRequest, LogLevel};
+use thiserror::Error;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// Errors that can occur when fetching the remote environment.
+#[derive(Error, Debug)]
+pub enum RemoteEnvError {
+    /// Both `feature.env.include` and `feature.env.exclude` are set.
+    #[error("both `feature.env.include` ({0}) and `feature.env.exclude` ({1}) are set")]
+    IncludeAndExclude(String, String),
+    /// The agent did not respond in `agent.communication_timeout`.
+    #[error("timeout waiting for remote environment variables")]
+    Timeout,
+    /// The connection with the agent is gone.
+    #[error("agent connection unexpectedly closed")]
+    ConnectionClosed,
+    /// The agent closed the connection, with its message.
+    #[error("agent closed the connection with message: `{0}`")]
+    AgentClosed(String),
+    /// The agent sent something else than the environment.
+    #[error("unexpected message from the agent: {0}")]
+    UnexpectedMessage(String),
+}
+
+/// Fetches the remote environment through the agent connection, with the `feature.env` of the
+/// `config` applied.
+pub async fn fetch_remote_env(
+    config: &LayerConfig,
+    agent_tx: &Sender<ClientMessage>,
+    agent_rx: &mut Receiver<DaemonMessage>,
+) -> Result<HashMap<String, String>, RemoteEnvError> {
+    let mut env_vars = HashMap::new();
+
+    let (env_vars_exclude, env_vars_include) = match (
+        config
+            .feature
+            .env
+            .exclude
+            .clone()
+            .map(|exclude| exclude.join(";")),
+        config
+            .feature
+            .env
+            .include
+            .clone()
+            .map(|include| include.join(";")),
+    ) {
+        (Some(exclude), Some(include)) => {
+            return Err(RemoteEnvError::IncludeAndExclude(include, exclude))
+        }
+        (Some(exclude), None) => (HashSet::from(EnvVars(exclude)), HashSet::new()),
+        (None, Some(include)) => (HashSet::new(), HashSet::from(EnvVars(include))),
+        (None, None) => (HashSet::new(), HashSet::from(EnvVars("*".to_owned()))),
+    };
+
+    let communication_timeout =
+        Duration::from_secs(config.agent.communication_timeout.unwrap_or(30).into());
+
+    if !env_vars_exclude.is_empty() || !env_vars_include.is_empty() {
+        let remote_env = tokio::time::timeout(
+            communication_timeout,
+            get_remote_env(agent_tx, agent_rx, env_vars_exclude, env_vars_include),
+        )
+        .await
+        .map_err(|_| RemoteEnvError::Timeout)??;
+        env_vars.extend(remote_env);
+        if let Some(overrides) = &config.feature.env.r#override {
+            env_vars.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+
+    Ok(env_vars)
+}
+
+async fn get_remote_env(
+    agent_tx: &Sender<ClientMessage>,
+    agent_rx: &mut Receiver<DaemonMessage>,
+    env_vars_filter: HashSet<String>,
+    env_vars_select: HashSet<String>,
+) -> Result<HashMap<String, String>, RemoteEnvError> {
+    agent_tx
+        .send(ClientMessage::GetEnvVarsRequest(GetEnvVarsRequest {
+            env_vars_filter,
+            env_vars_select,
+        }))
+        .await
+        .map_err(|_| RemoteEnvError::ConnectionClosed)?;
+
+    loop {
+        match agent_rx.recv().await {
+            Some(DaemonMessage::GetEnvVarsResponse(Ok(remote_env))) => {
+                tracing::trace!("DaemonMessage::GetEnvVarsResponse {:#?}!", remote_env.len());
+                break Ok(remote_env);
+            }
+            Some(DaemonMessage::LogMessage(msg)) => match msg.level {
+                LogLevel::Error => tracing::error!("Agent log: {}", msg.message),
+                LogLevel::Warn => tracing::warn!("Agent log: {}", msg.message),
+            },
+            Some(DaemonMessage::Close(msg)) => break Err(RemoteEnvError::AgentClosed(msg)),
+            Some(msg) => break Err(RemoteEnvError::UnexpectedMessage(format!("{msg:#?}"))),
+            None => break Err(RemoteEnvError::ConnectionClosed),
+        }
+    }
+}
+
+/// The `env` as `KEY="value"` lines, sorted by key.
+///
+/// The values are escaped for the dotenv parsers that expand variables in double quotes (e.g.
+/// docker compose), so a `$` in a value stays a `$`.
+pub fn to_dotenv(env: &HashMap<String, String>) -> String {
+    env.iter()
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "\\$")
+                .replace('\n', "\\n");
+            format!("{key}=\"{value}\"\n")
+        })
+        .collect()
+}
+
+/// The `env` as a JSON object, sorted by key.
+pub fn to_json(env: HashMap<String, String>) -> Result<String, serde_json::Error> {
+    let env = env.into_iter().collect::<BTreeMap<_, _>>();
+    Ok(format!("{}\n", serde_json::to_string_pretty(&env)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_dotenv_values() {
+        let env = HashMap::from([
+            ("PEM".to_string(), "line 1\nline 2".to_string()),
+            (
+                "DB_URL".to_string(),
+                r#"postgres://"user"@db\main"#.to_string(),
+            ),
+            ("PASSWORD".to_string(), "pa$$word${HOME}".to_string()),
+        ]);
+
+        assert_eq!(
+            to_dotenv(&env),
+            "DB_URL=\"postgres://\\\"user\\\"@db\\\\main\"\nPASSWORD=\"pa\\$\\$word\\${HOME}\"\nPEM=\"line 1\\nline 2\"\n"
+        );
+    }
+}
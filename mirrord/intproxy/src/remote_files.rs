@@ -0,0 +1,504 @@
+//! For keeping the remote files opened by the layers across reconnections to the agent.
+//!
+//! The descriptors the agent gives to the files and directories it opens for us are gone when we
+//! connect to the agent again (or to a new one). So the layers get descriptors from us instead,
+//! which we map to the descriptors of the agent in every [`FileRequest`] and [`FileResponse`].
+//!
+//! After a reconnection, the files are opened again (without truncating or creating them) and
+//! seeked to where the layers left them, so the layers can keep using the same descriptors.
+//! Directories are not opened again, requests using them fail.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+};
+
+use mirrord_protocol::{
+    file::{
+        BatchFileRequest, BatchFileResponse, ChecksumFileRequest, CloseDirRequest,
+        CloseFileRequest, FdOpenDirRequest, GetDEnts64Request, GetDEnts64StreamRequest,
+        OpenDirResponse, OpenFileRequest, OpenFileResponse, OpenOptionsInternal,
+        OpenRelativeFileRequest, ReadChecksummedFileRequest, ReadDirBatchRequest, ReadDirRequest,
+        ReadFileRequest, ReadFileStreamRequest, ReadLimitedFileRequest, SeekFileRequest,
+        SeekFileResponse, SeekFromInternal, WriteFileRequest, WriteLimitedFileRequest,
+        XstatFsRequest, XstatRequest,
+    },
+    FileRequest, FileResponse,
+};
+
+/// Agent descriptor used in requests for files and directories that are gone, so that the agent
+/// fails them.
+const LOST_FD: u64 = u64::MAX;
+
+/// A file opened by the layers.
+struct RemoteFile {
+    /// Descriptor in the agent, `None` when the file was lost in a reconnection.
+    agent_fd: Option<u64>,
+    /// Path in the target, `None` when the file can't be opened again (e.g. it was opened relative
+    /// to a descriptor we don't know the path of).
+    path: Option<PathBuf>,
+    open_options: OpenOptionsInternal,
+    /// Where the layers left the file, to seek to it after opening it again.
+    position: u64,
+}
+
+/// What the next [`FileResponse`] answers, while the files are being opened again.
+enum Reopen {
+    /// Opening of the file with this layer descriptor.
+    Open(u64),
+    /// Seeking of a file that was opened again.
+    Seek,
+}
+
+/// Maps the descriptors of the layers to the descriptors of the agent, and opens the files again
+/// after a reconnection. See the [module docs](self).
+#[derive(Default)]
+pub struct RemoteFiles {
+    /// Last descriptor given to the layers.
+    last_fd: u64,
+    /// Files, by the layer descriptor.
+    files: HashMap<u64, RemoteFile>,
+    /// Descriptors of directories in the agent, by the layer descriptor. `None` when the directory
+    /// was lost in a reconnection.
+    dirs: HashMap<u64, Option<u64>>,
+    /// Responses to our requests that open the files again, in the order they'll come.
+    reopening: VecDeque<Reopen>,
+}
+
+impl RemoteFiles {
+    fn next_fd(&mut self) -> u64 {
+        self.last_fd += 1;
+        self.last_fd
+    }
+
+    fn file_fd(&self, fd: u64) -> u64 {
+        self.files
+            .get(&fd)
+            .and_then(|file| file.agent_fd)
+            .unwrap_or(LOST_FD)
+    }
+
+    fn dir_fd(&self, fd: u64) -> u64 {
+        self.dirs.get(&fd).copied().flatten().unwrap_or(LOST_FD)
+    }
+
+    /// Whether the files are still being opened again after a reconnection. File requests of the
+    /// layers must wait until they are.
+    pub fn is_reopening(&self) -> bool {
+        !self.reopening.is_empty()
+    }
+
+    /// How many files the layers have open.
+    pub fn open_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Replaces the layer descriptors in the `request` with the agent descriptors.
+    pub fn request_to_agent(&self, request: FileRequest) -> FileRequest {
+        match request {
+            FileRequest::OpenRelative(OpenRelativeFileRequest {
+                relative_fd,
+                path,
+                open_options,
+            }) => FileRequest::OpenRelative(OpenRelativeFileRequest {
+                relative_fd: self.file_fd(relative_fd),
+                path,
+                open_options,
+            }),
+            FileRequest::Read(ReadFileRequest {
+                remote_fd,
+                buffer_size,
+            }) => FileRequest::Read(ReadFileRequest {
+                remote_fd: self.file_fd(remote_fd),
+                buffer_size,
+            }),
+            FileRequest::ReadLimited(ReadLimitedFileRequest {
+                remote_fd,
+                buffer_size,
+                start_from,
+            }) => FileRequest::ReadLimited(ReadLimitedFileRequest {
+                remote_fd: self.file_fd(remote_fd),
+                buffer_size,
+                start_from,
+            }),
+            FileRequest::ReadChecksummed(ReadChecksummedFileRequest {
+                remote_fd,
+                buffer_size,
+                start_from,
+            }) => FileRequest::ReadChecksummed(ReadChecksummedFileRequest {
+                remote_fd: self.file_fd(remote_fd),
+                buffer_size,
+                start_from,
+            }),
+            FileRequest::Seek(SeekFileRequest { fd, seek_from }) => {
+                FileRequest::Seek(SeekFileRequest {
+                    fd: self.file_fd(fd),
+                    seek_from,
+                })
+            }
+            FileRequest::Write(WriteFileRequest { fd, write_bytes }) => {
+                FileRequest::Write(WriteFileRequest {
+                    fd: self.file_fd(fd),
+                    write_bytes,
+                })
+            }
+            FileRequest::WriteLimited(WriteLimitedFileRequest {
+                remote_fd,
+                start_from,
+                write_bytes,
+            }) => FileRequest::WriteLimited(WriteLimitedFileRequest {
+                remote_fd: self.file_fd(remote_fd),
+                start_from,
+                write_bytes,
+            }),
+            FileRequest::Close(CloseFileRequest { fd }) => FileRequest::Close(CloseFileRequest {
+                fd: self.file_fd(fd),
+            }),
+            FileRequest::Xstat(XstatRequest {
+                path,
+                fd,
+                follow_symlink,
+            }) => FileRequest::Xstat(XstatRequest {
+                path,
+                fd: fd.map(|fd| self.file_fd(fd)),
+                follow_symlink,
+            }),
+            FileRequest::XstatFs(XstatFsRequest { fd }) => FileRequest::XstatFs(XstatFsRequest {
+                fd: self.file_fd(fd),
+            }),
+            FileRequest::FdOpenDir(FdOpenDirRequest { remote_fd }) => {
+                FileRequest::FdOpenDir(FdOpenDirRequest {
+                    remote_fd: self.file_fd(remote_fd),
+                })
+            }
+            FileRequest::ReadDir(ReadDirRequest { remote_fd }) => {
+                FileRequest::ReadDir(ReadDirRequest {
+                    remote_fd: self.dir_fd(remote_fd),
+                })
+            }
+            FileRequest::CloseDir(CloseDirRequest { remote_fd }) => {
+                FileRequest::CloseDir(CloseDirRequest {
+                    remote_fd: self.dir_fd(remote_fd),
+                })
+            }
+            FileRequest::GetDEnts64(GetDEnts64Request {
+                remote_fd,
+                buffer_size,
+            }) => FileRequest::GetDEnts64(GetDEnts64Request {
+                remote_fd: self.dir_fd(remote_fd),
+                buffer_size,
+            }),
+            FileRequest::ReadDirBatch(ReadDirBatchRequest {
+                remote_fd,
+                amount,
+                name_filter,
+            }) => FileRequest::ReadDirBatch(ReadDirBatchRequest {
+                remote_fd: self.dir_fd(remote_fd),
+                amount,
+                name_filter,
+            }),
+            FileRequest::Checksum(ChecksumFileRequest {
+                remote_fd,
+                start_from,
+                length,
+            }) => FileRequest::Checksum(ChecksumFileRequest {
+                remote_fd: self.file_fd(remote_fd),
+                start_from,
+                length,
+            }),
+            FileRequest::ReadStream(ReadFileStreamRequest {
+                remote_fd,
+                buffer_size,
+                start_from,
+                chunk_size,
+            }) => FileRequest::ReadStream(ReadFileStreamRequest {
+                remote_fd: self.file_fd(remote_fd),
+                buffer_size,
+                start_from,
+                chunk_size,
+            }),
+            FileRequest::GetDEnts64Stream(GetDEnts64StreamRequest {
+                remote_fd,
+                buffer_size,
+                chunk_size,
+            }) => FileRequest::GetDEnts64Stream(GetDEnts64StreamRequest {
+                remote_fd: self.dir_fd(remote_fd),
+                buffer_size,
+                chunk_size,
+            }),
+            FileRequest::Batch(BatchFileRequest { requests }) => {
+                FileRequest::Batch(BatchFileRequest {
+                    requests: requests
+                        .into_iter()
+                        .map(|request| self.request_to_agent(request))
+                        .collect(),
+                })
+            }
+            request @ (FileRequest::Open(..)
+            | FileRequest::Access(..)
+            | FileRequest::ScratchDir(..)) => request,
+        }
+    }
+
+    /// Replaces the agent descriptors in the `response` to the layer's `request` with the layer
+    /// descriptors. Tracks the files and directories the layers open, and where they are in the
+    /// files.
+    pub fn response_to_layer(
+        &mut self,
+        request: &FileRequest,
+        response: FileResponse,
+    ) -> FileResponse {
+        match (request, response) {
+            (
+                FileRequest::Open(OpenFileRequest { path, open_options }),
+                FileResponse::Open(Ok(OpenFileResponse { fd })),
+            ) => {
+                let file = RemoteFile {
+                    agent_fd: Some(fd),
+                    path: Some(path.clone()),
+                    open_options: *open_options,
+                    position: 0,
+                };
+                self.open_file(file)
+            }
+            (
+                FileRequest::OpenRelative(OpenRelativeFileRequest {
+                    relative_fd,
+                    path,
+                    open_options,
+                }),
+                FileResponse::Open(Ok(OpenFileResponse { fd })),
+            ) => {
+                let file = RemoteFile {
+                    agent_fd: Some(fd),
+                    path: self
+                        .files
+                        .get(relative_fd)
+                        .and_then(|parent| parent.path.as_ref())
+                        .map(|parent| parent.join(path)),
+                    open_options: *open_options,
+                    position: 0,
+                };
+                self.open_file(file)
+            }
+            (_, FileResponse::OpenDir(Ok(OpenDirResponse { fd }))) => {
+                let layer_fd = self.next_fd();
+                self.dirs.insert(layer_fd, Some(fd));
+                FileResponse::OpenDir(Ok(OpenDirResponse { fd: layer_fd }))
+            }
+            (
+                FileRequest::Read(ReadFileRequest { remote_fd, .. }),
+                FileResponse::Read(Ok(read)),
+            ) => {
+                if let Some(file) = self.files.get_mut(remote_fd) {
+                    file.position += read.read_amount;
+                }
+                FileResponse::Read(Ok(read))
+            }
+            (
+                FileRequest::ReadChecksummed(ReadChecksummedFileRequest {
+                    remote_fd,
+                    start_from: None,
+                    ..
+                }),
+                FileResponse::ReadChecksummed(Ok(read)),
+            ) => {
+                if let Some(file) = self.files.get_mut(remote_fd) {
+                    file.position += read.read_amount;
+                }
+                FileResponse::ReadChecksummed(Ok(read))
+            }
+            (FileRequest::Write(WriteFileRequest { fd, .. }), FileResponse::Write(Ok(written))) => {
+                if let Some(file) = self.files.get_mut(fd) {
+                    file.position += written.written_amount;
+                }
+                FileResponse::Write(Ok(written))
+            }
+            (FileRequest::Seek(SeekFileRequest { fd, .. }), FileResponse::Seek(Ok(seek))) => {
+                if let Some(file) = self.files.get_mut(fd) {
+                    file.position = seek.result_offset;
+                }
+                FileResponse::Seek(Ok(seek))
+            }
+            (
+                FileRequest::GetDEnts64(GetDEnts64Request { remote_fd, .. }),
+                FileResponse::GetDEnts64(Ok(mut entries)),
+            ) => {
+                entries.fd = *remote_fd;
+                FileResponse::GetDEnts64(Ok(entries))
+            }
+            (
+                FileRequest::ReadDirBatch(ReadDirBatchRequest { remote_fd, .. }),
+                FileResponse::ReadDirBatch(Ok(mut batch)),
+            ) => {
+                batch.fd = *remote_fd;
+                FileResponse::ReadDirBatch(Ok(batch))
+            }
+            (
+                FileRequest::Batch(BatchFileRequest { requests }),
+                FileResponse::Batch(Ok(BatchFileResponse { responses })),
+            ) => FileResponse::Batch(Ok(BatchFileResponse {
+                responses: requests
+                    .iter()
+                    .zip(responses)
+                    .map(|(request, response)| self.response_to_layer(request, response))
+                    .collect(),
+            })),
+            (_, response) => response,
+        }
+    }
+
+    fn open_file(&mut self, file: RemoteFile) -> FileResponse {
+        let layer_fd = self.next_fd();
+        self.files.insert(layer_fd, file);
+        FileResponse::Open(Ok(OpenFileResponse { fd: layer_fd }))
+    }
+
+    /// Forgets the file closed by the layers, returns its agent descriptor, if it has one.
+    pub fn close_file(&mut self, fd: u64) -> Option<u64> {
+        self.files.remove(&fd).and_then(|file| file.agent_fd)
+    }
+
+    /// Forgets the directory closed by the layers, returns its agent descriptor, if it has one.
+    pub fn close_dir(&mut self, fd: u64) -> Option<u64> {
+        self.dirs.remove(&fd).flatten()
+    }
+
+    /// Forgets the agent descriptors, since we connected to the agent again. Returns the requests
+    /// that open the files again, which must be sent before any other file request.
+    pub fn agent_reconnected(&mut self) -> Vec<FileRequest> {
+        self.reopening.clear();
+        self.dirs.values_mut().for_each(|dir| *dir = None);
+
+        let mut requests = Vec::new();
+        for (fd, file) in &mut self.files {
+            file.agent_fd = None;
+
+            let Some(path) = file.path.clone() else {
+                continue;
+            };
+            let open_options = OpenOptionsInternal {
+                truncate: false,
+                create: false,
+                create_new: false,
+                ..file.open_options
+            };
+
+            self.reopening.push_back(Reopen::Open(*fd));
+            requests.push(FileRequest::Open(OpenFileRequest { path, open_options }));
+        }
+
+        requests
+    }
+
+    /// Handles the `response` to one of the requests that open the files again. Returns the
+    /// request that seeks the file when it was opened again, or the request that closes it when
+    /// the layers closed it in the meantime.
+    pub fn reopen_response(&mut self, response: FileResponse) -> Option<FileRequest> {
+        let Some(Reopen::Open(fd)) = self.reopening.pop_front() else {
+            if let FileResponse::Seek(Err(error)) = response {
+                tracing::warn!(%error, "failed to seek a file opened again after reconnecting");
+            }
+            return None;
+        };
+        let Some(file) = self.files.get_mut(&fd) else {
+            let FileResponse::Open(Ok(OpenFileResponse { fd })) = response else {
+                return None;
+            };
+            return Some(FileRequest::Close(CloseFileRequest { fd }));
+        };
+
+        match response {
+            FileResponse::Open(Ok(OpenFileResponse { fd: agent_fd })) => {
+                file.agent_fd = Some(agent_fd);
+
+                // Appending files write at the end anyway.
+                if file.position == 0 || file.open_options.append {
+                    return None;
+                }
+
+                self.reopening.push_back(Reopen::Seek);
+                Some(FileRequest::Seek(SeekFileRequest {
+                    fd: agent_fd,
+                    seek_from: SeekFromInternal::Start(file.position),
+                }))
+            }
+            response => {
+                tracing::warn!(
+                    ?response,
+                    path = ?file.path,
+                    "failed to open a file again after reconnecting"
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::file::ReadFileResponse;
+
+    use super::*;
+
+    #[test]
+    fn reopens_at_position() {
+        let mut files = RemoteFiles::default();
+        let open = FileRequest::Open(OpenFileRequest {
+            path: "/app/log".into(),
+            open_options: OpenOptionsInternal {
+                read: true,
+                truncate: true,
+                ..Default::default()
+            },
+        });
+        let FileResponse::Open(Ok(OpenFileResponse { fd })) =
+            files.response_to_layer(&open, FileResponse::Open(Ok(OpenFileResponse { fd: 7 })))
+        else {
+            panic!("the file was not opened");
+        };
+        let read = FileRequest::Read(ReadFileRequest {
+            remote_fd: fd,
+            buffer_size: 16,
+        });
+        files.response_to_layer(
+            &read,
+            FileResponse::Read(Ok(ReadFileResponse {
+                bytes: vec![0; 16],
+                read_amount: 16,
+            })),
+        );
+
+        let reopen = files.agent_reconnected();
+        assert_eq!(
+            reopen,
+            [FileRequest::Open(OpenFileRequest {
+                path: "/app/log".into(),
+                open_options: OpenOptionsInternal {
+                    read: true,
+                    ..Default::default()
+                },
+            })]
+        );
+        assert_eq!(
+            files.reopen_response(FileResponse::Open(Ok(OpenFileResponse { fd: 3 }))),
+            Some(FileRequest::Seek(SeekFileRequest {
+                fd: 3,
+                seek_from: SeekFromInternal::Start(16),
+            }))
+        );
+        assert!(files.is_reopening());
+        files.reopen_response(FileResponse::Seek(Ok(SeekFileResponse {
+            result_offset: 16,
+        })));
+
+        assert!(!files.is_reopening());
+        assert_eq!(
+            files.request_to_agent(read),
+            FileRequest::Read(ReadFileRequest {
+                remote_fd: 3,
+                buffer_size: 16,
+            })
+        );
+    }
+}
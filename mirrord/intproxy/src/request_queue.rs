@@ -10,6 +10,9 @@
 //!
 //! Additionaly, single internal proxy handles multiple layer
 //! instances (coming from forks). This fifo stores their [`LayerId`]s as well.
+//!
+//! Optionally, the queue keeps a copy of each request, so that requests still waiting for a
+//! response can be sent again after the agent connection is re-established.
 
 use std::{collections::VecDeque, fmt};
 
@@ -26,17 +29,28 @@ pub struct RequestQueueEmpty;
 /// A queue used to match agent responses with layer requests.
 /// A single queue can be used for multiple types of requests only if the agent preserves order
 /// between them.
-#[derive(Default)]
-pub struct RequestQueue {
-    inner: VecDeque<(MessageId, LayerId)>,
+///
+/// `T` is the copy of the request kept in the queue, see [`RequestQueue::pending`].
+pub struct RequestQueue<T = ()> {
+    inner: VecDeque<(MessageId, LayerId, T)>,
 }
 
-impl fmt::Debug for RequestQueue {
+impl<T> Default for RequestQueue<T> {
+    fn default() -> Self {
+        Self {
+            inner: Default::default(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for RequestQueue<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids = |entry: &(MessageId, LayerId, T)| (entry.0, entry.1);
+
         f.debug_struct("RequestQueue")
             .field("queue_len", &self.inner.len())
-            .field("front", &self.inner.front().copied())
-            .field("back", &self.inner.back().copied())
+            .field("front", &self.inner.front().map(ids))
+            .field("back", &self.inner.back().map(ids))
             .finish()
     }
 }
@@ -45,12 +59,63 @@ impl RequestQueue {
     /// Save the request at the end of this queue.
     #[tracing::instrument(level = "trace")]
     pub fn insert(&mut self, message_id: MessageId, layer_id: LayerId) {
-        self.inner.push_back((message_id, layer_id));
+        self.insert_request(message_id, layer_id, ());
+    }
+}
+
+impl<T> RequestQueue<T> {
+    /// Save the request, together with its copy, at the end of this queue.
+    #[tracing::instrument(level = "trace", skip(request))]
+    pub fn insert_request(&mut self, message_id: MessageId, layer_id: LayerId, request: T) {
+        self.inner.push_back((message_id, layer_id, request));
     }
 
     /// Retrieve and remove a request from the front of this queue.
     #[tracing::instrument(level = "trace")]
     pub fn get(&mut self) -> Result<(MessageId, LayerId), RequestQueueEmpty> {
+        self.inner
+            .pop_front()
+            .map(|(message_id, layer_id, _)| (message_id, layer_id))
+            .ok_or(RequestQueueEmpty)
+    }
+
+    /// Retrieve and remove a request from the front of this queue, together with its copy.
+    #[tracing::instrument(level = "trace")]
+    pub fn get_request(&mut self) -> Result<(MessageId, LayerId, T), RequestQueueEmpty> {
         self.inner.pop_front().ok_or(RequestQueueEmpty)
     }
+
+    /// Removes all requests from this queue, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = (MessageId, LayerId)> + '_ {
+        self.inner
+            .drain(..)
+            .map(|(message_id, layer_id, _)| (message_id, layer_id))
+    }
+
+    /// Removes the requests for which `remove` returns `true`, keeping the order of the others.
+    /// Returns the removed requests, oldest first.
+    pub fn remove_if<F>(&mut self, mut remove: F) -> Vec<(MessageId, LayerId, T)>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let (removed, kept): (VecDeque<_>, VecDeque<_>) = self
+            .inner
+            .drain(..)
+            .partition(|(_, _, request)| remove(request));
+        self.inner = kept;
+
+        removed.into()
+    }
+
+    /// Copies of the requests that are still waiting for a response, oldest first.
+    pub fn pending(&self) -> impl Iterator<Item = &T> {
+        self.inner.iter().map(|(_, _, request)| request)
+    }
+
+    /// Like [`Self::pending`], with the ids of the requests.
+    pub fn pending_with_ids(&self) -> impl Iterator<Item = (MessageId, LayerId, &T)> {
+        self.inner
+            .iter()
+            .map(|(message_id, layer_id, request)| (*message_id, *layer_id, request))
+    }
 }
@@ -0,0 +1,419 @@
+//! Live state of the session, for `mirrord status`.
+//!
+//! The [`IntProxy`](crate::IntProxy) records what goes through it (subscribed ports, connections,
+//! file operations, DNS queries) in a [`SessionStatus`]. The [`StatusServer`] writes the current
+//! status as JSON to every client of a unix socket in [`sessions_dir`], named after the pid of the
+//! internal proxy. The directory is private to the user, so other users can't read the statuses or
+//! replace the sockets. With `--progress=ipc:<path>`, the [`StatusEvents`] report the changes of
+//! the subscribed ports as they happen.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    fs::DirBuilder,
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use mirrord_config::LayerConfig;
+use mirrord_progress::{IpcOutput, SessionEvent, SubscriptionMode};
+use mirrord_protocol::{
+    outgoing::{tcp::DaemonTcpOutgoing, udp::DaemonUdpOutgoing},
+    tcp::{DaemonTcp, LayerTcp, LayerTcpSteal, StealType},
+    ClientMessage, DaemonMessage, Port,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, net::UnixListener, sync::watch};
+
+use crate::{
+    agent_conn::AgentConnectInfo,
+    background_tasks::{BackgroundTask, MessageBus},
+    ProxyMessage,
+};
+
+/// Directory of the status sockets of the running sessions of this user.
+pub fn sessions_dir() -> PathBuf {
+    let uid = unsafe { libc::getuid() };
+    std::env::temp_dir().join(format!("mirrord-sessions-{uid}"))
+}
+
+/// Fails with [`io::ErrorKind::PermissionDenied`] when `dir` is not a directory of this user that
+/// only they can access.
+pub fn check_private_dir(dir: &Path) -> io::Result<()> {
+    let uid = unsafe { libc::getuid() };
+
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the directory is not private to the user",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates `dir` private to this user, unless it already exists, see [`check_private_dir`].
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Err(fail) if fail.kind() != io::ErrorKind::AlreadyExists => return Err(fail),
+        _ => {}
+    }
+
+    check_private_dir(dir)
+}
+
+/// What a session is doing, as reported by `mirrord status`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStatus {
+    /// Process id of the internal proxy.
+    pub pid: u32,
+    /// When the session started, in seconds since the unix epoch.
+    pub started: u64,
+    /// Path of the target, `targetless` when there is none.
+    pub target: String,
+    pub namespace: Option<String>,
+    /// Name of the agent pod, `None` when the operator created the agent.
+    pub agent: Option<String>,
+    pub operator: bool,
+    /// Attempt of the reconnection to the agent in progress, `None` when connected.
+    pub reconnecting: Option<u32>,
+    /// How many times the connection with the agent was established again.
+    pub reconnects: u64,
+    /// Local processes connected to the session.
+    pub processes: usize,
+    pub mirrored_ports: BTreeSet<Port>,
+    /// Stolen ports, with their HTTP filter.
+    pub stolen_ports: BTreeMap<Port, Option<String>>,
+    pub mirrored_connections: u64,
+    pub stolen_connections: u64,
+    /// HTTP requests stolen with a filter.
+    pub stolen_requests: u64,
+    pub outgoing_connections: u64,
+    pub file_operations: u64,
+    pub dns_queries: u64,
+}
+
+impl SessionStatus {
+    /// Creates the status of a session that just started.
+    pub fn new(config: &LayerConfig, agent_connect_info: Option<&AgentConnectInfo>) -> Self {
+        Self {
+            pid: std::process::id(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default(),
+            target: config
+                .target
+                .path
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "targetless".to_string()),
+            namespace: config.target.namespace.clone(),
+            agent: match agent_connect_info {
+                Some(AgentConnectInfo::DirectKubernetes(info)) => Some(info.pod_name.clone()),
+                _ => None,
+            },
+            operator: matches!(agent_connect_info, Some(AgentConnectInfo::Operator(_))),
+            ..Default::default()
+        }
+    }
+
+    /// Records a message sent to the agent.
+    pub(crate) fn client_message(&mut self, message: &ClientMessage) {
+        match message {
+            ClientMessage::Tcp(LayerTcp::PortSubscribe(port)) => {
+                self.mirrored_ports.insert(*port);
+            }
+            ClientMessage::Tcp(LayerTcp::PortUnsubscribe(port)) => {
+                self.mirrored_ports.remove(port);
+            }
+            ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(steal_type)) => {
+                let filter = match steal_type {
+                    StealType::All(..) => None,
+                    StealType::FilteredHttp(_, filter) => Some(format!("header={filter}")),
+                    StealType::FilteredHttpEx(_, filter) => Some(filter.to_string()),
+                };
+                self.stolen_ports.insert(steal_type.get_port(), filter);
+            }
+            ClientMessage::TcpSteal(LayerTcpSteal::PortUnsubscribe(port)) => {
+                self.stolen_ports.remove(port);
+            }
+            ClientMessage::FileRequest(..) => self.file_operations += 1,
+            ClientMessage::GetAddrInfoRequest(..) | ClientMessage::GetAddrInfoRequestV2(..) => {
+                self.dns_queries += 1
+            }
+            _ => {}
+        }
+    }
+
+    /// The [`SessionEvent::SessionStarted`] of this session.
+    fn started_event(&self) -> SessionEvent {
+        SessionEvent::SessionStarted {
+            pid: self.pid,
+            target: self.target.clone(),
+            namespace: self.namespace.clone(),
+            agent: self.agent.clone(),
+            operator: self.operator,
+        }
+    }
+
+    /// The ports that were subscribed or unsubscribed since the `previous` status.
+    fn port_events(&self, previous: &SessionStatus) -> Vec<SessionEvent> {
+        let mirrored = self
+            .mirrored_ports
+            .difference(&previous.mirrored_ports)
+            .map(|port| SessionEvent::PortSubscribed {
+                port: *port,
+                mode: SubscriptionMode::Mirror,
+                filter: None,
+            });
+        let unmirrored = previous
+            .mirrored_ports
+            .difference(&self.mirrored_ports)
+            .map(|port| SessionEvent::PortUnsubscribed {
+                port: *port,
+                mode: SubscriptionMode::Mirror,
+            });
+        // A stolen port with a new filter is reported again.
+        let stolen = self
+            .stolen_ports
+            .iter()
+            .filter(|(port, filter)| previous.stolen_ports.get(port) != Some(filter))
+            .map(|(port, filter)| SessionEvent::PortSubscribed {
+                port: *port,
+                mode: SubscriptionMode::Steal,
+                filter: filter.clone(),
+            });
+        let unstolen = previous
+            .stolen_ports
+            .keys()
+            .filter(|port| !self.stolen_ports.contains_key(port))
+            .map(|port| SessionEvent::PortUnsubscribed {
+                port: *port,
+                mode: SubscriptionMode::Steal,
+            });
+
+        mirrored
+            .chain(unmirrored)
+            .chain(stolen)
+            .chain(unstolen)
+            .collect()
+    }
+
+    /// Records a message received from the agent.
+    pub(crate) fn daemon_message(&mut self, message: &DaemonMessage) {
+        match message {
+            DaemonMessage::Tcp(DaemonTcp::NewConnection(..)) => self.mirrored_connections += 1,
+            DaemonMessage::TcpSteal(
+                DaemonTcp::NewConnection(..) | DaemonTcp::NewConnectionV2(..),
+            ) => self.stolen_connections += 1,
+            DaemonMessage::TcpSteal(
+                DaemonTcp::HttpRequest(..) | DaemonTcp::HttpRequestFramed(..),
+            ) => self.stolen_requests += 1,
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(Ok(..)))
+            | DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Connect(Ok(..))) => {
+                self.outgoing_connections += 1
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serves the current [`SessionStatus`] on a unix socket, removed when the session ends.
+/// Run as a [`BackgroundTask`].
+pub struct StatusServer {
+    listener: UnixListener,
+    path: PathBuf,
+    status: watch::Receiver<SessionStatus>,
+}
+
+impl StatusServer {
+    /// Listens on the socket of this internal proxy in [`sessions_dir`].
+    pub fn bind(status: watch::Receiver<SessionStatus>) -> io::Result<Self> {
+        let dir = sessions_dir();
+        create_private_dir(&dir)?;
+
+        let path = dir.join(format!("{}.sock", std::process::id()));
+        // Left by a previous process with the same pid.
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        Ok(Self {
+            listener,
+            path,
+            status,
+        })
+    }
+}
+
+impl BackgroundTask for StatusServer {
+    type Error = Infallible;
+    type MessageIn = ();
+    type MessageOut = ProxyMessage;
+
+    async fn run(self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        loop {
+            tokio::select! {
+                msg = message_bus.recv() => {
+                    if msg.is_none() {
+                        break;
+                    }
+                },
+
+                accepted = self.listener.accept() => match accepted {
+                    Ok((mut stream, _)) => {
+                        let status = serde_json::to_vec(&*self.status.borrow())
+                            .unwrap_or_default();
+
+                        tokio::spawn(async move {
+                            if let Err(error) = stream.write_all(&status).await {
+                                tracing::debug!(%error, "failed to send the session status");
+                            }
+                        });
+                    }
+                    Err(error) => tracing::debug!(%error, "failed to accept a status client"),
+                },
+            }
+        }
+
+        let _ = std::fs::remove_file(&self.path);
+
+        Ok(())
+    }
+}
+
+/// Sends the [`SessionEvent`]s of the session to the [`IpcOutput`] of `--progress=ipc:<path>`:
+/// the start of the session, and the changes of its subscribed ports.
+/// Run as a [`BackgroundTask`].
+pub struct StatusEvents {
+    output: IpcOutput,
+    status: watch::Receiver<SessionStatus>,
+}
+
+impl StatusEvents {
+    pub fn new(output: IpcOutput, status: watch::Receiver<SessionStatus>) -> Self {
+        Self { output, status }
+    }
+}
+
+impl BackgroundTask for StatusEvents {
+    type Error = Infallible;
+    type MessageIn = ();
+    type MessageOut = ProxyMessage;
+
+    async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        let mut previous = self.status.borrow_and_update().clone();
+        self.output.send_event(previous.started_event());
+
+        loop {
+            tokio::select! {
+                msg = message_bus.recv() => {
+                    if msg.is_none() {
+                        break;
+                    }
+                },
+
+                changed = self.status.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+
+                    let current = self.status.borrow_and_update().clone();
+                    for event in current.port_events(&previous) {
+                        self.output.send_event(event);
+                    }
+                    previous = current;
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::{
+        file::CloseFileRequest,
+        tcp::{Filter, HttpFilter},
+        FileRequest,
+    };
+
+    use super::*;
+
+    /// The sessions dir is created private, and a dir that other users can access is rejected.
+    #[test]
+    fn private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("mirrord-status-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        create_private_dir(&dir).unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        create_private_dir(&dir).unwrap();
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let result = create_private_dir(&dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            result.map_err(|fail| fail.kind()),
+            Err(io::ErrorKind::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn tracks_stolen_ports() {
+        let mut status = SessionStatus::default();
+        let filter = HttpFilter::Path(Filter::new("/api".to_string()).unwrap());
+
+        status.client_message(&ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+            StealType::All(80),
+        )));
+        status.client_message(&ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+            StealType::FilteredHttpEx(8080, filter),
+        )));
+        status.client_message(&ClientMessage::TcpSteal(LayerTcpSteal::PortUnsubscribe(80)));
+
+        assert_eq!(
+            status.stolen_ports,
+            BTreeMap::from([(8080, Some("path=/api".to_string()))])
+        );
+    }
+
+    #[test]
+    fn port_events() {
+        let mut previous = SessionStatus::default();
+        previous.client_message(&ClientMessage::Tcp(LayerTcp::PortSubscribe(80)));
+        previous.client_message(&ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(
+            StealType::All(8080),
+        )));
+
+        let mut status = previous.clone();
+        status.client_message(&ClientMessage::Tcp(LayerTcp::PortUnsubscribe(80)));
+        status.client_message(&ClientMessage::Tcp(LayerTcp::PortSubscribe(3000)));
+        status.client_message(&ClientMessage::FileRequest(FileRequest::Close(
+            CloseFileRequest { fd: 1 },
+        )));
+
+        assert_eq!(
+            status.port_events(&previous),
+            vec![
+                SessionEvent::PortSubscribed {
+                    port: 3000,
+                    mode: SubscriptionMode::Mirror,
+                    filter: None,
+                },
+                SessionEvent::PortUnsubscribed {
+                    port: 80,
+                    mode: SubscriptionMode::Mirror,
+                },
+            ]
+        );
+    }
+}
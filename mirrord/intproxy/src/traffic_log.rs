@@ -0,0 +1,464 @@
+//! Log of the traffic intercepted in the session, written to `internal_proxy.traffic_log`,
+//! independent of `RUST_LOG`.
+//!
+//! Every mirrored, stolen and outgoing connection, and every HTTP request stolen with a filter, is
+//! a line of JSON written when it ends. Only the requests that matched the filter reach us, the
+//! agent passes the others to the target, so every logged request has the `filter` it matched.
+//!
+//! The values of the query parameters are left out of the logged paths, and secrets in the rest
+//! of the paths are redacted (see [`secrets`]). The file is only readable by the user.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, LineWriter, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{Method, Uri};
+use mirrord_protocol::{
+    outgoing::{
+        tcp::{DaemonTcpOutgoing, LayerTcpOutgoing},
+        udp::{DaemonUdpOutgoing, LayerUdpOutgoing},
+        DaemonConnect, DaemonRead, LayerClose, LayerWrite,
+    },
+    tcp::{
+        DaemonTcp, LayerTcp, LayerTcpSteal, NewTcpConnection, NewTcpConnectionV2, StealType,
+        TcpClose, TcpData, TcpSequencedClose, TcpSequencedData,
+    },
+    ClientMessage, ConnectionId, DaemonMessage, Port, RequestId,
+};
+use serde::Serialize;
+
+use crate::secrets;
+
+/// How the connection was intercepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum TrafficMode {
+    Mirror,
+    Steal,
+    OutgoingTcp,
+    OutgoingUdp,
+}
+
+/// A connection, logged when it's closed.
+#[derive(Debug, Serialize)]
+struct ConnectionEntry {
+    mode: TrafficMode,
+    connection_id: ConnectionId,
+    /// The remote peer, the client of the target for incoming connections.
+    peer: String,
+    /// Port of the target, for incoming connections.
+    port: Option<Port>,
+    /// When the connection was made, in milliseconds since the unix epoch.
+    start_ms: u64,
+    duration_ms: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+/// An HTTP request stolen with a filter, logged when it's answered or its connection is closed.
+#[derive(Debug, Serialize)]
+struct RequestEntry {
+    connection_id: ConnectionId,
+    request_id: RequestId,
+    port: Port,
+    method: String,
+    /// Path and query of the request.
+    path: String,
+    /// The filter the request matched.
+    filter: Option<String>,
+    /// When the request arrived, in milliseconds since the unix epoch.
+    start_ms: u64,
+    duration_ms: u64,
+    /// Status of the response, `None` when the connection was closed before it.
+    status: Option<u16>,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TrafficEntry {
+    Connection(ConnectionEntry),
+    HttpRequest(RequestEntry),
+}
+
+/// Milliseconds since the unix epoch.
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Writes the [`TrafficEntry`]s of the session to the traffic log file.
+pub(crate) struct TrafficLog {
+    file: LineWriter<File>,
+    /// Filters of the stolen ports.
+    filters: HashMap<Port, Option<String>>,
+    /// Open connections.
+    connections: HashMap<(TrafficMode, ConnectionId), (Instant, ConnectionEntry)>,
+    /// Stolen requests waiting for their responses.
+    requests: HashMap<(ConnectionId, RequestId), (Instant, RequestEntry)>,
+}
+
+impl TrafficLog {
+    /// Appends to the file at `path`.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+
+        Ok(Self {
+            file: LineWriter::new(file),
+            filters: Default::default(),
+            connections: Default::default(),
+            requests: Default::default(),
+        })
+    }
+
+    /// Records a message sent to the agent.
+    pub(crate) fn client_message(&mut self, message: &ClientMessage) {
+        match message {
+            ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(steal_type)) => {
+                let filter = match steal_type {
+                    StealType::All(..) => None,
+                    StealType::FilteredHttp(_, filter) => Some(format!("header={filter}")),
+                    StealType::FilteredHttpEx(_, filter) => Some(filter.to_string()),
+                };
+                self.filters.insert(steal_type.get_port(), filter);
+            }
+            ClientMessage::TcpSteal(LayerTcpSteal::Data(TcpData {
+                connection_id,
+                bytes,
+            })) => self.sent(TrafficMode::Steal, *connection_id, bytes.len()),
+            ClientMessage::TcpSteal(LayerTcpSteal::HttpResponse(response)) => self.responded(
+                (response.connection_id, response.request_id),
+                response.internal_response.status().as_u16(),
+                response.internal_response.body().len(),
+            ),
+            ClientMessage::TcpSteal(LayerTcpSteal::HttpResponseFramed(response)) => self.responded(
+                (response.connection_id, response.request_id),
+                response.internal_response.status().as_u16(),
+                response.internal_response.body().data().len(),
+            ),
+            ClientMessage::TcpSteal(LayerTcpSteal::ConnectionUnsubscribe(connection_id)) => {
+                self.closed(TrafficMode::Steal, *connection_id)
+            }
+            ClientMessage::Tcp(LayerTcp::ConnectionUnsubscribe(connection_id)) => {
+                self.closed(TrafficMode::Mirror, *connection_id)
+            }
+            ClientMessage::TcpOutgoing(LayerTcpOutgoing::Write(LayerWrite {
+                connection_id,
+                bytes,
+            })) => self.sent(TrafficMode::OutgoingTcp, *connection_id, bytes.len()),
+            ClientMessage::UdpOutgoing(LayerUdpOutgoing::Write(LayerWrite {
+                connection_id,
+                bytes,
+            })) => self.sent(TrafficMode::OutgoingUdp, *connection_id, bytes.len()),
+            ClientMessage::TcpOutgoing(LayerTcpOutgoing::Close(LayerClose { connection_id })) => {
+                self.closed(TrafficMode::OutgoingTcp, *connection_id)
+            }
+            ClientMessage::UdpOutgoing(LayerUdpOutgoing::Close(LayerClose { connection_id })) => {
+                self.closed(TrafficMode::OutgoingUdp, *connection_id)
+            }
+            _ => {}
+        }
+    }
+
+    /// Records a message received from the agent.
+    pub(crate) fn daemon_message(&mut self, message: &DaemonMessage) {
+        match message {
+            DaemonMessage::Tcp(DaemonTcp::NewConnection(connection)) => {
+                self.incoming(TrafficMode::Mirror, connection)
+            }
+            DaemonMessage::TcpSteal(
+                DaemonTcp::NewConnection(connection)
+                | DaemonTcp::NewConnectionV2(NewTcpConnectionV2 { connection, .. }),
+            ) => self.incoming(TrafficMode::Steal, connection),
+            DaemonMessage::Tcp(
+                DaemonTcp::Data(TcpData {
+                    connection_id,
+                    bytes,
+                })
+                | DaemonTcp::DataSequenced(TcpSequencedData {
+                    connection_id,
+                    bytes,
+                    ..
+                }),
+            ) => self.received(TrafficMode::Mirror, *connection_id, bytes.len()),
+            DaemonMessage::TcpSteal(DaemonTcp::Data(TcpData {
+                connection_id,
+                bytes,
+            })) => self.received(TrafficMode::Steal, *connection_id, bytes.len()),
+            DaemonMessage::Tcp(
+                DaemonTcp::Close(TcpClose { connection_id })
+                | DaemonTcp::CloseSequenced(TcpSequencedClose { connection_id, .. }),
+            ) => self.closed(TrafficMode::Mirror, *connection_id),
+            DaemonMessage::TcpSteal(DaemonTcp::Close(TcpClose { connection_id })) => {
+                self.closed(TrafficMode::Steal, *connection_id)
+            }
+            DaemonMessage::TcpSteal(DaemonTcp::HttpRequest(request)) => self.request(
+                (request.connection_id, request.request_id),
+                request.port,
+                &request.internal_request.method,
+                &request.internal_request.uri,
+                request.internal_request.body.len(),
+            ),
+            DaemonMessage::TcpSteal(DaemonTcp::HttpRequestFramed(request)) => self.request(
+                (request.connection_id, request.request_id),
+                request.port,
+                &request.internal_request.method,
+                &request.internal_request.uri,
+                request.internal_request.body.data().len(),
+            ),
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(Ok(connect))) => {
+                self.outgoing(TrafficMode::OutgoingTcp, connect)
+            }
+            DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Connect(Ok(connect))) => {
+                self.outgoing(TrafficMode::OutgoingUdp, connect)
+            }
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Read(Ok(DaemonRead {
+                connection_id,
+                bytes,
+            }))) => self.received(TrafficMode::OutgoingTcp, *connection_id, bytes.len()),
+            DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Read(Ok(DaemonRead {
+                connection_id,
+                bytes,
+            }))) => self.received(TrafficMode::OutgoingUdp, *connection_id, bytes.len()),
+            DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Close(connection_id)) => {
+                self.closed(TrafficMode::OutgoingTcp, *connection_id)
+            }
+            DaemonMessage::UdpOutgoing(DaemonUdpOutgoing::Close(connection_id)) => {
+                self.closed(TrafficMode::OutgoingUdp, *connection_id)
+            }
+            _ => {}
+        }
+    }
+
+    /// Logs the connections and requests made through the previous agent connection, they're
+    /// gone.
+    pub(crate) fn agent_reconnected(&mut self) {
+        let connections = self.connections.keys().copied().collect::<Vec<_>>();
+        for (mode, connection_id) in connections {
+            self.closed(mode, connection_id);
+        }
+    }
+
+    fn incoming(&mut self, mode: TrafficMode, connection: &NewTcpConnection) {
+        let entry = ConnectionEntry {
+            mode,
+            connection_id: connection.connection_id,
+            peer: format!("{}:{}", connection.remote_address, connection.source_port),
+            port: Some(connection.destination_port),
+            start_ms: now_ms(),
+            duration_ms: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        };
+
+        self.connections
+            .insert((mode, connection.connection_id), (Instant::now(), entry));
+    }
+
+    fn outgoing(&mut self, mode: TrafficMode, connect: &DaemonConnect) {
+        let entry = ConnectionEntry {
+            mode,
+            connection_id: connect.connection_id,
+            peer: connect.remote_address.to_string(),
+            port: None,
+            start_ms: now_ms(),
+            duration_ms: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        };
+
+        self.connections
+            .insert((mode, connect.connection_id), (Instant::now(), entry));
+    }
+
+    fn received(&mut self, mode: TrafficMode, connection_id: ConnectionId, bytes: usize) {
+        if let Some((_, entry)) = self.connections.get_mut(&(mode, connection_id)) {
+            entry.bytes_received += bytes as u64;
+        }
+    }
+
+    fn sent(&mut self, mode: TrafficMode, connection_id: ConnectionId, bytes: usize) {
+        if let Some((_, entry)) = self.connections.get_mut(&(mode, connection_id)) {
+            entry.bytes_sent += bytes as u64;
+        }
+    }
+
+    fn closed(&mut self, mode: TrafficMode, connection_id: ConnectionId) {
+        if mode == TrafficMode::Steal {
+            let requests = self
+                .requests
+                .keys()
+                .filter(|(id, _)| *id == connection_id)
+                .copied()
+                .collect::<Vec<_>>();
+            for key in requests {
+                if let Some((start, mut entry)) = self.requests.remove(&key) {
+                    entry.duration_ms = start.elapsed().as_millis() as u64;
+                    self.write(TrafficEntry::HttpRequest(entry));
+                }
+            }
+        }
+
+        if let Some((start, mut entry)) = self.connections.remove(&(mode, connection_id)) {
+            entry.duration_ms = start.elapsed().as_millis() as u64;
+            self.write(TrafficEntry::Connection(entry));
+        }
+    }
+
+    fn request(
+        &mut self,
+        key: (ConnectionId, RequestId),
+        port: Port,
+        method: &Method,
+        uri: &Uri,
+        bytes: usize,
+    ) {
+        let entry = RequestEntry {
+            connection_id: key.0,
+            request_id: key.1,
+            port,
+            method: method.to_string(),
+            path: logged_path(uri),
+            filter: self.filters.get(&port).cloned().flatten(),
+            start_ms: now_ms(),
+            duration_ms: 0,
+            status: None,
+            bytes_received: bytes as u64,
+            bytes_sent: 0,
+        };
+
+        self.requests.insert(key, (Instant::now(), entry));
+    }
+
+    fn responded(&mut self, key: (ConnectionId, RequestId), status: u16, bytes: usize) {
+        if let Some((start, mut entry)) = self.requests.remove(&key) {
+            entry.duration_ms = start.elapsed().as_millis() as u64;
+            entry.status = Some(status);
+            entry.bytes_sent = bytes as u64;
+            self.write(TrafficEntry::HttpRequest(entry));
+        }
+    }
+
+    fn write(&mut self, entry: TrafficEntry) {
+        let written = serde_json::to_vec(&entry)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file.write_all(&line)
+            });
+        if let Err(error) = written {
+            tracing::warn!(%error, "failed to write to the traffic log");
+        }
+    }
+}
+
+/// Path of `uri` as it's logged, the values of the query parameters often carry tokens and
+/// personal data.
+fn logged_path(uri: &Uri) -> String {
+    let Some(path) = uri.path_and_query() else {
+        return "/".to_string();
+    };
+
+    let path = match path.query() {
+        Some(query) => {
+            let query = query
+                .split('&')
+                .map(|param| match param.split_once('=') {
+                    Some((name, _)) => format!("{name}=[REDACTED]"),
+                    None => param.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            format!("{}?{query}", path.path())
+        }
+        None => path.path().to_string(),
+    };
+
+    secrets::redact_all(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        os::unix::fs::PermissionsExt,
+    };
+
+    use rstest::rstest;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn logs_closed_connection() {
+        let path = std::env::temp_dir().join(format!("mirrord-traffic-{}.log", std::process::id()));
+        let mut log = TrafficLog::open(&path).unwrap();
+
+        log.daemon_message(&DaemonMessage::TcpSteal(DaemonTcp::NewConnection(
+            NewTcpConnection {
+                connection_id: 1,
+                remote_address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)),
+                destination_port: 80,
+                source_port: 50000,
+                local_address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            },
+        )));
+        log.daemon_message(&DaemonMessage::TcpSteal(DaemonTcp::Data(TcpData {
+            connection_id: 1,
+            bytes: b"ping".to_vec(),
+        })));
+        log.client_message(&ClientMessage::TcpSteal(LayerTcpSteal::Data(TcpData {
+            connection_id: 1,
+            bytes: b"pong!".to_vec(),
+        })));
+        log.daemon_message(&DaemonMessage::TcpSteal(DaemonTcp::Close(TcpClose {
+            connection_id: 1,
+        })));
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        let mut entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        entry
+            .as_object_mut()
+            .unwrap()
+            .retain(|key, _| !key.ends_with("_ms"));
+
+        assert_eq!(
+            entry,
+            json!({
+                "type": "connection",
+                "mode": "steal",
+                "connection_id": 1,
+                "peer": "10.0.0.7:50000",
+                "port": 80,
+                "bytes_received": 4,
+                "bytes_sent": 5,
+            })
+        );
+    }
+
+    #[rstest]
+    #[case::no_query("/api/users", "/api/users")]
+    #[case::query(
+        "/api/users?token=abc123&page=2&debug",
+        "/api/users?token=[REDACTED]&page=[REDACTED]&debug"
+    )]
+    #[case::empty_value("/search?q=", "/search?q=[REDACTED]")]
+    fn leaves_out_query_values(#[case] uri: &str, #[case] logged: &str) {
+        assert_eq!(logged_path(&uri.parse().unwrap()), logged);
+    }
+}
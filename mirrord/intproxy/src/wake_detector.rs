@@ -0,0 +1,127 @@
+//! Detection of the machine waking from sleep.
+//!
+//! While the machine sleeps, the connection with the agent silently dies (the API server or a NAT
+//! on the way drops it), but our sockets only find out after long timeouts. In the meantime the
+//! layer hangs on every request.
+//!
+//! The monotonic clock does not advance during sleep, while the wall clock does. We compare both on
+//! every tick, and a wall clock jump far bigger than the time we've actually been running means the
+//! machine was asleep.
+
+use std::{
+    convert::Infallible,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+use crate::{
+    background_tasks::{BackgroundTask, MessageBus},
+    ProxyMessage,
+};
+
+/// Sends [`ProxyMessage::SystemWoke`] when it notices that the machine was asleep.
+/// Run as a [`BackgroundTask`].
+pub struct WakeDetector {
+    ticker: Interval,
+    /// Minimal difference between the clocks that we treat as sleep.
+    threshold: Duration,
+    last_check: (Instant, SystemTime),
+}
+
+impl WakeDetector {
+    /// Creates a new instance of this struct.
+    ///
+    /// # Arguments
+    ///
+    /// * frequency - how often the clocks are compared
+    /// * threshold - minimal difference between the clocks that we treat as sleep, small jumps
+    ///   happen when the wall clock is adjusted
+    pub fn new(frequency: Duration, threshold: Duration) -> Self {
+        let mut ticker = time::interval(frequency);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        Self {
+            ticker,
+            threshold,
+            last_check: (Instant::now(), SystemTime::now()),
+        }
+    }
+}
+
+/// Compares the clocks at `last` and `now`, and returns how long the machine was asleep in
+/// between, if at least `threshold`.
+fn asleep_for(
+    last: (Instant, SystemTime),
+    now: (Instant, SystemTime),
+    threshold: Duration,
+) -> Option<Duration> {
+    let running = now.0.saturating_duration_since(last.0);
+    // Fails when the wall clock went back, which is never caused by sleep.
+    let wall = now.1.duration_since(last.1).ok()?;
+
+    wall.checked_sub(running)
+        .filter(|asleep| *asleep >= threshold)
+}
+
+impl BackgroundTask for WakeDetector {
+    type Error = Infallible;
+    type MessageIn = ();
+    type MessageOut = ProxyMessage;
+
+    async fn run(mut self, message_bus: &mut MessageBus<Self>) -> Result<(), Self::Error> {
+        loop {
+            tokio::select! {
+                _ = self.ticker.tick() => {
+                    let now = (Instant::now(), SystemTime::now());
+                    let last = std::mem::replace(&mut self.last_check, now);
+
+                    if let Some(asleep) = asleep_for(last, now, self.threshold) {
+                        tracing::warn!(?asleep, "the machine woke from sleep");
+                        message_bus.send(ProxyMessage::SystemWoke).await;
+                    }
+                },
+
+                msg = message_bus.recv() => if msg.is_none() {
+                    tracing::trace!("message bus closed, exiting");
+                    break Ok(());
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_sleep() {
+        let threshold = Duration::from_secs(10);
+        let last = (Instant::now(), SystemTime::now());
+
+        // Wall clock adjusted a bit.
+        let now = (
+            last.0 + Duration::from_secs(5),
+            last.1 + Duration::from_secs(7),
+        );
+        assert_eq!(asleep_for(last, now, threshold), None);
+
+        // Wall clock went back.
+        let now = (
+            last.0 + Duration::from_secs(5),
+            last.1 - Duration::from_secs(60),
+        );
+        assert_eq!(asleep_for(last, now, threshold), None);
+
+        // Asleep for an hour.
+        let now = (
+            last.0 + Duration::from_secs(5),
+            last.1 + Duration::from_secs(3605),
+        );
+        assert_eq!(
+            asleep_for(last, now, threshold),
+            Some(Duration::from_secs(3600))
+        );
+    }
+}
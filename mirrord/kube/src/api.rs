@@ -5,7 +5,10 @@ use tokio::sync::mpsc;
 use tracing::Instrument;
 
 pub mod container;
+pub mod credentials;
 pub mod kubernetes;
+pub mod proxy;
+pub mod retry;
 pub mod runtime;
 
 const CONNECTION_CHANNEL_SIZE: usize = 1000;
@@ -39,6 +39,17 @@ pub struct ContainerParams {
     /// Value for [`AGENT_OPERATOR_CERT_ENV`](mirrord_protocol::AGENT_OPERATOR_CERT_ENV) set in
     /// the agent container.
     pub tls_cert: Option<String>,
+    /// Set when the agent should pass OpenShift's security context constraints, see
+    /// [`AgentConfig::openshift`].
+    pub openshift: Option<OpenShiftParams>,
+}
+
+/// Adjustments of the agent spec for OpenShift, see [`ContainerParams::openshift`].
+#[derive(Clone, Debug, Default)]
+pub struct OpenShiftParams {
+    /// User to run targetless agents as, from the namespace's allowed UID range (see
+    /// [`AgentConfig::openshift_uid_range`]). When not set, SCC admission picks one.
+    pub run_as_user: Option<i64>,
 }
 
 impl ContainerParams {
@@ -58,6 +69,7 @@ impl ContainerParams {
             gid,
             port,
             tls_cert: None,
+            openshift: None,
         }
     }
 }
@@ -1,24 +1,28 @@
-use futures::StreamExt;
+use std::time::Duration;
+
 use k8s_openapi::api::core::v1::{EphemeralContainer as KubeEphemeralContainer, Pod};
 use kube::{
     api::PostParams,
     runtime::{watcher, WatchStreamExt},
-    Client,
+    Api, Client,
 };
 use mirrord_config::agent::AgentConfig;
 use mirrord_progress::Progress;
-use serde_json::json;
-use tokio::pin;
+use serde_json::{json, Value};
 use tracing::debug;
 
 use super::util::agent_env;
 use crate::{
     api::{
         container::{
-            util::{base_command_line, get_capabilities, wait_for_agent_startup},
+            util::{
+                base_command_line, get_capabilities, openshift_security_context,
+                wait_for_agent_startup, watch_until,
+            },
             ContainerParams, ContainerVariant,
         },
         kubernetes::{get_k8s_resource_api, AgentKubernetesConnectInfo},
+        retry::RetryPolicy,
         runtime::RuntimeData,
     },
     error::{KubeApiError, Result},
@@ -42,24 +46,19 @@ fn is_ephemeral_container_running(pod: Pod, container_name: &str) -> bool {
         .unwrap_or(false)
 }
 
-pub async fn create_ephemeral_agent<P, V>(
-    client: &Client,
+/// Builds the agent's ephemeral container, with the environment of the target container.
+///
+/// Only reads the target pod, see [`create_ephemeral_agent`] for adding the container to it.
+pub async fn ephemeral_container_manifest<V>(
+    pod_api: &Api<Pod>,
     runtime_data: &RuntimeData,
     variant: &V,
-    progress: &P,
-) -> Result<AgentKubernetesConnectInfo>
+) -> Result<KubeEphemeralContainer>
 where
-    P: Progress + Send + Sync,
     V: ContainerVariant<Update = KubeEphemeralContainer>,
 {
-    let params = variant.params();
-    // Ephemeral should never be targetless, so there should be runtime data.
-    let mut container_progress = progress.subtask("creating ephemeral container...");
-
     let mut ephemeral_container: KubeEphemeralContainer = variant.as_update()?;
-    debug!("Requesting ephemeral_containers_subresource");
 
-    let pod_api = get_k8s_resource_api(client, runtime_data.pod_namespace.as_deref());
     let pod: Pod = pod_api.get(&runtime_data.pod_name).await?;
     let pod_spec = pod.spec.ok_or(KubeApiError::PodSpecNotFound)?;
 
@@ -81,6 +80,20 @@ where
         ephemeral_container.env_from = Some(env)
     }
 
+    Ok(ephemeral_container)
+}
+
+/// Adds the `ephemeral_container` to the target pod's `ephemeralcontainers` subresource.
+///
+/// Succeeds when the container is already there. Its name is random, so an earlier attempt (or an
+/// earlier call, see `KubernetesAPI::create_agent`) added it, but we didn't get the response.
+async fn add_ephemeral_container(
+    pod_api: &Api<Pod>,
+    runtime_data: &RuntimeData,
+    ephemeral_container: &KubeEphemeralContainer,
+) -> Result<()> {
+    debug!("Requesting ephemeral_containers_subresource");
+
     let mut ephemeral_containers_subresource: Pod = pod_api
         .get_subresource("ephemeralcontainers", &runtime_data.pod_name)
         .await
@@ -91,13 +104,16 @@ where
         .as_mut()
         .ok_or(KubeApiError::PodSpecNotFound)?;
 
-    spec.ephemeral_containers = match spec.ephemeral_containers.clone() {
-        Some(mut ephemeral_containers) => {
-            ephemeral_containers.push(ephemeral_container);
-            Some(ephemeral_containers)
-        }
-        None => Some(vec![ephemeral_container]),
-    };
+    let ephemeral_containers = spec
+        .ephemeral_containers
+        .get_or_insert_with(Default::default);
+    if ephemeral_containers
+        .iter()
+        .any(|container| container.name == ephemeral_container.name)
+    {
+        return Ok(());
+    }
+    ephemeral_containers.push(ephemeral_container.clone());
 
     pod_api
         .replace_subresource(
@@ -109,6 +125,39 @@ where
         .await
         .map_err(KubeApiError::KubeError)?;
 
+    Ok(())
+}
+
+pub async fn create_ephemeral_agent<P, V>(
+    client: &Client,
+    runtime_data: &RuntimeData,
+    variant: &V,
+    progress: &P,
+) -> Result<AgentKubernetesConnectInfo>
+where
+    P: Progress + Send + Sync,
+    V: ContainerVariant<Update = KubeEphemeralContainer>,
+{
+    let params = variant.params();
+    // Ephemeral should never be targetless, so there should be runtime data.
+    let mut container_progress = progress.subtask("creating ephemeral container...");
+
+    let pod_api = get_k8s_resource_api(client, runtime_data.pod_namespace.as_deref());
+    let retry_policy = RetryPolicy::new(variant.agent_config());
+
+    let ephemeral_container = retry_policy
+        .run(&container_progress, "getting the target pod", |_| {
+            ephemeral_container_manifest(&pod_api, runtime_data, variant)
+        })
+        .await?;
+    retry_policy
+        .run(
+            &container_progress,
+            "adding the ephemeral container",
+            |_| add_ephemeral_container(&pod_api, runtime_data, &ephemeral_container),
+        )
+        .await?;
+
     let watcher_config = watcher::Config::default()
         .fields(&format!("metadata.name={}", &runtime_data.pod_name))
         .timeout(60);
@@ -117,17 +166,24 @@ where
 
     let mut container_progress = progress.subtask("waiting for container to be ready...");
 
-    let stream = watcher(pod_api.clone(), watcher_config).applied_objects();
-    pin!(stream);
-
-    while let Some(Ok(pod)) = stream.next().await {
-        if is_ephemeral_container_running(pod, &params.name) {
-            debug!("container ready");
-            break;
-        } else {
-            debug!("container not ready yet");
-        }
-    }
+    let stream = watcher(pod_api.clone(), watcher_config)
+        .default_backoff()
+        .applied_objects();
+    watch_until(
+        stream,
+        Duration::from_secs(variant.agent_config().startup_timeout),
+        "waiting for the ephemeral container to run",
+        |pod| {
+            let running = is_ephemeral_container_running(pod, &params.name);
+            if running {
+                debug!("container ready");
+            } else {
+                debug!("container not ready yet");
+            }
+            running
+        },
+    )
+    .await?;
 
     let version =
         wait_for_agent_startup(&pod_api, &runtime_data.pod_name, params.name.clone()).await?;
@@ -147,6 +203,7 @@ where
     debug!("container is ready");
     Ok(AgentKubernetesConnectInfo {
         pod_name: runtime_data.pod_name.to_string(),
+        agent_container: params.name.clone(),
         agent_port: params.port,
         namespace: runtime_data.pod_namespace.clone(),
         agent_version: version,
@@ -202,18 +259,28 @@ impl ContainerVariant for EphemeralTargetedVariant<'_> {
         } = self;
         let env = agent_env(agent, params);
 
+        let mut security_context = json!({
+            "runAsGroup": params.gid,
+            "capabilities": {
+                "add": get_capabilities(agent),
+            },
+            "privileged": agent.privileged,
+            "runAsNonRoot": agent.privileged.then_some(false),
+            "runAsUser": agent.privileged.then_some(0),
+        });
+        if let Some(openshift) = &params.openshift
+            && let Value::Object(fields) = openshift_security_context(agent, openshift, true)
+        {
+            security_context
+                .as_object_mut()
+                .expect("security context is an object")
+                .extend(fields);
+        }
+
         serde_json::from_value(json!({
             "name": params.name,
             "image": agent.image(),
-            "securityContext": {
-                "runAsGroup": params.gid,
-                "capabilities": {
-                    "add": get_capabilities(agent),
-                },
-                "privileged": agent.privileged,
-                "runAsNonRoot": agent.privileged.then_some(false),
-                "runAsUser": agent.privileged.then_some(0),
-            },
+            "securityContext": security_context,
             "imagePullPolicy": agent.image_pull_policy,
             "targetContainerName": runtime_data.container_name,
             "env": env,
@@ -222,3 +289,54 @@ impl ContainerVariant for EphemeralTargetedVariant<'_> {
         .map_err(KubeApiError::from)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use http::{Method, Request, Response};
+    use hyper::Body;
+
+    use super::*;
+    use crate::api::runtime::ContainerRuntime;
+
+    /// Adding the same container again (e.g. when retrying the agent creation) doesn't touch the
+    /// pod, the API server only gets the `GET` for the `ephemeralcontainers` subresource.
+    #[tokio::test]
+    async fn container_already_added() {
+        let service = tower::service_fn(|request: Request<Body>| async move {
+            assert_eq!(request.method(), Method::GET, "{}", request.uri());
+
+            let pod = json!({
+                "apiVersion": "v1",
+                "kind": "Pod",
+                "metadata": { "name": "app", "namespace": "default" },
+                "spec": {
+                    "containers": [],
+                    "ephemeralContainers": [{ "name": "mirrord-agent-abc" }],
+                },
+            });
+            Ok::<_, Infallible>(Response::new(Body::from(pod.to_string())))
+        });
+        let pod_api = Api::<Pod>::namespaced(Client::new(service, "default"), "default");
+
+        let runtime_data = RuntimeData {
+            pod_name: "app".into(),
+            pod_namespace: Some("default".into()),
+            node_name: "node".into(),
+            container_id: "id".into(),
+            container_runtime: ContainerRuntime::Containerd,
+            container_name: "app".into(),
+            mesh: None,
+            env_containers: vec![],
+        };
+        let ephemeral_container = KubeEphemeralContainer {
+            name: "mirrord-agent-abc".into(),
+            ..Default::default()
+        };
+
+        add_ephemeral_container(&pod_api, &runtime_data, &ephemeral_container)
+            .await
+            .unwrap();
+    }
+}
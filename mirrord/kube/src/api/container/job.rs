@@ -1,5 +1,9 @@
-use futures::StreamExt;
-use k8s_openapi::api::{batch::v1::Job, core::v1::Pod};
+use std::time::Duration;
+
+use k8s_openapi::{
+    api::{batch::v1::Job, core::v1::Pod},
+    DeepMerge,
+};
 use kube::{
     api::{ListParams, PostParams},
     runtime::{watcher, WatchStreamExt},
@@ -8,17 +12,17 @@ use kube::{
 use mirrord_config::agent::AgentConfig;
 use mirrord_progress::Progress;
 use serde_json::json;
-use tokio::pin;
 use tracing::debug;
 
 use crate::{
     api::{
         container::{
-            pod::{PodTargetedVariant, PodVariant},
-            util::wait_for_agent_startup,
+            pod::{PodSidecarVariant, PodTargetedVariant, PodVariant},
+            util::{wait_for_agent_startup, watch_until},
             ContainerParams, ContainerVariant,
         },
         kubernetes::{get_k8s_resource_api, AgentKubernetesConnectInfo},
+        retry::RetryPolicy,
         runtime::RuntimeData,
     },
     error::{KubeApiError, Result},
@@ -40,11 +44,22 @@ where
     let agent_pod: Job = variant.as_update()?;
 
     let job_api = get_k8s_resource_api(client, agent.namespace.as_deref());
-
-    job_api
-        .create(&PostParams::default(), &agent_pod)
-        .await
-        .map_err(KubeApiError::KubeError)?;
+    let retry_policy = RetryPolicy::new(agent);
+
+    retry_policy
+        .run(&pod_progress, "creating the agent job", |_| {
+            let (job_api, agent_pod) = (&job_api, &agent_pod);
+            async move {
+                match job_api.create(&PostParams::default(), agent_pod).await {
+                    // The job's name is random, so it was created by an earlier attempt (or an
+                    // earlier call, see `KubernetesAPI::create_agent`), but we didn't get the
+                    // response.
+                    Err(kube::Error::Api(response)) if response.code == 409 => Ok(()),
+                    result => result.map(drop).map_err(KubeApiError::KubeError),
+                }
+            }
+        })
+        .await?;
 
     let watcher_config = watcher::Config::default()
         .labels(&format!("job-name={}", params.name))
@@ -56,24 +71,30 @@ where
 
     let pod_api: Api<Pod> = get_k8s_resource_api(client, agent.namespace.as_deref());
 
-    let stream = watcher(pod_api.clone(), watcher_config).applied_objects();
-    pin!(stream);
-
-    while let Some(Ok(pod)) = stream.next().await {
-        if let Some(status) = &pod.status
-            && let Some(phase) = &status.phase
-        {
+    let stream = watcher(pod_api.clone(), watcher_config)
+        .default_backoff()
+        .applied_objects();
+    watch_until(
+        stream,
+        Duration::from_secs(agent.startup_timeout),
+        "waiting for the agent pod to run",
+        |pod| {
+            let phase = pod.status.and_then(|status| status.phase);
             debug!("Pod Phase = {phase:?}");
-            if phase == "Running" {
-                break;
-            }
-        }
-    }
-
-    let pods = pod_api
-        .list(&ListParams::default().labels(&format!("job-name={}", params.name)))
-        .await
-        .map_err(KubeApiError::KubeError)?;
+            phase.as_deref() == Some("Running")
+        },
+    )
+    .await?;
+
+    let list_params = ListParams::default().labels(&format!("job-name={}", params.name));
+    let pods = retry_policy
+        .run(&pod_progress, "listing the agent pods", |_| async {
+            pod_api
+                .list(&list_params)
+                .await
+                .map_err(KubeApiError::KubeError)
+        })
+        .await?;
 
     let pod_name = pods
         .items
@@ -97,6 +118,7 @@ where
 
     Ok(AgentKubernetesConnectInfo {
         pod_name,
+        agent_container: "mirrord-agent".to_string(),
         agent_port: params.port,
         namespace: agent.namespace.clone(),
         agent_version: version,
@@ -115,6 +137,20 @@ impl<'c> JobVariant<PodVariant<'c>> {
     }
 }
 
+impl<'c> JobVariant<PodSidecarVariant<'c>> {
+    /// See [`PodSidecarVariant`].
+    pub fn sidecar(
+        agent: &'c AgentConfig,
+        params: &'c ContainerParams,
+        runtime_data: &'c RuntimeData,
+        target_pod: &'c Pod,
+    ) -> Self {
+        JobVariant {
+            inner: PodSidecarVariant::new(agent, params, runtime_data, target_pod),
+        }
+    }
+}
+
 impl<T> ContainerVariant for JobVariant<T>
 where
     T: ContainerVariant<Update = Pod>,
@@ -133,6 +169,11 @@ where
         let agent = self.agent_config();
         let params = self.params();
 
+        let mut template = self.inner.as_update()?;
+        if let Some(patch) = &agent.pod_template_patch {
+            template.merge_from(serde_json::from_value(patch.clone())?);
+        }
+
         serde_json::from_value(json!({
             "metadata": {
                 "name": params.name,
@@ -148,7 +189,7 @@ where
             },
             "spec": {
                 "ttlSecondsAfterFinished": agent.ttl,
-                "template": self.inner.as_update()?
+                "template": template
             }
         }))
         .map_err(KubeApiError::from)
@@ -199,7 +240,10 @@ mod test {
 
     use super::*;
     use crate::api::{
-        container::util::{get_capabilities, DEFAULT_TOLERATIONS},
+        container::{
+            util::{get_capabilities, DEFAULT_TOLERATIONS},
+            OpenShiftParams,
+        },
         runtime::ContainerRuntime,
     };
 
@@ -212,6 +256,7 @@ mod test {
             port: 3000,
             gid: 13,
             tls_cert: None,
+            openshift: None,
         };
 
         let update = JobVariant::new(&agent, &params).as_update()?;
@@ -292,6 +337,7 @@ mod test {
             port: 3000,
             gid: 13,
             tls_cert: None,
+            openshift: None,
         };
 
         let update = JobTargetedVariant::new(
@@ -305,6 +351,7 @@ mod test {
                 container_id: "container".to_string(),
                 container_runtime: ContainerRuntime::Docker,
                 container_name: "foo".to_string(),
+                env_containers: vec![],
             },
         )
         .as_update()?;
@@ -408,4 +455,281 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn targetless_openshift() -> Result<(), Box<dyn std::error::Error>> {
+        let mut config_context = ConfigContext::default();
+        let agent = AgentFileConfig::default().generate_config(&mut config_context)?;
+        let params = ContainerParams {
+            name: "foobar".to_string(),
+            port: 3000,
+            gid: 13,
+            tls_cert: None,
+            openshift: Some(OpenShiftParams {
+                run_as_user: Some(1000680000),
+            }),
+        };
+
+        let update = JobVariant::new(&agent, &params).as_update()?;
+        let container = update
+            .spec
+            .and_then(|spec| spec.template.spec)
+            .and_then(|spec| spec.containers.into_iter().next())
+            .ok_or("missing agent container")?;
+
+        assert_eq!(
+            container.security_context,
+            Some(serde_json::from_value(json!({
+                "allowPrivilegeEscalation": false,
+                "capabilities": {
+                    "drop": ["ALL"],
+                },
+                "runAsNonRoot": true,
+                "runAsUser": 1000680000,
+                "seccompProfile": {
+                    "type": "RuntimeDefault",
+                },
+            }))?)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn targetless_scheduling() -> Result<(), Box<dyn std::error::Error>> {
+        let mut config_context = ConfigContext::default();
+        let agent = AgentFileConfig {
+            node_selector: Some([("pool".to_string(), "tools".to_string())].into()),
+            affinity: Some(serde_json::from_value(json!({
+                "nodeAffinity": {
+                    "requiredDuringSchedulingIgnoredDuringExecution": {
+                        "nodeSelectorTerms": [{
+                            "matchExpressions": [{
+                                "key": "kubernetes.io/arch",
+                                "operator": "In",
+                                "values": ["amd64"],
+                            }],
+                        }],
+                    },
+                },
+            }))?),
+            priority_class_name: Some("mirrord".to_string()),
+            ..Default::default()
+        }
+        .generate_config(&mut config_context)?;
+        let params = ContainerParams {
+            name: "foobar".to_string(),
+            port: 3000,
+            gid: 13,
+            tls_cert: None,
+            openshift: None,
+        };
+
+        let spec = JobVariant::new(&agent, &params)
+            .as_update()?
+            .spec
+            .and_then(|spec| spec.template.spec)
+            .ok_or("missing pod spec")?;
+
+        assert_eq!(spec.node_selector, agent.node_selector);
+        assert_eq!(spec.affinity, agent.affinity);
+        assert_eq!(spec.priority_class_name.as_deref(), Some("mirrord"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pod_template_patch() -> Result<(), Box<dyn std::error::Error>> {
+        let mut config_context = ConfigContext::default();
+        let agent = AgentFileConfig {
+            pod_template_patch: Some(json!({
+                "metadata": {
+                    "annotations": { "team": "tools" }
+                },
+                "spec": {
+                    "containers": [
+                        {
+                            "name": "mirrord-agent",
+                            "securityContext": { "runAsNonRoot": true }
+                        },
+                        {
+                            "name": "proxy",
+                            "image": "proxy"
+                        }
+                    ]
+                }
+            })),
+            ..Default::default()
+        }
+        .generate_config(&mut config_context)?;
+        let params = ContainerParams {
+            name: "foobar".to_string(),
+            port: 3000,
+            gid: 13,
+            tls_cert: None,
+            openshift: None,
+        };
+
+        let template = JobVariant::new(&agent, &params)
+            .as_update()?
+            .spec
+            .map(|spec| spec.template)
+            .ok_or("missing pod template")?;
+
+        let annotations = template
+            .metadata
+            .and_then(|metadata| metadata.annotations)
+            .unwrap_or_default();
+        assert_eq!(annotations.get("team").map(String::as_str), Some("tools"));
+        assert_eq!(
+            annotations
+                .get("sidecar.istio.io/inject")
+                .map(String::as_str),
+            Some("false")
+        );
+
+        let containers = template.spec.ok_or("missing pod spec")?.containers;
+        let [agent_container, proxy] = containers.as_slice() else {
+            panic!("expected the agent and the patched in container: {containers:?}");
+        };
+        assert_eq!(agent_container.name, "mirrord-agent");
+        assert_eq!(
+            agent_container.command.as_deref(),
+            Some(&["./mirrord-agent", "-l", "3000", "targetless"].map(String::from)[..])
+        );
+        assert_eq!(
+            agent_container
+                .security_context
+                .as_ref()
+                .and_then(|context| context.run_as_non_root),
+            Some(true)
+        );
+        assert_eq!(proxy.name, "proxy");
+
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar() -> Result<(), Box<dyn std::error::Error>> {
+        let mut config_context = ConfigContext::default();
+        let agent = AgentFileConfig::default().generate_config(&mut config_context)?;
+        let params = ContainerParams {
+            name: "foobar".to_string(),
+            port: 3000,
+            gid: 13,
+            tls_cert: None,
+            openshift: None,
+        };
+        let runtime_data = RuntimeData {
+            mesh: None,
+            pod_name: "pod".to_string(),
+            pod_namespace: None,
+            node_name: "fargate-foobaz".to_string(),
+            container_id: "container".to_string(),
+            container_runtime: ContainerRuntime::Containerd,
+            container_name: "foo".to_string(),
+            env_containers: vec![],
+        };
+        let target_pod: Pod = serde_json::from_value(json!({
+            "metadata": {
+                "name": "pod",
+                "labels": { "app": "foo" }
+            },
+            "spec": {
+                "serviceAccountName": "foo-sa",
+                "volumes": [{ "name": "config", "configMap": { "name": "foo-config" } }],
+                "containers": [
+                    {
+                        "name": "sidecar",
+                        "image": "sidecar"
+                    },
+                    {
+                        "name": "foo",
+                        "image": "foo",
+                        "env": [{ "name": "FOO", "value": "bar" }],
+                        "volumeMounts": [{ "name": "config", "mountPath": "/config" }],
+                        "ports": [
+                            { "name": "http", "containerPort": 8080 },
+                            { "name": "dns", "containerPort": 5353, "protocol": "UDP" }
+                        ]
+                    }
+                ]
+            },
+            "status": {
+                "podIP": "10.0.0.5"
+            }
+        }))?;
+
+        let template = JobVariant::sidecar(&agent, &params, &runtime_data, &target_pod)
+            .as_update()?
+            .spec
+            .map(|spec| spec.template)
+            .ok_or("missing pod template")?;
+
+        let labels = template.metadata.and_then(|metadata| metadata.labels);
+        assert_eq!(
+            labels.as_ref().and_then(|labels| labels.get("app")),
+            Some(&"foo".to_string())
+        );
+
+        let spec = template.spec.ok_or("missing pod spec")?;
+        assert_eq!(spec.service_account_name.as_deref(), Some("foo-sa"));
+        assert_eq!(spec.node_name, None);
+        assert_eq!(spec.host_pid, None);
+        assert_eq!(spec.volumes.as_ref().map(Vec::len), Some(1));
+
+        let [container] = spec.containers.as_slice() else {
+            panic!("expected only the agent container: {:?}", spec.containers);
+        };
+        assert_eq!(
+            container.command.as_deref(),
+            Some(
+                &[
+                    "./mirrord-agent",
+                    "-l",
+                    "3000",
+                    "sidecar",
+                    "--forward-to",
+                    "10.0.0.5",
+                    "--forward-port",
+                    "8080"
+                ]
+                .map(String::from)[..]
+            )
+        );
+        assert_eq!(container.security_context, None);
+        assert_eq!(container.volume_mounts.as_ref().map(Vec::len), Some(1));
+        assert_eq!(container.ports.as_ref().map(Vec::len), Some(2));
+
+        let env = container.env.as_deref().unwrap_or_default();
+        assert!(env
+            .iter()
+            .any(|var| var.name == "FOO" && var.value.as_deref() == Some("bar")));
+        assert!(env.iter().any(|var| var.name == "MIRRORD_AGENT_POD_IP"));
+
+        // Without the target's IP nothing is forwarded, so services must not send traffic to the
+        // copy.
+        let target_pod = Pod {
+            status: None,
+            ..target_pod
+        };
+        let template = JobVariant::sidecar(&agent, &params, &runtime_data, &target_pod)
+            .as_update()?
+            .spec
+            .map(|spec| spec.template)
+            .ok_or("missing pod template")?;
+
+        let labels = template.metadata.and_then(|metadata| metadata.labels);
+        assert_eq!(labels.as_ref().and_then(|labels| labels.get("app")), None);
+
+        let spec = template.spec.ok_or("missing pod spec")?;
+        assert_eq!(
+            spec.containers
+                .first()
+                .and_then(|container| container.command.as_deref()),
+            Some(&["./mirrord-agent", "-l", "3000", "sidecar"].map(String::from)[..])
+        );
+
+        Ok(())
+    }
 }
@@ -6,14 +6,38 @@ use super::util::agent_env;
 use crate::{
     api::{
         container::{
-            util::{base_command_line, get_capabilities, DEFAULT_TOLERATIONS},
-            ContainerParams, ContainerVariant,
+            util::{
+                base_command_line, get_capabilities, openshift_security_context,
+                DEFAULT_TOLERATIONS,
+            },
+            ContainerParams, ContainerVariant, OpenShiftParams,
         },
         runtime::RuntimeData,
     },
     error::{KubeApiError, Result},
 };
 
+/// Update of the agent container for [`ContainerParams::openshift`].
+fn openshift_update(
+    agent: &AgentConfig,
+    openshift: &OpenShiftParams,
+    targeted: bool,
+) -> Result<Pod> {
+    let security_context = openshift_security_context(agent, openshift, targeted);
+
+    serde_json::from_value(json!({
+        "spec": {
+            "containers": [
+                {
+                    "name": "mirrord-agent",
+                    "securityContext": security_context,
+                }
+            ]
+        }
+    }))
+    .map_err(KubeApiError::from)
+}
+
 pub struct PodVariant<'c> {
     agent: &'c AgentConfig,
     command_line: Vec<String>,
@@ -40,20 +64,9 @@ impl<'c> PodVariant<'c> {
             params,
         }
     }
-}
-
-impl ContainerVariant for PodVariant<'_> {
-    type Update = Pod;
 
-    fn agent_config(&self) -> &AgentConfig {
-        self.agent
-    }
-
-    fn params(&self) -> &ContainerParams {
-        self.params
-    }
-
-    fn as_update(&self) -> Result<Pod> {
+    /// The pod spec shared by targetless and targeted agents.
+    fn base_pod(&self) -> Result<Pod> {
         let PodVariant {
             agent,
             command_line,
@@ -96,6 +109,7 @@ impl ContainerVariant for PodVariant<'_> {
                 "restartPolicy": "Never",
                 "imagePullSecrets": agent.image_pull_secrets,
                 "tolerations": tolerations,
+                "priorityClassName": agent.priority_class_name,
                 "containers": [
                     {
                         "name": "mirrord-agent",
@@ -113,6 +127,170 @@ impl ContainerVariant for PodVariant<'_> {
     }
 }
 
+impl ContainerVariant for PodVariant<'_> {
+    type Update = Pod;
+
+    fn agent_config(&self) -> &AgentConfig {
+        self.agent
+    }
+
+    fn params(&self) -> &ContainerParams {
+        self.params
+    }
+
+    fn as_update(&self) -> Result<Pod> {
+        let mut pod = self.base_pod()?;
+
+        // Targeted agents have a `nodeName`, these could only make them fail admission.
+        pod.merge_from(serde_json::from_value(json!({
+            "spec": {
+                "nodeSelector": self.agent.node_selector,
+                "affinity": self.agent.affinity,
+            }
+        }))?);
+
+        if let Some(openshift) = &self.params.openshift {
+            pod.merge_from(openshift_update(self.agent, openshift, false)?);
+        }
+
+        Ok(pod)
+    }
+}
+
+/// Agent in a copy of the target pod, in place of the target container, see
+/// [`AgentConfig::sidecar`].
+///
+/// Unprivileged, it gets the target's service account, scheduling constraints and volumes, and
+/// the target container's environment, volume mounts and ports.
+///
+/// When the target pod has an IP, the agent is a reverse proxy in front of it: it forwards the
+/// connections to the target container's ports that it doesn't steal, and the unmatched HTTP
+/// requests, to the target pod. Only then the copy gets the target's labels, so that services
+/// send traffic to it too, as none of that traffic is lost.
+pub struct PodSidecarVariant<'c> {
+    inner: PodVariant<'c>,
+    runtime_data: &'c RuntimeData,
+    target_pod: &'c Pod,
+    /// Whether the agent forwards the traffic it doesn't steal to the target pod.
+    forwards: bool,
+}
+
+impl<'c> PodSidecarVariant<'c> {
+    pub fn new(
+        agent: &'c AgentConfig,
+        params: &'c ContainerParams,
+        runtime_data: &'c RuntimeData,
+        target_pod: &'c Pod,
+    ) -> Self {
+        let mut command_line = base_command_line(agent, params);
+
+        command_line.push("sidecar".to_owned());
+
+        let target_ip = target_pod
+            .status
+            .as_ref()
+            .and_then(|status| status.pod_ip.clone());
+        if let Some(target_ip) = &target_ip {
+            command_line.extend(["--forward-to".to_owned(), target_ip.clone()]);
+
+            let target_ports = target_pod
+                .spec
+                .iter()
+                .flat_map(|spec| &spec.containers)
+                .filter(|container| container.name == runtime_data.container_name)
+                .flat_map(|container| container.ports.iter().flatten())
+                .filter(|port| port.protocol.as_deref().unwrap_or("TCP") == "TCP");
+            for port in target_ports {
+                command_line.push("--forward-port".to_owned());
+                command_line.push(port.container_port.to_string());
+            }
+        }
+
+        PodSidecarVariant {
+            inner: PodVariant::with_command_line(agent, params, command_line),
+            runtime_data,
+            target_pod,
+            forwards: target_ip.is_some(),
+        }
+    }
+}
+
+impl ContainerVariant for PodSidecarVariant<'_> {
+    type Update = Pod;
+
+    fn agent_config(&self) -> &AgentConfig {
+        self.inner.agent_config()
+    }
+
+    fn params(&self) -> &ContainerParams {
+        self.inner.params()
+    }
+
+    fn as_update(&self) -> Result<Pod> {
+        let PodSidecarVariant {
+            runtime_data,
+            target_pod,
+            forwards,
+            ..
+        } = self;
+
+        let target_spec = target_pod
+            .spec
+            .as_ref()
+            .ok_or(KubeApiError::PodSpecNotFound)?;
+        let target_container = target_spec
+            .containers
+            .iter()
+            .find(|container| container.name == runtime_data.container_name)
+            .ok_or_else(|| KubeApiError::ContainerNotFound(runtime_data.container_name.clone()))?;
+
+        let mut pod = self.inner.base_pod()?;
+
+        // Without forwarding, the traffic that services would send to the copy and the agent
+        // doesn't steal would be lost.
+        if *forwards {
+            pod.metadata
+                .labels
+                .get_or_insert_with(Default::default)
+                .extend(target_pod.metadata.labels.clone().unwrap_or_default());
+        }
+
+        let spec = pod.spec.get_or_insert_with(Default::default);
+        spec.service_account_name = target_spec.service_account_name.clone();
+        spec.node_selector = target_spec.node_selector.clone();
+        spec.affinity = target_spec.affinity.clone();
+        spec.volumes = target_spec.volumes.clone();
+        spec.tolerations
+            .get_or_insert_with(Default::default)
+            .extend(target_spec.tolerations.iter().flatten().cloned());
+
+        if let Some(container) = spec.containers.first_mut() {
+            // The target's environment overrides the agent's, like with ephemeral agents.
+            let mut env = container.env.take().unwrap_or_default();
+            env.extend(target_container.env.iter().flatten().cloned());
+            env.push(serde_json::from_value(json!({
+                "name": "MIRRORD_AGENT_POD_IP",
+                "valueFrom": {
+                    "fieldRef": {
+                        "fieldPath": "status.podIP"
+                    }
+                }
+            }))?);
+
+            container.env = Some(env);
+            container.env_from = target_container.env_from.clone();
+            container.volume_mounts = target_container.volume_mounts.clone();
+            container.ports = target_container.ports.clone();
+        }
+
+        if let Some(openshift) = &self.params().openshift {
+            pod.merge_from(openshift_update(self.agent_config(), openshift, false)?);
+        }
+
+        Ok(pod)
+    }
+}
+
 pub struct PodTargetedVariant<'c> {
     inner: PodVariant<'c>,
     runtime_data: &'c RuntimeData,
@@ -138,6 +316,14 @@ impl<'c> PodTargetedVariant<'c> {
             command_line.extend(["--mesh".to_string(), mesh.to_string()]);
         }
 
+        for (container_id, prefix) in &runtime_data.env_containers {
+            let arg = match prefix {
+                Some(prefix) => format!("{container_id}={prefix}"),
+                None => container_id.clone(),
+            };
+            command_line.extend(["--env-container".to_string(), arg]);
+        }
+
         let inner = PodVariant::with_command_line(agent, params, command_line);
 
         PodTargetedVariant {
@@ -207,8 +393,13 @@ impl ContainerVariant for PodTargetedVariant<'_> {
             }
         }))?;
 
-        let mut pod = self.inner.as_update()?;
+        let mut pod = self.inner.base_pod()?;
         pod.merge_from(update);
+
+        if let Some(openshift) = &params.openshift {
+            pod.merge_from(openshift_update(agent, openshift, true)?);
+        }
+
         Ok(pod)
     }
 }
@@ -1,15 +1,24 @@
-use std::sync::LazyLock;
+use std::{sync::LazyLock, time::Duration};
 
-use futures::{AsyncBufReadExt, TryStreamExt};
+use futures::{AsyncBufReadExt, Stream, StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::{Pod, Toleration};
-use kube::{api::LogParams, Api};
+use kube::{api::LogParams, runtime::watcher, Api};
 use mirrord_config::agent::{AgentConfig, LinuxCapability};
+use mirrord_progress::timings::{self, StartupPhase};
 use mirrord_protocol::AGENT_OPERATOR_CERT_ENV;
 use regex::Regex;
 use serde_json::{json, Value};
+use tokio::pin;
 use tracing::warn;
 
-use crate::{api::container::ContainerParams, error::Result};
+use crate::{
+    api::{
+        container::{ContainerParams, OpenShiftParams},
+        credentials::unauthorized_watch,
+        retry::is_transient_watch,
+    },
+    error::{KubeApiError, Result},
+};
 
 static AGENT_READY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new("agent ready( - version (\\S+))?").expect("failed to create regex")
@@ -33,6 +42,52 @@ pub(super) fn get_capabilities(agent: &AgentConfig) -> Vec<LinuxCapability> {
         .collect()
 }
 
+/// Annotation with the UIDs OpenShift allows in a namespace, e.g. `1000680000/10000`.
+pub(crate) const OPENSHIFT_UID_RANGE_ANNOTATION: &str = "openshift.io/sa.scc.uid-range";
+
+/// Returns the first UID of an [`OPENSHIFT_UID_RANGE_ANNOTATION`], which is either
+/// `<first>/<size>` or `<first>-<last>`.
+pub(crate) fn parse_openshift_uid_range(range: &str) -> Option<i64> {
+    range
+        .split(['/', '-'])
+        .next()
+        .and_then(|first| first.trim().parse().ok())
+}
+
+/// `securityContext` of the agent container, for passing OpenShift's security context
+/// constraints.
+///
+/// Targetless agents need no capabilities and don't run as root, so they pass the `restricted`
+/// SCCs. Targeted agents still need their capabilities, but drop everything else.
+pub(super) fn openshift_security_context(
+    agent: &AgentConfig,
+    openshift: &OpenShiftParams,
+    targeted: bool,
+) -> Value {
+    if targeted {
+        json!({
+            "capabilities": {
+                "drop": ["ALL"],
+                "add": get_capabilities(agent),
+            },
+            // Privileged containers are not confined by seccomp anyway.
+            "seccompProfile": (!agent.privileged).then(|| json!({ "type": "RuntimeDefault" })),
+        })
+    } else {
+        json!({
+            "allowPrivilegeEscalation": false,
+            "capabilities": {
+                "drop": ["ALL"],
+            },
+            "runAsNonRoot": true,
+            "runAsUser": openshift.run_as_user,
+            "seccompProfile": {
+                "type": "RuntimeDefault",
+            },
+        })
+    }
+}
+
 /// Builds mirrord agent environment variables.
 pub(super) fn agent_env(agent: &AgentConfig, params: &&ContainerParams) -> Vec<Value> {
     let mut env = vec![
@@ -78,6 +133,13 @@ pub(super) fn base_command_line(agent: &AgentConfig, params: &ContainerParams) -
         command_line.push("-t".to_owned());
         command_line.push(timeout.to_string());
     }
+    if let Some(timeout) = agent.pause_timeout {
+        command_line.push("--pause-timeout".to_owned());
+        command_line.push(timeout.to_string());
+    }
+    if agent.remote_exec {
+        command_line.push("--remote-exec".to_owned());
+    }
 
     #[cfg(debug_assertions)]
     if agent.test_error {
@@ -97,6 +159,9 @@ pub(super) async fn wait_for_agent_startup(
     pod_name: &str,
     container_name: String,
 ) -> Result<Option<String>> {
+    // The logs can only be read once the container is running.
+    timings::mark(StartupPhase::AgentSchedule);
+
     let logs = pod_api
         .log_stream(
             pod_name,
@@ -115,13 +180,64 @@ pub(super) async fn wait_for_agent_startup(
         };
 
         let version = captures.get(2).map(|m| m.as_str().to_string());
+        timings::mark(StartupPhase::AgentReady);
         return Ok(version);
     }
 
     warn!("Agent did not print 'agent ready' message");
+    timings::mark(StartupPhase::AgentReady);
     Ok(None)
 }
 
+/// Waits until `ready` accepts an object from the `stream` of a [`watcher`], for up to `timeout`.
+///
+/// The watcher restarts with its backoff after [transient](is_transient_watch) errors, other
+/// errors fail the wait. So does the API server rejecting our credentials (see
+/// [`unauthorized_watch`]), `KubernetesAPI::create_agent` refreshes them and watches again. `what`
+/// describes the wait, e.g. `"waiting for the agent pod"`.
+pub(super) async fn watch_until<K, S>(
+    stream: S,
+    timeout: Duration,
+    what: &str,
+    mut ready: impl FnMut(K) -> bool,
+) -> Result<()>
+where
+    S: Stream<Item = Result<K, watcher::Error>>,
+{
+    let wait = async {
+        pin!(stream);
+
+        while let Some(object) = stream.next().await {
+            let object = match object {
+                Ok(object) => object,
+                Err(error) => match unauthorized_watch(error) {
+                    Ok(error) => return Err(KubeApiError::KubeError(error)),
+                    Err(error) if is_transient_watch(&error) => {
+                        warn!(%error, what, "watch failed, retrying");
+                        continue;
+                    }
+                    Err(error) => return Err(KubeApiError::WatchFailed(what.to_string(), error)),
+                },
+            };
+
+            if ready(object) {
+                break;
+            }
+        }
+
+        Ok(())
+    };
+
+    tokio::time::timeout(timeout, wait)
+        .await
+        .unwrap_or_else(|_| {
+            Err(KubeApiError::KubeApiTimeout(
+                what.to_string(),
+                timeout.as_secs(),
+            ))
+        })
+}
+
 #[cfg(test)]
 mod test {
     use rstest::rstest;
@@ -136,4 +252,60 @@ mod test {
 
         assert_eq!(captures.get(2).map(|c| c.as_str()), version);
     }
+
+    fn watch_error(code: u16) -> watcher::Error {
+        watcher::Error::WatchError(kube::core::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "error".to_string(),
+            reason: "error".to_string(),
+            code,
+        })
+    }
+
+    #[tokio::test]
+    async fn watch_waits_out_transient_errors() {
+        let stream = futures::stream::iter([Err(watch_error(503)), Ok(1), Ok(2), Ok(3)]);
+        let mut seen = Vec::new();
+
+        watch_until(stream, Duration::from_secs(5), "test", |object| {
+            seen.push(object);
+            object == 2
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(seen, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn watch_fails_on_other_errors() {
+        let stream = futures::stream::iter([Err(watch_error(403)), Ok(1)]);
+
+        let result = watch_until(stream, Duration::from_secs(5), "test", |_| true).await;
+
+        assert!(
+            matches!(result, Err(KubeApiError::WatchFailed(..))),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_times_out() {
+        let stream = futures::stream::pending::<Result<(), watcher::Error>>();
+
+        let result = watch_until(stream, Duration::from_millis(100), "test", |_| true).await;
+
+        assert!(
+            matches!(result, Err(KubeApiError::KubeApiTimeout(..))),
+            "{result:?}"
+        );
+    }
+
+    #[rstest]
+    #[case("1000680000/10000", Some(1000680000))]
+    #[case("1000680000-1000689999", Some(1000680000))]
+    #[case("", None)]
+    fn openshift_uid_range(#[case] range: &str, #[case] first: Option<i64>) {
+        assert_eq!(parse_openshift_uid_range(range), first);
+    }
 }
@@ -0,0 +1,311 @@
+//! Keeps kube [`Client`]s usable for the whole session.
+//!
+//! Clusters like EKS, GKE and AKS hand out short lived tokens through kubeconfig exec plugins
+//! (`aws eks get-token`, `gke-gcloud-auth-plugin`, `kubelogin`). Connections that are already
+//! established stay open when the token expires, but dialing a new one (e.g. re-establishing the
+//! agent port-forward or the operator websocket) fails with `401 Unauthorized`. Recreating the
+//! [`Client`] runs the exec plugin again, which gets us a fresh token.
+//!
+//! Exec plugins can be slow (they often call out to the cloud provider), so the [`Client`]s are
+//! cached per [`KubeClientSettings`] (i.e. per kubeconfig and context), and shared by all the
+//! [`RefreshingClient`]s in the process.
+//!
+//! With the `incluster` feature, we use the projected service account token, which kubelet
+//! rotates on disk before it expires. The [`Client`] is recreated whenever the token file changes,
+//! so it never holds on to an expired token.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use kube::{client::UpgradeConnectionError, runtime::watcher, Client};
+use mirrord_config::LayerConfig;
+use tracing::{debug, warn};
+
+use crate::{
+    api::kubernetes::create_kube_api,
+    error::{KubeApiError, Result},
+};
+
+/// [`Client`]s shared by all [`RefreshingClient`]s.
+static CLIENTS: LazyLock<Mutex<HashMap<KubeClientSettings, CachedClient>>> =
+    LazyLock::new(Default::default);
+
+/// Where kubelet keeps the projected service account token of the pod we run in.
+#[cfg(feature = "incluster")]
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+#[derive(Clone)]
+struct CachedClient {
+    client: Client,
+    created_at: Instant,
+    /// [`token_modified`] when the [`Client`] was created.
+    token_modified: Option<SystemTime>,
+}
+
+impl CachedClient {
+    /// `token_modified` should be checked before the `client` is created, so that we don't miss a
+    /// rotation that happens in the meantime.
+    fn new(client: Client, token_modified: Option<SystemTime>) -> Self {
+        Self {
+            client,
+            created_at: Instant::now(),
+            token_modified,
+        }
+    }
+}
+
+/// When the service account token we run with was last rotated. Always [`None`] outside of the
+/// cluster.
+fn token_modified() -> Option<SystemTime> {
+    #[cfg(feature = "incluster")]
+    {
+        std::fs::metadata(SERVICE_ACCOUNT_TOKEN)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    #[cfg(not(feature = "incluster"))]
+    {
+        None
+    }
+}
+
+/// Everything we need to create a kube [`Client`] again, with fresh credentials.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct KubeClientSettings {
+    pub accept_invalid_certificates: bool,
+    pub kubeconfig: Option<String>,
+    pub kube_context: Option<String>,
+    pub proxy: Option<String>,
+    pub api_url: Option<String>,
+}
+
+impl KubeClientSettings {
+    /// Settings for the cluster where the agent (or the operator) runs, see
+    /// [`AgentConfig::kube_context`](mirrord_config::agent::AgentConfig::kube_context).
+    pub fn for_agent(config: &LayerConfig) -> Self {
+        Self::with_context(
+            config,
+            config
+                .agent
+                .kube_context
+                .clone()
+                .or_else(|| config.kube_context.clone()),
+        )
+    }
+
+    /// Settings for the cluster where the target is, see
+    /// [`TargetConfig::kube_context`](mirrord_config::target::TargetConfig::kube_context).
+    pub fn for_target(config: &LayerConfig) -> Self {
+        Self::with_context(
+            config,
+            config
+                .target
+                .kube_context
+                .clone()
+                .or_else(|| config.kube_context.clone()),
+        )
+    }
+
+    fn with_context(config: &LayerConfig, kube_context: Option<String>) -> Self {
+        Self {
+            accept_invalid_certificates: config.accept_invalid_certificates,
+            kubeconfig: config.kubeconfig.clone(),
+            kube_context,
+            proxy: config.proxy.clone(),
+            api_url: config.kube_api_url.clone(),
+        }
+    }
+
+    /// Creates a new [`Client`], see [`create_kube_api`].
+    pub async fn create_client(&self) -> Result<Client> {
+        create_kube_api(
+            self.accept_invalid_certificates,
+            self.kubeconfig.clone(),
+            self.kube_context.clone(),
+            self.proxy.clone(),
+            self.api_url.clone(),
+        )
+        .await
+    }
+}
+
+/// Checks if the API server rejected our credentials, in which case we should get new ones and
+/// try again.
+pub fn is_unauthorized(error: &kube::Error) -> bool {
+    match error {
+        kube::Error::Api(response) => response.code == 401,
+        kube::Error::UpgradeConnection(UpgradeConnectionError::ProtocolSwitch(status)) => {
+            status.as_u16() == 401
+        }
+        kube::Error::Auth(..) => true,
+        _ => false,
+    }
+}
+
+/// Returns the error of a [`watcher`] request that the API server rejected our credentials for
+/// (see [`is_unauthorized`]), other errors are given back.
+///
+/// The [`watcher`] keeps retrying with the same [`Client`], so it never recovers from these. The
+/// watch has to be started again with a refreshed one.
+pub fn unauthorized_watch(error: watcher::Error) -> Result<kube::Error, watcher::Error> {
+    match error {
+        watcher::Error::InitialListFailed(error)
+        | watcher::Error::WatchStartFailed(error)
+        | watcher::Error::WatchFailed(error)
+            if is_unauthorized(&error) =>
+        {
+            Ok(error)
+        }
+        watcher::Error::WatchError(response) if response.code == 401 => {
+            Ok(kube::Error::Api(response))
+        }
+        error => Err(error),
+    }
+}
+
+/// A [`Client`] that is recreated when its credentials get old or are rejected by the API
+/// server.
+///
+/// Use it for anything that dials new connections late into the session.
+pub struct RefreshingClient {
+    settings: KubeClientSettings,
+}
+
+impl RefreshingClient {
+    /// Clients older than this are recreated before use. EKS tokens are valid for 15 minutes,
+    /// GKE and AKS tokens for an hour, so this keeps us ahead of all of them.
+    pub const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+    /// Reuses the cached [`Client`] for these `settings`, or creates a new one.
+    pub async fn new(settings: KubeClientSettings) -> Result<Self> {
+        let refreshing = Self { settings };
+        refreshing.client().await?;
+
+        Ok(refreshing)
+    }
+
+    /// Wraps an already created `client`, which should come from the same `settings`.
+    pub fn with_client(settings: KubeClientSettings, client: Client) -> Self {
+        CLIENTS
+            .lock()
+            .expect("kube clients cache lock poisoned")
+            .insert(
+                settings.clone(),
+                CachedClient::new(client, token_modified()),
+            );
+
+        Self { settings }
+    }
+
+    /// Returns the current [`Client`], proactively recreating it when it's older than
+    /// [`Self::MAX_AGE`], or when the service account token was rotated since it was created.
+    pub async fn client(&self) -> Result<Client> {
+        let current = CLIENTS
+            .lock()
+            .expect("kube clients cache lock poisoned")
+            .get(&self.settings)
+            .cloned();
+
+        match current {
+            Some(cached) if cached.created_at.elapsed() >= Self::MAX_AGE => {
+                debug!("kube client credentials are getting old, refreshing");
+                self.refresh().await
+            }
+            Some(cached) if cached.token_modified != token_modified() => {
+                debug!("service account token was rotated, reloading it");
+                self.refresh().await
+            }
+            Some(cached) => Ok(cached.client),
+            None => self.refresh().await,
+        }
+    }
+
+    /// Recreates the [`Client`], which runs the kubeconfig exec plugin again.
+    pub async fn refresh(&self) -> Result<Client> {
+        let token_modified = token_modified();
+        let client = self.settings.create_client().await?;
+
+        CLIENTS
+            .lock()
+            .expect("kube clients cache lock poisoned")
+            .insert(
+                self.settings.clone(),
+                CachedClient::new(client.clone(), token_modified),
+            );
+
+        Ok(client)
+    }
+
+    /// Runs the `request`, and when it fails with [`is_unauthorized`], refreshes the credentials
+    /// and runs it once more.
+    pub async fn retry_unauthorized<T, E, F, Fut>(&self, mut request: F) -> Result<T>
+    where
+        E: Into<KubeApiError>,
+        F: FnMut(Client) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        match request(self.client().await?).await.map_err(Into::into) {
+            Err(KubeApiError::KubeError(error)) if is_unauthorized(&error) => {
+                warn!(%error, "kube credentials were rejected, refreshing them and retrying");
+
+                let client = self.refresh().await?;
+                request(client).await.map_err(Into::into)
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::StatusCode;
+    use kube::core::ErrorResponse;
+
+    use super::*;
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: String::new(),
+            reason: String::new(),
+            code,
+        })
+    }
+
+    #[test]
+    fn unauthorized_watch_errors() {
+        assert!(unauthorized_watch(watcher::Error::InitialListFailed(api_error(401))).is_ok());
+        assert!(unauthorized_watch(watcher::Error::WatchFailed(api_error(401))).is_ok());
+
+        let response = ErrorResponse {
+            status: "Failure".to_string(),
+            message: String::new(),
+            reason: "Unauthorized".to_string(),
+            code: 401,
+        };
+        assert!(matches!(
+            unauthorized_watch(watcher::Error::WatchError(response)),
+            Ok(kube::Error::Api(ErrorResponse { code: 401, .. }))
+        ));
+
+        assert!(unauthorized_watch(watcher::Error::WatchStartFailed(api_error(500))).is_err());
+        assert!(unauthorized_watch(watcher::Error::NoResourceVersion).is_err());
+    }
+
+    #[test]
+    fn unauthorized_errors() {
+        assert!(is_unauthorized(&api_error(401)));
+        assert!(is_unauthorized(&kube::Error::UpgradeConnection(
+            UpgradeConnectionError::ProtocolSwitch(StatusCode::UNAUTHORIZED)
+        )));
+
+        assert!(!is_unauthorized(&api_error(403)));
+        assert!(!is_unauthorized(&kube::Error::UpgradeConnection(
+            UpgradeConnectionError::ProtocolSwitch(StatusCode::SERVICE_UNAVAILABLE)
+        )));
+    }
+}
@@ -1,59 +1,93 @@
-use std::ops::Deref;
+use std::{ops::Deref, sync::OnceLock};
 
 use k8s_openapi::{
-    api::core::v1::{Namespace, Pod},
+    api::{
+        batch::v1::Job,
+        core::v1::{EphemeralContainer, Namespace, Pod},
+    },
     NamespaceResourceScope,
 };
 use kube::{
-    api::ListParams,
+    api::{DeleteParams, ListParams},
     config::{KubeConfigOptions, Kubeconfig},
     Api, Client, Config, Discovery,
 };
 use mirrord_config::{
     agent::AgentConfig,
-    feature::network::incoming::IncomingMode,
+    feature::{env::EnvContainer, network::incoming::IncomingMode},
     target::{Target, TargetConfig},
     LayerConfig,
 };
 use mirrord_progress::Progress;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 use crate::{
     api::{
         container::{
-            ephemeral::EphemeralTargetedVariant,
+            ephemeral::{ephemeral_container_manifest, EphemeralTargetedVariant},
             job::{JobTargetedVariant, JobVariant},
             targeted::Targeted,
             targetless::Targetless,
-            ContainerApi, ContainerParams,
+            util::{parse_openshift_uid_range, OPENSHIFT_UID_RANGE_ANNOTATION},
+            ContainerApi, ContainerParams, ContainerVariant, OpenShiftParams,
         },
+        credentials::{is_unauthorized, KubeClientSettings, RefreshingClient},
+        proxy::{resolve_proxy_url, ssh},
+        retry::RetryPolicy,
         runtime::{RuntimeData, RuntimeDataProvider},
     },
     error::{KubeApiError, Result},
 };
 
+#[cfg(not(feature = "incluster"))]
+mod exec;
 pub mod rollout;
 
 pub struct KubernetesAPI {
     client: Client,
     agent: AgentConfig,
+    /// Used to dial new connections to the agent with fresh credentials. Only available when
+    /// we've created the [`Client`] ourselves, see [`KubernetesAPI::create`].
+    credentials: Option<RefreshingClient>,
+    /// Used to resolve the target, when it's in a different cluster than the agent (see
+    /// [`TargetConfig::kube_context`]).
+    target_credentials: Option<RefreshingClient>,
+    /// Whether the cluster is OpenShift, see [`KubernetesAPI::is_openshift`].
+    openshift: OnceLock<bool>,
 }
 
 impl KubernetesAPI {
     pub async fn create(config: &LayerConfig) -> Result<Self> {
-        let client = create_kube_api(
-            config.accept_invalid_certificates,
-            config.kubeconfig.clone(),
-            config.kube_context.clone(),
-        )
-        .await?;
+        let agent_settings = KubeClientSettings::for_agent(config);
+        let target_settings = KubeClientSettings::for_target(config);
+
+        let target_credentials = if target_settings != agent_settings {
+            Some(RefreshingClient::new(target_settings).await?)
+        } else {
+            None
+        };
+
+        let credentials = RefreshingClient::new(agent_settings).await?;
+        let client = credentials.client().await?;
 
-        Ok(KubernetesAPI::new(client, config.agent.clone()))
+        Ok(KubernetesAPI {
+            credentials: Some(credentials),
+            target_credentials,
+            client,
+            agent: config.agent.clone(),
+            openshift: OnceLock::new(),
+        })
     }
 
     pub fn new(client: Client, agent: AgentConfig) -> Self {
-        KubernetesAPI { client, agent }
+        KubernetesAPI {
+            client,
+            agent,
+            credentials: None,
+            target_credentials: None,
+            openshift: OnceLock::new(),
+        }
     }
 
     /// Returns a reference to the [`Client`] used by this instance.
@@ -61,29 +95,103 @@ impl KubernetesAPI {
         &self.client
     }
 
+    /// Returns a [`Client`] with credentials that are not about to expire, see
+    /// [`RefreshingClient::client`].
+    ///
+    /// Falls back to [`KubernetesAPI::client`] when we haven't created it ourselves.
+    async fn fresh_client(&self) -> Result<Client> {
+        match &self.credentials {
+            Some(credentials) => credentials.client().await,
+            None => Ok(self.client.clone()),
+        }
+    }
+
+    /// Returns a [`Client`] for the cluster the target is in, falling back to
+    /// [`KubernetesAPI::fresh_client`] when it's the same cluster as the agent's.
+    async fn target_client(&self) -> Result<Client> {
+        match &self.target_credentials {
+            Some(credentials) => credentials.client().await,
+            None => self.fresh_client().await,
+        }
+    }
+
     /// Returns a reference to the [`AgentConfig`] used by this instance.
     pub fn agent_config(&self) -> &AgentConfig {
         &self.agent
     }
 
-    pub async fn detect_openshift<P>(&self, progress: &P) -> Result<()>
-    where
-        P: Progress + Send + Sync,
-    {
+    /// Checks whether the cluster is OpenShift, by looking for its `route.openshift.io` API group.
+    pub async fn is_openshift(&self) -> Result<bool> {
+        if let Some(openshift) = self.openshift.get() {
+            return Ok(*openshift);
+        }
+
         // filter openshift to make it a lot faster
-        if Discovery::new(self.client.clone())
+        let openshift = Discovery::new(self.client.clone())
             .filter(&["route.openshift.io"])
             .run()
             .await?
-            .has_group("route.openshift.io")
-        {
+            .has_group("route.openshift.io");
+
+        Ok(*self.openshift.get_or_init(|| openshift))
+    }
+
+    pub async fn detect_openshift<P>(&self, progress: &P) -> Result<()>
+    where
+        P: Progress + Send + Sync,
+    {
+        if !self.is_openshift().await? {
+            debug!("OpenShift was not detected.");
+        } else if self.agent.openshift == Some(false) {
             progress.warning("mirrord has detected it's running on OpenShift. Due to the default PSP of OpenShift, mirrord may not be able to create the agent. Please refer to the documentation at https://mirrord.dev/docs/faq/limitations/#does-mirrord-support-openshift");
         } else {
-            debug!("OpenShift was not detected.");
+            progress.info("mirrord has detected it's running on OpenShift, and will create the agent with a spec compatible with its security context constraints. Targeted agents still need an SCC that allows host PID, host paths and their capabilities, please refer to the documentation at https://mirrord.dev/docs/faq/limitations/#does-mirrord-support-openshift");
         }
         Ok(())
     }
 
+    /// Resolves [`ContainerParams::openshift`], detecting OpenShift when
+    /// [`AgentConfig::openshift`] is not set.
+    ///
+    /// `targeted` agents always run as the user SCC admission picks, so we only look up the
+    /// namespace's UID range for targetless ones.
+    async fn openshift_params(&self, targeted: bool) -> Result<Option<OpenShiftParams>> {
+        let openshift = match self.agent.openshift {
+            Some(openshift) => openshift,
+            None => self.is_openshift().await.unwrap_or_else(|error| {
+                debug!(%error, "couldn't determine OpenShift");
+                false
+            }),
+        };
+
+        if !openshift {
+            return Ok(None);
+        } else if targeted || !self.agent.openshift_uid_range {
+            return Ok(Some(Default::default()));
+        }
+
+        let client = self.fresh_client().await?;
+        let namespace = self
+            .agent
+            .namespace
+            .clone()
+            .unwrap_or_else(|| client.default_namespace().to_string());
+
+        let run_as_user = Api::<Namespace>::all(client)
+            .get(&namespace)
+            .await?
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(OPENSHIFT_UID_RANGE_ANNOTATION))
+            .and_then(|range| parse_openshift_uid_range(range))
+            .ok_or(KubeApiError::OpenShiftUidRangeNotFound(namespace))?;
+
+        Ok(Some(OpenShiftParams {
+            run_as_user: Some(run_as_user),
+        }))
+    }
+
     /// Connect to the agent using plain TCP connection.
     #[cfg(feature = "incluster")]
     pub async fn create_connection(
@@ -99,9 +207,19 @@ impl KubernetesAPI {
 
         use tokio::net::TcpStream;
 
-        let pod_api: Api<Pod> = get_k8s_resource_api(&self.client, namespace.as_deref());
+        let get_pod = |client: Client| {
+            let pod_api: Api<Pod> = get_k8s_resource_api(&client, namespace.as_deref());
+            let pod_name = &pod_name;
 
-        let pod = pod_api.get(&pod_name).await?;
+            async move { pod_api.get(pod_name).await }
+        };
+
+        // Sessions can be long, so our service account token might have been rotated since we've
+        // created the agent.
+        let pod = match &self.credentials {
+            Some(credentials) => credentials.retry_unauthorized(get_pod).await?,
+            None => get_pod(self.client.clone()).await?,
+        };
 
         let conn = if let Some(pod_ip) = pod.status.and_then(|status| status.pod_ip) {
             // When pod_ip is available we directly create it as SocketAddr to prevent tokio from
@@ -133,26 +251,47 @@ impl KubernetesAPI {
         Ok(conn)
     }
 
-    /// Connects to the agent using kube's [`Api::portforward`].
+    /// Connects to the agent using kube's [`Api::portforward`], or [`Api::exec`] when
+    /// [`AgentConfig::connection`] is `exec`.
+    ///
+    /// When the API server rejects our credentials (they might have expired mid-session), the
+    /// port-forward is dialed again with fresh ones.
     #[cfg(not(feature = "incluster"))]
     pub async fn create_connection(
         &self,
         connect_info: AgentKubernetesConnectInfo,
     ) -> Result<Box<dyn UnpinStream>> {
+        use mirrord_config::agent::AgentConnection;
         use tokio_retry::{
             strategy::{jitter, ExponentialBackoff},
             Retry,
         };
 
-        let pod_api: Api<Pod> =
-            get_k8s_resource_api(&self.client, connect_info.namespace.as_deref());
-        let retry_strategy = ExponentialBackoff::from_millis(10).map(jitter).take(3);
-        let ports = &[connect_info.agent_port];
-        let mut port_forwarder = Retry::spawn(retry_strategy, || {
-            trace!("port-forward to pod {:?}", &connect_info);
-            pod_api.portforward(&connect_info.pod_name, ports)
-        })
-        .await?;
+        if self.agent.connection == AgentConnection::Exec {
+            return self.create_exec_connection(connect_info).await;
+        }
+
+        let port_forward = |client: Client| {
+            let connect_info = &connect_info;
+
+            async move {
+                let pod_api: Api<Pod> =
+                    get_k8s_resource_api(&client, connect_info.namespace.as_deref());
+                let retry_strategy = ExponentialBackoff::from_millis(10).map(jitter).take(3);
+                let ports = &[connect_info.agent_port];
+
+                Retry::spawn(retry_strategy, || {
+                    trace!("port-forward to pod {:?}", connect_info);
+                    pod_api.portforward(&connect_info.pod_name, ports)
+                })
+                .await
+            }
+        };
+
+        let mut port_forwarder = match &self.credentials {
+            Some(credentials) => credentials.retry_unauthorized(port_forward).await?,
+            None => port_forward(self.client.clone()).await?,
+        };
 
         let stream = port_forwarder
             .take_stream(connect_info.agent_port)
@@ -163,9 +302,119 @@ impl KubernetesAPI {
         Ok(stream)
     }
 
+    /// Connects to the agent by running a bridge in the agent container with [`Api::exec`], see
+    /// [`exec::ExecStream`].
+    #[cfg(not(feature = "incluster"))]
+    async fn create_exec_connection(
+        &self,
+        connect_info: AgentKubernetesConnectInfo,
+    ) -> Result<Box<dyn UnpinStream>> {
+        use kube::api::AttachParams;
+
+        use self::exec::ExecStream;
+
+        let exec = |client: Client| {
+            let connect_info = &connect_info;
+
+            async move {
+                let pod_api: Api<Pod> =
+                    get_k8s_resource_api(&client, connect_info.namespace.as_deref());
+                let command = [
+                    "./mirrord-agent".to_string(),
+                    "-l".to_string(),
+                    connect_info.agent_port.to_string(),
+                    "bridge".to_string(),
+                ];
+                let params = AttachParams::default()
+                    .container(connect_info.agent_container.clone())
+                    .stdin(true)
+                    .stdout(true)
+                    .stderr(false);
+
+                trace!("exec bridge in pod {:?}", connect_info);
+                pod_api.exec(&connect_info.pod_name, command, &params).await
+            }
+        };
+
+        let process = match &self.credentials {
+            Some(credentials) => credentials.retry_unauthorized(exec).await?,
+            None => exec(self.client.clone()).await?,
+        };
+
+        let stream: Box<dyn UnpinStream> =
+            Box::new(ExecStream::new(process).ok_or(KubeApiError::AgentExecFailed)?);
+
+        Ok(stream)
+    }
+
+    /// Dials the agent again, after the port-forward from [`KubernetesAPI::create_connection`]
+    /// was dropped (e.g. the API server restarted or closed an idle stream).
+    ///
+    /// Retries with exponential backoff for roughly 30 seconds. The agent waits for its
+    /// `communication_timeout` for a client to come back, so keep the two in the same ballpark.
+    #[cfg(not(feature = "incluster"))]
+    #[tracing::instrument(level = "trace", skip(self), err)]
+    pub async fn reconnect_connection(
+        &self,
+        connect_info: AgentKubernetesConnectInfo,
+    ) -> Result<Box<dyn UnpinStream>> {
+        use std::time::Duration;
+
+        use tokio_retry::{
+            strategy::{jitter, ExponentialBackoff},
+            Retry,
+        };
+
+        let retry_strategy = ExponentialBackoff::from_millis(2)
+            .factor(100)
+            .max_delay(Duration::from_secs(5))
+            .map(jitter)
+            .take(10);
+
+        Retry::spawn(retry_strategy, || {
+            tracing::warn!("port-forward to the agent dropped, reconnecting");
+            self.create_connection(connect_info.clone())
+        })
+        .await
+    }
+
+    /// Deletes the agent [`Job`] (with its pod) created by [`KubernetesAPI::create_agent`], when
+    /// the agent is replaced with a new one.
+    ///
+    /// An ephemeral agent can't be removed from the target pod, it exits on its own once no client
+    /// is connected to it.
+    #[tracing::instrument(level = "trace", skip(self), err)]
+    pub async fn delete_agent(&self, connect_info: &AgentKubernetesConnectInfo) -> Result<()> {
+        if self.agent.ephemeral {
+            return Ok(());
+        }
+
+        let client = self.fresh_client().await?;
+        let namespace = connect_info.namespace.as_deref();
+
+        let Some(pod) = get_k8s_resource_api::<Pod>(&client, namespace)
+            .get_opt(&connect_info.pod_name)
+            .await?
+        else {
+            return Ok(());
+        };
+
+        let job_api = get_k8s_resource_api::<Job>(&client, namespace);
+        for owner in pod.metadata.owner_references.unwrap_or_default() {
+            if owner.kind == "Job" {
+                job_api
+                    .delete(&owner.name, &DeleteParams::background())
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// # Params
     ///
-    /// * `config` - if passed, will be checked against cluster setup
+    /// * `env_containers` - other containers to load environment variables from, see
+    ///   [`EnvConfig::containers`](mirrord_config::feature::env::EnvConfig::containers)
     /// * `tls_cert` - value for
     ///   [`AGENT_OPERATOR_CERT_ENV`](mirrord_protocol::AGENT_OPERATOR_CERT_ENV), for creating an
     ///   agent from the operator. In usage from this repo this is always `None`.
@@ -173,18 +422,40 @@ impl KubernetesAPI {
     pub async fn create_agent_params(
         &self,
         target: &TargetConfig,
+        env_containers: &[EnvContainer],
         tls_cert: Option<String>,
     ) -> Result<(ContainerParams, Option<RuntimeData>), KubeApiError> {
+        let client = self.target_client().await?;
+
         let runtime_data = match target.path.as_ref().unwrap_or(&Target::Targetless) {
             Target::Targetless => None,
-            path => path
-                .runtime_data(&self.client, target.namespace.as_deref())
-                .await?
-                .into(),
+            path => {
+                let mut runtime_data = path
+                    .runtime_data(&client, target.namespace.as_deref())
+                    .await?;
+
+                let container_named = match path {
+                    Target::Deployment(target) => target.container.is_some(),
+                    Target::Pod(target) => target.container.is_some(),
+                    Target::Rollout(target) => target.container.is_some(),
+                    Target::Targetless => false,
+                };
+                runtime_data
+                    .select_containers(
+                        &client,
+                        target.container_index,
+                        container_named,
+                        env_containers,
+                    )
+                    .await?;
+
+                Some(runtime_data)
+            }
         };
 
         let mut params = ContainerParams::new();
         params.tls_cert = tls_cert;
+        params.openshift = self.openshift_params(runtime_data.is_some()).await?;
 
         Ok((params, runtime_data))
     }
@@ -195,7 +466,11 @@ impl KubernetesAPI {
     /// * `tls_cert` - value for
     ///   [`AGENT_OPERATOR_CERT_ENV`](mirrord_protocol::AGENT_OPERATOR_CERT_ENV), for creating an
     ///   agent from the operator. In usage from this repo this is always `None`.
-    #[tracing::instrument(level = "trace", skip(self, progress))]
+    #[tracing::instrument(
+        level = "info",
+        name = "agent_creation",
+        skip(self, progress, config, tls_cert)
+    )]
     pub async fn create_agent<P>(
         &self,
         progress: &mut P,
@@ -206,7 +481,26 @@ impl KubernetesAPI {
     where
         P: Progress + Send + Sync,
     {
-        let (params, runtime_data) = self.create_agent_params(target, tls_cert).await?;
+        if self.agent.ephemeral && self.target_credentials.is_some() {
+            return Err(KubeApiError::EphemeralAgentContext);
+        }
+
+        let env_containers = config
+            .and_then(|config| config.feature.env.containers.as_deref())
+            .unwrap_or_default();
+        if self.agent.ephemeral && !env_containers.is_empty() {
+            progress.warning(
+                "`feature.env.containers` is not supported with `agent.ephemeral`, only the \
+                 target container's environment will be loaded.",
+            );
+        }
+
+        let retry_policy = RetryPolicy::new(&self.agent);
+        let (params, runtime_data) = retry_policy
+            .run(&*progress, "resolving the target", |_| {
+                self.create_agent_params(target, env_containers, tls_cert.clone())
+            })
+            .await?;
 
         let incoming_mode = config.map(|config| config.feature.network.incoming.mode);
         let is_mesh = runtime_data
@@ -224,34 +518,248 @@ impl KubernetesAPI {
 
         info!(?params, "Spawning new agent");
 
-        let agent_connect_info = match (runtime_data, self.agent.ephemeral) {
-            (None, false) => {
-                let variant = JobVariant::new(&self.agent, &params);
+        let client = self.fresh_client().await?;
+
+        let sidecar_pod = retry_policy
+            .run(&*progress, "getting the target pod", |_| {
+                self.sidecar_target_pod(&client, runtime_data.as_ref())
+            })
+            .await?;
+        if sidecar_pod.is_some() {
+            progress.warning(
+                "The agent runs in a copy of the target pod (the target is on a virtual node, or \
+                 `agent.sidecar` is set), so only the traffic Kubernetes sends to the copy can be \
+                 stolen. Mirroring traffic, pausing the target, passing unmatched HTTP requests \
+                 to the target and files outside the target's volumes are not available.",
+            );
+
+            if matches!(incoming_mode, Some(IncomingMode::Mirror)) {
+                progress.warning(
+                    "`network.incoming.mode = \"mirror\"` is not available when the agent runs in \
+                     a copy of the target pod, set it to \"steal\" to receive incoming traffic.",
+                );
+            }
+        }
 
-                Targetless::new(&self.client, &variant)
+        let agent_connect_info = match self
+            .spawn_agent(
+                &client,
+                progress,
+                &params,
+                runtime_data.as_ref(),
+                sidecar_pod.as_ref(),
+            )
+            .await
+        {
+            // Our credentials might have been revoked before they got old. The agent keeps its
+            // (random) name in `params`, so the retry picks up what the first try created,
+            // instead of creating another agent.
+            Err(KubeApiError::KubeError(error)) if is_unauthorized(&error) => {
+                let Some(credentials) = &self.credentials else {
+                    return Err(error.into());
+                };
+
+                warn!(%error, "kube credentials were rejected, refreshing them and retrying");
+                let client = credentials.refresh().await?;
+                self.spawn_agent(
+                    &client,
+                    progress,
+                    &params,
+                    runtime_data.as_ref(),
+                    sidecar_pod.as_ref(),
+                )
+                .await?
+            }
+            result => result?,
+        };
+
+        info!(?agent_connect_info, "Created agent pod");
+
+        Ok(agent_connect_info)
+    }
+
+    /// Returns the agent resource that [`KubernetesAPI::create_agent`] would create, without
+    /// creating anything in the cluster. The cluster is only read, to resolve the target.
+    ///
+    /// The agent's name is random, so it differs from the one a later
+    /// [`KubernetesAPI::create_agent`] uses.
+    #[tracing::instrument(level = "trace", skip(self), err)]
+    pub async fn render_agent(
+        &self,
+        target: &TargetConfig,
+        config: Option<&LayerConfig>,
+    ) -> Result<AgentManifest, KubeApiError> {
+        if self.agent.ephemeral && self.target_credentials.is_some() {
+            return Err(KubeApiError::EphemeralAgentContext);
+        }
+
+        let env_containers = config
+            .and_then(|config| config.feature.env.containers.as_deref())
+            .unwrap_or_default();
+        let (params, runtime_data) = self
+            .create_agent_params(target, env_containers, None)
+            .await?;
+
+        let sidecar_pod = self
+            .sidecar_target_pod(&self.client, runtime_data.as_ref())
+            .await?;
+
+        match AgentKind::new(
+            self.agent.ephemeral,
+            runtime_data.as_ref(),
+            sidecar_pod.as_ref(),
+        )? {
+            AgentKind::Sidecar(runtime_data, target_pod) => {
+                let agent = self.sidecar_agent_config(runtime_data);
+
+                JobVariant::sidecar(&agent, &params, runtime_data, target_pod)
+                    .as_update()
+                    .map(AgentManifest::Job)
+            }
+            AgentKind::Targetless => JobVariant::new(&self.agent, &params)
+                .as_update()
+                .map(AgentManifest::Job),
+            AgentKind::Targeted(runtime_data) => {
+                JobTargetedVariant::new(&self.agent, &params, runtime_data)
+                    .as_update()
+                    .map(AgentManifest::Job)
+            }
+            AgentKind::Ephemeral(runtime_data) => {
+                let variant = EphemeralTargetedVariant::new(&self.agent, &params, runtime_data);
+                let pod_api =
+                    get_k8s_resource_api(&self.client, runtime_data.pod_namespace.as_deref());
+
+                ephemeral_container_manifest(&pod_api, runtime_data, &variant)
+                    .await
+                    .map(AgentManifest::EphemeralContainer)
+            }
+        }
+    }
+
+    /// Returns the target pod when the agent should run in a copy of it, see
+    /// [`AgentConfig::sidecar`]. When not set, that's when the target is on a virtual node.
+    async fn sidecar_target_pod(
+        &self,
+        client: &Client,
+        runtime_data: Option<&RuntimeData>,
+    ) -> Result<Option<Pod>, KubeApiError> {
+        let Some(runtime_data) = runtime_data else {
+            return Ok(None);
+        };
+
+        let sidecar = match self.agent.sidecar {
+            Some(true) if self.target_credentials.is_some() => {
+                return Err(KubeApiError::SidecarAgentContext)
+            }
+            Some(sidecar) => sidecar,
+            None => {
+                !self.agent.ephemeral
+                    && self.target_credentials.is_none()
+                    && runtime_data.is_virtual_node(client).await
+            }
+        };
+
+        if !sidecar {
+            return Ok(None);
+        }
+
+        let pod_api: Api<Pod> = get_k8s_resource_api(client, runtime_data.pod_namespace.as_deref());
+        pod_api
+            .get(&runtime_data.pod_name)
+            .await
+            .map(Some)
+            .map_err(KubeApiError::KubeError)
+    }
+
+    /// The copy of the target pod lives in the target's namespace.
+    fn sidecar_agent_config(&self, runtime_data: &RuntimeData) -> AgentConfig {
+        AgentConfig {
+            namespace: runtime_data.pod_namespace.clone(),
+            ..self.agent.clone()
+        }
+    }
+
+    /// Creates the agent with the given `client`, and waits until it's ready, see
+    /// [`KubernetesAPI::create_agent`].
+    ///
+    /// The agent runs in a copy of `sidecar_pod` when given, see
+    /// [`KubernetesAPI::sidecar_target_pod`].
+    async fn spawn_agent<P>(
+        &self,
+        client: &Client,
+        progress: &mut P,
+        params: &ContainerParams,
+        runtime_data: Option<&RuntimeData>,
+        sidecar_pod: Option<&Pod>,
+    ) -> Result<AgentKubernetesConnectInfo, KubeApiError>
+    where
+        P: Progress + Send + Sync,
+    {
+        match AgentKind::new(self.agent.ephemeral, runtime_data, sidecar_pod)? {
+            AgentKind::Sidecar(runtime_data, target_pod) => {
+                let agent = self.sidecar_agent_config(runtime_data);
+                let variant = JobVariant::sidecar(&agent, params, runtime_data, target_pod);
+
+                Targetless::new(client, &variant)
                     .create_agent(progress)
-                    .await?
+                    .await
             }
-            (Some(runtime_data), false) => {
-                let variant = JobTargetedVariant::new(&self.agent, &params, &runtime_data);
+            AgentKind::Targetless => {
+                let variant = JobVariant::new(&self.agent, params);
 
-                Targeted::new(&self.client, &runtime_data, &variant)
+                Targetless::new(client, &variant)
                     .create_agent(progress)
-                    .await?
+                    .await
             }
-            (Some(runtime_data), true) => {
-                let variant = EphemeralTargetedVariant::new(&self.agent, &params, &runtime_data);
+            AgentKind::Targeted(runtime_data) => {
+                let variant = JobTargetedVariant::new(&self.agent, params, runtime_data);
 
-                Targeted::new(&self.client, &runtime_data, &variant)
+                Targeted::new(client, runtime_data, &variant)
                     .create_agent(progress)
-                    .await?
+                    .await
             }
-            (None, true) => return Err(KubeApiError::MissingRuntimeData),
-        };
+            AgentKind::Ephemeral(runtime_data) => {
+                let variant = EphemeralTargetedVariant::new(&self.agent, params, runtime_data);
 
-        info!(?agent_connect_info, "Created agent pod");
+                Targeted::new(client, runtime_data, &variant)
+                    .create_agent(progress)
+                    .await
+            }
+        }
+    }
+}
 
-        Ok(agent_connect_info)
+/// The kind of agent resource [`KubernetesAPI::create_agent`] creates, and
+/// [`KubernetesAPI::render_agent`] returns.
+#[derive(Debug)]
+enum AgentKind<'a> {
+    /// A targetless agent [`Job`].
+    Targetless,
+    /// An agent [`Job`] on the target's node.
+    Targeted(&'a RuntimeData),
+    /// An ephemeral agent container in the target pod, with `agent.ephemeral`.
+    Ephemeral(&'a RuntimeData),
+    /// An agent [`Job`] running in a copy of the target pod, see [`AgentConfig::sidecar`].
+    Sidecar(&'a RuntimeData, &'a Pod),
+}
+
+impl<'a> AgentKind<'a> {
+    /// `sidecar_pod` is the target pod when the agent should run in a copy of it, see
+    /// [`KubernetesAPI::sidecar_target_pod`].
+    fn new(
+        ephemeral: bool,
+        runtime_data: Option<&'a RuntimeData>,
+        sidecar_pod: Option<&'a Pod>,
+    ) -> Result<Self, KubeApiError> {
+        match (runtime_data, sidecar_pod, ephemeral) {
+            (Some(runtime_data), Some(target_pod), _) => {
+                Ok(Self::Sidecar(runtime_data, target_pod))
+            }
+            (None, _, false) => Ok(Self::Targetless),
+            (Some(runtime_data), None, false) => Ok(Self::Targeted(runtime_data)),
+            (Some(runtime_data), None, true) => Ok(Self::Ephemeral(runtime_data)),
+            (None, _, true) => Err(KubeApiError::MissingRuntimeData),
+        }
     }
 }
 
@@ -274,16 +782,94 @@ impl<T> UnpinStream for T where
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct AgentKubernetesConnectInfo {
     pub pod_name: String,
+    /// Name of the agent's container in the pod, used when connecting with `exec`.
+    pub agent_container: String,
     pub agent_port: u16,
     pub namespace: Option<String>,
     pub agent_version: Option<String>,
 }
 
+/// The agent resource, as created by [`KubernetesAPI::create_agent`]. Returned from
+/// [`KubernetesAPI::render_agent`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum AgentManifest {
+    /// The agent's [`Job`], targetless or targeted.
+    Job(Job),
+    /// The agent's container, added to the target pod's `ephemeralcontainers` subresource, with
+    /// `agent.ephemeral`.
+    EphemeralContainer(EphemeralContainer),
+}
+
+/// Creates a kube [`Client`], optionally going through the given `proxy`.
+///
+/// When `proxy` is not set, we use the proxy from the kubeconfig or the env (see
+/// [`resolve_proxy_url`]). Upgraded connections (port forwarding, operator websockets) go through
+/// the same proxy, using `CONNECT` tunneling (or socks5, for `socks5://` proxies).
+///
+/// An `ssh://[user@]host[:port]` proxy is a bastion we reach with `ssh`, see [`ssh`].
+///
+/// With `api_url` (a `kubectl proxy`, see
+/// [`LayerConfig::kube_api_url`](mirrord_config::LayerConfig::kube_api_url)), all requests go
+/// there without credentials, and only the default namespace is taken from the kubeconfig.
 pub async fn create_kube_api<P>(
     accept_invalid_certificates: bool,
     kubeconfig: Option<P>,
     kube_context: Option<String>,
+    proxy: Option<String>,
+    api_url: Option<String>,
 ) -> Result<Client>
+where
+    P: AsRef<str>,
+{
+    let kubeconfig_config = load_kubeconfig(kubeconfig, kube_context);
+
+    let mut config = match api_url.as_deref() {
+        Some(api_url) => {
+            let cluster_url = api_url
+                .parse()
+                .map_err(|_| KubeApiError::InvalidKubeApiUrl(api_url.to_string()))?;
+
+            // There might be no kubeconfig at all, the proxy has everything we need.
+            let default_namespace = kubeconfig_config
+                .await
+                .map(|config| config.default_namespace)
+                .unwrap_or_else(|error| {
+                    debug!(%error, "failed to load the kubeconfig, using the default namespace");
+                    "default".to_string()
+                });
+
+            Config {
+                default_namespace,
+                ..Config::new(cluster_url)
+            }
+        }
+        None => kubeconfig_config.await?,
+    };
+    config.accept_invalid_certs = accept_invalid_certificates;
+
+    let proxy = match proxy {
+        Some(proxy) if ssh::is_ssh_proxy(&proxy) => Some(ssh::local_proxy(&proxy).await?),
+        proxy => proxy,
+    };
+    config.proxy_url = match api_url {
+        // The API proxy usually runs locally, only an explicit proxy makes sense in front of it.
+        Some(..) => resolve_proxy_url(proxy.as_deref(), None, &config.cluster_url, |_| None)?,
+        None => resolve_proxy_url(
+            proxy.as_deref(),
+            config.proxy_url.take(),
+            &config.cluster_url,
+            |key| std::env::var(key).ok(),
+        )?,
+    };
+    debug!(cluster_url = %config.cluster_url, proxy_url = ?config.proxy_url, "Creating kube client");
+
+    Client::try_from(config).map_err(KubeApiError::from)
+}
+
+/// Loads the kube [`Config`] from the `kubeconfig` file (or the default one) and the
+/// `kube_context`, or the in-cluster config.
+async fn load_kubeconfig<P>(kubeconfig: Option<P>, kube_context: Option<String>) -> Result<Config>
 where
     P: AsRef<str>,
 {
@@ -292,7 +878,7 @@ where
         ..Default::default()
     };
 
-    let mut config = if let Some(kubeconfig) = kubeconfig {
+    let config = if let Some(kubeconfig) = kubeconfig {
         let kubeconfig = shellexpand::full(&kubeconfig)
             .map_err(|e| KubeApiError::ConfigPathExpansionError(e.to_string()))?;
         let parsed_kube_config = Kubeconfig::read_from(kubeconfig.deref())?;
@@ -305,8 +891,8 @@ where
         // kube or incluster configuration.
         Config::infer().await?
     };
-    config.accept_invalid_certs = accept_invalid_certificates;
-    Client::try_from(config).map_err(KubeApiError::from)
+
+    Ok(config)
 }
 
 pub fn get_k8s_resource_api<K>(client: &Client, namespace: Option<&str>) -> Api<K>
@@ -342,3 +928,85 @@ pub async fn namespace_exists_for_client(namespace: &str, client: &Client) -> bo
     let api: Api<Namespace> = Api::all(client.clone());
     api.get(namespace).await.is_ok()
 }
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::api::runtime::ContainerRuntime;
+
+    /// With `api_url`, the client works without a kubeconfig.
+    #[tokio::test]
+    async fn kube_api_url_without_kubeconfig() {
+        let client = create_kube_api(
+            false,
+            Some("/nonexistent/kubeconfig"),
+            None,
+            None,
+            Some("http://127.0.0.1:8001".to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(client.default_namespace(), "default");
+
+        let error = create_kube_api(
+            false,
+            Some("/nonexistent/kubeconfig"),
+            None,
+            None,
+            Some("not a url".to_string()),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(error, KubeApiError::InvalidKubeApiUrl(..)),
+            "{error:?}"
+        );
+    }
+
+    fn runtime_data() -> RuntimeData {
+        RuntimeData {
+            pod_name: "app".into(),
+            pod_namespace: None,
+            node_name: "node".into(),
+            container_id: "id".into(),
+            container_runtime: ContainerRuntime::Containerd,
+            container_name: "app".into(),
+            mesh: None,
+            env_containers: vec![],
+        }
+    }
+
+    /// The agent resource is picked the same way for [`KubernetesAPI::create_agent`] and
+    /// [`KubernetesAPI::render_agent`].
+    #[rstest]
+    #[case::targetless(false, false, false, "Targetless")]
+    #[case::targeted(false, true, false, "Targeted")]
+    #[case::ephemeral(true, true, false, "Ephemeral")]
+    #[case::sidecar(false, true, true, "Sidecar")]
+    #[case::sidecar_over_ephemeral(true, true, true, "Sidecar")]
+    #[case::ephemeral_targetless(true, false, false, "MissingRuntimeData")]
+    fn agent_kind(
+        #[case] ephemeral: bool,
+        #[case] targeted: bool,
+        #[case] sidecar: bool,
+        #[case] expected: &str,
+    ) {
+        let runtime_data = runtime_data();
+        let target_pod = Pod::default();
+
+        let kind = AgentKind::new(
+            ephemeral,
+            targeted.then_some(&runtime_data),
+            sidecar.then_some(&target_pod),
+        );
+
+        let kind = match kind {
+            Ok(kind) => format!("{kind:?}"),
+            Err(error) => format!("{error:?}"),
+        };
+        assert!(kind.starts_with(expected), "{kind}");
+    }
+}
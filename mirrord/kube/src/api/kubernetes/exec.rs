@@ -0,0 +1,127 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use kube::api::AttachedProcess;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Connection to the agent through the stdio of the bridge we `exec` in the agent container
+/// (`mirrord-agent bridge`).
+///
+/// Writes go to the bridge's stdin, and reads come from its stdout.
+pub struct ExecStream<P = AttachedProcess> {
+    stdin: Pin<Box<dyn AsyncWrite + Send>>,
+    stdout: Pin<Box<dyn AsyncRead + Send>>,
+    /// Kept so that the exec session stays alive as long as the stream.
+    _process: P,
+}
+
+impl ExecStream {
+    /// Returns [`None`] if the `process` was not attached with both stdin and stdout.
+    pub fn new(mut process: AttachedProcess) -> Option<Self> {
+        let stdin = Box::pin(process.stdin()?);
+        let stdout = Box::pin(process.stdout()?);
+
+        Some(Self {
+            stdin,
+            stdout,
+            _process: process,
+        })
+    }
+}
+
+impl<P: Unpin> AsyncRead for ExecStream<P> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.stdout.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl<P: Unpin> AsyncWrite for ExecStream<P> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.stdin.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stdin.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stdin.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    /// A stream over in-memory pipes, with the bridge's ends of its stdin and stdout.
+    fn stream() -> (ExecStream<()>, DuplexStream, DuplexStream) {
+        let (stdin, bridge_stdin) = duplex(64);
+        let (bridge_stdout, stdout) = duplex(64);
+
+        let stream = ExecStream {
+            stdin: Box::pin(stdin),
+            stdout: Box::pin(stdout),
+            _process: (),
+        };
+
+        (stream, bridge_stdin, bridge_stdout)
+    }
+
+    #[tokio::test]
+    async fn keeps_order() {
+        let (mut stream, mut bridge_stdin, mut bridge_stdout) = stream();
+
+        for chunk in [&b"first "[..], b"second ", b"third"] {
+            stream.write_all(chunk).await.unwrap();
+            bridge_stdout.write_all(chunk).await.unwrap();
+        }
+        stream.flush().await.unwrap();
+
+        let mut written = [0; 18];
+        bridge_stdin.read_exact(&mut written).await.unwrap();
+        assert_eq!(&written, b"first second third");
+
+        let mut read = [0; 18];
+        stream.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"first second third");
+    }
+
+    /// When the bridge exits, its stdout ends, and so do the reads, after what it wrote.
+    #[tokio::test]
+    async fn ends_on_exit() {
+        let (mut stream, _bridge_stdin, mut bridge_stdout) = stream();
+
+        bridge_stdout.write_all(b"bye").await.unwrap();
+        drop(bridge_stdout);
+
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"bye");
+    }
+
+    /// Shutting the stream down closes the bridge's stdin.
+    #[tokio::test]
+    async fn shutdown_closes_stdin() {
+        let (mut stream, mut bridge_stdin, _bridge_stdout) = stream();
+
+        stream.write_all(b"last").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut written = Vec::new();
+        bridge_stdin.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"last");
+    }
+}
@@ -10,14 +10,51 @@ use serde::Deserialize;
 pub struct Rollout {
     metadata: ObjectMeta,
     pub spec: serde_json::Value,
+    #[serde(default)]
+    pub status: Option<serde_json::Value>,
 }
 
 impl Rollout {
+    /// Label Argo Rollouts puts on every pod, with the hash of the pod template of its
+    /// ReplicaSet.
+    pub const POD_TEMPLATE_HASH_LABEL: &'static str = "rollouts-pod-template-hash";
+
     pub fn match_labels(&self) -> Option<BTreeMap<String, String>> {
         let match_labels = self.spec.get("selector")?.get("matchLabels")?;
 
         serde_json::from_value(match_labels.clone()).ok()
     }
+
+    /// Name of the Deployment referenced in `spec.workloadRef`, if the rollout takes its pod
+    /// template (and selector) from an existing Deployment.
+    pub fn workload_ref_deployment(&self) -> Option<&str> {
+        let workload_ref = self.spec.get("workloadRef")?;
+
+        (workload_ref.get("kind")?.as_str()? == "Deployment")
+            .then(|| workload_ref.get("name")?.as_str())
+            .flatten()
+    }
+
+    /// Pod template hash of the live ReplicaSet, that is the stable one (`status.stableRS`),
+    /// falling back to the current one when the rollout has not become stable yet.
+    pub fn live_pod_template_hash(&self) -> Option<&str> {
+        let status = self.status.as_ref()?;
+
+        status
+            .get("stableRS")
+            .or_else(|| status.get("currentPodHash"))?
+            .as_str()
+            .filter(|hash| !hash.is_empty())
+    }
+
+    /// Number of ready pods, across all of the rollout's ReplicaSets.
+    pub fn available_replicas(&self) -> i64 {
+        self.status
+            .as_ref()
+            .and_then(|status| status.get("availableReplicas"))
+            .and_then(serde_json::Value::as_i64)
+            .unwrap_or_default()
+    }
 }
 
 impl Resource for Rollout {
@@ -44,3 +81,49 @@ impl Metadata for Rollout {
         &mut self.metadata
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rollout(spec: serde_json::Value, status: Option<serde_json::Value>) -> Rollout {
+        Rollout {
+            metadata: Default::default(),
+            spec,
+            status,
+        }
+    }
+
+    #[test]
+    fn live_pod_template_hash() {
+        let stable = rollout(
+            json!({}),
+            Some(json!({"stableRS": "abc", "currentPodHash": "def"})),
+        );
+        assert_eq!(stable.live_pod_template_hash(), Some("abc"));
+
+        let progressing = rollout(json!({}), Some(json!({"currentPodHash": "def"})));
+        assert_eq!(progressing.live_pod_template_hash(), Some("def"));
+
+        assert_eq!(rollout(json!({}), None).live_pod_template_hash(), None);
+    }
+
+    #[test]
+    fn workload_ref() {
+        let with_ref = rollout(
+            json!({"workloadRef": {"apiVersion": "apps/v1", "kind": "Deployment", "name": "app"}}),
+            None,
+        );
+        assert_eq!(with_ref.workload_ref_deployment(), Some("app"));
+        assert_eq!(with_ref.match_labels(), None);
+
+        let with_selector = rollout(json!({"selector": {"matchLabels": {"app": "app"}}}), None);
+        assert_eq!(with_selector.workload_ref_deployment(), None);
+        assert_eq!(
+            with_selector.match_labels(),
+            Some(BTreeMap::from([("app".to_string(), "app".to_string())]))
+        );
+    }
+}
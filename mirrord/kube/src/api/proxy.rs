@@ -0,0 +1,203 @@
+use http::Uri;
+
+use crate::error::{KubeApiError, Result};
+
+pub mod ssh;
+
+/// Env variables that hold the proxy for `https` clusters, in order of precedence.
+const HTTPS_PROXY_VARS: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+
+/// Env variables that hold the proxy for `http` clusters, in order of precedence.
+const HTTP_PROXY_VARS: [&str; 2] = ["HTTP_PROXY", "http_proxy"];
+
+/// Env variables that hold the hosts that should be reached directly, in order of precedence.
+const NO_PROXY_VARS: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// Picks the proxy that should be used to reach the cluster at `cluster_url`.
+///
+/// The explicit `proxy` (from the mirrord config) takes precedence, then the `proxy-url` from the
+/// kubeconfig, then the `HTTPS_PROXY`/`HTTP_PROXY` env variables (depending on the cluster scheme).
+/// Proxies from the env are ignored when the cluster host matches `NO_PROXY`.
+///
+/// Empty env variables are treated as unset, since that's how we disable them when `use_proxy` is
+/// `false`.
+pub fn resolve_proxy_url<E>(
+    proxy: Option<&str>,
+    from_kubeconfig: Option<Uri>,
+    cluster_url: &Uri,
+    env: E,
+) -> Result<Option<Uri>>
+where
+    E: Fn(&str) -> Option<String>,
+{
+    if let Some(proxy) = proxy {
+        return parse_proxy_url(proxy).map(Some);
+    }
+
+    if from_kubeconfig.is_some() {
+        return Ok(from_kubeconfig);
+    }
+
+    let lookup = |vars: &[&str]| {
+        vars.iter()
+            .filter_map(|var| env(var))
+            .find(|value| !value.is_empty())
+    };
+
+    let proxy_vars = match cluster_url.scheme_str() {
+        Some("http") => &HTTP_PROXY_VARS,
+        _ => &HTTPS_PROXY_VARS,
+    };
+
+    let Some(proxy) = lookup(proxy_vars) else {
+        return Ok(None);
+    };
+
+    let bypassed = match (cluster_url.host(), lookup(&NO_PROXY_VARS)) {
+        (Some(host), Some(no_proxy)) => is_no_proxy_host(host, &no_proxy),
+        _ => false,
+    };
+
+    if bypassed {
+        Ok(None)
+    } else {
+        parse_proxy_url(&proxy).map(Some)
+    }
+}
+
+fn parse_proxy_url(proxy: &str) -> Result<Uri> {
+    proxy
+        .parse()
+        .map_err(|_| KubeApiError::InvalidProxyUrl(proxy.to_string()))
+}
+
+/// Checks whether `host` matches any of the comma separated entries in `no_proxy`.
+///
+/// An entry matches the host itself and all of its subdomains, a leading `.` is ignored, and `*`
+/// matches every host. Ports in the entries are ignored.
+fn is_no_proxy_host(host: &str, no_proxy: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+
+            let entry = match entry.rsplit_once(':') {
+                // Keep IPv6 addresses intact, they contain `:` without a port.
+                Some((name, port))
+                    if !name.contains(':') && port.chars().all(|c| c.is_ascii_digit()) =>
+                {
+                    name
+                }
+                _ => entry,
+            };
+            let entry = entry
+                .trim_start_matches('.')
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+
+            host.eq_ignore_ascii_case(entry)
+                || host
+                    .len()
+                    .checked_sub(entry.len() + 1)
+                    .and_then(|split| host.get(split..))
+                    .and_then(|suffix| suffix.strip_prefix('.'))
+                    .is_some_and(|suffix| suffix.eq_ignore_ascii_case(entry))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    fn env_from<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        move |key| vars.get(key).map(|value| value.to_string())
+    }
+
+    #[rstest]
+    #[case::exact("cluster.local", "cluster.local", true)]
+    #[case::subdomain("api.cluster.local", "cluster.local", true)]
+    #[case::leading_dot("api.cluster.local", ".cluster.local", true)]
+    #[case::wildcard("api.cluster.local", "*", true)]
+    #[case::with_port("api.cluster.local", "other.com, api.cluster.local:6443", true)]
+    #[case::ip("10.0.0.1", "10.0.0.1", true)]
+    #[case::ipv6("[::1]", "::1", true)]
+    #[case::partial_label("evilcluster.local", "cluster.local", false)]
+    #[case::other("api.cluster.local", "example.com", false)]
+    #[case::empty("api.cluster.local", " , ", false)]
+    fn no_proxy_matching(#[case] host: &str, #[case] no_proxy: &str, #[case] expected: bool) {
+        assert_eq!(is_no_proxy_host(host, no_proxy), expected);
+    }
+
+    #[test]
+    fn proxy_precedence() {
+        let cluster: Uri = "https://api.cluster.local:6443".parse().unwrap();
+        let env = env_from(&[("HTTPS_PROXY", "http://env-proxy:3128")]);
+
+        let explicit = resolve_proxy_url(
+            Some("http://config-proxy:3128"),
+            Some("http://kubeconfig-proxy:3128".parse().unwrap()),
+            &cluster,
+            &env,
+        )
+        .unwrap();
+        assert_eq!(explicit.unwrap(), "http://config-proxy:3128");
+
+        let kubeconfig = resolve_proxy_url(
+            None,
+            Some("http://kubeconfig-proxy:3128".parse().unwrap()),
+            &cluster,
+            &env,
+        )
+        .unwrap();
+        assert_eq!(kubeconfig.unwrap(), "http://kubeconfig-proxy:3128");
+
+        let from_env = resolve_proxy_url(None, None, &cluster, &env).unwrap();
+        assert_eq!(from_env.unwrap(), "http://env-proxy:3128");
+    }
+
+    #[test]
+    fn env_proxy_respects_scheme_and_no_proxy() {
+        let https_cluster: Uri = "https://api.cluster.local:6443".parse().unwrap();
+        let http_cluster: Uri = "http://api.cluster.local:8080".parse().unwrap();
+
+        let env = env_from(&[("HTTPS_PROXY", ""), ("https_proxy", "http://lower:3128")]);
+        assert_eq!(
+            resolve_proxy_url(None, None, &https_cluster, &env)
+                .unwrap()
+                .unwrap(),
+            "http://lower:3128"
+        );
+        assert!(resolve_proxy_url(None, None, &http_cluster, &env)
+            .unwrap()
+            .is_none());
+
+        let env = env_from(&[
+            ("HTTPS_PROXY", "http://proxy:3128"),
+            ("NO_PROXY", "localhost,.cluster.local"),
+        ]);
+        assert!(resolve_proxy_url(None, None, &https_cluster, &env)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_proxy_url() {
+        let cluster: Uri = "https://api.cluster.local:6443".parse().unwrap();
+
+        assert!(matches!(
+            resolve_proxy_url(Some("http://bad proxy"), None, &cluster, |_| None),
+            Err(KubeApiError::InvalidProxyUrl(..))
+        ));
+    }
+}
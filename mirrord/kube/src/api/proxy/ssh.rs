@@ -0,0 +1,388 @@
+//! Reaching the cluster through an ssh bastion (jump host), configured with an
+//! `ssh://[user@]host[:port]` proxy.
+//!
+//! kube can only talk to http(s) and socks5 proxies, so we serve a local http proxy that handles
+//! every `CONNECT` with `ssh -W <destination> <bastion>`, which pipes the connection through the
+//! bastion over ssh's stdio. This reuses the user's ssh setup (`~/.ssh/config`, agent, keys), and
+//! since the ssh processes belong to connections, they never outlive mirrord.
+//!
+//! The local proxy listens on localhost, where other users can reach it too, so it requires a
+//! random token generated for each proxy, which kube sends as the proxy's basic auth password.
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    process::Stdio,
+    sync::{LazyLock, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::Uri;
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+};
+use tracing::{debug, warn};
+
+use crate::error::{KubeApiError, Result};
+
+/// Urls (with the credentials) of the local proxies that were already started, per bastion.
+static PROXIES: LazyLock<Mutex<HashMap<Uri, String>>> = LazyLock::new(Default::default);
+
+/// User in the credentials of the local proxies, only the token (the password) is checked.
+const PROXY_USER: &str = "mirrord";
+
+/// Longest request head we read from a client of the local proxy.
+const MAX_REQUEST_HEAD: u64 = 16 * 1024;
+
+/// An `ssh://[user@]host[:port]` bastion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bastion {
+    /// `host` or `user@host`, as passed to `ssh`.
+    destination: String,
+    port: Option<u16>,
+}
+
+impl Bastion {
+    fn parse(spec: &Uri) -> Result<Self> {
+        let invalid = || KubeApiError::InvalidProxyUrl(spec.to_string());
+
+        let authority = spec.authority().ok_or_else(invalid)?;
+        let host = authority.host();
+        if host.is_empty() || !matches!(spec.path(), "" | "/") {
+            return Err(invalid());
+        }
+
+        let user = authority.as_str().rsplit_once('@').map(|(user, _)| user);
+
+        // `ssh` would read these as options (e.g. `-oProxyCommand=...`).
+        if host.starts_with('-')
+            || user.is_some_and(|user| user.is_empty() || user.starts_with('-'))
+        {
+            return Err(invalid());
+        }
+
+        let destination = match user {
+            Some(user) => format!("{user}@{host}"),
+            None => host.to_string(),
+        };
+
+        Ok(Self {
+            destination,
+            port: authority.port_u16(),
+        })
+    }
+
+    /// Arguments for `ssh`, to connect to `target` (`host:port`) through this bastion.
+    fn ssh_args(&self, target: &str) -> Vec<String> {
+        // Stdio is used for the connection, so ssh can't prompt for passwords or host keys.
+        let mut args = vec![
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-W".to_string(),
+            target.to_string(),
+        ];
+
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+
+        // Nothing after `--` is read as an option.
+        args.extend(["--".to_string(), self.destination.clone()]);
+        args
+    }
+}
+
+/// Checks whether `proxy` should be handled by [`local_proxy`].
+pub fn is_ssh_proxy(proxy: &str) -> bool {
+    proxy.starts_with("ssh://")
+}
+
+/// Returns the url of a local http proxy that tunnels connections through the ssh bastion in
+/// `proxy`, starting it on first use.
+pub async fn local_proxy(proxy: &str) -> Result<String> {
+    let spec: Uri = proxy
+        .parse()
+        .map_err(|_| KubeApiError::InvalidProxyUrl(proxy.to_string()))?;
+    let bastion = Bastion::parse(&spec)?;
+
+    if let Some(url) = PROXIES
+        .lock()
+        .expect("ssh proxies lock poisoned")
+        .get(&spec)
+    {
+        return Ok(url.clone());
+    }
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .map_err(KubeApiError::SshProxyFailed)?;
+    let address: SocketAddr = listener
+        .local_addr()
+        .map_err(KubeApiError::SshProxyFailed)?;
+    debug!(%address, ?bastion, "Started local proxy for ssh bastion");
+
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let url = format!("http://{PROXY_USER}:{token}@{address}");
+
+    tokio::spawn(serve(listener, bastion, basic_authorization(&token)));
+    PROXIES
+        .lock()
+        .expect("ssh proxies lock poisoned")
+        .insert(spec, url.clone());
+
+    Ok(url)
+}
+
+/// The `Proxy-Authorization` header value kube sends with the `token`.
+fn basic_authorization(token: &str) -> String {
+    format!("Basic {}", STANDARD.encode(format!("{PROXY_USER}:{token}")))
+}
+
+/// Accepts connections from kube, that have to carry the `authorization` of this proxy.
+async fn serve(listener: TcpListener, bastion: Bastion, authorization: String) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let bastion = bastion.clone();
+                let authorization = authorization.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = tunnel(stream, &bastion, &authorization).await {
+                        warn!(%error, ?bastion, "Connection through ssh bastion failed");
+                    }
+                });
+            }
+            Err(error) => {
+                warn!(%error, "Local proxy for ssh bastion failed to accept a connection");
+            }
+        }
+    }
+}
+
+/// Handles a single `CONNECT` request from kube.
+async fn tunnel(stream: TcpStream, bastion: &Bastion, authorization: &str) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let Some(request) = read_connect_request(&mut read).await? else {
+        write
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+            .await?;
+        return Ok(());
+    };
+
+    if !request.is_authorized(authorization) {
+        warn!(
+            ?bastion,
+            "Unauthorized connection to the local proxy for ssh bastion"
+        );
+        write
+            .write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                  Proxy-Authenticate: Basic realm=\"mirrord\"\r\n\r\n",
+            )
+            .await?;
+        return Ok(());
+    }
+    let target = request.target;
+
+    let mut child = Command::new("ssh")
+        .args(bastion.ssh_args(&target))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            warn!(ssh = %line, "ssh bastion");
+        }
+    });
+
+    write
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+
+    let upstream = async {
+        io::copy(&mut read, &mut stdin).await?;
+        // Closes stdin, so ssh closes the remote connection as well.
+        drop(stdin);
+        Ok::<_, io::Error>(())
+    };
+    let downstream = async {
+        io::copy(&mut stdout, &mut write).await?;
+        write.shutdown().await
+    };
+
+    tokio::try_join!(upstream, downstream)?;
+
+    Ok(())
+}
+
+/// A `CONNECT` request to the local proxy.
+#[derive(Debug, PartialEq, Eq)]
+struct ConnectRequest {
+    /// `host:port` to connect to.
+    target: String,
+    /// Value of the `Proxy-Authorization` header.
+    authorization: Option<String>,
+}
+
+impl ConnectRequest {
+    /// Whether the request carries the `authorization` of the proxy, compared in constant time.
+    fn is_authorized(&self, authorization: &str) -> bool {
+        self.authorization.as_deref().is_some_and(|received| {
+            received.len() == authorization.len()
+                && received
+                    .bytes()
+                    .zip(authorization.bytes())
+                    .fold(0, |diff, (received, expected)| diff | (received ^ expected))
+                    == 0
+        })
+    }
+}
+
+/// Reads the request head (up to [`MAX_REQUEST_HEAD`] bytes), and returns the request when it's
+/// a `CONNECT`.
+async fn read_connect_request<R>(read: &mut R) -> io::Result<Option<ConnectRequest>>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut head = read.take(MAX_REQUEST_HEAD);
+
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+
+    let mut authorization = None;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if head.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("proxy-authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    Ok(match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) if method.eq_ignore_ascii_case("CONNECT") => {
+            Some(ConnectRequest {
+                target: target.to_string(),
+                authorization,
+            })
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::host("ssh://bastion.corp", "bastion.corp", None)]
+    #[case::user("ssh://jump@bastion.corp", "jump@bastion.corp", None)]
+    #[case::port("ssh://jump@bastion.corp:2222", "jump@bastion.corp", Some(2222))]
+    fn parses_bastion(#[case] spec: &str, #[case] destination: &str, #[case] port: Option<u16>) {
+        let bastion = Bastion::parse(&spec.parse().unwrap()).unwrap();
+        assert_eq!(
+            bastion,
+            Bastion {
+                destination: destination.to_string(),
+                port
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_path() {
+        assert!(matches!(
+            Bastion::parse(&"ssh://bastion.corp/path".parse().unwrap()),
+            Err(KubeApiError::InvalidProxyUrl(..))
+        ));
+    }
+
+    #[rstest]
+    #[case::user("ssh://-oProxyCommand=touch%20pwned@bastion.corp")]
+    #[case::empty_user("ssh://@bastion.corp")]
+    fn rejects_option_like(#[case] spec: &str) {
+        assert!(matches!(
+            Bastion::parse(&spec.parse().unwrap()),
+            Err(KubeApiError::InvalidProxyUrl(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_connect_request() {
+        let authorization = basic_authorization("token");
+        let head = format!(
+            "CONNECT api.cluster.local:443 HTTP/1.1\r\nHost: api.cluster.local:443\r\n\
+             proxy-authorization: {authorization}\r\n\r\n"
+        );
+
+        let request = read_connect_request(&mut head.as_bytes())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.target, "api.cluster.local:443");
+        assert!(request.is_authorized(&authorization));
+        assert!(!request.is_authorized(&basic_authorization("other")));
+    }
+
+    #[tokio::test]
+    async fn rejects_unauthenticated_request() {
+        let head = "CONNECT api.cluster.local:443 HTTP/1.1\r\n\r\n";
+
+        let request = read_connect_request(&mut head.as_bytes())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!request.is_authorized(&basic_authorization("token")));
+    }
+
+    #[tokio::test]
+    async fn ignores_other_methods() {
+        let head = "GET / HTTP/1.1\r\n\r\n";
+
+        assert_eq!(
+            read_connect_request(&mut head.as_bytes()).await.unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn ssh_args() {
+        let bastion = Bastion {
+            destination: "jump@bastion.corp".to_string(),
+            port: Some(2222),
+        };
+
+        assert_eq!(
+            bastion.ssh_args("api.cluster.local:443"),
+            [
+                "-o",
+                "BatchMode=yes",
+                "-W",
+                "api.cluster.local:443",
+                "-p",
+                "2222",
+                "--",
+                "jump@bastion.corp"
+            ]
+        );
+    }
+}
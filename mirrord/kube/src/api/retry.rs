@@ -0,0 +1,272 @@
+//! Retries of the Kubernetes API calls made while creating the agent, so that a busy or briefly
+//! unavailable API server doesn't abort the whole run. See [`AgentConfig::kube_api_retries`] and
+//! [`AgentConfig::kube_api_timeout`].
+use std::{future::Future, time::Duration};
+
+use kube::runtime::watcher;
+use mirrord_config::agent::AgentConfig;
+use mirrord_progress::Progress;
+use rand::Rng;
+use tracing::warn;
+
+use crate::error::{KubeApiError, Result};
+
+/// Whether the `error` is worth retrying: the API server is throttling us or is unavailable, or
+/// the call timed out.
+pub fn is_transient(error: &KubeApiError) -> bool {
+    match error {
+        KubeApiError::KubeError(kube::Error::Api(response)) => is_transient_code(response.code),
+        KubeApiError::KubeApiTimeout(..) => true,
+        _ => false,
+    }
+}
+
+/// Whether a [`watcher`] that failed with `error` should be left to restart: the API server is
+/// throttling us or is unavailable, the connection to it broke, or the watch fell behind (`410
+/// Gone`, the watcher lists the objects again).
+pub fn is_transient_watch(error: &watcher::Error) -> bool {
+    match error {
+        watcher::Error::InitialListFailed(error)
+        | watcher::Error::WatchStartFailed(error)
+        | watcher::Error::WatchFailed(error) => match error {
+            kube::Error::Api(response) => is_transient_code(response.code),
+            kube::Error::HyperError(..) | kube::Error::Service(..) => true,
+            _ => false,
+        },
+        watcher::Error::WatchError(response) => {
+            response.code == 410 || is_transient_code(response.code)
+        }
+        watcher::Error::NoResourceVersion => false,
+    }
+}
+
+/// Whether the API server responded with a status `code` that goes away on its own.
+fn is_transient_code(code: u16) -> bool {
+    matches!(code, 429 | 502 | 503 | 504)
+}
+
+/// Jittered exponential backoff between the attempts of [`RetryPolicy::run`].
+#[derive(Debug)]
+struct Backoff {
+    attempt: u32,
+    max_attempts: u32,
+}
+
+impl Backoff {
+    const MIN_DELAY: Duration = Duration::from_millis(500);
+
+    const MAX_DELAY: Duration = Duration::from_secs(10);
+
+    /// Returns how long to wait before the next attempt, or [`None`] if we should give up.
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= self.max_attempts {
+            return None;
+        }
+
+        let delay = Self::MIN_DELAY
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(Self::MAX_DELAY);
+        self.attempt += 1;
+
+        // Spread retries from multiple users, so that they don't hit the API server all at once.
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+
+        Some(delay.mul_f64(jitter))
+    }
+}
+
+/// How the Kubernetes API calls made while creating the agent are retried, see
+/// [`RetryPolicy::run`].
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How many times a failed call is retried.
+    retries: u32,
+    /// How long a single attempt can take.
+    timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(agent: &AgentConfig) -> Self {
+        Self {
+            retries: agent.kube_api_retries,
+            timeout: Duration::from_secs(agent.kube_api_timeout),
+        }
+    }
+
+    /// Runs the `request`, retrying it while it fails with a [transient](is_transient) error or
+    /// takes longer than the timeout. The `request` gets the number of the attempt, starting at
+    /// `0`.
+    ///
+    /// Reports the retries with a `progress` subtask, so that the user doesn't think mirrord
+    /// hangs. `what` describes the call, e.g. `"creating the agent job"`.
+    pub async fn run<T, F, Fut, P>(&self, progress: &P, what: &str, mut request: F) -> Result<T>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+        P: Progress,
+    {
+        let mut backoff = Backoff {
+            attempt: 0,
+            max_attempts: self.retries,
+        };
+        let mut retry_progress: Option<P> = None;
+
+        loop {
+            let result = tokio::time::timeout(self.timeout, request(backoff.attempt))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(KubeApiError::KubeApiTimeout(
+                        what.to_string(),
+                        self.timeout.as_secs(),
+                    ))
+                });
+
+            match result {
+                Err(error) if is_transient(&error) => {
+                    let Some(delay) = backoff.next_delay() else {
+                        if let Some(mut retry_progress) = retry_progress {
+                            retry_progress.failure(Some("out of retries, giving up"));
+                        }
+
+                        return Err(error);
+                    };
+
+                    warn!(%error, ?delay, what, "Kubernetes API call failed, retrying");
+
+                    let retry_progress = retry_progress
+                        .get_or_insert_with(|| progress.subtask(&format!("retrying {what}")));
+                    retry_progress.info(&format!(
+                        "{error}, retrying in {}ms (attempt {} of {})",
+                        delay.as_millis(),
+                        backoff.attempt,
+                        self.retries,
+                    ));
+
+                    tokio::time::sleep(delay).await;
+                }
+                result => {
+                    if let Some(mut retry_progress) = retry_progress {
+                        retry_progress.success(Some(&format!("{what} succeeded")));
+                    }
+
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use kube::core::ErrorResponse;
+    use mirrord_progress::NullProgress;
+    use rstest::rstest;
+
+    use super::*;
+
+    fn error_response(code: u16) -> ErrorResponse {
+        ErrorResponse {
+            status: "Failure".to_string(),
+            message: "error".to_string(),
+            reason: "error".to_string(),
+            code,
+        }
+    }
+
+    fn api_error(code: u16) -> KubeApiError {
+        KubeApiError::KubeError(kube::Error::Api(error_response(code)))
+    }
+
+    #[rstest]
+    #[case::throttled(watcher::Error::WatchError(error_response(429)), true)]
+    #[case::gone(watcher::Error::WatchError(error_response(410)), true)]
+    #[case::unavailable(
+        watcher::Error::InitialListFailed(kube::Error::Api(error_response(503))),
+        true
+    )]
+    #[case::forbidden(
+        watcher::Error::WatchStartFailed(kube::Error::Api(error_response(403))),
+        false
+    )]
+    #[case::not_found(watcher::Error::WatchError(error_response(404)), false)]
+    #[case::no_version(watcher::Error::NoResourceVersion, false)]
+    fn transient_watch(#[case] error: watcher::Error, #[case] transient: bool) {
+        assert_eq!(is_transient_watch(&error), transient);
+    }
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            timeout: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn backoff_gives_up() {
+        let mut backoff = Backoff {
+            attempt: 0,
+            max_attempts: 4,
+        };
+
+        for _ in 0..4 {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay >= Backoff::MIN_DELAY / 2);
+            assert!(delay <= Backoff::MAX_DELAY);
+        }
+
+        assert!(backoff.next_delay().is_none());
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let attempts = AtomicU32::new(0);
+
+        let result = policy(3)
+            .run(&NullProgress, "test", |attempt| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match attempt {
+                        0 => Err(api_error(429)),
+                        1 => Err(api_error(503)),
+                        _ => Ok(attempt),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result, 2);
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let result = policy(0)
+            .run(&NullProgress, "test", |_| {
+                std::future::pending::<Result<()>>()
+            })
+            .await;
+
+        assert!(
+            matches!(result, Err(KubeApiError::KubeApiTimeout(..))),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_other_errors() {
+        let attempts = AtomicU32::new(0);
+
+        let result = policy(3)
+            .run(&NullProgress, "test", |_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), _>(api_error(404)) }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+}
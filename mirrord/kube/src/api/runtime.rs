@@ -8,12 +8,15 @@ use std::{
 use k8s_openapi::{
     api::{
         apps::v1::Deployment,
-        core::v1::{Node, Pod},
+        core::v1::{ContainerStatus, Node, Pod},
     },
     apimachinery::pkg::api::resource::Quantity,
 };
 use kube::{api::ListParams, Api, Client};
-use mirrord_config::target::{DeploymentTarget, PodTarget, RolloutTarget, Target};
+use mirrord_config::{
+    feature::env::EnvContainer,
+    target::{DeploymentTarget, PodTarget, RolloutTarget, Target},
+};
 use mirrord_protocol::MeshVendor;
 
 use crate::{
@@ -52,6 +55,10 @@ pub struct RuntimeData {
 
     /// Used to check if we're running with a mesh/sidecar in `detect_mesh_mirror_mode`.
     pub mesh: Option<MeshVendor>,
+
+    /// Ids (and prefixes) of the other containers we load environment variables from, see
+    /// [`RuntimeData::select_containers`].
+    pub env_containers: Vec<(String, Option<String>)>,
 }
 
 impl RuntimeData {
@@ -87,29 +94,7 @@ impl RuntimeData {
         })?;
 
         let container_name = chosen_status.name.clone();
-        let container_id_full = chosen_status
-            .container_id
-            .as_ref()
-            .ok_or(KubeApiError::ContainerIdNotFound)?
-            .to_owned();
-
-        let mut split = container_id_full.split("://");
-
-        let container_runtime = match split.next() {
-            Some("docker") => ContainerRuntime::Docker,
-            Some("containerd") => ContainerRuntime::Containerd,
-            Some("cri-o") => ContainerRuntime::CriO,
-            _ => {
-                return Err(KubeApiError::ContainerRuntimeParseError(
-                    container_id_full.to_string(),
-                ))
-            }
-        };
-
-        let container_id = split
-            .next()
-            .ok_or_else(|| KubeApiError::ContainerRuntimeParseError(container_id_full.to_string()))?
-            .to_owned();
+        let (container_runtime, container_id) = parse_container_id(chosen_status)?;
 
         Ok(RuntimeData {
             pod_name,
@@ -119,9 +104,85 @@ impl RuntimeData {
             container_runtime,
             container_name,
             mesh,
+            env_containers: Default::default(),
         })
     }
 
+    /// Selects the target container by `container_index` (unless the target path already named
+    /// one, `container_named`), and finds the `env_containers` in the pod.
+    ///
+    /// Does nothing (and doesn't fetch the pod again) when there's nothing to select.
+    #[tracing::instrument(level = "trace", skip(self, client), err)]
+    pub async fn select_containers(
+        &mut self,
+        client: &Client,
+        container_index: Option<usize>,
+        container_named: bool,
+        env_containers: &[EnvContainer],
+    ) -> Result<()> {
+        let container_index = container_index.filter(|_| !container_named);
+        if container_index.is_none() && env_containers.is_empty() {
+            return Ok(());
+        }
+
+        let pod_api: Api<Pod> = get_k8s_resource_api(client, self.pod_namespace.as_deref());
+        let pod = pod_api.get(&self.pod_name).await?;
+
+        if let Some(index) = container_index {
+            let name = pod
+                .spec
+                .as_ref()
+                .ok_or(KubeApiError::PodSpecNotFound)?
+                .containers
+                .get(index)
+                .ok_or_else(|| KubeApiError::ContainerNotFound(format!("#{index}")))?
+                .name
+                .clone();
+
+            *self = Self::from_pod(&pod, &Some(name))?;
+        }
+
+        let container_statuses = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.container_statuses.as_ref())
+            .ok_or(KubeApiError::ContainerStatusNotFound)?;
+
+        self.env_containers = env_containers
+            .iter()
+            .map(|EnvContainer { name, prefix }| {
+                let status = container_statuses
+                    .iter()
+                    .find(|status| &status.name == name)
+                    .ok_or_else(|| KubeApiError::ContainerNotFound(name.clone()))?;
+                let (_, container_id) = parse_container_id(status)?;
+
+                Ok((container_id, prefix.clone()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(())
+    }
+
+    /// Whether the target runs on a virtual node (see [`VIRTUAL_NODE_LABELS`]), where the agent
+    /// has to run as a sidecar. A node we can't read counts as not virtual.
+    #[tracing::instrument(level = "trace", skip(client), ret)]
+    pub async fn is_virtual_node(&self, client: &Client) -> bool {
+        let node_api: Api<Node> = Api::all(client.clone());
+
+        let labels = match node_api.get(&self.node_name).await {
+            Ok(node) => node.metadata.labels.unwrap_or_default(),
+            Err(error) => {
+                tracing::debug!(%error, "failed to get the target's node");
+                return false;
+            }
+        };
+
+        VIRTUAL_NODE_LABELS
+            .iter()
+            .any(|(key, value)| labels.get(*key).is_some_and(|label| label == value))
+    }
+
     #[tracing::instrument(level = "trace", skip(client), ret)]
     pub async fn check_node(&self, client: &kube::Client) -> NodeCheck {
         let node_api: Api<Node> = Api::all(client.clone());
@@ -166,6 +227,40 @@ impl RuntimeData {
     }
 }
 
+/// Labels of nodes where privileged agents can't run: virtual kubelets and EKS Fargate.
+const VIRTUAL_NODE_LABELS: [(&str, &str); 2] = [
+    ("type", "virtual-kubelet"),
+    ("eks.amazonaws.com/compute-type", "fargate"),
+];
+
+/// Splits the `<runtime>://<id>` container id from the [`ContainerStatus`].
+fn parse_container_id(status: &ContainerStatus) -> Result<(ContainerRuntime, String)> {
+    let container_id_full = status
+        .container_id
+        .as_ref()
+        .ok_or(KubeApiError::ContainerIdNotFound)?;
+
+    let mut split = container_id_full.split("://");
+
+    let container_runtime = match split.next() {
+        Some("docker") => ContainerRuntime::Docker,
+        Some("containerd") => ContainerRuntime::Containerd,
+        Some("cri-o") => ContainerRuntime::CriO,
+        _ => {
+            return Err(KubeApiError::ContainerRuntimeParseError(
+                container_id_full.to_string(),
+            ))
+        }
+    };
+
+    let container_id = split
+        .next()
+        .ok_or_else(|| KubeApiError::ContainerRuntimeParseError(container_id_full.to_string()))?
+        .to_owned();
+
+    Ok((container_runtime, container_id))
+}
+
 #[derive(Debug)]
 pub enum NodeCheck {
     Success,
@@ -310,12 +405,36 @@ impl RuntimeDataFromLabels for RolloutTarget {
             .await
             .map_err(KubeApiError::KubeError)?;
 
-        rollout.match_labels().ok_or_else(|| {
+        let labels = match (rollout.match_labels(), rollout.workload_ref_deployment()) {
+            (Some(labels), _) => Some(labels),
+            // The selector is optional when the pod template comes from a Deployment.
+            (None, Some(deployment)) => DeploymentTarget {
+                deployment: deployment.to_string(),
+                container: None,
+            }
+            .get_labels(client, namespace)
+            .await
+            .ok(),
+            (None, None) => None,
+        };
+
+        let mut labels = labels.ok_or_else(|| {
             KubeApiError::DeploymentNotFound(format!(
                 "Label for rollout: {}, not found!",
                 self.rollout.clone()
             ))
-        })
+        })?;
+
+        // During a canary or blue-green update, the selector also matches the pods of the new
+        // ReplicaSet, we want the ones that are actually live.
+        if let Some(hash) = rollout.live_pod_template_hash() {
+            labels.insert(
+                Rollout::POD_TEMPLATE_HASH_LABEL.to_string(),
+                hash.to_string(),
+            );
+        }
+
+        Ok(labels)
     }
 }
 
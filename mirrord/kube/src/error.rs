@@ -60,6 +60,27 @@ pub enum KubeApiError {
     #[error("Port not found in port forward")]
     PortForwardFailed,
 
+    #[error("Failed to attach to the stdio of the agent bridge")]
+    AgentExecFailed,
+
+    #[error("Ephemeral agents run in the target's cluster, so `target.kube_context` and `agent.kube_context` must be the same")]
+    EphemeralAgentContext,
+
+    #[error("`kube_api_url` `{0}` is not a valid URL")]
+    InvalidKubeApiUrl(String),
+
+    #[error("Kubernetes API call ({0}) timed out after {1}s")]
+    KubeApiTimeout(String, u64),
+
+    #[error("Failed {0}: {1}")]
+    WatchFailed(String, kube::runtime::watcher::Error),
+
+    #[error("Sidecar agents run in a copy of the target pod, so `target.kube_context` and `agent.kube_context` must be the same")]
+    SidecarAgentContext,
+
+    #[error("`agent.openshift_uid_range` is set, but namespace `{0}` has no valid `openshift.io/sa.scc.uid-range` annotation")]
+    OpenShiftUidRangeNotFound(String),
+
     #[error("Invaild Address Conversion: {0}")]
     InvalidAddress(#[from] AddrParseError),
 
@@ -78,4 +99,10 @@ pub enum KubeApiError {
 
     #[error("Path expansion for kubeconfig failed: {0}")]
     ConfigPathExpansionError(String),
+
+    #[error("Invalid proxy url `{0}`!")]
+    InvalidProxyUrl(String),
+
+    #[error("Failed to start the local proxy for the ssh bastion: {0}")]
+    SshProxyFailed(std::io::Error),
 }
@@ -11,6 +11,9 @@
 //! It affects the way [`api::kubernetes::KubernetesAPI`] connects to created agents.
 //! From outside of the cluster, [`kube`]s port forwarding is used.
 //! From inside of the cluster, plain TCP connection is made.
+//!
+//! In the cluster we authenticate with the pod's projected service account token. Clients are
+//! rebuilt when kubelet rotates it, see [`api::credentials`].
 
 pub mod api;
 pub mod error;
@@ -98,6 +98,9 @@ pub fn hook_fn(
 }
 
 /// Same as above but calls the original function if detour guard is active.
+///
+/// Also calls the original function when the hook panics and the layer disables itself, see
+/// `on_layer_error` in the config.
 #[proc_macro_attribute]
 pub fn hook_guard_fn(
     _args: proc_macro::TokenStream,
@@ -161,6 +164,10 @@ pub fn hook_guard_fn(
                 crate::detour::HookFn::default_const()
         };
 
+        // Panics are caught, so that the layer can disable itself instead of taking the process
+        // down, see `crate::layer_error`. The hook then runs once more, without the internal
+        // proxy, so that remote handles fail instead of reaching the original function, which
+        // doesn't know them. The original function is called only if it panics again.
         let statements = proper_function.block.stmts.to_vec();
         let mut modified_function = proper_function;
         modified_function.block.stmts = Block::parse_within
@@ -169,9 +176,23 @@ pub fn hook_guard_fn(
                 if __bypass.is_none() {
                     return #static_name (#fn_arg_names);
                 }
+
+                let __hook = || {
+                    #(#statements)*
+                };
+
+                match std::panic::catch_unwind(std::panic::AssertUnwindSafe(&__hook)) {
+                    Ok(result) => result,
+                    Err(payload) => {
+                        crate::layer_error::on_panic(payload);
+                        crate::detour::take_remote_handle();
+
+                        std::panic::catch_unwind(std::panic::AssertUnwindSafe(&__hook))
+                            .unwrap_or_else(|_| #static_name (#fn_arg_names))
+                    }
+                }
             ))
             .unwrap();
-        modified_function.block.stmts.extend(statements);
 
         let output = quote! {
             #[allow(non_camel_case_types)]
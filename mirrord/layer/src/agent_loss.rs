@@ -0,0 +1,64 @@
+//! Keeps the process running locally when the agent is lost, with `on_agent_loss: "degrade"`.
+//!
+//! The agent is lost when a request to the internal proxy fails (other than with
+//! [`ProxyError::Interrupted`], which the proxy recovers from), e.g. because it gave up
+//! reconnecting and exited. From then on, the layer stops talking to the proxy, and the hooks
+//! bypass to the local functions for new files, connections and DNS queries.
+//!
+//! Files, directories and sockets that were handled remotely can't be bypassed, the local
+//! functions don't know them. Operations on them fail with `EIO`, see
+//! [`mark_remote_handle`](crate::detour::mark_remote_handle).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mirrord_config::OnAgentLoss;
+
+use crate::{error::HookError, proxy_connection::ProxyError, SETUP};
+
+/// Set when the agent was lost and the layer does everything locally.
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the agent was lost and the layer does everything locally.
+pub(crate) fn is_degraded() -> bool {
+    DEGRADED.load(Ordering::Relaxed)
+}
+
+/// Called when a request to the internal proxy failed with the given `error`.
+///
+/// Returns whether the layer degrades to local operations (with `on_agent_loss: "degrade"`),
+/// warning the user the first time.
+pub(crate) fn degrade(error: &ProxyError) -> bool {
+    if matches!(error, ProxyError::Interrupted | ProxyError::Signaled) {
+        return false;
+    }
+
+    let on_agent_loss = SETUP
+        .get()
+        .map(|setup| setup.on_agent_loss())
+        .unwrap_or_default();
+    if on_agent_loss != OnAgentLoss::Degrade {
+        return false;
+    }
+
+    if !DEGRADED.swap(true, Ordering::Relaxed) {
+        // Logged as an error, so that it's shown with the default log level.
+        tracing::error!(
+            %error,
+            "The connection with the agent was lost. The process keeps running, but new files are \
+            opened locally, outgoing connections and DNS queries go local, and no incoming \
+            traffic arrives. Remote files and directories that are still open fail with EIO."
+        );
+    }
+
+    true
+}
+
+/// Whether the operation that failed with the given `error` should be done locally, because the
+/// agent was lost.
+pub(crate) fn bypasses(error: &HookError) -> bool {
+    match error {
+        HookError::AgentLost => true,
+        HookError::ProxyError(error) => degrade(error),
+        _ => false,
+    }
+}
@@ -0,0 +1,610 @@
+//! Carries the environment that loads the layer into the child processes.
+//!
+//! Child processes inherit the environment of the process, so they load the layer too. But
+//! programs may spawn children with an environment of their own (e.g. `env -i`, or
+//! `Command::env_clear`), where `LD_PRELOAD`/`DYLD_INSERT_LIBRARIES` and the `MIRRORD_*` variables
+//! (the config and the address of the internal proxy) are missing, so these children escape
+//! mirrord.
+//!
+//! We [`capture`] these variables when the layer starts, and add the missing ones to the
+//! environment passed to `execve` and `posix_spawn`/`posix_spawnp`.
+//!
+//! Newer glibc spawns the children of `posix_spawn` with `clone3`, which is not exported, so we
+//! catch them in the parent, in `posix_spawn`, and again in `execve`. `vfork` children share the
+//! memory of the parent until they exec, so we turn `vfork` into `fork`, see
+//! [`vfork_detour`](crate::vfork_detour).
+//!
+//! On Linux, the CLI may also ship the layer built for the other architecture, for children of
+//! that architecture (e.g. x86_64 programs under emulation on aarch64), whose dynamic loader
+//! would skip the layer of this process. We read the ELF header of the executed program (or of the
+//! interpreter of a script), and load the layer of its architecture, see [`with_exec_layer_env`].
+
+use std::{
+    ffi::{CStr, CString},
+    ptr,
+    sync::OnceLock,
+};
+
+use libc::c_char;
+#[cfg(target_os = "linux")]
+use libc::{c_int, c_void, pid_t};
+#[cfg(target_os = "linux")]
+use mirrord_layer_macro::hook_guard_fn;
+
+#[cfg(target_os = "linux")]
+use crate::{hooks::HookManager, replace};
+
+/// Variable that loads the layer into the process.
+#[cfg(target_os = "linux")]
+const INJECTION_ENV_VAR: &str = "LD_PRELOAD";
+#[cfg(target_os = "macos")]
+const INJECTION_ENV_VAR: &str = "DYLD_INSERT_LIBRARIES";
+
+/// Prefix of the variables that configure the layer.
+const MIRRORD_ENV_PREFIX: &str = "MIRRORD_";
+
+/// `e_machine` of x86_64 ELF files.
+#[cfg(target_os = "linux")]
+const EM_X86_64: u16 = 62;
+
+/// `e_machine` of aarch64 ELF files.
+#[cfg(target_os = "linux")]
+const EM_AARCH64: u16 = 183;
+
+/// `e_machine` of the executables that load the layer of this process.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const EM_LAYER: u16 = EM_X86_64;
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const EM_LAYER: u16 = EM_AARCH64;
+
+/// Variables with the path of the layer built for every architecture, by the `e_machine` of the
+/// executables that load it. Set by the CLI when it ships the layer of the other architecture
+/// too, see `extract.rs` in the CLI.
+#[cfg(target_os = "linux")]
+const LAYER_FILE_ENV_VARS: [(u16, &str); 2] = [
+    (EM_X86_64, "MIRRORD_LAYER_FILE_X86_64"),
+    (EM_AARCH64, "MIRRORD_LAYER_FILE_AARCH64"),
+];
+
+/// How many bytes of the executed file we read, enough for the ELF header and for the shebang
+/// line of a script.
+#[cfg(target_os = "linux")]
+const HEADER_SIZE: usize = 256;
+
+/// The layer environment, set in [`capture`].
+static LAYER_ENV: OnceLock<Vec<LayerVar>> = OnceLock::new();
+
+/// Variable of the layer environment.
+#[derive(Debug)]
+struct LayerVar {
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl LayerVar {
+    /// The value of this variable in the `NAME=value` `entry`, if it's an entry of this variable.
+    fn value_in<'a>(&self, entry: &'a [u8]) -> Option<&'a [u8]> {
+        entry.strip_prefix(self.name.as_slice())?.strip_prefix(b"=")
+    }
+
+    /// Whether this variable is set in the `NAME=value` `entry`.
+    ///
+    /// The injection variable also has to include our value, as the program may set it to load
+    /// libraries of its own.
+    fn is_set_in(&self, entry: &[u8]) -> bool {
+        match self.value_in(entry) {
+            Some(value) if self.name == INJECTION_ENV_VAR.as_bytes() => value
+                .windows(self.value.len())
+                .any(|window| window == self.value),
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// `NAME=value` entry that sets this variable, keeping the libraries that the `entry` already
+    /// loads with the injection variable.
+    fn entry(&self, entry: Option<&[u8]>) -> Option<CString> {
+        let mut new_entry = [self.name.as_slice(), b"="].concat();
+        if let Some(value) = entry
+            .and_then(|entry| self.value_in(entry))
+            .filter(|value| !value.is_empty())
+        {
+            new_entry.extend_from_slice(value);
+            new_entry.push(b':');
+        }
+        new_entry.extend_from_slice(&self.value);
+
+        CString::new(new_entry).ok()
+    }
+}
+
+/// Captures the layer environment of this process, to be carried into its children.
+///
+/// Should be called when the layer starts, before the program changes the environment.
+pub(crate) fn capture() {
+    let layer_env = std::env::vars()
+        .filter(|(name, value)| {
+            !value.is_empty()
+                && name != crate::REMOTE_ENV_FETCHED
+                && (name == INJECTION_ENV_VAR || name.starts_with(MIRRORD_ENV_PREFIX))
+        })
+        .map(|(name, value)| LayerVar {
+            name: name.into_bytes(),
+            value: value.into_bytes(),
+        })
+        .collect();
+
+    let _ = LAYER_ENV.set(layer_env);
+}
+
+/// Entries of the null-terminated `envp` array.
+unsafe fn entries<'a>(envp: *const *const c_char) -> impl Iterator<Item = &'a [u8]> {
+    let mut next = envp;
+
+    std::iter::from_fn(move || {
+        if next.is_null() || (*next).is_null() {
+            return None;
+        }
+
+        let entry = CStr::from_ptr(*next).to_bytes();
+        next = next.add(1);
+        Some(entry)
+    })
+}
+
+/// Returns the environment `envp` with the missing layer variables added, or [`None`] when none
+/// is missing.
+///
+/// Doesn't allocate when none is missing, so that it's safe to call from the child of a `clone3`
+/// that shares the memory of the parent.
+pub(crate) unsafe fn with_layer_env(envp: *const *const c_char) -> Option<Vec<CString>> {
+    let layer_env = LAYER_ENV.get()?;
+    if layer_env
+        .iter()
+        .all(|var| entries(envp).any(|entry| var.is_set_in(entry)))
+    {
+        return None;
+    }
+
+    let mut env = entries(envp)
+        .map(|entry| {
+            let var = layer_env.iter().find(|var| var.value_in(entry).is_some());
+            match var {
+                Some(var) if !var.is_set_in(entry) => var.entry(Some(entry)),
+                _ => CString::new(entry).ok(),
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    env.extend(
+        layer_env
+            .iter()
+            .filter(|var| !entries(envp).any(|entry| var.value_in(entry).is_some()))
+            .filter_map(|var| var.entry(None)),
+    );
+
+    Some(env)
+}
+
+/// `e_machine` of the ELF file that starts with `header`.
+#[cfg(target_os = "linux")]
+fn elf_machine(header: &[u8]) -> Option<u16> {
+    if !header.starts_with(b"\x7fELF") {
+        return None;
+    }
+
+    let machine = [*header.get(18)?, *header.get(19)?];
+    match header.get(5)? {
+        1 => Some(u16::from_le_bytes(machine)),
+        2 => Some(u16::from_be_bytes(machine)),
+        _ => None,
+    }
+}
+
+/// Interpreter of the script that starts with `header`, from its `#!` line.
+#[cfg(target_os = "linux")]
+fn shebang_interpreter(header: &[u8]) -> Option<&[u8]> {
+    let line = header
+        .strip_prefix(b"#!")?
+        .split(|byte| *byte == b'\n')
+        .next()?;
+
+    line.split(|byte| *byte == b' ' || *byte == b'\t')
+        .find(|word| !word.is_empty())
+}
+
+/// Reads the start of the file at `path` into `buffer`, returning how many bytes were read.
+///
+/// Uses `buffer` only, so that it's safe to call from the child of a `clone3`, see
+/// [`with_layer_env`]. Called from the exec hooks, so the file hooks bypass these calls.
+#[cfg(target_os = "linux")]
+unsafe fn read_header(path: *const c_char, buffer: &mut [u8; HEADER_SIZE]) -> Option<usize> {
+    let fd = libc::open(path, libc::O_RDONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return None;
+    }
+
+    let read = libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len());
+    libc::close(fd);
+
+    usize::try_from(read).ok()
+}
+
+/// `e_machine` of the program that runs when executing the file at `path`: of the file itself,
+/// or of the interpreter of a script.
+#[cfg(target_os = "linux")]
+unsafe fn exec_machine(path: *const c_char) -> Option<u16> {
+    let mut header = [0; HEADER_SIZE];
+    let read = read_header(path, &mut header)?;
+    let header = header.get(..read)?;
+
+    if let Some(machine) = elf_machine(header) {
+        return Some(machine);
+    }
+
+    // A NUL-terminated copy of the interpreter path, on the stack.
+    let interpreter = shebang_interpreter(header)?;
+    let mut interpreter_path = [0; HEADER_SIZE];
+    interpreter_path
+        .get_mut(..interpreter.len())?
+        .copy_from_slice(interpreter);
+
+    let mut header = [0; HEADER_SIZE];
+    let read = read_header(interpreter_path.as_ptr().cast(), &mut header)?;
+    elf_machine(header.get(..read)?)
+}
+
+/// Path of the layer to load into the program executed from `path`, when that's not the layer of
+/// this process, because the program is built for the other architecture.
+#[cfg(target_os = "linux")]
+unsafe fn exec_layer_file(path: *const c_char) -> Option<&'static [u8]> {
+    let layer_env = LAYER_ENV.get()?;
+    let layer_file = |machine: u16| {
+        let (_, name) = LAYER_FILE_ENV_VARS.iter().find(|(em, _)| *em == machine)?;
+        layer_env
+            .iter()
+            .find(|var| var.name == name.as_bytes())
+            .map(|var| var.value.as_slice())
+    };
+
+    // Nothing to switch to when the CLI doesn't ship the layer of the other architecture.
+    layer_file(EM_LAYER)?;
+
+    match exec_machine(path)? {
+        EM_LAYER => None,
+        machine => layer_file(machine),
+    }
+}
+
+/// The injection variable `entry`, loading `layer_file` instead of the layer of the other
+/// architectures.
+#[cfg(target_os = "linux")]
+fn with_layer_file(entry: &[u8], layer_file: &[u8]) -> Option<CString> {
+    let Some(value) = entry
+        .strip_prefix(INJECTION_ENV_VAR.as_bytes())
+        .and_then(|rest| rest.strip_prefix(b"="))
+    else {
+        return CString::new(entry).ok();
+    };
+
+    let layer_env = LAYER_ENV.get()?;
+    let other_layer_files = LAYER_FILE_ENV_VARS
+        .iter()
+        .filter_map(|(_, name)| layer_env.iter().find(|var| var.name == name.as_bytes()))
+        .map(|var| var.value.as_slice())
+        .filter(|file| *file != layer_file)
+        .collect::<Vec<_>>();
+
+    let libraries = value
+        .split(|byte| *byte == b':')
+        .map(|library| {
+            if other_layer_files.contains(&library) {
+                layer_file
+            } else {
+                library
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(&b':');
+
+    CString::new([INJECTION_ENV_VAR.as_bytes(), b"=", libraries.as_slice()].concat()).ok()
+}
+
+/// Returns the environment of [`with_layer_env`] for the program executed from `path`, which
+/// also loads the layer built for the architecture of the program, or [`None`] when it doesn't
+/// change `envp`.
+///
+/// Allocates only when it changes `envp`, see [`with_layer_env`].
+#[cfg(target_os = "linux")]
+unsafe fn with_exec_layer_env(
+    path: *const c_char,
+    envp: *const *const c_char,
+) -> Option<Vec<CString>> {
+    let env = with_layer_env(envp);
+    let Some(layer_file) = exec_layer_file(path) else {
+        return env;
+    };
+
+    let current = match &env {
+        Some(env) => env.iter().map(|entry| entry.to_bytes()).collect::<Vec<_>>(),
+        None => entries(envp).collect(),
+    };
+
+    current
+        .into_iter()
+        .map(|entry| with_layer_file(entry, layer_file))
+        .collect()
+}
+
+/// Path of the program that `posix_spawnp` executes for `file`: `file` itself when it has a `/`,
+/// or the first executable file named `file` in the `PATH` of this process.
+#[cfg(target_os = "linux")]
+unsafe fn spawnp_path(file: *const c_char) -> Option<CString> {
+    use std::{
+        ffi::OsStr,
+        os::unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::MetadataExt,
+        },
+    };
+
+    let file = CStr::from_ptr(file);
+    if file.to_bytes().contains(&b'/') {
+        return Some(file.to_owned());
+    }
+
+    let file = OsStr::from_bytes(file.to_bytes());
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(file))
+        .find(|path| {
+            path.metadata()
+                .is_ok_and(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+        })
+        .and_then(|path| CString::new(path.into_os_string().into_vec()).ok())
+}
+
+/// Null-terminated array of pointers to the entries of `env`, to be passed as `envp`.
+pub(crate) fn env_pointers(env: &[CString]) -> Vec<*const c_char> {
+    env.iter()
+        .map(|entry| entry.as_ptr())
+        .chain([ptr::null()])
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn enable_hooks(hook_manager: &mut HookManager) {
+    replace!(hook_manager, "execve", execve_detour, FnExecve, FN_EXECVE);
+    replace!(
+        hook_manager,
+        "posix_spawn",
+        posix_spawn_detour,
+        FnPosix_spawn,
+        FN_POSIX_SPAWN
+    );
+    replace!(
+        hook_manager,
+        "posix_spawnp",
+        posix_spawnp_detour,
+        FnPosix_spawnp,
+        FN_POSIX_SPAWNP
+    );
+}
+
+/// Hook for `libc::execve`, that adds the missing layer variables to `envp`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn execve_detour(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    match with_exec_layer_env(path, envp) {
+        Some(env) => FN_EXECVE(path, argv, env_pointers(&env).as_ptr()),
+        None => FN_EXECVE(path, argv, envp),
+    }
+}
+
+/// Hook for `libc::posix_spawn`, that adds the missing layer variables to `envp`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn posix_spawn_detour(
+    pid: *mut pid_t,
+    path: *const c_char,
+    file_actions: *const c_void,
+    attrp: *const c_void,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    match with_exec_layer_env(path, envp) {
+        Some(env) => FN_POSIX_SPAWN(
+            pid,
+            path,
+            file_actions,
+            attrp,
+            argv,
+            env_pointers(&env).as_ptr(),
+        ),
+        None => FN_POSIX_SPAWN(pid, path, file_actions, attrp, argv, envp),
+    }
+}
+
+/// Hook for `libc::posix_spawnp`, see [`posix_spawn_detour`].
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn posix_spawnp_detour(
+    pid: *mut pid_t,
+    file: *const c_char,
+    file_actions: *const c_void,
+    attrp: *const c_void,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    let env = match spawnp_path(file) {
+        Some(path) => with_exec_layer_env(path.as_ptr(), envp),
+        None => with_layer_env(envp),
+    };
+
+    match env {
+        Some(env) => FN_POSIX_SPAWNP(
+            pid,
+            file,
+            file_actions,
+            attrp,
+            argv,
+            env_pointers(&env).as_ptr(),
+        ),
+        None => FN_POSIX_SPAWNP(pid, file, file_actions, attrp, argv, envp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer_var(name: &str, value: &str) -> LayerVar {
+        LayerVar {
+            name: name.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn injection_var_keeps_other_libraries() {
+        let var = layer_var(INJECTION_ENV_VAR, "/tmp/libmirrord_layer.so");
+        let entry = format!("{INJECTION_ENV_VAR}=/usr/lib/other.so");
+
+        assert!(!var.is_set_in(entry.as_bytes()));
+        assert_eq!(
+            var.entry(Some(entry.as_bytes())).unwrap().to_bytes(),
+            format!("{INJECTION_ENV_VAR}=/usr/lib/other.so:/tmp/libmirrord_layer.so").as_bytes()
+        );
+    }
+
+    #[test]
+    fn other_values_are_kept() {
+        let var = layer_var("MIRRORD_CONNECT_TCP", "127.0.0.1:1234");
+
+        assert!(var.is_set_in(b"MIRRORD_CONNECT_TCP=127.0.0.1:5678"));
+        assert!(!var.is_set_in(b"MIRRORD_CONNECT_TCP_OTHER=127.0.0.1:1234"));
+    }
+
+    /// The layer of this process, and of the other architecture, in [`LAYER_ENV`].
+    #[cfg(target_os = "linux")]
+    fn layer_files() -> (&'static [u8], &'static [u8], u16) {
+        let (cross_machine, cross_var) = LAYER_FILE_ENV_VARS
+            .into_iter()
+            .find(|(machine, _)| *machine != EM_LAYER)
+            .unwrap();
+        let (_, layer_var_name) = LAYER_FILE_ENV_VARS
+            .into_iter()
+            .find(|(machine, _)| *machine == EM_LAYER)
+            .unwrap();
+
+        LAYER_ENV.get_or_init(|| {
+            vec![
+                layer_var(INJECTION_ENV_VAR, "/tmp/libmirrord_layer.so"),
+                layer_var(layer_var_name, "/tmp/libmirrord_layer.so"),
+                layer_var(cross_var, "/tmp/cross/libmirrord_layer.so"),
+            ]
+        });
+
+        (
+            b"/tmp/libmirrord_layer.so",
+            b"/tmp/cross/libmirrord_layer.so",
+            cross_machine,
+        )
+    }
+
+    /// Writes `contents` to a file in the temp dir.
+    #[cfg(target_os = "linux")]
+    fn temp_file(name: &str, contents: &[u8]) -> CString {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+
+        CString::new(path.into_os_string().into_encoded_bytes()).unwrap()
+    }
+
+    /// Start of an ELF header, up to `e_machine`.
+    #[cfg(target_os = "linux")]
+    fn elf_header(machine: u16) -> Vec<u8> {
+        let mut header = b"\x7fELF\x02\x01\x01".to_vec();
+        header.resize(18, 0);
+        header.extend_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn elf_and_script_machine() {
+        assert_eq!(elf_machine(&elf_header(EM_AARCH64)), Some(EM_AARCH64));
+        assert_eq!(elf_machine(b"#!/bin/sh\n"), None);
+
+        let mut big_endian = b"\x7fELF\x02\x02\x01".to_vec();
+        big_endian.resize(18, 0);
+        big_endian.extend_from_slice(&EM_X86_64.to_be_bytes());
+        assert_eq!(elf_machine(&big_endian), Some(EM_X86_64));
+
+        assert_eq!(
+            shebang_interpreter(b"#! /usr/bin/env  python3\nprint()\n"),
+            Some(b"/usr/bin/env".as_slice())
+        );
+        assert_eq!(shebang_interpreter(b"echo\n"), None);
+
+        let this = unsafe { exec_machine(c"/proc/self/exe".as_ptr()) };
+        assert_eq!(this, Some(EM_LAYER));
+
+        let script = temp_file("script", b"#!/proc/self/exe\n");
+        assert_eq!(unsafe { exec_machine(script.as_ptr()) }, Some(EM_LAYER));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn loads_layer_of_executed_architecture() {
+        let (layer_file, cross_layer_file, cross_machine) = layer_files();
+
+        let native = temp_file("native", &elf_header(EM_LAYER));
+        assert_eq!(unsafe { exec_layer_file(native.as_ptr()) }, None);
+
+        let cross = temp_file("cross", &elf_header(cross_machine));
+        assert_eq!(
+            unsafe { exec_layer_file(cross.as_ptr()) },
+            Some(cross_layer_file)
+        );
+
+        let entry = [
+            INJECTION_ENV_VAR.as_bytes(),
+            b"=/usr/lib/other.so:",
+            layer_file,
+        ]
+        .concat();
+        let expected = [
+            INJECTION_ENV_VAR.as_bytes(),
+            b"=/usr/lib/other.so:",
+            cross_layer_file,
+        ]
+        .concat();
+        assert_eq!(
+            with_layer_file(&entry, cross_layer_file)
+                .unwrap()
+                .to_bytes(),
+            expected
+        );
+        assert_eq!(
+            with_layer_file(&expected, layer_file).unwrap().to_bytes(),
+            entry
+        );
+        assert_eq!(
+            with_layer_file(b"MIRRORD_CONNECT_TCP=127.0.0.1:1234", cross_layer_file)
+                .unwrap()
+                .to_bytes(),
+            b"MIRRORD_CONNECT_TCP=127.0.0.1:1234"
+        );
+
+        // The environment of `env -i`.
+        let envp = [ptr::null()];
+        let env = unsafe { with_exec_layer_env(cross.as_ptr(), envp.as_ptr()) }.unwrap();
+        assert!(env.iter().any(|entry| entry.to_bytes()
+            == [INJECTION_ENV_VAR.as_bytes(), b"=", cross_layer_file].concat()));
+    }
+}
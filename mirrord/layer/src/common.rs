@@ -9,10 +9,11 @@ use mirrord_sip::{MIRRORD_TEMP_BIN_DIR_CANONIC_STRING, MIRRORD_TEMP_BIN_DIR_STRI
 use tracing::warn;
 
 use crate::{
+    agent_loss,
     detour::{Bypass, Detour},
     error::{HookError, HookResult},
     file::OpenOptionsInternalExt,
-    PROXY_CONNECTION,
+    layer_error, PROXY_CONNECTION,
 };
 
 /// Makes a request to the internal proxy using global [`PROXY_CONNECTION`].
@@ -22,6 +23,14 @@ where
     T: IsLayerRequestWithResponse + Debug,
     T::Response: Debug,
 {
+    if agent_loss::is_degraded() {
+        return Err(HookError::AgentLost);
+    }
+
+    if layer_error::is_disabled() {
+        return Err(HookError::LayerDisabled);
+    }
+
     // SAFETY: mutation happens only on initialization.
     unsafe {
         PROXY_CONNECTION
@@ -37,6 +46,14 @@ where
 pub fn make_proxy_request_no_response<T: IsLayerRequest + Debug>(
     request: T,
 ) -> HookResult<MessageId> {
+    if agent_loss::is_degraded() {
+        return Err(HookError::AgentLost);
+    }
+
+    if layer_error::is_disabled() {
+        return Err(HookError::LayerDisabled);
+    }
+
     // SAFETY: mutation happens only on initialization.
     unsafe {
         PROXY_CONNECTION
@@ -47,6 +64,14 @@ pub fn make_proxy_request_no_response<T: IsLayerRequest + Debug>(
     }
 }
 
+/// Whether the layer stopped talking to the internal proxy, because the agent was lost or the
+/// layer disabled itself.
+///
+/// Files and directories that were handled remotely are gone then, and there's nothing to close.
+pub(crate) fn is_proxy_gone() -> bool {
+    agent_loss::is_degraded() || layer_error::is_disabled()
+}
+
 /// Converts raw pointer values `P` to some other type.
 ///
 /// ## Usage
@@ -9,12 +9,18 @@ use core::{
     convert,
     ops::{FromResidual, Residual, Try},
 };
-use std::{cell::RefCell, ops::Deref, os::unix::prelude::*, path::PathBuf, sync::OnceLock};
+use std::{
+    cell::{Cell, RefCell},
+    ops::Deref,
+    os::unix::prelude::*,
+    path::PathBuf,
+    sync::OnceLock,
+};
 
 #[cfg(target_os = "macos")]
 use libc::c_char;
 
-use crate::error::HookError;
+use crate::{agent_loss, error::HookError, layer_error};
 
 thread_local!(
     /// Holds the thread-local state for bypassing the layer's detour functions.
@@ -40,6 +46,26 @@ thread_local!(
     static DETOUR_BYPASS: RefCell<bool> = const { RefCell::new(false) }
 );
 
+thread_local!(
+    /// Set when the current hook operates on a file, directory or socket that is handled remotely,
+    /// see [`mark_remote_handle`].
+    static REMOTE_HANDLE: Cell<bool> = const { Cell::new(false) }
+);
+
+/// Marks the current hook as operating on a remote file, directory or socket.
+///
+/// When the agent is lost or the layer disabled itself, such a hook fails with
+/// [`HookError::RemoteHandleLost`] instead of calling the [`libc`] function, which doesn't know the
+/// handle (a remote directory stream isn't even a valid `DIR *`).
+pub(crate) fn mark_remote_handle() {
+    REMOTE_HANDLE.set(true);
+}
+
+/// Whether the current hook was marked with [`mark_remote_handle`], resetting the mark.
+pub(crate) fn take_remote_handle() -> bool {
+    REMOTE_HANDLE.replace(false)
+}
+
 /// Sets [`DETOUR_BYPASS`] to `false`.
 ///
 /// Prefer relying on the [`Drop`] implementation of [`DetourGuard`] instead.
@@ -63,6 +89,9 @@ pub(crate) struct DetourGuard;
 
 impl DetourGuard {
     /// Create a new DetourGuard if it's not already enabled.
+    ///
+    /// Still created when the layer disabled itself, see [`layer_error`], so that the hooks can
+    /// tell remote handles apart from local ones.
     pub(crate) fn new() -> Option<Self> {
         DETOUR_BYPASS.with(|enabled| {
             if let Ok(bypass) = enabled.try_borrow()
@@ -71,6 +100,7 @@ impl DetourGuard {
                 None
             } else if let Ok(mut bypass) = enabled.try_borrow_mut() {
                 *bypass = true;
+                REMOTE_HANDLE.set(false);
                 Some(Self)
             } else {
                 None
@@ -134,6 +164,19 @@ pub(crate) enum Bypass {
     /// Similar to `LocalFdNotFound`, but for [`OPEN_DIRS`](crate::file::open_dirs::OPEN_DIRS).
     LocalDirStreamNotFound(usize),
 
+    /// Similar to `LocalFdNotFound`, but for [`FTS_STREAMS`](crate::file::fts::FTS_STREAMS).
+    #[cfg(target_os = "linux")]
+    LocalFtsStreamNotFound(usize),
+
+    /// Called a file tree traversal function (like `glob` or `fts_open`) with flags that we don't
+    /// handle remotely.
+    UnsupportedFlags(i32),
+
+    /// The operation is handled remotely only by an experimental feature (named here) that the
+    /// experimental config disables, see
+    /// [`ExperimentalFeature`](mirrord_config::experimental::ExperimentalFeature).
+    Experimental(&'static str),
+
     /// A conversion from [`SockAddr`](socket2::sockaddr::SockAddr) to
     /// [`SocketAddr`](std::net::SocketAddr) failed.
     AddressConversion,
@@ -166,6 +209,10 @@ pub(crate) enum Bypass {
     /// through the agent).
     ReadOnly(PathBuf),
 
+    /// Opening the [`PathBuf`] remotely failed with `ENOENT` or `EACCES`, and it matches
+    /// `feature.fs.fallback_local`, so it's opened locally instead.
+    LocalFallback(PathBuf),
+
     /// Called [`write`](crate::file::ops::write) with `write_bytes` set to [`None`].
     EmptyBuffer,
 
@@ -191,6 +238,11 @@ pub(crate) enum Bypass {
     /// Socket is connecting to localhots and we're asked to ignore it.
     IgnoreLocalhost(u16),
 
+    /// Application is binding a TCP port on a loopback address, which is not explicitly listed in
+    /// the incoming config, see
+    /// [`IncomingConfig::intercept_loopback`](mirrord_config::feature::network::incoming::IncomingConfig::intercept_loopback).
+    LoopbackPort(u16),
+
     /// Application is binding a port, while mirrord is running targetless. A targetless agent does
     /// is not exposed by a service, so bind locally.
     BindWhenTargetless,
@@ -207,6 +259,17 @@ pub(crate) enum Bypass {
     /// Hostname should be resolved locally.
     /// Currently this is the case only when the layer operates in the `trace only` mode.
     LocalHostname,
+
+    /// `feature.network.dns` is disabled, and only cluster-internal names are resolved remotely,
+    /// see [`is_cluster_name`](crate::socket::is_cluster_name).
+    LocalDns,
+
+    /// The agent was lost, and the layer does everything locally, see
+    /// [`agent_loss`](crate::agent_loss).
+    AgentLost,
+
+    /// The layer failed internally and disabled itself, see [`layer_error`].
+    LayerError,
 }
 
 /// [`ControlFlow`](std::ops::ControlFlow)-like enum to be used by hooks.
@@ -349,11 +412,29 @@ where
     ///
     /// - `Success` -> Return the contained value.
     /// - `Bypass` -> Call the bypass and return its value.
-    /// - `Error` -> Convert to libc value and return it.
+    /// - `Error` -> Convert to libc value and return it, or call the bypass if the agent was lost
+    ///   or the layer disabled itself. Hooks on remote handles (see [`mark_remote_handle`]) fail
+    ///   with [`HookError::RemoteHandleLost`] instead.
     pub(crate) fn unwrap_or_bypass_with<F: FnOnce(Bypass) -> S>(self, op: F) -> S {
+        let remote_handle = take_remote_handle();
+
         match self {
             Detour::Success(s) => s,
             Detour::Bypass(b) => op(b),
+            Detour::Error(e) if agent_loss::bypasses(&e) => {
+                if remote_handle {
+                    HookError::RemoteHandleLost.into()
+                } else {
+                    op(Bypass::AgentLost)
+                }
+            }
+            Detour::Error(e) if layer_error::bypasses(&e) => {
+                if remote_handle {
+                    HookError::RemoteHandleLost.into()
+                } else {
+                    op(Bypass::LayerError)
+                }
+            }
             Detour::Error(e) => e.into(),
         }
     }
@@ -362,11 +443,29 @@ where
     ///
     /// `Success` -> Return the contained value.
     /// `Bypass` -> Return provided value.
-    /// `Error` -> Convert to libc value and return it.
+    /// `Error` -> Convert to libc value and return it, or return provided value if the agent was
+    /// lost or the layer disabled itself. Hooks on remote handles (see [`mark_remote_handle`]) fail
+    /// with [`HookError::RemoteHandleLost`] instead.
     pub(crate) fn unwrap_or_bypass(self, value: S) -> S {
+        let remote_handle = take_remote_handle();
+
         match self {
             Detour::Success(s) => s,
             Detour::Bypass(_) => value,
+            Detour::Error(e) if agent_loss::bypasses(&e) => {
+                if remote_handle {
+                    HookError::RemoteHandleLost.into()
+                } else {
+                    value
+                }
+            }
+            Detour::Error(e) if layer_error::bypasses(&e) => {
+                if remote_handle {
+                    HookError::RemoteHandleLost.into()
+                } else {
+                    value
+                }
+            }
             Detour::Error(e) => e.into(),
         }
     }
@@ -448,3 +547,36 @@ impl<T> OnceLockExt<T> for OnceLock<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// When the agent is lost, or the layer disabled itself, new operations go local, and
+    /// operations on remote handles fail.
+    #[rstest]
+    #[case::agent_lost_new_operation(HookError::AgentLost, false, 7, None)]
+    #[case::agent_lost_remote_handle(HookError::AgentLost, true, -1, Some(libc::EIO))]
+    #[case::layer_disabled_new_operation(HookError::LayerDisabled, false, 7, None)]
+    #[case::layer_disabled_remote_handle(HookError::LayerDisabled, true, -1, Some(libc::EIO))]
+    fn goes_local_or_fails(
+        #[case] error: HookError,
+        #[case] remote_handle: bool,
+        #[case] expected: i32,
+        #[case] expected_errno: Option<i32>,
+    ) {
+        if remote_handle {
+            mark_remote_handle();
+        }
+        errno::set_errno(errno::Errno(0));
+
+        let result = Detour::<i32>::Error(error).unwrap_or_bypass(7);
+
+        assert_eq!(result, expected);
+        assert_eq!(errno::errno().0, expected_errno.unwrap_or(0));
+        // The mark doesn't outlive the hook.
+        assert!(!take_remote_handle());
+    }
+}
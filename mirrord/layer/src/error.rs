@@ -2,15 +2,17 @@ use std::{env::VarError, net::SocketAddr, ptr, str::ParseBoolError};
 
 use errno::set_errno;
 use ignore_codes::*;
-use libc::{c_char, hostent, DIR, FILE};
+use libc::{c_char, c_void, hostent, DIR, FILE};
 use mirrord_config::config::ConfigError;
 use mirrord_protocol::{ResponseError, SerializationError};
 #[cfg(target_os = "macos")]
 use mirrord_sip::SipError;
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{graceful_exit, proxy_connection::ProxyError};
+#[cfg(target_os = "linux")]
+use crate::file::fts::FtsEnt;
+use crate::{agent_loss, graceful_exit, layer_error, proxy_connection::ProxyError};
 
 /// Private module for preventing access to the [`IGNORE_ERROR_CODES`] constant.
 mod ignore_codes {
@@ -95,6 +97,22 @@ pub(crate) enum HookError {
     #[error("mirrord-layer: Proxy connection failed: `{0}`")]
     ProxyError(#[from] ProxyError),
 
+    /// The agent was lost and the layer does everything locally, see
+    /// [`agent_loss`](crate::agent_loss).
+    #[error("mirrord-layer: The agent was lost, not sending a hook message!")]
+    AgentLost,
+
+    /// The layer failed internally and doesn't talk to the internal proxy anymore, see
+    /// [`layer_error`](crate::layer_error).
+    #[error("mirrord-layer: mirrord is disabled, not sending a hook message!")]
+    LayerDisabled,
+
+    /// The agent was lost or the layer disabled itself, and the hook operates on a file,
+    /// directory or socket that was handled remotely, see
+    /// [`mark_remote_handle`](crate::detour::mark_remote_handle).
+    #[error("mirrord-layer: The remote file, directory or socket is no longer available!")]
+    RemoteHandleLost,
+
     #[cfg(target_os = "linux")]
     #[error("mirrord-layer: Invalid descriptor argument")]
     BadDescriptor,
@@ -195,6 +213,14 @@ pub(crate) type HookResult<T, E = HookError> = std::result::Result<T, E>;
 impl From<HookError> for i64 {
     fn from(fail: HookError) -> Self {
         match fail {
+            HookError::ResponseError(ref error)
+                if mirrord_intproxy_protocol::is_connection_lost(error) =>
+            {
+                warn!(
+                    "mirrord-layer: the agent connection was lost before the remote operation \
+                    completed, it may or may not have been applied"
+                )
+            }
             HookError::AddressAlreadyBound(_)
             | HookError::ResponseError(
                 ResponseError::NotFound(_)
@@ -215,6 +241,20 @@ impl From<HookError> for i64 {
             HookError::SocketUnsuportedIpv6 => {
                 info!("{fail}")
             }
+            HookError::ProxyError(ProxyError::Interrupted | ProxyError::Signaled)
+            | HookError::AgentLost
+            | HookError::LayerDisabled => {
+                info!("{fail}")
+            }
+            HookError::RemoteHandleLost => {
+                warn!("{fail}")
+            }
+            HookError::ProxyError(ref err) if agent_loss::degrade(err) => {
+                info!("{fail}")
+            }
+            HookError::ProxyError(ref err) if layer_error::disable(err) => {
+                info!("{fail}")
+            }
             HookError::ProxyError(ref err) => {
                 graceful_exit!(
                     "Proxy error, connectivity issue or a bug. \n\
@@ -228,7 +268,14 @@ impl From<HookError> for i64 {
             HookError::Null(_) => libc::EINVAL,
             HookError::TryFromInt(_) => libc::EINVAL,
             HookError::CannotGetProxyConnection => libc::EINVAL,
+            // Lets the application retry, the proxy is already reconnecting to the agent.
+            HookError::ProxyError(ProxyError::Interrupted) => libc::EINTR,
+            // Like the syscall would, when a signal interrupts it.
+            HookError::ProxyError(ProxyError::Signaled) => libc::EINTR,
             HookError::ProxyError(_) => libc::EINVAL,
+            HookError::AgentLost => libc::EIO,
+            HookError::LayerDisabled => libc::EIO,
+            HookError::RemoteHandleLost => libc::EIO,
             HookError::IO(io_fail) => io_fail.raw_os_error().unwrap_or(libc::EIO),
             HookError::LockError => libc::EINVAL,
             HookError::ResponseError(response_fail) => match response_fail {
@@ -337,6 +384,23 @@ impl From<HookError> for *mut c_char {
     }
 }
 
+impl From<HookError> for *mut c_void {
+    fn from(fail: HookError) -> Self {
+        let _ = i64::from(fail);
+
+        ptr::null_mut()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<HookError> for *mut FtsEnt {
+    fn from(fail: HookError) -> Self {
+        let _ = i64::from(fail);
+
+        ptr::null_mut()
+    }
+}
+
 impl From<frida_gum::Error> for LayerError {
     fn from(err: frida_gum::Error) -> Self {
         LayerError::Frida(err)
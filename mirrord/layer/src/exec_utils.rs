@@ -18,6 +18,7 @@ use null_terminated::Nul;
 use tracing::{trace, warn};
 
 use crate::{
+    child_env,
     common::{strip_mirrord_path, CheckedInto},
     detour::{
         Bypass::{
@@ -53,6 +54,13 @@ pub(crate) unsafe fn enable_execve_hook(
         FnPosix_spawn,
         FN_POSIX_SPAWN
     );
+    replace!(
+        hook_manager,
+        "posix_spawnp",
+        posix_spawnp_detour,
+        FnPosix_spawnp,
+        FN_POSIX_SPAWNP
+    );
     replace!(
         hook_manager,
         "_NSGetExecutablePath",
@@ -214,12 +222,18 @@ unsafe fn patch_sip_for_new_process(
 ///
 /// If there is an error in the detour, we don't exit or anything, we just call the original libc
 /// function with the original passed arguments.
+///
+/// We also add the missing layer variables to `envp`, see [`child_env`].
 #[hook_guard_fn]
 pub(crate) unsafe extern "C" fn execve_detour(
     path: *const c_char,
     argv: *const *const c_char,
     envp: *const *const c_char,
 ) -> c_int {
+    let layer_env = child_env::with_layer_env(envp);
+    let layer_envp = layer_env.as_deref().map(child_env::env_pointers);
+    let envp = layer_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+
     match patch_sip_for_new_process(path, argv) {
         Success((new_path, new_argv)) => {
             let new_argv = new_argv.null_vec();
@@ -235,7 +249,6 @@ pub(crate) unsafe extern "C" fn execve_detour(
 
 /// Hook for `libc::posix_spawn`.
 /// Same as [`execve_detour`], with all the extra arguments present here being passed untouched.
-// TODO: do we also need to hook posix_spawnp?
 #[hook_guard_fn]
 pub(crate) unsafe extern "C" fn posix_spawn_detour(
     pid: *const pid_t,
@@ -245,6 +258,10 @@ pub(crate) unsafe extern "C" fn posix_spawn_detour(
     argv: *const *const c_char,
     envp: *const *const c_char,
 ) -> c_int {
+    let layer_env = child_env::with_layer_env(envp);
+    let layer_envp = layer_env.as_deref().map(child_env::env_pointers);
+    let envp = layer_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+
     match patch_sip_for_new_process(path, argv) {
         Success((new_path, new_argv)) => {
             let new_argv = new_argv.null_vec();
@@ -261,6 +278,38 @@ pub(crate) unsafe extern "C" fn posix_spawn_detour(
     }
 }
 
+/// Hook for `libc::posix_spawnp`, used to spawn programs by name (e.g. by `xcrun`).
+/// Same as [`posix_spawn_detour`], the `file` is looked up in `PATH` when checking it for SIP, and
+/// the patched version is passed as a full path.
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn posix_spawnp_detour(
+    pid: *const pid_t,
+    file: *const c_char,
+    file_actions: *const c_void,
+    attrp: *const c_void,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    let layer_env = child_env::with_layer_env(envp);
+    let layer_envp = layer_env.as_deref().map(child_env::env_pointers);
+    let envp = layer_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+
+    match patch_sip_for_new_process(file, argv) {
+        Success((new_path, new_argv)) => {
+            let new_argv = new_argv.null_vec();
+            FN_POSIX_SPAWNP(
+                pid,
+                new_path.as_ptr(),
+                file_actions,
+                attrp,
+                new_argv.as_ptr() as *const *const c_char,
+                envp,
+            )
+        }
+        _ => FN_POSIX_SPAWNP(pid, file, file_actions, attrp, argv, envp),
+    }
+}
+
 #[hook_guard_fn]
 pub(crate) unsafe extern "C" fn _nsget_executable_path_detour(
     path: *mut c_char,
@@ -22,10 +22,20 @@ use mirrord_protocol::file::{
 #[cfg(target_os = "linux")]
 use mirrord_protocol::file::{GetDEnts64Request, GetDEnts64Response};
 
+pub(crate) mod batch;
+pub(crate) mod cluster_files;
+pub(crate) mod explain;
 pub(crate) mod filter;
+#[cfg(target_os = "linux")]
+pub(crate) mod fts;
 pub(crate) mod hooks;
 pub(crate) mod open_dirs;
 pub(crate) mod ops;
+pub(crate) mod readahead;
+#[cfg(target_os = "linux")]
+pub(crate) mod readiness;
+pub(crate) mod scratch;
+pub(crate) mod traversal;
 
 type RemoteFd = u64;
 type LocalFd = RawFd;
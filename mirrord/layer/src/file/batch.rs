@@ -0,0 +1,399 @@
+//! Batching of the small file reads and writes that threads make at the same time.
+//!
+//! Each file operation costs a round trip to the agent, so programs that read or write a few bytes
+//! at a time from many threads spend most of their time waiting. While a small operation is on
+//! its way to the agent, the ones made by other threads are collected, and sent in a single
+//! [`BatchFileRequest`] when it's done. Once operations are being batched, each batch also waits
+//! [`BATCH_WINDOW`] for more of them before it's sent.
+//!
+//! A thread that reads a few bytes at a time can't be batched with itself, so its small `read`s
+//! are served from [`SMALL_OPERATION_SIZE`] reads instead, see [`read`].
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, SeekFrom},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, LazyLock, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use mirrord_intproxy_protocol::{
+    IsLayerRequestWithResponse, LayerToProxyMessage, ProxyToLayerMessage,
+};
+use mirrord_protocol::{
+    file::{
+        BatchFileRequest, BatchFileResponse, ReadFileRequest, ReadFileResponse, SeekFileRequest,
+    },
+    FileRequest, FileResponse, RemoteResult, ResponseError,
+};
+use tracing::debug;
+
+use crate::{common, detour::Detour, error::HookResult, proxy_connection::ProxyError};
+
+/// Reads and writes of up to this many bytes are batched.
+pub(crate) const SMALL_OPERATION_SIZE: u64 = 4096;
+
+/// How long a batch waits for more operations, when the previous one had more than one.
+const BATCH_WINDOW: Duration = Duration::from_millis(2);
+
+/// Most operations sent in a single batch.
+const MAX_BATCH_LEN: usize = 64;
+
+/// Set when the agent can't handle [`BatchFileRequest`]s, then every operation is sent on its own.
+static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// The [`Batcher`], and the [`Condvar`] notified when a batch is done.
+static BATCHER: LazyLock<(Mutex<Batcher>, Condvar)> = LazyLock::new(Default::default);
+
+/// What the application didn't read yet of the data we [`read`] for it, by the fd of the file in
+/// the agent.
+static UNREAD: LazyLock<DashMap<u64, Arc<Mutex<Vec<u8>>>>> = LazyLock::new(DashMap::new);
+
+/// What became of an operation that was sent in a batch.
+#[derive(Debug)]
+enum Outcome {
+    /// The response of the agent to the operation.
+    Response(FileResponse),
+    /// The batch wasn't handled, or the operation can be done again, so it's sent on its own.
+    SendAlone,
+    /// The batch may have been handled, but we didn't get the response to the operation, which
+    /// fails instead of being done twice.
+    Lost,
+}
+
+/// Operations waiting to be batched, and the responses to the ones that were sent.
+///
+/// The thread that finds no batch being sent becomes the one that sends the next one, the other
+/// threads wait for their responses.
+struct Batcher {
+    /// Process that owns this state, a forked child starts over.
+    pid: u32,
+    next_ticket: u64,
+    /// Operations waiting for the next batch, with their tickets.
+    waiting: Vec<(u64, FileRequest)>,
+    /// Whether a thread is collecting or sending a batch.
+    sending: bool,
+    /// How many operations were in the last batch.
+    last_len: usize,
+    /// What became of the operations that were sent.
+    responses: HashMap<u64, Outcome>,
+}
+
+impl Default for Batcher {
+    fn default() -> Self {
+        Self {
+            pid: process::id(),
+            next_ticket: 0,
+            waiting: Default::default(),
+            sending: false,
+            last_len: 0,
+            responses: Default::default(),
+        }
+    }
+}
+
+impl Batcher {
+    fn lock() -> MutexGuard<'static, Self> {
+        let mut batcher = BATCHER.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // The threads that were sending or waiting didn't make it through `fork`.
+        if batcher.pid != process::id() {
+            *batcher = Default::default();
+        }
+
+        batcher
+    }
+
+    /// Takes the operations for the next batch.
+    fn take_batch(&mut self) -> Vec<(u64, FileRequest)> {
+        let len = self.waiting.len().min(MAX_BATCH_LEN);
+        self.last_len = len;
+        self.waiting.drain(..len).collect()
+    }
+
+    /// Stores the `responses` to a batch, and lets the next one be sent.
+    fn finish(&mut self, responses: Vec<(u64, Outcome)>) {
+        self.responses.extend(responses);
+        self.sending = false;
+        BATCHER.1.notify_all();
+    }
+}
+
+/// Sends the small file operation `request`, in a batch with the ones that other threads make
+/// meanwhile.
+///
+/// Falls back to [`common::make_proxy_request_with_response`] when the agent doesn't support
+/// batches. When the batch failed otherwise, only operations that can be done again are sent on
+/// their own, the others fail with `EIO`, as the agent may have done them already.
+pub(crate) fn make_proxy_request_with_response<T>(request: T) -> HookResult<T::Response>
+where
+    T: IsLayerRequestWithResponse + Clone + Debug,
+    T::Response: Debug,
+{
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return common::make_proxy_request_with_response(request);
+    }
+
+    let LayerToProxyMessage::File(file_request) = request.clone().wrap() else {
+        return common::make_proxy_request_with_response(request);
+    };
+
+    let mut batcher = Batcher::lock();
+    let ticket = batcher.next_ticket;
+    batcher.next_ticket += 1;
+    batcher.waiting.push((ticket, file_request));
+
+    loop {
+        if let Some(response) = batcher.responses.remove(&ticket) {
+            drop(batcher);
+
+            return match response {
+                Outcome::Response(response) => {
+                    T::try_unwrap_response(ProxyToLayerMessage::File(response))
+                        .map_err(|response| ProxyError::UnexpectedResponse(response).into())
+                }
+                Outcome::SendAlone => common::make_proxy_request_with_response(request),
+                Outcome::Lost => Err(io::Error::from_raw_os_error(libc::EIO).into()),
+            };
+        }
+
+        if batcher.sending {
+            batcher = BATCHER
+                .1
+                .wait(batcher)
+                .unwrap_or_else(PoisonError::into_inner);
+            continue;
+        }
+
+        batcher.sending = true;
+        if batcher.last_len > 1 {
+            drop(batcher);
+            thread::sleep(BATCH_WINDOW);
+            batcher = Batcher::lock();
+        }
+
+        let batch = batcher.take_batch();
+        drop(batcher);
+
+        // Alone, our operation goes as it is, while the others wait for it.
+        if matches!(batch.as_slice(), [(only, _)] if *only == ticket) {
+            let response = common::make_proxy_request_with_response(request);
+            Batcher::lock().finish(Default::default());
+            return response;
+        }
+
+        let responses = send_batch(batch);
+        Batcher::lock().finish(responses);
+        batcher = Batcher::lock();
+    }
+}
+
+/// Sends the operations in a [`BatchFileRequest`], and returns what became of each of them.
+fn send_batch(batch: Vec<(u64, FileRequest)>) -> Vec<(u64, Outcome)> {
+    let (tickets, requests): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+    let repeatable = requests.iter().map(is_repeatable).collect::<Vec<_>>();
+
+    let result = common::make_proxy_request_with_response(BatchFileRequest { requests });
+    if matches!(result, Ok(Err(ResponseError::NotImplemented))) {
+        UNSUPPORTED.store(true, Ordering::Relaxed);
+    }
+
+    tickets
+        .into_iter()
+        .zip(outcomes(&repeatable, result))
+        .collect()
+}
+
+/// Whether the operation can be sent again when we don't know if the agent did it, it doesn't
+/// move the position of the file nor change it.
+fn is_repeatable(request: &FileRequest) -> bool {
+    matches!(request, FileRequest::ReadLimited(..))
+}
+
+/// What became of each operation of a batch, given whether they are [`is_repeatable`], and the
+/// `result` of the batch.
+fn outcomes(
+    repeatable: &[bool],
+    result: HookResult<RemoteResult<BatchFileResponse>>,
+) -> Vec<Outcome> {
+    match result {
+        Ok(Ok(BatchFileResponse { responses })) if responses.len() == repeatable.len() => {
+            return responses.into_iter().map(Outcome::Response).collect();
+        }
+        // The agent checks the whole batch before handling any of it.
+        Ok(Err(ResponseError::NotImplemented)) => {
+            return repeatable.iter().map(|_| Outcome::SendAlone).collect();
+        }
+        result => debug!(?result, "batch of file operations failed"),
+    }
+
+    repeatable
+        .iter()
+        .map(|repeatable| {
+            if *repeatable {
+                Outcome::SendAlone
+            } else {
+                Outcome::Lost
+            }
+        })
+        .collect()
+}
+
+/// Reads up to `amount` bytes (at most [`SMALL_OPERATION_SIZE`]) at the position of the file with
+/// `remote_fd`, for a small `read` of the application.
+///
+/// Like stdio does, we read [`SMALL_OPERATION_SIZE`] bytes, and serve the next `read`s from what's
+/// left of them, so a thread that reads a few bytes at a time (e.g. a CSV parser) doesn't wait for
+/// the agent on each one. The position of the file in the agent is past what the application read
+/// then, anything else that depends on it first has to [`give_back`] the unread data, and other
+/// reads have to [`take_unread`] it.
+pub(crate) fn read(remote_fd: u64, amount: u64) -> HookResult<RemoteResult<ReadFileResponse>> {
+    let unread = UNREAD.entry(remote_fd).or_default().clone();
+    let mut unread = unread.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if unread.is_empty() {
+        match make_proxy_request_with_response(ReadFileRequest {
+            remote_fd,
+            buffer_size: SMALL_OPERATION_SIZE,
+        })? {
+            Ok(ReadFileResponse { bytes, .. }) => *unread = bytes,
+            Err(fail) => return Ok(Err(fail)),
+        }
+    }
+
+    Ok(Ok(drain(&mut unread, amount)))
+}
+
+/// Takes up to `amount` bytes of the data the application didn't [`read`] yet from the file with
+/// `remote_fd`, [`None`] when there's none.
+///
+/// Reads that don't go through [`read`] start with this, the file in the agent is past it.
+pub(crate) fn take_unread(remote_fd: u64, amount: u64) -> Option<ReadFileResponse> {
+    let unread = UNREAD.get(&remote_fd)?.clone();
+    let mut unread = unread.lock().unwrap_or_else(PoisonError::into_inner);
+
+    (!unread.is_empty()).then(|| drain(&mut unread, amount))
+}
+
+/// Takes up to `amount` bytes from the front of `unread`.
+fn drain(unread: &mut Vec<u8>, amount: u64) -> ReadFileResponse {
+    let read_amount = amount.min(unread.len() as u64);
+    let bytes = unread.drain(..read_amount as usize).collect();
+
+    ReadFileResponse { bytes, read_amount }
+}
+
+/// Seeks the file with `remote_fd` in the agent back to where the application is, before the data
+/// it didn't [`read`] yet.
+pub(crate) fn give_back(remote_fd: u64) -> Detour<()> {
+    let Some((_, unread)) = UNREAD.remove(&remote_fd) else {
+        return Detour::Success(());
+    };
+
+    let unread = unread.lock().unwrap_or_else(PoisonError::into_inner).len();
+    if unread > 0 {
+        common::make_proxy_request_with_response(SeekFileRequest {
+            fd: remote_fd,
+            seek_from: SeekFrom::Current(-(unread as i64)).into(),
+        })??;
+    }
+
+    Detour::Success(())
+}
+
+/// Forgets the unread data of the file with `remote_fd`, which was closed.
+pub(crate) fn forget(remote_fd: u64) {
+    UNREAD.remove(&remote_fd);
+}
+
+#[cfg(test)]
+mod test {
+    use mirrord_protocol::file::WriteFileResponse;
+
+    use super::*;
+    use crate::error::HookError;
+
+    fn written(written_amount: u64) -> FileResponse {
+        FileResponse::Write(Ok(WriteFileResponse { written_amount }))
+    }
+
+    #[test]
+    fn outcomes_of_handled_batch() {
+        let outcomes = outcomes(
+            &[false, true],
+            Ok(Ok(BatchFileResponse {
+                responses: vec![written(1), written(2)],
+            })),
+        );
+
+        assert!(
+            matches!(
+                outcomes.as_slice(),
+                [Outcome::Response(first), Outcome::Response(second)]
+                    if *first == written(1) && *second == written(2)
+            ),
+            "{outcomes:?}"
+        );
+    }
+
+    /// Nothing was done when the agent can't handle the batch, so it's all sent again.
+    #[test]
+    fn outcomes_of_unsupported_batch() {
+        let outcomes = outcomes(&[false, true], Ok(Err(ResponseError::NotImplemented)));
+
+        assert!(
+            matches!(
+                outcomes.as_slice(),
+                [Outcome::SendAlone, Outcome::SendAlone]
+            ),
+            "{outcomes:?}"
+        );
+    }
+
+    #[test]
+    fn takes_unread() {
+        // Not a real remote fd, so no other test uses it.
+        let remote_fd = u64::MAX;
+        assert!(take_unread(remote_fd, 4).is_none());
+
+        UNREAD.insert(remote_fd, Arc::new(Mutex::new(b"abcdef".to_vec())));
+        let taken = take_unread(remote_fd, 4).unwrap();
+        assert_eq!(
+            (taken.bytes.as_slice(), taken.read_amount),
+            (&b"abcd"[..], 4)
+        );
+        let taken = take_unread(remote_fd, 4).unwrap();
+        assert_eq!((taken.bytes.as_slice(), taken.read_amount), (&b"ef"[..], 2));
+        assert!(take_unread(remote_fd, 4).is_none());
+
+        forget(remote_fd);
+    }
+
+    /// Writes are not done twice when we don't know whether the batch was handled.
+    #[test]
+    fn outcomes_of_failed_batch() {
+        let failed = outcomes(
+            &[false, true],
+            Err(HookError::IO(io::Error::from(io::ErrorKind::BrokenPipe))),
+        );
+        let mismatched = outcomes(
+            &[false, true],
+            Ok(Ok(BatchFileResponse {
+                responses: vec![written(1)],
+            })),
+        );
+
+        for outcomes in [failed, mismatched] {
+            assert!(
+                matches!(outcomes.as_slice(), [Outcome::Lost, Outcome::SendAlone]),
+                "{outcomes:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! `ConfigMap`s and `Secret`s of the target's namespace, readable under
+//! `/mirrord/configmaps/<name>/<key>` and `/mirrord/secrets/<name>/<key>`, see
+//! `feature.fs.config_maps` and `feature.fs.secrets`.
+//!
+//! The CLI fetches them into a local directory when the session starts ([`CLUSTER_FILES_DIR_ENV`]),
+//! and we handle their paths in that directory instead. They can be opened (for reading only) and
+//! `stat`ed, but not listed.
+
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+};
+
+use libc::c_int;
+use mirrord_config::feature::fs::CLUSTER_FILES_DIR_ENV;
+use mirrord_protocol::file::{OpenOptionsInternal, XstatResponse};
+
+use super::{
+    hooks::{FN_ACCESS, FN_OPEN},
+    OpenOptionsInternalExt,
+};
+use crate::detour::Detour;
+
+/// Where the `ConfigMap`s and `Secret`s are, for the application.
+const VIRTUAL_ROOT: &str = "/mirrord";
+
+/// Where the `ConfigMap`s and `Secret`s are, locally.
+static CLUSTER_FILES_DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| env::var_os(CLUSTER_FILES_DIR_ENV).map(PathBuf::from));
+
+/// Where the file at `path` under [`VIRTUAL_ROOT`] is locally, [`None`] when `path` is not one of
+/// the `ConfigMap`s and `Secret`s.
+pub(crate) fn local_path(path: &Path) -> Option<PathBuf> {
+    resolve(CLUSTER_FILES_DIR.as_ref()?, path)
+}
+
+/// Where the file at `path` under [`VIRTUAL_ROOT`] is in `dir`, [`None`] when `path` is not under
+/// [`VIRTUAL_ROOT`], or could leave `dir`.
+fn resolve(dir: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(VIRTUAL_ROOT).ok()?;
+
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(..)))
+        .then(|| dir.join(relative))
+}
+
+/// Opens the file at `local_path` with the caller's `open_flags` (e.g. `O_CLOEXEC`), fails with
+/// `EROFS` when opened for writing.
+pub(crate) fn open(local_path: &Path, open_flags: c_int) -> Detour<RawFd> {
+    if OpenOptionsInternal::from_flags(open_flags).is_write() {
+        return Detour::Error(std::io::Error::from_raw_os_error(libc::EROFS).into());
+    }
+
+    let local_path = CString::new(local_path.as_os_str().as_bytes())?;
+
+    // `errno` is set when it fails.
+    Detour::Success(unsafe { FN_OPEN(local_path.as_ptr(), open_flags) })
+}
+
+/// Checks whether the file at `local_path` can be accessed with `mode`, like `access`.
+pub(crate) fn access(local_path: &Path, mode: u8) -> Detour<c_int> {
+    let local_path = CString::new(local_path.as_os_str().as_bytes())?;
+
+    // `errno` is set when it fails.
+    Detour::Success(unsafe { FN_ACCESS(local_path.as_ptr(), mode.into()) })
+}
+
+/// The metadata of the file at `local_path`.
+pub(crate) fn xstat(local_path: &Path, follow_symlink: bool) -> Detour<XstatResponse> {
+    let metadata = if follow_symlink {
+        fs::metadata(local_path)
+    } else {
+        fs::symlink_metadata(local_path)
+    }?;
+
+    Detour::Success(XstatResponse {
+        metadata: metadata.into(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("/mirrord/configmaps/app/key", Some("/tmp/files/configmaps/app/key"))]
+    #[case("/mirrord/secrets/db/password", Some("/tmp/files/secrets/db/password"))]
+    #[case("/mirrord", Some("/tmp/files"))]
+    #[case("/mirrord/../etc/passwd", None)]
+    #[case("/mirrord/secrets/db/../../../etc/passwd", None)]
+    #[case("/mirrordx/secrets/db/password", None)]
+    #[case("/etc/passwd", None)]
+    #[case("mirrord/secrets/db/password", None)]
+    fn resolves_under_dir(#[case] path: &str, #[case] expected: Option<&str>) {
+        assert_eq!(
+            resolve(Path::new("/tmp/files"), Path::new(path)),
+            expected.map(PathBuf::from)
+        );
+    }
+}
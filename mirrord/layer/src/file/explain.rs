@@ -0,0 +1,190 @@
+//! "Explain this path": logs every decision the layer makes about the paths that match the globs
+//! set by the user, to answer "why is this file local/remote?" without full trace logs.
+//!
+//! The globs come from [`FS_EXPLAIN_ENV`] (separated with `,`) and from the file at
+//! [`FS_EXPLAIN_FILE_ENV`] (one per line). The file is reloaded when it changes, so the
+//! explanations can be toggled while the process runs.
+//!
+//! Explanations are logged with the [`EXPLAIN_TARGET`] target, which is enabled at `info` on top of
+//! `RUST_LOG` (see `init_tracing`).
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use mirrord_intproxy_protocol::IsLayerRequestWithResponse;
+
+use crate::{common, error::HookResult, file::traversal::wildcard_match};
+
+/// Globs of the explained paths, separated with `,`.
+pub(crate) const FS_EXPLAIN_ENV: &str = "MIRRORD_FS_EXPLAIN";
+
+/// Path to a file with globs of the explained paths, one per line.
+pub(crate) const FS_EXPLAIN_FILE_ENV: &str = "MIRRORD_FS_EXPLAIN_FILE";
+
+/// [`tracing`] target of the explanations.
+pub(crate) const EXPLAIN_TARGET: &str = "mirrord_fs_explain";
+
+/// How often we check whether the file at [`FS_EXPLAIN_FILE_ENV`] changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+static EXPLAIN_GLOBS: LazyLock<Option<ExplainGlobs>> = LazyLock::new(ExplainGlobs::from_env);
+
+/// Globs from the file at [`FS_EXPLAIN_FILE_ENV`].
+#[derive(Default)]
+struct FileGlobs {
+    checked_at: Option<Instant>,
+    modified: Option<SystemTime>,
+    globs: Vec<String>,
+}
+
+struct ExplainGlobs {
+    /// From [`FS_EXPLAIN_ENV`].
+    fixed: Vec<String>,
+    /// Path to the file from [`FS_EXPLAIN_FILE_ENV`], with its last loaded globs.
+    file: Option<(PathBuf, Mutex<FileGlobs>)>,
+}
+
+impl ExplainGlobs {
+    fn from_env() -> Option<Self> {
+        let fixed = env::var(FS_EXPLAIN_ENV)
+            .map(|globs| parse_globs(&globs, ','))
+            .unwrap_or_default();
+        let file = env::var_os(FS_EXPLAIN_FILE_ENV)
+            .map(|path| (PathBuf::from(path), Mutex::new(FileGlobs::default())));
+
+        (!fixed.is_empty() || file.is_some()).then_some(Self { fixed, file })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let matches = |glob: &String| wildcard_match(glob.as_bytes(), path.as_bytes(), true);
+
+        if self.fixed.iter().any(matches) {
+            return true;
+        }
+
+        let Some((file, globs)) = &self.file else {
+            return false;
+        };
+        let Ok(mut globs) = globs.lock() else {
+            return false;
+        };
+        globs.reload(file);
+
+        globs.globs.iter().any(matches)
+    }
+}
+
+impl FileGlobs {
+    /// Reloads the globs from `file` if it changed, at most once per [`RELOAD_INTERVAL`].
+    /// A missing file means no globs.
+    fn reload(&mut self, file: &Path) {
+        let now = Instant::now();
+        if self
+            .checked_at
+            .is_some_and(|checked_at| now.duration_since(checked_at) < RELOAD_INTERVAL)
+        {
+            return;
+        }
+        self.checked_at = Some(now);
+
+        let modified = fs::metadata(file)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+
+        self.globs = fs::read_to_string(file)
+            .map(|globs| parse_globs(&globs, '\n'))
+            .unwrap_or_default();
+        tracing::info!(target: EXPLAIN_TARGET, globs = ?self.globs, "explained paths reloaded");
+    }
+}
+
+fn parse_globs(globs: &str, separator: char) -> Vec<String> {
+    globs
+        .split(separator)
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Whether the user asked for any explanations, see [`FS_EXPLAIN_ENV`] and
+/// [`FS_EXPLAIN_FILE_ENV`].
+pub(crate) fn enabled() -> bool {
+    EXPLAIN_GLOBS.is_some()
+}
+
+/// Whether decisions about `path` should be explained.
+pub(crate) fn explained(path: &str) -> bool {
+    EXPLAIN_GLOBS
+        .as_ref()
+        .is_some_and(|globs| globs.matches(path))
+}
+
+/// Makes the request with [`common::make_proxy_request_with_response`], and explains it (with
+/// its timing) when `path` is [`explained`].
+pub(crate) fn make_proxy_request_with_response<T>(
+    path: Option<&Path>,
+    request: T,
+) -> HookResult<T::Response>
+where
+    T: IsLayerRequestWithResponse + fmt::Debug,
+    T::Response: fmt::Debug,
+{
+    let Some(path) = path.and_then(Path::to_str).filter(|path| explained(path)) else {
+        return common::make_proxy_request_with_response(request);
+    };
+
+    let request_debug = format!("{request:?}");
+    let start = Instant::now();
+    let response = common::make_proxy_request_with_response(request);
+
+    tracing::info!(
+        target: EXPLAIN_TARGET,
+        path,
+        request = request_debug,
+        ?response,
+        elapsed = ?start.elapsed(),
+        "sent to the agent",
+    );
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_globs_reload() {
+        let dir = std::env::temp_dir().join(format!("mirrord-explain-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("globs");
+
+        let mut globs = FileGlobs::default();
+        globs.reload(&file);
+        assert!(globs.globs.is_empty());
+
+        fs::write(&file, "/etc/*\n\n  /app/config.json  \n").unwrap();
+        globs.checked_at = None;
+        globs.reload(&file);
+        assert_eq!(globs.globs, ["/etc/*", "/app/config.json"]);
+
+        // Not checked again so soon.
+        fs::remove_file(&file).unwrap();
+        globs.reload(&file);
+        assert_eq!(globs.globs, ["/etc/*", "/app/config.json"]);
+
+        globs.checked_at = None;
+        globs.reload(&file);
+        assert!(globs.globs.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
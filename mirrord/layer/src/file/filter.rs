@@ -6,7 +6,7 @@
 /// match [`generate_local_set`];
 ///
 /// 2. Using the overrides for `read_only`, `read_write` and `local`.
-use std::env;
+use std::{env, time::Instant};
 
 use mirrord_config::{
     feature::fs::{FsConfig, FsModeConfig},
@@ -17,6 +17,7 @@ use regex::{RegexSet, RegexSetBuilder};
 use crate::{
     detour::{Bypass, Detour},
     error::HookError,
+    file::explain,
 };
 
 mod not_found_by_default;
@@ -38,9 +39,15 @@ fn generate_local_set() -> RegexSet {
         .expect("Building local path regex set failed")
 }
 
-/// List of files that mirrord should use remotely read only
-fn generate_remote_ro_set() -> RegexSet {
-    let patterns = read_remote_by_default::PATHS;
+/// List of files that mirrord should use remotely read only, with the timezone files of the target
+/// when `target_locale` is set (see `feature.env.target_locale`).
+fn generate_remote_ro_set(target_locale: bool) -> RegexSet {
+    let timezone: &[&str] = if target_locale {
+        &read_remote_by_default::TIMEZONE_PATHS
+    } else {
+        &[]
+    };
+    let patterns = read_remote_by_default::PATHS.iter().chain(timezone);
     RegexSetBuilder::new(patterns)
         .case_insensitive(true)
         .build()
@@ -71,6 +78,8 @@ pub struct FileFilter {
     read_write: RegexSet,
     local: RegexSet,
     not_found: RegexSet,
+    fallback_local: RegexSet,
+    remote_temp: RegexSet,
     default_local: RegexSet,
     default_remote_ro: RegexSet,
     default_not_found: RegexSet,
@@ -90,14 +99,20 @@ impl FileFilter {
     /// removed) If path matches include, it continues to check if the path has specific
     /// behavior, if not, it checks if the path matches the default exclude list.
     /// If not, it does the default behavior set by user (default is read only remote).
+    ///
+    /// With `target_locale` (`feature.env.target_locale`), the timezone files are read remotely
+    /// by default too.
     #[mirrord_layer_macro::instrument(level = "trace")]
-    pub fn new(fs_config: FsConfig) -> Self {
+    pub fn new(fs_config: FsConfig, target_locale: bool) -> Self {
         let FsConfig {
             read_write,
             read_only,
             local,
             mode,
             not_found,
+            fallback_local,
+            remote_temp,
+            ..
         } = fs_config;
 
         let read_write =
@@ -107,9 +122,13 @@ impl FileFilter {
         let local = Self::make_regex_set(local).expect("building local path regex set failed");
         let not_found =
             Self::make_regex_set(not_found).expect("building not-found regex set failed");
+        let fallback_local =
+            Self::make_regex_set(fallback_local).expect("building fallback-local regex set failed");
+        let remote_temp =
+            Self::make_regex_set(remote_temp).expect("building remote-temp regex set failed");
 
         let default_local = generate_local_set();
-        let default_remote_ro = generate_remote_ro_set();
+        let default_remote_ro = generate_remote_ro_set(target_locale);
         let default_not_found = generate_not_found_set();
 
         Self {
@@ -117,6 +136,8 @@ impl FileFilter {
             read_write,
             local,
             not_found,
+            fallback_local,
+            remote_temp,
             default_local,
             default_remote_ro,
             default_not_found,
@@ -124,33 +145,84 @@ impl FileFilter {
         }
     }
 
+    /// Whether `text` should be opened locally when opening it remotely fails, see
+    /// `feature.fs.fallback_local`.
+    pub fn falls_back_locally(&self, text: &str) -> bool {
+        self.fallback_local.is_match(text)
+    }
+
+    /// Whether `text` is a temporary file kept in the remote scratch directory, see
+    /// `feature.fs.remote_temp`.
+    pub fn in_remote_temp(&self, text: &str) -> bool {
+        self.remote_temp.is_match(text)
+    }
+
     /// Checks if `text` matches the regex held by the initialized variant of `FileFilter`,
     /// and the whether the path is queried for write converting the result a `Detour`.
     ///
     /// `op` is used to lazily initialize a `Bypass` case.
     pub fn continue_or_bypass_with<F>(&self, text: &str, write: bool, op: F) -> Detour<()>
+    where
+        F: FnOnce() -> Bypass,
+    {
+        let explained = explain::explained(text);
+        let start = explained.then(Instant::now);
+
+        let (rule, detour) = self.check(text, write, op);
+
+        if let Some(start) = start {
+            tracing::info!(
+                target: explain::EXPLAIN_TARGET,
+                path = text,
+                write,
+                rule,
+                result = ?detour,
+                elapsed = ?start.elapsed(),
+                "file filter",
+            );
+        }
+
+        detour
+    }
+
+    /// Does the work of [`FileFilter::continue_or_bypass_with`], also returns the name of the
+    /// rule that decided.
+    fn check<F>(&self, text: &str, write: bool, op: F) -> (&'static str, Detour<()>)
     where
         F: FnOnce() -> Bypass,
     {
         match self.mode {
-            FsModeConfig::Local => Detour::Bypass(op()),
-            _ if self.not_found.is_match(text) => Detour::Error(HookError::FileNotFound),
-            _ if self.read_write.is_match(text) => Detour::Success(()),
+            FsModeConfig::Local => ("feature.fs.mode local", Detour::Bypass(op())),
+            _ if self.not_found.is_match(text) => (
+                "feature.fs.not_found",
+                Detour::Error(HookError::FileNotFound),
+            ),
+            _ if self.read_write.is_match(text) => ("feature.fs.read_write", Detour::Success(())),
             _ if self.read_only.is_match(text) => {
                 if write {
-                    Detour::Bypass(op())
+                    ("feature.fs.read_only", Detour::Bypass(op()))
                 } else {
-                    Detour::Success(())
+                    ("feature.fs.read_only", Detour::Success(()))
                 }
             }
-            _ if self.local.is_match(text) => Detour::Bypass(op()),
-            _ if self.default_not_found.is_match(text) => Detour::Error(HookError::FileNotFound),
-            _ if self.default_remote_ro.is_match(text) && !write => Detour::Success(()),
-            _ if self.default_local.is_match(text) => Detour::Bypass(op()),
-            FsModeConfig::LocalWithOverrides => Detour::Bypass(op()),
-            FsModeConfig::Write => Detour::Success(()),
-            FsModeConfig::Read if write => Detour::Bypass(Bypass::ReadOnly(text.into())),
-            FsModeConfig::Read => Detour::Success(()),
+            _ if self.local.is_match(text) => ("feature.fs.local", Detour::Bypass(op())),
+            _ if self.default_not_found.is_match(text) => (
+                "default not found paths",
+                Detour::Error(HookError::FileNotFound),
+            ),
+            _ if self.default_remote_ro.is_match(text) && !write => {
+                ("default remote read only paths", Detour::Success(()))
+            }
+            _ if self.default_local.is_match(text) => ("default local paths", Detour::Bypass(op())),
+            FsModeConfig::LocalWithOverrides => {
+                ("feature.fs.mode localwithoverrides", Detour::Bypass(op()))
+            }
+            FsModeConfig::Write => ("feature.fs.mode write", Detour::Success(())),
+            FsModeConfig::Read if write => (
+                "feature.fs.mode read",
+                Detour::Bypass(Bypass::ReadOnly(text.into())),
+            ),
+            FsModeConfig::Read => ("feature.fs.mode read", Detour::Success(())),
         }
     }
 }
@@ -398,10 +470,12 @@ mod tests {
             read_only,
             local,
             not_found,
+            fallback_local: None,
             mode,
+            ..Default::default()
         };
 
-        let file_filter = FileFilter::new(fs_config);
+        let file_filter = FileFilter::new(fs_config, false);
 
         let res =
             file_filter.continue_or_bypass_with(path, write, || Bypass::IgnoredFile("".into()));
@@ -437,7 +511,7 @@ mod tests {
             ..Default::default()
         };
 
-        let file_filter = FileFilter::new(fs_config);
+        let file_filter = FileFilter::new(fs_config, false);
 
         let res =
             file_filter.continue_or_bypass_with(path, write, || Bypass::IgnoredFile("".into()));
@@ -446,6 +520,28 @@ mod tests {
         assert_eq!(res.kind(), expected);
     }
 
+    #[rstest]
+    #[case(true, "/etc/localtime", DetourKind::Success)]
+    #[case(true, "/usr/share/zoneinfo/Europe/Berlin", DetourKind::Success)]
+    #[case(false, "/etc/localtime", DetourKind::Bypass)]
+    #[case(false, "/usr/share/zoneinfo/Europe/Berlin", DetourKind::Bypass)]
+    fn remote_timezone(
+        #[case] target_locale: bool,
+        #[case] path: &str,
+        #[case] expected: DetourKind,
+    ) {
+        let fs_config = FsConfig {
+            mode: FsModeConfig::LocalWithOverrides,
+            ..Default::default()
+        };
+
+        let file_filter = FileFilter::new(fs_config, target_locale);
+
+        let res =
+            file_filter.continue_or_bypass_with(path, false, || Bypass::IgnoredFile("".into()));
+        assert_eq!(res.kind(), expected);
+    }
+
     /// Sanity test for empty [`RegexSet`] behaviour.
     #[test]
     fn empty_regex_set() {
@@ -463,10 +559,38 @@ mod tests {
     #[case("/root/.config/gcloud/some_file", DetourKind::Success)]
     #[case("/root/.nuget/packages/microsoft.azure.amqp", DetourKind::Success)]
     fn not_found_set(#[case] path: &str, #[case] expected: DetourKind) {
-        let filter = FileFilter::new(Default::default());
+        let filter = FileFilter::new(Default::default(), false);
         let res = filter.continue_or_bypass_with(path, false, || Bypass::IgnoredFile("".into()));
         println!("filter result: {res:?}");
 
         assert_eq!(res.kind(), expected);
     }
+
+    #[rstest]
+    #[case("/app/local-only.conf", true)]
+    #[case("/app/remote.conf", false)]
+    fn fallback_local_set(#[case] path: &str, #[case] expected: bool) {
+        let fs_config = FsConfig {
+            fallback_local: Some(VecOrSingle::Single(r"local-only".to_string())),
+            ..Default::default()
+        };
+
+        let file_filter = FileFilter::new(fs_config, false);
+
+        assert_eq!(file_filter.falls_back_locally(path), expected);
+    }
+
+    #[rstest]
+    #[case("/tmp/build-1/out.txt", true)]
+    #[case("/tmp/cache/out.txt", false)]
+    fn remote_temp_set(#[case] path: &str, #[case] expected: bool) {
+        let fs_config = FsConfig {
+            remote_temp: Some(VecOrSingle::Single(r"^/tmp/build-".to_string())),
+            ..Default::default()
+        };
+
+        let file_filter = FileFilter::new(fs_config, false);
+
+        assert_eq!(file_filter.in_remote_temp(path), expected);
+    }
 }
@@ -1,8 +1,15 @@
-/// These paths will be read remotely by default when `fs.feature.mode` is set to
-/// `localwithoverrides`.
+/// These paths will be read remotely by default in every `feature.fs.mode` but `local`, unless the
+/// `feature.fs` overrides match them.
 pub const PATHS: [&str; 3] = [
     // for dns resolving
     r"^/etc/resolv.conf$",
     r"^/etc/hosts$",
     r"^/etc/hostname$",
 ];
+
+/// The timezone of the target, also read remotely by default with `feature.env.target_locale`.
+pub const TIMEZONE_PATHS: [&str; 3] = [
+    r"^/etc/localtime$",
+    r"^/etc/timezone$",
+    r"^/usr/share/zoneinfo/",
+];
@@ -0,0 +1,490 @@
+//! Implementation of remote `fts_*` streams, on top of the [`RemoteWalker`]. Used in the
+//! `fts_open`, `fts_read`, `fts_children`, `fts_set` and `fts_close` hooks.
+//!
+//! The [`FtsEnt`]s we return are allocated with `libc::calloc`, and live until the stream is
+//! closed.
+
+use std::{
+    mem,
+    path::Path,
+    ptr,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use dashmap::DashMap;
+use errno::{set_errno, Errno};
+use libc::{
+    c_char, c_int, c_long, c_short, c_ushort, c_void, dev_t, ino64_t, nlink_t, stat64, EINVAL,
+    ENOMEM,
+};
+
+use super::{
+    hooks::fill_stat,
+    traversal::{ensure_remote_root, NodeKind, RemoteWalker, WalkNode, WalkOrder},
+};
+use crate::{
+    common::CheckedInto,
+    detour::{Bypass, Detour},
+};
+
+/// `fts_open` options, from `fts.h`.
+pub(crate) const FTS_COMFOLLOW: c_int = 0x0001;
+pub(crate) const FTS_LOGICAL: c_int = 0x0002;
+pub(crate) const FTS_NOCHDIR: c_int = 0x0004;
+pub(crate) const FTS_NOSTAT: c_int = 0x0008;
+pub(crate) const FTS_PHYSICAL: c_int = 0x0010;
+pub(crate) const FTS_XDEV: c_int = 0x0040;
+
+/// Options we can handle remotely. We never change the working directory, so `FTS_NOCHDIR` is
+/// implied, and we always stat the files, which is allowed with `FTS_NOSTAT`.
+///
+/// `FTS_SEEDOT` is not supported, since the agent doesn't list `.` and `..`.
+const SUPPORTED_OPTIONS: c_int =
+    FTS_COMFOLLOW | FTS_LOGICAL | FTS_NOCHDIR | FTS_NOSTAT | FTS_PHYSICAL | FTS_XDEV;
+
+/// [`FtsEnt::fts_info`] values.
+const FTS_D: c_ushort = 1;
+const FTS_DC: c_ushort = 2;
+const FTS_DNR: c_ushort = 4;
+const FTS_DP: c_ushort = 6;
+const FTS_F: c_ushort = 8;
+const FTS_NS: c_ushort = 10;
+const FTS_SL: c_ushort = 12;
+const FTS_SLNONE: c_ushort = 13;
+
+/// `fts_set` instructions.
+const FTS_AGAIN: c_int = 1;
+const FTS_FOLLOW: c_int = 2;
+const FTS_NOINSTR: c_int = 3;
+const FTS_SKIP: c_int = 4;
+
+/// Level of the parent of the roots.
+const FTS_ROOTPARENTLEVEL: c_short = -1;
+
+/// glibc's `FTSENT` (the `fts64_*` functions use the same layout on 64-bit).
+#[repr(C)]
+pub(crate) struct FtsEnt {
+    pub(crate) fts_cycle: *mut FtsEnt,
+    pub(crate) fts_parent: *mut FtsEnt,
+    pub(crate) fts_link: *mut FtsEnt,
+    pub(crate) fts_number: c_long,
+    pub(crate) fts_pointer: *mut c_void,
+    pub(crate) fts_accpath: *mut c_char,
+    pub(crate) fts_path: *mut c_char,
+    pub(crate) fts_errno: c_int,
+    pub(crate) fts_symfd: c_int,
+    pub(crate) fts_pathlen: c_ushort,
+    pub(crate) fts_namelen: c_ushort,
+    pub(crate) fts_ino: ino64_t,
+    pub(crate) fts_dev: dev_t,
+    pub(crate) fts_nlink: nlink_t,
+    pub(crate) fts_level: c_short,
+    pub(crate) fts_info: c_ushort,
+    pub(crate) fts_flags: c_ushort,
+    pub(crate) fts_instr: c_ushort,
+    pub(crate) fts_statp: *mut stat64,
+    /// Allocated with the length of the name (plus the null terminator).
+    pub(crate) fts_name: [c_char; 1],
+}
+
+/// `compar` argument of `fts_open`.
+pub(crate) type FtsCompar =
+    Option<unsafe extern "C" fn(*const *const FtsEnt, *const *const FtsEnt) -> c_int>;
+
+fn info_of(kind: NodeKind) -> c_ushort {
+    match kind {
+        NodeKind::File => FTS_F,
+        NodeKind::Dir => FTS_D,
+        NodeKind::DirUnreadable => FTS_DNR,
+        NodeKind::DirCycle => FTS_DC,
+        NodeKind::DirPost => FTS_DP,
+        NodeKind::Symlink => FTS_SL,
+        NodeKind::DanglingSymlink => FTS_SLNONE,
+        NodeKind::StatFailed => FTS_NS,
+    }
+}
+
+/// Allocates the [`FtsEnt`] of `node`, returns null if we're out of memory.
+///
+/// Roots are named after the path they were opened with, like in `fts`.
+unsafe fn alloc_entry(node: &WalkNode, parent: *mut FtsEnt) -> *mut FtsEnt {
+    let name = if node.level == 0 {
+        node.path.as_str()
+    } else {
+        node.name()
+    };
+
+    let entry = libc::calloc(1, mem::size_of::<FtsEnt>() + name.len()).cast::<FtsEnt>();
+    let path = libc::calloc(1, node.path.len() + 1).cast::<c_char>();
+    let statp = libc::calloc(1, mem::size_of::<stat64>()).cast::<stat64>();
+
+    if entry.is_null() || path.is_null() || statp.is_null() {
+        libc::free(entry.cast());
+        libc::free(path.cast());
+        libc::free(statp.cast());
+
+        return ptr::null_mut();
+    }
+
+    ptr::copy_nonoverlapping(node.path.as_ptr(), path.cast(), node.path.len());
+    ptr::copy_nonoverlapping(
+        name.as_ptr(),
+        ptr::addr_of_mut!((*entry).fts_name).cast(),
+        name.len(),
+    );
+
+    let out = &mut *entry;
+    if let Some(metadata) = node.metadata.as_ref() {
+        fill_stat(statp, metadata);
+        out.fts_ino = metadata.inode;
+        out.fts_dev = metadata.device_id;
+        out.fts_nlink = metadata.hard_links as _;
+    }
+
+    out.fts_parent = parent;
+    out.fts_accpath = path;
+    out.fts_path = path;
+    out.fts_errno = node.errno;
+    out.fts_pathlen = node.path.len().try_into().unwrap_or(c_ushort::MAX);
+    out.fts_namelen = name.len().try_into().unwrap_or(c_ushort::MAX);
+    out.fts_level = node.level.try_into().unwrap_or(c_short::MAX);
+    out.fts_info = info_of(node.kind);
+    out.fts_instr = FTS_NOINSTR as c_ushort;
+    out.fts_statp = statp;
+
+    entry
+}
+
+unsafe fn free_entry(entry: *mut FtsEnt) {
+    if let Some(entry) = entry.as_mut() {
+        libc::free(entry.fts_path.cast());
+        libc::free(entry.fts_statp.cast());
+    }
+
+    libc::free(entry.cast());
+}
+
+/// Sorts the [`WalkNode`]s with the `compar` function the user gave to `fts_open`.
+fn compare_with(
+    compar: unsafe extern "C" fn(*const *const FtsEnt, *const *const FtsEnt) -> c_int,
+    a: &WalkNode,
+    b: &WalkNode,
+) -> std::cmp::Ordering {
+    unsafe {
+        let a = alloc_entry(a, ptr::null_mut());
+        let b = alloc_entry(b, ptr::null_mut());
+
+        let ordering = if a.is_null() || b.is_null() {
+            std::cmp::Ordering::Equal
+        } else {
+            compar(&a.cast_const(), &b.cast_const()).cmp(&0)
+        };
+
+        free_entry(a);
+        free_entry(b);
+
+        ordering
+    }
+}
+
+/// A remote `fts` stream.
+struct FtsStream {
+    walker: RemoteWalker,
+    /// Parent of the root entries.
+    root_parent: *mut FtsEnt,
+    /// Entries of the directories we're in, from the root down, so that we can report
+    /// [`NodeKind::DirPost`] with the same entry as [`NodeKind::Dir`] (programs keep state in
+    /// `fts_number` and `fts_pointer`).
+    dirs: Vec<*mut FtsEnt>,
+    /// Last entry returned by `fts_read`.
+    current: Option<(*mut FtsEnt, WalkNode)>,
+    /// Entries to free when the stream is closed.
+    allocated: Vec<*mut FtsEnt>,
+}
+
+// SAFETY: the entries are only accessed by the user (who owns the stream), and by us while the
+// stream is locked.
+unsafe impl Send for FtsStream {}
+
+impl FtsStream {
+    fn parent_of(&self, node: &WalkNode) -> *mut FtsEnt {
+        node.level
+            .checked_sub(1)
+            .and_then(|level| self.dirs.get(level))
+            .copied()
+            .unwrap_or(self.root_parent)
+    }
+
+    unsafe fn alloc(&mut self, node: &WalkNode, parent: *mut FtsEnt) -> *mut FtsEnt {
+        let entry = alloc_entry(node, parent);
+
+        if entry.is_null() {
+            set_errno(Errno(ENOMEM));
+        } else {
+            self.allocated.push(entry);
+        }
+
+        entry
+    }
+
+    unsafe fn read(&mut self) -> *mut FtsEnt {
+        let Some(node) = self.walker.next_node() else {
+            self.current = None;
+            set_errno(Errno(0));
+            return ptr::null_mut();
+        };
+
+        let entry = if node.kind == NodeKind::DirPost {
+            let entry = self
+                .dirs
+                .get(node.level)
+                .copied()
+                .unwrap_or(ptr::null_mut());
+            self.dirs.truncate(node.level);
+
+            if let Some(entry) = entry.as_mut() {
+                entry.fts_info = FTS_DP;
+                entry.fts_instr = FTS_NOINSTR as c_ushort;
+            }
+
+            entry
+        } else {
+            let entry = self.alloc(&node, self.parent_of(&node));
+            if entry.is_null() {
+                return entry;
+            }
+
+            match node.kind {
+                NodeKind::Dir => {
+                    self.dirs.truncate(node.level);
+                    self.dirs.push(entry);
+                }
+                NodeKind::DirCycle => {
+                    (*entry).fts_cycle = self
+                        .dirs
+                        .iter()
+                        .copied()
+                        .find(|dir| {
+                            (**dir).fts_dev == (*entry).fts_dev
+                                && (**dir).fts_ino == (*entry).fts_ino
+                        })
+                        .unwrap_or(ptr::null_mut());
+                }
+                _ => {}
+            }
+
+            entry
+        };
+
+        self.current = Some((entry, node));
+
+        entry
+    }
+
+    /// Returns the linked list of the children of the entry last returned by `fts_read` (or of the
+    /// roots, if `fts_read` was not called yet).
+    unsafe fn children(&mut self) -> *mut FtsEnt {
+        set_errno(Errno(0));
+
+        let (parent, nodes): (_, Vec<_>) = match &self.current {
+            None => (
+                self.root_parent,
+                self.walker.pending_roots().cloned().collect(),
+            ),
+            Some((entry, node)) if node.kind == NodeKind::Dir => {
+                match self.walker.pending_children(node) {
+                    Some(children) => (*entry, children.cloned().collect()),
+                    None => return ptr::null_mut(),
+                }
+            }
+            Some(_) => return ptr::null_mut(),
+        };
+
+        let mut head = ptr::null_mut();
+        for node in nodes.iter().rev() {
+            let entry = self.alloc(node, parent);
+            if entry.is_null() {
+                return entry;
+            }
+
+            (*entry).fts_link = head;
+            head = entry;
+        }
+
+        head
+    }
+
+    unsafe fn set(&mut self, entry: *mut FtsEnt, instr: c_int) -> c_int {
+        if !matches!(instr, FTS_AGAIN | FTS_FOLLOW | FTS_NOINSTR | FTS_SKIP) {
+            set_errno(Errno(EINVAL));
+            return -1;
+        }
+
+        if let Some(entry) = entry.as_mut() {
+            entry.fts_instr = instr as c_ushort;
+        }
+
+        // Instructions only apply to the entry we're at.
+        let Some((current, node)) = &self.current else {
+            return 0;
+        };
+        if *current != entry {
+            return 0;
+        }
+
+        match instr {
+            FTS_SKIP => self.walker.skip_children(node),
+            FTS_AGAIN => self.walker.revisit(node, false),
+            FTS_FOLLOW if matches!(node.kind, NodeKind::Symlink | NodeKind::DanglingSymlink) => {
+                self.walker.revisit(node, true)
+            }
+            _ => {}
+        }
+
+        0
+    }
+}
+
+impl Drop for FtsStream {
+    fn drop(&mut self) {
+        unsafe {
+            self.allocated
+                .drain(..)
+                .chain([self.root_parent])
+                .for_each(|entry| free_entry(entry));
+        }
+    }
+}
+
+/// Global instance of [`FtsStreams`]. Used in hooks.
+pub(crate) static FTS_STREAMS: LazyLock<FtsStreams> = LazyLock::new(FtsStreams::new);
+
+/// Remote `fts` streams, by the address of the `FTS` handle we gave the user.
+pub(crate) struct FtsStreams {
+    inner: DashMap<usize, Arc<Mutex<FtsStream>>>,
+}
+
+impl FtsStreams {
+    fn new() -> Self {
+        Self {
+            inner: DashMap::with_capacity(4),
+        }
+    }
+
+    fn get(&self, handle: usize) -> Detour<Arc<Mutex<FtsStream>>> {
+        Detour::Success(
+            self.inner
+                .get(&handle)
+                .ok_or(Bypass::LocalFtsStreamNotFound(handle))?
+                .clone(),
+        )
+    }
+
+    /// Starts a remote traversal of the (null terminated) `path_argv`, if all of the paths are
+    /// handled remotely. Returns the `FTS` handle.
+    pub(crate) unsafe fn open(
+        &self,
+        path_argv: *const *mut c_char,
+        options: c_int,
+        compar: FtsCompar,
+    ) -> Detour<*mut c_void> {
+        if options & !SUPPORTED_OPTIONS != 0 || options & (FTS_LOGICAL | FTS_PHYSICAL) == 0 {
+            return Detour::Bypass(Bypass::UnsupportedFlags(options));
+        }
+
+        if path_argv.is_null() {
+            return Detour::Bypass(Bypass::EmptyOption);
+        }
+
+        let mut roots = Vec::new();
+        for index in 0.. {
+            let raw_path = *path_argv.add(index);
+            if raw_path.is_null() {
+                break;
+            }
+
+            let root: String = raw_path.cast_const().checked_into()?;
+            ensure_remote_root(Path::new(&root))?;
+            roots.push(root);
+        }
+
+        if roots.is_empty() {
+            return Detour::Bypass(Bypass::EmptyOption);
+        }
+
+        let root_parent = alloc_entry(
+            &WalkNode {
+                path: String::new(),
+                base: 0,
+                level: 0,
+                kind: NodeKind::Dir,
+                metadata: None,
+                errno: 0,
+            },
+            ptr::null_mut(),
+        );
+        if root_parent.is_null() {
+            set_errno(Errno(ENOMEM));
+            return Detour::Success(ptr::null_mut());
+        }
+        (*root_parent).fts_level = FTS_ROOTPARENTLEVEL;
+
+        let order = compar.map(|compar| -> WalkOrder {
+            Box::new(move |a: &WalkNode, b: &WalkNode| compare_with(compar, a, b))
+        });
+
+        let walker = RemoteWalker::new(
+            roots,
+            options & FTS_LOGICAL != 0,
+            options & FTS_COMFOLLOW != 0,
+            options & FTS_XDEV != 0,
+            order,
+        );
+
+        let stream = Arc::new(Mutex::new(FtsStream {
+            walker,
+            root_parent,
+            dirs: Vec::new(),
+            current: None,
+            allocated: Vec::new(),
+        }));
+
+        let handle = Arc::as_ptr(&stream) as usize;
+        self.inner.insert(handle, stream);
+
+        Detour::Success(handle as *mut c_void)
+    }
+
+    /// `fts_read` on the stream with the given `handle`.
+    pub(crate) fn read(&self, handle: usize) -> Detour<*mut FtsEnt> {
+        let stream = self.get(handle)?;
+        let mut stream = stream.lock()?;
+
+        Detour::Success(unsafe { stream.read() })
+    }
+
+    /// `fts_children` on the stream with the given `handle`.
+    pub(crate) fn children(&self, handle: usize) -> Detour<*mut FtsEnt> {
+        let stream = self.get(handle)?;
+        let mut stream = stream.lock()?;
+
+        Detour::Success(unsafe { stream.children() })
+    }
+
+    /// `fts_set` on the stream with the given `handle`.
+    pub(crate) fn set(&self, handle: usize, entry: *mut FtsEnt, instr: c_int) -> Detour<c_int> {
+        let stream = self.get(handle)?;
+        let mut stream = stream.lock()?;
+
+        Detour::Success(unsafe { stream.set(entry, instr) })
+    }
+
+    /// `fts_close` on the stream with the given `handle`, freeing all of its entries.
+    pub(crate) fn close(&self, handle: usize) -> Detour<c_int> {
+        self.inner
+            .remove(&handle)
+            .ok_or(Bypass::LocalFtsStreamNotFound(handle))?;
+
+        Detour::Success(0)
+    }
+}
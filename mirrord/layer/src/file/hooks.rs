@@ -5,15 +5,16 @@ use core::ffi::{c_size_t, c_ssize_t};
 ///
 /// NOTICE: If a file operation fails, it might be because it depends on some `libc` function
 /// that is not being hooked (`strace` the program to check).
-use std::{ffi::CString, os::unix::io::RawFd, ptr, slice, time::Duration};
+use std::{ffi::CString, mem, os::unix::io::RawFd, path::Path, ptr, slice, time::Duration};
 
 use errno::{set_errno, Errno};
 use libc::{
-    self, c_char, c_int, c_void, dirent, iovec, off_t, size_t, ssize_t, stat, statfs, AT_EACCESS,
-    AT_FDCWD, DIR, EINVAL, O_DIRECTORY, O_RDONLY,
+    self, c_char, c_int, c_void, dirent, glob_t, iovec, off_t, size_t, ssize_t, stat, statfs,
+    AT_EACCESS, AT_FDCWD, DIR, EINVAL, GLOB_ABORTED, GLOB_APPEND, GLOB_DOOFFS, GLOB_NOMATCH,
+    GLOB_NOSPACE, O_DIRECTORY, O_RDONLY,
 };
 #[cfg(target_os = "linux")]
-use libc::{dirent64, stat64, statx, EBADF, ENOENT, ENOTDIR};
+use libc::{dirent64, epoll_event, glob64_t, stat64, statx, EBADF, ENOENT, ENOTDIR};
 use mirrord_layer_macro::{hook_fn, hook_guard_fn};
 use mirrord_protocol::file::{
     FsMetadataInternal, MetadataInternal, ReadFileResponse, WriteFileResponse,
@@ -25,15 +26,27 @@ use tracing::trace;
 #[cfg(target_os = "linux")]
 use tracing::{error, info, warn};
 
-use super::{open_dirs, ops::*, OpenOptionsInternalExt};
-#[cfg(target_os = "macos")]
-use crate::detour::Bypass;
+#[cfg(target_os = "linux")]
+use super::fts::{FtsCompar, FtsEnt, FTS_STREAMS};
+#[cfg(target_os = "linux")]
+use super::readiness;
+#[cfg(target_os = "linux")]
+use super::traversal::glob_flags;
+use super::{
+    open_dirs,
+    ops::*,
+    traversal::{
+        self,
+        ftw::{self, Ftw},
+        GlobOptions, GlobOutcome, NodeKind, RemoteWalker,
+    },
+};
 #[cfg(target_os = "linux")]
 use crate::error::HookError::ResponseError;
 use crate::{
     close_layer_fd,
     common::CheckedInto,
-    detour::{Detour, DetourGuard},
+    detour::{Bypass, Detour, DetourGuard},
     error::HookError,
     file::{
         open_dirs::OPEN_DIRS,
@@ -67,11 +80,10 @@ fn update_ptr_from_bypass(ptr: *const c_char, bypass: Bypass) -> *const c_char {
 #[mirrord_layer_macro::instrument(level = "trace", ret)]
 unsafe fn open_logic(raw_path: *const c_char, open_flags: c_int, _mode: c_int) -> Detour<RawFd> {
     let path = raw_path.checked_into();
-    let open_options = OpenOptionsInternalExt::from_flags(open_flags);
 
-    trace!("path {:#?} | open_options {:#?}", path, open_options);
+    trace!("path {:#?} | open_flags {:#o}", path, open_flags);
 
-    open(path, open_options)
+    open(path, open_flags)
 }
 
 /// Hook for `libc::open`.
@@ -305,9 +317,7 @@ pub(crate) unsafe extern "C" fn openat_detour(
     raw_path: *const c_char,
     open_flags: c_int,
 ) -> RawFd {
-    let open_options = OpenOptionsInternalExt::from_flags(open_flags);
-
-    openat(fd, raw_path.checked_into(), open_options).unwrap_or_bypass_with(|_bypass| {
+    openat(fd, raw_path.checked_into(), open_flags).unwrap_or_bypass_with(|_bypass| {
         #[cfg(target_os = "macos")]
         let raw_path = update_ptr_from_bypass(raw_path, _bypass);
         FN_OPENAT(fd, raw_path, open_flags)
@@ -325,9 +335,7 @@ pub(crate) unsafe extern "C" fn openat64_detour(
     raw_path: *const c_char,
     open_flags: c_int,
 ) -> RawFd {
-    let open_options = OpenOptionsInternalExt::from_flags(open_flags);
-
-    openat(fd, raw_path.checked_into(), open_options).unwrap_or_bypass_with(|_bypass| {
+    openat(fd, raw_path.checked_into(), open_flags).unwrap_or_bypass_with(|_bypass| {
         #[cfg(target_os = "macos")]
         let raw_path = update_ptr_from_bypass(raw_path, _bypass);
         FN_OPENAT64(fd, raw_path, open_flags)
@@ -340,9 +348,7 @@ pub(crate) unsafe extern "C" fn _openat_nocancel_detour(
     raw_path: *const c_char,
     open_flags: c_int,
 ) -> RawFd {
-    let open_options = OpenOptionsInternalExt::from_flags(open_flags);
-
-    openat(fd, raw_path.checked_into(), open_options).unwrap_or_bypass_with(|_bypass| {
+    openat(fd, raw_path.checked_into(), open_flags).unwrap_or_bypass_with(|_bypass| {
         #[cfg(target_os = "macos")]
         let raw_path = update_ptr_from_bypass(raw_path, _bypass);
         FN__OPENAT_NOCANCEL(fd, raw_path, open_flags)
@@ -692,6 +698,19 @@ pub(crate) unsafe extern "C" fn fdatasync_detour(fd: RawFd) -> c_int {
     fsync(fd).unwrap_or_bypass_with(|_| FN_FDATASYNC(fd))
 }
 
+/// Hook for `libc::epoll_ctl`, see [`readiness`].
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn epoll_ctl_detour(
+    epfd: c_int,
+    op: c_int,
+    fd: RawFd,
+    event: *mut epoll_event,
+) -> c_int {
+    readiness::epoll_ctl(epfd, op, fd, event)
+        .unwrap_or_bypass_with(|_| FN_EPOLL_CTL(epfd, op, fd, event))
+}
+
 /// Tries to convert input to type O, if it fails it returns the max value of O.
 /// For example, if you put u32::MAX into a u8, it will return u8::MAX.
 fn best_effort_cast<I: Bounded, O: TryFrom<I> + Bounded>(input: I) -> O {
@@ -705,7 +724,7 @@ fn nano_to_secs(nano: i64) -> i64 {
 }
 
 /// Fills the `stat` struct with the metadata
-unsafe extern "C" fn fill_stat(out_stat: *mut stat64, metadata: &MetadataInternal) {
+pub(super) unsafe extern "C" fn fill_stat(out_stat: *mut stat64, metadata: &MetadataInternal) {
     out_stat.write_bytes(0, 1);
     let out = &mut *out_stat;
     // on macOS the types might be different, so we try to cast and do our best..
@@ -1031,6 +1050,390 @@ pub(crate) unsafe extern "C" fn preadv_detour(
         .unwrap_or_bypass_with(|_| FN_PREADV(fd, iovecs, iovec_count, offset))
 }
 
+/// `errfunc` argument of `glob`.
+type GlobErrFunc = Option<extern "C" fn(*const c_char, c_int) -> c_int>;
+
+/// Appends `paths` to the `gl_pathv` of `pglob` (initializing it first, unless `GLOB_APPEND` is
+/// set), allocating with `libc`, so that `globfree` can release it.
+unsafe fn fill_glob(pglob: &mut glob_t, paths: Vec<String>, flags: c_int) -> c_int {
+    if flags & GLOB_APPEND == 0 {
+        pglob.gl_pathc = 0;
+        pglob.gl_pathv = ptr::null_mut();
+        if flags & GLOB_DOOFFS == 0 {
+            pglob.gl_offs = 0;
+        }
+    }
+
+    let offs = pglob.gl_offs;
+    let Some(size) = offs
+        .checked_add(pglob.gl_pathc)
+        .and_then(|used| used.checked_add(paths.len() + 1))
+        .and_then(|len| len.checked_mul(mem::size_of::<*mut c_char>()))
+    else {
+        return GLOB_NOSPACE;
+    };
+
+    let pathv = libc::realloc(pglob.gl_pathv.cast(), size).cast::<*mut c_char>();
+    if pathv.is_null() {
+        return GLOB_NOSPACE;
+    }
+
+    if pglob.gl_pathv.is_null() {
+        (0..offs).for_each(|index| pathv.add(index).write(ptr::null_mut()));
+    }
+    pglob.gl_pathv = pathv;
+
+    let mut result = 0;
+    for path in paths.into_iter().filter_map(|path| CString::new(path).ok()) {
+        let path = libc::strdup(path.as_ptr());
+        if path.is_null() {
+            result = GLOB_NOSPACE;
+            break;
+        }
+
+        pathv.add(offs + pglob.gl_pathc).write(path);
+        pglob.gl_pathc += 1;
+    }
+    pathv.add(offs + pglob.gl_pathc).write(ptr::null_mut());
+
+    result
+}
+
+/// Expands `pattern` remotely when its leading directory is handled remotely, see
+/// [`traversal::glob`].
+unsafe fn glob_logic(
+    pattern: *const c_char,
+    flags: c_int,
+    errfunc: GlobErrFunc,
+    pglob: *mut glob_t,
+) -> Detour<c_int> {
+    let pglob = pglob.as_mut()?;
+    let options = GlobOptions::from_flags(flags).ok_or(Bypass::UnsupportedFlags(flags))?;
+    let pattern: String = pattern.checked_into()?;
+
+    let outcome = traversal::glob(&pattern, &options, |path, errno| {
+        let (Some(errfunc), Ok(path)) = (errfunc, CString::new(path)) else {
+            return false;
+        };
+
+        errfunc(path.as_ptr(), errno) != 0
+    })?;
+
+    let result = match outcome {
+        GlobOutcome::Matched(paths) => fill_glob(pglob, paths, flags),
+        GlobOutcome::NoMatch => match fill_glob(pglob, Vec::new(), flags) {
+            0 => GLOB_NOMATCH,
+            failed => failed,
+        },
+        GlobOutcome::Aborted => {
+            fill_glob(pglob, Vec::new(), flags);
+            GLOB_ABORTED
+        }
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        pglob.gl_flags = if traversal::has_magic(&pattern, options.escape) {
+            flags | glob_flags::GLOB_MAGCHAR
+        } else {
+            flags
+        };
+    }
+
+    Detour::Success(result)
+}
+
+/// Hook for `libc::glob`.
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn glob_detour(
+    pattern: *const c_char,
+    flags: c_int,
+    errfunc: GlobErrFunc,
+    pglob: *mut glob_t,
+) -> c_int {
+    match glob_logic(pattern, flags, errfunc, pglob) {
+        Detour::Success(result) => result,
+        Detour::Bypass(_) => FN_GLOB(pattern, flags, errfunc, pglob),
+        Detour::Error(fail) => {
+            let _ = i32::from(fail);
+            GLOB_ABORTED
+        }
+    }
+}
+
+/// Hook for `libc::glob64`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn glob64_detour(
+    pattern: *const c_char,
+    flags: c_int,
+    errfunc: GlobErrFunc,
+    pglob: *mut glob64_t,
+) -> c_int {
+    match glob_logic(pattern, flags, errfunc, pglob.cast()) {
+        Detour::Success(result) => result,
+        Detour::Bypass(_) => FN_GLOB64(pattern, flags, errfunc, pglob),
+        Detour::Error(fail) => {
+            let _ = i32::from(fail);
+            GLOB_ABORTED
+        }
+    }
+}
+
+/// `fn` argument of `nftw`.
+type NftwFn = unsafe extern "C" fn(*const c_char, *const stat, c_int, *mut Ftw) -> c_int;
+
+/// `fn` argument of `nftw64`.
+#[cfg(target_os = "linux")]
+type Nftw64Fn = unsafe extern "C" fn(*const c_char, *const stat64, c_int, *mut Ftw) -> c_int;
+
+/// Walks the tree at `dirpath` remotely, when it's handled remotely, calling `callback` for every
+/// file like `nftw` does.
+///
+/// Returns [`None`] when the call should be bypassed.
+///
+/// Unlike in other hooks, we hold the [`DetourGuard`] only while we talk to the agent, and not
+/// while `callback` runs, so that what it does with the paths it gets is handled remotely too.
+unsafe fn nftw_logic<F>(dirpath: *const c_char, flags: c_int, callback: F) -> Option<c_int>
+where
+    F: Fn(*const c_char, *const stat64, c_int, *mut Ftw) -> c_int,
+{
+    let mut walker = {
+        let _guard = DetourGuard::new()?;
+
+        if flags & !ftw::SUPPORTED_FLAGS != 0 {
+            return None;
+        }
+
+        let Detour::Success(root) = CheckedInto::<String>::checked_into(dirpath) else {
+            return None;
+        };
+
+        match traversal::ensure_remote_root(Path::new(&root)) {
+            Detour::Success(()) => {}
+            Detour::Bypass(_) => return None,
+            Detour::Error(fail) => return Some(fail.into()),
+        }
+
+        RemoteWalker::new(
+            vec![root],
+            flags & ftw::FTW_PHYS == 0,
+            false,
+            flags & ftw::FTW_MOUNT != 0,
+            None,
+        )
+    };
+
+    let depth_first = flags & ftw::FTW_DEPTH != 0;
+
+    loop {
+        let node = {
+            let _guard = DetourGuard::new();
+            walker.next_node()
+        };
+        let Some(node) = node else {
+            return Some(0);
+        };
+
+        let typeflag = match node.kind {
+            NodeKind::StatFailed if node.level == 0 => {
+                set_errno(Errno(node.errno));
+                return Some(-1);
+            }
+            NodeKind::File => ftw::FTW_F,
+            NodeKind::Dir if depth_first => continue,
+            NodeKind::Dir | NodeKind::DirCycle => ftw::FTW_D,
+            NodeKind::DirPost if depth_first => ftw::FTW_DP,
+            NodeKind::DirPost => continue,
+            NodeKind::DirUnreadable => ftw::FTW_DNR,
+            NodeKind::Symlink => ftw::FTW_SL,
+            NodeKind::DanglingSymlink => ftw::FTW_SLN,
+            NodeKind::StatFailed => ftw::FTW_NS,
+        };
+
+        let Ok(path) = CString::new(node.path.as_str()) else {
+            continue;
+        };
+
+        let mut stat_buf = mem::zeroed::<stat64>();
+        if let Some(metadata) = node.metadata.as_ref() {
+            fill_stat(&mut stat_buf, metadata);
+        }
+
+        let mut ftw = Ftw {
+            base: node.base as c_int,
+            level: node.level as c_int,
+        };
+
+        let result = callback(path.as_ptr(), &stat_buf, typeflag, &mut ftw);
+
+        #[cfg(target_os = "linux")]
+        if flags & ftw::FTW_ACTIONRETVAL != 0 {
+            match result {
+                ftw::FTW_CONTINUE => {}
+                ftw::FTW_SKIP_SUBTREE => walker.skip_children(&node),
+                ftw::FTW_SKIP_SIBLINGS => walker.skip_siblings(&node),
+                _ => return Some(result),
+            }
+
+            continue;
+        }
+
+        if result != 0 {
+            return Some(result);
+        }
+    }
+}
+
+/// Hook for `libc::nftw`.
+#[hook_fn]
+pub(crate) unsafe extern "C" fn nftw_detour(
+    dirpath: *const c_char,
+    callback: Option<NftwFn>,
+    nopenfd: c_int,
+    flags: c_int,
+) -> c_int {
+    callback
+        .and_then(|callback| {
+            nftw_logic(dirpath, flags, |path, stat_buf, typeflag, ftw| {
+                callback(path, stat_buf.cast(), typeflag, ftw)
+            })
+        })
+        .unwrap_or_else(|| FN_NFTW(dirpath, callback, nopenfd, flags))
+}
+
+/// Hook for `libc::nftw64`.
+#[cfg(target_os = "linux")]
+#[hook_fn]
+pub(crate) unsafe extern "C" fn nftw64_detour(
+    dirpath: *const c_char,
+    callback: Option<Nftw64Fn>,
+    nopenfd: c_int,
+    flags: c_int,
+) -> c_int {
+    callback
+        .and_then(|callback| {
+            nftw_logic(dirpath, flags, |path, stat_buf, typeflag, ftw| {
+                callback(path, stat_buf, typeflag, ftw)
+            })
+        })
+        .unwrap_or_else(|| FN_NFTW64(dirpath, callback, nopenfd, flags))
+}
+
+/// Hook for `libc::fts_open`.
+///
+/// Starts a remote traversal when all of the paths are handled remotely, see [`FTS_STREAMS`].
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts_open_detour(
+    path_argv: *const *mut c_char,
+    options: c_int,
+    compar: FtsCompar,
+) -> *mut c_void {
+    FTS_STREAMS
+        .open(path_argv, options, compar)
+        .unwrap_or_bypass_with(|_| FN_FTS_OPEN(path_argv, options, compar))
+}
+
+/// Hook for `libc::fts64_open`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts64_open_detour(
+    path_argv: *const *mut c_char,
+    options: c_int,
+    compar: FtsCompar,
+) -> *mut c_void {
+    FTS_STREAMS
+        .open(path_argv, options, compar)
+        .unwrap_or_bypass_with(|_| FN_FTS64_OPEN(path_argv, options, compar))
+}
+
+/// Hook for `libc::fts_read`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts_read_detour(ftsp: *mut c_void) -> *mut FtsEnt {
+    FTS_STREAMS
+        .read(ftsp as usize)
+        .unwrap_or_bypass_with(|_| FN_FTS_READ(ftsp))
+}
+
+/// Hook for `libc::fts64_read`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts64_read_detour(ftsp: *mut c_void) -> *mut FtsEnt {
+    FTS_STREAMS
+        .read(ftsp as usize)
+        .unwrap_or_bypass_with(|_| FN_FTS64_READ(ftsp))
+}
+
+/// Hook for `libc::fts_children`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts_children_detour(
+    ftsp: *mut c_void,
+    options: c_int,
+) -> *mut FtsEnt {
+    FTS_STREAMS
+        .children(ftsp as usize)
+        .unwrap_or_bypass_with(|_| FN_FTS_CHILDREN(ftsp, options))
+}
+
+/// Hook for `libc::fts64_children`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts64_children_detour(
+    ftsp: *mut c_void,
+    options: c_int,
+) -> *mut FtsEnt {
+    FTS_STREAMS
+        .children(ftsp as usize)
+        .unwrap_or_bypass_with(|_| FN_FTS64_CHILDREN(ftsp, options))
+}
+
+/// Hook for `libc::fts_set`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts_set_detour(
+    ftsp: *mut c_void,
+    entry: *mut FtsEnt,
+    instr: c_int,
+) -> c_int {
+    FTS_STREAMS
+        .set(ftsp as usize, entry, instr)
+        .unwrap_or_bypass_with(|_| FN_FTS_SET(ftsp, entry, instr))
+}
+
+/// Hook for `libc::fts64_set`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts64_set_detour(
+    ftsp: *mut c_void,
+    entry: *mut FtsEnt,
+    instr: c_int,
+) -> c_int {
+    FTS_STREAMS
+        .set(ftsp as usize, entry, instr)
+        .unwrap_or_bypass_with(|_| FN_FTS64_SET(ftsp, entry, instr))
+}
+
+/// Hook for `libc::fts_close`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts_close_detour(ftsp: *mut c_void) -> c_int {
+    FTS_STREAMS
+        .close(ftsp as usize)
+        .unwrap_or_bypass_with(|_| FN_FTS_CLOSE(ftsp))
+}
+
+/// Hook for `libc::fts64_close`.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(crate) unsafe extern "C" fn fts64_close_detour(ftsp: *mut c_void) -> c_int {
+    FTS_STREAMS
+        .close(ftsp as usize)
+        .unwrap_or_bypass_with(|_| FN_FTS64_CLOSE(ftsp))
+}
+
 /// Convenience function to setup file hooks (`x_detour`) with `frida_gum`.
 pub(crate) unsafe fn enable_file_hooks(hook_manager: &mut HookManager) {
     replace!(hook_manager, "open", open_detour, FnOpen, FN_OPEN);
@@ -1126,6 +1529,17 @@ pub(crate) unsafe fn enable_file_hooks(hook_manager: &mut HookManager) {
         FN_FACCESSAT
     );
 
+    #[cfg(target_os = "linux")]
+    {
+        replace!(
+            hook_manager,
+            "epoll_ctl",
+            epoll_ctl_detour,
+            FnEpoll_ctl,
+            FN_EPOLL_CTL
+        );
+    }
+
     replace!(hook_manager, "fsync", fsync_detour, FnFsync, FN_FSYNC);
     replace!(
         hook_manager,
@@ -1156,6 +1570,86 @@ pub(crate) unsafe fn enable_file_hooks(hook_manager: &mut HookManager) {
         replace!(hook_manager, "statx", statx_detour, FnStatx, FN_STATX);
     }
 
+    replace!(hook_manager, "glob", glob_detour, FnGlob, FN_GLOB);
+    replace!(hook_manager, "nftw", nftw_detour, FnNftw, FN_NFTW);
+
+    #[cfg(target_os = "linux")]
+    {
+        replace!(hook_manager, "glob64", glob64_detour, FnGlob64, FN_GLOB64);
+        replace!(hook_manager, "nftw64", nftw64_detour, FnNftw64, FN_NFTW64);
+
+        replace!(
+            hook_manager,
+            "fts_open",
+            fts_open_detour,
+            FnFts_open,
+            FN_FTS_OPEN
+        );
+        replace!(
+            hook_manager,
+            "fts_read",
+            fts_read_detour,
+            FnFts_read,
+            FN_FTS_READ
+        );
+        replace!(
+            hook_manager,
+            "fts_children",
+            fts_children_detour,
+            FnFts_children,
+            FN_FTS_CHILDREN
+        );
+        replace!(
+            hook_manager,
+            "fts_set",
+            fts_set_detour,
+            FnFts_set,
+            FN_FTS_SET
+        );
+        replace!(
+            hook_manager,
+            "fts_close",
+            fts_close_detour,
+            FnFts_close,
+            FN_FTS_CLOSE
+        );
+        replace!(
+            hook_manager,
+            "fts64_open",
+            fts64_open_detour,
+            FnFts64_open,
+            FN_FTS64_OPEN
+        );
+        replace!(
+            hook_manager,
+            "fts64_read",
+            fts64_read_detour,
+            FnFts64_read,
+            FN_FTS64_READ
+        );
+        replace!(
+            hook_manager,
+            "fts64_children",
+            fts64_children_detour,
+            FnFts64_children,
+            FN_FTS64_CHILDREN
+        );
+        replace!(
+            hook_manager,
+            "fts64_set",
+            fts64_set_detour,
+            FnFts64_set,
+            FN_FTS64_SET
+        );
+        replace!(
+            hook_manager,
+            "fts64_close",
+            fts64_close_detour,
+            FnFts64_close,
+            FN_FTS64_CLOSE
+        );
+    }
+
     #[cfg(not(all(target_os = "macos", target_arch = "x86_64")))]
     {
         replace!(
@@ -1321,5 +1815,7 @@ pub(crate) unsafe fn enable_file_hooks(hook_manager: &mut HookManager) {
             FnOpendir,
             FN_OPENDIR
         );
+        replace!(hook_manager, "glob$INODE64", glob_detour, FnGlob, FN_GLOB);
+        replace!(hook_manager, "nftw$INODE64", nftw_detour, FnNftw, FN_NFTW);
     }
 }
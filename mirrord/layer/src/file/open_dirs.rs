@@ -12,7 +12,7 @@ use mirrord_protocol::file::{CloseDirRequest, DirEntryInternal, ReadDirRequest,
 use super::{DirStreamFd, LocalFd, RemoteFd, OPEN_FILES};
 use crate::{
     common,
-    detour::{Bypass, Detour},
+    detour::{self, Bypass, Detour},
     error::HookError,
 };
 
@@ -52,6 +52,7 @@ impl OpenDirs {
             .get(&local_dir_fd)
             .ok_or(Bypass::LocalDirStreamNotFound(local_dir_fd))?
             .clone();
+        detour::mark_remote_handle();
 
         let guard = dir.lock().expect("lock poisoned");
 
@@ -65,6 +66,7 @@ impl OpenDirs {
             .get(&local_dir_fd)
             .ok_or(Bypass::LocalDirStreamNotFound(local_dir_fd))?
             .clone();
+        detour::mark_remote_handle();
 
         let guard = dir.lock().expect("lock poisoned");
 
@@ -89,6 +91,7 @@ impl OpenDirs {
             .get(&local_dir_fd)
             .ok_or(Bypass::LocalDirStreamNotFound(local_dir_fd))?
             .clone();
+        detour::mark_remote_handle();
 
         let mut guard = dir.lock().expect("lock poisoned");
 
@@ -120,6 +123,7 @@ impl OpenDirs {
             .get(&local_dir_fd)
             .ok_or(Bypass::LocalDirStreamNotFound(local_dir_fd))?
             .clone();
+        detour::mark_remote_handle();
 
         let mut guard = dir.lock().expect("lock poisoned");
 
@@ -142,6 +146,11 @@ impl OpenDirs {
         let mut guard = dir.lock().expect("lock poisoned");
         guard.closed = true;
         OPEN_FILES.remove(&guard.base_fd);
+
+        if common::is_proxy_gone() {
+            return Detour::Success(0);
+        }
+
         common::make_proxy_request_no_response(CloseDirRequest {
             remote_fd: guard.remote_fd,
         })?;
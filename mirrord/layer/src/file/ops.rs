@@ -1,28 +1,58 @@
 #[cfg(target_os = "linux")]
 use std::time::Duration;
-use std::{env, ffi::CString, io::SeekFrom, os::unix::io::RawFd, path::PathBuf};
+use std::{
+    env,
+    ffi::CString,
+    io::SeekFrom,
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 #[cfg(target_os = "linux")]
 use libc::{c_char, statx, statx_timestamp};
 use libc::{c_int, iovec, unlink, AT_FDCWD};
-use mirrord_protocol::file::{
-    OpenFileRequest, OpenFileResponse, OpenOptionsInternal, ReadFileResponse, SeekFileResponse,
-    WriteFileResponse, XstatFsResponse, XstatResponse,
+use mirrord_protocol::{
+    file::{
+        ChecksumFileRequest, ChecksumFileResponse, FileChecksum, OpenFileRequest, OpenFileResponse,
+        OpenOptionsInternal, ReadChecksummedFileRequest, ReadChecksummedFileResponse,
+        ReadFileResponse, SeekFileResponse, WriteFileResponse, XstatFsResponse, XstatResponse,
+    },
+    RemoteResult, ResponseError,
 };
 use rand::distributions::{Alphanumeric, DistString};
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
 
-use super::{hooks::FN_OPEN, open_dirs::OPEN_DIRS, *};
+use super::{batch, cluster_files, hooks::FN_OPEN, open_dirs::OPEN_DIRS, readahead, scratch, *};
 #[cfg(target_os = "linux")]
 use crate::common::CheckedInto;
 use crate::{
     common,
-    detour::{Bypass, Detour},
+    detour::{self, Bypass, Detour},
     error::{HookError, HookResult as Result},
+    proxy_connection::ProxyError,
 };
 
-/// 1 Megabyte. Large read requests can lead to timeouts.
-const MAX_READ_SIZE: u64 = 1024 * 1024;
+/// 16 Megabytes. The internal proxy streams large reads in chunks when the agent supports it,
+/// and limits them to 1 Megabyte otherwise, but the whole response is still held in memory.
+const MAX_READ_SIZE: u64 = 16 * 1024 * 1024;
+
+/// 1 Megabyte. Verified reads are not streamed, and large read requests can lead to timeouts.
+const MAX_VERIFIED_READ_SIZE: u64 = 1024 * 1024;
+
+/// 1 Megabyte. Larger `pwrite`s are sent in chunks of this size, see [`pwrite`].
+const WRITE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How many times we send a positional read whose data got corrupted on the way before giving
+/// up, see [`read_verified`].
+const READ_ATTEMPTS: usize = 3;
+
+/// Set once the agent turns out not to support [`ReadChecksummedFileRequest`]s, then reads are
+/// not verified.
+static READ_CHECKSUM_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// How many times we send a single chunk of a large `pwrite` before giving up.
+const WRITE_CHUNK_ATTEMPTS: usize = 3;
 
 /// Helper macro for checking if the given path should be handled remotely.
 /// Uses global [`crate::setup()`].
@@ -47,11 +77,24 @@ macro_rules! ensure_not_ignored {
 pub(crate) struct RemoteFile {
     pub fd: u64,
     pub path: String,
+    /// Whether the file was opened for reading, see [`readiness`](super::readiness).
+    pub readable: bool,
+    /// Whether the file was opened for writing, see [`readiness`](super::readiness).
+    pub writable: bool,
+    /// Whether the file was opened with `O_APPEND`, then the agent writes it at its end, see
+    /// [`pwrite`].
+    pub append: bool,
 }
 
 impl RemoteFile {
-    pub(crate) fn new(fd: u64, path: String) -> Self {
-        Self { fd, path }
+    pub(crate) fn new(fd: u64, path: String, open_options: &OpenOptionsInternal) -> Self {
+        Self {
+            fd,
+            path,
+            readable: open_options.read,
+            writable: open_options.write || open_options.append,
+            append: open_options.append,
+        }
     }
 
     /// Sends a [`OpenFileRequest`] message, opening the file in the agent.
@@ -60,9 +103,12 @@ impl RemoteFile {
         path: PathBuf,
         open_options: OpenOptionsInternal,
     ) -> Detour<OpenFileResponse> {
-        let requesting_file = OpenFileRequest { path, open_options };
+        let requesting_file = OpenFileRequest {
+            path: path.clone(),
+            open_options,
+        };
 
-        let response = common::make_proxy_request_with_response(requesting_file)??;
+        let response = explain::make_proxy_request_with_response(Some(&path), requesting_file)??;
 
         Detour::Success(response)
     }
@@ -72,15 +118,23 @@ impl RemoteFile {
     /// Blocking request and wait on already found remote_fd
     #[mirrord_layer_macro::instrument(level = "trace")]
     pub(crate) fn remote_read(remote_fd: u64, read_amount: u64) -> Detour<ReadFileResponse> {
-        // Limit read size because if we read too much it can lead to a timeout
-        // Seems also that bincode doesn't do well with large buffers
         let read_amount = std::cmp::min(read_amount, MAX_READ_SIZE);
         let reading_file = ReadFileRequest {
             remote_fd,
             buffer_size: read_amount,
         };
 
-        let response = common::make_proxy_request_with_response(reading_file)??;
+        // What a small read left unread comes first, the file in the agent is already past it.
+        let response = match batch::take_unread(remote_fd, read_amount) {
+            Some(response) => response,
+            None => match read_verified(remote_fd, read_amount, None)? {
+                Some(response) => response,
+                None if read_amount <= batch::SMALL_OPERATION_SIZE => {
+                    batch::read(remote_fd, read_amount)??
+                }
+                None => common::make_proxy_request_with_response(reading_file)??,
+            },
+        };
 
         Detour::Success(response)
     }
@@ -88,6 +142,10 @@ impl RemoteFile {
     /// Sends a [`CloseFileRequest`] message, closing the file in the agent.
     #[mirrord_layer_macro::instrument(level = "trace")]
     pub(crate) fn remote_close(fd: u64) -> Result<()> {
+        if common::is_proxy_gone() {
+            return Ok(());
+        }
+
         common::make_proxy_request_no_response(CloseFileRequest { fd })?;
         Ok(())
     }
@@ -101,6 +159,8 @@ impl Drop for RemoteFile {
         // operation to complete. The write operation is hooked and at some point tries to lock
         // `OPEN_FILES`, which means the thread deadlocks with itself (we call
         // `OPEN_FILES.lock()?.remove()` and then while still locked, `OPEN_FILES.lock()` again)
+        readahead::forget(self.fd);
+        batch::forget(self.fd);
         Self::remote_close(self.fd).expect(
             "mirrord failed to send close file message to main layer thread. Error: {err:?}",
         );
@@ -111,13 +171,14 @@ impl Drop for RemoteFile {
 /// `mirrord_agent::util::IndexAllocator`).
 fn get_remote_fd(local_fd: RawFd) -> Detour<u64> {
     // don't add a trace here since it causes deadlocks in some cases.
-    Detour::Success(
-        OPEN_FILES
-            .get(&local_fd)
-            .map(|remote_file| remote_file.fd)
-            // Bypass if we're not managing the relative part.
-            .ok_or(Bypass::LocalFdNotFound(local_fd))?,
-    )
+    let remote_fd = OPEN_FILES
+        .get(&local_fd)
+        .map(|remote_file| remote_file.fd)
+        // Bypass if we're not managing the relative part.
+        .ok_or(Bypass::LocalFdNotFound(local_fd))?;
+    detour::mark_remote_handle();
+
+    Detour::Success(remote_fd)
 }
 
 /// Create temporary local file to get a valid local fd.
@@ -158,17 +219,28 @@ fn close_remote_file_on_failure(fd: u64) -> Result<()> {
 /// _local_ and _remote_ file association, plus **inserting** it into the storage for
 /// [`OPEN_FILES`].
 #[mirrord_layer_macro::instrument(level = "trace", ret)]
-pub(crate) fn open(path: Detour<PathBuf>, open_options: OpenOptionsInternal) -> Detour<RawFd> {
+pub(crate) fn open(path: Detour<PathBuf>, open_flags: c_int) -> Detour<RawFd> {
     let path = path?;
+    let open_options = OpenOptionsInternal::from_flags(open_flags);
 
     if path.is_relative() {
         // Calls with non absolute paths are sent to libc::open.
         Detour::Bypass(Bypass::RelativePath(path.clone()))?
     };
 
-    ensure_not_ignored!(path, open_options.is_write());
+    if let Some(local_path) = cluster_files::local_path(&path) {
+        return cluster_files::open(&local_path, open_flags);
+    }
+
+    let remote_path = remote_path(path.clone(), open_options.is_write())?;
 
-    let OpenFileResponse { fd: remote_fd } = RemoteFile::remote_open(path.clone(), open_options)?;
+    let OpenFileResponse { fd: remote_fd } =
+        match RemoteFile::remote_open(remote_path, open_options) {
+            Detour::Error(error) if falls_back_locally(&path, &error) => {
+                Detour::Bypass(Bypass::LocalFallback(path.clone()))?
+            }
+            result => result?,
+        };
 
     // TODO: Need a way to say "open a directory", right now `is_dir` always returns false.
     // This requires having a fake directory name (`/fake`, for example), instead of just converting
@@ -177,19 +249,73 @@ pub(crate) fn open(path: Detour<PathBuf>, open_options: OpenOptionsInternal) ->
 
     OPEN_FILES.insert(
         local_file_fd,
-        Arc::new(RemoteFile::new(remote_fd, path.display().to_string())),
+        Arc::new(RemoteFile::new(
+            remote_fd,
+            path.display().to_string(),
+            &open_options,
+        )),
     );
 
     Detour::Success(local_file_fd)
 }
 
+/// Where the file at `path` is in the remote, which is in the scratch directory for the temporary
+/// files that match `feature.fs.remote_temp` (see [`scratch`]).
+///
+/// Bypasses with [`Bypass::IgnoredFile`] when `path` should be handled locally.
+fn remote_path(path: PathBuf, write: bool) -> Detour<PathBuf> {
+    if let Some(remote_path) = scratch::remote_path(&path)? {
+        return Detour::Success(remote_path);
+    }
+
+    ensure_not_ignored!(path, write);
+
+    Detour::Success(path)
+}
+
+/// Whether the failure to open `path` remotely should be retried locally, which is when the file
+/// doesn't exist or can't be accessed in the remote, and `path` matches
+/// `feature.fs.fallback_local`.
+fn falls_back_locally(path: &Path, error: &HookError) -> bool {
+    let missing_or_denied = match error {
+        HookError::ResponseError(ResponseError::NotFound(_)) => true,
+        HookError::ResponseError(ResponseError::RemoteIO(io_error)) => {
+            matches!(io_error.raw_os_error, Some(libc::ENOENT | libc::EACCES))
+        }
+        _ => false,
+    };
+
+    missing_or_denied
+        && crate::setup()
+            .file_filter()
+            .falls_back_locally(path.to_str().unwrap_or_default())
+}
+
+/// Bypasses with [`Bypass::LocalFallback`] when the remote `result` for the absolute `path` should
+/// be retried locally (see [`falls_back_locally`]), so that checking a file agrees with opening it.
+///
+/// `opendir` opens the directory with [`open`], which falls back on its own.
+fn or_local_fallback<T>(path: Option<&Path>, result: RemoteResult<T>) -> Detour<T> {
+    let error = match result {
+        Ok(value) => return Detour::Success(value),
+        Err(error) => HookError::from(error),
+    };
+
+    match path {
+        Some(path) if falls_back_locally(path, &error) => {
+            Detour::Bypass(Bypass::LocalFallback(path.to_path_buf()))
+        }
+        _ => Detour::Error(error),
+    }
+}
+
 /// creates a directory stream for the `remote_fd` in the agent
 #[mirrord_layer_macro::instrument(level = "trace", ret)]
 pub(crate) fn fdopendir(fd: RawFd) -> Detour<usize> {
     // usize == ptr size
     // we don't return a pointer to an address that contains DIR
 
-    let remote_file_fd = OPEN_FILES.get(&fd).ok_or(Bypass::LocalFdNotFound(fd))?.fd;
+    let remote_file_fd = get_remote_fd(fd)?;
 
     let open_dir_request = FdOpenDirRequest {
         remote_fd: remote_file_fd,
@@ -207,18 +333,16 @@ pub(crate) fn fdopendir(fd: RawFd) -> Detour<usize> {
 }
 
 #[mirrord_layer_macro::instrument(level = "trace", ret)]
-pub(crate) fn openat(
-    fd: RawFd,
-    path: Detour<PathBuf>,
-    open_options: OpenOptionsInternal,
-) -> Detour<RawFd> {
+pub(crate) fn openat(fd: RawFd, path: Detour<PathBuf>, open_flags: c_int) -> Detour<RawFd> {
     let path = path?;
 
     // `openat` behaves the same as `open` when the path is absolute. When called with AT_FDCWD, the
     // call is propagated to `open`.
     if path.is_absolute() || fd == AT_FDCWD {
-        open(Detour::Success(path), open_options)
+        open(Detour::Success(path), open_flags)
     } else {
+        let open_options = OpenOptionsInternal::from_flags(open_flags);
+
         // Relative path requires special handling, we must identify the relative part (relative to
         // what).
         let remote_fd = get_remote_fd(fd)?;
@@ -230,13 +354,17 @@ pub(crate) fn openat(
         };
 
         let OpenFileResponse { fd: remote_fd } =
-            common::make_proxy_request_with_response(requesting_file)??;
+            explain::make_proxy_request_with_response(Some(&path), requesting_file)??;
 
         let local_file_fd = create_local_fake_file(remote_fd)?;
 
         OPEN_FILES.insert(
             local_file_fd,
-            Arc::new(RemoteFile::new(remote_fd, path.display().to_string())),
+            Arc::new(RemoteFile::new(
+                remote_fd,
+                path.display().to_string(),
+                &open_options,
+            )),
         );
 
         Detour::Success(local_file_fd)
@@ -247,8 +375,15 @@ pub(crate) fn openat(
 ///
 /// **Bypassed** when trying to load system files, and files from the current working directory, see
 /// `open`.
+///
+/// Sequential reads can be served by [`readahead`], and small ones by [`batch::read`].
 pub(crate) fn read(local_fd: RawFd, read_amount: u64) -> Detour<ReadFileResponse> {
-    get_remote_fd(local_fd).and_then(|remote_fd| RemoteFile::remote_read(remote_fd, read_amount))
+    let remote_fd = get_remote_fd(local_fd)?;
+
+    match readahead::read(remote_fd, read_amount)? {
+        Some(response) => Detour::Success(response),
+        None => RemoteFile::remote_read(remote_fd, read_amount),
+    }
 }
 
 /// Helper for dealing with a potential null pointer being passed to `*const iovec` from
@@ -264,36 +399,237 @@ pub(crate) fn readv(iovs: Option<&[iovec]>) -> Detour<(&[iovec], u64)> {
 pub(crate) fn pread(local_fd: RawFd, buffer_size: u64, offset: u64) -> Detour<ReadFileResponse> {
     // We're only interested in files that are paired with mirrord-agent.
     let remote_fd = get_remote_fd(local_fd)?;
+    remote_pread(remote_fd, buffer_size, offset)
+}
 
+/// [`pread`] of the file with `remote_fd` in the agent.
+pub(crate) fn remote_pread(
+    remote_fd: u64,
+    buffer_size: u64,
+    offset: u64,
+) -> Detour<ReadFileResponse> {
     let reading_file = ReadLimitedFileRequest {
         remote_fd,
         buffer_size,
         start_from: offset,
     };
 
-    let response = common::make_proxy_request_with_response(reading_file)??;
+    let response = match read_verified(remote_fd, buffer_size, Some(offset))? {
+        Some(response) => response,
+        None if buffer_size <= batch::SMALL_OPERATION_SIZE => {
+            batch::make_proxy_request_with_response(reading_file)??
+        }
+        None => common::make_proxy_request_with_response(reading_file)??,
+    };
 
     Detour::Success(response)
 }
 
+/// Reads with a [`ReadChecksummedFileRequest`] when `feature.fs.verify_reads` is set, and checks
+/// that the data is intact. Returns [`None`] when the read is not verified, so it has to be done
+/// the usual way.
+///
+/// Reads at `start_from` are sent up to [`READ_ATTEMPTS`] times, reads at the current position
+/// (`start_from` is [`None`]) already moved it, so they fail right away.
+fn read_verified(
+    remote_fd: u64,
+    buffer_size: u64,
+    start_from: Option<u64>,
+) -> Detour<Option<ReadFileResponse>> {
+    if !crate::setup().fs_config().verify_reads || READ_CHECKSUM_UNSUPPORTED.load(Ordering::Relaxed)
+    {
+        return Detour::Success(None);
+    }
+
+    let request = ReadChecksummedFileRequest {
+        remote_fd,
+        buffer_size: buffer_size.min(MAX_VERIFIED_READ_SIZE),
+        start_from,
+    };
+    let read = send_verified(request, common::make_proxy_request_with_response);
+    if matches!(read, Detour::Success(None)) {
+        READ_CHECKSUM_UNSUPPORTED.store(true, Ordering::Relaxed);
+    }
+
+    read
+}
+
+/// Sends `request` with `send` until the data comes back intact, see [`read_verified`].
+///
+/// Returns [`None`] when the agent doesn't support [`ReadChecksummedFileRequest`]s.
+fn send_verified(
+    request: ReadChecksummedFileRequest,
+    mut send: impl FnMut(
+        ReadChecksummedFileRequest,
+    ) -> Result<RemoteResult<ReadChecksummedFileResponse>>,
+) -> Detour<Option<ReadFileResponse>> {
+    let attempts = request.start_from.map_or(1, |_| READ_ATTEMPTS);
+    for attempt in 1..=attempts {
+        match send(request.clone())? {
+            Err(ResponseError::NotImplemented) => return Detour::Success(None),
+            Err(fail) => return Detour::Error(fail.into()),
+            Ok(read) if read.is_intact() => return Detour::Success(Some(read.into())),
+            Ok(..) => {
+                warn!(
+                    remote_fd = request.remote_fd,
+                    start_from = ?request.start_from,
+                    attempt,
+                    "remote file read doesn't match its checksum"
+                );
+            }
+        }
+    }
+
+    Detour::Error(std::io::Error::from_raw_os_error(libc::EIO).into())
+}
+
+/// Buffers larger than [`WRITE_CHUNK_SIZE`] are written in chunks, each one verified with a
+/// [`ChecksumFileRequest`]. A chunk that didn't make it intact is written again at its own offset,
+/// so we never restart the whole transfer, nor leave corrupted data behind.
+///
+/// Files opened with `O_APPEND` are written at their end whatever the `offset` (like on Linux), so
+/// their chunks can't be verified at it, and are only written in order.
 pub(crate) fn pwrite(local_fd: RawFd, buffer: &[u8], offset: u64) -> Detour<WriteFileResponse> {
-    let remote_fd = get_remote_fd(local_fd)?;
+    let (remote_fd, append) = OPEN_FILES
+        .get(&local_fd)
+        .map(|remote_file| (remote_file.fd, remote_file.append))
+        .ok_or(Bypass::LocalFdNotFound(local_fd))?;
+    detour::mark_remote_handle();
     trace!("pwrite: local_fd {local_fd}");
+    readahead::stop(remote_fd)?;
+    batch::give_back(remote_fd)?;
+
+    if buffer.len() <= WRITE_CHUNK_SIZE {
+        let writing_file = WriteLimitedFileRequest {
+            remote_fd,
+            write_bytes: buffer.to_vec(),
+            start_from: offset,
+        };
+
+        let response = if buffer.len() as u64 <= batch::SMALL_OPERATION_SIZE {
+            batch::make_proxy_request_with_response(writing_file)??
+        } else {
+            common::make_proxy_request_with_response(writing_file)??
+        };
+
+        return Detour::Success(response);
+    }
+
+    let mut written_amount = 0;
+    for chunk in buffer.chunks(WRITE_CHUNK_SIZE) {
+        let chunk_written = if append {
+            common::make_proxy_request_with_response(WriteLimitedFileRequest {
+                remote_fd,
+                write_bytes: chunk.to_vec(),
+                start_from: offset + written_amount,
+            })??
+            .written_amount
+        } else {
+            pwrite_verified_chunk(remote_fd, chunk, offset + written_amount)?
+        };
+        written_amount += chunk_written;
 
-    let writing_file = WriteLimitedFileRequest {
+        if chunk_written < chunk.len() as u64 {
+            break;
+        }
+    }
+
+    Detour::Success(WriteFileResponse { written_amount })
+}
+
+/// Writes a single `chunk` of a large [`pwrite`] at `offset`, and verifies it with a
+/// [`ChecksumFileRequest`], retrying up to [`WRITE_CHUNK_ATTEMPTS`] times.
+///
+/// Returns how many bytes of the `chunk` were written.
+fn pwrite_verified_chunk(remote_fd: u64, chunk: &[u8], offset: u64) -> Detour<u64> {
+    send_verified_chunk(
         remote_fd,
-        write_bytes: buffer.to_vec(),
-        start_from: offset,
-    };
+        chunk,
+        offset,
+        common::make_proxy_request_with_response,
+        common::make_proxy_request_with_response,
+    )
+}
 
-    let response = common::make_proxy_request_with_response(writing_file)??;
+/// Sends the requests of [`pwrite_verified_chunk`] with `write` and `checksum`.
+///
+/// The chunk is written at its own offset, so it's also written again when the agent connection
+/// was lost before it was verified, and the transfer resumes with it once the internal proxy
+/// connects to the agent again.
+fn send_verified_chunk(
+    remote_fd: u64,
+    chunk: &[u8],
+    offset: u64,
+    mut write: impl FnMut(WriteLimitedFileRequest) -> Result<RemoteResult<WriteFileResponse>>,
+    mut checksum: impl FnMut(ChecksumFileRequest) -> Result<RemoteResult<ChecksumFileResponse>>,
+) -> Detour<u64> {
+    for attempt in 1..=WRITE_CHUNK_ATTEMPTS {
+        let response = write(WriteLimitedFileRequest {
+            remote_fd,
+            write_bytes: chunk.to_vec(),
+            start_from: offset,
+        });
+        if connection_lost(&response) {
+            warn!(
+                remote_fd,
+                offset, attempt, "agent connection lost while writing a remote file chunk"
+            );
+            continue;
+        }
+        let WriteFileResponse { written_amount } = response??;
 
-    Detour::Success(response)
+        let written = &chunk[..(written_amount as usize).min(chunk.len())];
+        let response = checksum(ChecksumFileRequest {
+            remote_fd,
+            start_from: offset,
+            length: written.len() as u64,
+        });
+        if connection_lost(&response) {
+            warn!(
+                remote_fd,
+                offset, attempt, "agent connection lost while verifying a remote file chunk"
+            );
+            continue;
+        }
+
+        match response? {
+            // The agent is too old to verify the chunk.
+            Err(ResponseError::NotImplemented) => return Detour::Success(written_amount),
+            Err(fail) => return Detour::Error(fail.into()),
+            Ok(ChecksumFileResponse { checksum, length })
+                if length == written.len() as u64 && checksum == FileChecksum::of(written) =>
+            {
+                return Detour::Success(written_amount);
+            }
+            Ok(..) => {
+                warn!(
+                    remote_fd,
+                    offset,
+                    attempt,
+                    "remote file chunk doesn't match what we wrote, writing it again"
+                );
+            }
+        }
+    }
+
+    Detour::Error(std::io::Error::from_raw_os_error(libc::EIO).into())
+}
+
+/// Whether the request failed with `result` because the agent connection was lost or reset before
+/// the agent responded, see [`mirrord_intproxy_protocol::connection_lost`].
+fn connection_lost<T>(result: &Result<RemoteResult<T>>) -> bool {
+    match result {
+        Err(HookError::ProxyError(ProxyError::Interrupted)) => true,
+        Ok(Err(error)) => mirrord_intproxy_protocol::is_connection_lost(error),
+        _ => false,
+    }
 }
 
 #[mirrord_layer_macro::instrument(level = "trace")]
 pub(crate) fn lseek(local_fd: RawFd, offset: i64, whence: i32) -> Detour<u64> {
     let remote_fd = get_remote_fd(local_fd)?;
+    readahead::stop(remote_fd)?;
+    batch::give_back(remote_fd)?;
 
     let seek_from = match whence {
         libc::SEEK_SET => SeekFrom::Start(offset as u64),
@@ -322,6 +658,8 @@ pub(crate) fn lseek(local_fd: RawFd, offset: i64, whence: i32) -> Detour<u64> {
 
 pub(crate) fn write(local_fd: RawFd, write_bytes: Option<Vec<u8>>) -> Detour<isize> {
     let remote_fd = get_remote_fd(local_fd)?;
+    readahead::stop(remote_fd)?;
+    batch::give_back(remote_fd)?;
 
     let writing_file = WriteFileRequest {
         fd: remote_fd,
@@ -329,7 +667,12 @@ pub(crate) fn write(local_fd: RawFd, write_bytes: Option<Vec<u8>>) -> Detour<isi
     };
 
     let WriteFileResponse { written_amount } =
-        common::make_proxy_request_with_response(writing_file)??;
+        if writing_file.write_bytes.len() as u64 <= batch::SMALL_OPERATION_SIZE {
+            batch::make_proxy_request_with_response(writing_file)??
+        } else {
+            common::make_proxy_request_with_response(writing_file)??
+        };
+
     Detour::Success(written_amount.try_into()?)
 }
 
@@ -342,14 +685,20 @@ pub(crate) fn access(path: Detour<PathBuf>, mode: u8) -> Detour<c_int> {
         Detour::Bypass(Bypass::RelativePath(path.clone()))?
     };
 
-    ensure_not_ignored!(path, false);
+    if let Some(local_path) = cluster_files::local_path(&path) {
+        return cluster_files::access(&local_path, mode);
+    }
+
+    let local_path = path.clone();
+    let path = remote_path(path, false)?;
 
     let access = AccessFileRequest {
-        pathname: path,
+        pathname: path.clone(),
         mode,
     };
 
-    let _ = common::make_proxy_request_with_response(access)??;
+    let result = explain::make_proxy_request_with_response(Some(&path), access)?;
+    or_local_fallback(Some(&local_path), result)?;
 
     Detour::Success(0)
 }
@@ -373,25 +722,27 @@ pub(crate) fn xstat(
     fd: Option<RawFd>,
     follow_symlink: bool,
 ) -> Detour<XstatResponse> {
+    // The absolute path of the file, for `feature.fs.fallback_local`.
+    let mut local_path = None;
+
     // Can't use map because we need to propagate captured error
     let (path, fd) = match (rawish_path, fd) {
         // fstatat
         (Some(path), Some(fd)) => {
             let path = path?;
-            let fd = {
-                if fd == AT_FDCWD {
-                    if path.is_relative() {
-                        // Calls with non absolute paths are sent to libc::fstatat.
-                        return Detour::Bypass(Bypass::RelativePath(path));
-                    } else {
-                        ensure_not_ignored!(path, false);
-                        None
-                    }
+            if fd == AT_FDCWD {
+                if path.is_relative() {
+                    // Calls with non absolute paths are sent to libc::fstatat.
+                    return Detour::Bypass(Bypass::RelativePath(path));
+                } else if let Some(local_path) = cluster_files::local_path(&path) {
+                    return cluster_files::xstat(&local_path, follow_symlink);
                 } else {
-                    Some(get_remote_fd(fd)?)
+                    local_path = Some(path.clone());
+                    (Some(remote_path(path, false)?), None)
                 }
-            };
-            (Some(path), fd)
+            } else {
+                (Some(path), Some(get_remote_fd(fd)?))
+            }
         }
         // lstat/stat
         (Some(path), None) => {
@@ -400,8 +751,11 @@ pub(crate) fn xstat(
                 // Calls with non absolute paths are sent to libc::open.
                 return Detour::Bypass(Bypass::RelativePath(path));
             }
-            ensure_not_ignored!(path, false);
-            (Some(path), None)
+            if let Some(local_path) = cluster_files::local_path(&path) {
+                return cluster_files::xstat(&local_path, follow_symlink);
+            }
+            local_path = Some(path.clone());
+            (Some(remote_path(path, false)?), None)
         }
         // fstat
         (None, Some(fd)) => (None, Some(get_remote_fd(fd)?)),
@@ -411,13 +765,13 @@ pub(crate) fn xstat(
 
     let lstat = XstatRequest {
         fd,
-        path,
+        path: path.clone(),
         follow_symlink,
     };
 
-    let response = common::make_proxy_request_with_response(lstat)??;
+    let result = explain::make_proxy_request_with_response(path.as_deref(), lstat)?;
 
-    Detour::Success(response)
+    or_local_fallback(local_path.as_deref(), result)
 }
 
 /// Logic for the `libc::statx` function.
@@ -456,9 +810,11 @@ pub(crate) fn statx_logic(
         return Detour::Error(HookError::BadFlag);
     }
 
+    // The absolute path of the file, for `feature.fs.fallback_local`.
+    let local_path = path_name.is_absolute().then(|| path_name.clone());
+
     let (fd, path) = if path_name.is_absolute() {
-        ensure_not_ignored!(path_name, false);
-        (None, Some(path_name))
+        (None, Some(remote_path(path_name, false)?))
     } else if !path_name.as_os_str().is_empty() && dir_fd == libc::AT_FDCWD {
         return Detour::Bypass(Bypass::RelativePath(path_name));
     } else if !path_name.as_os_str().is_empty() {
@@ -478,11 +834,12 @@ pub(crate) fn statx_logic(
 
         let request = XstatRequest {
             fd,
-            path,
+            path: path.clone(),
             follow_symlink,
         };
 
-        common::make_proxy_request_with_response(request)??.metadata
+        let result = explain::make_proxy_request_with_response(path.as_deref(), request)?;
+        or_local_fallback(local_path.as_deref(), result)?.metadata
     };
 
     /// Converts a nanosecond timestamp from
@@ -608,9 +965,12 @@ pub(crate) fn realpath(path: Detour<PathBuf>) -> Detour<PathBuf> {
 
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::{cell::Cell, path::PathBuf};
+
+    use rstest::rstest;
+
+    use super::*;
 
-    use super::absolute_path;
     #[test]
     fn test_absolute_normal() {
         assert_eq!(
@@ -626,4 +986,149 @@ mod test {
             PathBuf::from("/a/b/c")
         )
     }
+
+    type Response = Result<RemoteResult<ReadChecksummedFileResponse>>;
+
+    /// Answers the first `corrupted` reads with a wrong checksum, and the rest intact, counting
+    /// them in `sent`.
+    fn agent(
+        corrupted: usize,
+        sent: &Cell<usize>,
+    ) -> impl FnMut(ReadChecksummedFileRequest) -> Response + '_ {
+        move |request| {
+            let attempt = sent.replace(sent.get() + 1);
+            let bytes = vec![7; request.buffer_size as usize];
+            let checksum = FileChecksum::of(&bytes).wrapping_add(u64::from(attempt < corrupted));
+
+            Ok(Ok(ReadChecksummedFileResponse {
+                read_amount: bytes.len() as u64,
+                bytes,
+                checksum,
+            }))
+        }
+    }
+
+    fn request(start_from: Option<u64>) -> ReadChecksummedFileRequest {
+        ReadChecksummedFileRequest {
+            remote_fd: 1,
+            buffer_size: 16,
+            start_from,
+        }
+    }
+
+    #[rstest]
+    #[case::intact(Some(0), 0, 1)]
+    #[case::intact_at_position(None, 0, 1)]
+    #[case::retried(Some(0), READ_ATTEMPTS - 1, READ_ATTEMPTS)]
+    fn verified(#[case] start_from: Option<u64>, #[case] corrupted: usize, #[case] sends: usize) {
+        let sent = Cell::new(0);
+
+        let Detour::Success(Some(read)) =
+            send_verified(request(start_from), agent(corrupted, &sent))
+        else {
+            panic!("the read wasn't verified");
+        };
+
+        assert_eq!(read.bytes, [7; 16]);
+        assert_eq!(read.read_amount, 16);
+        assert_eq!(sent.get(), sends);
+    }
+
+    /// Reads at the current position aren't sent again, since the first one already moved it.
+    #[rstest]
+    #[case::positional(Some(0), READ_ATTEMPTS)]
+    #[case::at_position(None, 1)]
+    fn corrupted(#[case] start_from: Option<u64>, #[case] sends: usize) {
+        let sent = Cell::new(0);
+
+        let read = send_verified(request(start_from), agent(usize::MAX, &sent));
+
+        assert!(matches!(
+            read,
+            Detour::Error(HookError::IO(ref error)) if error.raw_os_error() == Some(libc::EIO)
+        ));
+        assert_eq!(sent.get(), sends);
+    }
+
+    /// Answers every write in full and every checksum right, except for the first `lost_writes`
+    /// writes and `lost_checksums` checksums, which fail as if the agent connection was lost.
+    /// Counts the writes in `writes`.
+    fn writing_agent(
+        lost_writes: usize,
+        lost_checksums: usize,
+        writes: &Cell<usize>,
+    ) -> (
+        impl FnMut(WriteLimitedFileRequest) -> Result<RemoteResult<WriteFileResponse>> + '_,
+        impl FnMut(ChecksumFileRequest) -> Result<RemoteResult<ChecksumFileResponse>>,
+    ) {
+        let write = move |request: WriteLimitedFileRequest| {
+            if writes.replace(writes.get() + 1) < lost_writes {
+                return Ok(Err(mirrord_intproxy_protocol::connection_lost()));
+            }
+
+            Ok(Ok(WriteFileResponse {
+                written_amount: request.write_bytes.len() as u64,
+            }))
+        };
+
+        let mut checksums = 0;
+        let checksum = move |request: ChecksumFileRequest| {
+            checksums += 1;
+            if checksums <= lost_checksums {
+                return Err(HookError::ProxyError(ProxyError::Interrupted));
+            }
+
+            Ok(Ok(ChecksumFileResponse {
+                checksum: FileChecksum::of(&vec![7; request.length as usize]),
+                length: request.length,
+            }))
+        };
+
+        (write, checksum)
+    }
+
+    /// A chunk is written again when the agent connection was lost before it was verified.
+    #[rstest]
+    #[case::not_lost(0, 0, 1)]
+    #[case::write_lost(1, 0, 2)]
+    #[case::checksum_lost(0, 1, 2)]
+    fn chunk_resumed(
+        #[case] lost_writes: usize,
+        #[case] lost_checksums: usize,
+        #[case] writes: usize,
+    ) {
+        let sent = Cell::new(0);
+        let (write, checksum) = writing_agent(lost_writes, lost_checksums, &sent);
+
+        let written = send_verified_chunk(1, &[7; 16], 0, write, checksum);
+
+        assert!(matches!(written, Detour::Success(16)));
+        assert_eq!(sent.get(), writes);
+    }
+
+    #[test]
+    fn chunk_lost() {
+        let sent = Cell::new(0);
+        let (write, checksum) = writing_agent(usize::MAX, 0, &sent);
+
+        let written = send_verified_chunk(1, &[7; 16], 0, write, checksum);
+
+        assert!(matches!(
+            written,
+            Detour::Error(HookError::IO(ref error)) if error.raw_os_error() == Some(libc::EIO)
+        ));
+        assert_eq!(sent.get(), WRITE_CHUNK_ATTEMPTS);
+    }
+
+    #[test]
+    fn not_supported() {
+        let read = send_verified(request(Some(0)), |_| Ok(Err(ResponseError::NotImplemented)));
+        assert!(matches!(read, Detour::Success(None)));
+
+        let read = send_verified(request(Some(0)), |_| Ok(Err(ResponseError::NotFound(1))));
+        assert!(matches!(
+            read,
+            Detour::Error(HookError::ResponseError(ResponseError::NotFound(1)))
+        ));
+    }
 }
@@ -0,0 +1,296 @@
+//! Readahead of remote files that are read sequentially, see `feature.fs.readahead`.
+//!
+//! After [`SEQUENTIAL_READS`] `read`s of a remote file in a row, with nothing else done to it in
+//! between, we take over its position: the `read`s are served from windows of the file read with
+//! `pread`s, and the next window is read in the background while the application reads the
+//! current one. So apps that stream large remote files (log tailers, model loaders) don't wait for
+//! a round trip to the agent on every `read`.
+//!
+//! The position of the file in the agent stays where it was while we read ahead. When the
+//! application does anything else that depends on it, or changes the file (`lseek`, `write`,
+//! `pwrite`), we [`stop`]: seek the file in the agent to where the application is, and start
+//! counting `read`s again.
+//!
+//! The position is only tracked in this process, so a file shared with another process (e.g.
+//! after a `fork`) that reads it at the same time can't be read ahead.
+//!
+//! The windows of all files are read in the background one at a time, by a single thread, see
+//! [`in_background`].
+
+use std::{
+    io::SeekFrom,
+    sync::{
+        mpsc::{self, Receiver, SendError, Sender},
+        Arc, LazyLock, Mutex,
+    },
+    thread,
+};
+
+use dashmap::DashMap;
+use mirrord_protocol::file::{ReadFileResponse, SeekFileRequest, SeekFileResponse};
+
+use super::{batch, ops};
+use crate::{
+    common,
+    detour::{Detour, DetourGuard},
+};
+
+/// How many `read`s of a file in a row make us read it ahead.
+const SEQUENTIAL_READS: usize = 2;
+
+/// State of the readahead of the remote files, by their fd in the agent.
+static READAHEADS: LazyLock<DashMap<u64, Arc<Mutex<Readahead>>>> = LazyLock::new(DashMap::new);
+
+/// Work done by the background thread, see [`in_background`].
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Sends the [`Job`]s to the background thread, with the pid of the process that started it.
+static BACKGROUND: Mutex<Option<(u32, Sender<Job>)>> = Mutex::new(None);
+
+/// Runs the `job` in the background thread, after the ones that were sent before it.
+///
+/// The thread is started with the first job, and again in a process that was forked from the one
+/// that started it, since threads don't survive `fork`.
+fn in_background(job: Job) {
+    let Ok(mut background) = BACKGROUND.lock() else {
+        return;
+    };
+
+    let pid = std::process::id();
+    let job = match background.as_ref() {
+        Some((started_by, sender)) if *started_by == pid => match sender.send(job) {
+            Ok(()) => return,
+            Err(SendError(job)) => job,
+        },
+        _ => job,
+    };
+
+    let (sender, receiver) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+        let _guard = DetourGuard::new();
+        for job in receiver {
+            job();
+        }
+    });
+
+    let _ = sender.send(job);
+    *background = Some((pid, sender));
+}
+
+#[derive(Default)]
+struct Readahead {
+    /// `read`s of the file in a row, until we take over its position.
+    reads: usize,
+    /// Where the application is in the file, once we took over its position.
+    position: Option<u64>,
+    /// Data of the file that was read ahead, starting at `position`.
+    buffer: Vec<u8>,
+    /// Window that is being read in the background, it starts where `buffer` ends.
+    prefetch: Option<Receiver<Option<ReadFileResponse>>>,
+}
+
+impl Readahead {
+    /// Starts reading the `window` after `buffer` in the background.
+    fn prefetch(&mut self, remote_fd: u64, position: u64, window: u64) {
+        let start = position + self.buffer.len() as u64;
+        let (sender, receiver) = mpsc::sync_channel(1);
+
+        in_background(Box::new(move || {
+            let response = match ops::remote_pread(remote_fd, window, start) {
+                Detour::Success(response) => Some(response),
+                _ => None,
+            };
+            let _ = sender.send(response);
+        }));
+
+        self.prefetch = Some(receiver);
+    }
+
+    /// Takes the `amount` bytes at `position` out of `buffer`, reading the missing ones (and the
+    /// rest of the `window`) first with `pread(size, start)`.
+    ///
+    /// Also returns whether the end of the file was reached.
+    fn serve(
+        &mut self,
+        position: u64,
+        amount: u64,
+        window: u64,
+        pread: impl FnOnce(u64, u64) -> Detour<ReadFileResponse>,
+    ) -> Detour<(ReadFileResponse, bool)> {
+        let mut end_of_file = false;
+        let missing = amount.saturating_sub(self.buffer.len() as u64);
+        if missing > 0 {
+            let start = position + self.buffer.len() as u64;
+            let size = window.max(missing);
+            let ReadFileResponse { bytes, read_amount } = pread(size, start)?;
+
+            end_of_file = read_amount < size;
+            self.buffer.extend(bytes);
+        }
+
+        let read_amount = amount.min(self.buffer.len() as u64);
+        let bytes = self
+            .buffer
+            .drain(..read_amount as usize)
+            .collect::<Vec<_>>();
+        self.position = Some(position + read_amount);
+
+        Detour::Success((ReadFileResponse { bytes, read_amount }, end_of_file))
+    }
+
+    /// Adds the window that was read in the background to `buffer`, if it was read successfully.
+    fn take_prefetched(&mut self) {
+        if let Some(ReadFileResponse { bytes, .. }) = self
+            .prefetch
+            .take()
+            .and_then(|prefetch| prefetch.recv().ok().flatten())
+        {
+            self.buffer.extend(bytes);
+        }
+    }
+}
+
+/// Reads `amount` bytes of the file with `remote_fd` when we read it ahead, returns [`None`] when
+/// the `read` has to be done the usual way.
+pub(crate) fn read(remote_fd: u64, amount: u64) -> Detour<Option<ReadFileResponse>> {
+    let Some(window) = crate::setup()
+        .fs_config()
+        .readahead
+        .filter(|window| *window > 0)
+    else {
+        return Detour::Success(None);
+    };
+
+    let readahead = READAHEADS.entry(remote_fd).or_default().clone();
+    let mut readahead = readahead.lock()?;
+
+    let position = match readahead.position {
+        Some(position) => position,
+        None => {
+            readahead.reads += 1;
+            if readahead.reads <= SEQUENTIAL_READS {
+                return Detour::Success(None);
+            }
+
+            batch::give_back(remote_fd)?;
+            let SeekFileResponse { result_offset } =
+                common::make_proxy_request_with_response(SeekFileRequest {
+                    fd: remote_fd,
+                    seek_from: SeekFrom::Current(0).into(),
+                })??;
+            readahead.position = Some(result_offset);
+            result_offset
+        }
+    };
+
+    if (readahead.buffer.len() as u64) < amount {
+        readahead.take_prefetched();
+    }
+
+    let (response, end_of_file) = readahead.serve(position, amount, window, |size, start| {
+        ops::remote_pread(remote_fd, size, start)
+    })?;
+    let position = position + response.read_amount;
+
+    if !end_of_file && readahead.prefetch.is_none() {
+        readahead.prefetch(remote_fd, position, window);
+    }
+
+    Detour::Success(Some(response))
+}
+
+/// Stops reading the file with `remote_fd` ahead, and seeks it in the agent to where the
+/// application is.
+pub(crate) fn stop(remote_fd: u64) -> Detour<()> {
+    let Some((_, readahead)) = READAHEADS.remove(&remote_fd) else {
+        return Detour::Success(());
+    };
+
+    let Some(position) = readahead.lock()?.position else {
+        return Detour::Success(());
+    };
+
+    common::make_proxy_request_with_response(SeekFileRequest {
+        fd: remote_fd,
+        seek_from: SeekFrom::Start(position).into(),
+    })??;
+
+    Detour::Success(())
+}
+
+/// Forgets the readahead of the file with `remote_fd`, which was closed.
+pub(crate) fn forget(remote_fd: u64) {
+    READAHEADS.remove(&remote_fd);
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::ThreadId;
+
+    use super::*;
+
+    fn response(bytes: &[u8]) -> ReadFileResponse {
+        ReadFileResponse {
+            bytes: bytes.to_vec(),
+            read_amount: bytes.len() as u64,
+        }
+    }
+
+    #[test]
+    fn serves_from_buffer() {
+        let mut readahead = Readahead {
+            buffer: b"hello world".to_vec(),
+            ..Default::default()
+        };
+
+        let Detour::Success((read, end_of_file)) =
+            readahead.serve(10, 5, 64, |_, _| panic!("nothing is missing"))
+        else {
+            panic!("serve failed");
+        };
+        assert_eq!(read, response(b"hello"));
+        assert!(!end_of_file);
+        assert_eq!(readahead.position, Some(15));
+        assert_eq!(readahead.buffer, b" world");
+    }
+
+    #[test]
+    fn reads_missing_window() {
+        let mut readahead = Readahead {
+            buffer: b"hel".to_vec(),
+            ..Default::default()
+        };
+
+        let Detour::Success((read, end_of_file)) = readahead.serve(10, 5, 64, |size, start| {
+            assert_eq!((size, start), (64, 13));
+            Detour::Success(response(b"lo world"))
+        }) else {
+            panic!("serve failed");
+        };
+        assert_eq!(read, response(b"hello"));
+        assert!(end_of_file);
+        assert_eq!(readahead.buffer, b" world");
+    }
+
+    #[test]
+    fn one_background_thread() {
+        let (sender, receiver) = mpsc::channel::<(usize, ThreadId)>();
+        for job in 0..3 {
+            let sender = sender.clone();
+            in_background(Box::new(move || {
+                let _ = sender.send((job, thread::current().id()));
+            }));
+        }
+        drop(sender);
+
+        let jobs = receiver.iter().collect::<Vec<_>>();
+        assert_eq!(
+            jobs.iter().map(|(job, _)| *job).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert!(jobs
+            .windows(2)
+            .all(|pair| matches!(pair, [(_, a), (_, b)] if a == b)));
+        assert!(jobs.iter().all(|(_, id)| *id != thread::current().id()));
+    }
+}
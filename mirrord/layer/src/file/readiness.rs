@@ -0,0 +1,148 @@
+//! Readiness of remote files for `epoll`.
+//!
+//! The local fd of a remote file is an empty temporary file (see `create_local_fake_file`), which
+//! `poll` and `select` already report as always ready, but `epoll_ctl` refuses to watch
+//! (`EPERM`). Reads and writes of remote files never return `EAGAIN`, they wait for the agent
+//! instead, so a remote file is always ready for what it was opened for: reading
+//! ([`RemoteFile::readable`]), writing ([`RemoteFile::writable`]), or both. We register an always
+//! ready stand-in for it, for exactly those events.
+//!
+//! [`RemoteFile::readable`]: super::ops::RemoteFile::readable
+//! [`RemoteFile::writable`]: super::ops::RemoteFile::writable
+
+use std::{
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::io::RawFd,
+    },
+    sync::LazyLock,
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use errno::{set_errno, Errno};
+use libc::{c_int, epoll_event, EFD_CLOEXEC, EFD_NONBLOCK, ENOENT, EPOLL_CTL_ADD, EPOLL_CTL_DEL};
+
+use super::{hooks::FN_EPOLL_CTL, OPEN_FILES};
+use crate::detour::{Bypass, Detour};
+
+/// Always ready stand-ins for the remote files registered with `epoll_ctl`, by local fd.
+///
+/// An `eventfd` with a non-zero counter is always readable and writable, and `epoll` reports the
+/// `data` of the registration, not the fd, so the process can't tell the difference.
+static EPOLL_STAND_INS: LazyLock<DashMap<RawFd, OwnedFd>> = LazyLock::new(DashMap::new);
+
+/// Whether the remote file at `fd` is ready for reading and for writing, [`None`] when `fd` is
+/// not a remote file.
+fn remote_readiness(fd: RawFd) -> Option<(bool, bool)> {
+    OPEN_FILES
+        .get(&fd)
+        .map(|remote_file| (remote_file.readable, remote_file.writable))
+}
+
+/// Registers an always ready stand-in for the remote file at `fd` (see [`EPOLL_STAND_INS`]), for
+/// the events it's ready for.
+#[mirrord_layer_macro::instrument(level = "trace", ret)]
+pub(crate) unsafe fn epoll_ctl(
+    epfd: c_int,
+    op: c_int,
+    fd: RawFd,
+    event: *mut epoll_event,
+) -> Detour<c_int> {
+    let (readable, writable) = remote_readiness(fd).ok_or(Bypass::LocalFdNotFound(fd))?;
+
+    let stand_in = match stand_in(fd, op) {
+        Ok(stand_in) => stand_in,
+        Err(errno) => {
+            set_errno(errno);
+            return Detour::Success(-1);
+        }
+    };
+
+    if op == EPOLL_CTL_DEL || event.is_null() {
+        return Detour::Success(FN_EPOLL_CTL(epfd, op, stand_in, event));
+    }
+
+    let mut event = *event;
+    event.events = epoll_events(event.events, readable, writable);
+
+    Detour::Success(FN_EPOLL_CTL(epfd, op, stand_in, &mut event))
+}
+
+/// The `epoll` stand-in of the remote file at `fd`, created only when it's being added
+/// (`EPOLL_CTL_ADD`).
+///
+/// Modifying or removing a remote file that was never added fails with `ENOENT`, like it does for
+/// any fd that isn't registered with the `epoll` instance.
+fn stand_in(fd: RawFd, op: c_int) -> Result<RawFd, Errno> {
+    match EPOLL_STAND_INS.entry(fd) {
+        Entry::Occupied(entry) => Ok(entry.get().as_raw_fd()),
+        Entry::Vacant(..) if op != EPOLL_CTL_ADD => Err(Errno(ENOENT)),
+        Entry::Vacant(entry) => {
+            let stand_in = unsafe { libc::eventfd(1, EFD_CLOEXEC | EFD_NONBLOCK) };
+            if stand_in == -1 {
+                return Err(errno::errno());
+            }
+
+            Ok(entry
+                .insert(unsafe { OwnedFd::from_raw_fd(stand_in) })
+                .as_raw_fd())
+        }
+    }
+}
+
+/// `epoll` events of a file that is ready for reading and/or writing, out of the requested
+/// `events`.
+fn epoll_events(mut events: u32, readable: bool, writable: bool) -> u32 {
+    if !readable {
+        events &= !((libc::EPOLLIN | libc::EPOLLRDNORM) as u32);
+    }
+    if !writable {
+        events &= !((libc::EPOLLOUT | libc::EPOLLWRNORM) as u32);
+    }
+
+    events
+}
+
+/// Closes the `epoll` stand-in of the remote file at `fd`, which also removes it from the `epoll`
+/// instances.
+pub(crate) fn close(fd: RawFd) {
+    EPOLL_STAND_INS.remove(&fd);
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::read_only(true, false, (libc::EPOLLIN | libc::EPOLLET) as u32)]
+    #[case::write_only(false, true, (libc::EPOLLOUT | libc::EPOLLET) as u32)]
+    #[case::read_write(true, true, (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLET) as u32)]
+    fn epoll_ready_for(#[case] readable: bool, #[case] writable: bool, #[case] expected: u32) {
+        let requested = (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLET) as u32;
+        assert_eq!(epoll_events(requested, readable, writable), expected);
+    }
+
+    /// Fds that aren't open in the test process, so they don't clash with real ones.
+    #[rstest]
+    #[case::modify(1_000_001, libc::EPOLL_CTL_MOD)]
+    #[case::delete(1_000_002, EPOLL_CTL_DEL)]
+    fn not_added(#[case] fd: RawFd, #[case] op: c_int) {
+        assert_eq!(stand_in(fd, op).unwrap_err().0, ENOENT);
+        assert!(!EPOLL_STAND_INS.contains_key(&fd));
+    }
+
+    #[test]
+    fn one_stand_in_until_closed() {
+        let fd = 1_000_003;
+
+        let added = stand_in(fd, EPOLL_CTL_ADD).unwrap();
+        assert_eq!(stand_in(fd, libc::EPOLL_CTL_MOD).unwrap(), added);
+        assert_eq!(stand_in(fd, EPOLL_CTL_DEL).unwrap(), added);
+
+        close(fd);
+        assert!(!EPOLL_STAND_INS.contains_key(&fd));
+        assert_eq!(stand_in(fd, EPOLL_CTL_DEL).unwrap_err().0, ENOENT);
+    }
+}
@@ -0,0 +1,93 @@
+//! Local temporary files that live in a scratch directory in the target, see
+//! `feature.fs.remote_temp`.
+//!
+//! The agent creates the scratch directory with the first [`ScratchDirRequest`], and removes it
+//! when the session ends. A temporary file keeps its path relative to the local temporary
+//! directory, e.g. `/tmp/build-1/out.txt` is `<scratch>/build-1/out.txt`. `mkdir` is not hooked,
+//! so we have the agent create the parent directory of a temporary file before it's opened.
+
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+};
+
+use dashmap::DashMap;
+use mirrord_protocol::{
+    file::{ScratchDirRequest, ScratchDirResponse},
+    ResponseError,
+};
+
+use crate::{common, detour::Detour};
+
+/// Directories that were created in the scratch directory, by their path relative to it.
+static SCRATCH_DIRS: LazyLock<DashMap<PathBuf, PathBuf>> = LazyLock::new(DashMap::new);
+
+/// Set once the agent turns out not to support [`ScratchDirRequest`]s, then temporary files stay
+/// local.
+static SCRATCH_DIR_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Where `path` is in the local temporary directory (`$TMPDIR` or `/tmp`), [`None`] when it's not
+/// in it, or leaves it with `..`.
+fn relative_to_temp_dir(path: &Path) -> Option<&Path> {
+    let relative = [env::temp_dir(), PathBuf::from("/tmp")]
+        .iter()
+        .find_map(|temp_dir| path.strip_prefix(temp_dir).ok())?;
+
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(..)))
+        .then_some(relative)
+}
+
+/// Where the local temporary file at `path` is in the target, when it matches
+/// `feature.fs.remote_temp`, [`None`] when it's handled the usual way.
+///
+/// Creates the parent directory of the file in the scratch directory.
+pub(crate) fn remote_path(path: &Path) -> Detour<Option<PathBuf>> {
+    if SCRATCH_DIR_UNSUPPORTED.load(Ordering::Relaxed)
+        || !crate::setup()
+            .file_filter()
+            .in_remote_temp(path.to_str().unwrap_or_default())
+    {
+        return Detour::Success(None);
+    }
+
+    let Some(relative) = relative_to_temp_dir(path) else {
+        return Detour::Success(None);
+    };
+
+    // The temporary directory itself is the scratch directory.
+    let (parent, file_name) = match (relative.parent(), relative.file_name()) {
+        (Some(parent), Some(file_name)) => (parent, Some(file_name)),
+        _ => (relative, None),
+    };
+
+    let remote_dir = match SCRATCH_DIRS.get(parent) {
+        Some(remote_dir) => remote_dir.clone(),
+        None => {
+            let response = common::make_proxy_request_with_response(ScratchDirRequest {
+                path: parent.to_path_buf(),
+            })?;
+
+            let ScratchDirResponse { path: remote_dir } = match response {
+                Err(ResponseError::NotImplemented) => {
+                    SCRATCH_DIR_UNSUPPORTED.store(true, Ordering::Relaxed);
+                    return Detour::Success(None);
+                }
+                response => response?,
+            };
+
+            SCRATCH_DIRS.insert(parent.to_path_buf(), remote_dir.clone());
+            remote_dir
+        }
+    };
+
+    Detour::Success(Some(match file_name {
+        Some(file_name) => remote_dir.join(file_name),
+        None => remote_dir,
+    }))
+}
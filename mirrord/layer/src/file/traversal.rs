@@ -0,0 +1,1049 @@
+//! Remote implementation of the file tree traversal APIs (`glob`, `nftw` and the `fts_*` family).
+//!
+//! These functions are implemented in libc on top of `opendir`/`readdir`/`stat`, but not
+//! necessarily through the symbols we hook, so a program that walks a remote tree would get a mix
+//! of local and remote results. When the root of the traversal should be handled remotely, we list
+//! the directories and stat the files through the agent instead, and report the results with the
+//! semantics of the original API.
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use libc::{c_int, DT_DIR, DT_LNK, DT_UNKNOWN, ENOTDIR};
+use mirrord_config::experimental::ExperimentalFeature;
+use mirrord_protocol::{
+    file::{
+        CloseDirRequest, DirEntryInternal, FdOpenDirRequest, MetadataInternal, OpenDirResponse,
+        OpenFileRequest, OpenFileResponse, OpenOptionsInternal, ReadDirBatchRequest,
+        ReadDirBatchResponse, ReadDirRequest, ReadDirResponse, XstatRequest, XstatResponse,
+    },
+    ResponseError,
+};
+
+use super::{explain, ops::RemoteFile};
+use crate::{
+    common,
+    detour::{Bypass, Detour},
+    error::{HookError, HookResult},
+};
+
+/// Amount of entries we ask for in a single [`ReadDirBatchRequest`].
+const READDIR_BATCH_SIZE: usize = 128;
+
+/// Checks whether a traversal rooted at `path` should be handled remotely.
+pub(crate) fn ensure_remote_root(path: &Path) -> Detour<()> {
+    let traversal_hooks = ExperimentalFeature::TraversalHooks;
+    if !crate::setup().is_experimental_enabled(traversal_hooks) {
+        return Detour::Bypass(Bypass::Experimental(traversal_hooks.name()));
+    }
+
+    if path.is_relative() {
+        return Detour::Bypass(Bypass::RelativePath(path.to_path_buf()));
+    }
+
+    crate::setup().file_filter().continue_or_bypass_with(
+        path.to_str().unwrap_or_default(),
+        false,
+        || Bypass::IgnoredFile(path.to_path_buf()),
+    )
+}
+
+/// Sets `errno` from `fail` (the same way a failed hook would), and returns it.
+pub(crate) fn errno_of(fail: HookError) -> c_int {
+    let _ = i64::from(fail);
+    errno::errno().0
+}
+
+/// Stats the remote `path`.
+fn remote_stat(path: &Path, follow_symlink: bool) -> HookResult<MetadataInternal> {
+    let XstatResponse { metadata } = explain::make_proxy_request_with_response(
+        Some(path),
+        XstatRequest {
+            path: Some(path.to_path_buf()),
+            fd: None,
+            follow_symlink,
+        },
+    )??;
+
+    Ok(metadata)
+}
+
+/// Lists the entries of the remote directory at `path` (without `.` and `..`).
+///
+/// When `name_filter` is set, only entries that match it are returned (see
+/// [`ReadDirBatchRequest::name_filter`]).
+fn remote_dir_entries(path: &Path, name_filter: Option<&str>) -> HookResult<Vec<DirEntryInternal>> {
+    let OpenFileResponse { fd } = explain::make_proxy_request_with_response(
+        Some(path),
+        OpenFileRequest {
+            path: path.to_path_buf(),
+            open_options: OpenOptionsInternal {
+                read: true,
+                ..Default::default()
+            },
+        },
+    )??;
+
+    let opened = common::make_proxy_request_with_response(FdOpenDirRequest { remote_fd: fd });
+    // The directory stream doesn't need the file.
+    RemoteFile::remote_close(fd)?;
+    let OpenDirResponse { fd: remote_fd } = opened??;
+
+    let entries = read_remote_dir(remote_fd, name_filter);
+    common::make_proxy_request_no_response(CloseDirRequest { remote_fd })?;
+
+    entries
+}
+
+/// Reads the whole remote directory stream `remote_fd`.
+///
+/// Reads the entries one by one, filtering the names locally, unless
+/// [`ExperimentalFeature::BatchedDirReads`] is enabled and the agent supports
+/// [`ReadDirBatchRequest`].
+fn read_remote_dir(remote_fd: u64, name_filter: Option<&str>) -> HookResult<Vec<DirEntryInternal>> {
+    let mut entries = Vec::new();
+
+    while crate::setup().is_experimental_enabled(ExperimentalFeature::BatchedDirReads) {
+        let request = ReadDirBatchRequest {
+            remote_fd,
+            amount: READDIR_BATCH_SIZE,
+            name_filter: name_filter.map(ToString::to_string),
+        };
+
+        match common::make_proxy_request_with_response(request)? {
+            Ok(ReadDirBatchResponse { dir_entries, .. }) if dir_entries.is_empty() => {
+                return Ok(entries)
+            }
+            Ok(ReadDirBatchResponse { dir_entries, .. }) => entries.extend(dir_entries),
+            Err(ResponseError::NotImplemented) => break,
+            Err(fail) => return Err(fail.into()),
+        }
+    }
+
+    loop {
+        let ReadDirResponse { direntry } =
+            common::make_proxy_request_with_response(ReadDirRequest { remote_fd })??;
+
+        match direntry {
+            Some(entry)
+                if name_filter.map_or(true, |filter| {
+                    wildcard_match(filter.as_bytes(), entry.name.as_bytes(), false)
+                }) =>
+            {
+                entries.push(entry)
+            }
+            Some(_) => {}
+            None => return Ok(entries),
+        }
+    }
+}
+
+fn is_dir_mode(mode: u32) -> bool {
+    (mode as libc::mode_t & libc::S_IFMT) == libc::S_IFDIR
+}
+
+fn is_symlink_mode(mode: u32) -> bool {
+    (mode as libc::mode_t & libc::S_IFMT) == libc::S_IFLNK
+}
+
+/// Appends `name` to the directory `path`.
+fn join(path: &str, name: &str) -> String {
+    if path.ends_with('/') {
+        format!("{path}{name}")
+    } else {
+        format!("{path}/{name}")
+    }
+}
+
+/// Checks whether the glob `pattern` contains unescaped wildcards.
+pub(crate) fn has_magic(pattern: &str, escape: bool) -> bool {
+    let mut bytes = pattern.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' if escape => {
+                bytes.next();
+            }
+            b'*' | b'?' | b'[' => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+/// Removes the escaping backslashes from a `pattern` without wildcards.
+fn unescape(pattern: &str, escape: bool) -> String {
+    if !escape {
+        return pattern.to_string();
+    }
+
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.push(chars.next().unwrap_or('\\')),
+            c => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+/// Matches a file `name` against a single path component of a glob `pattern`.
+///
+/// Supports `*`, `?` and bracket expressions (with ranges, `!`/`^` negation and `[:class:]`s).
+/// When `escape` is set, a backslash makes the next character match literally.
+pub(crate) fn wildcard_match(pattern: &[u8], name: &[u8], escape: bool) -> bool {
+    let mut p = 0;
+    let mut n = 0;
+    // Where to resume from when we fail to match after a `*`, so that it consumes one more byte.
+    let mut resume = None;
+
+    while let Some(&byte) = name.get(n) {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            resume = Some((p, n));
+            continue;
+        }
+
+        match match_token(pattern, p, byte, escape) {
+            Some(next) => {
+                p = next;
+                n += 1;
+            }
+            None => match resume {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    resume = Some((star_p, n));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern
+        .get(p..)
+        .is_some_and(|rest| rest.iter().all(|&token| token == b'*'))
+}
+
+/// Matches `byte` against the (non `*`) token that starts at `pattern[p]`, returning the position
+/// of the next token on success.
+fn match_token(pattern: &[u8], p: usize, byte: u8, escape: bool) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => match match_bracket(pattern, p + 1, byte, escape) {
+            Some((next, true)) => Some(next),
+            Some((_, false)) => None,
+            // Unterminated bracket, `[` is just a regular character.
+            None => (byte == b'[').then_some(p + 1),
+        },
+        b'\\' if escape => match pattern.get(p + 1) {
+            Some(&literal) => (literal == byte).then_some(p + 2),
+            None => (byte == b'\\').then_some(p + 1),
+        },
+        literal => (literal == byte).then_some(p + 1),
+    }
+}
+
+/// Matches `byte` against the bracket expression whose contents start at `pattern[start]`.
+///
+/// Returns the position after the closing `]` and whether `byte` matched, or [`None`] if the
+/// bracket is not terminated.
+fn match_bracket(pattern: &[u8], start: usize, byte: u8, escape: bool) -> Option<(usize, bool)> {
+    let mut i = start;
+    let negate = matches!(pattern.get(i), Some(b'!' | b'^'));
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+
+    loop {
+        let mut low = *pattern.get(i)?;
+
+        // `]` right after the opening `[` (or negation) is a regular character.
+        if low == b']' && !first {
+            return Some((i + 1, matched != negate));
+        }
+        first = false;
+
+        if low == b'[' && pattern.get(i + 1) == Some(&b':') {
+            let class_end = pattern
+                .get(i + 2..)?
+                .windows(2)
+                .position(|window| window == b":]")
+                .map(|position| i + 2 + position);
+
+            if let Some(class_end) = class_end {
+                let class = pattern.get(i + 2..class_end)?;
+                matched |= class_matches(class, byte);
+                i = class_end + 2;
+                continue;
+            }
+        }
+
+        if low == b'\\' && escape {
+            i += 1;
+            low = *pattern.get(i)?;
+        }
+        i += 1;
+
+        match (pattern.get(i), pattern.get(i + 1)) {
+            (Some(b'-'), Some(&high)) if high != b']' => {
+                i += 2;
+                let high = if high == b'\\' && escape {
+                    i += 1;
+                    *pattern.get(i - 1)?
+                } else {
+                    high
+                };
+
+                matched |= (low..=high).contains(&byte);
+            }
+            _ => matched |= low == byte,
+        }
+    }
+}
+
+/// Matches `byte` against the POSIX character `class` (like `alpha` in `[[:alpha:]]`).
+fn class_matches(class: &[u8], byte: u8) -> bool {
+    match class {
+        b"alnum" => byte.is_ascii_alphanumeric(),
+        b"alpha" => byte.is_ascii_alphabetic(),
+        b"blank" => byte == b' ' || byte == b'\t',
+        b"cntrl" => byte.is_ascii_control(),
+        b"digit" => byte.is_ascii_digit(),
+        b"graph" => byte.is_ascii_graphic(),
+        b"lower" => byte.is_ascii_lowercase(),
+        b"print" => byte.is_ascii_graphic() || byte == b' ',
+        b"punct" => byte.is_ascii_punctuation(),
+        b"space" => byte.is_ascii_whitespace(),
+        b"upper" => byte.is_ascii_uppercase(),
+        b"xdigit" => byte.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+/// Splits an absolute glob `pattern` into its leading directory (the components without
+/// wildcards, unescaped), and the remaining components.
+fn split_pattern(pattern: &str, escape: bool) -> (String, Vec<&str>) {
+    let mut components = pattern
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .peekable();
+
+    let mut root = String::new();
+    while let Some(component) = components.next_if(|component| !has_magic(component, escape)) {
+        root.push('/');
+        root.push_str(&unescape(component, escape));
+    }
+
+    if root.is_empty() {
+        root.push('/');
+    }
+
+    (root, components.collect())
+}
+
+/// Options of a remote [`glob`], taken from the `glob` flags.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct GlobOptions {
+    /// `GLOB_ERR`, stop on the first directory that can't be read.
+    pub(crate) err: bool,
+    /// `GLOB_MARK`, append a `/` to directories.
+    pub(crate) mark: bool,
+    /// `GLOB_NOSORT`, return the paths in the order they were found.
+    pub(crate) nosort: bool,
+    /// `GLOB_NOCHECK`, return the pattern itself when nothing matches.
+    pub(crate) nocheck: bool,
+    /// `GLOB_NOMAGIC`, like `GLOB_NOCHECK`, but only for patterns without wildcards.
+    pub(crate) nomagic: bool,
+    /// Backslashes escape the next character, unless `GLOB_NOESCAPE` is set.
+    pub(crate) escape: bool,
+    /// `GLOB_PERIOD`, wildcards may match a leading `.`.
+    pub(crate) period: bool,
+    /// `GLOB_ONLYDIR`, match only directories.
+    pub(crate) only_dir: bool,
+}
+
+/// Flags from `glob.h` that are not in [`libc`].
+#[cfg(target_os = "linux")]
+pub(crate) mod glob_flags {
+    use libc::c_int;
+
+    pub(crate) const GLOB_PERIOD: c_int = 1 << 7;
+    pub(crate) const GLOB_MAGCHAR: c_int = 1 << 8;
+    pub(crate) const GLOB_NOMAGIC: c_int = 1 << 11;
+    pub(crate) const GLOB_ONLYDIR: c_int = 1 << 13;
+}
+
+/// Flags from `glob.h` that are not in [`libc`].
+#[cfg(target_os = "macos")]
+pub(crate) mod glob_flags {
+    use libc::c_int;
+
+    pub(crate) const GLOB_NOMAGIC: c_int = 0x0200;
+    pub(crate) const GLOB_QUOTE: c_int = 0x0400;
+}
+
+impl GlobOptions {
+    /// Flags that [`GlobOptions`] (and the `glob` hook) can handle. Anything else (like
+    /// `GLOB_BRACE` or `GLOB_ALTDIRFUNC`) makes us bypass the call.
+    const SUPPORTED_FLAGS: c_int = libc::GLOB_ERR
+        | libc::GLOB_MARK
+        | libc::GLOB_NOSORT
+        | libc::GLOB_DOOFFS
+        | libc::GLOB_NOCHECK
+        | libc::GLOB_APPEND
+        | libc::GLOB_NOESCAPE
+        | glob_flags::GLOB_NOMAGIC;
+
+    #[cfg(target_os = "linux")]
+    const PLATFORM_FLAGS: c_int = glob_flags::GLOB_PERIOD | glob_flags::GLOB_ONLYDIR;
+
+    // Backslash quoting is the default, so `GLOB_QUOTE` doesn't change anything.
+    #[cfg(target_os = "macos")]
+    const PLATFORM_FLAGS: c_int = glob_flags::GLOB_QUOTE;
+
+    /// Returns [`None`] when `flags` contains flags we don't support.
+    pub(crate) fn from_flags(flags: c_int) -> Option<Self> {
+        if flags & !(Self::SUPPORTED_FLAGS | Self::PLATFORM_FLAGS) != 0 {
+            return None;
+        }
+
+        Some(Self {
+            err: flags & libc::GLOB_ERR != 0,
+            mark: flags & libc::GLOB_MARK != 0,
+            nosort: flags & libc::GLOB_NOSORT != 0,
+            nocheck: flags & libc::GLOB_NOCHECK != 0,
+            nomagic: flags & glob_flags::GLOB_NOMAGIC != 0,
+            escape: flags & libc::GLOB_NOESCAPE == 0,
+            #[cfg(target_os = "linux")]
+            period: flags & glob_flags::GLOB_PERIOD != 0,
+            #[cfg(target_os = "linux")]
+            only_dir: flags & glob_flags::GLOB_ONLYDIR != 0,
+            #[cfg(target_os = "macos")]
+            period: false,
+            #[cfg(target_os = "macos")]
+            only_dir: false,
+        })
+    }
+
+    /// What `glob` returns when nothing matched `pattern`.
+    fn no_match(&self, pattern: &str) -> GlobOutcome {
+        if self.nocheck || (self.nomagic && !has_magic(pattern, self.escape)) {
+            GlobOutcome::Matched(vec![pattern.to_string()])
+        } else {
+            GlobOutcome::NoMatch
+        }
+    }
+
+    /// Checks whether the file `name` matches the `component` of the pattern.
+    fn matches_name(&self, component: &str, name: &str) -> bool {
+        // A leading `.` has to be matched explicitly.
+        let explicit_period =
+            component.starts_with('.') || (self.escape && component.starts_with("\\."));
+
+        if name.starts_with('.') && !explicit_period && !self.period {
+            return false;
+        }
+
+        wildcard_match(component.as_bytes(), name.as_bytes(), self.escape)
+    }
+}
+
+/// Result of a remote [`glob`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum GlobOutcome {
+    /// The matched paths, to be appended to `gl_pathv`.
+    Matched(Vec<String>),
+    /// `GLOB_NOMATCH`
+    NoMatch,
+    /// `GLOB_ABORTED`
+    Aborted,
+}
+
+/// A path that matched the pattern so far.
+struct GlobCandidate {
+    path: String,
+    /// Set when we already know whether the path is a directory.
+    is_dir: Option<bool>,
+    /// Whether we've seen the path in a directory listing (paths built from components without
+    /// wildcards are not checked).
+    exists: bool,
+}
+
+impl GlobCandidate {
+    fn is_dir(&mut self) -> bool {
+        let path = &self.path;
+
+        *self.is_dir.get_or_insert_with(|| {
+            remote_stat(Path::new(path), true).is_ok_and(|metadata| is_dir_mode(metadata.mode))
+        })
+    }
+}
+
+/// Expands the absolute glob `pattern` remotely.
+///
+/// `on_error` is called with the path and `errno` of every directory we fail to read, and
+/// returning `true` from it aborts the expansion (like the `errfunc` of `glob`).
+pub(crate) fn glob<E>(pattern: &str, options: &GlobOptions, mut on_error: E) -> Detour<GlobOutcome>
+where
+    E: FnMut(&str, c_int) -> bool,
+{
+    if !pattern.starts_with('/') {
+        return Detour::Bypass(Bypass::RelativePath(PathBuf::from(pattern)));
+    }
+
+    let (root, components) = split_pattern(pattern, options.escape);
+
+    match ensure_remote_root(Path::new(&root)) {
+        Detour::Error(HookError::FileNotFound) => {
+            return Detour::Success(options.no_match(pattern))
+        }
+        checked => checked?,
+    }
+
+    let mut candidates = vec![GlobCandidate {
+        path: root,
+        is_dir: None,
+        exists: false,
+    }];
+
+    for (index, component) in components.iter().enumerate() {
+        let mut next = Vec::new();
+
+        for base in &candidates {
+            if !has_magic(component, options.escape) {
+                next.push(GlobCandidate {
+                    path: join(&base.path, &unescape(component, options.escape)),
+                    is_dir: None,
+                    exists: false,
+                });
+                continue;
+            }
+
+            // The agent can do the simple wildcards for us, saving us some traffic.
+            let name_filter =
+                (!component.contains(['[', '\\']) && *component != "*").then_some(*component);
+
+            let entries = match remote_dir_entries(Path::new(&base.path), name_filter) {
+                Ok(entries) => entries,
+                Err(fail) => {
+                    let errno = errno_of(fail);
+                    if errno != ENOTDIR && (on_error(&base.path, errno) || options.err) {
+                        return Detour::Success(GlobOutcome::Aborted);
+                    }
+
+                    continue;
+                }
+            };
+
+            let dot_entries = component
+                .starts_with('.')
+                .then_some([(".", DT_DIR), ("..", DT_DIR)])
+                .into_iter()
+                .flatten();
+
+            let entries = entries
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.file_type))
+                .chain(dot_entries)
+                .filter(|(name, _)| options.matches_name(component, name));
+
+            for (name, file_type) in entries {
+                next.push(GlobCandidate {
+                    path: join(&base.path, name),
+                    is_dir: match file_type {
+                        DT_DIR => Some(true),
+                        DT_LNK | DT_UNKNOWN => None,
+                        _ => Some(false),
+                    },
+                    exists: true,
+                });
+            }
+        }
+
+        // Only directories can have more components matched inside them (listing a file fails
+        // anyway, but it's cheaper to check the entry types we already have).
+        if components
+            .get(index + 1)
+            .is_some_and(|next_component| has_magic(next_component, options.escape))
+        {
+            next.retain_mut(GlobCandidate::is_dir);
+        }
+
+        candidates = next;
+    }
+
+    let dirs_only = options.only_dir || pattern.ends_with('/');
+    let mut matches = Vec::with_capacity(candidates.len());
+
+    for mut candidate in candidates {
+        if !candidate.exists {
+            match remote_stat(Path::new(&candidate.path), false) {
+                Ok(metadata) if !is_symlink_mode(metadata.mode) => {
+                    candidate.is_dir = Some(is_dir_mode(metadata.mode))
+                }
+                Ok(_) => {}
+                Err(_) => continue,
+            }
+        }
+
+        if (dirs_only || options.mark) && candidate.is_dir() {
+            if !candidate.path.ends_with('/') {
+                candidate.path.push('/');
+            }
+        } else if dirs_only {
+            continue;
+        }
+
+        matches.push(candidate.path);
+    }
+
+    if matches.is_empty() {
+        return Detour::Success(options.no_match(pattern));
+    }
+
+    if !options.nosort {
+        matches.sort_unstable();
+    }
+
+    Detour::Success(GlobOutcome::Matched(matches))
+}
+
+/// What a [`WalkNode`] is, which decides how it's reported by the traversal API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NodeKind {
+    /// Anything that is not a directory or a symlink.
+    File,
+    /// A directory, before its children are visited.
+    Dir,
+    /// A directory we failed to list.
+    DirUnreadable,
+    /// A directory that is also one of its own ancestors (we got here following symlinks).
+    DirCycle,
+    /// A directory, after its children were visited.
+    DirPost,
+    /// A symlink that we did not follow.
+    Symlink,
+    /// A symlink that points to a file that doesn't exist.
+    DanglingSymlink,
+    /// A file we failed to stat.
+    StatFailed,
+}
+
+/// A file visited by the [`RemoteWalker`].
+#[derive(Debug, Clone)]
+pub(crate) struct WalkNode {
+    /// Full path of the file (starting with the root path).
+    pub(crate) path: String,
+    /// Offset of the file name in [`WalkNode::path`].
+    pub(crate) base: usize,
+    /// Depth of the file, the roots are at level 0.
+    pub(crate) level: usize,
+    pub(crate) kind: NodeKind,
+    /// Not available when the stat failed.
+    pub(crate) metadata: Option<MetadataInternal>,
+    /// `errno` of the failed stat or directory listing, `0` otherwise.
+    pub(crate) errno: c_int,
+}
+
+impl WalkNode {
+    /// Stats the remote `path`, falling back to the symlink itself when it can't be followed.
+    fn stat(path: String, base: usize, level: usize, follow_symlinks: bool) -> Self {
+        let (kind, metadata, errno) = match remote_stat(Path::new(&path), follow_symlinks) {
+            Ok(metadata) if is_dir_mode(metadata.mode) => (NodeKind::Dir, Some(metadata), 0),
+            Ok(metadata) if is_symlink_mode(metadata.mode) => {
+                (NodeKind::Symlink, Some(metadata), 0)
+            }
+            Ok(metadata) => (NodeKind::File, Some(metadata), 0),
+            Err(fail) => {
+                let errno = errno_of(fail);
+
+                match remote_stat(Path::new(&path), false) {
+                    Ok(metadata) if follow_symlinks && is_symlink_mode(metadata.mode) => {
+                        (NodeKind::DanglingSymlink, Some(metadata), 0)
+                    }
+                    _ => (NodeKind::StatFailed, None, errno),
+                }
+            }
+        };
+
+        Self {
+            path,
+            base,
+            level,
+            kind,
+            metadata,
+            errno,
+        }
+    }
+
+    /// Name of the file (the last component of [`WalkNode::path`]).
+    pub(crate) fn name(&self) -> &str {
+        self.path.get(self.base..).unwrap_or_default()
+    }
+
+    fn id(&self) -> Option<(u64, u64)> {
+        self.metadata
+            .as_ref()
+            .map(|metadata| (metadata.device_id, metadata.inode))
+    }
+}
+
+/// Ordering of the siblings in a [`RemoteWalker`] traversal.
+pub(crate) type WalkOrder = Box<dyn FnMut(&WalkNode, &WalkNode) -> Ordering + Send>;
+
+/// A directory we're in the middle of visiting.
+struct WalkFrame {
+    dir: WalkNode,
+    /// Children that were not visited yet.
+    children: VecDeque<WalkNode>,
+}
+
+/// Preorder (and postorder, see [`NodeKind::DirPost`]) traversal of remote file trees, shared by
+/// the `nftw` and `fts_*` hooks.
+///
+/// Every directory is listed (and its children are stat-ed) when the traversal enters it.
+pub(crate) struct RemoteWalker {
+    follow_symlinks: bool,
+    /// Don't descend into directories that are on another device than their root.
+    same_device: bool,
+    order: Option<WalkOrder>,
+    /// Roots that were not visited yet.
+    roots: VecDeque<WalkNode>,
+    /// Device of the root we're currently in.
+    root_device: Option<u64>,
+    /// The directories we're in, from the root down.
+    stack: Vec<WalkFrame>,
+    /// Node to visit (again) before anything else.
+    revisit: Option<WalkNode>,
+}
+
+impl RemoteWalker {
+    /// Prepares the traversal of `roots`, stat-ing them.
+    ///
+    /// `follow_roots` makes us follow the roots that are symlinks, even if `follow_symlinks` is
+    /// not set.
+    pub(crate) fn new(
+        roots: Vec<String>,
+        follow_symlinks: bool,
+        follow_roots: bool,
+        same_device: bool,
+        mut order: Option<WalkOrder>,
+    ) -> Self {
+        let mut roots: Vec<_> = roots
+            .into_iter()
+            .map(|root| {
+                let base = root
+                    .trim_end_matches('/')
+                    .rfind('/')
+                    .map_or(0, |slash| slash + 1);
+                WalkNode::stat(root, base, 0, follow_symlinks || follow_roots)
+            })
+            .collect();
+
+        if let Some(order) = order.as_mut() {
+            roots.sort_by(|a, b| order(a, b));
+        }
+
+        Self {
+            follow_symlinks,
+            same_device,
+            order,
+            roots: roots.into(),
+            root_device: None,
+            stack: Vec::new(),
+            revisit: None,
+        }
+    }
+
+    /// The roots that were not visited yet.
+    pub(crate) fn pending_roots(&self) -> impl Iterator<Item = &WalkNode> {
+        self.roots.iter()
+    }
+
+    /// The children of `dir` that were not visited yet, if we're currently in it.
+    pub(crate) fn pending_children(
+        &self,
+        dir: &WalkNode,
+    ) -> Option<impl Iterator<Item = &WalkNode>> {
+        self.stack
+            .last()
+            .filter(|frame| frame.dir.path == dir.path)
+            .map(|frame| frame.children.iter())
+    }
+
+    /// Returns the next file of the traversal.
+    pub(crate) fn next_node(&mut self) -> Option<WalkNode> {
+        if let Some(node) = self.revisit.take() {
+            return Some(self.enter(node));
+        }
+
+        if let Some(frame) = self.stack.last_mut() {
+            if let Some(child) = frame.children.pop_front() {
+                return Some(self.enter(child));
+            }
+
+            let frame = self.stack.pop()?;
+            return Some(WalkNode {
+                kind: NodeKind::DirPost,
+                ..frame.dir
+            });
+        }
+
+        let root = self.roots.pop_front()?;
+        self.root_device = root.metadata.as_ref().map(|metadata| metadata.device_id);
+
+        Some(self.enter(root))
+    }
+
+    /// Lists the children of `node` if it's a directory we should descend into.
+    fn enter(&mut self, mut node: WalkNode) -> WalkNode {
+        if node.kind != NodeKind::Dir {
+            return node;
+        }
+
+        let id = node.id();
+        if self.stack.iter().any(|frame| frame.dir.id() == id) {
+            node.kind = NodeKind::DirCycle;
+            return node;
+        }
+
+        let other_device = self.same_device
+            && node.metadata.as_ref().map(|metadata| metadata.device_id) != self.root_device;
+
+        let children = if other_device {
+            VecDeque::new()
+        } else {
+            match self.list_children(&node) {
+                Ok(children) => children,
+                Err(errno) => {
+                    node.kind = NodeKind::DirUnreadable;
+                    node.errno = errno;
+                    return node;
+                }
+            }
+        };
+
+        self.stack.push(WalkFrame {
+            dir: node.clone(),
+            children,
+        });
+
+        node
+    }
+
+    fn list_children(&mut self, dir: &WalkNode) -> Result<VecDeque<WalkNode>, c_int> {
+        let entries = remote_dir_entries(Path::new(&dir.path), None).map_err(errno_of)?;
+
+        let mut children: Vec<_> = entries
+            .into_iter()
+            .map(|entry| {
+                let path = join(&dir.path, &entry.name);
+                let base = path.len() - entry.name.len();
+                WalkNode::stat(path, base, dir.level + 1, self.follow_symlinks)
+            })
+            .collect();
+
+        if let Some(order) = self.order.as_mut() {
+            children.sort_by(|a, b| order(a, b));
+        }
+
+        Ok(children.into())
+    }
+
+    /// Don't visit the children of `dir` (if it's the directory we've just entered).
+    pub(crate) fn skip_children(&mut self, dir: &WalkNode) {
+        if let Some(frame) = self.stack.last_mut()
+            && frame.dir.path == dir.path
+        {
+            frame.children.clear();
+        }
+    }
+
+    /// Don't visit the remaining siblings of `node` (nor the children of `node`).
+    pub(crate) fn skip_siblings(&mut self, node: &WalkNode) {
+        if node.level == 0 {
+            self.roots.clear();
+        }
+
+        self.stack
+            .iter_mut()
+            .filter(|frame| frame.dir.level + 1 >= node.level)
+            .for_each(|frame| frame.children.clear());
+    }
+
+    /// Visits `node` again on the next call to [`RemoteWalker::next_node`], this time following it
+    /// if it's a symlink and `follow` is set.
+    pub(crate) fn revisit(&mut self, node: &WalkNode, follow: bool) {
+        if let Some(frame) = self.stack.last()
+            && frame.dir.path == node.path
+        {
+            self.stack.pop();
+        }
+
+        self.revisit = Some(WalkNode::stat(
+            node.path.clone(),
+            node.base,
+            node.level,
+            follow || self.follow_symlinks,
+        ));
+    }
+}
+
+/// Definitions from `ftw.h`, which are not in [`libc`].
+pub(crate) mod ftw {
+    use libc::c_int;
+
+    /// `struct FTW`, the last argument of the `nftw` callback.
+    #[repr(C)]
+    pub(crate) struct Ftw {
+        pub(crate) base: c_int,
+        pub(crate) level: c_int,
+    }
+
+    pub(crate) const FTW_F: c_int = 0;
+    pub(crate) const FTW_D: c_int = 1;
+    pub(crate) const FTW_DNR: c_int = 2;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_NS: c_int = 3;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_SL: c_int = 4;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_DP: c_int = 5;
+    #[cfg(target_os = "macos")]
+    pub(crate) const FTW_DP: c_int = 3;
+    #[cfg(target_os = "macos")]
+    pub(crate) const FTW_NS: c_int = 4;
+    #[cfg(target_os = "macos")]
+    pub(crate) const FTW_SL: c_int = 5;
+    pub(crate) const FTW_SLN: c_int = 6;
+
+    pub(crate) const FTW_PHYS: c_int = 1;
+    pub(crate) const FTW_MOUNT: c_int = 2;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_DEPTH: c_int = 8;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_ACTIONRETVAL: c_int = 16;
+    #[cfg(target_os = "macos")]
+    pub(crate) const FTW_DEPTH: c_int = 4;
+
+    /// Callback results with `FTW_ACTIONRETVAL`.
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_CONTINUE: c_int = 0;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_SKIP_SUBTREE: c_int = 2;
+    #[cfg(target_os = "linux")]
+    pub(crate) const FTW_SKIP_SIBLINGS: c_int = 3;
+
+    /// Flags we can handle remotely. `FTW_CHDIR` is not one of them, as we can't change the
+    /// working directory to a remote one.
+    #[cfg(target_os = "linux")]
+    pub(crate) const SUPPORTED_FLAGS: c_int = FTW_PHYS | FTW_MOUNT | FTW_DEPTH | FTW_ACTIONRETVAL;
+    #[cfg(target_os = "macos")]
+    pub(crate) const SUPPORTED_FLAGS: c_int = FTW_PHYS | FTW_MOUNT | FTW_DEPTH;
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("*", "file.txt", true)]
+    #[case("*.txt", "file.txt", true)]
+    #[case("*.txt", "file.txt.bak", false)]
+    #[case("f?le.*", "file.rs", true)]
+    #[case("*a*b*", "xaxxbx", true)]
+    #[case("*a*b", "xaxxbx", false)]
+    #[case("[abc]*", "bin", true)]
+    #[case("[!abc]*", "bin", false)]
+    #[case("[^a-c]*", "etc", true)]
+    #[case("[]x]", "]", true)]
+    #[case("[[:digit:]][[:alpha:]]", "1a", true)]
+    #[case("[[:digit:]]", "a", false)]
+    #[case("[unterminated", "[unterminated", true)]
+    #[case("\\*", "*", true)]
+    #[case("\\*", "a", false)]
+    #[case("", "", true)]
+    #[case("", "a", false)]
+    fn wildcards(#[case] pattern: &str, #[case] name: &str, #[case] expected: bool) {
+        assert_eq!(
+            wildcard_match(pattern.as_bytes(), name.as_bytes(), true),
+            expected
+        );
+    }
+
+    #[test]
+    fn no_escape() {
+        assert!(wildcard_match(b"a\\*", b"a\\b", false));
+        assert!(!wildcard_match(b"a\\*", b"a\\b", true));
+    }
+
+    #[test]
+    fn leading_period() {
+        let options = GlobOptions::from_flags(0).unwrap();
+        assert!(!options.matches_name("*", ".hidden"));
+        assert!(options.matches_name(".*", ".hidden"));
+        assert!(options.matches_name("\\.h*", ".hidden"));
+
+        let options = GlobOptions {
+            period: true,
+            ..options
+        };
+        assert!(options.matches_name("*", ".hidden"));
+    }
+
+    #[test]
+    fn pattern_split() {
+        assert_eq!(
+            split_pattern("/app/config/*.yaml", true),
+            ("/app/config".to_string(), vec!["*.yaml"])
+        );
+        assert_eq!(
+            split_pattern("//app/*/x\\?/file", true),
+            ("/app".to_string(), vec!["*", "x\\?", "file"])
+        );
+        assert_eq!(
+            split_pattern("/app/x\\?", true),
+            ("/app/x?".to_string(), vec![])
+        );
+        assert_eq!(split_pattern("/*", true), ("/".to_string(), vec!["*"]));
+    }
+
+    #[test]
+    fn no_match_result() {
+        let options = GlobOptions::from_flags(libc::GLOB_NOCHECK).unwrap();
+        assert_eq!(
+            options.no_match("/a/*"),
+            GlobOutcome::Matched(vec!["/a/*".to_string()])
+        );
+
+        let options = GlobOptions::from_flags(glob_flags::GLOB_NOMAGIC).unwrap();
+        assert_eq!(options.no_match("/a/*"), GlobOutcome::NoMatch);
+        assert_eq!(
+            options.no_match("/a/b"),
+            GlobOutcome::Matched(vec!["/a/b".to_string()])
+        );
+    }
+
+    #[test]
+    fn unsupported_flags() {
+        // `GLOB_BRACE` on linux, `GLOB_ALTDIRFUNC` on macOS.
+        #[cfg(target_os = "linux")]
+        let unsupported = 1 << 10;
+        #[cfg(target_os = "macos")]
+        let unsupported = 0x0040;
+
+        assert!(GlobOptions::from_flags(unsupported | libc::GLOB_MARK).is_none());
+    }
+}
@@ -2,7 +2,10 @@ use std::arch::asm;
 
 use tracing::trace;
 
-use crate::{go::c_abi_syscall6_handler, macros::hook_symbol, HookManager};
+use crate::{
+    go::{c_abi_syscall6_handler, go_version, hook_runtime_syscall6},
+    HookManager,
+};
 
 type VoidFn = unsafe extern "C" fn() -> ();
 static mut FN_ASMCGOCALL: Option<VoidFn> = None;
@@ -81,7 +84,7 @@ unsafe extern "C" fn go_syscall_new_detour() {
 }
 
 /// Hooks for when hooking a post go 1.19 binary
-fn post_go1_19(hook_manager: &mut HookManager) {
+fn post_go1_19(hook_manager: &mut HookManager, version: (u32, u32)) {
     unsafe {
         FN_ASMCGOCALL = std::mem::transmute::<
             frida_gum::NativePointer,
@@ -92,10 +95,11 @@ fn post_go1_19(hook_manager: &mut HookManager) {
                 .expect("found go but couldn't find runtime.asmcgocall please file a bug"),
         );
     }
-    hook_symbol!(
+    hook_runtime_syscall6(
         hook_manager,
-        "runtime/internal/syscall.Syscall6.abi0",
-        go_syscall_new_detour
+        version,
+        ".abi0",
+        go_syscall_new_detour as *mut libc::c_void,
     );
 }
 
@@ -105,20 +109,10 @@ fn post_go1_19(hook_manager: &mut HookManager) {
 ///   - File zsyscall_linux_amd64.go generated using mksyscall.pl.
 ///   - <https://cs.opensource.google/go/go/+/refs/tags/go1.18.5:src/syscall/syscall_unix.go>
 pub(crate) fn enable_hooks(hook_manager: &mut HookManager) {
-    if let Some(version_symbol) =
-        hook_manager.resolve_symbol_main_module("runtime.buildVersion.str")
-    {
-        // Version str is `go1.xx` - take only last 4 characters.
-        let version = unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                version_symbol.0.add(2) as *const u8,
-                4,
-            ))
-        };
-        let version_parsed: f32 = version.parse().unwrap();
-        if version_parsed >= 1.19 {
-            trace!("found version >= 1.19");
-            post_go1_19(hook_manager);
+    if let Some(version) = go_version(hook_manager) {
+        if version >= (1, 19) {
+            trace!(?version, "found version >= 1.19");
+            post_go1_19(hook_manager, version);
         } else {
             trace!("found version < 1.19, arm64 not supported - not hooking");
         }
@@ -4,7 +4,12 @@ use errno::errno;
 use tracing::trace;
 
 use crate::{
-    close_detour, file::hooks::*, hooks::HookManager, macros::hook_symbol, socket::hooks::*,
+    close_detour,
+    file::hooks::*,
+    go::{go_version, hook_runtime_syscall6},
+    hooks::HookManager,
+    macros::hook_symbol,
+    socket::hooks::*,
 };
 /*
  * Reference for which syscalls are managed by the handlers:
@@ -12,6 +17,7 @@ use crate::{
  * SYS_read, SYS_write, SYS_lseek, SYS_faccessat: Syscall
  *
  * SYS_socket, SYS_bind, SYS_listen, SYS_accept, SYS_close: Syscall
+ * SYS_dup, SYS_dup2, SYS_dup3, SYS_fcntl (F_DUPFD, F_DUPFD_CLOEXEC): Syscall
  * SYS_accept4: Syscall6
  *
  * SYS_getdents64: Syscall on go 1.18, Syscall6 on go 1.19.
@@ -326,6 +332,15 @@ unsafe extern "C" fn c_abi_syscall_handler(
         libc::SYS_connect => connect_detour(param1 as _, param2 as _, param3 as _) as i64,
         libc::SYS_accept => accept_detour(param1 as _, param2 as _, param3 as _) as i64,
         libc::SYS_close => close_detour(param1 as _) as i64,
+        libc::SYS_dup => dup_detour(param1 as _) as i64,
+        libc::SYS_dup2 => dup2_detour(param1 as _, param2 as _) as i64,
+        libc::SYS_dup3 => dup3_detour(param1 as _, param2 as _, param3 as _) as i64,
+        // `os.File` and `net` dup their fds with `F_DUPFD_CLOEXEC`.
+        libc::SYS_fcntl
+            if matches!(param2 as libc::c_int, libc::F_DUPFD | libc::F_DUPFD_CLOEXEC) =>
+        {
+            fcntl_detour(param1 as _, param2 as _, param3 as usize) as i64
+        }
 
         _ if crate::setup().fs_config().is_active() => match syscall {
             libc::SYS_read => read_detour(param1 as _, param2 as _, param3 as _) as i64,
@@ -507,11 +522,12 @@ fn pre_go1_19(hook_manager: &mut HookManager) {
 }
 
 /// Hooks for when hooking a post go 1.19 binary
-fn post_go1_19(hook_manager: &mut HookManager) {
-    hook_symbol!(
+fn post_go1_19(hook_manager: &mut HookManager, version: (u32, u32)) {
+    hook_runtime_syscall6(
         hook_manager,
-        "runtime/internal/syscall.Syscall6",
-        go_syscall_new_detour
+        version,
+        "",
+        go_syscall_new_detour as *mut libc::c_void,
     );
 }
 
@@ -521,20 +537,10 @@ fn post_go1_19(hook_manager: &mut HookManager) {
 ///   - File zsyscall_linux_amd64.go generated using mksyscall.pl.
 ///   - <https://cs.opensource.google/go/go/+/refs/tags/go1.18.5:src/syscall/syscall_unix.go>
 pub(crate) fn enable_hooks(hook_manager: &mut HookManager) {
-    if let Some(version_symbol) =
-        hook_manager.resolve_symbol_main_module("runtime.buildVersion.str")
-    {
-        // Version str is `go1.xx` - take only last 4 characters.
-        let version = unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                version_symbol.0.add(2) as *const u8,
-                4,
-            ))
-        };
-        let version_parsed: f32 = version.parse().unwrap();
-        if version_parsed >= 1.19 {
-            trace!("found version >= 1.19");
-            post_go1_19(hook_manager);
+    if let Some(version) = go_version(hook_manager) {
+        if version >= (1, 19) {
+            trace!(?version, "found version >= 1.19");
+            post_go1_19(hook_manager, version);
         } else {
             trace!("found version < 1.19");
             pre_go1_19(hook_manager);
@@ -3,9 +3,9 @@
     target_os = "linux"
 ))]
 use errno::errno;
-use tracing::trace;
+use tracing::{trace, warn};
 
-use crate::{close_detour, file::hooks::*, socket::hooks::*};
+use crate::{close_detour, file::hooks::*, hooks::HookManager, socket::hooks::*};
 
 #[cfg_attr(
     all(target_os = "linux", target_arch = "x86_64"),
@@ -17,6 +17,52 @@ use crate::{close_detour, file::hooks::*, socket::hooks::*};
 )]
 pub(crate) mod go_hooks;
 
+/// `(major, minor)` version of the Go runtime in the main module, e.g. `(1, 23)` for `go1.23.4`.
+pub(crate) fn go_version(hook_manager: &HookManager) -> Option<(u32, u32)> {
+    let version_symbol = hook_manager.resolve_symbol_main_module("runtime.buildVersion.str")?;
+    // Version str is `go1.xx` - take only the 4 characters after `go`.
+    let version = unsafe { std::slice::from_raw_parts(version_symbol.0.add(2) as *const u8, 4) };
+
+    parse_go_version(version)
+}
+
+/// Parses `1.xx` from `runtime.buildVersion`, ignoring what follows the minor version.
+fn parse_go_version(version: &[u8]) -> Option<(u32, u32)> {
+    let (major, minor) = std::str::from_utf8(version).ok()?.split_once('.')?;
+    let minor = minor.split(|c: char| !c.is_ascii_digit()).next()?;
+
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Hooks `Syscall6` of the runtime's raw syscall package, which Go >= 1.19 uses for (almost) all
+/// syscalls. The package moved from `runtime/internal/syscall` to `internal/runtime/syscall` in
+/// Go 1.23.
+///
+/// `suffix` is the ABI suffix of the hooked symbol on this architecture. Warns when the symbol is
+/// missing, since files and sockets of the binary would silently bypass mirrord.
+pub(crate) fn hook_runtime_syscall6(
+    hook_manager: &mut HookManager,
+    version: (u32, u32),
+    suffix: &str,
+    detour: *mut libc::c_void,
+) {
+    let package = if version >= (1, 23) {
+        "internal/runtime/syscall"
+    } else {
+        "runtime/internal/syscall"
+    };
+    let symbol = format!("{package}.Syscall6{suffix}");
+
+    match hook_manager.hook_symbol_main_module(&symbol, detour) {
+        Ok(_) => trace!("hooked {symbol:?} in main module"),
+        Err(err) => warn!(
+            "hook {symbol:?} in main module failed with err {err:?}, syscalls of this Go {}.{} \
+            binary are not intercepted",
+            version.0, version.1
+        ),
+    }
+}
+
 /// Syscall & Syscall6 handler - supports upto 6 params, mainly used for
 /// accept4 Note: Depending on success/failure Syscall may or may not call this handler
 #[no_mangle]
@@ -42,6 +88,16 @@ unsafe extern "C" fn c_abi_syscall6_handler(
         libc::SYS_listen => listen_detour(param1 as _, param2 as _) as i64,
         libc::SYS_accept => accept_detour(param1 as _, param2 as _, param3 as _) as i64,
         libc::SYS_close => close_detour(param1 as _) as i64,
+        libc::SYS_dup => dup_detour(param1 as _) as i64,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_dup2 => dup2_detour(param1 as _, param2 as _) as i64,
+        libc::SYS_dup3 => dup3_detour(param1 as _, param2 as _, param3 as _) as i64,
+        // `os.File` and `net` dup their fds with `F_DUPFD_CLOEXEC`.
+        libc::SYS_fcntl
+            if matches!(param2 as libc::c_int, libc::F_DUPFD | libc::F_DUPFD_CLOEXEC) =>
+        {
+            fcntl_detour(param1 as _, param2 as _, param3 as usize) as i64
+        }
         libc::SYS_connect => connect_detour(param1 as _, param2 as _, param3 as _) as i64,
 
         _ if crate::setup().fs_config().is_active() => {
@@ -156,3 +212,16 @@ unsafe extern "C" fn c_abi_syscall6_handler(
         syscall_result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn go_versions() {
+        assert_eq!(parse_go_version(b"1.19"), Some((1, 19)));
+        assert_eq!(parse_go_version(b"1.23"), Some((1, 23)));
+        assert_eq!(parse_go_version(b"1.9."), Some((1, 9)));
+        assert_eq!(parse_go_version(b"devel"), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! Returns the user and group IDs of the target from `getuid` and friends, with
+//! `feature.identity`.
+//!
+//! Programs that check whether they run as root, or compare their uid with the owner of files,
+//! behave differently locally than in the target pod. The identity is read from
+//! `/proc/1/status` in the target container, that is of the process that the container runs,
+//! which reflects its `securityContext`.
+
+use std::{path::PathBuf, sync::OnceLock};
+
+use errno::{set_errno, Errno};
+use libc::{c_int, gid_t, uid_t, EINVAL};
+use mirrord_layer_macro::hook_guard_fn;
+use mirrord_protocol::file::{OpenFileResponse, OpenOptionsInternal, ReadFileResponse};
+use tracing::{trace, warn};
+
+use crate::{detour::Detour, file::ops::RemoteFile, hooks::HookManager, replace};
+
+/// Identity of the target, fetched on first use with [`identity`].
+///
+/// [`None`] when it couldn't be fetched, in which case the hooks return the local identity.
+static IDENTITY: OnceLock<Option<Identity>> = OnceLock::new();
+
+/// User and group IDs of a process, as listed in its `/proc/[pid]/status`.
+#[derive(Debug, PartialEq, Eq)]
+struct Identity {
+    uid: uid_t,
+    euid: uid_t,
+    gid: gid_t,
+    egid: gid_t,
+    groups: Vec<gid_t>,
+}
+
+impl Identity {
+    /// Parses the `Uid:`, `Gid:` and `Groups:` lines of a `/proc/[pid]/status` file.
+    fn from_status(status: &str) -> Option<Self> {
+        let field = |name: &str| {
+            status.lines().find_map(|line| {
+                line.strip_prefix(name)?
+                    .strip_prefix(':')
+                    .map(|values| values.split_whitespace().map(str::parse::<u32>))
+            })
+        };
+
+        let mut uids = field("Uid")?;
+        let mut gids = field("Gid")?;
+
+        Some(Self {
+            uid: uids.next()?.ok()?,
+            euid: uids.next()?.ok()?,
+            gid: gids.next()?.ok()?,
+            egid: gids.next()?.ok()?,
+            groups: field("Groups")?.collect::<Result<_, _>>().ok()?,
+        })
+    }
+}
+
+/// Reads the identity of the target from `/proc/1/status` in the target container.
+fn remote_identity() -> Detour<Option<Identity>> {
+    let OpenFileResponse { fd } = RemoteFile::remote_open(
+        PathBuf::from("/proc/1/status"),
+        OpenOptionsInternal {
+            read: true,
+            ..Default::default()
+        },
+    )?;
+
+    let ReadFileResponse { bytes, read_amount } = RemoteFile::remote_read(fd, 4096)?;
+
+    let _ = RemoteFile::remote_close(fd).inspect_err(|fail| {
+        trace!("Leaking remote file fd (should be harmless) due to {fail:#?}!")
+    });
+
+    let status = String::from_utf8_lossy(bytes.get(..read_amount as usize).unwrap_or(&bytes));
+    Detour::Success(Identity::from_status(&status))
+}
+
+/// The identity of the target, or [`None`] when it couldn't be fetched.
+fn identity() -> Option<&'static Identity> {
+    IDENTITY
+        .get_or_init(|| {
+            let identity = match remote_identity() {
+                Detour::Success(identity) => identity,
+                Detour::Bypass(_) => None,
+                Detour::Error(error) => {
+                    warn!(%error, "failed to fetch the identity of the target");
+                    None
+                }
+            };
+
+            if identity.is_none() {
+                eprintln!(
+                    "mirrord: WARNING: couldn't get the user and group IDs of the target, \
+                    the local ones are used instead."
+                );
+            }
+
+            identity
+        })
+        .as_ref()
+}
+
+pub(crate) unsafe fn enable_identity_hooks(hook_manager: &mut HookManager) {
+    replace!(hook_manager, "getuid", getuid_detour, FnGetuid, FN_GETUID);
+    replace!(
+        hook_manager,
+        "geteuid",
+        geteuid_detour,
+        FnGeteuid,
+        FN_GETEUID
+    );
+    replace!(hook_manager, "getgid", getgid_detour, FnGetgid, FN_GETGID);
+    replace!(
+        hook_manager,
+        "getegid",
+        getegid_detour,
+        FnGetegid,
+        FN_GETEGID
+    );
+    replace!(
+        hook_manager,
+        "getgroups",
+        getgroups_detour,
+        FnGetgroups,
+        FN_GETGROUPS
+    );
+}
+
+/// Hook for `libc::getuid`.
+#[hook_guard_fn]
+unsafe extern "C" fn getuid_detour() -> uid_t {
+    identity()
+        .map(|identity| identity.uid)
+        .unwrap_or_else(|| FN_GETUID())
+}
+
+/// Hook for `libc::geteuid`.
+#[hook_guard_fn]
+unsafe extern "C" fn geteuid_detour() -> uid_t {
+    identity()
+        .map(|identity| identity.euid)
+        .unwrap_or_else(|| FN_GETEUID())
+}
+
+/// Hook for `libc::getgid`.
+#[hook_guard_fn]
+unsafe extern "C" fn getgid_detour() -> gid_t {
+    identity()
+        .map(|identity| identity.gid)
+        .unwrap_or_else(|| FN_GETGID())
+}
+
+/// Hook for `libc::getegid`.
+#[hook_guard_fn]
+unsafe extern "C" fn getegid_detour() -> gid_t {
+    identity()
+        .map(|identity| identity.egid)
+        .unwrap_or_else(|| FN_GETEGID())
+}
+
+/// Hook for `libc::getgroups`.
+///
+/// Like the original, returns the number of groups when `size` is 0, and fails with `EINVAL`
+/// when `list` can't hold all of them.
+#[hook_guard_fn]
+unsafe extern "C" fn getgroups_detour(size: c_int, list: *mut gid_t) -> c_int {
+    let Some(identity) = identity() else {
+        return FN_GETGROUPS(size, list);
+    };
+
+    let count = identity.groups.len();
+    if size == 0 {
+        return count as c_int;
+    }
+
+    if size < 0 || (size as usize) < count {
+        set_errno(Errno(EINVAL));
+        return -1;
+    }
+
+    list.copy_from_nonoverlapping(identity.groups.as_ptr(), count);
+    count as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_from_status() {
+        let status = "Name:\tnode\nUmask:\t0022\nState:\tS (sleeping)\nTgid:\t1\nPid:\t1\n\
+            Uid:\t1000\t1001\t1001\t1001\nGid:\t2000\t2001\t2001\t2001\nFDSize:\t64\n\
+            Groups:\t2000 3000 \nNStgid:\t1\n";
+
+        assert_eq!(
+            Identity::from_status(status),
+            Some(Identity {
+                uid: 1000,
+                euid: 1001,
+                gid: 2000,
+                egid: 2001,
+                groups: vec![2000, 3000],
+            })
+        );
+    }
+
+    #[test]
+    fn identity_without_groups() {
+        let status = "Uid:\t0\t0\t0\t0\nGid:\t0\t0\t0\t0\nGroups:\t\n";
+
+        assert_eq!(
+            Identity::from_status(status).map(|identity| identity.groups),
+            Some(vec![])
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Keeps the process running when the layer fails internally, with `on_layer_error: "degrade"`.
+//!
+//! Internal errors are failures of the layer itself, like a poisoned lock, an error in the protocol
+//! with the internal proxy, or a panic in a hook. Instead of taking the process down with it, the
+//! layer stops talking to the internal proxy for the rest of the process, and the hooks bypass to
+//! the local functions for new files, connections and DNS queries.
+//!
+//! The hooks themselves stay installed: files, directories and sockets that were handled remotely
+//! can't be bypassed, the local functions don't know them. Operations on them fail with `EIO`, see
+//! [`mark_remote_handle`](crate::detour::mark_remote_handle).
+//!
+//! Errors that mean the agent was lost are handled by [`agent_loss`](crate::agent_loss) first.
+
+use std::{
+    any::Any,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use mirrord_config::OnLayerError;
+
+use crate::{error::HookError, proxy_connection::ProxyError, SETUP};
+
+/// Set when the layer failed internally and doesn't talk to the internal proxy anymore.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the layer failed internally and doesn't talk to the internal proxy anymore.
+pub(crate) fn is_disabled() -> bool {
+    DISABLED.load(Ordering::Relaxed)
+}
+
+/// Called on an internal `error` of the layer.
+///
+/// Returns whether the layer disables itself (with `on_layer_error: "degrade"`), reporting the
+/// error to the user the first time.
+pub(crate) fn disable(error: &dyn fmt::Display) -> bool {
+    let on_layer_error = SETUP
+        .get()
+        .map(|setup| setup.on_layer_error())
+        .unwrap_or_default();
+    if on_layer_error != OnLayerError::Degrade {
+        return false;
+    }
+
+    if !DISABLED.swap(true, Ordering::Relaxed) {
+        // Logged as an error, so that it's shown with the default log level.
+        tracing::error!(
+            %error,
+            "mirrord failed internally. The process keeps running, but new files are opened \
+            locally, and outgoing connections and DNS queries go local. Remote files, directories \
+            and sockets that are still open fail with EIO. You may report it to us on \
+            https://github.com/metalbear-co/mirrord/issues"
+        );
+    }
+
+    true
+}
+
+/// Whether the operation that failed with the given `error` should be done locally, because the
+/// error is internal and the layer disabled itself.
+pub(crate) fn bypasses(error: &HookError) -> bool {
+    match error {
+        HookError::LayerDisabled => true,
+        HookError::ProxyError(ProxyError::Interrupted | ProxyError::Signaled) => false,
+        HookError::ProxyError(_) | HookError::LockError => disable(error),
+        _ => false,
+    }
+}
+
+/// Called when a hook panicked, with the `payload` of the panic.
+///
+/// Returns if the layer disables itself, so that the hook can run again without the internal proxy.
+/// Otherwise the panic continues.
+pub(crate) fn on_panic(payload: Box<dyn Any + Send>) {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panic in a hook");
+
+    if !disable(&message) {
+        std::panic::resume_unwind(payload);
+    }
+}
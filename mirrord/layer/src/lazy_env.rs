@@ -0,0 +1,271 @@
+//! Resolves the variables that are missing from the local environment from the remote one, on
+//! `getenv`, with `feature.env.lazy`.
+//!
+//! The remote environment is copied into the process when it starts, but some programs look up
+//! variables whose names are computed at runtime, or re-read the environment long after it
+//! started (e.g. after it was cleared). When `getenv` doesn't find a variable, we fetch the remote
+//! environment from the agent (once it's fetched, it's cached) and return the variable from it.
+//!
+//! The variables the process removes with `unsetenv` are not looked up, they stay gone. When the
+//! remote environment can't be fetched, we wait before trying again, longer after each failure,
+//! so that a missing agent doesn't slow down every `getenv`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CStr, CString},
+    ptr,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use libc::{c_char, c_int};
+use mirrord_config::util::VecOrSingle;
+use mirrord_layer_macro::hook_guard_fn;
+use tracing::warn;
+
+use crate::{
+    common::make_proxy_request_with_response, env_vars_request, hooks::HookManager, replace, setup,
+};
+
+/// The remote environment, fetched on the first missing variable with [`remote_env`].
+///
+/// The values are never removed, so that the pointers returned from `getenv` stay valid.
+static REMOTE_ENV: OnceLock<HashMap<String, CString>> = OnceLock::new();
+
+/// Names of the variables the process removed with `unsetenv`.
+static UNSET_VARS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// When we try to fetch the [`REMOTE_ENV`] again, after it failed.
+static FETCH_BACKOFF: Mutex<Backoff> = Mutex::new(Backoff::new());
+
+/// Delays between the attempts to fetch the remote environment, doubled after each failure.
+struct Backoff {
+    /// [`None`] until an attempt fails.
+    next_attempt: Option<Instant>,
+    delay: Duration,
+}
+
+impl Backoff {
+    const FIRST_DELAY: Duration = Duration::from_secs(1);
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    const fn new() -> Self {
+        Self {
+            next_attempt: None,
+            delay: Self::FIRST_DELAY,
+        }
+    }
+
+    fn ready(&self, now: Instant) -> bool {
+        self.next_attempt
+            .map_or(true, |next_attempt| now >= next_attempt)
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.next_attempt = Some(now + self.delay);
+        self.delay = (self.delay * 2).min(Self::MAX_DELAY);
+    }
+}
+
+/// The remote environment, [`None`] when it couldn't be fetched (it's fetched again on a missing
+/// variable after the [`FETCH_BACKOFF`]).
+///
+/// Like the environment copied into the process when it starts, includes
+/// [`override`](mirrord_config::feature::env::EnvConfig::override) and doesn't include the
+/// variables in [`unset`](mirrord_config::feature::env::EnvConfig::unset), that the user wants
+/// gone.
+fn remote_env() -> Option<&'static HashMap<String, CString>> {
+    if let Some(remote_env) = REMOTE_ENV.get() {
+        return Some(remote_env);
+    }
+
+    if !FETCH_BACKOFF.lock().ok()?.ready(Instant::now()) {
+        return None;
+    }
+
+    let fetched = match env_vars_request().map(make_proxy_request_with_response) {
+        None => Ok(Default::default()),
+        Some(Ok(Ok(remote_env))) => Ok(remote_env),
+        Some(Ok(Err(error))) => Err(error.to_string()),
+        Some(Err(error)) => Err(error.to_string()),
+    };
+    let remote_env = match fetched {
+        Ok(remote_env) => remote_env,
+        Err(error) => {
+            warn!(%error, "failed to fetch the remote environment");
+            if let Ok(mut backoff) = FETCH_BACKOFF.lock() {
+                backoff.failed(Instant::now());
+            }
+            return None;
+        }
+    };
+
+    let env_config = setup().env_config();
+    let remote_env = resolve(
+        remote_env,
+        env_config.r#override.as_ref(),
+        env_config.unset.as_ref(),
+    );
+
+    // Another thread may have fetched it in the meantime, we keep the first one.
+    Some(REMOTE_ENV.get_or_init(|| remote_env))
+}
+
+/// Applies `overrides` and `unset` to the fetched `remote_env`.
+fn resolve(
+    mut remote_env: HashMap<String, String>,
+    overrides: Option<&HashMap<String, String>>,
+    unset: Option<&VecOrSingle<String>>,
+) -> HashMap<String, CString> {
+    if let Some(overrides) = overrides {
+        remote_env.extend(overrides.clone());
+    }
+
+    remote_env
+        .into_iter()
+        .filter(|(name, _)| {
+            !unset.is_some_and(|unset| unset.as_slice().iter().any(|var| var == name))
+        })
+        .filter_map(|(name, value)| Some((name, CString::new(value).ok()?)))
+        .collect()
+}
+
+/// Looks up the variable `name` in the remote environment, returns a null pointer when it's not
+/// there.
+unsafe fn remote_getenv(name: *const c_char) -> *mut c_char {
+    if name.is_null() {
+        return ptr::null_mut();
+    }
+
+    let Ok(name) = CStr::from_ptr(name).to_str() else {
+        return ptr::null_mut();
+    };
+
+    if UNSET_VARS
+        .lock()
+        .map_or(true, |unset_vars| unset_vars.contains(name))
+    {
+        return ptr::null_mut();
+    }
+
+    remote_env()
+        .and_then(|remote_env| remote_env.get(name))
+        .map_or(ptr::null_mut(), |value| value.as_ptr().cast_mut())
+}
+
+pub(crate) unsafe fn enable_lazy_env_hooks(hook_manager: &mut HookManager) {
+    replace!(hook_manager, "getenv", getenv_detour, FnGetenv, FN_GETENV);
+    replace!(
+        hook_manager,
+        "unsetenv",
+        unsetenv_detour,
+        FnUnsetenv,
+        FN_UNSETENV
+    );
+
+    #[cfg(target_os = "linux")]
+    replace!(
+        hook_manager,
+        "secure_getenv",
+        secure_getenv_detour,
+        FnSecure_getenv,
+        FN_SECURE_GETENV
+    );
+}
+
+/// Hook for `libc::getenv`, that looks up the variables that are missing locally in the remote
+/// environment.
+#[hook_guard_fn]
+unsafe extern "C" fn getenv_detour(name: *const c_char) -> *mut c_char {
+    let value = FN_GETENV(name);
+    if value.is_null() {
+        remote_getenv(name)
+    } else {
+        value
+    }
+}
+
+/// Hook for `libc::secure_getenv`, see [`getenv_detour`].
+///
+/// In secure-execution mode (e.g. setuid programs), where the original returns a null pointer,
+/// nothing is looked up.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+unsafe extern "C" fn secure_getenv_detour(name: *const c_char) -> *mut c_char {
+    let value = FN_SECURE_GETENV(name);
+    if value.is_null() && libc::getauxval(libc::AT_SECURE) == 0 {
+        remote_getenv(name)
+    } else {
+        value
+    }
+}
+
+/// Hook for `libc::unsetenv`, so that the variables the process removes are not looked up in the
+/// remote environment anymore.
+#[hook_guard_fn]
+unsafe extern "C" fn unsetenv_detour(name: *const c_char) -> c_int {
+    let result = FN_UNSETENV(name);
+
+    if result == 0 {
+        if let (Ok(name), Ok(mut unset_vars)) = (CStr::from_ptr(name).to_str(), UNSET_VARS.lock()) {
+            unset_vars.insert(name.to_string());
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolves_overrides_and_unset() {
+        let remote_env = HashMap::from([
+            ("REGION".to_string(), "1".to_string()),
+            ("AWS_PROFILE".to_string(), "prod".to_string()),
+            ("INVALID".to_string(), "nul\0byte".to_string()),
+        ]);
+        let overrides = HashMap::from([
+            ("REGION".to_string(), "2".to_string()),
+            ("LOCAL_ONLY".to_string(), "yes".to_string()),
+        ]);
+        let unset = VecOrSingle::Single("AWS_PROFILE".to_string());
+
+        let resolved = resolve(remote_env, Some(&overrides), Some(&unset));
+
+        assert_eq!(
+            resolved,
+            HashMap::from([
+                ("REGION".to_string(), CString::new("2").unwrap()),
+                ("LOCAL_ONLY".to_string(), CString::new("yes").unwrap()),
+            ])
+        );
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let mut backoff = Backoff::new();
+        let now = Instant::now();
+        assert!(backoff.ready(now));
+
+        backoff.failed(now);
+        assert!(!backoff.ready(now));
+        assert!(backoff.ready(now + Duration::from_secs(1)));
+
+        backoff.failed(now);
+        assert!(!backoff.ready(now + Duration::from_secs(1)));
+        assert!(backoff.ready(now + Duration::from_secs(2)));
+
+        (0..10).for_each(|_| backoff.failed(now));
+        assert!(backoff.ready(now + Backoff::MAX_DELAY));
+    }
+
+    #[test]
+    fn unset_wins_over_override() {
+        let overrides = HashMap::from([("AWS_PROFILE".to_string(), "dev".to_string())]);
+        let unset = VecOrSingle::Multiple(vec!["AWS_PROFILE".to_string()]);
+
+        assert!(resolve(Default::default(), Some(&overrides), Some(&unset)).is_empty());
+    }
+}
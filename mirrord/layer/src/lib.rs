@@ -68,7 +68,6 @@ extern crate core;
 use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
-    net::SocketAddr,
     panic,
     sync::OnceLock,
     time::Duration,
@@ -89,7 +88,7 @@ use mirrord_config::{
 use mirrord_intproxy_protocol::NewSessionRequest;
 use mirrord_layer_macro::{hook_fn, hook_guard_fn};
 use mirrord_protocol::{EnvVars, GetEnvVarsRequest};
-use proxy_connection::ProxyConnection;
+use proxy_connection::{ProxyAddress, ProxyConnection};
 use setup::LayerSetup;
 use socket::SOCKETS;
 use tracing_subscriber::{fmt::format::FmtSpan, prelude::*};
@@ -99,6 +98,8 @@ use crate::{
     load::LoadType,
 };
 
+mod agent_loss;
+mod child_env;
 mod common;
 mod debugger_ports;
 mod detour;
@@ -107,9 +108,13 @@ mod error;
 mod exec_utils;
 mod file;
 mod hooks;
+mod identity;
+mod layer_error;
+mod lazy_env;
 mod load;
 mod macros;
 mod proxy_connection;
+mod remote_exec;
 mod setup;
 mod socket;
 
@@ -202,6 +207,7 @@ fn layer_pre_initialization() -> Result<(), LayerError> {
         #[cfg(target_os = "macos")]
         LoadType::SIPOnly => sip_only_layer_start(config, patch_binaries),
         LoadType::Skip => load_only_layer_start(&config),
+        LoadType::RemoteExec => remote_exec_layer_start(&config),
     }
 
     Ok(())
@@ -219,15 +225,8 @@ fn load_only_layer_start(config: &LayerConfig) {
         return;
     }
 
-    let address: SocketAddr = config
-        .connect_tcp
-        .as_ref()
-        .expect("missing internal proxy address")
-        .parse()
-        .expect("failed to parse internal proxy address");
-
     let new_connection = ProxyConnection::new(
-        address,
+        ProxyAddress::from_config(config),
         NewSessionRequest::New(
             EXECUTABLE_ARGS
                 .get()
@@ -247,6 +246,21 @@ fn load_only_layer_start(config: &LayerConfig) {
     }
 }
 
+/// Runs the process in the target container, see [`remote_exec`]. Never returns, unless in trace
+/// only mode, where the process runs locally.
+fn remote_exec_layer_start(config: &LayerConfig) {
+    load_only_layer_start(config);
+
+    if PROXY_CONNECTION.get().is_some() {
+        remote_exec::run(
+            &EXECUTABLE_ARGS
+                .get()
+                .expect("EXECUTABLE_ARGS MUST BE SET")
+                .args,
+        );
+    }
+}
+
 /// The one true start of mirrord-layer.
 ///
 /// Calls [`layer_pre_initialization`], which runs mirrord-layer.
@@ -285,11 +299,26 @@ fn init_tracing() {
                     .compact()
                     .with_writer(std::io::stderr),
             )
-            .with(tracing_subscriber::EnvFilter::from_default_env())
+            .with(explain_filter(
+                tracing_subscriber::EnvFilter::from_default_env(),
+            ))
             .init();
     };
 }
 
+/// Enables the explanations from [`file::explain`] on top of the user's filter, when the user asked
+/// for them.
+fn explain_filter(filter: tracing_subscriber::EnvFilter) -> tracing_subscriber::EnvFilter {
+    if !file::explain::enabled() {
+        return filter;
+    }
+
+    let directive = format!("{}=info", file::explain::EXPLAIN_TARGET)
+        .parse()
+        .expect("explain directive is valid");
+    filter.add_directive(directive)
+}
+
 /// Occurs after [`layer_pre_initialization`] has succeeded.
 ///
 /// Initialized the main parts of mirrord-layer.
@@ -324,9 +353,12 @@ fn layer_start(mut config: LayerConfig) {
     if trace_only {
         config.feature.fs.mode = FsModeConfig::Local;
         config.feature.network.dns = false;
+        config.feature.network.cluster_names = false;
         config.feature.network.incoming.mode = IncomingMode::Off;
         config.feature.network.outgoing.tcp = false;
         config.feature.network.outgoing.udp = false;
+        config.feature.identity = false;
+        config.feature.env.lazy = None;
     }
 
     init_tracing();
@@ -340,10 +372,15 @@ fn layer_start(mut config: LayerConfig) {
     let state = LayerSetup::new(config, debugger_ports, local_hostname);
     SETUP.set(state).unwrap();
 
+    child_env::capture();
+
     let state = setup();
     enable_hooks(
         state.fs_config().is_active(),
         state.remote_dns_enabled(),
+        state.remote_cluster_names(),
+        state.identity_enabled(),
+        state.env_config().lazy.unwrap_or(false),
         state.sip_binaries(),
     );
 
@@ -357,7 +394,7 @@ fn layer_start(mut config: LayerConfig) {
     }
 
     unsafe {
-        let address = setup().proxy_address();
+        let address = setup().proxy_address().clone();
         let new_connection = ProxyConnection::new(
             address,
             NewSessionRequest::New(process_info),
@@ -376,6 +413,10 @@ fn layer_start(mut config: LayerConfig) {
             .unwrap_or(false);
     if fetch_env {
         let env = fetch_env_vars();
+        for var in setup().env_config().locale_vars_to_unset(&env) {
+            std::env::remove_var(var);
+        }
+
         for (key, value) in env {
             std::env::set_var(key, value);
         }
@@ -393,9 +434,11 @@ fn layer_start(mut config: LayerConfig) {
 /// Name of environment variable used to mark whether remote environment has already been fetched.
 const REMOTE_ENV_FETCHED: &str = "MIRRORD_REMOTE_ENV_FETCHED";
 
-/// Fetches remote environment from the agent.
-/// Uses [`SETUP`] and [`PROXY_CONNECTION`] globals.
-fn fetch_env_vars() -> HashMap<String, String> {
+/// The request for the remote environment, with the includes and excludes of
+/// [`EnvConfig`](mirrord_config::feature::env::EnvConfig), or [`None`] when no variable is
+/// requested.
+/// Uses [`SETUP`] global.
+pub(crate) fn env_vars_request() -> Option<GetEnvVarsRequest> {
     let (env_vars_exclude, env_vars_include) = match (
         setup()
             .env_config()
@@ -416,22 +459,28 @@ fn fetch_env_vars() -> HashMap<String, String> {
         (None, None) => (HashSet::new(), HashSet::from(EnvVars("*".to_owned()))),
     };
 
-    if !env_vars_exclude.is_empty() || !env_vars_include.is_empty() {
-        let mut remote_env = make_proxy_request_with_response(GetEnvVarsRequest {
-            env_vars_filter: env_vars_exclude,
-            env_vars_select: env_vars_include,
-        })
+    (!env_vars_exclude.is_empty() || !env_vars_include.is_empty()).then_some(GetEnvVarsRequest {
+        env_vars_filter: env_vars_exclude,
+        env_vars_select: env_vars_include,
+    })
+}
+
+/// Fetches remote environment from the agent.
+/// Uses [`SETUP`] and [`PROXY_CONNECTION`] globals.
+fn fetch_env_vars() -> HashMap<String, String> {
+    let Some(request) = env_vars_request() else {
+        return Default::default();
+    };
+
+    let mut remote_env = make_proxy_request_with_response(request)
         .expect("failed to make request to proxy")
         .expect("failed to fetch remote env");
 
-        if let Some(overrides) = setup().env_config().r#override.as_ref() {
-            remote_env.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
-        }
-
-        remote_env
-    } else {
-        Default::default()
+    if let Some(overrides) = setup().env_config().r#override.as_ref() {
+        remote_env.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
     }
+
+    remote_env
 }
 
 /// We need to hook execve syscall to allow mirrord-layer to be loaded with sip patch when loading
@@ -451,6 +500,15 @@ fn sip_only_layer_start(mut config: LayerConfig, patch_binaries: Vec<String>) {
         read_only: None,
         local: None,
         not_found: None,
+        fallback_local: None,
+        max_file_size: None,
+        max_transfer_bytes: None,
+        max_open_files: None,
+        verify_reads: false,
+        readahead: None,
+        remote_temp: None,
+        config_maps: None,
+        secrets: None,
     };
     let debugger_ports = DebuggerPorts::from_env();
     let setup = LayerSetup::new(config, debugger_ports, true);
@@ -471,8 +529,18 @@ fn sip_only_layer_start(mut config: LayerConfig, patch_binaries: Vec<String>) {
 /// - `enabled_remote_dns`: replaces [`libc::getaddrinfo`] and [`libc::freeaddrinfo`] when this is
 ///   `true`, see [`NetworkConfig`], and
 ///   [`hooks::enable_socket_hooks`](socket::hooks::enable_socket_hooks).
+///
+/// - `enabled_cluster_names`: replaces them also when `enabled_remote_dns` is `false`, to resolve
+///   only the cluster-internal names remotely.
 #[mirrord_layer_macro::instrument(level = "trace")]
-fn enable_hooks(enabled_file_ops: bool, enabled_remote_dns: bool, patch_binaries: Vec<String>) {
+fn enable_hooks(
+    enabled_file_ops: bool,
+    enabled_remote_dns: bool,
+    enabled_cluster_names: bool,
+    enabled_identity: bool,
+    enabled_lazy_env: bool,
+    patch_binaries: Vec<String>,
+) {
     let mut hook_manager = HookManager::default();
 
     unsafe {
@@ -514,9 +582,21 @@ fn enable_hooks(enabled_file_ops: bool, enabled_remote_dns: bool, patch_binaries
         };
 
         replace!(&mut hook_manager, "fork", fork_detour, FnFork, FN_FORK);
+        replace!(&mut hook_manager, "vfork", vfork_detour, FnVfork, FN_VFORK);
+    };
+
+    #[cfg(target_os = "linux")]
+    unsafe {
+        child_env::enable_hooks(&mut hook_manager)
     };
 
-    unsafe { socket::hooks::enable_socket_hooks(&mut hook_manager, enabled_remote_dns) };
+    unsafe {
+        socket::hooks::enable_socket_hooks(
+            &mut hook_manager,
+            enabled_remote_dns,
+            enabled_cluster_names,
+        )
+    };
 
     #[cfg(target_os = "macos")]
     unsafe {
@@ -527,6 +607,14 @@ fn enable_hooks(enabled_file_ops: bool, enabled_remote_dns: bool, patch_binaries
         unsafe { file::hooks::enable_file_hooks(&mut hook_manager) };
     }
 
+    if enabled_identity {
+        unsafe { identity::enable_identity_hooks(&mut hook_manager) };
+    }
+
+    if enabled_lazy_env {
+        unsafe { lazy_env::enable_lazy_env_hooks(&mut hook_manager) };
+    }
+
     #[cfg(all(
         any(target_arch = "x86_64", target_arch = "aarch64"),
         target_os = "linux"
@@ -553,6 +641,8 @@ pub(crate) fn close_layer_fd(fd: c_int) {
         socket.close();
     } else if setup().fs_config().is_active() {
         OPEN_FILES.remove(&fd);
+        #[cfg(target_os = "linux")]
+        file::readiness::close(fd);
     }
 }
 
@@ -593,12 +683,24 @@ pub(crate) unsafe extern "C" fn fork_detour() -> pid_t {
                 }
             };
 
-            let new_connection = ProxyConnection::new(
-                parent_connection.proxy_addr(),
+            if common::is_proxy_gone() {
+                tracing::debug!("Skipping new intproxy connection (agent lost or layer disabled)");
+                std::mem::forget(parent_connection);
+                return res;
+            }
+
+            let new_connection = match ProxyConnection::new(
+                parent_connection.proxy_addr().clone(),
                 NewSessionRequest::Forked(parent_connection.layer_id()),
                 PROXY_CONNECTION_TIMEOUT,
-            )
-            .expect("failed to establish proxy connection for child");
+            ) {
+                Ok(connection) => connection,
+                Err(error) if layer_error::disable(&error) => {
+                    std::mem::forget(parent_connection);
+                    return res;
+                }
+                Err(error) => panic!("failed to establish proxy connection for child: {error}"),
+            };
             PROXY_CONNECTION
                 .set(new_connection)
                 .expect("Failed setting PROXY_CONNECTION in child fork");
@@ -615,6 +717,16 @@ pub(crate) unsafe extern "C" fn fork_detour() -> pid_t {
     res
 }
 
+/// Hook for `libc::vfork`, that forks instead.
+///
+/// The child of `vfork` shares the memory of the parent until it execs, so it can't have an
+/// intproxy connection of its own, and the layer can't safely run in it. `fork` is a valid
+/// implementation of `vfork`, so we fork, and [`fork_detour`] sets up the child.
+#[hook_fn]
+pub(crate) unsafe extern "C" fn vfork_detour() -> pid_t {
+    fork_detour()
+}
+
 /// No need to guard because we call another detour which will do the guard for us.
 ///
 /// ## Hook
@@ -113,19 +113,59 @@ impl ExecuteArgs {
             return false;
         }
 
-        !skip_processes
+        !self.is_one_of(skip_processes)
+    }
+
+    /// Checks if this process is one of the given `names`, either by executable name or by the
+    /// name it was invoked as.
+    fn is_one_of<S: AsRef<str>>(&self, names: &[S]) -> bool {
+        names
             .iter()
             .any(|name| name.as_ref() == self.exec_name || name.as_ref() == self.invoked_as)
     }
 
+    /// Checks if this process is one of the `only_processes` (`feature.only_processes`), all
+    /// processes are when it's not set.
+    fn is_allowed<S: AsRef<str>>(&self, only_processes: Option<&[S]>) -> bool {
+        only_processes.map_or(true, |names| self.is_one_of(names))
+    }
+
     /// Determine the [`LoadType`] for this process.
     pub fn load_type(&self, config: &LayerConfig) -> LoadType {
+        let remote_exec = config
+            .feature
+            .remote_exec
+            .as_ref()
+            .map(VecOrSingle::as_slice)
+            .unwrap_or(&[]);
+
+        if self.is_one_of(remote_exec) {
+            trace!("Running process remotely: {self}.");
+            return LoadType::RemoteExec;
+        }
+
         let skip_processes = config
             .skip_processes
             .as_ref()
             .map(VecOrSingle::as_slice)
             .unwrap_or(&[]);
 
+        let only_processes = config
+            .feature
+            .only_processes
+            .as_ref()
+            .map(VecOrSingle::as_slice);
+
+        if !self.is_allowed(only_processes) {
+            trace!("Not loading into process not in `feature.only_processes`: {self}.");
+
+            // Keep SIP-patching the children, as one of them may be an allowed process.
+            #[cfg(target_os = "macos")]
+            return LoadType::SIPOnly;
+            #[cfg(not(target_os = "macos"))]
+            return LoadType::Skip;
+        }
+
         if self.should_load(skip_processes, config.skip_build_tools) {
             trace!("Loading into process: {self}.");
             LoadType::Full
@@ -190,6 +230,9 @@ pub enum LoadType {
     /// Skip on current process, make only a dummy connection to the internal proxy (to prevent
     /// timeouts)
     Skip,
+
+    /// Run the process in the target container instead (`feature.remote_exec`)
+    RemoteExec,
 }
 
 #[cfg(test)]
@@ -241,4 +284,43 @@ mod tests {
 
         assert!(!executable_name.should_load(skip_processes, skip_build_tools));
     }
+
+    #[rstest]
+    #[case("pg_dump", "pg_dump", &["pg_dump"], true)]
+    #[case("pg_dump-16", "pg_dump", &["pg_dump"], true)]
+    #[case("pg_restore", "pg_restore", &["pg_dump", "psql"], false)]
+    fn is_one_of(
+        #[case] exec_name: &str,
+        #[case] invoked_as: &str,
+        #[case] names: &[&str],
+        #[case] expected: bool,
+    ) {
+        let executable_name = ExecuteArgs {
+            exec_name: exec_name.to_string(),
+            invoked_as: invoked_as.to_string(),
+            args: Vec::new(),
+        };
+
+        assert_eq!(executable_name.is_one_of(names), expected);
+    }
+
+    #[rstest]
+    #[case("node", "node", None, true)]
+    #[case("node", "node", Some(&["node"][..]), true)]
+    #[case("npm", "npm", Some(&["node"][..]), false)]
+    #[case("bash", "sh", Some(&["node", "sh"][..]), true)]
+    fn is_allowed(
+        #[case] exec_name: &str,
+        #[case] invoked_as: &str,
+        #[case] only_processes: Option<&[&str]>,
+        #[case] expected: bool,
+    ) {
+        let executable_name = ExecuteArgs {
+            exec_name: exec_name.to_string(),
+            invoked_as: invoked_as.to_string(),
+            args: Vec::new(),
+        };
+
+        assert_eq!(executable_name.is_allowed(only_processes), expected);
+    }
 }
@@ -1,8 +1,10 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
-    io,
+    io::{self, Read, Write},
     net::{SocketAddr, TcpStream},
+    os::unix::net::UnixStream,
+    path::PathBuf,
     sync::{
         atomic::{AtomicU64, Ordering},
         Mutex, PoisonError,
@@ -10,8 +12,9 @@ use std::{
     time::Duration,
 };
 
+use mirrord_config::LayerConfig;
 use mirrord_intproxy_protocol::{
-    codec::{self, CodecError, SyncDecoder, SyncEncoder},
+    codec::{CodecError, SyncDecoder, SyncEncoder},
     IsLayerRequest, IsLayerRequestWithResponse, LayerId, LayerToProxyMessage, LocalMessage,
     MessageId, NewSessionRequest, ProxyToLayerMessage,
 };
@@ -25,6 +28,10 @@ pub enum ProxyError {
     ConnectionClosed,
     #[error("unexpected response: {0:?}")]
     UnexpectedResponse(ProxyToLayerMessage),
+    #[error("request interrupted by a reset of the agent connection, retry it")]
+    Interrupted,
+    #[error("request interrupted by a signal")]
+    Signaled,
     #[error("connection lock poisoned")]
     LockPoisoned,
     #[error("{0}")]
@@ -39,29 +46,108 @@ impl<T> From<PoisonError<T>> for ProxyError {
 
 pub type Result<T> = core::result::Result<T, ProxyError>;
 
+/// Address of the internal proxy, a localhost TCP port or a Unix socket (with
+/// `internal_proxy.unix_socket`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ProxyAddress {
+    /// Reads the address the mirrord CLI passed in `connect_unix` or `connect_tcp`.
+    pub fn from_config(config: &LayerConfig) -> Self {
+        if let Some(path) = &config.connect_unix {
+            return Self::Unix(path.into());
+        }
+
+        let address = config
+            .connect_tcp
+            .as_ref()
+            .expect("missing internal proxy address")
+            .parse()
+            .expect("failed to parse internal proxy address");
+
+        Self::Tcp(address)
+    }
+}
+
+/// Connection with the internal proxy.
+#[derive(Debug)]
+enum ProxyStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl ProxyStream {
+    fn connect(address: &ProxyAddress, timeout: Duration) -> io::Result<Self> {
+        match address {
+            ProxyAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Ok(Self::Tcp(stream))
+            }
+            ProxyAddress::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Ok(Self::Unix(stream))
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+}
+
+impl Read for ProxyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ProxyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ProxyConnection {
-    sender: Mutex<SyncEncoder<LocalMessage<LayerToProxyMessage>, TcpStream>>,
+    sender: Mutex<SyncEncoder<LocalMessage<LayerToProxyMessage>, ProxyStream>>,
     responses: Mutex<ResponseManager>,
     next_message_id: AtomicU64,
     layer_id: LayerId,
-    proxy_addr: SocketAddr,
+    proxy_addr: ProxyAddress,
 }
 
 impl ProxyConnection {
     pub fn new(
-        proxy_addr: SocketAddr,
+        proxy_addr: ProxyAddress,
         session: NewSessionRequest,
         timeout: Duration,
     ) -> Result<Self> {
-        let connection = TcpStream::connect(proxy_addr)?;
-        connection.set_read_timeout(Some(timeout))?;
-        connection.set_write_timeout(Some(timeout))?;
-
-        let (mut sender, receiver) = codec::make_sync_framed::<
-            LocalMessage<LayerToProxyMessage>,
-            LocalMessage<ProxyToLayerMessage>,
-        >(connection)?;
+        let connection = ProxyStream::connect(&proxy_addr, timeout)?;
+        let mut sender = SyncEncoder::new(connection.try_clone()?);
+        let receiver = SyncDecoder::new(connection);
 
         sender.send(&LocalMessage {
             message_id: 0,
@@ -69,7 +155,7 @@ impl ProxyConnection {
         })?;
 
         let mut responses = ResponseManager::new(receiver);
-        let response = responses.receive(0)?;
+        let response = responses.receive(0, false)?;
         let ProxyToLayerMessage::NewSession(layer_id) = &response else {
             return Err(ProxyError::UnexpectedResponse(response));
         };
@@ -101,8 +187,10 @@ impl ProxyConnection {
         Ok(message_id)
     }
 
-    pub fn receive(&self, response_id: u64) -> Result<ProxyToLayerMessage> {
-        self.responses.lock()?.receive(response_id)
+    /// Waits for the response to the request `response_id`. When `interruptible`, fails with
+    /// [`ProxyError::Signaled`] when a signal interrupts the wait.
+    pub fn receive(&self, response_id: u64, interruptible: bool) -> Result<ProxyToLayerMessage> {
+        self.responses.lock()?.receive(response_id, interruptible)
     }
 
     #[mirrord_layer_macro::instrument(level = "trace", skip(self), ret)]
@@ -112,10 +200,35 @@ impl ProxyConnection {
         T::Response: Debug,
     {
         let response_id = self.send(request.wrap())?;
-        let response = self.receive(response_id)?;
+        let pending = PendingResponse {
+            connection: self,
+            response_id,
+        };
+        // Only requests we can abandon, we'd lose the response of the others (e.g. the bytes a
+        // read consumed).
+        let response = self.receive(response_id, T::CANCELLABLE)?;
+        std::mem::forget(pending);
+
+        if let ProxyToLayerMessage::Interrupted = response {
+            return Err(ProxyError::Interrupted);
+        }
+
         T::try_unwrap_response(response).map_err(ProxyError::UnexpectedResponse)
     }
 
+    /// Tells the internal proxy that we no longer wait for the response to the request
+    /// `response_id`, so that it can abandon the work in the agent. The response is dropped if it
+    /// comes anyway.
+    fn cancel(&self, response_id: MessageId) {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses.cancelled.insert(response_id);
+        }
+
+        if let Err(error) = self.send(LayerToProxyMessage::Cancel(response_id)) {
+            tracing::debug!(%error, response_id, "failed to cancel request");
+        }
+    }
+
     #[mirrord_layer_macro::instrument(level = "trace", skip(self), ret)]
     pub fn make_request_no_response<T: IsLayerRequest + Debug>(
         &self,
@@ -128,40 +241,76 @@ impl ProxyConnection {
         self.layer_id
     }
 
-    pub fn proxy_addr(&self) -> SocketAddr {
-        self.proxy_addr
+    pub fn proxy_addr(&self) -> &ProxyAddress {
+        &self.proxy_addr
+    }
+}
+
+/// Cancels the request when we stop waiting for its response before it comes, that is when
+/// [`ProxyConnection::receive`] fails (e.g. it timed out, or a signal interrupted it), or the
+/// thread unwinds from it (e.g. it was cancelled with `pthread_cancel`).
+///
+/// Forgotten once the response comes.
+struct PendingResponse<'a> {
+    connection: &'a ProxyConnection,
+    response_id: MessageId,
+}
+
+impl Drop for PendingResponse<'_> {
+    fn drop(&mut self) {
+        self.connection.cancel(self.response_id);
     }
 }
 
 #[derive(Debug)]
 struct ResponseManager {
-    receiver: SyncDecoder<LocalMessage<ProxyToLayerMessage>, TcpStream>,
+    receiver: SyncDecoder<LocalMessage<ProxyToLayerMessage>, ProxyStream>,
     outstanding_responses: HashMap<u64, ProxyToLayerMessage>,
+    /// Requests that we no longer wait for, their responses are dropped.
+    cancelled: HashSet<u64>,
 }
 
 impl ResponseManager {
-    fn new(receiver: SyncDecoder<LocalMessage<ProxyToLayerMessage>, TcpStream>) -> Self {
+    fn new(receiver: SyncDecoder<LocalMessage<ProxyToLayerMessage>, ProxyStream>) -> Self {
         Self {
             receiver,
             outstanding_responses: Default::default(),
+            cancelled: Default::default(),
         }
     }
 
-    fn receive(&mut self, response_id: u64) -> Result<ProxyToLayerMessage> {
+    /// Waits for the response to the request `response_id`, keeping the responses to other
+    /// requests that come first.
+    ///
+    /// When `interruptible`, fails with [`ProxyError::Signaled`] when a signal interrupts the wait
+    /// (between the messages). Otherwise the wait is retried, the read timeout of the socket makes
+    /// every signal interrupt it, regardless of `SA_RESTART`.
+    fn receive(&mut self, response_id: u64, interruptible: bool) -> Result<ProxyToLayerMessage> {
         if let Some(response) = self.outstanding_responses.remove(&response_id) {
             return Ok(response);
         }
 
         loop {
-            let response = self
-                .receiver
-                .receive()?
-                .ok_or(ProxyError::ConnectionClosed)?;
+            let response = if interruptible {
+                self.receiver.receive_interruptible()
+            } else {
+                self.receiver.receive()
+            };
+            let response = match response {
+                Err(CodecError::IoError(error)) if error.kind() == io::ErrorKind::Interrupted => {
+                    return Err(ProxyError::Signaled)
+                }
+                response => response?.ok_or(ProxyError::ConnectionClosed)?,
+            };
 
             if response.message_id == response_id {
                 break Ok(response.inner);
             }
 
+            if self.cancelled.remove(&response.message_id) {
+                continue;
+            }
+
             self.outstanding_responses
                 .insert(response.message_id, response.inner);
         }
@@ -0,0 +1,89 @@
+//! Runs the process in the target container instead of locally, for `feature.remote_exec`.
+//!
+//! The layer takes over the process before its `main`: it starts the same command in the target
+//! container, forwards stdin to it and its output back, and exits with its exit code.
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    thread,
+};
+
+use mirrord_intproxy_protocol::{ExecCloseStdin, ExecOutput, ExecPoll, ExecStart};
+use mirrord_protocol::exec::{ExecData, ExecExit, ExecId};
+
+use crate::{
+    common::{make_proxy_request_no_response, make_proxy_request_with_response},
+    error::HookResult,
+};
+
+/// Size of the buffer for reading stdin.
+const STDIN_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Runs the command in `args` in the target container, and exits with its exit code.
+pub(crate) fn run(args: &[OsString]) -> ! {
+    let argv = args
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let code = match remote_exec(argv) {
+        Ok(ExecExit::Code(code)) => code,
+        Ok(ExecExit::Signal(signal)) => 128 + signal,
+        Err(error) => {
+            eprintln!("mirrord failed to run the process in the target container: {error}");
+            1
+        }
+    };
+
+    std::process::exit(code)
+}
+
+fn remote_exec(argv: Vec<String>) -> HookResult<ExecExit> {
+    let id = make_proxy_request_with_response(ExecStart { argv })??;
+
+    thread::spawn(move || {
+        if let Err(error) = forward_stdin(id) {
+            tracing::warn!(%error, "failed to forward stdin to the remote process");
+        }
+    });
+
+    let mut stdout = io::stdout().lock();
+    let mut stderr = io::stderr().lock();
+
+    loop {
+        let ExecOutput {
+            stdout: out,
+            stderr: err,
+            exit,
+        } = make_proxy_request_with_response(ExecPoll(id))??;
+
+        stdout.write_all(&out)?;
+        stdout.flush()?;
+        stderr.write_all(&err)?;
+
+        if let Some(exit) = exit {
+            break Ok(exit);
+        }
+    }
+}
+
+fn forward_stdin(id: ExecId) -> HookResult<()> {
+    let mut stdin = io::stdin().lock();
+    let mut buffer = vec![0; STDIN_BUFFER_SIZE];
+
+    loop {
+        let read = stdin.read(&mut buffer)?;
+        let Some(bytes) = buffer.get(..read).filter(|bytes| !bytes.is_empty()) else {
+            break;
+        };
+
+        make_proxy_request_no_response(ExecData {
+            id,
+            bytes: bytes.to_vec(),
+        })?;
+    }
+
+    make_proxy_request_no_response(ExecCloseStdin(id))?;
+
+    Ok(())
+}
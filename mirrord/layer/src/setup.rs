@@ -1,13 +1,14 @@
 use std::{collections::HashSet, net::SocketAddr};
 
 use mirrord_config::{
+    experimental::ExperimentalFeature,
     feature::{
         env::EnvConfig,
         fs::FsConfig,
         network::{incoming::IncomingConfig, outgoing::OutgoingConfig},
     },
     util::VecOrSingle,
-    LayerConfig,
+    LayerConfig, OnAgentLoss, OnLayerError,
 };
 use mirrord_intproxy_protocol::PortSubscription;
 use mirrord_protocol::{
@@ -16,7 +17,10 @@ use mirrord_protocol::{
 };
 use regex::RegexSet;
 
-use crate::{debugger_ports::DebuggerPorts, file::filter::FileFilter, socket::OutgoingSelector};
+use crate::{
+    debugger_ports::DebuggerPorts, file::filter::FileFilter, proxy_connection::ProxyAddress,
+    socket::OutgoingSelector,
+};
 
 /// Complete layer setup.
 /// Contains [`LayerConfig`] and derived from it structs, which are used in multiple places across
@@ -28,14 +32,17 @@ pub struct LayerSetup {
     debugger_ports: DebuggerPorts,
     remote_unix_streams: RegexSet,
     outgoing_selector: OutgoingSelector,
-    proxy_address: SocketAddr,
+    proxy_address: ProxyAddress,
     incoming_mode: IncomingMode,
     local_hostname: bool,
 }
 
 impl LayerSetup {
     pub fn new(config: LayerConfig, debugger_ports: DebuggerPorts, local_hostname: bool) -> Self {
-        let file_filter = FileFilter::new(config.feature.fs.clone());
+        let file_filter = FileFilter::new(
+            config.feature.fs.clone(),
+            config.feature.env.target_locale.unwrap_or_default(),
+        );
 
         let remote_unix_streams = config
             .feature
@@ -52,12 +59,7 @@ impl LayerSetup {
         let outgoing_selector: OutgoingSelector =
             OutgoingSelector::new(&config.feature.network.outgoing);
 
-        let proxy_address = config
-            .connect_tcp
-            .as_ref()
-            .expect("missing internal proxy address")
-            .parse()
-            .expect("failed to parse internal proxy address");
+        let proxy_address = ProxyAddress::from_config(&config);
 
         let incoming_mode = IncomingMode::new(&config.feature.network.incoming);
 
@@ -97,6 +99,20 @@ impl LayerSetup {
         self.config.feature.network.dns
     }
 
+    pub fn remote_cluster_names(&self) -> bool {
+        self.config.feature.network.cluster_names
+    }
+
+    pub fn ipv6_enabled(&self) -> bool {
+        self.config.feature.network.ipv6
+    }
+
+    /// The identity of the target is returned from `getuid` and friends, except when running
+    /// without a target, where there's no identity to return.
+    pub fn identity_enabled(&self) -> bool {
+        self.config.feature.identity && !self.targetless()
+    }
+
     pub fn targetless(&self) -> bool {
         self.config.target.path.is_none()
     }
@@ -121,8 +137,16 @@ impl LayerSetup {
         &self.remote_unix_streams
     }
 
-    pub fn proxy_address(&self) -> SocketAddr {
-        self.proxy_address
+    pub fn proxy_address(&self) -> &ProxyAddress {
+        &self.proxy_address
+    }
+
+    pub fn on_agent_loss(&self) -> OnAgentLoss {
+        self.config.on_agent_loss
+    }
+
+    pub fn on_layer_error(&self) -> OnLayerError {
+        self.config.on_layer_error
     }
 
     pub fn incoming_mode(&self) -> &IncomingMode {
@@ -132,6 +156,10 @@ impl LayerSetup {
     pub fn local_hostname(&self) -> bool {
         self.local_hostname
     }
+
+    pub fn is_experimental_enabled(&self, feature: ExperimentalFeature) -> bool {
+        self.config.is_experimental_enabled(feature)
+    }
 }
 
 /// HTTP filter used by the layer with the `steal` feature.
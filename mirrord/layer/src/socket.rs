@@ -1,6 +1,7 @@
 //! We implement each hook function in a safe function as much as possible, having the unsafe do the
 //! absolute minimum
 use std::{
+    iter,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
     os::unix::io::RawFd,
     str::FromStr,
@@ -15,7 +16,8 @@ use mirrord_config::feature::network::outgoing::{
 };
 use mirrord_intproxy_protocol::{NetProtocol, PortUnsubscribe};
 use mirrord_protocol::{
-    outgoing::SocketAddress, DnsLookupError, ResolveErrorKindInternal, ResponseError,
+    dns::AddressFamily, outgoing::SocketAddress, DnsLookupError, ResolveErrorKindInternal,
+    ResponseError,
 };
 use socket2::SockAddr;
 use tracing::warn;
@@ -24,7 +26,7 @@ use crate::{
     common,
     detour::{Bypass, Detour, DetourGuard, OptionExt},
     error::{HookError, HookResult},
-    socket::ops::{remote_getaddrinfo, REMOTE_DNS_REVERSE_MAPPING},
+    socket::ops::{remote_getaddrinfo, REMOTE_DNS_NAME_ADDRESSES, REMOTE_DNS_REVERSE_MAPPING},
 };
 
 pub(super) mod hooks;
@@ -63,6 +65,13 @@ pub struct Connected {
     /// The address of the interceptor socket, this is what we're really connected to in the
     /// outgoing feature.
     layer_address: Option<SocketAddress>,
+
+    /// Where an incoming connection was originally sent to in the target, before it was
+    /// redirected to the agent.
+    ///
+    /// Whenever the user calls `getsockopt(SO_ORIGINAL_DST)`, this is the address we return to
+    /// them. Only set for connections we [`ops::accept`].
+    original_destination: Option<SocketAddress>,
 }
 
 /// Represents a [`SocketState`] where the user made a [`libc::bind`] call, and we intercepted it.
@@ -178,6 +187,17 @@ impl UserSocket {
     }
 }
 
+/// Whether `name` is a cluster-internal name, that is the name of a service
+/// (`my-service.my-namespace.svc`) or anything else under `cluster.local`
+/// (`my-service.my-namespace.svc.cluster.local`).
+///
+/// Single-label names (`my-service`) are not, they might as well be local hosts.
+pub(crate) fn is_cluster_name(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    name.ends_with(".svc") || name.ends_with(".cluster.local")
+}
+
 /// Holds valid address that we should use to `connect_outgoing`.
 #[derive(Debug, Clone, Copy)]
 enum ConnectionThrough {
@@ -270,6 +290,12 @@ impl OutgoingSelector {
     ///
     /// So if the user specified a selector with `0.0.0.0:0`, we're going to be always matching on
     /// it.
+    ///
+    /// ## `cluster_names`
+    ///
+    /// Addresses of cluster-internal names that no `remote` filter matched still go through the
+    /// agent with `feature.network.cluster_names`. The explicit filters always win, so a `local`
+    /// filter that matches them sends them local.
     #[mirrord_layer_macro::instrument(level = "trace", ret)]
     fn get_connection_through(
         &self,
@@ -282,8 +308,17 @@ impl OutgoingSelector {
             Self::Remote(filters) => (filters, false),
         };
 
+        let addresses = Self::same_name_addresses(address);
         for filter in filters {
-            if !filter.matches(address, protocol, selector_is_local)? {
+            let mut matched = false;
+            for address in &addresses {
+                matched = filter.matches(*address, protocol, selector_is_local)?;
+                if matched {
+                    break;
+                }
+            }
+
+            if !matched {
                 continue;
             }
 
@@ -294,13 +329,47 @@ impl OutgoingSelector {
             };
         }
 
-        if selector_is_local {
+        if selector_is_local
+            || (crate::setup().remote_cluster_names() && Self::is_cluster_address(address))
+        {
             Ok(ConnectionThrough::Remote(address))
         } else {
             Self::get_local_address_to_connect(address).map(ConnectionThrough::Local)
         }
     }
 
+    /// `address`, and the other addresses that were resolved remotely for the same name, with the
+    /// same port.
+    ///
+    /// Apps that race IPv4 and IPv6 connections (happy eyeballs) connect to several addresses of a
+    /// name at once, so the filters are matched against all of them, and the connections go the
+    /// same way whichever wins the race.
+    fn same_name_addresses(address: SocketAddr) -> Vec<SocketAddr> {
+        let name = REMOTE_DNS_REVERSE_MAPPING
+            .get(&address.ip())
+            .map(|entry| entry.value().clone());
+        let Some(addresses) = name.and_then(|name| REMOTE_DNS_NAME_ADDRESSES.get(&name)) else {
+            return vec![address];
+        };
+
+        iter::once(address)
+            .chain(
+                addresses
+                    .iter()
+                    .filter(|ip| **ip != address.ip())
+                    .map(|ip| SocketAddr::new(*ip, address.port())),
+            )
+            .collect()
+    }
+
+    /// Whether `address` was resolved remotely from a cluster-internal name, see
+    /// [`is_cluster_name`].
+    fn is_cluster_address(address: SocketAddr) -> bool {
+        REMOTE_DNS_REVERSE_MAPPING
+            .get(&address.ip())
+            .is_some_and(|hostname| is_cluster_name(hostname.value()))
+    }
+
     /// Helper function that looks into the [`REMOTE_DNS_REVERSE_MAPPING`] for `address`, so we can
     /// retrieve the hostname and resolve it locally (when applicable).
     ///
@@ -312,7 +381,8 @@ impl OutgoingSelector {
     /// Returns 1 of 2 possibilities:
     ///
     /// 1. `address` is in [`REMOTE_DNS_REVERSE_MAPPING`]: resolves the hostname locally, then
-    /// return the first result
+    /// return the first result of the same family as `address` (the socket can't connect to the
+    /// other one), or the first result when there is none
     /// 2. `address` is **NOT** in [`REMOTE_DNS_REVERSE_MAPPING`]: return the `address` as is;
     #[mirrord_layer_macro::instrument(level = "trace", ret)]
     fn get_local_address_to_connect(address: SocketAddr) -> HookResult<SocketAddr> {
@@ -332,9 +402,15 @@ impl OutgoingSelector {
         };
 
         let _guard = DetourGuard::new();
-        (hostname, address.port())
+        let resolved = (hostname, address.port())
             .to_socket_addrs()?
-            .next()
+            .collect::<Vec<_>>();
+
+        resolved
+            .iter()
+            .find(|resolved| resolved.is_ipv4() == address.is_ipv4())
+            .or(resolved.first())
+            .copied()
             .ok_or(HookError::DNSNoName)
     }
 }
@@ -383,7 +459,7 @@ impl OutgoingFilterExt for OutgoingFilter {
         match &self.address {
             AddressFilter::Name((name, port)) => {
                 let resolved_ips = if crate::setup().remote_dns_enabled() && !force_local_dns {
-                    match remote_getaddrinfo(name.to_string()) {
+                    match remote_getaddrinfo(name.to_string(), AddressFamily::Both) {
                         Ok(res) => res.into_iter().map(|(_, ip)| ip).collect(),
                         Err(HookError::ResponseError(ResponseError::DnsLookup(
                             DnsLookupError {
@@ -497,3 +573,50 @@ impl SocketAddrExt for SocketAddr {
             .and_then(|address| address.as_socket().bypass(Bypass::AddressConversion))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("my-service.my-namespace.svc", true)]
+    #[case("my-service.my-namespace.svc.cluster.local.", true)]
+    #[case("MY-SERVICE.MY-NAMESPACE.SVC.CLUSTER.LOCAL", true)]
+    #[case("10-0-0-1.my-namespace.pod.cluster.local", true)]
+    #[case("my-service", false)]
+    #[case("localhost", false)]
+    #[case("::1", false)]
+    #[case("example.com", false)]
+    #[case("svc.example.com", false)]
+    fn cluster_name(#[case] name: &str, #[case] expected: bool) {
+        assert_eq!(is_cluster_name(name), expected);
+    }
+
+    #[test]
+    fn same_name_addresses() {
+        let ipv4 = "10.20.30.40".parse().unwrap();
+        let ipv6 = "fd00::2040".parse().unwrap();
+        ops::remember_remote_name(ipv4, "happy-eyeballs.example.com.".to_string());
+        ops::remember_remote_name(ipv6, "happy-eyeballs.example.com.".to_string());
+
+        assert_eq!(
+            OutgoingSelector::same_name_addresses(SocketAddr::new(ipv6, 443)),
+            vec![SocketAddr::new(ipv6, 443), SocketAddr::new(ipv4, 443)]
+        );
+
+        let unresolved = "10.20.30.41:443".parse().unwrap();
+        assert_eq!(
+            OutgoingSelector::same_name_addresses(unresolved),
+            vec![unresolved]
+        );
+
+        // The IPv4 address now belongs to another name.
+        ops::remember_remote_name(ipv4, "other.example.com.".to_string());
+        assert_eq!(
+            OutgoingSelector::same_name_addresses(SocketAddr::new(ipv6, 443)),
+            vec![SocketAddr::new(ipv6, 443)]
+        );
+    }
+}
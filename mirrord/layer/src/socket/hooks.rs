@@ -81,6 +81,26 @@ pub(crate) unsafe extern "C" fn getsockname_detour(
         .unwrap_or_bypass_with(|_| FN_GETSOCKNAME(sockfd, address, address_len))
 }
 
+/// Hook for `libc::getsockopt`.
+///
+/// Only `SO_ORIGINAL_DST` is handled, for connections stolen from the target.
+#[cfg(target_os = "linux")]
+#[hook_guard_fn]
+pub(super) unsafe extern "C" fn getsockopt_detour(
+    sockfd: RawFd,
+    level: c_int,
+    optname: c_int,
+    optval: *mut c_void,
+    optlen: *mut socklen_t,
+) -> c_int {
+    if optname == libc::SO_ORIGINAL_DST && matches!(level, libc::SOL_IP | libc::SOL_IPV6) {
+        original_destination(sockfd, optval, optlen)
+            .unwrap_or_bypass_with(|_| FN_GETSOCKOPT(sockfd, level, optname, optval, optlen))
+    } else {
+        FN_GETSOCKOPT(sockfd, level, optname, optval, optlen)
+    }
+}
+
 /// Hook for `libc::gethostname`.
 ///
 /// Reads remote hostname bytes into `raw_name`, will rais EINVAL errno and return -1 if hostname
@@ -183,7 +203,7 @@ pub(super) unsafe extern "C" fn _accept_nocancel_detour(
 
 /// <https://github.com/metalbear-co/mirrord/issues/184>
 #[hook_fn]
-pub(super) unsafe extern "C" fn fcntl_detour(fd: c_int, cmd: c_int, mut arg: ...) -> c_int {
+pub(crate) unsafe extern "C" fn fcntl_detour(fd: c_int, cmd: c_int, mut arg: ...) -> c_int {
     let arg = arg.arg::<usize>();
     let fcntl_result = FN_FCNTL(fd, cmd, arg);
     let guard = DetourGuard::new();
@@ -202,7 +222,7 @@ pub(super) unsafe extern "C" fn fcntl_detour(fd: c_int, cmd: c_int, mut arg: ...
 }
 
 #[hook_guard_fn]
-pub(super) unsafe extern "C" fn dup_detour(fd: c_int) -> c_int {
+pub(crate) unsafe extern "C" fn dup_detour(fd: c_int) -> c_int {
     let dup_result = FN_DUP(fd);
 
     if dup_result == -1 {
@@ -216,7 +236,7 @@ pub(super) unsafe extern "C" fn dup_detour(fd: c_int) -> c_int {
 }
 
 #[hook_guard_fn]
-pub(super) unsafe extern "C" fn dup2_detour(oldfd: c_int, newfd: c_int) -> c_int {
+pub(crate) unsafe extern "C" fn dup2_detour(oldfd: c_int, newfd: c_int) -> c_int {
     if oldfd == newfd {
         return newfd;
     }
@@ -235,7 +255,7 @@ pub(super) unsafe extern "C" fn dup2_detour(oldfd: c_int, newfd: c_int) -> c_int
 
 #[cfg(target_os = "linux")]
 #[hook_guard_fn]
-pub(super) unsafe extern "C" fn dup3_detour(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
+pub(crate) unsafe extern "C" fn dup3_detour(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
     let dup3_result = FN_DUP3(oldfd, newfd, flags);
 
     if dup3_result == -1 {
@@ -500,7 +520,11 @@ unsafe extern "C" fn dns_configuration_free_detour(config: *mut dns_config_t) {
     // It should drop it automatically
 }
 
-pub(crate) unsafe fn enable_socket_hooks(hook_manager: &mut HookManager, enabled_remote_dns: bool) {
+pub(crate) unsafe fn enable_socket_hooks(
+    hook_manager: &mut HookManager,
+    enabled_remote_dns: bool,
+    enabled_cluster_names: bool,
+) {
     replace!(hook_manager, "socket", socket_detour, FnSocket, FN_SOCKET);
 
     replace!(
@@ -598,6 +622,14 @@ pub(crate) unsafe fn enable_socket_hooks(hook_manager: &mut HookManager, enabled
         );
 
         replace!(hook_manager, "dup3", dup3_detour, FnDup3, FN_DUP3);
+
+        replace!(
+            hook_manager,
+            "getsockopt",
+            getsockopt_detour,
+            FnGetsockopt,
+            FN_GETSOCKOPT
+        );
     }
 
     replace!(hook_manager, "accept", accept_detour, FnAccept, FN_ACCEPT);
@@ -609,7 +641,7 @@ pub(crate) unsafe fn enable_socket_hooks(hook_manager: &mut HookManager, enabled
         FN__ACCEPT_NOCANCEL
     );
 
-    if enabled_remote_dns {
+    if enabled_remote_dns || enabled_cluster_names {
         replace!(
             hook_manager,
             "gethostbyname",
@@ -633,22 +665,23 @@ pub(crate) unsafe fn enable_socket_hooks(hook_manager: &mut HookManager, enabled
             FnFreeaddrinfo,
             FN_FREEADDRINFO
         );
-        #[cfg(target_os = "macos")]
-        {
-            replace!(
-                hook_manager,
-                "dns_configuration_copy",
-                dns_configuration_copy_detour,
-                FnDns_configuration_copy,
-                FN_DNS_CONFIGURATION_COPY
-            );
-            replace!(
-                hook_manager,
-                "dns_configuration_free",
-                dns_configuration_free_detour,
-                FnDns_configuration_free,
-                FN_DNS_CONFIGURATION_FREE
-            );
-        }
+    }
+
+    #[cfg(target_os = "macos")]
+    if enabled_remote_dns {
+        replace!(
+            hook_manager,
+            "dns_configuration_copy",
+            dns_configuration_copy_detour,
+            FnDns_configuration_copy,
+            FN_DNS_CONFIGURATION_COPY
+        );
+        replace!(
+            hook_manager,
+            "dns_configuration_free",
+            dns_configuration_free_detour,
+            FnDns_configuration_free,
+            FN_DNS_CONFIGURATION_FREE
+        );
     }
 }
@@ -1,6 +1,7 @@
 use alloc::ffi::CString;
 use core::{ffi::CStr, mem};
 use std::{
+    collections::BTreeSet,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
     os::{
@@ -20,7 +21,7 @@ use mirrord_intproxy_protocol::{
     OutgoingConnectResponse, PortSubscribe,
 };
 use mirrord_protocol::{
-    dns::{GetAddrInfoRequest, LookupRecord},
+    dns::{AddressFamily, GetAddrInfoRequestV2, LookupRecord},
     file::{OpenFileResponse, OpenOptionsInternal, ReadFileResponse},
 };
 use socket2::SockAddr;
@@ -28,9 +29,11 @@ use tracing::{error, trace};
 
 use super::{hooks::*, *};
 use crate::{
-    detour::{Detour, OnceLockExt, OptionDetourExt, OptionExt},
+    agent_loss,
+    detour::{self, Detour, OnceLockExt, OptionDetourExt, OptionExt},
     error::HookError,
     file::{self, OPEN_FILES},
+    layer_error,
 };
 
 /// Holds the pair of [`IpAddr`] with their hostnames, resolved remotely through
@@ -41,6 +44,29 @@ use crate::{
 pub(super) static REMOTE_DNS_REVERSE_MAPPING: LazyLock<DashMap<IpAddr, String>> =
     LazyLock::new(|| DashMap::with_capacity(8));
 
+/// The addresses of every hostname in [`REMOTE_DNS_REVERSE_MAPPING`], so that finding the other
+/// addresses of a name doesn't go over all of them on every `connect`.
+pub(super) static REMOTE_DNS_NAME_ADDRESSES: LazyLock<DashMap<String, BTreeSet<IpAddr>>> =
+    LazyLock::new(|| DashMap::with_capacity(8));
+
+/// Remembers that `ip` was resolved remotely from `name`, in [`REMOTE_DNS_REVERSE_MAPPING`] and
+/// [`REMOTE_DNS_NAME_ADDRESSES`].
+pub(super) fn remember_remote_name(ip: IpAddr, name: String) {
+    let previous = REMOTE_DNS_REVERSE_MAPPING.insert(ip, name.clone());
+
+    if let Some(previous) = previous.filter(|previous| *previous != name) {
+        if let Some(mut addresses) = REMOTE_DNS_NAME_ADDRESSES.get_mut(&previous) {
+            addresses.remove(&ip);
+        }
+        REMOTE_DNS_NAME_ADDRESSES.remove_if(&previous, |_, addresses| addresses.is_empty());
+    }
+
+    REMOTE_DNS_NAME_ADDRESSES
+        .entry(name)
+        .or_default()
+        .insert(ip);
+}
+
 /// Hostname initialized from the agent with [`gethostname`].
 pub(crate) static HOSTNAME: OnceLock<CString> = OnceLock::new();
 
@@ -123,7 +149,17 @@ pub(super) fn socket(domain: c_int, type_: c_int, protocol: c_int) -> Detour<Raw
         Ok(())
     }?;
 
-    if domain == libc::AF_INET6 {
+    // Sockets created after the agent was lost, or after the layer disabled itself, are never
+    // handled remotely.
+    if agent_loss::is_degraded() {
+        return Detour::Bypass(Bypass::AgentLost);
+    }
+    if layer_error::is_disabled() {
+        return Detour::Bypass(Bypass::LayerError);
+    }
+
+    // IPv6 is only supported for TCP, and only with `feature.network.ipv6`.
+    if domain == libc::AF_INET6 && (!crate::setup().ipv6_enabled() || socket_kind.is_udp()) {
         return Detour::Error(HookError::SocketUnsuportedIpv6);
     }
 
@@ -196,7 +232,7 @@ fn is_ignored_tcp_port(addr: &SocketAddr, config: &IncomingConfig) -> bool {
     let not_whitelisted = config
         .ports
         .as_ref()
-        .map(|ports| !ports.contains(&mapped_port))
+        .map(|ports| !ports.contains(mapped_port))
         .unwrap_or(http_filter_used);
 
     is_ignored_port(addr) || (not_stolen_with_filter && not_whitelisted)
@@ -212,7 +248,6 @@ pub(super) fn bind(
 ) -> Detour<i32> {
     let requested_address = SocketAddr::try_from_raw(raw_address, address_length)?;
     let requested_port = requested_address.port();
-    let incoming_config = crate::setup().incoming_config();
 
     let mut socket = {
         SOCKETS
@@ -227,12 +262,28 @@ pub(super) fn bind(
             })?
     };
 
+    // Only outgoing connections go through the agent over IPv6, so IPv6 listeners stay local. The
+    // socket is not in `SOCKETS` anymore, so we don't manage what's done with it after the bind.
+    if socket.domain == libc::AF_INET6 {
+        return Detour::Bypass(Bypass::Domain(libc::AF_INET6));
+    }
+
+    let incoming_config = crate::setup().incoming_config();
+
     // we don't use `is_localhost` here since unspecified means to listen
     // on all IPs.
     if incoming_config.ignore_localhost && requested_address.ip().is_loopback() {
         return Detour::Bypass(Bypass::IgnoreLocalhost(requested_port));
     }
 
+    // Listeners bound only on loopback are usually internal (e.g. admin servers).
+    if requested_address.ip().is_loopback()
+        && matches!(socket.kind, SocketKind::Tcp(_))
+        && !incoming_config.intercepts_loopback_port(requested_port)
+    {
+        return Detour::Bypass(Bypass::LoopbackPort(requested_port));
+    }
+
     // To handle #1458, we don't ignore port `0` for UDP.
     if (is_ignored_tcp_port(&requested_address, incoming_config)
         && matches!(socket.kind, SocketKind::Tcp(_)))
@@ -323,6 +374,8 @@ pub(super) fn listen(sockfd: RawFd, backlog: c_int) -> Detour<i32> {
             .map(|(_, socket)| socket)
             .bypass(Bypass::LocalFdNotFound(sockfd))?
     };
+    // The socket may be bound to another address than the one the application asked for.
+    detour::mark_remote_handle();
 
     let setup = crate::setup();
 
@@ -434,6 +487,7 @@ fn connect_outgoing<const CALL_CONNECT: bool>(
             remote_address,
             local_address: in_cluster_address,
             layer_address: Some(layer_address),
+            original_destination: None,
         };
 
         trace!("we are connected {connected:#?}");
@@ -694,6 +748,31 @@ pub(super) fn getsockname(
     fill_address(address, address_len, local_address.try_into()?)
 }
 
+/// Returns the address that the connection we [`accept`]ed on `sockfd` was originally sent to in
+/// the target, for `getsockopt(SO_ORIGINAL_DST)`.
+///
+/// Transparent proxies use it to find out where to forward the connection.
+#[cfg(target_os = "linux")]
+#[mirrord_layer_macro::instrument(level = "trace", ret, skip(optval, optlen))]
+pub(super) fn original_destination(
+    sockfd: RawFd,
+    optval: *mut c_void,
+    optlen: *mut socklen_t,
+) -> Detour<i32> {
+    let original_destination = SOCKETS
+        .get(&sockfd)
+        .bypass(Bypass::LocalFdNotFound(sockfd))
+        .and_then(|entry| match &entry.value().state {
+            SocketState::Connected(Connected {
+                original_destination: Some(original_destination),
+                ..
+            }) => Detour::Success(original_destination.clone()),
+            _ => Detour::Bypass(Bypass::InvalidState(sockfd)),
+        })?;
+
+    fill_address(optval.cast(), optlen, original_destination.try_into()?)
+}
+
 /// When the fd is "ours", we accept and use [`ConnMetadataRequest`] to retrieve peer address from
 /// the internal proxy.
 #[mirrord_layer_macro::instrument(level = "trace", ret, skip(address, address_len))]
@@ -732,6 +811,7 @@ pub(super) fn accept(
     let ConnMetadataResponse {
         remote_source,
         local_address,
+        original_destination,
     } = common::make_proxy_request_with_response(ConnMetadataRequest {
         listener_address,
         peer_address,
@@ -741,6 +821,7 @@ pub(super) fn accept(
         remote_address: remote_source.into(),
         local_address: SocketAddr::new(local_address, port).into(),
         layer_address: None,
+        original_destination: Some(original_destination.into()),
     });
 
     let new_socket = UserSocket::new(domain, type_, protocol, state, type_.try_into()?);
@@ -763,30 +844,30 @@ pub(super) fn fcntl(orig_fd: c_int, cmd: c_int, fcntl_fd: i32) -> Result<(), Hoo
 /// Managed part of our [`dup_detour`], that clones the `Arc<T>` thing we have keyed by `fd`
 /// ([`UserSocket`], or [`RemoteFile`]).
 ///
+/// Every copy of a remote file fd shares the same [`RemoteFile`], which is only closed in the
+/// agent when the last copy is closed (and dropped from [`OPEN_FILES`]).
+///
 /// - `SWITCH_MAP`:
 ///
-/// Indicates that we're switching the `fd` from [`SOCKETS`] to [`OPEN_FILES`] (or vice-versa).
+/// Indicates that `dup_fd` might have been in use, and was closed by the kernel before being
+/// replaced with the copy of `fd`, so whatever we had keyed by it is gone (even when `fd` is not
+/// managed by us).
 ///
 /// We need this to properly handle some cases in [`fcntl`], [`dup2_detour`], and [`dup3_detour`].
 /// Extra relevant for node on macos.
 #[mirrord_layer_macro::instrument(level = "trace", ret)]
 pub(super) fn dup<const SWITCH_MAP: bool>(fd: c_int, dup_fd: i32) -> Result<(), HookError> {
-    if let Some(socket) = SOCKETS.get(&fd).map(|entry| entry.value().clone()) {
-        SOCKETS.insert(dup_fd as RawFd, socket);
-
-        if SWITCH_MAP {
-            OPEN_FILES.remove(&dup_fd);
-        }
-
-        return Ok(());
+    if SWITCH_MAP {
+        SOCKETS.remove(&dup_fd);
+        OPEN_FILES.remove(&dup_fd);
+        #[cfg(target_os = "linux")]
+        crate::file::readiness::close(dup_fd);
     }
 
-    if let Some(file) = OPEN_FILES.view(&fd, |_, file| file.clone()) {
+    if let Some(socket) = SOCKETS.get(&fd).map(|entry| entry.value().clone()) {
+        SOCKETS.insert(dup_fd as RawFd, socket);
+    } else if let Some(file) = OPEN_FILES.view(&fd, |_, file| file.clone()) {
         OPEN_FILES.insert(dup_fd as RawFd, file);
-
-        if SWITCH_MAP {
-            SOCKETS.remove(&dup_fd);
-        }
     }
 
     Ok(())
@@ -797,17 +878,30 @@ pub(super) fn dup<const SWITCH_MAP: bool>(fd: c_int, dup_fd: i32) -> Result<(),
 ///
 /// # Note
 ///
-/// This function updates the mapping in [`REMOTE_DNS_REVERSE_MAPPING`].
+/// Only addresses of the given `family` are returned, even when the agent can't resolve it and
+/// returns IPv4 addresses instead.
+///
+/// This function updates the mappings in [`REMOTE_DNS_REVERSE_MAPPING`] and
+/// [`REMOTE_DNS_NAME_ADDRESSES`].
 #[mirrord_layer_macro::instrument(level = "trace", ret)]
-pub(super) fn remote_getaddrinfo(node: String) -> HookResult<Vec<(String, IpAddr)>> {
-    let addr_info_list = common::make_proxy_request_with_response(GetAddrInfoRequest { node })?.0?;
+pub(super) fn remote_getaddrinfo(
+    node: String,
+    family: AddressFamily,
+) -> HookResult<Vec<(String, IpAddr)>> {
+    let addr_info_list =
+        common::make_proxy_request_with_response(GetAddrInfoRequestV2 { node, family })?.0?;
 
     addr_info_list.iter().for_each(|lookup| {
-        REMOTE_DNS_REVERSE_MAPPING.insert(lookup.ip, lookup.name.clone());
+        remember_remote_name(lookup.ip, lookup.name.clone());
     });
 
     Ok(addr_info_list
         .into_iter()
+        .filter(|LookupRecord { ip, .. }| match family {
+            AddressFamily::Ipv4Only => ip.is_ipv4(),
+            AddressFamily::Ipv6Only => ip.is_ipv6(),
+            AddressFamily::Both => true,
+        })
         .map(|LookupRecord { name, ip }| (name, ip))
         .collect())
 }
@@ -842,6 +936,10 @@ pub(super) fn getaddrinfo(
         })?
         .into();
 
+    if !crate::setup().remote_dns_enabled() && !is_cluster_name(&node) {
+        Detour::Bypass(Bypass::LocalDns)?;
+    }
+
     let service = rawish_service
         .map(CStr::to_str)
         .transpose()
@@ -861,21 +959,37 @@ pub(super) fn getaddrinfo(
 
     // TODO(alex): Use more fields from `raw_hints` to respect the user's `getaddrinfo` call.
     let libc::addrinfo {
+        ai_family,
         ai_socktype,
         ai_protocol,
         ..
     } = raw_hints;
 
+    // Apps that race IPv4 and IPv6 connections (happy eyeballs) ask for both, and connect to each
+    // through the agent. Without IPv6 support they can't create the IPv6 sockets.
+    let family = match ai_family {
+        _ if !crate::setup().ipv6_enabled() => AddressFamily::Ipv4Only,
+        libc::AF_INET => AddressFamily::Ipv4Only,
+        libc::AF_INET6 => AddressFamily::Ipv6Only,
+        _ => AddressFamily::Both,
+    };
+
     // Convert `service` into a port.
     let service = service.map_or(0, |s| s.parse().unwrap_or_default());
 
     // Some apps (gRPC on Python) use `::` to listen on all interfaces, and usually that just means
-    // resolve on unspecified. So we just return that in IpV4 because we don't support ipv6.
+    // resolve on unspecified. So we just return that in IpV4, unless only IPv6 was asked for.
     let resolved_addr = if node == "::" {
+        let unspecified = if family == AddressFamily::Ipv6Only {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+
         // name is "" because that's what happens in real flow.
-        vec![("".to_string(), IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+        vec![("".to_string(), unspecified)]
     } else {
-        remote_getaddrinfo(node.clone())?
+        remote_getaddrinfo(node.clone(), family)?
     };
 
     // Only care about: `ai_family`, `ai_socktype`, `ai_protocol`.
@@ -972,7 +1086,11 @@ pub(super) fn gethostbyname(raw_name: Option<&CStr>) -> Detour<*mut hostent> {
         })?
         .into();
 
-    let hosts_and_ips = remote_getaddrinfo(name.clone())?;
+    if !crate::setup().remote_dns_enabled() && !is_cluster_name(&name) {
+        Detour::Bypass(Bypass::LocalDns)?;
+    }
+
+    let hosts_and_ips = remote_getaddrinfo(name.clone(), AddressFamily::Ipv4Only)?;
 
     // We could `unwrap` here, as this would have failed on the previous conversion.
     let host_name = CString::new(name)?;
@@ -1334,3 +1452,75 @@ pub(super) fn sendmsg(
 
     Detour::Success(sent_result)
 }
+
+#[cfg(test)]
+mod tests {
+    use mirrord_protocol::file::OpenOptionsInternal;
+
+    use super::*;
+    use crate::file::ops::RemoteFile;
+
+    /// Copies share what we keep for the original fd, and replacing an fd (e.g. with `dup2`)
+    /// forgets what we kept for it, even when the copied fd is not ours.
+    #[test]
+    fn dup_replaces_managed_fds() {
+        // High enough not to collide with the fds of the other tests.
+        let (file_fd, socket_fd, copy_fd, unmanaged_fd) = (10_001, 10_002, 10_003, 10_004);
+
+        let file = Arc::new(RemoteFile::new(
+            7,
+            "/app/data".to_string(),
+            &OpenOptionsInternal::default(),
+        ));
+        let socket = Arc::new(UserSocket::new(
+            libc::AF_INET,
+            libc::SOCK_STREAM,
+            0,
+            SocketState::Initialized,
+            SocketKind::Tcp(libc::SOCK_STREAM),
+        ));
+        OPEN_FILES.insert(file_fd, file.clone());
+        SOCKETS.insert(socket_fd, socket.clone());
+
+        dup::<false>(file_fd, copy_fd).unwrap();
+        assert!(Arc::ptr_eq(&OPEN_FILES.get(&copy_fd).unwrap(), &file));
+        assert_eq!(Arc::strong_count(&file), 3);
+
+        dup::<true>(socket_fd, copy_fd).unwrap();
+        assert!(Arc::ptr_eq(&SOCKETS.get(&copy_fd).unwrap(), &socket));
+        assert!(!OPEN_FILES.contains_key(&copy_fd));
+        assert_eq!(Arc::strong_count(&file), 2);
+
+        dup::<true>(unmanaged_fd, socket_fd).unwrap();
+        assert!(!SOCKETS.contains_key(&socket_fd));
+        assert!(SOCKETS.contains_key(&copy_fd));
+
+        SOCKETS.remove(&copy_fd);
+        OPEN_FILES.remove(&file_fd);
+        // Closing the last copy closes the file in the agent, which is not there.
+        std::mem::forget(file);
+    }
+
+    /// IPv6 listeners stay local, and we stop managing their sockets.
+    #[test]
+    fn bind_ipv6_bypasses() {
+        let sockfd = 10_005;
+        SOCKETS.insert(
+            sockfd,
+            Arc::new(UserSocket::new(
+                libc::AF_INET6,
+                libc::SOCK_STREAM,
+                0,
+                SocketState::Initialized,
+                SocketKind::Tcp(libc::SOCK_STREAM),
+            )),
+        );
+
+        let address = SockAddr::from("[::]:80".parse::<SocketAddr>().unwrap());
+        assert!(matches!(
+            bind(sockfd, address.as_ptr(), address.len()),
+            Detour::Bypass(Bypass::Domain(libc::AF_INET6))
+        ));
+        assert!(!SOCKETS.contains_key(&sockfd));
+    }
+}
@@ -0,0 +1,24 @@
+"""
+Accepts one connection and prints the address returned from `getsockopt(SO_ORIGINAL_DST)`.
+"""
+import socket
+
+# Not exported by the `socket` module, from `linux/netfilter_ipv4.h`.
+SO_ORIGINAL_DST = 80
+
+
+def main():
+    with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as listen:
+        listen.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+        listen.bind(("0.0.0.0", 21233))
+        listen.listen(1)
+        conn, _ = listen.accept()
+        with conn:
+            raw = conn.getsockopt(socket.SOL_IP, SO_ORIGINAL_DST, 16)
+            port = int.from_bytes(raw[2:4], "big")
+            ip = socket.inet_ntoa(raw[4:8])
+            print(f"original destination: {ip}:{port}", flush=True)
+
+
+if __name__ == "__main__":
+    main()
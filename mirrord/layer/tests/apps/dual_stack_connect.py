@@ -0,0 +1,33 @@
+"""
+Resolves a name to addresses of both families, and races connections to all of them, like happy
+eyeballs clients do.
+"""
+import selectors
+import socket
+
+
+def main():
+    addresses = socket.getaddrinfo("dual-stack.example", 80, type=socket.SOCK_STREAM)
+    families = {family for family, *_ in addresses}
+    assert families == {socket.AF_INET, socket.AF_INET6}, addresses
+
+    selector = selectors.DefaultSelector()
+    for family, type_, proto, _, address in addresses:
+        sock = socket.socket(family, type_, proto)
+        sock.setblocking(False)
+        sock.connect_ex(address)
+        selector.register(sock, selectors.EVENT_WRITE)
+
+    pending = len(addresses)
+    while pending:
+        for key, _ in selector.select(timeout=10):
+            sock = key.fileobj
+            assert sock.getsockopt(socket.SOL_SOCKET, socket.SO_ERROR) == 0
+            selector.unregister(sock)
+            print(f"connected to {sock.getpeername()[0]}", flush=True)
+            sock.close()
+            pending -= 1
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,3 @@
+module dup_go
+
+go 1.20
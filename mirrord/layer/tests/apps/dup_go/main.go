@@ -0,0 +1,62 @@
+package main
+
+import (
+	"C"
+	"fmt"
+	"os"
+	"os/signal"
+	"syscall"
+)
+
+const TEXT = "Pineapples."
+
+// Tests: SYS_dup, SYS_dup2/SYS_dup3, SYS_fcntl(F_DUPFD_CLOEXEC)
+//
+// Every copy of the file replaces the previous one, which is closed. Only the read from the last
+// copy should reach the remote file, and only the close of the last copy should close it.
+func main() {
+	sigs := make(chan os.Signal, 1)
+	signal.Notify(sigs, syscall.SIGINT, syscall.SIGTERM)
+
+	fd, err := syscall.Open("/app/test.txt", syscall.O_RDONLY, 0)
+	if err != nil {
+		panic(err)
+	}
+
+	dup, err := syscall.Dup(fd)
+	if err != nil {
+		panic(err)
+	}
+	syscall.Close(fd)
+
+	// `SYS_dup2` on amd64, `SYS_dup3` on arm64.
+	if err := syscall.Dup2(dup, 100); err != nil {
+		panic(err)
+	}
+	syscall.Close(dup)
+
+	if err := syscall.Dup3(100, 101, syscall.O_CLOEXEC); err != nil {
+		panic(err)
+	}
+	syscall.Close(100)
+
+	last, _, errno := syscall.Syscall(syscall.SYS_FCNTL, 101, syscall.F_DUPFD_CLOEXEC, 0)
+	if errno != 0 {
+		panic(errno)
+	}
+	syscall.Close(101)
+
+	buffer := make([]byte, len(TEXT))
+	read, err := syscall.Read(int(last), buffer)
+	if err != nil {
+		panic(err)
+	}
+	if string(buffer[:read]) != TEXT {
+		panic(fmt.Errorf("Expected %s, got %s", TEXT, string(buffer[:read])))
+	}
+	syscall.Close(int(last))
+
+	// Wait for SIGTERM/SIGINT before exiting, to give mirrord time to complete the close detour,
+	// since Go returns from `Close` before that.
+	<-sigs
+}
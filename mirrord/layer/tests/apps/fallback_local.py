@@ -0,0 +1,21 @@
+"""
+Checks and reads a file that exists only locally, while the remote fails for it with `ENOENT`.
+"""
+import os
+
+PATH = "/tmp/mirrord-fallback-local.txt"
+
+
+def main():
+    size = os.stat(PATH).st_size
+    assert os.access(PATH, os.R_OK)
+
+    with open(PATH) as file:
+        contents = file.read()
+    assert len(contents) == size, (contents, size)
+
+    print(f"local contents: {contents}", flush=True)
+
+
+if __name__ == "__main__":
+    main()
@@ -72,6 +72,14 @@ impl TestIntProxy {
             panic!("unexpected message: {msg:?}");
         };
 
+        let msg = res.recv().await;
+        let ClientMessage::SwitchCapabilities(capabilities) = msg else {
+            panic!("unexpected message: {msg:?}");
+        };
+
+        res.send(DaemonMessage::SwitchCapabilitiesResponse(capabilities))
+            .await;
+
         res
     }
 
@@ -608,6 +616,9 @@ pub enum Application {
     PythonSelfConnect,
     PythonDontLoad,
     PythonListen,
+    PythonOriginalDst,
+    PythonDualStackConnect,
+    PythonFallbackLocal,
     RustFileOps,
     Go19FileOps,
     Go20FileOps,
@@ -620,19 +631,32 @@ pub enum Application {
     Go19DirBypass,
     Go20DirBypass,
     Go21Issue834,
+    Go23Issue834,
+    Go24Issue834,
     Go20Issue834,
     Go19Issue834,
     BashShebang,
     Go19Read,
     Go20Read,
     Go21Read,
+    Go23Read,
+    Go24Read,
+    Go21Dup,
+    Go23Dup,
+    Go24Dup,
     Go19Write,
     Go20Write,
     Go21Write,
+    Go23Write,
+    Go24Write,
     Go19LSeek,
     Go20LSeek,
     Go21LSeek,
+    Go23LSeek,
+    Go24LSeek,
     Go21FAccessAt,
+    Go23FAccessAt,
+    Go24FAccessAt,
     Go19FAccessAt,
     Go20FAccessAt,
     Go19SelfOpen,
@@ -687,7 +711,10 @@ impl Application {
             Application::PythonFlaskHTTP
             | Application::PythonSelfConnect
             | Application::PythonDontLoad
-            | Application::PythonListen => Self::get_python3_executable().await,
+            | Application::PythonListen
+            | Application::PythonOriginalDst
+            | Application::PythonDualStackConnect
+            | Application::PythonFallbackLocal => Self::get_python3_executable().await,
             Application::PythonFastApiHTTP => String::from("uvicorn"),
             Application::Fork => String::from("tests/apps/fork/out.c_test_app"),
             Application::Realpath => String::from("tests/apps/realpath/out.c_test_app"),
@@ -714,19 +741,32 @@ impl Application {
             Application::Go20Issue834 => String::from("tests/apps/issue834/20.go_test_app"),
             Application::Go19Issue834 => String::from("tests/apps/issue834/19.go_test_app"),
             Application::Go21Issue834 => String::from("tests/apps/issue834/21.go_test_app"),
+            Application::Go23Issue834 => String::from("tests/apps/issue834/23.go_test_app"),
+            Application::Go24Issue834 => String::from("tests/apps/issue834/24.go_test_app"),
             Application::Go19DirBypass => String::from("tests/apps/dir_go_bypass/19.go_test_app"),
             Application::Go20DirBypass => String::from("tests/apps/dir_go_bypass/20.go_test_app"),
             Application::BashShebang => String::from("tests/apps/nothing.sh"),
             Application::Go21Read => String::from("tests/apps/read_go/21.go_test_app"),
+            Application::Go23Read => String::from("tests/apps/read_go/23.go_test_app"),
+            Application::Go24Read => String::from("tests/apps/read_go/24.go_test_app"),
+            Application::Go21Dup => String::from("tests/apps/dup_go/21.go_test_app"),
+            Application::Go23Dup => String::from("tests/apps/dup_go/23.go_test_app"),
+            Application::Go24Dup => String::from("tests/apps/dup_go/24.go_test_app"),
             Application::Go19Read => String::from("tests/apps/read_go/19.go_test_app"),
             Application::Go20Read => String::from("tests/apps/read_go/20.go_test_app"),
             Application::Go21Write => String::from("tests/apps/write_go/21.go_test_app"),
+            Application::Go23Write => String::from("tests/apps/write_go/23.go_test_app"),
+            Application::Go24Write => String::from("tests/apps/write_go/24.go_test_app"),
             Application::Go19Write => String::from("tests/apps/write_go/19.go_test_app"),
             Application::Go20Write => String::from("tests/apps/write_go/20.go_test_app"),
             Application::Go21LSeek => String::from("tests/apps/lseek_go/21.go_test_app"),
+            Application::Go23LSeek => String::from("tests/apps/lseek_go/23.go_test_app"),
+            Application::Go24LSeek => String::from("tests/apps/lseek_go/24.go_test_app"),
             Application::Go19LSeek => String::from("tests/apps/lseek_go/19.go_test_app"),
             Application::Go20LSeek => String::from("tests/apps/lseek_go/20.go_test_app"),
             Application::Go21FAccessAt => String::from("tests/apps/faccessat_go/21.go_test_app"),
+            Application::Go23FAccessAt => String::from("tests/apps/faccessat_go/23.go_test_app"),
+            Application::Go24FAccessAt => String::from("tests/apps/faccessat_go/24.go_test_app"),
             Application::Go19FAccessAt => String::from("tests/apps/faccessat_go/19.go_test_app"),
             Application::Go20FAccessAt => String::from("tests/apps/faccessat_go/20.go_test_app"),
             Application::Go19SelfOpen => String::from("tests/apps/self_open/19.go_test_app"),
@@ -832,6 +872,21 @@ impl Application {
                 println!("using script from {app_path:?}");
                 vec![String::from("-u"), app_path.to_string_lossy().to_string()]
             }
+            Application::PythonOriginalDst => {
+                app_path.push("app_original_dst.py");
+                println!("using script from {app_path:?}");
+                vec![String::from("-u"), app_path.to_string_lossy().to_string()]
+            }
+            Application::PythonDualStackConnect => {
+                app_path.push("dual_stack_connect.py");
+                println!("using script from {app_path:?}");
+                vec![String::from("-u"), app_path.to_string_lossy().to_string()]
+            }
+            Application::PythonFallbackLocal => {
+                app_path.push("fallback_local.py");
+                println!("using script from {app_path:?}");
+                vec![String::from("-u"), app_path.to_string_lossy().to_string()]
+            }
             Application::PythonFastApiHTTP => vec![
                 String::from("--port=9999"),
                 String::from("--host=0.0.0.0"),
@@ -865,20 +920,33 @@ impl Application {
             | Application::Go19FileOps
             | Application::Go20FileOps
             | Application::Go21Issue834
+            | Application::Go23Issue834
+            | Application::Go24Issue834
             | Application::Go20Issue834
             | Application::Go19Issue834
             | Application::Go20Read
             | Application::Go19Read
             | Application::Go21Read
+            | Application::Go23Read
+            | Application::Go24Read
+            | Application::Go21Dup
+            | Application::Go23Dup
+            | Application::Go24Dup
             | Application::Go20Write
             | Application::Go19Write
             | Application::Go21Write
+            | Application::Go23Write
+            | Application::Go24Write
             | Application::Go20LSeek
             | Application::Go19LSeek
             | Application::Go21LSeek
+            | Application::Go23LSeek
+            | Application::Go24LSeek
             | Application::Go20FAccessAt
             | Application::Go19FAccessAt
             | Application::Go21FAccessAt
+            | Application::Go23FAccessAt
+            | Application::Go24FAccessAt
             | Application::Fork
             | Application::Realpath
             | Application::RustFileOps
@@ -928,7 +996,10 @@ impl Application {
             Application::PythonFastApiHTTP => 1234,
             Application::RustIssue1123 => 41222,
             Application::PythonListen => 21232,
+            Application::PythonOriginalDst => 21233,
             Application::PythonDontLoad
+            | Application::PythonDualStackConnect
+            | Application::PythonFallbackLocal
             | Application::RustFileOps
             | Application::RustDnsResolve
             | Application::JavaTemurinSip
@@ -941,18 +1012,31 @@ impl Application {
             | Application::Go20Issue834
             | Application::Go19Issue834
             | Application::Go21Issue834
+            | Application::Go23Issue834
+            | Application::Go24Issue834
             | Application::Go20Read
             | Application::Go19Read
             | Application::Go21Read
+            | Application::Go23Read
+            | Application::Go24Read
+            | Application::Go21Dup
+            | Application::Go23Dup
+            | Application::Go24Dup
             | Application::Go20Write
             | Application::Go19Write
             | Application::Go21Write
+            | Application::Go23Write
+            | Application::Go24Write
             | Application::Go20LSeek
             | Application::Go19LSeek
             | Application::Go21LSeek
+            | Application::Go23LSeek
+            | Application::Go24LSeek
             | Application::Go20FAccessAt
             | Application::Go19FAccessAt
             | Application::Go21FAccessAt
+            | Application::Go23FAccessAt
+            | Application::Go24FAccessAt
             | Application::Go19DirBypass
             | Application::Go20DirBypass
             | Application::Go19SelfOpen
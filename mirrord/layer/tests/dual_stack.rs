@@ -0,0 +1,110 @@
+#![cfg(target_os = "linux")]
+#![feature(assert_matches)]
+#![warn(clippy::indexing_slicing)]
+
+use std::{collections::HashSet, net::SocketAddr, path::PathBuf, time::Duration};
+
+use mirrord_protocol::{
+    dns::{AddressFamily, DnsLookup, GetAddrInfoRequestV2, GetAddrInfoResponse, LookupRecord},
+    outgoing::{
+        tcp::{DaemonTcpOutgoing, LayerTcpOutgoing},
+        DaemonConnect, LayerClose, LayerConnect, SocketAddress,
+    },
+    ClientMessage, DaemonMessage,
+};
+use rstest::rstest;
+
+mod common;
+
+pub use common::*;
+
+/// Start an application that resolves a name to an IPv4 and an IPv6 address and connects to both
+/// at once, and verify that both connections go through the agent.
+#[rstest]
+#[tokio::test]
+#[timeout(Duration::from_secs(60))]
+async fn dual_stack_connect(dylib_path: &PathBuf) {
+    let (mut test_process, mut intproxy) = Application::PythonDualStackConnect
+        .start_process_with_layer(
+            dylib_path,
+            vec![
+                ("MIRRORD_FILE_MODE", "local"),
+                ("MIRRORD_ENABLE_IPV6", "true"),
+            ],
+            None,
+        )
+        .await;
+
+    let msg = intproxy.recv().await;
+    let ClientMessage::GetAddrInfoRequestV2(GetAddrInfoRequestV2 {
+        node,
+        family: AddressFamily::Both,
+    }) = msg
+    else {
+        panic!("Invalid message received from layer: {msg:?}");
+    };
+    assert_eq!(node, "dual-stack.example");
+
+    let peers: [SocketAddr; 2] = [
+        "1.2.3.4:80".parse().unwrap(),
+        "[2001:db8::1]:80".parse().unwrap(),
+    ];
+    intproxy
+        .send(DaemonMessage::GetAddrInfoResponse(GetAddrInfoResponse(Ok(
+            DnsLookup(
+                peers
+                    .iter()
+                    .map(|peer| LookupRecord {
+                        name: node.clone(),
+                        ip: peer.ip(),
+                    })
+                    .collect(),
+            ),
+        ))))
+        .await;
+
+    let mut connected = HashSet::new();
+    for connection_id in 0..peers.len() as u64 {
+        let msg = intproxy.recv().await;
+        let ClientMessage::TcpOutgoing(LayerTcpOutgoing::Connect(LayerConnect {
+            remote_address: SocketAddress::Ip(addr),
+        })) = msg
+        else {
+            panic!("Invalid message received from layer: {msg:?}");
+        };
+        assert!(peers.contains(&addr), "unexpected peer {addr}");
+        assert!(connected.insert(addr), "connected twice to {addr}");
+
+        let local_address: SocketAddr = if addr.is_ipv4() {
+            "4.4.4.4:4444".parse().unwrap()
+        } else {
+            "[2001:db8::2]:4444".parse().unwrap()
+        };
+        intproxy
+            .send(DaemonMessage::TcpOutgoing(DaemonTcpOutgoing::Connect(Ok(
+                DaemonConnect {
+                    connection_id,
+                    remote_address: addr.into(),
+                    local_address: local_address.into(),
+                },
+            ))))
+            .await;
+    }
+
+    loop {
+        match intproxy.try_recv().await {
+            Some(ClientMessage::TcpOutgoing(LayerTcpOutgoing::Close(LayerClose { .. }))) => {}
+            None => break,
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    test_process.wait_assert_success().await;
+    test_process
+        .assert_stdout_contains("connected to 1.2.3.4")
+        .await;
+    test_process
+        .assert_stdout_contains("connected to 2001:db8::1")
+        .await;
+    test_process.assert_no_error_in_stderr().await;
+}
@@ -0,0 +1,67 @@
+#![cfg(target_os = "linux")]
+#![feature(assert_matches)]
+#![warn(clippy::indexing_slicing)]
+
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use mirrord_protocol::{
+    file::{AccessFileRequest, OpenFileRequest, XstatRequest},
+    ClientMessage, DaemonMessage, FileRequest, FileResponse, ResponseError,
+};
+use rstest::rstest;
+
+mod common;
+
+pub use common::*;
+
+/// Start an application that stats, checks and opens a file matched by
+/// `feature.fs.fallback_local`, which doesn't exist in the remote, and verify that every one of
+/// these calls falls back to the local file.
+#[rstest]
+#[tokio::test]
+#[timeout(Duration::from_secs(60))]
+async fn fallback_local(dylib_path: &PathBuf, config_dir: &PathBuf) {
+    let path = PathBuf::from("/tmp/mirrord-fallback-local.txt");
+    std::fs::write(&path, "only here").unwrap();
+
+    let application = Application::PythonFallbackLocal;
+    let (mut test_process, mut intproxy) = application
+        .start_process_with_layer(
+            dylib_path,
+            vec![],
+            Some(config_dir.join("fallback_local.json").to_str().unwrap()),
+        )
+        .await;
+
+    let mut requests = HashSet::new();
+    while let Some(message) = intproxy.try_recv().await {
+        let response = match message {
+            ClientMessage::FileRequest(FileRequest::Xstat(XstatRequest {
+                path: Some(requested),
+                ..
+            })) if requested == path => FileResponse::Xstat(Err(ResponseError::NotFound(0))),
+            ClientMessage::FileRequest(FileRequest::Access(AccessFileRequest {
+                pathname, ..
+            })) if pathname == path => FileResponse::Access(Err(ResponseError::NotFound(0))),
+            ClientMessage::FileRequest(FileRequest::Open(OpenFileRequest {
+                path: requested,
+                ..
+            })) if requested == path => FileResponse::Open(Err(ResponseError::NotFound(0))),
+            other => panic!("unexpected message: {other:?}"),
+        };
+
+        requests.insert(std::mem::discriminant(&response));
+        intproxy.send(DaemonMessage::File(response)).await;
+    }
+
+    test_process.wait_assert_success().await;
+    test_process
+        .assert_stdout_contains("local contents: only here")
+        .await;
+    test_process.assert_no_error_in_stderr().await;
+
+    // The stat, the access check and the open.
+    assert_eq!(requests.len(), 3, "{requests:?}");
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -571,7 +571,13 @@ async fn go_dir_bypass(
 #[tokio::test]
 #[timeout(Duration::from_secs(10))]
 async fn read_go(
-    #[values(Application::Go19Read, Application::Go20Read, Application::Go21Read)]
+    #[values(
+        Application::Go19Read,
+        Application::Go20Read,
+        Application::Go21Read,
+        Application::Go23Read,
+        Application::Go24Read
+    )]
     application: Application,
     dylib_path: &PathBuf,
 ) {
@@ -606,12 +612,53 @@ async fn read_go(
     test_process.assert_no_error_in_stderr().await;
 }
 
+/// Test go dups of a remote file fd, with `SYS_dup`, `SYS_dup2`/`SYS_dup3` and
+/// `fcntl(F_DUPFD_CLOEXEC)`.
+/// The app closes every fd once it made the next copy, so the file must only be closed in the agent
+/// after the last copy is read and closed. Waits for the signal after the close, like [`read_go`].
+#[rstest]
+#[tokio::test]
+#[timeout(Duration::from_secs(10))]
+async fn dup_go(
+    #[values(Application::Go21Dup, Application::Go23Dup, Application::Go24Dup)]
+    application: Application,
+    dylib_path: &PathBuf,
+) {
+    let (mut test_process, mut intproxy) = application
+        .start_process_with_layer(dylib_path, vec![("MIRRORD_FILE_MODE", "read")], None)
+        .await;
+
+    let fd = 1;
+    intproxy
+        .expect_file_open_for_reading("/app/test.txt", fd)
+        .await;
+
+    // The app reads once, with a buffer of the size of the contents.
+    intproxy.expect_single_file_read("Pineapples.", fd).await;
+
+    intproxy.expect_file_close(fd).await;
+    signal::kill(
+        Pid::from_raw(test_process.child.id().unwrap() as pid_t),
+        Signal::SIGTERM,
+    )
+    .unwrap();
+
+    test_process.wait_assert_success().await;
+    test_process.assert_no_error_in_stderr().await;
+}
+
 /// Test go file write.
 #[rstest]
 #[tokio::test]
 #[timeout(Duration::from_secs(10))]
 async fn write_go(
-    #[values(Application::Go19Write, Application::Go20Write, Application::Go21Write)]
+    #[values(
+        Application::Go19Write,
+        Application::Go20Write,
+        Application::Go21Write,
+        Application::Go23Write,
+        Application::Go24Write
+    )]
     application: Application,
     dylib_path: &PathBuf,
 ) {
@@ -638,7 +685,13 @@ async fn write_go(
 #[tokio::test]
 #[timeout(Duration::from_secs(10))]
 async fn lseek_go(
-    #[values(Application::Go19LSeek, Application::Go20LSeek, Application::Go21LSeek)]
+    #[values(
+        Application::Go19LSeek,
+        Application::Go20LSeek,
+        Application::Go21LSeek,
+        Application::Go23LSeek,
+        Application::Go24LSeek
+    )]
     application: Application,
     dylib_path: &PathBuf,
 ) {
@@ -670,7 +723,9 @@ async fn faccessat_go(
     #[values(
         Application::Go19FAccessAt,
         Application::Go20FAccessAt,
-        Application::Go21FAccessAt
+        Application::Go21FAccessAt,
+        Application::Go23FAccessAt,
+        Application::Go24FAccessAt
     )]
     application: Application,
     dylib_path: &PathBuf,
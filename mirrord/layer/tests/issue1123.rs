@@ -25,6 +25,7 @@ async fn test_issue1123(
             vec![
                 ("RUST_LOG", "mirrord=trace"),
                 ("MIRRORD_FILE_MODE", "local"),
+                ("MIRRORD_INCOMING_INTERCEPT_LOOPBACK", "true"),
                 ("MIRRORD_UDP_OUTGOING", "false"),
                 ("MIRRORD_REMOTE_DNS", "false"),
             ],
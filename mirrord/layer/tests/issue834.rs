@@ -18,7 +18,9 @@ async fn test_issue834(
     #[values(
         Application::Go19Issue834,
         Application::Go20Issue834,
-        Application::Go21Issue834
+        Application::Go21Issue834,
+        Application::Go23Issue834,
+        Application::Go24Issue834
     )]
     application: Application,
     dylib_path: &PathBuf,
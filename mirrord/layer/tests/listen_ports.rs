@@ -34,6 +34,7 @@ async fn listen_ports(
             vec![
                 ("RUST_LOG", "mirrord=trace"),
                 ("MIRRORD_FILE_MODE", "local"),
+                ("MIRRORD_INCOMING_INTERCEPT_LOOPBACK", "true"),
             ],
             Some(config_path.to_str().unwrap()),
         )
@@ -76,3 +77,60 @@ async fn listen_ports(
     test_process.assert_no_error_in_stderr().await;
     test_process.assert_no_error_in_stdout().await;
 }
+
+/// Start an application (and load the layer into it) that listens on two loopback ports, only one
+/// of which is listed in `listen_ports`, and verify that the other one remains local.
+#[rstest]
+#[tokio::test]
+#[timeout(Duration::from_secs(60))]
+async fn listen_ports_unlisted_loopback(
+    #[values(Application::RustListenPorts)] application: Application,
+    dylib_path: &PathBuf,
+    config_dir: &PathBuf,
+) {
+    let mut config_path = config_dir.clone();
+    config_path.push("listen_ports.json");
+    let (mut test_process, mut intproxy) = application
+        .start_process_with_layer(
+            dylib_path,
+            vec![
+                ("RUST_LOG", "mirrord=trace"),
+                ("MIRRORD_FILE_MODE", "local"),
+            ],
+            Some(config_path.to_str().unwrap()),
+        )
+        .await;
+
+    assert_matches!(
+        intproxy.recv().await,
+        ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(StealType::All(80)))
+    );
+    intproxy
+        .send(DaemonMessage::TcpSteal(DaemonTcp::SubscribeResult(Ok(80))))
+        .await;
+    let mut stream = TcpStream::connect("127.0.0.1:51222").await.unwrap();
+    println!("connected to listener at port 51222");
+    stream.write_all(b"HELLO").await.unwrap();
+
+    // Port 40000 is not listed, so the application binds it without the layer.
+    let mut stream = loop {
+        match TcpStream::connect("127.0.0.1:40000").await {
+            Ok(stream) => break stream,
+            Err(..) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    };
+    println!("connected to listener at port 40000");
+    stream.write_all(b"HELLO").await.unwrap();
+
+    loop {
+        match intproxy.try_recv().await {
+            Some(ClientMessage::TcpSteal(LayerTcpSteal::PortUnsubscribe(80))) => {}
+            None => break,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!(intproxy.try_recv().await, None);
+    test_process.wait_assert_success().await;
+    test_process.assert_no_error_in_stderr().await;
+    test_process.assert_no_error_in_stdout().await;
+}
@@ -0,0 +1,78 @@
+#![cfg(target_os = "linux")]
+#![feature(assert_matches)]
+#![warn(clippy::indexing_slicing)]
+
+use std::{assert_matches::assert_matches, path::PathBuf, time::Duration};
+
+use mirrord_protocol::{
+    tcp::{DaemonTcp, LayerTcpSteal, NewTcpConnection, NewTcpConnectionV2, StealType},
+    ClientMessage, DaemonMessage,
+};
+use rstest::rstest;
+
+mod common;
+
+pub use common::*;
+
+/// Start an application that accepts a stolen connection and verify that
+/// `getsockopt(SO_ORIGINAL_DST)` returns the original destination sent by the agent, not the
+/// address of the intproxy.
+#[rstest]
+#[tokio::test]
+#[timeout(Duration::from_secs(60))]
+async fn original_dst(dylib_path: &PathBuf) {
+    let application = Application::PythonOriginalDst;
+    let port = application.get_app_port();
+    let (mut test_process, mut intproxy) = application
+        .start_process_with_layer(
+            dylib_path,
+            vec![
+                ("MIRRORD_FILE_MODE", "local"),
+                ("MIRRORD_AGENT_TCP_STEAL_TRAFFIC", "true"),
+            ],
+            None,
+        )
+        .await;
+
+    assert_matches!(
+        intproxy.recv().await,
+        ClientMessage::TcpSteal(LayerTcpSteal::PortSubscribe(StealType::All(subscribed)))
+            if subscribed == port
+    );
+    intproxy
+        .send(DaemonMessage::TcpSteal(DaemonTcp::SubscribeResult(Ok(
+            port,
+        ))))
+        .await;
+
+    intproxy
+        .send(DaemonMessage::TcpSteal(DaemonTcp::NewConnectionV2(
+            NewTcpConnectionV2 {
+                connection: NewTcpConnection {
+                    connection_id: 0,
+                    remote_address: "2.2.2.2".parse().unwrap(),
+                    destination_port: port,
+                    source_port: 31415,
+                    local_address: "1.1.1.1".parse().unwrap(),
+                },
+                original_destination: "10.0.0.7:8080".parse().unwrap(),
+            },
+        )))
+        .await;
+
+    loop {
+        match intproxy.try_recv().await {
+            Some(ClientMessage::TcpSteal(LayerTcpSteal::ConnectionUnsubscribe(0))) => {}
+            Some(ClientMessage::TcpSteal(LayerTcpSteal::PortUnsubscribe(unsubscribed)))
+                if unsubscribed == port => {}
+            None => break,
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    test_process.wait_assert_success().await;
+    test_process
+        .assert_stdout_contains("original destination: 10.0.0.7:8080")
+        .await;
+    test_process.assert_no_error_in_stderr().await;
+}
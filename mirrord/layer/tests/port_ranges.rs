@@ -0,0 +1,58 @@
+#![cfg(target_os = "linux")]
+#![feature(assert_matches)]
+#![warn(clippy::indexing_slicing)]
+
+use std::{path::PathBuf, time::Duration};
+
+use mirrord_protocol::{
+    tcp::{DaemonTcp, LayerTcp},
+    ClientMessage, DaemonMessage,
+};
+use rstest::rstest;
+
+mod common;
+
+pub use common::*;
+
+/// Start an application that listens on a port inside a range from
+/// `feature.network.incoming.ports`, and verify that the port is subscribed.
+#[rstest]
+#[tokio::test]
+#[timeout(Duration::from_secs(60))]
+async fn port_in_range(
+    #[values(Application::PythonListen)] application: Application,
+    dylib_path: &PathBuf,
+    config_dir: &PathBuf,
+) {
+    let mut config_path = config_dir.clone();
+    config_path.push("port_ranges.json");
+    let (mut test_process, mut intproxy) = application
+        .start_process_with_layer(
+            dylib_path,
+            vec![("MIRRORD_FILE_MODE", "local")],
+            Some(config_path.to_str().unwrap()),
+        )
+        .await;
+
+    let port = application.get_app_port();
+    assert_eq!(
+        intproxy.recv().await,
+        ClientMessage::Tcp(LayerTcp::PortSubscribe(port))
+    );
+    intproxy
+        .send(DaemonMessage::Tcp(DaemonTcp::SubscribeResult(Ok(port))))
+        .await;
+
+    loop {
+        match intproxy.try_recv().await {
+            Some(ClientMessage::Tcp(LayerTcp::PortUnsubscribe(unsubscribed)))
+                if unsubscribed == port => {}
+            None => break,
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    test_process.wait_assert_success().await;
+    test_process.assert_no_error_in_stderr().await;
+    test_process.assert_no_error_in_stdout().await;
+}
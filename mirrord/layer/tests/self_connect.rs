@@ -18,7 +18,14 @@ use mirrord_protocol::tcp::LayerTcp;
 async fn self_connect(dylib_path: &PathBuf) {
     let application = Application::PythonSelfConnect;
     let (mut test_process, mut intproxy) = application
-        .start_process_with_layer_and_port(dylib_path, vec![("MIRRORD_FILE_MODE", "local")], None)
+        .start_process_with_layer_and_port(
+            dylib_path,
+            vec![
+                ("MIRRORD_FILE_MODE", "local"),
+                ("MIRRORD_INCOMING_INTERCEPT_LOOPBACK", "true"),
+            ],
+            None,
+        )
         .await;
     match intproxy.try_recv().await {
         // Accepting both a PortUnsubscribe and a hangup without it, so that this test does not
@@ -1,41 +1,57 @@
 use std::{
     fmt::{self, Display},
-    io,
+    future::Future,
+    io::{self, Read, Write},
+    time::Duration,
 };
 
 use base64::{engine::general_purpose, Engine as _};
 use chrono::{DateTime, Utc};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use futures::{SinkExt, StreamExt};
 use http::request::Request;
-use kube::{api::PostParams, Api, Client, Resource};
+use kube::{
+    api::PostParams, client::UpgradeConnectionError, core::ErrorResponse, Api, Client, Resource,
+};
 use mirrord_analytics::{AnalyticsHash, AnalyticsOperatorProperties, Reporter};
 use mirrord_auth::{
+    cert_provider::CertificateProvider,
     certificate::Certificate,
     credential_store::{CredentialStoreSync, UserIdentity},
     credentials::LicenseValidity,
     error::AuthenticationError,
 };
 use mirrord_config::{
-    feature::network::incoming::ConcurrentSteal,
+    feature::{
+        network::incoming::ConcurrentSteal,
+        split_queues::{KafkaTopicConfig, SplitQueuesConfig, SqsQueueConfig},
+    },
     target::{Target, TargetConfig},
     LayerConfig,
 };
 use mirrord_kube::{
-    api::kubernetes::{create_kube_api, get_k8s_resource_api},
+    api::{
+        credentials::{is_unauthorized, KubeClientSettings, RefreshingClient},
+        kubernetes::{create_kube_api, get_k8s_resource_api},
+    },
     error::KubeApiError,
 };
-use mirrord_progress::Progress;
+use mirrord_progress::{NullProgress, Progress};
 use mirrord_protocol::{ClientMessage, DaemonMessage};
+use rand::Rng;
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    time::{Instant, MissedTickBehavior},
+};
 use tokio_tungstenite::tungstenite::{Error as TungsteniteError, Message};
 use tracing::{debug, error, info, warn};
 
 use crate::crd::{
-    CopyTargetCrd, CopyTargetSpec, MirrordOperatorCrd, OperatorFeatures, SessionCrd, TargetCrd,
-    OPERATOR_STATUS_NAME,
+    CopyTargetCrd, CopyTargetSpec, LicenseUsageCrd, LicenseUsageSpec, MirrordOperatorCrd,
+    OperatorFeatures, SessionCrd, TargetCrd, OPERATOR_STATUS_NAME,
 };
 
 static CONNECTION_CHANNEL_SIZE: usize = 1000;
@@ -50,6 +66,7 @@ pub enum OperatorOperation {
     WebsocketConnection,
     CopyingTarget,
     GettingStatus,
+    GettingLicenseUsage,
     SessionManagement,
 }
 
@@ -61,6 +78,7 @@ impl Display for OperatorOperation {
             Self::WebsocketConnection => "creating a websocket connection",
             Self::CopyingTarget => "copying target",
             Self::GettingStatus => "getting status",
+            Self::GettingLicenseUsage => "getting license usage",
             Self::SessionManagement => "session management",
         };
 
@@ -68,6 +86,74 @@ impl Display for OperatorOperation {
     }
 }
 
+/// Known categories of failures reported by the operator in its [`ErrorResponse`]s.
+///
+/// Allows us to suggest a concrete action to the user, instead of just displaying the raw
+/// [`kube::Error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperatorFailureKind {
+    /// The operator license has expired.
+    LicenseExpired,
+    /// All seats of the operator license are already taken.
+    SeatLimitReached,
+    /// The target (or the requested feature) is blocked by a `MirrordPolicy`.
+    BlockedByPolicy,
+    /// The operator does not support this version of the mirrord CLI/plugin.
+    VersionMismatch,
+}
+
+impl OperatorFailureKind {
+    /// Categorizes an [`ErrorResponse`] returned by the operator, based on its `reason` and
+    /// `code`. Returns [`None`] if this is not an error we know how to handle.
+    ///
+    /// The `message` is meant for people and can change, so it's never matched.
+    pub fn from_response(response: &ErrorResponse) -> Option<Self> {
+        match (response.code, response.reason.as_str()) {
+            (_, "LicenseExpired") | (402, _) => Some(Self::LicenseExpired),
+            (_, "SeatLimitReached") => Some(Self::SeatLimitReached),
+            (_, "BlockedByPolicy") => Some(Self::BlockedByPolicy),
+            (_, "UnsupportedClientVersion") | (426, _) => Some(Self::VersionMismatch),
+            _ => None,
+        }
+    }
+
+    /// Suggested action the user can take to recover from this failure.
+    pub fn remediation(self) -> &'static str {
+        match self {
+            Self::LicenseExpired => {
+                "Visit https://app.metalbear.co to renew your mirrord for Teams license, \
+                or set `\"operator\": false` in the mirrord config to continue without the operator."
+            }
+            Self::SeatLimitReached => {
+                "Ask your mirrord for Teams administrator to free up or add seats at \
+                https://app.metalbear.co, or try again once another user has finished their session."
+            }
+            Self::BlockedByPolicy => {
+                "Choose a different target, or ask your cluster administrator to review the \
+                `MirrordPolicy` resources in the target's namespace \
+                (`kubectl get mirrordpolicies -n <namespace>`)."
+            }
+            Self::VersionMismatch => {
+                "Update your mirrord CLI/plugin to match the version of the operator, or ask your \
+                cluster administrator to upgrade the operator."
+            }
+        }
+    }
+}
+
+impl Display for OperatorFailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let as_str = match self {
+            Self::LicenseExpired => "operator license has expired",
+            Self::SeatLimitReached => "operator license seat limit was reached",
+            Self::BlockedByPolicy => "blocked by a mirrord policy",
+            Self::VersionMismatch => "operator does not support this mirrord version",
+        };
+
+        f.write_str(as_str)
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum OperatorApiError {
     #[error("invalid target: {reason}")]
@@ -76,6 +162,9 @@ pub enum OperatorApiError {
     #[error("failed to build a websocket connect request: {0}")]
     ConnectRequestBuildError(HttpError),
 
+    #[error("failed to serialize queue splitting config: {0}")]
+    SplitQueuesSerializeError(serde_json::Error),
+
     #[error("failed to create mirrord operator API: {0}")]
     CreateApiError(KubeApiError),
 
@@ -105,10 +194,135 @@ pub enum OperatorApiError {
 
     #[error("Operator has expired license, falling back to OSS usage.")]
     NoLicense,
+
+    #[error("failed to get the client certificate from `auth.cert_provider_command`: {0}")]
+    CertificateProvider(AuthenticationError),
+
+    #[error("{operation} failed, {kind}: {message}")]
+    OperatorFailure {
+        kind: OperatorFailureKind,
+        message: String,
+        operation: OperatorOperation,
+    },
+}
+
+impl OperatorApiError {
+    /// Wraps a [`kube::Error`] that occurred during the given [`OperatorOperation`].
+    ///
+    /// [`ErrorResponse`]s that match an [`OperatorFailureKind`] become
+    /// [`OperatorApiError::OperatorFailure`], everything else becomes
+    /// [`OperatorApiError::KubeError`].
+    pub fn from_kube_error(error: kube::Error, operation: OperatorOperation) -> Self {
+        match &error {
+            kube::Error::Api(response) => match OperatorFailureKind::from_response(response) {
+                Some(kind) => Self::OperatorFailure {
+                    kind,
+                    message: response.message.clone(),
+                    operation,
+                },
+                None => Self::KubeError { error, operation },
+            },
+            _ => Self::KubeError { error, operation },
+        }
+    }
 }
 
 type Result<T, E = OperatorApiError> = std::result::Result<T, E>;
 
+/// Returns whether the API server rejected the request because it's overloaded, e.g. due to its
+/// API priority and fairness limits.
+fn is_throttled(error: &kube::Error) -> bool {
+    let code = match error {
+        kube::Error::Api(response) => response.code,
+        kube::Error::UpgradeConnection(UpgradeConnectionError::ProtocolSwitch(status)) => {
+            status.as_u16()
+        }
+        _ => return false,
+    };
+
+    matches!(code, 429 | 503)
+}
+
+/// Jittered exponential backoff used when retrying throttled requests, see [`retry_throttled`].
+#[derive(Debug, Default)]
+struct ThrottleBackoff {
+    attempt: u32,
+}
+
+impl ThrottleBackoff {
+    const MAX_ATTEMPTS: u32 = 8;
+
+    /// The API server sends `Retry-After: 1` with its throttling responses. [`kube`] does not
+    /// expose response headers in its errors, so we never wait less than that.
+    const MIN_DELAY: Duration = Duration::from_secs(1);
+
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+
+    /// Returns how long to wait before the next attempt, or [`None`] if we should give up.
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= Self::MAX_ATTEMPTS {
+            return None;
+        }
+
+        let delay = Self::MIN_DELAY
+            .saturating_mul(1 << self.attempt)
+            .min(Self::MAX_DELAY);
+        self.attempt += 1;
+
+        // Spread retries from multiple users, so that they don't hit the API server all at once.
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+
+        Some(delay.mul_f64(jitter).max(Self::MIN_DELAY))
+    }
+}
+
+/// Runs the `request`, retrying it with a [`ThrottleBackoff`] while the API server is throttling
+/// us (see [`is_throttled`]).
+///
+/// Reports the wait with a `progress` subtask, so that the user doesn't think mirrord hangs. The
+/// API server doesn't tell where the request is in its queues, so only the wait is reported.
+async fn retry_throttled<T, F, Fut, P>(progress: &P, mut request: F) -> Result<T, kube::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, kube::Error>>,
+    P: Progress,
+{
+    let mut backoff = ThrottleBackoff::default();
+    let mut waiting_progress: Option<P> = None;
+
+    loop {
+        match request().await {
+            Err(error) if is_throttled(&error) => {
+                let Some(delay) = backoff.next_delay() else {
+                    if let Some(mut waiting_progress) = waiting_progress {
+                        waiting_progress.failure(Some("API server is still busy, giving up"));
+                    }
+
+                    return Err(error);
+                };
+
+                warn!(%error, ?delay, "API server is throttling requests, retrying");
+
+                let waiting_progress = waiting_progress
+                    .get_or_insert_with(|| progress.subtask("waiting for API server capacity"));
+                waiting_progress.info(&format!(
+                    "API server is busy, retrying in {}s",
+                    delay.as_secs_f64().ceil(),
+                ));
+
+                tokio::time::sleep(delay).await;
+            }
+            result => {
+                if let Some(mut waiting_progress) = waiting_progress {
+                    waiting_progress.success(Some("API server capacity available"));
+                }
+
+                return result;
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OperatorSessionMetadata {
     client_certificate: Option<Certificate>,
@@ -117,15 +331,28 @@ pub struct OperatorSessionMetadata {
     operator_features: Vec<OperatorFeatures>,
     protocol_version: Option<semver::Version>,
     copy_pod_enabled: Option<bool>,
+    /// SQS queues the user wants to split (`feature.split_queues.sqs`).
+    #[serde(default)]
+    sqs_splits: Vec<SqsQueueConfig>,
+    /// Kafka topics the user wants to split (`feature.split_queues.kafka`).
+    #[serde(default)]
+    kafka_splits: Vec<KafkaTopicConfig>,
 }
 
 impl OperatorSessionMetadata {
+    /// Name of the session request header that carries the json encoded [`SqsQueueConfig`]s.
+    const SQS_SPLITS_HEADER: &'static str = "x-split-queues-sqs";
+
+    /// Name of the session request header that carries the json encoded [`KafkaTopicConfig`]s.
+    const KAFKA_SPLITS_HEADER: &'static str = "x-split-queues-kafka";
+
     fn new(
         client_certificate: Option<Certificate>,
         fingerprint: Option<String>,
         operator_features: Vec<OperatorFeatures>,
         protocol_version: Option<semver::Version>,
         copy_pod_enabled: Option<bool>,
+        split_queues: &SplitQueuesConfig,
     ) -> Self {
         Self {
             client_certificate,
@@ -134,9 +361,30 @@ impl OperatorSessionMetadata {
             operator_features,
             protocol_version,
             copy_pod_enabled,
+            sqs_splits: split_queues.sqs.clone(),
+            kafka_splits: split_queues.kafka.clone(),
         }
     }
 
+    /// Returns the session request headers describing the queues the user wants to split.
+    fn split_queues_headers(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut headers = Vec::new();
+
+        if !self.sqs_splits.is_empty() {
+            let sqs_splits = serde_json::to_string(&self.sqs_splits)
+                .map_err(OperatorApiError::SplitQueuesSerializeError)?;
+            headers.push((Self::SQS_SPLITS_HEADER, sqs_splits));
+        }
+
+        if !self.kafka_splits.is_empty() {
+            let kafka_splits = serde_json::to_string(&self.kafka_splits)
+                .map_err(OperatorApiError::SplitQueuesSerializeError)?;
+            headers.push((Self::KAFKA_SPLITS_HEADER, kafka_splits));
+        }
+
+        Ok(headers)
+    }
+
     fn client_credentials(&self) -> io::Result<Option<String>> {
         self.client_certificate
             .as_ref()
@@ -164,6 +412,17 @@ impl OperatorSessionMetadata {
     fn proxy_feature_enabled(&self) -> bool {
         self.operator_features.contains(&OperatorFeatures::ProxyApi)
     }
+
+    fn compression(&self) -> MessageCompression {
+        if self
+            .operator_features
+            .contains(&OperatorFeatures::MessageCompression)
+        {
+            MessageCompression::Deflate
+        } else {
+            MessageCompression::Disabled
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -180,11 +439,21 @@ pub struct OperatorSessionInformation {
 
 pub struct OperatorApi {
     client: Client,
+    /// Used to dial the operator websocket again with fresh credentials, when the ones in
+    /// [`Self::client`] are rejected.
+    credentials: RefreshingClient,
     target_api: Api<TargetCrd>,
     copy_target_api: Api<CopyTargetCrd>,
     target_namespace: Option<String>,
     target_config: TargetConfig,
     on_concurrent_steal: ConcurrentSteal,
+    /// How often to ping an idle operator connection, see [`ConnectionWrapper`].
+    keepalive_interval: Option<Duration>,
+    /// Issues the client certificates instead of the operator, from
+    /// [`AuthConfig::cert_provider_command`](mirrord_config::auth::AuthConfig::cert_provider_command).
+    cert_provider: Option<CertificateProvider>,
+    /// Whether the client certificates are kept in the OS keychain, see [`CredentialStoreSync`].
+    credentials_keychain: bool,
 }
 
 /// Connection to existing operator session.
@@ -199,13 +468,61 @@ pub struct OperatorSessionConnection {
 
 /// Allows us to access the operator's [`SessionCrd`] [`Api`].
 pub async fn session_api(config: Option<String>) -> Result<Api<SessionCrd>> {
-    let kube_api: Client = create_kube_api(false, config, None)
+    let kube_api: Client = create_kube_api(false, config, None, None, None)
         .await
         .map_err(OperatorApiError::CreateApiError)?;
 
     Ok(Api::all(kube_api))
 }
 
+/// Fetches the [`LicenseUsageSpec`] of the given operator.
+///
+/// Returns [`None`] if the operator does not support [`OperatorFeatures::LicenseUsage`].
+pub async fn license_usage(
+    client: Client,
+    operator: &MirrordOperatorCrd,
+) -> Result<Option<LicenseUsageSpec>> {
+    let supported = operator
+        .spec
+        .features
+        .as_deref()
+        .unwrap_or_default()
+        .contains(&OperatorFeatures::LicenseUsage);
+    if !supported {
+        return Ok(None);
+    }
+
+    let api: Api<LicenseUsageCrd> = Api::all(client);
+    api.get(OPERATOR_STATUS_NAME)
+        .await
+        .map(|usage| Some(usage.spec))
+        .map_err(|error| {
+            OperatorApiError::from_kube_error(error, OperatorOperation::GettingLicenseUsage)
+        })
+}
+
+/// Returns a warning for the user if the license seat limit is nearly reached, that is when at
+/// most 10% of the seats (but at least one) are left.
+pub fn seat_limit_warning(usage: &LicenseUsageSpec) -> Option<String> {
+    let seats_total = usage.seats_total?;
+    let seats_left = seats_total.saturating_sub(usage.seats_used);
+
+    if seats_left > (seats_total / 10).max(1) {
+        return None;
+    }
+
+    let message = if seats_left == 0 {
+        format!("All {seats_total} operator license seats are taken, new sessions may be rejected.")
+    } else {
+        format!(
+            "Operator license seat limit is nearly reached, {seats_left} of {seats_total} seat{} left.",
+            if seats_left > 1 { "s" } else { "" }
+        )
+    };
+
+    Some(message)
+}
+
 impl OperatorApi {
     /// We allow copied pods to live only for 30 seconds before the internal proxy connects.
     const COPIED_POD_IDLE_TTL: u32 = 30;
@@ -220,6 +537,49 @@ impl OperatorApi {
             });
         }
 
+        let split_queues = &config.feature.split_queues;
+        let features = operator.spec.features.as_deref().unwrap_or_default();
+        let unsupported_split = if !split_queues.sqs.is_empty()
+            && !features.contains(&OperatorFeatures::SqsQueueSplitting)
+        {
+            Some("SQS queue splitting")
+        } else if !split_queues.kafka.is_empty()
+            && !features.contains(&OperatorFeatures::KafkaTopicSplitting)
+        {
+            Some("Kafka topic splitting")
+        } else {
+            None
+        };
+
+        if let Some(feature) = unsupported_split {
+            return Err(OperatorApiError::UnsupportedFeature {
+                feature: feature.into(),
+                operator_version: operator.spec.operator_version.clone(),
+            });
+        }
+
+        // The operator picks the target container and its environment on its own.
+        let unsupported_container = if config.target.container_index.is_some() {
+            Some("target.container_index")
+        } else if config
+            .feature
+            .env
+            .containers
+            .as_ref()
+            .is_some_and(|containers| !containers.is_empty())
+        {
+            Some("feature.env.containers")
+        } else {
+            None
+        };
+
+        if let Some(feature) = unsupported_container {
+            return Err(OperatorApiError::UnsupportedFeature {
+                feature: feature.into(),
+                operator_version: operator.spec.operator_version.clone(),
+            });
+        }
+
         Ok(())
     }
 
@@ -234,7 +594,14 @@ impl OperatorApi {
 
         let subscription_id = operator.spec.license.subscription_id.clone();
 
-        let mut credential_store = CredentialStoreSync::open().await?;
+        if let Some(cert_provider) = &api.cert_provider {
+            return cert_provider
+                .get_credentials(&fingerprint, subscription_id.as_deref())
+                .await
+                .map(|credentials| Some(credentials.as_ref().clone()));
+        }
+
+        let mut credential_store = CredentialStoreSync::open(api.credentials_keychain).await?;
         credential_store
             .get_client_certificate::<MirrordOperatorCrd>(&api.client, fingerprint, subscription_id)
             .await
@@ -257,7 +624,7 @@ impl OperatorApi {
     {
         let operator_api = OperatorApi::new(config).await?;
 
-        let operator = operator_api.fetch_operator().await?;
+        let operator = operator_api.fetch_operator(progress).await?;
 
         // Warns the user if their license is close to expiring or fallback to OSS if expired
         if let Some(days_until_expiration) =
@@ -294,12 +661,30 @@ impl OperatorApi {
             return Err(OperatorApiError::NoLicense);
         }
 
+        match license_usage(operator_api.client.clone(), &operator).await {
+            Ok(usage) => {
+                if let Some(seats_message) = usage.as_ref().and_then(seat_limit_warning) {
+                    progress.warning(&seats_message);
+                    warn!(seats_message);
+                }
+            }
+            Err(error) => debug!(%error, "failed to fetch operator license usage"),
+        }
+
         Self::check_config(config, &operator)?;
 
-        let client_certificate = Self::get_client_certificate(&operator_api, &operator)
-            .await
-            .ok()
-            .flatten();
+        let client_certificate = match Self::get_client_certificate(&operator_api, &operator).await
+        {
+            Ok(certificate) => certificate,
+            // The user wants the certificates of their own PKI, don't go on without one.
+            Err(error) if operator_api.cert_provider.is_some() => {
+                return Err(OperatorApiError::CertificateProvider(error));
+            }
+            Err(error) => {
+                warn!(%error, "failed to get the client certificate");
+                None
+            }
+        };
         let metadata = OperatorSessionMetadata::new(
             client_certificate,
             operator.spec.license.fingerprint,
@@ -309,6 +694,7 @@ impl OperatorApi {
                 .protocol_version
                 .and_then(|str_version| str_version.parse().ok()),
             operator.spec.copy_target_enabled,
+            &config.feature.split_queues,
         );
 
         metadata.set_operator_properties(analytics);
@@ -337,6 +723,7 @@ impl OperatorApi {
             let mut copy_progress = progress.subtask("copying target");
             let copied = operator_api
                 .copy_target(
+                    progress,
                     &metadata,
                     config.target.path.clone().unwrap_or(Target::Targetless),
                     config.feature.copy_target.scale_down,
@@ -346,7 +733,7 @@ impl OperatorApi {
 
             OperatorSessionTarget::Copied(copied)
         } else {
-            let raw_target = operator_api.fetch_target().await?;
+            let raw_target = operator_api.fetch_target(progress).await?;
             OperatorSessionTarget::Raw(raw_target)
         };
 
@@ -354,7 +741,7 @@ impl OperatorApi {
             target: target_to_connect,
             metadata,
         };
-        let connection = operator_api.connect_target(session_info).await?;
+        let connection = operator_api.connect_target(progress, session_info).await?;
 
         Ok(connection)
     }
@@ -371,20 +758,32 @@ impl OperatorApi {
             .set_operator_properties(analytics);
 
         let operator_api = OperatorApi::new(config).await?;
-        operator_api.connect_target(session_information).await
+        operator_api
+            .connect_target(&NullProgress, session_information)
+            .await
     }
 
     async fn new(config: &LayerConfig) -> Result<Self> {
         let target_config = config.target.clone();
         let on_concurrent_steal = config.feature.network.incoming.on_concurrent_steal;
-
-        let client = create_kube_api(
-            config.accept_invalid_certificates,
-            config.kubeconfig.clone(),
-            config.kube_context.clone(),
-        )
-        .await
-        .map_err(OperatorApiError::CreateApiError)?;
+        let keepalive_interval = match config.internal_proxy.operator_keepalive_interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+        let cert_provider = config
+            .auth
+            .cert_provider_command
+            .clone()
+            .map(CertificateProvider::new);
+        let credentials_keychain = config.auth.keychain;
+
+        let credentials = RefreshingClient::new(KubeClientSettings::for_agent(config))
+            .await
+            .map_err(OperatorApiError::CreateApiError)?;
+        let client = credentials
+            .client()
+            .await
+            .map_err(OperatorApiError::CreateApiError)?;
 
         let target_namespace = if target_config.path.is_some() {
             target_config.namespace.clone()
@@ -400,35 +799,36 @@ impl OperatorApi {
 
         Ok(OperatorApi {
             client,
+            credentials,
             target_api,
             copy_target_api,
             target_namespace,
             target_config,
             on_concurrent_steal,
+            keepalive_interval,
+            cert_provider,
+            credentials_keychain,
         })
     }
 
-    #[tracing::instrument(level = "trace", skip(self), ret)]
-    async fn fetch_operator(&self) -> Result<MirrordOperatorCrd> {
+    #[tracing::instrument(level = "trace", skip(self, progress), ret)]
+    async fn fetch_operator<P: Progress>(&self, progress: &P) -> Result<MirrordOperatorCrd> {
         let api: Api<MirrordOperatorCrd> = Api::all(self.client.clone());
-        api.get(OPERATOR_STATUS_NAME)
+        retry_throttled(progress, || api.get(OPERATOR_STATUS_NAME))
             .await
-            .map_err(|error| OperatorApiError::KubeError {
-                error,
-                operation: OperatorOperation::FindingOperator,
+            .map_err(|error| {
+                OperatorApiError::from_kube_error(error, OperatorOperation::FindingOperator)
             })
     }
 
     /// See `operator/controller/src/target.rs::TargetProvider::get_resource`.
-    #[tracing::instrument(level = "trace", fields(self.target_config), skip(self))]
-    async fn fetch_target(&self) -> Result<TargetCrd> {
+    #[tracing::instrument(level = "trace", fields(self.target_config), skip(self, progress))]
+    async fn fetch_target<P: Progress>(&self, progress: &P) -> Result<TargetCrd> {
         let target_name = TargetCrd::target_name_by_config(&self.target_config);
-        self.target_api
-            .get(&target_name)
+        retry_throttled(progress, || self.target_api.get(&target_name))
             .await
-            .map_err(|error| OperatorApiError::KubeError {
-                error,
-                operation: OperatorOperation::FindingTarget,
+            .map_err(|error| {
+                OperatorApiError::from_kube_error(error, OperatorOperation::FindingTarget)
             })
     }
 
@@ -518,10 +918,53 @@ impl OperatorApi {
         }
     }
 
+    /// Builds the request used to create a websocket connection to the operator.
+    fn connect_request(
+        &self,
+        session_info: &OperatorSessionInformation,
+    ) -> Result<Request<Vec<u8>>> {
+        let UserIdentity { name, hostname } = UserIdentity::load();
+
+        let mut builder = Request::builder()
+            .uri(self.connect_url(session_info))
+            .header("x-session-id", session_info.metadata.session_id.to_string());
+
+        if let Some(name) = name {
+            builder = builder.header("x-client-name", name);
+        };
+
+        if let Some(hostname) = hostname {
+            builder = builder.header("x-client-hostname", hostname);
+        };
+
+        if let Some(compression) = session_info.metadata.compression().header_value() {
+            builder = builder.header(MessageCompression::HEADER, compression);
+        }
+
+        for (name, value) in session_info.metadata.split_queues_headers()? {
+            builder = builder.header(name, value);
+        }
+
+        match session_info.metadata.client_credentials() {
+            Ok(Some(credentials)) => {
+                builder = builder.header("x-client-der", credentials);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                debug!("CredentialStore error: {err}");
+            }
+        }
+
+        builder
+            .body(vec![])
+            .map_err(OperatorApiError::ConnectRequestBuildError)
+    }
+
     /// Create websocket connection to operator.
-    #[tracing::instrument(level = "trace", skip(self))]
-    async fn connect_target(
+    #[tracing::instrument(level = "trace", skip(self, progress))]
+    async fn connect_target<P: Progress>(
         &self,
+        progress: &P,
         session_info: OperatorSessionInformation,
     ) -> Result<OperatorSessionConnection> {
         // why are we checking on client side..?
@@ -531,47 +974,45 @@ impl OperatorApi {
             self.check_no_port_locks(target).await?;
         }
 
-        let UserIdentity { name, hostname } = UserIdentity::load();
+        // Requests can't be cloned, so we remake them for every attempt from these parts.
+        let request = self.connect_request(&session_info)?;
+        let (uri, headers) = (request.uri(), request.headers());
 
-        let request = {
-            let mut builder = Request::builder()
-                .uri(self.connect_url(&session_info))
-                .header("x-session-id", session_info.metadata.session_id.to_string());
+        let connect = |client: Client| {
+            retry_throttled(progress, move || {
+                let mut request = Request::new(vec![]);
+                *request.uri_mut() = uri.clone();
+                *request.headers_mut() = headers.clone();
 
-            if let Some(name) = name {
-                builder = builder.header("x-client-name", name);
-            };
+                let client = client.clone();
+                async move { client.connect(request).await }
+            })
+        };
 
-            if let Some(hostname) = hostname {
-                builder = builder.header("x-client-hostname", hostname);
-            };
+        // Our credentials might have expired if this session was created a while ago.
+        let connection = match connect(self.client.clone()).await {
+            Err(error) if is_unauthorized(&error) => {
+                warn!(%error, "operator rejected our credentials, refreshing them and retrying");
 
-            match session_info.metadata.client_credentials() {
-                Ok(Some(credentials)) => {
-                    builder = builder.header("x-client-der", credentials);
-                }
-                Ok(None) => {}
-                Err(err) => {
-                    debug!("CredentialStore error: {err}");
-                }
+                let client = self
+                    .credentials
+                    .refresh()
+                    .await
+                    .map_err(OperatorApiError::CreateApiError)?;
+                connect(client).await
             }
+            result => result,
+        }
+        .map_err(|error| {
+            OperatorApiError::from_kube_error(error, OperatorOperation::WebsocketConnection)
+        })?;
 
-            builder
-                .body(vec![])
-                .map_err(OperatorApiError::ConnectRequestBuildError)?
-        };
-
-        let connection =
-            self.client
-                .connect(request)
-                .await
-                .map_err(|error| OperatorApiError::KubeError {
-                    error,
-                    operation: OperatorOperation::WebsocketConnection,
-                })?;
-
-        let (tx, rx) =
-            ConnectionWrapper::wrap(connection, session_info.metadata.protocol_version.clone());
+        let (tx, rx) = ConnectionWrapper::wrap(
+            connection,
+            session_info.metadata.protocol_version.clone(),
+            session_info.metadata.compression(),
+            self.keepalive_interval,
+        );
 
         Ok(OperatorSessionConnection {
             tx,
@@ -587,9 +1028,10 @@ impl OperatorApi {
     ///
     /// `copy_target` feature is not available for all target types.
     /// Target type compatibility is checked by the operator.
-    #[tracing::instrument(level = "trace", skip(self))]
-    async fn copy_target(
+    #[tracing::instrument(level = "trace", skip(self, progress))]
+    async fn copy_target<P: Progress>(
         &self,
+        progress: &P,
         session_metadata: &OperatorSessionMetadata,
         target: Target,
         scale_down: bool,
@@ -605,13 +1047,12 @@ impl OperatorApi {
             },
         );
 
-        self.copy_target_api
-            .create(&PostParams::default(), &requested)
-            .await
-            .map_err(|error| OperatorApiError::KubeError {
-                error,
-                operation: OperatorOperation::CopyingTarget,
-            })
+        let post_params = PostParams::default();
+        retry_throttled(progress, || {
+            self.copy_target_api.create(&post_params, &requested)
+        })
+        .await
+        .map_err(|error| OperatorApiError::from_kube_error(error, OperatorOperation::CopyingTarget))
     }
 }
 
@@ -627,6 +1068,101 @@ enum ConnectionWrapperError {
     InvalidMessage(Message),
     #[error("message channel is closed")]
     ChannelClosed,
+    #[error("failed to compress or decompress message: {0}")]
+    CompressionError(#[from] io::Error),
+    #[error("unknown compression marker {0}")]
+    UnknownCompressionMarker(u8),
+    #[error("decompressed message is larger than {0} bytes")]
+    DecompressedTooLarge(u64),
+    #[error("nothing received from the operator for {0:?}, connection is dead")]
+    KeepaliveTimeout(Duration),
+}
+
+/// Compression of the websocket messages exchanged with the operator.
+///
+/// Used only when the operator advertises [`OperatorFeatures::MessageCompression`]. In that case
+/// every binary message starts with a single marker byte, which tells whether the rest of the
+/// message is a raw or a deflated bincode payload. Payloads smaller than
+/// [`MessageCompression::THRESHOLD`] are not worth compressing and are sent raw.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageCompression {
+    /// Messages are plain bincode payloads, without any marker.
+    #[default]
+    Disabled,
+    /// Messages are prefixed with a marker and large payloads are deflated.
+    Deflate,
+}
+
+impl MessageCompression {
+    /// Header sent with the connect request, so that the operator knows to use the same
+    /// compression in the messages it sends back.
+    pub const HEADER: &'static str = "x-mirrord-compression";
+
+    /// Payloads of this size (in bytes) or larger are compressed.
+    pub const THRESHOLD: usize = 1024;
+
+    /// Largest payload (in bytes) we decompress, same as the default websocket message size limit,
+    /// so that a small deflated message can't take all of our memory.
+    const MAX_PAYLOAD_SIZE: u64 = 64 << 20;
+
+    const RAW_MARKER: u8 = 0;
+    const DEFLATE_MARKER: u8 = 1;
+
+    fn header_value(self) -> Option<&'static str> {
+        match self {
+            Self::Disabled => None,
+            Self::Deflate => Some("deflate"),
+        }
+    }
+
+    /// Prepares an encoded [`ClientMessage`] to be sent as a websocket message.
+    fn compress(self, payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Disabled => Ok(payload),
+            Self::Deflate if payload.len() < Self::THRESHOLD => {
+                let mut message = Vec::with_capacity(payload.len() + 1);
+                message.push(Self::RAW_MARKER);
+                message.extend(payload);
+                Ok(message)
+            }
+            Self::Deflate => {
+                let mut encoder =
+                    DeflateEncoder::new(vec![Self::DEFLATE_MARKER], Compression::fast());
+                encoder.write_all(&payload)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Extracts an encoded [`DaemonMessage`] from a received websocket message.
+    fn decompress(self, mut message: Vec<u8>) -> Result<Vec<u8>, ConnectionWrapperError> {
+        if self == Self::Disabled {
+            return Ok(message);
+        }
+
+        match message.first().copied() {
+            Some(Self::RAW_MARKER) => {
+                message.remove(0);
+                Ok(message)
+            }
+            Some(Self::DEFLATE_MARKER) => {
+                let mut payload = Vec::with_capacity(message.len() * 2);
+                DeflateDecoder::new(message.get(1..).unwrap_or_default())
+                    .take(Self::MAX_PAYLOAD_SIZE + 1)
+                    .read_to_end(&mut payload)?;
+
+                if payload.len() as u64 > Self::MAX_PAYLOAD_SIZE {
+                    return Err(ConnectionWrapperError::DecompressedTooLarge(
+                        Self::MAX_PAYLOAD_SIZE,
+                    ));
+                }
+
+                Ok(payload)
+            }
+            Some(marker) => Err(ConnectionWrapperError::UnknownCompressionMarker(marker)),
+            None => Ok(message),
+        }
+    }
 }
 
 pub struct ConnectionWrapper<T> {
@@ -634,6 +1170,14 @@ pub struct ConnectionWrapper<T> {
     client_rx: Receiver<ClientMessage>,
     daemon_tx: Sender<DaemonMessage>,
     protocol_version: Option<semver::Version>,
+    compression: MessageCompression,
+    /// How often we send [`Message::Ping`]s to keep the connection alive.
+    ///
+    /// Intermediate load balancers drop websocket connections that are idle for too long, which
+    /// happens e.g. when the user holds a breakpoint.
+    keepalive_interval: Option<Duration>,
+    /// When we last received anything from the operator, used to detect dead connections.
+    last_received: Instant,
 }
 
 impl<T> ConnectionWrapper<T>
@@ -644,9 +1188,15 @@ where
         + Unpin
         + 'stream,
 {
+    /// After this many [`Self::keepalive_interval`]s without receiving anything from the
+    /// operator, we consider the connection dead.
+    const KEEPALIVE_MISSED_LIMIT: u32 = 3;
+
     fn wrap(
         connection: T,
         protocol_version: Option<semver::Version>,
+        compression: MessageCompression,
+        keepalive_interval: Option<Duration>,
     ) -> (Sender<ClientMessage>, Receiver<DaemonMessage>) {
         let (client_tx, client_rx) = mpsc::channel(CONNECTION_CHANNEL_SIZE);
         let (daemon_tx, daemon_rx) = mpsc::channel(CONNECTION_CHANNEL_SIZE);
@@ -656,6 +1206,9 @@ where
             connection,
             client_rx,
             daemon_tx,
+            compression,
+            keepalive_interval,
+            last_received: Instant::now(),
         };
 
         tokio::spawn(async move {
@@ -667,11 +1220,39 @@ where
         (client_tx, daemon_rx)
     }
 
+    /// Sends the [`ClientMessage`] to the operator, or answers the
+    /// [`ClientMessage::SwitchProtocolVersion`] on its behalf when it doesn't support the
+    /// negotiation.
+    async fn route_client_message(
+        &mut self,
+        client_message: ClientMessage,
+    ) -> Result<(), ConnectionWrapperError> {
+        match client_message {
+            ClientMessage::SwitchProtocolVersion(version) => {
+                if let Some(operator_protocol_version) = self.protocol_version.as_ref() {
+                    self.handle_client_message(ClientMessage::SwitchProtocolVersion(
+                        operator_protocol_version.min(&version).clone(),
+                    ))
+                    .await
+                } else {
+                    self.daemon_tx
+                        .send(DaemonMessage::SwitchProtocolVersionResponse(
+                            "1.2.1".parse().expect("Bad static version"),
+                        ))
+                        .await
+                        .map_err(|_| ConnectionWrapperError::ChannelClosed)
+                }
+            }
+            client_message => self.handle_client_message(client_message).await,
+        }
+    }
+
     async fn handle_client_message(
         &mut self,
         client_message: ClientMessage,
     ) -> Result<(), ConnectionWrapperError> {
         let payload = bincode::encode_to_vec(client_message, bincode::config::standard())?;
+        let payload = self.compression.compress(payload)?;
 
         self.connection.send(payload.into()).await?;
 
@@ -682,8 +1263,15 @@ where
         &mut self,
         daemon_message: Result<Message, TungsteniteError>,
     ) -> Result<(), ConnectionWrapperError> {
-        match daemon_message? {
+        let daemon_message = daemon_message?;
+        self.last_received = Instant::now();
+
+        match daemon_message {
+            // `tungstenite` answers pings on its own, these only tell us that the operator is
+            // still there.
+            Message::Ping(..) | Message::Pong(..) => Ok(()),
             Message::Binary(payload) => {
+                let payload = self.compression.decompress(payload)?;
                 let (daemon_message, _) = bincode::decode_from_slice::<DaemonMessage, _>(
                     &payload,
                     bincode::config::standard(),
@@ -698,24 +1286,51 @@ where
         }
     }
 
+    /// Called on every tick of the [`Self::keepalive_interval`]. Pings the operator, or gives up
+    /// on the connection when it's been silent for [`Self::KEEPALIVE_MISSED_LIMIT`] intervals.
+    async fn keepalive(&mut self, interval: Duration) -> Result<(), ConnectionWrapperError> {
+        let silent_for = self.last_received.elapsed();
+
+        if silent_for >= interval * Self::KEEPALIVE_MISSED_LIMIT {
+            let error = ConnectionWrapperError::KeepaliveTimeout(silent_for);
+
+            // Lets the intproxy know why the connection is gone.
+            let _ = self
+                .daemon_tx
+                .send(DaemonMessage::Close(error.to_string()))
+                .await;
+
+            return Err(error);
+        }
+
+        self.connection.send(Message::Ping(vec![])).await?;
+
+        Ok(())
+    }
+
     async fn start(mut self) -> Result<(), ConnectionWrapperError> {
+        let mut keepalive = self.keepalive_interval.map(|interval| {
+            let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            (ticker, interval)
+        });
+
         loop {
+            let keepalive_tick = async {
+                match keepalive.as_mut() {
+                    Some((ticker, interval)) => {
+                        ticker.tick().await;
+                        *interval
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+
             tokio::select! {
+                interval = keepalive_tick => self.keepalive(interval).await?,
                 client_message = self.client_rx.recv() => {
                     match client_message {
-                        Some(ClientMessage::SwitchProtocolVersion(version)) => {
-                            if let Some(operator_protocol_version) = self.protocol_version.as_ref() {
-                                self.handle_client_message(ClientMessage::SwitchProtocolVersion(operator_protocol_version.min(&version).clone())).await?;
-                            } else {
-                                self.daemon_tx
-                                    .send(DaemonMessage::SwitchProtocolVersionResponse(
-                                        "1.2.1".parse().expect("Bad static version"),
-                                    ))
-                                    .await
-                                    .map_err(|_| ConnectionWrapperError::ChannelClosed)?;
-                            }
-                        }
-                        Some(client_message) => self.handle_client_message(client_message).await?,
+                        Some(client_message) => self.route_client_message(client_message).await?,
                         None => break,
                     }
                 }
@@ -733,3 +1348,218 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use kube::core::ErrorResponse;
+    use mirrord_config::{
+        config::{ConfigContext, MirrordConfig},
+        feature::{
+            env::EnvContainer,
+            split_queues::{KafkaTopicConfig, SplitQueuesConfig},
+        },
+        LayerFileConfig,
+    };
+    use rstest::rstest;
+
+    use super::{
+        is_throttled, seat_limit_warning, ConnectionWrapperError, MessageCompression, OperatorApi,
+        OperatorApiError, OperatorFailureKind, OperatorSessionMetadata, ThrottleBackoff,
+    };
+    use crate::crd::{LicenseInfoOwned, LicenseUsageSpec, MirrordOperatorCrd, MirrordOperatorSpec};
+
+    #[rstest]
+    #[case(MessageCompression::Disabled, 16)]
+    #[case(MessageCompression::Disabled, 4096)]
+    #[case(MessageCompression::Deflate, 16)]
+    #[case(MessageCompression::Deflate, 4096)]
+    fn compression_roundtrip(#[case] compression: MessageCompression, #[case] size: usize) {
+        let payload = (0..size).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+
+        let message = compression.compress(payload.clone()).unwrap();
+        if compression == MessageCompression::Deflate && size >= MessageCompression::THRESHOLD {
+            assert!(message.len() < payload.len());
+        }
+
+        assert_eq!(compression.decompress(message).unwrap(), payload);
+    }
+
+    #[test]
+    fn decompressed_size_is_capped() {
+        let payload = vec![0; MessageCompression::MAX_PAYLOAD_SIZE as usize + 1];
+        let message = MessageCompression::Deflate.compress(payload).unwrap();
+
+        assert!(matches!(
+            MessageCompression::Deflate.decompress(message),
+            Err(ConnectionWrapperError::DecompressedTooLarge(..))
+        ));
+    }
+
+    #[test]
+    fn unknown_compression_marker() {
+        assert!(MessageCompression::Deflate
+            .decompress(vec![7, 1, 2])
+            .is_err());
+    }
+
+    #[rstest]
+    #[case(429, true)]
+    #[case(503, true)]
+    #[case(500, false)]
+    #[case(403, false)]
+    fn throttled_api_error(#[case] code: u16, #[case] throttled: bool) {
+        let error = kube::Error::Api(ErrorResponse {
+            status: "Failure".into(),
+            message: "".into(),
+            reason: "".into(),
+            code,
+        });
+
+        assert_eq!(is_throttled(&error), throttled);
+    }
+
+    #[test]
+    fn throttle_backoff_gives_up() {
+        let mut backoff = ThrottleBackoff::default();
+
+        for _ in 0..ThrottleBackoff::MAX_ATTEMPTS {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay >= ThrottleBackoff::MIN_DELAY);
+            assert!(delay <= ThrottleBackoff::MAX_DELAY);
+        }
+
+        assert!(backoff.next_delay().is_none());
+    }
+
+    #[test]
+    fn split_queues_headers() {
+        let mut split_queues = SplitQueuesConfig {
+            sqs: vec![],
+            kafka: vec![],
+        };
+        let metadata = OperatorSessionMetadata::new(None, None, vec![], None, None, &split_queues);
+        assert!(metadata.split_queues_headers().unwrap().is_empty());
+
+        split_queues.kafka.push(KafkaTopicConfig {
+            topic: "orders".into(),
+            consumer_group: "processor".into(),
+            header_filter: [("author".to_string(), "^me$".to_string())].into(),
+        });
+        let metadata = OperatorSessionMetadata::new(None, None, vec![], None, None, &split_queues);
+
+        assert_eq!(
+            metadata.split_queues_headers().unwrap(),
+            vec![(
+                OperatorSessionMetadata::KAFKA_SPLITS_HEADER,
+                r#"[{"topic":"orders","consumer_group":"processor","header_filter":{"author":"^me$"}}]"#
+                    .to_string()
+            )]
+        );
+    }
+
+    #[rstest]
+    #[case(1, None, false)]
+    #[case(3, Some(5), false)]
+    #[case(4, Some(5), true)]
+    #[case(5, Some(5), true)]
+    #[case(7, Some(5), true)]
+    #[case(89, Some(100), false)]
+    #[case(90, Some(100), true)]
+    fn seat_limit(
+        #[case] seats_used: usize,
+        #[case] seats_total: Option<usize>,
+        #[case] warns: bool,
+    ) {
+        let usage = LicenseUsageSpec {
+            seats_used,
+            seats_total,
+            expire_at: Default::default(),
+            user_sessions: Default::default(),
+        };
+
+        assert_eq!(seat_limit_warning(&usage).is_some(), warns);
+    }
+
+    #[rstest]
+    #[case(402, "PaymentRequired", "", Some(OperatorFailureKind::LicenseExpired))]
+    #[case(
+        403,
+        "SeatLimitReached",
+        "",
+        Some(OperatorFailureKind::SeatLimitReached)
+    )]
+    #[case(
+        403,
+        "BlockedByPolicy",
+        "blocked by policy `no-steal`",
+        Some(OperatorFailureKind::BlockedByPolicy)
+    )]
+    #[case(403, "Forbidden", "RBAC: access denied", None)]
+    #[case(403, "Forbidden", "violates PodSecurity policy \"restricted\"", None)]
+    #[case(
+        400,
+        "UnsupportedClientVersion",
+        "",
+        Some(OperatorFailureKind::VersionMismatch)
+    )]
+    #[case(404, "NotFound", "", None)]
+    fn failure_kind_from_response(
+        #[case] code: u16,
+        #[case] reason: &str,
+        #[case] message: &str,
+        #[case] expected: Option<OperatorFailureKind>,
+    ) {
+        let response = ErrorResponse {
+            status: "Failure".into(),
+            message: message.into(),
+            reason: reason.into(),
+            code,
+        };
+
+        assert_eq!(OperatorFailureKind::from_response(&response), expected);
+    }
+
+    #[rstest]
+    #[case(None, None, true)]
+    #[case(Some(1), None, false)]
+    #[case(None, Some(vec![]), true)]
+    #[case(None, Some(vec![EnvContainer { name: "worker".into(), prefix: None }]), false)]
+    fn container_options_not_supported(
+        #[case] container_index: Option<usize>,
+        #[case] containers: Option<Vec<EnvContainer>>,
+        #[case] supported: bool,
+    ) {
+        let operator = MirrordOperatorCrd::new(
+            "operator",
+            MirrordOperatorSpec {
+                operator_version: "3.0.0".into(),
+                default_namespace: "default".into(),
+                features: None,
+                license: LicenseInfoOwned {
+                    name: "license".into(),
+                    organization: "org".into(),
+                    expire_at: chrono::NaiveDate::MAX,
+                    fingerprint: None,
+                    subscription_id: None,
+                },
+                protocol_version: None,
+                copy_target_enabled: None,
+            },
+        );
+
+        let mut config = LayerFileConfig::default()
+            .generate_config(&mut ConfigContext::default())
+            .unwrap();
+        config.target.container_index = container_index;
+        config.feature.env.containers = containers;
+
+        let result = OperatorApi::check_config(&config, &operator);
+        assert_eq!(result.is_ok(), supported, "{result:?}");
+        if !supported {
+            assert!(matches!(
+                result,
+                Err(OperatorApiError::UnsupportedFeature { .. })
+            ));
+        }
+    }
+}
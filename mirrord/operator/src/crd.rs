@@ -29,6 +29,7 @@ impl TargetCrd {
     /// for example:
     /// deploy.nginx
     /// deploy.nginx.container.nginx
+    /// rollout.nginx.container.nginx
     pub fn target_name(target: &Target) -> String {
         let (type_name, target, container) = match target {
             Target::Deployment(target) => ("deploy", &target.deployment, &target.container),
@@ -66,6 +67,8 @@ impl From<TargetCrd> for TargetConfig {
         TargetConfig {
             path: crd.spec.target,
             namespace: crd.metadata.namespace,
+            container_index: None,
+            kube_context: None,
         }
     }
 }
@@ -140,6 +143,33 @@ pub struct Session {
 )]
 pub struct SessionSpec;
 
+/// Resource used to query the operator's license usage.
+///
+/// Available only when the operator reports [`OperatorFeatures::LicenseUsage`], fetched under the
+/// [`OPERATOR_STATUS_NAME`] name.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "operator.metalbear.co",
+    version = "v1",
+    kind = "LicenseUsage",
+    root = "LicenseUsageCrd"
+)]
+pub struct LicenseUsageSpec {
+    /// Number of license seats currently taken.
+    pub seats_used: usize,
+    /// Number of seats in the license, [`None`] if the license does not limit seats.
+    pub seats_total: Option<usize>,
+    pub expire_at: NaiveDate,
+    /// Number of active sessions of each user.
+    pub user_sessions: Vec<UserSessionCount>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct UserSessionCount {
+    pub user: String,
+    pub sessions: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct LicenseInfoOwned {
     pub name: String,
@@ -154,6 +184,17 @@ pub struct LicenseInfoOwned {
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, JsonSchema)]
 pub enum OperatorFeatures {
     ProxyApi,
+    /// The operator accepts and produces compressed websocket messages (see
+    /// `MessageCompression` in the client module).
+    MessageCompression,
+    /// The operator serves [`LicenseUsageCrd`].
+    LicenseUsage,
+    /// The operator can split SQS queues between the target and mirrord sessions (see
+    /// `feature.split_queues` in the mirrord config).
+    SqsQueueSplitting,
+    /// The operator can split Kafka topics between the target and mirrord sessions (see
+    /// `feature.split_queues` in the mirrord config).
+    KafkaTopicSplitting,
 }
 
 /// This [`Resource`](kube::Resource) represents a copy pod created from an existing [`Target`]
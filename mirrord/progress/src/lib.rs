@@ -1,4 +1,22 @@
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{FileTypeExt, OpenOptionsExt},
+            net::UnixStream,
+        },
+    },
+    path::Path,
+    sync::{
+        mpsc::{self, SyncSender},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
 
 use enum_dispatch::enum_dispatch;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
@@ -6,11 +24,16 @@ use serde::Serialize;
 use serde_json::{to_string, Value};
 
 pub mod messages;
+pub mod timings;
 
 /// The environment variable name that is used
 /// to determine the mode of progress reporting
 pub const MIRRORD_PROGRESS_ENV: &str = "MIRRORD_PROGRESS_MODE";
 
+/// Prefix of the [`MIRRORD_PROGRESS_ENV`] mode that sends the [`JsonProgress`] messages to the
+/// [`IpcOutput`] at the path that follows it.
+pub const IPC_MODE_PREFIX: &str = "ipc:";
+
 /// Progress report API for displaying notifications in cli/extensions.
 ///
 /// This is our IDE friendly way of sending notification messages from the cli, be careful not to
@@ -44,6 +67,14 @@ pub trait Progress: Sized {
 
     /// Control if drop without calling succes is considered failure.
     fn set_fail_on_drop(&mut self, fail: bool);
+
+    /// Runs `f` with the progress hidden, e.g. to prompt the user in the terminal.
+    fn suspend<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        f()
+    }
 }
 
 /// `ProgressMode` specifies the way progress is reported
@@ -92,26 +123,41 @@ pub struct JsonProgress {
     name: String,
     done: bool,
     fail_on_drop: bool,
+    /// Where the messages go instead of stdout, in the `ipc:<path>` mode.
+    output: Option<IpcOutput>,
 }
 
 impl JsonProgress {
     pub fn new(text: &str) -> JsonProgress {
+        Self::with_output(text, None)
+    }
+
+    /// Sends the messages to `output`, or prints them to stdout when it's [`None`].
+    pub fn with_output(text: &str, output: Option<IpcOutput>) -> JsonProgress {
         let progress = JsonProgress {
             parent: None,
             name: text.to_string(),
             done: false,
             fail_on_drop: true,
+            output,
         };
         progress.print_new_task();
         progress
     }
 
+    fn send(&self, message: ProgressMessage) {
+        match &self.output {
+            Some(output) => output.send(&message),
+            None => message.print(),
+        }
+    }
+
     fn print_new_task(&self) {
         let message = ProgressMessage::NewTask(NewTaskMessage {
             name: self.name.clone(),
             parent: self.parent.clone(),
         });
-        message.print();
+        self.send(message);
     }
 
     fn print_finished_task(&self, success: bool, msg: Option<&str>) {
@@ -120,7 +166,7 @@ impl JsonProgress {
             message: msg.map(|s| s.to_string()),
             success,
         });
-        message.print();
+        self.send(message);
     }
 }
 
@@ -131,6 +177,7 @@ impl Progress for JsonProgress {
             name: text.to_string(),
             done: false,
             fail_on_drop: true,
+            output: self.output.clone(),
         };
         task.print_new_task();
         task
@@ -142,7 +189,7 @@ impl Progress for JsonProgress {
         let message = ProgressMessage::Info {
             message: msg.to_string(),
         };
-        message.print();
+        self.send(message);
     }
 
     fn ide(&self, value: serde_json::Value) {
@@ -152,7 +199,7 @@ impl Progress for JsonProgress {
             .unwrap_or(false)
         {
             let message = ProgressMessage::IdeMessage { message: value };
-            message.print();
+            self.send(message);
         }
     }
 
@@ -160,7 +207,7 @@ impl Progress for JsonProgress {
         let message = ProgressMessage::Warning(WarningMessage {
             message: msg.to_string(),
         });
-        message.print();
+        self.send(message);
     }
 
     fn failure(&mut self, msg: Option<&str>) {
@@ -290,6 +337,13 @@ impl Progress for SpinnerProgress {
         let _ = self.root_progress.println(msg);
     }
 
+    fn suspend<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.root_progress.suspend(f)
+    }
+
     fn warning(&self, msg: &str) {
         let formatted_message = format!("! {msg}");
         self.print(&formatted_message);
@@ -354,6 +408,9 @@ impl ProgressTracker {
             Ok("json") => JsonProgress::new(text).into(),
             Ok("off") => NullProgress.into(),
             Ok("std" | "standard") => SpinnerProgress::new(text).into(),
+            Ok(mode) if mode.starts_with(IPC_MODE_PREFIX) => {
+                JsonProgress::with_output(text, Some(IpcOutput::from_env()?)).into()
+            }
             _ => return None,
         };
 
@@ -361,6 +418,156 @@ impl ProgressTracker {
     }
 }
 
+/// Unix socket (that a program driving mirrord, e.g. an IDE extension, listens on) or named pipe,
+/// where the [`JsonProgress`] messages and the [`SessionEvent`]s go in the `ipc:<path>` mode, one
+/// JSON object per line.
+///
+/// Every process of the session (the cli, the internal proxy) has its own connection, or opens
+/// the pipe on its own. The lines are written from a dedicated thread, so a reader that doesn't
+/// keep up never blocks mirrord, the lines that don't fit in its queue are dropped instead.
+#[derive(Debug, Clone)]
+pub struct IpcOutput(SyncSender<IpcCommand>);
+
+#[derive(Debug)]
+enum IpcCommand {
+    Line(Vec<u8>),
+    /// Responds once the lines sent before it are written.
+    Flush(SyncSender<()>),
+}
+
+impl IpcOutput {
+    /// How many lines wait for the writer thread, before the next ones are dropped.
+    const QUEUE_SIZE: usize = 1024;
+    /// How long [`IpcOutput::flush`] waits for the queued lines to be written.
+    const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Connects to the unix socket at `path`, or opens it for writing when it's a named pipe.
+    ///
+    /// Fails with `ENXIO` when nothing has the named pipe open for reading, instead of waiting
+    /// for a reader.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = if std::fs::metadata(path)?.file_type().is_fifo() {
+            Box::new(open_pipe(path)?)
+        } else {
+            Box::new(UnixStream::connect(path)?)
+        };
+
+        let (tx, rx) = mpsc::sync_channel(Self::QUEUE_SIZE);
+        thread::Builder::new()
+            .name("mirrord-progress-ipc".into())
+            .spawn(move || write_lines(writer, rx))?;
+
+        Ok(Self(tx))
+    }
+
+    /// The output of this process in the `ipc:<path>` mode of [`MIRRORD_PROGRESS_ENV`], connected
+    /// once. [`None`] in the other modes, or when the `path` can't be opened.
+    pub fn from_env() -> Option<Self> {
+        static OUTPUT: OnceLock<Option<IpcOutput>> = OnceLock::new();
+
+        OUTPUT
+            .get_or_init(|| {
+                let mode = std::env::var(MIRRORD_PROGRESS_ENV).ok()?;
+                let path = mode.strip_prefix(IPC_MODE_PREFIX)?;
+                IpcOutput::connect(Path::new(path)).ok()
+            })
+            .clone()
+    }
+
+    pub fn send_event(&self, event: SessionEvent) {
+        self.send(&ProgressMessage::Event(event));
+    }
+
+    /// Waits (for a short while) until the lines sent so far are written, e.g. before the process
+    /// is replaced with `exec`.
+    pub fn flush(&self) {
+        let (tx, rx) = mpsc::sync_channel(1);
+        if self.0.send(IpcCommand::Flush(tx)).is_ok() {
+            let _ = rx.recv_timeout(Self::FLUSH_TIMEOUT);
+        }
+    }
+
+    /// Queues the `message` for the writer thread. Errors are ignored, like when printing to
+    /// stdout, and the message is dropped when the queue is full.
+    fn send(&self, message: &ProgressMessage) {
+        let Ok(mut line) = serde_json::to_vec(message) else {
+            return;
+        };
+        line.push(b'\n');
+
+        let _ = self.0.try_send(IpcCommand::Line(line));
+    }
+}
+
+/// Opens the named pipe at `path` for writing, without waiting for a reader, then makes the writes
+/// blocking again (they're done by the writer thread).
+fn open_pipe(path: &Path) -> io::Result<File> {
+    let pipe = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+
+    let fd = pipe.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(pipe)
+}
+
+/// Runs in the writer thread of an [`IpcOutput`], until all its senders are gone or the reader
+/// goes away.
+fn write_lines(mut writer: Box<dyn Write + Send>, commands: mpsc::Receiver<IpcCommand>) {
+    for command in commands {
+        match command {
+            IpcCommand::Line(line) => {
+                if writer.write_all(&line).is_err() {
+                    return;
+                }
+            }
+            IpcCommand::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// How the traffic of a subscribed port reaches the local application.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionMode {
+    Mirror,
+    Steal,
+}
+
+/// Structured events about the session, sent (besides the tasks and warnings) in the
+/// `ipc:<path>` mode, so that programs driving mirrord don't have to parse its output.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// The internal proxy connected to the agent.
+    SessionStarted {
+        /// Process id of the internal proxy, as in `mirrord status`.
+        pid: u32,
+        /// Path of the target, `targetless` when there is none.
+        target: String,
+        namespace: Option<String>,
+        /// Name of the agent pod, [`None`] when the operator created the agent.
+        agent: Option<String>,
+        operator: bool,
+    },
+    /// The local application subscribed to the remote `port`.
+    PortSubscribed {
+        port: u16,
+        mode: SubscriptionMode,
+        /// HTTP filter of a stolen port.
+        filter: Option<String>,
+    },
+    /// The local application stopped listening on the remote `port`.
+    PortUnsubscribed { port: u16, mode: SubscriptionMode },
+}
+
 /// Message sent when a new task is created using subtask/new
 #[derive(Serialize, Debug, Clone, Default)]
 struct NewTaskMessage {
@@ -446,6 +653,8 @@ enum ProgressMessage {
         /// Should be an [`IdeMessage`] converted to [`Value`].
         message: Value,
     },
+    /// Only sent in the `ipc:<path>` mode, see [`IpcOutput`].
+    Event(SessionEvent),
 }
 
 impl ProgressMessage {
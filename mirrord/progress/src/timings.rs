@@ -0,0 +1,148 @@
+//! Durations of the phases of the session startup, printed by `mirrord exec --timings` and sent
+//! with the analytics, to tell which phase makes a session slow to start.
+//!
+//! Every process marks the phases it goes through in its own timeline, as they end. The cli marks
+//! the ones up to [`StartupPhase::AgentReady`], and passes them to the internal proxy in
+//! [`STARTUP_TIMINGS_ENV`], which marks the rest.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Environment variable with the phases the cli marked, for the internal proxy, see [`to_env`].
+pub const STARTUP_TIMINGS_ENV: &str = "MIRRORD_STARTUP_TIMINGS";
+
+/// Phase of the session startup, in the order they usually happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupPhase {
+    /// Authenticating to the Kubernetes API (or to the operator).
+    KubeAuth,
+    /// Until the agent container is running.
+    AgentSchedule,
+    /// Until the agent is ready to accept connections.
+    AgentReady,
+    /// Until the first message from the local application reaches the internal proxy.
+    FirstProxyMessage,
+    /// Until the agent confirms the first port subscription.
+    PortSubscribe,
+}
+
+impl StartupPhase {
+    const ALL: [Self; 5] = [
+        Self::KubeAuth,
+        Self::AgentSchedule,
+        Self::AgentReady,
+        Self::FirstProxyMessage,
+        Self::PortSubscribe,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::KubeAuth => "kube_auth",
+            Self::AgentSchedule => "agent_schedule",
+            Self::AgentReady => "agent_ready",
+            Self::FirstProxyMessage => "first_proxy_message",
+            Self::PortSubscribe => "port_subscribe",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|phase| phase.name() == name)
+    }
+}
+
+struct Timeline {
+    /// When the last phase ended, [`None`] until the timeline is [`start`]ed.
+    last: Option<Instant>,
+    phases: Vec<(StartupPhase, Duration)>,
+}
+
+static TIMELINE: Mutex<Timeline> = Mutex::new(Timeline {
+    last: None,
+    phases: Vec::new(),
+});
+
+fn timeline<R>(f: impl FnOnce(&mut Timeline) -> R) -> R {
+    let mut timeline = TIMELINE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(&mut timeline)
+}
+
+/// Starts the timeline of this process, its first phase is measured from now.
+pub fn start() {
+    timeline(|timeline| timeline.last = Some(Instant::now()));
+}
+
+/// Marks the end of `phase`, which took the time since the previous mark (or [`start`]).
+///
+/// Only the first mark of each phase counts, and nothing is marked before [`start`].
+pub fn mark(phase: StartupPhase) {
+    timeline(|timeline| {
+        let Some(last) = timeline.last else {
+            return;
+        };
+
+        if timeline.phases.iter().any(|(marked, _)| *marked == phase) {
+            return;
+        }
+
+        let now = Instant::now();
+        timeline.phases.push((phase, now - last));
+        timeline.last = Some(now);
+    });
+}
+
+/// The phases marked so far, in the order they ended.
+pub fn phases() -> Vec<(StartupPhase, Duration)> {
+    timeline(|timeline| timeline.phases.clone())
+}
+
+/// Encodes the phases marked so far for [`STARTUP_TIMINGS_ENV`], as `name=millis` pairs
+/// separated by `,`.
+pub fn to_env() -> String {
+    phases()
+        .into_iter()
+        .map(|(phase, duration)| format!("{}={}", phase.name(), duration.as_millis()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Adds the phases from [`STARTUP_TIMINGS_ENV`], marked by the parent process, to the timeline.
+pub fn inherit() {
+    let Ok(encoded) = std::env::var(STARTUP_TIMINGS_ENV) else {
+        return;
+    };
+
+    let inherited = encoded.split(',').filter_map(|pair| {
+        let (name, millis) = pair.split_once('=')?;
+        let duration = Duration::from_millis(millis.parse().ok()?);
+        Some((StartupPhase::from_name(name)?, duration))
+    });
+
+    timeline(|timeline| timeline.phases.extend(inherited));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_each_phase_once() {
+        mark(StartupPhase::KubeAuth);
+        assert!(phases().is_empty());
+
+        start();
+        mark(StartupPhase::KubeAuth);
+        mark(StartupPhase::AgentReady);
+        mark(StartupPhase::KubeAuth);
+
+        let marked = phases()
+            .into_iter()
+            .map(|(phase, _)| phase)
+            .collect::<Vec<_>>();
+        assert_eq!(marked, [StartupPhase::KubeAuth, StartupPhase::AgentReady]);
+        assert_eq!(to_env().split(',').count(), 2);
+    }
+}
@@ -0,0 +1,165 @@
+//! Features that the two sides of a connection support, negotiated with
+//! [`ClientMessage::SwitchCapabilities`](crate::ClientMessage::SwitchCapabilities).
+//!
+//! Comparing [`VERSION`](crate::VERSION)s only works when the features are released in order, so
+//! each feature has its own flag. Peers that don't support the negotiation get the capabilities
+//! implied by their version, see [`Capabilities::from_version`].
+
+use bincode::{
+    de::{BorrowDecoder, Decoder},
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    BorrowDecode, Decode, Encode,
+};
+use semver::Version;
+
+use crate::{
+    dns::ADDRINFO_FAMILY_VERSION,
+    exec::REMOTE_EXEC_VERSION,
+    file::{
+        CHECKSUM_VERSION, FILE_BATCH_VERSION, FILE_STREAM_VERSION, READDIR_BATCH_VERSION,
+        READ_CHECKSUM_VERSION, SCRATCH_DIR_VERSION,
+    },
+    tcp::{
+        CONNECTION_PAUSE_VERSION, HTTP_FILTERED_UPGRADE_VERSION, HTTP_FRAMED_VERSION,
+        MIRROR_SEQUENCE_VERSION, ORIGINAL_DESTINATION_VERSION, STEAL_FILTER_UPDATE_VERSION,
+    },
+    CANCEL_REQUEST_VERSION,
+};
+
+bitflags::bitflags! {
+    /// Set of features supported by a peer.
+    ///
+    /// New flags are only ever added, flags unknown to the other side are dropped when it decodes
+    /// them.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Capabilities: u64 {
+        /// [`DaemonTcp::HttpRequestFramed`](crate::tcp::DaemonTcp::HttpRequestFramed).
+        const HTTP_FRAMED = 1;
+        /// Upgrades of filtered HTTP connections, e.g. to websockets.
+        const HTTP_FILTERED_UPGRADE = 1 << 1;
+        /// Mirrored data with sequence numbers.
+        const MIRROR_SEQUENCE = 1 << 2;
+        /// Updating the filter of a steal subscription in place.
+        const STEAL_FILTER_UPDATE = 1 << 3;
+        /// Running processes in the target with `feature.remote_exec`.
+        const REMOTE_EXEC = 1 << 4;
+        /// [`FileRequest::ReadDirBatch`](crate::FileRequest::ReadDirBatch).
+        const READDIR_BATCH = 1 << 5;
+        /// [`FileRequest::Checksum`](crate::FileRequest::Checksum).
+        const CHECKSUM = 1 << 6;
+        /// File reads and `getdents64` responses sent in chunks.
+        const FILE_STREAM = 1 << 7;
+        /// [`ClientMessage::CancelRequest`](crate::ClientMessage::CancelRequest).
+        const CANCEL_REQUEST = 1 << 8;
+        /// [`FileRequest::Batch`](crate::FileRequest::Batch).
+        const FILE_BATCH = 1 << 9;
+        /// [`FileRequest::ReadChecksummed`](crate::FileRequest::ReadChecksummed).
+        const READ_CHECKSUM = 1 << 10;
+        /// [`FileRequest::ScratchDir`](crate::FileRequest::ScratchDir).
+        const SCRATCH_DIR = 1 << 11;
+        /// [`ClientMessage::GetAddrInfoRequestV2`](crate::ClientMessage::GetAddrInfoRequestV2).
+        const ADDRINFO_FAMILY = 1 << 12;
+        /// [`DaemonTcp::NewConnectionV2`](crate::tcp::DaemonTcp::NewConnectionV2).
+        const ORIGINAL_DESTINATION = 1 << 13;
+        /// [`LayerTcpSteal::ConnectionPause`](crate::tcp::LayerTcpSteal::ConnectionPause) and
+        /// [`LayerTcpOutgoing::Pause`](crate::outgoing::tcp::LayerTcpOutgoing::Pause).
+        const CONNECTION_PAUSE = 1 << 14;
+        /// [`LayerTcpSteal::PortSubscribe`](crate::tcp::LayerTcpSteal::PortSubscribe) without a
+        /// filter for a port the client already stole without a filter succeeds, instead of
+        /// failing with [`ResponseError::PortAlreadyStolen`](crate::ResponseError::PortAlreadyStolen).
+        ///
+        /// Only negotiated, no version implies it.
+        const STEAL_RESUBSCRIBE = 1 << 15;
+    }
+}
+
+impl Capabilities {
+    /// The capabilities implied by the `version` of a peer that doesn't support the negotiation.
+    pub fn from_version(version: &Version) -> Self {
+        [
+            (&*HTTP_FRAMED_VERSION, Self::HTTP_FRAMED),
+            (&*HTTP_FILTERED_UPGRADE_VERSION, Self::HTTP_FILTERED_UPGRADE),
+            (&*MIRROR_SEQUENCE_VERSION, Self::MIRROR_SEQUENCE),
+            (&*STEAL_FILTER_UPDATE_VERSION, Self::STEAL_FILTER_UPDATE),
+            (&*REMOTE_EXEC_VERSION, Self::REMOTE_EXEC),
+            (&*READDIR_BATCH_VERSION, Self::READDIR_BATCH),
+            (&*CHECKSUM_VERSION, Self::CHECKSUM),
+            (&*FILE_STREAM_VERSION, Self::FILE_STREAM),
+            (&*CANCEL_REQUEST_VERSION, Self::CANCEL_REQUEST),
+            (&*FILE_BATCH_VERSION, Self::FILE_BATCH),
+            (&*READ_CHECKSUM_VERSION, Self::READ_CHECKSUM),
+            (&*SCRATCH_DIR_VERSION, Self::SCRATCH_DIR),
+            (&*ADDRINFO_FAMILY_VERSION, Self::ADDRINFO_FAMILY),
+            (&*ORIGINAL_DESTINATION_VERSION, Self::ORIGINAL_DESTINATION),
+            (&*CONNECTION_PAUSE_VERSION, Self::CONNECTION_PAUSE),
+        ]
+        .into_iter()
+        .filter(|(requirement, _)| requirement.matches(version))
+        .fold(Self::empty(), |capabilities, (_, capability)| {
+            capabilities | capability
+        })
+    }
+}
+
+/// Nothing is supported until the capabilities are negotiated.
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl Encode for Capabilities {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.bits().encode(encoder)
+    }
+}
+
+impl Decode for Capabilities {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        u64::decode(decoder).map(Self::from_bits_truncate)
+    }
+}
+
+impl<'de> BorrowDecode<'de> for Capabilities {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_old_version() {
+        let capabilities = Capabilities::from_version(&Version::new(1, 6, 0));
+
+        assert!(!capabilities.contains(Capabilities::FILE_STREAM));
+        assert!(!capabilities.contains(Capabilities::CANCEL_REQUEST));
+    }
+
+    #[test]
+    fn original_destination_from_version() {
+        assert!(!Capabilities::from_version(&Version::new(1, 16, 0))
+            .contains(Capabilities::ORIGINAL_DESTINATION));
+        assert!(Capabilities::from_version(&Version::new(1, 17, 0))
+            .contains(Capabilities::ORIGINAL_DESTINATION));
+    }
+
+    #[test]
+    fn steal_resubscribe_not_from_version() {
+        assert!(!Capabilities::from_version(&Version::new(2, 0, 0))
+            .contains(Capabilities::STEAL_RESUBSCRIBE));
+    }
+
+    #[test]
+    fn unknown_flags_are_dropped() {
+        let config = bincode::config::standard();
+        let bytes = bincode::encode_to_vec(u64::MAX, config).unwrap();
+
+        let (capabilities, _) =
+            bincode::decode_from_slice::<Capabilities, _>(&bytes, config).unwrap();
+        assert_eq!(capabilities, Capabilities::all());
+    }
+}
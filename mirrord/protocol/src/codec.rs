@@ -12,14 +12,20 @@ use mirrord_macros::protocol_break;
 use semver::VersionReq;
 
 use crate::{
-    dns::{GetAddrInfoRequest, GetAddrInfoResponse},
+    capabilities::Capabilities,
+    dns::{GetAddrInfoRequest, GetAddrInfoRequestV2, GetAddrInfoResponse},
+    exec::{DaemonExec, LayerExec},
     file::{
-        AccessFileRequest, AccessFileResponse, CloseDirRequest, CloseFileRequest, FdOpenDirRequest,
-        GetDEnts64Request, GetDEnts64Response, OpenDirResponse, OpenFileRequest, OpenFileResponse,
-        OpenRelativeFileRequest, ReadDirRequest, ReadDirResponse, ReadFileRequest,
-        ReadFileResponse, ReadLimitedFileRequest, SeekFileRequest, SeekFileResponse,
-        WriteFileRequest, WriteFileResponse, WriteLimitedFileRequest, XstatFsRequest,
-        XstatFsResponse, XstatRequest, XstatResponse,
+        AccessFileRequest, AccessFileResponse, BatchFileRequest, BatchFileResponse,
+        ChecksumFileRequest, ChecksumFileResponse, CloseDirRequest, CloseFileRequest,
+        FdOpenDirRequest, FileStreamEnd, GetDEnts64Chunk, GetDEnts64Request, GetDEnts64Response,
+        GetDEnts64StreamRequest, OpenDirResponse, OpenFileRequest, OpenFileResponse,
+        OpenRelativeFileRequest, ReadChecksummedFileRequest, ReadChecksummedFileResponse,
+        ReadDirBatchRequest, ReadDirBatchResponse, ReadDirRequest, ReadDirResponse, ReadFileChunk,
+        ReadFileRequest, ReadFileResponse, ReadFileStreamRequest, ReadLimitedFileRequest,
+        ScratchDirRequest, ScratchDirResponse, SeekFileRequest, SeekFileResponse, WriteFileRequest,
+        WriteFileResponse, WriteLimitedFileRequest, XstatFsRequest, XstatFsResponse, XstatRequest,
+        XstatResponse,
     },
     outgoing::{
         tcp::{DaemonTcpOutgoing, LayerTcpOutgoing},
@@ -81,12 +87,48 @@ pub enum FileRequest {
     ReadDir(ReadDirRequest),
     CloseDir(CloseDirRequest),
     GetDEnts64(GetDEnts64Request),
+    ReadDirBatch(ReadDirBatchRequest),
+    Checksum(ChecksumFileRequest),
+    ReadStream(ReadFileStreamRequest),
+    GetDEnts64Stream(GetDEnts64StreamRequest),
+    Batch(BatchFileRequest),
+    ReadChecksummed(ReadChecksummedFileRequest),
+    ScratchDir(ScratchDirRequest),
 }
 
 /// Minimal mirrord-protocol version that allows `ClientMessage::ReadyForLogs` message.
 pub static CLIENT_READY_FOR_LOGS: LazyLock<VersionReq> =
     LazyLock::new(|| ">=1.3.1".parse().expect("Bad Identifier"));
 
+/// Minimal mirrord-protocol version that allows [`ClientMessage::CancelRequest`].
+pub static CANCEL_REQUEST_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.12.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`ClientMessage::SwitchCapabilities`].
+pub static CAPABILITIES_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.13.0".parse().expect("Bad Identifier"));
+
+/// Tells the agent to abandon a request whose response nobody waits for anymore.
+///
+/// The agent handles the requests of each kind in order, so a request is identified by the number
+/// of requests of the same kind sent before it on the connection, starting at 0. Only the requests
+/// that can take long are counted, and can be cancelled.
+///
+/// The agent still responds to the cancelled request, so that the responses keep their order.
+/// Requests that were already handled are not affected.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CancelRequest {
+    /// A [`FileRequest::ReadStream`] or [`FileRequest::GetDEnts64Stream`], counted together.
+    ///
+    /// The stream ends early, with a [`FileResponse::StreamEnd`]. A read from the current position
+    /// of the file moves the file back to where the stream started.
+    FileStream(u64),
+    /// A [`LayerTcpOutgoing::Connect`].
+    ///
+    /// The agent stops connecting, and fails the request with [`io::ErrorKind::Interrupted`].
+    TcpConnect(u64),
+}
+
 /// `-layer` --> `-agent` messages.
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub enum ClientMessage {
@@ -103,6 +145,14 @@ pub enum ClientMessage {
     PauseTargetRequest(bool),
     SwitchProtocolVersion(#[bincode(with_serde)] semver::Version),
     ReadyForLogs,
+    Exec(LayerExec),
+    CancelRequest(CancelRequest),
+    /// Sent after the [`ClientMessage::SwitchProtocolVersion`], with all the [`Capabilities`] of
+    /// the client. The agent responds with the ones that both sides support.
+    SwitchCapabilities(Capabilities),
+    /// Sent instead of [`ClientMessage::GetAddrInfoRequest`] when the agent supports it, the
+    /// response is the same.
+    GetAddrInfoRequestV2(GetAddrInfoRequestV2),
 }
 
 /// Type alias for `Result`s that should be returned from mirrord-agent to mirrord-layer.
@@ -122,6 +172,16 @@ pub enum FileResponse {
     ReadDir(RemoteResult<ReadDirResponse>),
     OpenDir(RemoteResult<OpenDirResponse>),
     GetDEnts64(RemoteResult<GetDEnts64Response>),
+    ReadDirBatch(RemoteResult<ReadDirBatchResponse>),
+    Checksum(RemoteResult<ChecksumFileResponse>),
+    /// Chunk of the response to [`FileRequest::ReadStream`], an error ends the stream.
+    ReadChunk(RemoteResult<ReadFileChunk>),
+    /// Chunk of the response to [`FileRequest::GetDEnts64Stream`], an error ends the stream.
+    GetDEnts64Chunk(RemoteResult<GetDEnts64Chunk>),
+    StreamEnd(FileStreamEnd),
+    Batch(RemoteResult<BatchFileResponse>),
+    ReadChecksummed(RemoteResult<ReadChecksummedFileResponse>),
+    ScratchDir(RemoteResult<ScratchDirResponse>),
 }
 
 /// `-agent` --> `-layer` messages.
@@ -143,6 +203,9 @@ pub enum DaemonMessage {
     GetAddrInfoResponse(GetAddrInfoResponse),
     PauseTarget(DaemonPauseTarget),
     SwitchProtocolVersionResponse(#[bincode(with_serde)] semver::Version),
+    Exec(DaemonExec),
+    /// The [`Capabilities`] supported by both sides, see [`ClientMessage::SwitchCapabilities`].
+    SwitchCapabilitiesResponse(Capabilities),
 }
 
 pub struct ProtocolCodec<I, O> {
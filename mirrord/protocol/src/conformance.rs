@@ -0,0 +1,406 @@
+//! Golden encoded messages, for checking that other implementations of the protocol (e.g. custom
+//! operators, or mock agents) match the wire format of this crate.
+//!
+//! The corpus lives in the `corpus` directory of this crate, one file per protocol version that
+//! added messages to it, so implementations in other languages can use it directly. Each line is
+//! `<client|daemon> <name> <hex encoded message>`.
+//!
+//! Rust implementations can run [`check_decoder`] and [`check_encoder`] against
+//! [`client_messages`] and [`daemon_messages`]:
+//!
+//! ```
+//! use mirrord_protocol::{conformance, ClientMessage};
+//!
+//! let result = conformance::check_decoder(&conformance::client_messages(), |bytes| {
+//!     bincode::decode_from_slice::<ClientMessage, _>(bytes, bincode::config::standard())
+//!         .map(|(message, _)| message)
+//!         .map_err(|error| error.to_string())
+//! });
+//!
+//! assert!(result.is_ok(), "{result:?}");
+//! ```
+use std::{
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use crate::{
+    capabilities::Capabilities,
+    dns::{AddressFamily, GetAddrInfoRequestV2},
+    exec::{DaemonExec, ExecData, ExecExit, LayerExec},
+    file::{
+        BatchFileRequest, BatchFileResponse, ChecksumFileRequest, ChecksumFileResponse,
+        CloseFileRequest, FileStreamEnd, ReadChecksummedFileRequest, ReadChecksummedFileResponse,
+        ReadFileChunk, ReadFileRequest, ReadFileStreamRequest, ScratchDirRequest,
+        ScratchDirResponse, WriteFileResponse,
+    },
+    pause::DaemonPauseTarget,
+    tcp::{
+        DaemonTcp, LayerTcp, LayerTcpSteal, NewTcpConnection, NewTcpConnectionV2, TcpData,
+        TcpSequencedClose, TcpSequencedData,
+    },
+    CancelRequest, ClientMessage, DaemonMessage, FileRequest, FileResponse, LogMessage,
+};
+
+/// The corpus files, with the protocol version that generated them. Never change a file once it
+/// was released, add a new one instead.
+const CORPUS: &[(&str, &str)] = &[
+    ("1.8.0", include_str!("../corpus/1.8.0.txt")),
+    ("1.9.0", include_str!("../corpus/1.9.0.txt")),
+    ("1.19.0", include_str!("../corpus/1.19.0.txt")),
+];
+
+/// The [`Capabilities`] in the capabilities messages of the corpus.
+fn golden_capabilities() -> Capabilities {
+    Capabilities::FILE_BATCH | Capabilities::CONNECTION_PAUSE
+}
+
+/// A message from the corpus.
+#[derive(Debug, Clone)]
+pub struct GoldenMessage<M> {
+    /// Name of the message, unique per direction.
+    pub name: &'static str,
+    /// Version of the corpus file this message comes from.
+    pub corpus_version: &'static str,
+    /// The message, as encoded on the wire.
+    pub encoded: Vec<u8>,
+    /// The decoded message.
+    pub message: M,
+}
+
+/// A [`GoldenMessage`] that an implementation got wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub name: &'static str,
+    pub corpus_version: &'static str,
+    pub reason: String,
+}
+
+/// Messages sent by the client, in all the corpus files.
+pub fn client_messages() -> Vec<GoldenMessage<ClientMessage>> {
+    golden_messages("client", client_message)
+}
+
+/// Messages sent by the agent, in all the corpus files.
+pub fn daemon_messages() -> Vec<GoldenMessage<DaemonMessage>> {
+    golden_messages("daemon", daemon_message)
+}
+
+/// Checks that `decode` turns every [`GoldenMessage::encoded`] into its
+/// [`GoldenMessage::message`].
+pub fn check_decoder<M, D, E>(
+    golden: &[GoldenMessage<M>],
+    mut decode: D,
+) -> Result<(), Vec<Mismatch>>
+where
+    M: PartialEq + Debug,
+    D: FnMut(&[u8]) -> Result<M, E>,
+    E: Debug,
+{
+    let mismatches = golden
+        .iter()
+        .filter_map(|golden| {
+            let reason = match decode(&golden.encoded) {
+                Ok(message) if message == golden.message => return None,
+                Ok(message) => format!("decoded {message:?}, expected {:?}", golden.message),
+                Err(error) => format!("failed to decode: {error:?}"),
+            };
+
+            Some(golden.mismatch(reason))
+        })
+        .collect::<Vec<_>>();
+
+    mismatches.is_empty().then_some(()).ok_or(mismatches)
+}
+
+/// Checks that `encode` turns every [`GoldenMessage::message`] into its
+/// [`GoldenMessage::encoded`].
+pub fn check_encoder<M, F>(golden: &[GoldenMessage<M>], mut encode: F) -> Result<(), Vec<Mismatch>>
+where
+    F: FnMut(&M) -> Vec<u8>,
+{
+    let mismatches = golden
+        .iter()
+        .filter_map(|golden| {
+            let encoded = encode(&golden.message);
+
+            (encoded != golden.encoded).then(|| {
+                golden.mismatch(format!(
+                    "encoded {}, expected {}",
+                    to_hex(&encoded),
+                    to_hex(&golden.encoded)
+                ))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    mismatches.is_empty().then_some(()).ok_or(mismatches)
+}
+
+impl<M> GoldenMessage<M> {
+    fn mismatch(&self, reason: String) -> Mismatch {
+        Mismatch {
+            name: self.name,
+            corpus_version: self.corpus_version,
+            reason,
+        }
+    }
+}
+
+/// Parses the corpus lines for `direction`, and pairs them with the messages from `message`.
+///
+/// # Panics
+///
+/// On malformed corpus lines, or names `message` doesn't know. The corpus is part of this crate,
+/// so this is checked by its tests.
+fn golden_messages<M>(direction: &str, message: fn(&str) -> Option<M>) -> Vec<GoldenMessage<M>> {
+    CORPUS
+        .iter()
+        .flat_map(|(corpus_version, corpus)| {
+            corpus
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(move |line| {
+                    let mut parts = line.split_whitespace();
+                    let (Some(line_direction), Some(name), Some(hex), None) =
+                        (parts.next(), parts.next(), parts.next(), parts.next())
+                    else {
+                        panic!("malformed corpus line in {corpus_version}: {line}");
+                    };
+
+                    (line_direction == direction).then(|| GoldenMessage {
+                        name,
+                        corpus_version,
+                        encoded: from_hex(hex).unwrap_or_else(|| {
+                            panic!("invalid hex in corpus {corpus_version}: {line}")
+                        }),
+                        message: message(name).unwrap_or_else(|| {
+                            panic!("unknown {direction} message in corpus {corpus_version}: {name}")
+                        }),
+                    })
+                })
+        })
+        .collect()
+}
+
+fn client_message(name: &str) -> Option<ClientMessage> {
+    Some(match name {
+        "close" => ClientMessage::Close,
+        "tcp_port_subscribe" => ClientMessage::Tcp(LayerTcp::PortSubscribe(80)),
+        "tcp_steal_port_unsubscribe" => {
+            ClientMessage::TcpSteal(LayerTcpSteal::PortUnsubscribe(8080))
+        }
+        "file_close" => ClientMessage::FileRequest(FileRequest::Close(CloseFileRequest { fd: 3 })),
+        "ping" => ClientMessage::Ping,
+        "pause_target_request" => ClientMessage::PauseTargetRequest(true),
+        "switch_protocol_version" => {
+            ClientMessage::SwitchProtocolVersion(semver::Version::new(1, 8, 0))
+        }
+        "ready_for_logs" => ClientMessage::ReadyForLogs,
+        "exec_close_stdin" => ClientMessage::Exec(LayerExec::CloseStdin(7)),
+        "cancel_request_file_stream" => ClientMessage::CancelRequest(CancelRequest::FileStream(3)),
+        "switch_capabilities" => ClientMessage::SwitchCapabilities(golden_capabilities()),
+        "get_addr_info_v2" => ClientMessage::GetAddrInfoRequestV2(GetAddrInfoRequestV2 {
+            node: "db".to_string(),
+            family: AddressFamily::Both,
+        }),
+        "file_checksum" => {
+            ClientMessage::FileRequest(FileRequest::Checksum(ChecksumFileRequest::whole_file(3)))
+        }
+        "file_read_stream" => {
+            ClientMessage::FileRequest(FileRequest::ReadStream(ReadFileStreamRequest {
+                remote_fd: 3,
+                buffer_size: 4096,
+                start_from: Some(0),
+                chunk_size: 1024,
+            }))
+        }
+        "file_batch" => ClientMessage::FileRequest(FileRequest::Batch(BatchFileRequest {
+            requests: vec![FileRequest::Read(ReadFileRequest {
+                remote_fd: 3,
+                buffer_size: 100,
+            })],
+        })),
+        "file_read_checksummed" => {
+            ClientMessage::FileRequest(FileRequest::ReadChecksummed(ReadChecksummedFileRequest {
+                remote_fd: 3,
+                buffer_size: 100,
+                start_from: None,
+            }))
+        }
+        "file_scratch_dir" => {
+            ClientMessage::FileRequest(FileRequest::ScratchDir(ScratchDirRequest {
+                path: "cache".into(),
+            }))
+        }
+        "tcp_steal_connection_pause" => ClientMessage::TcpSteal(LayerTcpSteal::ConnectionPause(1)),
+        "tcp_steal_connection_resume" => {
+            ClientMessage::TcpSteal(LayerTcpSteal::ConnectionResume(1))
+        }
+        _ => return None,
+    })
+}
+
+fn daemon_message(name: &str) -> Option<DaemonMessage> {
+    Some(match name {
+        "close" => DaemonMessage::Close("bye".to_string()),
+        "tcp_data" => DaemonMessage::Tcp(DaemonTcp::Data(TcpData {
+            connection_id: 1,
+            bytes: vec![1, 2, 3],
+        })),
+        "log_message_warn" => DaemonMessage::LogMessage(LogMessage::warn("hi".to_string())),
+        "file_write" => DaemonMessage::File(FileResponse::Write(Ok(WriteFileResponse {
+            written_amount: 300,
+        }))),
+        "pong" => DaemonMessage::Pong,
+        "pause_target" => DaemonMessage::PauseTarget(DaemonPauseTarget::PauseResponse {
+            changed: true,
+            container_paused: false,
+        }),
+        "switch_protocol_version_response" => {
+            DaemonMessage::SwitchProtocolVersionResponse(semver::Version::new(1, 8, 0))
+        }
+        "exec_stdout" => DaemonMessage::Exec(DaemonExec::Stdout(ExecData {
+            id: 7,
+            bytes: vec![1, 2, 3],
+        })),
+        "exec_exited" => DaemonMessage::Exec(DaemonExec::Exited {
+            id: 7,
+            exit: ExecExit::Code(-1),
+        }),
+        "switch_capabilities_response" => {
+            DaemonMessage::SwitchCapabilitiesResponse(golden_capabilities())
+        }
+        "file_checksum" => DaemonMessage::File(FileResponse::Checksum(Ok(ChecksumFileResponse {
+            checksum: 42,
+            length: 300,
+        }))),
+        "file_read_chunk" => DaemonMessage::File(FileResponse::ReadChunk(Ok(ReadFileChunk {
+            sequence: 0,
+            bytes: vec![1, 2, 3],
+        }))),
+        "file_stream_end" => {
+            DaemonMessage::File(FileResponse::StreamEnd(FileStreamEnd { chunks: 1 }))
+        }
+        "file_batch" => DaemonMessage::File(FileResponse::Batch(Ok(BatchFileResponse {
+            responses: vec![FileResponse::Write(Ok(WriteFileResponse {
+                written_amount: 300,
+            }))],
+        }))),
+        // The checksum doesn't match the bytes, the corpus only checks the wire format.
+        "file_read_checksummed" => DaemonMessage::File(FileResponse::ReadChecksummed(Ok(
+            ReadChecksummedFileResponse {
+                bytes: vec![1, 2, 3],
+                read_amount: 3,
+                checksum: 42,
+            },
+        ))),
+        "file_scratch_dir" => {
+            DaemonMessage::File(FileResponse::ScratchDir(Ok(ScratchDirResponse {
+                path: "/scratch/cache".into(),
+            })))
+        }
+        "tcp_data_sequenced" => DaemonMessage::Tcp(DaemonTcp::DataSequenced(TcpSequencedData {
+            connection_id: 1,
+            sequence: 300,
+            bytes: vec![1, 2, 3],
+        })),
+        "tcp_close_sequenced" => DaemonMessage::Tcp(DaemonTcp::CloseSequenced(TcpSequencedClose {
+            connection_id: 1,
+            end_sequence: Some(3),
+        })),
+        "tcp_steal_new_connection_v2" => {
+            DaemonMessage::TcpSteal(DaemonTcp::NewConnectionV2(NewTcpConnectionV2 {
+                connection: NewTcpConnection {
+                    connection_id: 1,
+                    remote_address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                    destination_port: 80,
+                    source_port: 50000,
+                    local_address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+                },
+                original_destination: SocketAddr::new(Ipv4Addr::new(10, 96, 0, 10).into(), 8080),
+            }))
+        }
+        _ => return None,
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_codec::{Decoder, Encoder};
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::{ClientCodec, DaemonCodec};
+
+    fn encode<M, C: Encoder<M>>(codec: &mut C, message: M) -> Vec<u8>
+    where
+        C::Error: Debug,
+    {
+        let mut buf = BytesMut::new();
+        codec.encode(message, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    fn decode<C: Decoder>(codec: &mut C, bytes: &[u8]) -> Result<C::Item, String>
+    where
+        C::Error: Debug,
+    {
+        let mut buf = BytesMut::from(bytes);
+        let message = codec
+            .decode(&mut buf)
+            .map_err(|error| format!("{error:?}"))?
+            .ok_or("incomplete message")?;
+
+        if buf.is_empty() {
+            Ok(message)
+        } else {
+            Err(format!("{} bytes left over", buf.len()))
+        }
+    }
+
+    #[test]
+    fn codec_matches_corpus() {
+        check_encoder(&client_messages(), |message| {
+            encode(&mut ClientCodec::default(), message.clone())
+        })
+        .unwrap();
+        check_decoder(&client_messages(), |bytes| {
+            decode(&mut DaemonCodec::default(), bytes)
+        })
+        .unwrap();
+
+        check_encoder(&daemon_messages(), |message| {
+            encode(&mut DaemonCodec::default(), message.clone())
+        })
+        .unwrap();
+        check_decoder(&daemon_messages(), |bytes| {
+            decode(&mut ClientCodec::default(), bytes)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let mismatches = check_encoder(&daemon_messages(), |_| vec![0x07]).unwrap_err();
+
+        assert_eq!(mismatches.len(), daemon_messages().len() - 1);
+        assert!(mismatches.iter().all(|mismatch| mismatch.name != "pong"));
+    }
+}
@@ -1,12 +1,17 @@
 extern crate alloc;
 use core::ops::Deref;
-use std::net::IpAddr;
+use std::{net::IpAddr, sync::LazyLock};
 
 use bincode::{Decode, Encode};
+use semver::VersionReq;
 use trust_dns_resolver::{lookup_ip::LookupIp, proto::rr::resource::RecordParts};
 
 use crate::RemoteResult;
 
+/// Minimal mirrord-protocol version that allows [`GetAddrInfoRequestV2`].
+pub static ADDRINFO_FAMILY_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.18.0".parse().expect("Bad Identifier"));
+
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub struct LookupRecord {
     pub name: String,
@@ -75,3 +80,38 @@ impl Deref for GetAddrInfoResponse {
 pub struct GetAddrInfoRequest {
     pub node: String,
 }
+
+/// Address families to resolve a name to, see [`GetAddrInfoRequestV2`].
+#[derive(Encode, Decode, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum AddressFamily {
+    /// What [`GetAddrInfoRequest`] resolves.
+    #[default]
+    Ipv4Only,
+    Ipv6Only,
+    /// IPv4 and IPv6, for the application to try both (e.g. happy eyeballs).
+    Both,
+}
+
+/// [`GetAddrInfoRequest`] that resolves the name to the given [`AddressFamily`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct GetAddrInfoRequestV2 {
+    pub node: String,
+    pub family: AddressFamily,
+}
+
+impl From<GetAddrInfoRequest> for GetAddrInfoRequestV2 {
+    fn from(GetAddrInfoRequest { node }: GetAddrInfoRequest) -> Self {
+        Self {
+            node,
+            family: AddressFamily::Ipv4Only,
+        }
+    }
+}
+
+/// For agents that don't support [`GetAddrInfoRequestV2`], the name is resolved to IPv4 addresses
+/// only.
+impl From<GetAddrInfoRequestV2> for GetAddrInfoRequest {
+    fn from(GetAddrInfoRequestV2 { node, .. }: GetAddrInfoRequestV2) -> Self {
+        Self { node }
+    }
+}
@@ -0,0 +1,63 @@
+//! Messages for running processes in the target container (`feature.remote_exec`).
+use std::sync::LazyLock;
+
+use bincode::{Decode, Encode};
+use semver::VersionReq;
+
+use crate::RemoteResult;
+
+/// Minimal mirrord-protocol version that allows [`LayerExec`] and [`DaemonExec`] messages.
+pub static REMOTE_EXEC_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.9.0".parse().expect("Bad Identifier"));
+
+/// Identifies a remote process, chosen by the client.
+pub type ExecId = u64;
+
+/// `-layer` --> `-agent` messages regarding remote processes.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub enum LayerExec {
+    /// Starts a new process, answered with [`DaemonExec::Started`].
+    Start(ExecStartRequest),
+    /// Data for the stdin of the process.
+    Stdin(ExecData),
+    /// Closes the stdin of the process.
+    CloseStdin(ExecId),
+    /// Kills the process.
+    Kill(ExecId),
+}
+
+/// `-agent` --> `-layer` messages regarding remote processes.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub enum DaemonExec {
+    /// Result of [`LayerExec::Start`].
+    Started {
+        id: ExecId,
+        result: RemoteResult<()>,
+    },
+    /// Data from the stdout of the process.
+    Stdout(ExecData),
+    /// Data from the stderr of the process.
+    Stderr(ExecData),
+    /// The process exited, this is the last message about it.
+    Exited { id: ExecId, exit: ExecExit },
+}
+
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ExecStartRequest {
+    pub id: ExecId,
+    /// The command, the program is looked up in the `PATH` of the target container.
+    pub argv: Vec<String>,
+}
+
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ExecData {
+    pub id: ExecId,
+    pub bytes: Vec<u8>,
+}
+
+/// How a remote process exited.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExecExit {
+    Code(i32),
+    Signal(i32),
+}
@@ -5,11 +5,42 @@ use std::fs::DirEntry;
 use std::io;
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::DirEntryExt;
-use std::{fs::Metadata, io::SeekFrom, os::unix::prelude::MetadataExt, path::PathBuf};
+use std::{
+    fs::Metadata, io::SeekFrom, os::unix::prelude::MetadataExt, path::PathBuf, sync::LazyLock,
+};
 
 use bincode::{Decode, Encode};
 #[cfg(target_os = "linux")]
 use nix::sys::statfs::Statfs;
+use semver::VersionReq;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+use crate::{FileRequest, FileResponse};
+
+/// Minimal mirrord-protocol version that allows [`ReadDirBatchRequest`].
+pub static READDIR_BATCH_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.6.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`ChecksumFileRequest`].
+pub static CHECKSUM_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.8.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`ReadFileStreamRequest`] and
+/// [`GetDEnts64StreamRequest`].
+pub static FILE_STREAM_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.11.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`BatchFileRequest`].
+pub static FILE_BATCH_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.14.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`ReadChecksummedFileRequest`].
+pub static READ_CHECKSUM_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.15.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`ScratchDirRequest`].
+pub static SCRATCH_DIR_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.16.0".parse().expect("Bad Identifier"));
 
 /// Internal version of Metadata across operating system (macOS, Linux)
 /// Only mutual attributes
@@ -256,6 +287,51 @@ pub struct ReadLimitedFileRequest {
     pub start_from: u64,
 }
 
+/// Like [`ReadLimitedFileRequest`] (or [`ReadFileRequest`] when `start_from` is [`None`]), but
+/// the agent also sends the [`FileChecksum`] of what it read, so that data corrupted on the way
+/// is detected.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ReadChecksummedFileRequest {
+    pub remote_fd: u64,
+    pub buffer_size: u64,
+    pub start_from: Option<u64>,
+}
+
+/// Response to a [`ReadChecksummedFileRequest`], `checksum` is the [`FileChecksum`] of `bytes`.
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub struct ReadChecksummedFileResponse {
+    pub bytes: Vec<u8>,
+    pub read_amount: u64,
+    pub checksum: u64,
+}
+
+impl ReadChecksummedFileResponse {
+    /// Whether `bytes` are what the agent read.
+    pub fn is_intact(&self) -> bool {
+        self.read_amount == self.bytes.len() as u64
+            && self.checksum == FileChecksum::of(&self.bytes)
+    }
+}
+
+impl fmt::Debug for ReadChecksummedFileResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadChecksummedFileResponse")
+            .field("bytes (length)", &self.bytes.len())
+            .field("read_amount", &self.read_amount)
+            .field("checksum", &self.checksum)
+            .finish()
+    }
+}
+
+impl From<ReadChecksummedFileResponse> for ReadFileResponse {
+    fn from(response: ReadChecksummedFileResponse) -> Self {
+        Self {
+            bytes: response.bytes,
+            read_amount: response.read_amount,
+        }
+    }
+}
+
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub struct SeekFileRequest {
     pub fd: u64,
@@ -322,6 +398,70 @@ pub struct WriteLimitedFileRequest {
     pub write_bytes: Vec<u8>,
 }
 
+/// Asks for the [`FileChecksum`] of up to `length` bytes of the file, starting at `start_from`.
+///
+/// Used to verify (and resume from the last verified offset) large transfers done with
+/// [`ReadLimitedFileRequest`] and [`WriteLimitedFileRequest`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ChecksumFileRequest {
+    pub remote_fd: u64,
+    pub start_from: u64,
+    pub length: u64,
+}
+
+impl ChecksumFileRequest {
+    /// Asks for the [`FileChecksum`] of the whole file, e.g. to verify a copy of it.
+    pub fn whole_file(remote_fd: u64) -> Self {
+        Self {
+            remote_fd,
+            start_from: 0,
+            length: u64::MAX,
+        }
+    }
+}
+
+/// `length` is smaller than the requested one when the end of the file was reached.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ChecksumFileResponse {
+    pub checksum: u64,
+    pub length: u64,
+}
+
+/// Checksum of file contents, used with [`ChecksumFileRequest`] and
+/// [`ReadChecksummedFileRequest`] (64-bit XXH3).
+///
+/// Not cryptographic, it only detects data that got lost or corrupted in transfer.
+#[derive(Clone)]
+pub struct FileChecksum(Xxh3);
+
+impl FileChecksum {
+    /// Feeds more `bytes` into the checksum.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.digest()
+    }
+
+    /// Checksum of the given `bytes`.
+    pub fn of(bytes: &[u8]) -> u64 {
+        xxh3_64(bytes)
+    }
+}
+
+impl Default for FileChecksum {
+    fn default() -> Self {
+        Self(Xxh3::new())
+    }
+}
+
+impl fmt::Debug for FileChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FileChecksum").field(&self.value()).finish()
+    }
+}
+
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub struct CloseFileRequest {
     pub fd: u64,
@@ -378,6 +518,25 @@ pub struct ReadDirResponse {
     pub direntry: Option<DirEntryInternal>,
 }
 
+/// Reads up to `amount` entries from a directory opened with [`FdOpenDirRequest`].
+///
+/// Entries are taken from the same stream as the ones returned for [`ReadDirRequest`].
+/// When `name_filter` is set, the agent skips entries with names that don't match this glob
+/// pattern (`*` and `?` wildcards), so they're never sent to the layer.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ReadDirBatchRequest {
+    pub remote_fd: u64,
+    pub amount: usize,
+    pub name_filter: Option<String>,
+}
+
+/// Empty `dir_entries` means that the end of the directory stream was reached.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ReadDirBatchResponse {
+    pub fd: u64,
+    pub dir_entries: Vec<DirEntryInternal>,
+}
+
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub struct CloseDirRequest {
     pub remote_fd: u64,
@@ -395,3 +554,142 @@ pub struct GetDEnts64Response {
     pub entries: Vec<DirEntryInternal>,
     pub result_size: u64,
 }
+
+/// Like [`ReadFileRequest`] (or [`ReadLimitedFileRequest`] when `start_from` is set), but the
+/// agent responds with a stream of [`ReadFileChunk`]s of up to `chunk_size` bytes, ended with a
+/// [`FileStreamEnd`].
+///
+/// The agent sends every chunk as soon as it's read, so large reads don't have to fit in a single
+/// response.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ReadFileStreamRequest {
+    pub remote_fd: u64,
+    pub buffer_size: u64,
+    pub start_from: Option<u64>,
+    pub chunk_size: u64,
+}
+
+/// Part of the response to a [`ReadFileStreamRequest`].
+///
+/// `sequence` starts at 0 and grows by 1 with every chunk of the stream.
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub struct ReadFileChunk {
+    pub sequence: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Debug for ReadFileChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadFileChunk")
+            .field("sequence", &self.sequence)
+            .field("bytes (length)", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Like [`GetDEnts64Request`], but the agent responds with a stream of [`GetDEnts64Chunk`]s of up
+/// to `chunk_size` bytes of entries each, ended with a [`FileStreamEnd`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct GetDEnts64StreamRequest {
+    pub remote_fd: u64,
+    pub buffer_size: u64,
+    pub chunk_size: u64,
+}
+
+/// Part of the response to a [`GetDEnts64StreamRequest`], see [`ReadFileChunk`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct GetDEnts64Chunk {
+    pub sequence: u64,
+    pub fd: u64,
+    pub entries: Vec<DirEntryInternal>,
+    pub result_size: u64,
+}
+
+/// Ends the stream of chunks sent for a [`ReadFileStreamRequest`] or a
+/// [`GetDEnts64StreamRequest`].
+///
+/// `chunks` is the number of chunks that were sent, so that lost ones can be detected. A stream
+/// that failed ends with an error chunk instead.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct FileStreamEnd {
+    pub chunks: u64,
+}
+
+/// Small file operations sent in a single message, so that they don't cost a round trip each.
+///
+/// Only [`FileRequest::Read`], [`FileRequest::ReadLimited`], [`FileRequest::Write`],
+/// [`FileRequest::WriteLimited`] and [`FileRequest::Seek`] can be batched, the agent fails the
+/// whole batch with [`ResponseError::NotImplemented`](crate::ResponseError::NotImplemented)
+/// otherwise. They're handled in order.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct BatchFileRequest {
+    pub requests: Vec<FileRequest>,
+}
+
+impl BatchFileRequest {
+    /// Whether the `request` can be a part of a batch.
+    pub fn can_batch(request: &FileRequest) -> bool {
+        matches!(
+            request,
+            FileRequest::Read(..)
+                | FileRequest::ReadLimited(..)
+                | FileRequest::Write(..)
+                | FileRequest::WriteLimited(..)
+                | FileRequest::Seek(..)
+        )
+    }
+}
+
+/// Response to a [`BatchFileRequest`], with the response to each of its requests, in the same
+/// order.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct BatchFileResponse {
+    pub responses: Vec<FileResponse>,
+}
+
+/// Creates the directory `path` (with its parents) in the scratch directory of the client, a
+/// temporary directory in the target that the agent removes when the client disconnects.
+///
+/// `path` is relative to the scratch directory, an empty one is the scratch directory itself.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ScratchDirRequest {
+    pub path: PathBuf,
+}
+
+/// Response to a [`ScratchDirRequest`], with the absolute `path` of the directory in the target.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct ScratchDirResponse {
+    pub path: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_in_parts() {
+        let mut checksum = FileChecksum::default();
+        checksum.update(b"hello ");
+        checksum.update(b"world");
+
+        assert_eq!(checksum.value(), FileChecksum::of(b"hello world"));
+        assert_ne!(checksum.value(), FileChecksum::of(b"hello wormd"));
+    }
+
+    #[test]
+    fn checksummed_read_intact() {
+        let mut response = ReadChecksummedFileResponse {
+            bytes: b"data".to_vec(),
+            read_amount: 4,
+            checksum: FileChecksum::of(b"data"),
+        };
+        assert!(response.is_intact());
+
+        response.bytes[0] = b'D';
+        assert!(!response.is_intact());
+
+        response.bytes.truncate(0);
+        response.checksum = FileChecksum::of(&[]);
+        assert!(!response.is_intact());
+    }
+}
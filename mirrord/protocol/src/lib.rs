@@ -3,12 +3,16 @@
 #![feature(lazy_cell)]
 #![warn(clippy::indexing_slicing)]
 
+pub mod capabilities;
 pub mod codec;
+pub mod conformance;
 pub mod dns;
 pub mod error;
+pub mod exec;
 pub mod file;
 pub mod outgoing;
 pub mod pause;
+pub mod priority;
 pub mod tcp;
 
 use core::fmt;
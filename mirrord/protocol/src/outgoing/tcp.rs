@@ -6,6 +6,12 @@ pub enum LayerTcpOutgoing {
     Connect(LayerConnect),
     Write(LayerWrite),
     Close(LayerClose),
+    /// Asks the agent to stop reading from the connection, because the client can't keep up with
+    /// its data. Only sent when the agent has
+    /// [`Capabilities::CONNECTION_PAUSE`](crate::capabilities::Capabilities::CONNECTION_PAUSE).
+    Pause(ConnectionId),
+    /// Resumes a connection paused with [`LayerTcpOutgoing::Pause`].
+    Resume(ConnectionId),
 }
 
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
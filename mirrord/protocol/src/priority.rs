@@ -0,0 +1,94 @@
+//! Priority classes of the messages exchanged between the internal proxy and the agent.
+//!
+//! Everything goes through a single connection, so a big remote file scan could queue up
+//! megabytes of file responses in front of a stolen HTTP request. The agent uses [`FairShare`] to
+//! let the [`Priority::Interactive`] messages go before the chunks of streamed file reads, while
+//! still letting a [`Priority::Bulk`] one through every [`INTERACTIVE_PER_BULK`] messages, so
+//! that file operations don't starve either.
+//!
+//! Only messages that don't have to keep their order with each other are reordered. The streamed
+//! chunks answer a single request, and the file requests that come after it wait for them. The
+//! messages of the clients are never reordered, since they don't say which layer they come from,
+//! and a layer's file operations have to keep their order with its traffic (e.g. writing a file
+//! before connecting to a service that reads it).
+
+use crate::DaemonMessage;
+
+/// How many [`Priority::Interactive`] messages can be sent in a row while
+/// [`Priority::Bulk`] ones wait.
+pub const INTERACTIVE_PER_BULK: usize = 8;
+
+/// Priority class of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Traffic, DNS and control messages, someone is usually waiting for them.
+    Interactive,
+    /// File operations, which can move a lot of data.
+    Bulk,
+}
+
+/// Messages that have a [`Priority`].
+pub trait Prioritized {
+    fn priority(&self) -> Priority;
+}
+
+impl Prioritized for DaemonMessage {
+    fn priority(&self) -> Priority {
+        match self {
+            Self::File(..) => Priority::Bulk,
+            _ => Priority::Interactive,
+        }
+    }
+}
+
+/// Keeps track of the [`Priority::Interactive`] messages sent in a row, to give
+/// [`Priority::Bulk`] ones their turn.
+#[derive(Debug, Default)]
+pub struct FairShare {
+    interactive_in_row: usize,
+}
+
+impl FairShare {
+    /// Records that a message of the given priority was sent.
+    pub fn sent(&mut self, priority: Priority) {
+        match priority {
+            Priority::Interactive => self.interactive_in_row += 1,
+            Priority::Bulk => self.interactive_in_row = 0,
+        }
+    }
+
+    /// Whether a waiting [`Priority::Bulk`] message should be sent next.
+    pub fn bulk_turn(&self) -> bool {
+        self.interactive_in_row >= INTERACTIVE_PER_BULK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file::SeekFileResponse, FileResponse};
+
+    #[test]
+    fn priorities() {
+        let file_response = DaemonMessage::File(FileResponse::Seek(Ok(SeekFileResponse {
+            result_offset: 0,
+        })));
+
+        assert_eq!(file_response.priority(), Priority::Bulk);
+        assert_eq!(DaemonMessage::Pong.priority(), Priority::Interactive);
+    }
+
+    #[test]
+    fn bulk_is_not_starved() {
+        let mut fair_share = FairShare::default();
+
+        for _ in 0..INTERACTIVE_PER_BULK {
+            assert!(!fair_share.bulk_turn());
+            fair_share.sent(Priority::Interactive);
+        }
+        assert!(fair_share.bulk_turn());
+
+        fair_share.sent(Priority::Bulk);
+        assert!(!fair_share.bulk_turn());
+    }
+}
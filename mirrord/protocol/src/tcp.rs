@@ -3,7 +3,7 @@ use std::{
     collections::VecDeque,
     convert::Infallible,
     fmt,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
     sync::LazyLock,
     task::{Context, Poll},
@@ -34,6 +34,15 @@ pub struct NewTcpConnection {
     pub local_address: IpAddr,
 }
 
+/// [`NewTcpConnection`] with the address it was originally sent to, before it was redirected to
+/// the agent.
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct NewTcpConnectionV2 {
+    pub connection: NewTcpConnection,
+    /// What `getsockopt(SO_ORIGINAL_DST)` returns for the connection.
+    pub original_destination: SocketAddr,
+}
+
 #[derive(Encode, Decode, PartialEq, Eq, Clone)]
 pub struct TcpData {
     pub connection_id: ConnectionId,
@@ -54,6 +63,36 @@ pub struct TcpClose {
     pub connection_id: ConnectionId,
 }
 
+/// [`TcpData`] of a mirrored connection, tagged with its position in the connection's byte
+/// stream, so that the receiver can put segments back in order.
+#[derive(Encode, Decode, PartialEq, Eq, Clone)]
+pub struct TcpSequencedData {
+    pub connection_id: ConnectionId,
+    /// Offset of the first byte of `bytes` in the stream sent by the remote peer, starting from
+    /// 0.
+    pub sequence: u64,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Debug for TcpSequencedData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpSequencedData")
+            .field("connection_id", &self.connection_id)
+            .field("sequence", &self.sequence)
+            .field("bytes (length)", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// [`TcpClose`] of a mirrored connection that was sending [`TcpSequencedData`].
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
+pub struct TcpSequencedClose {
+    pub connection_id: ConnectionId,
+    /// Total length of the stream sent by the remote peer, if the connection was closed by the
+    /// remote peer.
+    pub end_sequence: Option<u64>,
+}
+
 /// Messages related to Tcp handler from client.
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
 pub enum LayerTcp {
@@ -73,6 +112,15 @@ pub enum DaemonTcp {
     SubscribeResult(RemoteResult<Port>),
     HttpRequest(HttpRequest<Vec<u8>>),
     HttpRequestFramed(HttpRequest<InternalHttpBody>),
+    /// Sent instead of [`DaemonTcp::Data`] for mirrored connections, when the client supports
+    /// [`MIRROR_SEQUENCE_VERSION`].
+    DataSequenced(TcpSequencedData),
+    /// Sent instead of [`DaemonTcp::Close`] for mirrored connections, when the client supports
+    /// [`MIRROR_SEQUENCE_VERSION`].
+    CloseSequenced(TcpSequencedClose),
+    /// Sent instead of [`DaemonTcp::NewConnection`] for stolen connections, when the client has
+    /// [`Capabilities::ORIGINAL_DESTINATION`](crate::capabilities::Capabilities::ORIGINAL_DESTINATION).
+    NewConnectionV2(NewTcpConnectionV2),
 }
 
 /// Wraps the string that will become a [`fancy_regex::Regex`], providing a nice API in
@@ -155,6 +203,12 @@ pub enum LayerTcpSteal {
     Data(TcpData),
     HttpResponse(HttpResponse<Vec<u8>>),
     HttpResponseFramed(HttpResponse<InternalHttpBody>),
+    /// Asks the agent to stop reading from the stolen connection, because the client can't keep
+    /// up with its data. Only sent when the agent has
+    /// [`Capabilities::CONNECTION_PAUSE`](crate::capabilities::Capabilities::CONNECTION_PAUSE).
+    ConnectionPause(ConnectionId),
+    /// Resumes a connection paused with [`LayerTcpSteal::ConnectionPause`].
+    ConnectionResume(ConnectionId),
 }
 
 /// (De-)Serializable HTTP request.
@@ -277,6 +331,30 @@ pub static HTTP_FRAMED_VERSION: LazyLock<VersionReq> =
 pub static HTTP_FILTERED_UPGRADE_VERSION: LazyLock<VersionReq> =
     LazyLock::new(|| ">=1.5.0".parse().expect("Bad Identifier"));
 
+/// Minimal mirrord-protocol version that allows [`DaemonTcp::DataSequenced`] and
+/// [`DaemonTcp::CloseSequenced`] instead of [`DaemonTcp::Data`] and [`DaemonTcp::Close`] for
+/// mirrored connections.
+pub static MIRROR_SEQUENCE_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.7.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`LayerTcpSteal::PortSubscribe`] with a
+/// [`StealType::FilteredHttpEx`] to replace the filter of a port the client already stole with a
+/// filter, instead of failing with [`ResponseError::PortAlreadyStolen`].
+pub static STEAL_FILTER_UPDATE_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.10.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`DaemonTcp::NewConnectionV2`] instead of
+/// [`DaemonTcp::NewConnection`] for stolen connections, for peers that don't negotiate
+/// [`Capabilities`](crate::capabilities::Capabilities).
+pub static ORIGINAL_DESTINATION_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.17.0".parse().expect("Bad Identifier"));
+
+/// Minimal mirrord-protocol version that allows [`LayerTcpSteal::ConnectionPause`] and
+/// [`LayerTcpOutgoing::Pause`](crate::outgoing::tcp::LayerTcpOutgoing::Pause), for peers that
+/// don't negotiate [`Capabilities`](crate::capabilities::Capabilities).
+pub static CONNECTION_PAUSE_VERSION: LazyLock<VersionReq> =
+    LazyLock::new(|| ">=1.19.0".parse().expect("Bad Identifier"));
+
 /// Protocol break - on version 2, please add source port, dest/src IP to the message
 /// so we can avoid losing this information.
 #[derive(Encode, Decode, Debug, PartialEq, Eq, Clone)]
@@ -321,6 +399,22 @@ pub struct InternalHttpResponse<Body> {
 }
 
 impl<B> InternalHttpResponse<B> {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &B {
+        &self.body
+    }
+
     pub fn map_body<T, F>(self, cb: F) -> InternalHttpResponse<T>
     where
         F: FnOnce(B) -> T,
@@ -345,6 +439,18 @@ impl<B> InternalHttpResponse<B> {
 pub struct InternalHttpBody(VecDeque<InternalHttpBodyFrame>);
 
 impl InternalHttpBody {
+    /// Contents of the data frames, without the trailers.
+    pub fn data(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .filter_map(|frame| match frame {
+                InternalHttpBodyFrame::Data(data) => Some(data.as_slice()),
+                InternalHttpBodyFrame::Trailers(..) => None,
+            })
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Self {
         InternalHttpBody(VecDeque::from([InternalHttpBodyFrame::Data(
             bytes.to_vec(),
@@ -82,9 +82,34 @@ mod main {
         subtype as u8 == macho::CPU_SUBTYPE_ARM64E as u8
     }
 
+    /// Whether this process is translated by Rosetta, i.e. it's an x64 process on Apple Silicon.
+    fn is_translated() -> bool {
+        let mut translated: libc::c_int = 0;
+        let mut size = std::mem::size_of::<libc::c_int>();
+        let result = unsafe {
+            libc::sysctlbyname(
+                c"sysctl.proc_translated".as_ptr(),
+                (&mut translated as *mut libc::c_int).cast(),
+                &mut size,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+
+        result == 0 && translated == 1
+    }
+
+    /// Whether arm64 binaries run natively on this machine.
+    ///
+    /// That's also the case for x64 processes translated by Rosetta, so we pick the arm64 binary
+    /// out of fat binaries for their children too, instead of running them under Rosetta. The
+    /// layer is a universal binary, so the children load the layer of their own architecture.
+    fn runs_arm64() -> bool {
+        cfg!(target_arch = "aarch64") || is_translated()
+    }
+
     /// Return whether a binary that is a member of a fat binary is arm64 (not arm64e).
     /// We don't include arm64e because the SIP patching trick does not work with arm64e binaries.
-    #[cfg(target_arch = "aarch64")]
     fn is_fat_arm64_arch(arch: &&impl FatArch) -> bool {
         matches!(arch.architecture(), Architecture::Aarch64)
             && !is_cpu_subtype_arm64e(arch.cpusubtype())
@@ -95,6 +120,15 @@ mod main {
         matches!(arch.architecture(), Architecture::X86_64)
     }
 
+    /// The binary to use out of a fat binary: arm64 (not arm64e) if arm64 binaries run natively
+    /// on this machine and the fat binary contains one, x64 otherwise.
+    fn find_fat_arch<A: FatArch>(fat_slice: &[A]) -> Option<&A> {
+        runs_arm64()
+            .then(|| fat_slice.iter().find(is_fat_arm64_arch))
+            .flatten()
+            .or_else(|| fat_slice.iter().find(is_fat_x64_arch))
+    }
+
     struct BinaryInfo {
         offset: usize,
         size: usize,
@@ -157,16 +191,7 @@ mod main {
                     let fat_slice = FatHeader::parse_arch32(bytes).map_err(|_| {
                         SipError::UnsupportedFileFormat("FatMach-O 32-bit".to_string())
                     })?;
-                    #[cfg(target_arch = "aarch64")]
-                    let found_arch = fat_slice
-                        .iter()
-                        .find(is_fat_arm64_arch)
-                        .or_else(|| fat_slice.iter().find(is_fat_x64_arch));
-
-                    #[cfg(target_arch = "x86_64")]
-                    let found_arch = fat_slice.iter().find(is_fat_x64_arch);
-
-                    found_arch
+                    find_fat_arch(fat_slice)
                         .map(|arch| Self::new(arch.offset() as usize, arch.size() as usize))
                         .ok_or(SipError::NoSupportedArchitecture)
                 }
@@ -179,16 +204,7 @@ mod main {
                         SipError::UnsupportedFileFormat("Mach-O 32-bit".to_string())
                     })?;
 
-                    #[cfg(target_arch = "aarch64")]
-                    let found_arch = fat_slice
-                        .iter()
-                        .find(is_fat_arm64_arch)
-                        .or_else(|| fat_slice.iter().find(is_fat_x64_arch));
-
-                    #[cfg(target_arch = "x86_64")]
-                    let found_arch = fat_slice.iter().find(is_fat_x64_arch);
-
-                    found_arch
+                    find_fat_arch(fat_slice)
                         .map(|arch| Self::new(arch.offset() as usize, arch.size() as usize))
                         .ok_or(SipError::NoSupportedArchitecture)
                 }
@@ -375,12 +391,44 @@ mod main {
                 // magic, whitespace and path.
                 (file_contents.get(start_of_path + 2..)?, file_contents.len())
             };
+        let interpreter_path = PathBuf::from(interpreter);
+        let env_program = if interpreter_path.file_name() == Some(OsStr::new("env")) {
+            get_env_program(file_contents, len_with_whitespace)
+        } else {
+            None
+        };
+
         Some(ScriptShebang {
-            interpreter_path: PathBuf::from(interpreter),
+            interpreter_path,
             start_of_rest_of_file: len_with_whitespace,
+            env_program,
         })
     }
 
+    /// Extract the program from a `#!/usr/bin/env program` shebang, where the arguments of `env`
+    /// start at `start_of_args`.
+    ///
+    /// Returns [`None`] when `env` gets options or variables before the program (e.g.
+    /// `#!/usr/bin/env -S python3 -u`), as then we can't run the program directly.
+    fn get_env_program(file_contents: &str, start_of_args: usize) -> Option<(String, usize)> {
+        let args = file_contents.get(start_of_args..)?;
+        let args = args.split('\n').next()?;
+        let start_of_program = args.find(|c: char| c != ' ' && c != '\t')?;
+        let program = args
+            .get(start_of_program..)?
+            .split(char::is_whitespace)
+            .next()?;
+
+        if program.starts_with('-') || program.contains('=') {
+            return None;
+        }
+
+        Some((
+            program.to_string(),
+            start_of_args + start_of_program + program.len(),
+        ))
+    }
+
     /// Including '#!', just until whitespace, no arguments.
     fn read_shebang_from_file<P: AsRef<Path>>(path: P) -> Result<Option<ScriptShebang>> {
         let mut f = std::fs::File::open(path)?;
@@ -405,6 +453,34 @@ mod main {
         /// !# /usr/bin/env bash
         ///                ^-- Rest of the file starts at index 15.
         start_of_rest_of_file: usize,
+
+        /// For `#!/usr/bin/env program` shebangs, the `program` that `env` looks up in `PATH`, and
+        /// the index right after it.
+        env_program: Option<(String, usize)>,
+    }
+
+    impl ScriptShebang {
+        /// Shebang that runs the program of a `#!/usr/bin/env program` shebang directly, if it's a
+        /// SIP-protected binary.
+        ///
+        /// The patched `env` would also run the program with the layer, through the `execve` hook,
+        /// but running it directly does not depend on how `env` looks it up and executes it.
+        fn sip_env_program(&self, patch_binaries: &[String]) -> Option<ScriptShebang> {
+            let (program, start_of_rest_of_file) = self.env_program.as_ref()?;
+            let program_path = get_complete_path(program.as_str()).ok()?;
+
+            if read_shebang_from_file(&program_path).ok()?.is_some()
+                || !is_binary_sip(&program_path, patch_binaries).ok()?
+            {
+                return None;
+            }
+
+            Some(ScriptShebang {
+                interpreter_path: program_path,
+                start_of_rest_of_file: *start_of_rest_of_file,
+                env_program: None,
+            })
+        }
     }
 
     #[derive(Debug)]
@@ -484,6 +560,13 @@ mod main {
         }
 
         if let Some(shebang) = read_shebang_from_file(&complete_path)? {
+            if let Some(shebang) = shebang.sip_env_program(patch_binaries) {
+                return Ok(SipScript {
+                    path: complete_path,
+                    shebang,
+                });
+            }
+
             let interpreter_complete_path = get_complete_path(&shebang.interpreter_path)?;
             if is_in_mirrord_tmp_dir(&interpreter_complete_path)? {
                 return Ok(NoSip);
@@ -675,10 +758,23 @@ mod main {
             assert_eq!(file_kind, FileKind::MachO64);
             let header: &MachHeader64<Endianness> = MachHeader::parse(&data[..], 0).unwrap();
             let cpu_type = header.cputype(Endianness::default());
-            #[cfg(target_arch = "aarch64")]
-            assert_eq!(cpu_type, macho::CPU_TYPE_ARM64);
-            #[cfg(target_arch = "x86_64")]
-            assert_eq!(cpu_type, macho::CPU_TYPE_X86_64);
+            if runs_arm64() {
+                assert_eq!(cpu_type, macho::CPU_TYPE_ARM64);
+            } else {
+                assert_eq!(cpu_type, macho::CPU_TYPE_X86_64);
+            }
+        }
+
+        /// `hw.optional.arm64` is set on Apple Silicon, also for processes translated by Rosetta.
+        #[test]
+        fn runs_arm64_on_apple_silicon() {
+            let output = std::process::Command::new("sysctl")
+                .args(["-n", "hw.optional.arm64"])
+                .output()
+                .unwrap();
+            let apple_silicon = String::from_utf8_lossy(&output.stdout).trim() == "1";
+
+            assert_eq!(runs_arm64(), apple_silicon);
         }
 
         fn test_patch_script(script_contents: &str) {
@@ -748,13 +844,55 @@ mod main {
             )
         }
 
+        #[test]
+        fn env_program_from_string() {
+            let contents = "#!/usr/bin/env  python3 -u\nprint()\n".to_string();
+            assert_eq!(
+                get_shebang_from_string(&contents).unwrap().env_program,
+                Some(("python3".to_string(), 23))
+            );
+            let contents = "#!/usr/bin/env -S python3 -u\n".to_string();
+            assert_eq!(
+                get_shebang_from_string(&contents).unwrap().env_program,
+                None
+            );
+            let contents = "#!/bin/bash\n".to_string();
+            assert_eq!(
+                get_shebang_from_string(&contents).unwrap().env_program,
+                None
+            );
+        }
+
+        /// Run `sip_patch` on a script with a `#!/usr/bin/env bash` shebang, verify that the new
+        /// shebang points to a patched version of `bash`, without `env`.
+        #[test]
+        fn patch_env_program() {
+            let mut script = tempfile::NamedTempFile::new().unwrap();
+            script.write_all(b"#!/usr/bin/env bash\nexit\n").unwrap();
+            script.flush().unwrap();
+            let changed_script_path = sip_patch(script.path().to_str().unwrap(), &Vec::new())
+                .unwrap()
+                .unwrap();
+            let new_shebang = read_shebang_from_file(&changed_script_path)
+                .unwrap()
+                .unwrap();
+            assert!(new_shebang
+                .interpreter_path
+                .starts_with(&*MIRRORD_TEMP_BIN_DIR_PATH_BUF));
+            assert!(new_shebang.interpreter_path.ends_with("bin/bash"));
+            assert_eq!(
+                std::fs::read_to_string(changed_script_path).unwrap(),
+                format!("#!{}\nexit\n", new_shebang.interpreter_path.display())
+            );
+        }
+
         /// Run `sip_patch` on a script with a shebang that points to `env`, verify that a path to
         /// a new script is returned, in which the shebang points to a patched version of `env`
         /// that is not SIPed.
         #[test]
         fn patch_shebang_and_binary() {
             let mut script = tempfile::NamedTempFile::new().unwrap();
-            let script_contents = "#!/usr/bin/env bash\nexit\n";
+            let script_contents = "#!/usr/bin/env -S bash\nexit\n";
             script.write_all(script_contents.as_ref()).unwrap();
             script.flush().unwrap();
             let changed_script_path = sip_patch(script.path().to_str().unwrap(), &Vec::new())
@@ -7,7 +7,7 @@ import fileinput
 
 from sys import stderr
 
-LISTEN_ADDR = ("127.0.0.1", 80)
+LISTEN_ADDR = ("0.0.0.0", 80)
 
 
 def main():
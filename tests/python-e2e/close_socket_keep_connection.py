@@ -8,7 +8,7 @@ import fileinput
 from sys import stderr
 
 TEST_DATA = b"test"
-LISTEN_ADDR = ("127.0.0.1", 80)
+LISTEN_ADDR = ("0.0.0.0", 80)
 
 
 def main():
@@ -118,8 +118,14 @@ mod traffic {
             "node",
             "node-e2e/outgoing/test_outgoing_traffic_make_request_after_listen.mjs",
         ];
-        let mut process =
-            run_exec_with_target(node_command, &service.target, None, None, None).await;
+        let mut process = run_exec_with_target(
+            node_command,
+            &service.target,
+            None,
+            None,
+            Some(vec![("MIRRORD_INCOMING_INTERCEPT_LOOPBACK", "true")]),
+        )
+        .await;
         let res = process.wait().await;
         assert!(res.success());
     }
@@ -446,8 +452,14 @@ mod traffic {
     pub async fn listen_localhost(#[future] service: KubeService) {
         let service = service.await;
         let node_command = vec!["node", "node-e2e/listen/test_listen_localhost.mjs"];
-        let mut process =
-            run_exec_with_target(node_command, &service.target, None, None, None).await;
+        let mut process = run_exec_with_target(
+            node_command,
+            &service.target,
+            None,
+            None,
+            Some(vec![("MIRRORD_INCOMING_INTERCEPT_LOOPBACK", "true")]),
+        )
+        .await;
         let res = process.wait().await;
         assert!(res.success());
     }